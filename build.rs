@@ -1,6 +1,11 @@
 use std::env;
+use std::fs;
 use std::path::PathBuf;
 
+// Internal SRAM of the ATmega128 in the linker's data address space
+const SRAM_START: u32 = 0x800100;
+const SRAM_END: u32 = 0x801100;
+
 fn main() {
     // Set linker script path
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
@@ -8,8 +13,49 @@ fn main() {
     // Configure for ATmega128
     println!("cargo:rustc-link-arg=-mmcu=atmega128");
 
-    // Pass CPU frequency for timing calculations
-    println!("cargo:rustc-env=MCU_FREQ_HZ=16000000");
+    // Keep the boot mailbox (hal::mailbox) where the bootloader expects it:
+    // .noinit at the start of SRAM, initialized data after it
+    println!("cargo:rustc-link-arg=-Wl,--section-start=.noinit=0x800100");
+    println!("cargo:rustc-link-arg=-Wl,--section-start=.data=0x800110");
+
+    // Pass CPU frequency for timing calculations, 16MHz unless the build
+    // environment overrides it (e.g. MCU_FREQ_HZ=7372800)
+    println!("cargo:rerun-if-env-changed=MCU_FREQ_HZ");
+    let freq = env::var("MCU_FREQ_HZ").unwrap_or_else(|_| "16000000".to_string());
+    println!("cargo:rustc-env=MCU_FREQ_HZ={}", freq);
+
+    // Supply voltage of the board in mV, 5V unless overridden (e.g.
+    // BOARD_VCC_MV=3300). AVCC is the ADC reference, so it scales every
+    // voltage reading.
+    println!("cargo:rerun-if-env-changed=BOARD_VCC_MV");
+    let vcc = env::var("BOARD_VCC_MV").unwrap_or_else(|_| "5000".to_string());
+    println!("cargo:rustc-env=BOARD_VCC_MV={}", vcc);
+
+    // Static buffer sizes, see config.rs. Defaults fit the 4KB of SRAM
+    // with room for the stack; shrink them to make space for other drivers.
+    let uart = buffer_size("UART_BUFFER_SIZE", 32);
+    let transport_rx = buffer_size("TRANSPORT_RX_BUFFER", 512);
+    let transport_tx = buffer_size("TRANSPORT_TX_BUFFER", 512);
+    let log_entries = buffer_size("LOG_BUFFER_ENTRIES", 32);
+    let events = buffer_size("EVENT_QUEUE_LEN", 32);
+    let task_stack = buffer_size("TASK_STACK_SIZE", 512);
+    let stack_reserve = buffer_size("STACK_RESERVE", 1024);
+
+    // Every static ends up in .noinit, .data or .bss, so the linker knows
+    // the real total once it has placed them: have it check __bss_end
+    // against the stack reserve. The firmware prints the same total at boot
+    // (diagnostics::memtest::static_ram_bytes). Passed as an input file,
+    // the script adds to the default one.
+    let ram_check = out_dir.join("ram_budget.ld");
+    fs::write(
+        &ram_check,
+        format!(
+            "ASSERT(__bss_end + {} <= 0x{:X}, \"static RAM leaves less than STACK_RESERVE ({}B) for the stack\");\n",
+            stack_reserve, SRAM_END, stack_reserve
+        ),
+    )
+    .expect("writing ram_budget.ld");
+    println!("cargo:rustc-link-arg={}", ram_check.display());
 
     // Debug vs Release configurations
     if env::var("PROFILE").unwrap() == "debug" {
@@ -30,9 +76,31 @@ fn main() {
     }
 
     // Output helpful build information
-    println!("cargo:warning=Building for ATmega128 at 16MHz");
+    println!("cargo:warning=Building for ATmega128 at {}Hz", freq);
     println!("cargo:warning=Output directory: {}", out_dir.display());
+    println!(
+        "cargo:warning=Buffers: UART 4x{}B, transport {}B rx + {}B tx, log {} entries, {} events, {}B task stacks",
+        uart, transport_rx, transport_tx, log_entries, events, task_stack
+    );
+    println!(
+        "cargo:warning=Static RAM checked at link time: at most {}B of {}B, {}B stack reserve",
+        (SRAM_END - SRAM_START) as usize - stack_reserve,
+        SRAM_END - SRAM_START,
+        stack_reserve
+    );
 
     // TODO: Add more conditional compilation flags as needed
     // TODO: Generate linker script if custom memory layout needed
-}
\ No newline at end of file
+}
+
+/// Size from the build environment, `default` unless overridden. Range
+/// checks happen in config.rs where the compiler reports them.
+fn buffer_size(var: &str, default: usize) -> usize {
+    println!("cargo:rerun-if-env-changed={}", var);
+    let size = match env::var(var) {
+        Ok(value) => value.parse().unwrap_or_else(|_| panic!("{} must be a plain number", var)),
+        Err(_) => default,
+    };
+    println!("cargo:rustc-env={}={}", var, size);
+    size
+}
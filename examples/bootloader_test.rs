@@ -4,7 +4,7 @@
 use panic_halt as _;
 use atmega128_firmware::{
     bootloader::Bootloader,
-    hal::{Flash, Uart, Spi},
+    hal::{board, Flash, Uart, Spi},
     drivers::SerialConsole,
 };
 
@@ -16,7 +16,7 @@ fn main() -> ! {
     let uart = Uart::new();
     let spi = Spi::new();
     
-    let flash = match Flash::new(spi, 0, 1, 2) {
+    let flash = match Flash::new(spi, board::flash_cs()) {
         Ok(flash) => flash,
         Err(_) => {
             console.write_line("Failed to initialize Flash!");
@@ -4,7 +4,7 @@
 use panic_halt as _;
 use atmega128_firmware::{
     drivers::{Calibration, Mpu6050, SerialConsole},
-    hal::{Spi, Twi, TwiSpeed, delay_ms},
+    hal::{board, Spi, Twi, TwiSpeed, delay_ms},
 };
 
 #[avr_device::entry]
@@ -24,7 +24,7 @@ fn main() -> ! {
     };
     
     let spi = Spi::new();
-    let flash = match Flash::new(spi, 0, 1, 2) {
+    let flash = match Flash::new(spi, board::flash_cs()) {
         Ok(flash) => flash,
         Err(_) => {
             console.write_line("Failed to initialize Flash!");
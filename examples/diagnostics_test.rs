@@ -6,7 +6,7 @@ use atmega128_firmware::{
     diagnostics::{Diagnostics, ErrorCode},
     logger::Logger,
     drivers::{Flash, SerialConsole},
-    hal::{Spi, delay_ms},
+    hal::{board, Adc, AdcArbiter, Spi, delay_ms},
 };
 
 #[avr_device::entry]
@@ -15,7 +15,7 @@ fn main() -> ! {
     console.write_line("Starting diagnostics test...");
     
     let spi = Spi::new();
-    let flash = match Flash::new(spi, 0, 1, 2) {
+    let flash = match Flash::new(spi, board::flash_cs()) {
         Ok(flash) => flash,
         Err(_) => {
             console.write_line("Failed to initialize Flash!");
@@ -25,9 +25,10 @@ fn main() -> ! {
     
     let logger = Logger::new(flash);
     let mut diagnostics = Diagnostics::new(logger);
+    let mut adc = AdcArbiter::new(Adc::new());
     
     console.write_line("Running system diagnostics...");
-    match diagnostics.run_diagnostics() {
+    match diagnostics.run_diagnostics(&mut adc) {
         Ok(_) => console.write_line("All diagnostics passed!"),
         Err(error) => {
             console.write_str("Diagnostic error: ");
@@ -4,19 +4,15 @@
 use panic_halt as _;
 use atmega128_firmware::{
     drivers::{Flash, FlashError, SerialConsole},
-    hal::{Spi, SpiMode},
+    hal::{board, Spi, SpiMode},
 };
 
-const FLASH_CS_PIN: u8 = 0;
-const FLASH_WP_PIN: u8 = 1;
-const FLASH_HOLD_PIN: u8 = 2;
-
 #[avr_device::entry]
 fn main() -> ! {
     let mut console = SerialConsole::new();
     let spi = Spi::new();
     
-    let mut flash = match Flash::new(spi, FLASH_CS_PIN, FLASH_WP_PIN, FLASH_HOLD_PIN) {
+    let mut flash = match Flash::new(spi, board::flash_cs()) {
         Ok(flash) => flash,
         Err(_) => {
             console.write_line("Failed to initialize flash!");
@@ -5,7 +5,7 @@ use panic_halt as _;
 use atmega128_firmware::{
     logger::{Logger, LogEntry},
     drivers::{Flash, SerialConsole, Mpu6050},
-    hal::{Spi, Twi, TwiSpeed, delay_ms},
+    hal::{board, Spi, Twi, TwiSpeed, delay_ms},
 };
 
 #[avr_device::entry]
@@ -14,7 +14,7 @@ fn main() -> ! {
     console.write_line("Initializing data logger...");
     
     let spi = Spi::new();
-    let flash = match Flash::new(spi, 0, 1, 2) {
+    let flash = match Flash::new(spi, board::flash_cs()) {
         Ok(flash) => flash,
         Err(_) => {
             console.write_line("Failed to initialize Flash!");
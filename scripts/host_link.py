@@ -0,0 +1,303 @@
+#!/usr/bin/env python3
+"""Reference host link client for the ATmega128 firmware.
+
+Usage:
+    ./host_link.py [--port /dev/ttyUSB0] [--baud 9600] info
+    ./host_link.py telemetry --period 100
+    ./host_link.py update firmware.bin
+    ./host_link.py reset --reason update --delay 10
+    ./host_link.py records
+    ./host_link.py get 1 1 [index]
+
+Frame format (both directions):
+    0x55 0xAA <command> <length> <payload...> <checksum> 0x0A
+where checksum is the bitwise NOT of the byte sum of everything before it.
+Channel packets (command 0x0C) carry <channel> <data...>; console text
+arrives on channel 0 and is printed to stderr.
+
+Requires pyserial (pip install pyserial).
+"""
+
+import argparse
+import struct
+import sys
+
+import serial
+
+HOST_LINK_VERSION = 3
+
+CMD_GET_DATA = 0x04
+CMD_RESET = 0x05
+CMD_HOST_LINK = 0x0B
+CMD_CHANNEL = 0x0C
+CMD_TELEMETRY = 0x15
+
+CHANNEL_CONSOLE = 0x00
+CHANNEL_TELEMETRY = 0x01
+
+OP_HELLO = 0x01
+OP_LIST_COMMANDS = 0x02
+OP_GET_CAPABILITIES = 0x03
+OP_TELEMETRY = 0x04
+OP_ENTER_BOOTLOADER = 0x05
+
+# Command::GetData ops and record types, see src/protocol/records.rs
+DATA_LIST = 0x01
+DATA_READ = 0x02
+RECORD_TYPES = {
+    0: "raw",
+    1: "system",
+    2: "imu",
+    3: "adc",
+    4: "task",
+    5: "error",
+}
+
+# Command::Reset ops and reasons, see src/shutdown/reset.rs
+RESET_NOW = 0x01
+RESET_DELAYED = 0x02
+RESET_CANCEL = 0x03
+RESET_STATUS = 0x04
+RESET_REASONS = {
+    "unspecified": 0,
+    "maintenance": 1,
+    "update": 2,
+    "fault-recovery": 3,
+}
+
+CAPABILITIES = {
+    0: "atmega128",
+    1: "debug",
+    2: "release",
+    3: "dmx",
+    4: "noise-injection",
+    5: "fault-injection",
+}
+
+# Board revision byte of the hello reply, see src/hal/board_id.rs
+BOARD_REVISIONS = {
+    1: "A",
+    2: "B",
+    3: "C",
+}
+
+COMMAND_NAMES = {
+    0x01: "Ping",
+    0x02: "GetStatus",
+    0x03: "SetConfig",
+    0x04: "GetData",
+    0x05: "Reset",
+    0x06: "UpdateFirmware",
+    0x07: "Debug",
+    0x08: "Cron",
+    0x09: "Watch",
+    0x0A: "Vibration",
+    0x0B: "HostLink",
+    0x0C: "Channel",
+    0x0D: "Safety",
+    0x0E: "Heartbeat",
+    0x0F: "RunSelfTest",
+    0x10: "SamplingPlan",
+    0x11: "Burst",
+    0x12: "PowerProfile",
+    0x13: "Hardening",
+    0x14: "Pipeline",
+    0x15: "Telemetry",
+}
+
+# Bootloader constants, see src/bootloader/mod.rs
+BOOT_MAGIC = 0xB007F11E
+BOOT_PAGE_SIZE = 256
+BOOT_APP_END = 0x1E000
+
+
+def checksum(data):
+    return ~sum(data) & 0xFF
+
+
+class HostLink:
+    def __init__(self, port, baud):
+        self.serial = serial.Serial(port, baud, timeout=1.0)
+
+    def send(self, command, payload=b""):
+        frame = bytes([0x55, 0xAA, command, len(payload)]) + payload
+        self.serial.write(frame + bytes([checksum(frame), 0x0A]))
+
+    def receive(self):
+        """Next packet, with console channel text printed on the way"""
+        while True:
+            command, payload = self.receive_raw()
+            if command == CMD_CHANNEL and payload[:1] == bytes([CHANNEL_CONSOLE]):
+                sys.stderr.write(payload[1:].decode("ascii", "replace"))
+                continue
+            return command, payload
+
+    def receive_raw(self):
+        while True:
+            if self.serial.read(1) != b"\x55":
+                continue
+            if self.serial.read(1) != b"\xAA":
+                continue
+            command, length = self.serial.read(2)
+            payload = self.serial.read(length)
+            check, end = self.serial.read(2)
+            frame = bytes([0x55, 0xAA, command, length]) + payload
+            if end != 0x0A or check != checksum(frame):
+                raise IOError("corrupt frame")
+            return command, payload
+
+    def request(self, op, args=b""):
+        self.send(CMD_HOST_LINK, bytes([op]) + args)
+        command, payload = self.receive()
+        if command != CMD_HOST_LINK:
+            raise IOError("unexpected reply 0x%02X" % command)
+        return payload
+
+    def hello(self):
+        reply = self.request(OP_HELLO)
+        version, major, minor, patch, max_payload = reply[:5]
+        if version != HOST_LINK_VERSION:
+            print("warning: firmware speaks host link v%d, client v%d"
+                  % (version, HOST_LINK_VERSION), file=sys.stderr)
+        board = BOARD_REVISIONS.get(reply[5], "unknown") if len(reply) > 5 else "unknown"
+        return version, "%d.%d.%d" % (major, minor, patch), max_payload, board
+
+    def commands(self):
+        return list(self.request(OP_LIST_COMMANDS))
+
+    def capabilities(self):
+        (bits,) = struct.unpack("<H", self.request(OP_GET_CAPABILITIES))
+        return [name for bit, name in CAPABILITIES.items() if bits & (1 << bit)]
+
+    def telemetry(self, period_ms):
+        self.request(OP_TELEMETRY, struct.pack("<H", period_ms))
+        while True:
+            command, payload = self.receive()
+            if command == CMD_TELEMETRY:
+                yield payload
+            elif command == CMD_CHANNEL and payload[:1] == bytes([CHANNEL_TELEMETRY]):
+                yield payload[1:]
+
+    def records(self):
+        """(subsystem, record, type, count, name) of every record offered"""
+        records = []
+        while True:
+            self.send(CMD_GET_DATA, bytes([DATA_LIST, len(records)]))
+            command, payload = self.receive()
+            if command != CMD_GET_DATA:
+                raise IOError("unexpected reply 0x%02X" % command)
+            total, pos = payload[0], 2
+            while pos < len(payload):
+                subsystem, record, kind, count, name_len = payload[pos:pos + 5]
+                name = payload[pos + 5:pos + 5 + name_len].decode("ascii", "replace")
+                records.append((subsystem, record, RECORD_TYPES.get(kind, "?"), count, name))
+                pos += 5 + name_len
+            if len(records) >= total or pos == 2:
+                return records
+
+    def read_record(self, subsystem, record, index=0):
+        """Returns (type, payload)"""
+        self.send(CMD_GET_DATA, bytes([DATA_READ, subsystem, record, index]))
+        command, payload = self.receive()
+        if command != CMD_GET_DATA:
+            raise IOError("unexpected reply 0x%02X" % command)
+        return RECORD_TYPES.get(payload[3], "?"), payload[4:]
+
+    def reset(self, op, args=b""):
+        """Returns (pending, reason, seconds left, last reason, reset count)"""
+        self.send(CMD_RESET, bytes([op]) + args)
+        command, payload = self.receive()
+        if command != CMD_RESET:
+            raise IOError("unexpected reply 0x%02X" % command)
+        return struct.unpack("<BBHBH", payload)
+
+    def update_firmware(self, image):
+        self.request(OP_ENTER_BOOTLOADER)
+
+        self.serial.write(b"\x7F")
+        if self.serial.read(1) != b"\x55":
+            raise IOError("bootloader did not answer")
+
+        # The bootloader programs every page up to its own start address
+        image = image.ljust(BOOT_APP_END, b"\xFF")
+        header = struct.pack("<IIII", BOOT_MAGIC, 0, len(image), 0)
+        self.serial.write(header)
+        if self.serial.read(1) != b"\xAA":
+            raise IOError("header rejected")
+
+        for offset in range(0, len(image), BOOT_PAGE_SIZE):
+            self.serial.write(image[offset:offset + BOOT_PAGE_SIZE])
+            if self.serial.read(1) != b"\xAC":
+                raise IOError("page at 0x%05X failed" % offset)
+
+        if self.serial.read(1) != b"\xCC":
+            raise IOError("verification failed")
+        return struct.unpack(">I", self.serial.read(4))[0]
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("--port", default="/dev/ttyUSB0")
+    parser.add_argument("--baud", type=int, default=9600)
+    sub = parser.add_subparsers(dest="action", required=True)
+    sub.add_parser("info")
+    telemetry = sub.add_parser("telemetry")
+    telemetry.add_argument("--period", type=int, default=100)
+    update = sub.add_parser("update")
+    update.add_argument("image")
+    reset = sub.add_parser("reset")
+    reset.add_argument("--reason", choices=RESET_REASONS, default="maintenance")
+    reset.add_argument("--delay", type=int, default=0, help="seconds")
+    reset.add_argument("--cancel", action="store_true")
+    reset.add_argument("--status", action="store_true")
+    sub.add_parser("records")
+    get = sub.add_parser("get")
+    get.add_argument("subsystem", type=lambda s: int(s, 0))
+    get.add_argument("record", type=lambda s: int(s, 0))
+    get.add_argument("index", type=int, nargs="?", default=0)
+    args = parser.parse_args()
+
+    link = HostLink(args.port, args.baud)
+
+    if args.action == "info":
+        version, firmware, max_payload, board = link.hello()
+        print("host link v%d, firmware %s, max payload %d, board rev %s"
+              % (version, firmware, max_payload, board))
+        print("capabilities:", ", ".join(link.capabilities()) or "none")
+        for command in link.commands():
+            print("  0x%02X %s" % (command, COMMAND_NAMES.get(command, "?")))
+    elif args.action == "telemetry":
+        try:
+            for payload in link.telemetry(args.period):
+                print(payload.hex())
+        except KeyboardInterrupt:
+            link.request(OP_TELEMETRY, struct.pack("<H", 0))
+    elif args.action == "reset":
+        reason = RESET_REASONS[args.reason]
+        if args.status:
+            status = link.reset(RESET_STATUS)
+        elif args.cancel:
+            status = link.reset(RESET_CANCEL)
+        elif args.delay:
+            status = link.reset(RESET_DELAYED, struct.pack("<BH", reason, args.delay))
+        else:
+            status = link.reset(RESET_NOW, bytes([reason]))
+        pending, _, left, last, count = status
+        names = {v: k for k, v in RESET_REASONS.items()}
+        print("pending: %s" % ("in %ds" % left if pending else "no"))
+        print("last host reset: %s, %d so far" % (names.get(last, "none"), count))
+    elif args.action == "records":
+        for subsystem, record, kind, count, name in link.records():
+            instances = " x%d" % count if count > 1 else ""
+            print("  %d.%d %-12s %s%s" % (subsystem, record, name, kind, instances))
+    elif args.action == "get":
+        kind, payload = link.read_record(args.subsystem, args.record, args.index)
+        print("%s: %s" % (kind, payload.hex()))
+    elif args.action == "update":
+        with open(args.image, "rb") as f:
+            crc = link.update_firmware(f.read())
+        print("done, flash crc32 0x%08X" % crc)
+
+
+if __name__ == "__main__":
+    main()
@@ -3,13 +3,22 @@
 
 #![no_std]
 
-use crate::drivers::{LedMatrix, SerialConsole, ButtonHandler, ButtonEvent};
-use crate::hal::{Adc, AdcChannel};
+pub mod cron;
+pub mod data_logger;
+pub mod gcode;
+pub mod pid_tune;
+pub mod speed_governor;
+
+use crate::drivers::{Button, LedMatrix, SerialConsole, ButtonHandler, ButtonEvent};
+use crate::hal::{AdcArbiter, AdcChannel, AdcReference};
+use crate::pgm_str;
 
 /// Main application state and logic
 pub struct Application {
     led_pattern: u8,
     adc_value: u16,
+    /// Button presses not yet announced on the console, one bit per button
+    pressed: u8,
 }
 
 impl Application {
@@ -18,21 +27,22 @@ impl Application {
         Self {
             led_pattern: 0,
             adc_value: 0,
+            pressed: 0,
         }
     }
 
-    /// Update application state
+    /// Update application state. Console output is left to
+    /// `write_console`, which the main loop runs as `Low` work.
     pub fn update(&mut self, 
         leds: &mut LedMatrix,
-        console: &mut SerialConsole,
         buttons: &mut ButtonHandler,
-        adc: &mut Adc
+        adc: &mut AdcArbiter
     ) {
         // Handle button events
         if let Some(event) = buttons.get_event() {
             match event {
                 ButtonEvent::Pressed(button) => {
-                    self.handle_button_press(button, console);
+                    self.pressed |= 1 << button as u8;
                 }
                 ButtonEvent::Released(_) => {}
             }
@@ -43,22 +53,32 @@ impl Application {
         self.led_pattern = self.led_pattern.wrapping_add(1);
 
         // Read ADC periodically
-        self.adc_value = adc.read_channel(AdcChannel::ADC0);
+        self.adc_value = adc.convert_blocking(AdcChannel::Adc0, AdcReference::Avcc);
+    }
+
+    /// Announce the button presses since the last call
+    pub fn write_console(&mut self, console: &mut SerialConsole) {
+        for button in [Button::Button0, Button::Button1, Button::Button2, Button::Button3] {
+            if self.pressed & (1 << button as u8) != 0 {
+                self.handle_button_press(button, console);
+            }
+        }
+        self.pressed = 0;
     }
 
     fn handle_button_press(&mut self, button: crate::drivers::Button, console: &mut SerialConsole) {
         match button {
+            crate::drivers::Button::Button0 => {
+                console.write_pgm_line(pgm_str!("Button 1 pressed!"));
+            }
             crate::drivers::Button::Button1 => {
-                console.write_line("Button 1 pressed!");
+                console.write_pgm_line(pgm_str!("Button 2 pressed!"));
             }
             crate::drivers::Button::Button2 => {
-                console.write_line("Button 2 pressed!");
+                console.write_pgm_line(pgm_str!("Button 3 pressed!"));
             }
             crate::drivers::Button::Button3 => {
-                console.write_line("Button 3 pressed!");
-            }
-            crate::drivers::Button::Button4 => {
-                console.write_line("Button 4 pressed!");
+                console.write_pgm_line(pgm_str!("Button 4 pressed!"));
             }
         }
     }
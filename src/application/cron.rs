@@ -0,0 +1,307 @@
+//! Programmable alarm scheduler
+//!
+//! Runs simple actions either at a fixed wall-clock time of day or at a
+//! periodic interval of system ticks. The schedule table lives in RAM, is
+//! persisted to its own sector of the external flash and can be edited by the
+//! host through `Command::Cron`.
+
+#![no_std]
+
+use crate::config::FLASH_CRON;
+use crate::diagnostics::flash_audit::{self, AuditRegion};
+use crate::drivers::flash::Flash;
+use crate::protocol::{ProtocolError, Result};
+
+const MAX_ENTRIES: usize = 8;
+const ENTRY_SIZE: usize = 8;
+const HEADER_SIZE: usize = 3;
+// 0xC207 tables were packed without their slot numbers
+const CRON_MAGIC: u16 = 0xC208;
+const SECONDS_PER_DAY: u32 = 86_400;
+
+// Protocol sub-commands carried in the first payload byte of Command::Cron
+const OP_LIST: u8 = 0x01;
+const OP_SET: u8 = 0x02;
+const OP_CLEAR: u8 = 0x03;
+const OP_SAVE: u8 = 0x04;
+const OP_LOAD: u8 = 0x05;
+
+/// Actions that can be scheduled
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum CronAction {
+    LogSnapshot = 0,
+    SensorBurst = 1,
+    RelayToggle = 2,
+}
+
+impl CronAction {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(CronAction::LogSnapshot),
+            1 => Some(CronAction::SensorBurst),
+            2 => Some(CronAction::RelayToggle),
+            _ => None,
+        }
+    }
+}
+
+/// When a schedule entry fires
+#[derive(Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// Every N system ticks (1 tick = 1ms), counted from the first poll
+    Interval(u32),
+    /// Once a day at the given second since midnight. An entry created or
+    /// loaded after that time first fires the next day.
+    DailyAt(u32),
+}
+
+/// Single schedule table entry
+#[derive(Clone, Copy)]
+pub struct CronEntry {
+    pub trigger: Trigger,
+    pub action: CronAction,
+    pub arg: u8,
+    /// Tick of the last run, `None` until the first poll
+    last_run: Option<u32>,
+    /// Armed once the clock has been seen before `at`
+    armed: bool,
+}
+
+impl CronEntry {
+    pub fn new(trigger: Trigger, action: CronAction, arg: u8) -> Self {
+        Self {
+            trigger,
+            action,
+            arg,
+            last_run: None,
+            armed: false,
+        }
+    }
+
+    fn encode(&self, out: &mut [u8]) {
+        let (kind, value) = match self.trigger {
+            Trigger::Interval(ticks) => (0u8, ticks),
+            Trigger::DailyAt(seconds) => (1u8, seconds),
+        };
+        out[0] = kind;
+        out[1..5].copy_from_slice(&value.to_le_bytes());
+        out[5] = self.action as u8;
+        out[6] = self.arg;
+        // The slot number in flash, reserved on the wire
+        out[7] = 0;
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let value = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+        let trigger = match data[0] {
+            0 if value > 0 => Trigger::Interval(value),
+            1 if value < SECONDS_PER_DAY => Trigger::DailyAt(value),
+            _ => return None,
+        };
+        let action = CronAction::from_u8(data[5])?;
+        Some(Self::new(trigger, action, data[6]))
+    }
+}
+
+/// Callback invoked when an entry fires
+pub type CronHandler = fn(CronAction, u8);
+
+/// Alarm/interval scheduler
+pub struct Cron {
+    entries: [Option<CronEntry>; MAX_ENTRIES],
+    handler: Option<CronHandler>,
+}
+
+impl Cron {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; MAX_ENTRIES],
+            handler: None,
+        }
+    }
+
+    pub fn set_handler(&mut self, handler: CronHandler) {
+        self.handler = Some(handler);
+    }
+
+    /// Place an entry in the given slot, replacing whatever was there
+    pub fn set_entry(&mut self, slot: usize, entry: CronEntry) -> Result<()> {
+        if slot >= MAX_ENTRIES {
+            return Err(ProtocolError::InvalidPacket);
+        }
+        self.entries[slot] = Some(entry);
+        Ok(())
+    }
+
+    pub fn clear_entry(&mut self, slot: usize) -> Result<()> {
+        if slot >= MAX_ENTRIES {
+            return Err(ProtocolError::InvalidPacket);
+        }
+        self.entries[slot] = None;
+        Ok(())
+    }
+
+    pub fn get_entry(&self, slot: usize) -> Option<CronEntry> {
+        self.entries.get(slot).copied().flatten()
+    }
+
+    /// Check all entries and run the ones that are due.
+    ///
+    /// `ticks` is the free running system tick counter. `time_of_day` is the
+    /// current wall-clock second since midnight, or `None` if no clock source
+    /// is available yet, in which case daily entries are skipped.
+    pub fn poll(&mut self, ticks: u32, time_of_day: Option<u32>) {
+        for slot in self.entries.iter_mut() {
+            let entry = match slot {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            let due = match entry.trigger {
+                Trigger::Interval(period) => match entry.last_run {
+                    // wrapping_sub keeps this correct across tick rollover
+                    Some(last) if ticks.wrapping_sub(last) < period => false,
+                    Some(_) => {
+                        entry.last_run = Some(ticks);
+                        true
+                    }
+                    // The first period starts now
+                    None => {
+                        entry.last_run = Some(ticks);
+                        false
+                    }
+                },
+                Trigger::DailyAt(at) => match time_of_day {
+                    Some(now) if now >= at && entry.armed => {
+                        entry.armed = false;
+                        true
+                    }
+                    // Arm on the first poll before `at` and re-arm once the
+                    // clock wraps past midnight
+                    Some(now) if now < at => {
+                        entry.armed = true;
+                        false
+                    }
+                    _ => false,
+                },
+            };
+
+            if due {
+                if let Some(handler) = self.handler {
+                    handler(entry.action, entry.arg);
+                }
+            }
+        }
+    }
+
+    /// Store the schedule table in its flash sector, each entry with its
+    /// slot number in the reserved last byte
+    pub fn save(&self, flash: &mut Flash) -> core::result::Result<(), ()> {
+        let mut buffer = [0u8; HEADER_SIZE + MAX_ENTRIES * ENTRY_SIZE];
+        buffer[0..2].copy_from_slice(&CRON_MAGIC.to_le_bytes());
+
+        let mut count = 0;
+        for (slot, entry) in self.entries.iter().enumerate() {
+            if let Some(entry) = entry {
+                let offset = HEADER_SIZE + count * ENTRY_SIZE;
+                entry.encode(&mut buffer[offset..offset + ENTRY_SIZE]);
+                buffer[offset + ENTRY_SIZE - 1] = slot as u8;
+                count += 1;
+            }
+        }
+        buffer[2] = count as u8;
+
+        flash_audit::mark_written(AuditRegion::Cron);
+        flash.erase_sector(FLASH_CRON).map_err(|_| ())?;
+        flash
+            .write(FLASH_CRON, &buffer[..HEADER_SIZE + count * ENTRY_SIZE])
+            .map_err(|_| ())?;
+        Ok(())
+    }
+
+    /// Restore the schedule table from flash, each entry to the slot it was
+    /// saved from. An erased or foreign sector, or one naming a slot out of
+    /// range or twice, leaves the current table untouched.
+    pub fn load(&mut self, flash: &mut Flash) -> core::result::Result<(), ()> {
+        let mut buffer = [0u8; HEADER_SIZE + MAX_ENTRIES * ENTRY_SIZE];
+        flash.read(FLASH_CRON, &mut buffer).map_err(|_| ())?;
+
+        if u16::from_le_bytes([buffer[0], buffer[1]]) != CRON_MAGIC {
+            return Err(());
+        }
+
+        let count = (buffer[2] as usize).min(MAX_ENTRIES);
+        let mut entries = [None; MAX_ENTRIES];
+        let mut seen = 0u8;
+        for i in 0..count {
+            let offset = HEADER_SIZE + i * ENTRY_SIZE;
+            let slot = buffer[offset + ENTRY_SIZE - 1] as usize;
+            if slot >= MAX_ENTRIES || seen & (1 << slot) != 0 {
+                return Err(());
+            }
+            seen |= 1 << slot;
+            entries[slot] = CronEntry::decode(&buffer[offset..offset + ENTRY_SIZE]);
+        }
+        self.entries = entries;
+        Ok(())
+    }
+
+    /// Handle a `Command::Cron` payload and write the reply into `response`.
+    ///
+    /// Payload layout: `[op, slot, entry(8 bytes)]`, where the entry is only
+    /// present for `OP_SET`. Returns the number of response bytes written.
+    pub fn handle_command(
+        &mut self,
+        data: &[u8],
+        flash: &mut Flash,
+        response: &mut [u8],
+    ) -> Result<usize> {
+        let op = *data.first().ok_or(ProtocolError::InvalidPacket)?;
+
+        match op {
+            OP_LIST => {
+                let mut len = 0;
+                for (slot, entry) in self.entries.iter().enumerate() {
+                    if let Some(entry) = entry {
+                        if len + 1 + ENTRY_SIZE > response.len() {
+                            return Err(ProtocolError::BufferOverflow);
+                        }
+                        response[len] = slot as u8;
+                        entry.encode(&mut response[len + 1..len + 1 + ENTRY_SIZE]);
+                        len += 1 + ENTRY_SIZE;
+                    }
+                }
+                Ok(len)
+            }
+            OP_SET => {
+                if data.len() != 2 + ENTRY_SIZE {
+                    return Err(ProtocolError::InvalidPacket);
+                }
+                let entry = CronEntry::decode(&data[2..]).ok_or(ProtocolError::InvalidPacket)?;
+                self.set_entry(data[1] as usize, entry)?;
+                Ok(0)
+            }
+            OP_CLEAR => {
+                let slot = *data.get(1).ok_or(ProtocolError::InvalidPacket)?;
+                self.clear_entry(slot as usize)?;
+                Ok(0)
+            }
+            OP_SAVE => {
+                self.save(flash).map_err(|_| ProtocolError::TransportError)?;
+                Ok(0)
+            }
+            OP_LOAD => {
+                self.load(flash).map_err(|_| ProtocolError::TransportError)?;
+                Ok(0)
+            }
+            _ => Err(ProtocolError::InvalidCommand),
+        }
+    }
+}
+
+impl Default for Cron {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,318 @@
+//! Low-power periodic sampling profile ("data logger mode")
+//!
+//! Turns the board into a battery-powered environmental logger: the MCU
+//! spends its time in PowerSave, wakes on the asynchronous timer, samples the
+//! selected sensors every `period_s` seconds, appends fixed-size records to
+//! the external flash and goes back to sleep.
+//!
+//! The timer that keeps running in PowerSave is TC0 on the 32.768kHz
+//! crystal, see `hal::rtc_soft`. Records carry its wall-clock seconds. The
+//! system tick uses TC0 as well, so this mode replaces the scheduler rather
+//! than running beside it.
+//!
+//! Awake and asleep time are measured from the timer itself and combined
+//! with a current profile (or a measured active current from a probe hook)
+//! into a running charge budget.
+#![no_std]
+
+use crate::config::{FLASH_DATALOG_END, FLASH_DATALOG_START, FLASH_SECTOR_SIZE};
+use crate::drivers::flash::Flash;
+use crate::hal::rtc_soft::{self, counts_to_ms, COUNTS_PER_SECOND};
+use crate::hal::{Power, SleepMode, Watchdog};
+
+const MAX_SENSORS: usize = 8;
+
+/// Record layout: `[marker, sensor, len, 0, seconds(u32 LE), data(8)]`
+pub const RECORD_SIZE: usize = 16;
+pub const RECORD_DATA: usize = 8;
+const RECORD_MARKER: u8 = 0xA5;
+const ERASED: u8 = 0xFF;
+
+#[derive(Debug)]
+pub enum DataLoggerError {
+    TooManySensors,
+    InvalidSensor,
+    Flash,
+}
+
+/// Fill `data` with one sample, returning the number of bytes used
+pub type SampleFn = fn(&mut [u8; RECORD_DATA]) -> Option<u8>;
+
+/// Measured active current in microamps, read once per sample cycle
+pub type CurrentProbe = fn() -> Option<u32>;
+
+/// Called after every sample cycle with the running budget
+pub type BudgetHook = fn(&BudgetReport);
+
+#[derive(Clone, Copy)]
+pub struct DataLoggerConfig {
+    /// Seconds between sample cycles
+    pub period_s: u16,
+    /// Bit mask of registered sensors to sample
+    pub sensors: u8,
+}
+
+impl Default for DataLoggerConfig {
+    fn default() -> Self {
+        Self {
+            period_s: 60,
+            sensors: 0xFF,
+        }
+    }
+}
+
+/// Board current draw used for the budget when nothing is measured
+#[derive(Clone, Copy)]
+pub struct CurrentProfile {
+    pub sleep_ua: u32,
+    pub active_ua: u32,
+}
+
+impl Default for CurrentProfile {
+    fn default() -> Self {
+        Self {
+            sleep_ua: 15,
+            active_ua: 12_000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct BudgetReport {
+    pub awake_ms: u32,
+    pub asleep_ms: u32,
+    /// Average current since the logger was started
+    pub average_ua: u32,
+    /// Charge drawn since the logger was started
+    pub charge_uah: u32,
+    pub records: u32,
+}
+
+#[derive(Clone, Copy)]
+struct LoggerSensor {
+    name: &'static str,
+    sample: SampleFn,
+}
+
+pub struct DataLogger {
+    config: DataLoggerConfig,
+    sensors: [Option<LoggerSensor>; MAX_SENSORS],
+    write_addr: u32,
+    profile: CurrentProfile,
+    probe: Option<CurrentProbe>,
+    hook: Option<BudgetHook>,
+    measured_active_ua: Option<u32>,
+    // Budget bookkeeping, in timer counts
+    wake_count: u32,
+    last_sample: u32,
+    awake_counts: u32,
+    asleep_counts: u32,
+    charge_ua_counts: u64,
+    records: u32,
+}
+
+impl DataLogger {
+    pub const fn new(config: DataLoggerConfig) -> Self {
+        Self {
+            config,
+            sensors: [None; MAX_SENSORS],
+            write_addr: FLASH_DATALOG_START,
+            profile: CurrentProfile {
+                sleep_ua: 15,
+                active_ua: 12_000,
+            },
+            probe: None,
+            hook: None,
+            measured_active_ua: None,
+            wake_count: 0,
+            last_sample: 0,
+            awake_counts: 0,
+            asleep_counts: 0,
+            charge_ua_counts: 0,
+            records: 0,
+        }
+    }
+
+    pub fn set_config(&mut self, config: DataLoggerConfig) {
+        self.config = config;
+    }
+
+    pub fn config(&self) -> DataLoggerConfig {
+        self.config
+    }
+
+    pub fn set_profile(&mut self, profile: CurrentProfile) {
+        self.profile = profile;
+    }
+
+    pub fn set_current_probe(&mut self, probe: CurrentProbe) {
+        self.probe = Some(probe);
+    }
+
+    pub fn set_budget_hook(&mut self, hook: BudgetHook) {
+        self.hook = Some(hook);
+    }
+
+    /// Add a sensor, returns its id (the bit in `DataLoggerConfig::sensors`)
+    pub fn register_sensor(&mut self, name: &'static str, sample: SampleFn) -> Result<usize, DataLoggerError> {
+        for (id, slot) in self.sensors.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some(LoggerSensor { name, sample });
+                return Ok(id);
+            }
+        }
+        Err(DataLoggerError::TooManySensors)
+    }
+
+    pub fn sensor_name(&self, id: usize) -> Option<&'static str> {
+        self.sensors.get(id).copied().flatten().map(|s| s.name)
+    }
+
+    /// Continue after the last record already in flash
+    pub fn resume(&mut self, flash: &mut Flash) -> Result<(), DataLoggerError> {
+        let mut addr = FLASH_DATALOG_START;
+        let mut header = [0u8; 1];
+        while addr < FLASH_DATALOG_END {
+            flash.read(addr, &mut header).map_err(|_| DataLoggerError::Flash)?;
+            if header[0] == ERASED {
+                break;
+            }
+            addr += RECORD_SIZE as u32;
+        }
+        self.write_addr = if addr >= FLASH_DATALOG_END { FLASH_DATALOG_START } else { addr };
+        Ok(())
+    }
+
+    /// Address the next record will be written to
+    pub fn write_address(&self) -> u32 {
+        self.write_addr
+    }
+
+    /// Start the crystal clock unless it already runs, it is the wake-up
+    pub fn start(&mut self) {
+        if !rtc_soft::is_running() {
+            rtc_soft::start();
+        }
+        self.wake_count = rtc_soft::counts();
+        self.last_sample = rtc_soft::uptime();
+    }
+
+    /// Sleep until the next timer wake-up and take samples when due
+    pub fn cycle(&mut self, power: &mut Power, watchdog: &mut Watchdog, flash: &mut Flash) -> Result<(), DataLoggerError> {
+        let _ = flash.power_down();
+        self.sleep(power);
+        watchdog.feed();
+
+        let uptime = rtc_soft::uptime();
+        if uptime.wrapping_sub(self.last_sample) < self.config.period_s as u32 {
+            return Ok(());
+        }
+        self.last_sample = uptime;
+
+        flash.release_power_down().map_err(|_| DataLoggerError::Flash)?;
+        let result = self.sample_all(rtc_soft::now(), flash);
+
+        if let Some(probe) = self.probe {
+            self.measured_active_ua = probe();
+        }
+        if let Some(hook) = self.hook {
+            hook(&self.report());
+        }
+        result
+    }
+
+    /// Run the logger forever
+    pub fn run(&mut self, power: &mut Power, watchdog: &mut Watchdog, flash: &mut Flash) -> ! {
+        self.start();
+        loop {
+            // A failed write is retried on the next cycle
+            let _ = self.cycle(power, watchdog, flash);
+        }
+    }
+
+    pub fn report(&self) -> BudgetReport {
+        let total = self.awake_counts + self.asleep_counts;
+        let average_ua = if total > 0 {
+            (self.charge_ua_counts / total as u64) as u32
+        } else {
+            0
+        };
+        BudgetReport {
+            awake_ms: counts_to_ms(self.awake_counts),
+            asleep_ms: counts_to_ms(self.asleep_counts),
+            average_ua,
+            charge_uah: (self.charge_ua_counts / (COUNTS_PER_SECOND as u64 * 3600)) as u32,
+            records: self.records,
+        }
+    }
+
+    fn sample_all(&mut self, seconds: u32, flash: &mut Flash) -> Result<(), DataLoggerError> {
+        for id in 0..MAX_SENSORS {
+            if self.config.sensors & (1 << id) == 0 {
+                continue;
+            }
+            let sensor = match self.sensors[id] {
+                Some(sensor) => sensor,
+                None => continue,
+            };
+            let mut data = [0u8; RECORD_DATA];
+            if let Some(len) = (sensor.sample)(&mut data) {
+                self.append(flash, id as u8, seconds, &data[..(len as usize).min(RECORD_DATA)])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, flash: &mut Flash, sensor: u8, seconds: u32, data: &[u8]) -> Result<(), DataLoggerError> {
+        if self.write_addr >= FLASH_DATALOG_END {
+            // Oldest data is overwritten once the region is full
+            self.write_addr = FLASH_DATALOG_START;
+        }
+        if self.write_addr % FLASH_SECTOR_SIZE == 0 {
+            flash.erase_sector(self.write_addr).map_err(|_| DataLoggerError::Flash)?;
+        }
+
+        let mut record = [0u8; RECORD_SIZE];
+        record[0] = RECORD_MARKER;
+        record[1] = sensor;
+        record[2] = data.len() as u8;
+        record[4..8].copy_from_slice(&seconds.to_le_bytes());
+        record[8..8 + data.len()].copy_from_slice(data);
+
+        flash.write(self.write_addr, &record).map_err(|_| DataLoggerError::Flash)?;
+        self.write_addr += RECORD_SIZE as u32;
+        self.records += 1;
+        Ok(())
+    }
+
+    fn sleep(&mut self, power: &mut Power) {
+        let before = rtc_soft::counts();
+        self.account(before.wrapping_sub(self.wake_count), self.active_ua());
+
+        rtc_soft::prepare_sleep();
+        power.enter_power_save();
+
+        let after = rtc_soft::counts();
+        // The system tick is stopped in this mode, residency comes from TC0
+        power.account(None, counts_to_ms(before.wrapping_sub(self.wake_count)));
+        power.account(Some(SleepMode::PowerSave), counts_to_ms(after.wrapping_sub(before)));
+        self.asleep_counts += after.wrapping_sub(before);
+        self.charge_ua_counts += after.wrapping_sub(before) as u64 * self.profile.sleep_ua as u64;
+        self.wake_count = after;
+    }
+
+    fn account(&mut self, awake: u32, current_ua: u32) {
+        self.awake_counts += awake;
+        self.charge_ua_counts += awake as u64 * current_ua as u64;
+    }
+
+    fn active_ua(&self) -> u32 {
+        self.measured_active_ua.unwrap_or(self.profile.active_ua)
+    }
+}
+
+impl Default for DataLogger {
+    fn default() -> Self {
+        Self::new(DataLoggerConfig::default())
+    }
+}
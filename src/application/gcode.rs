@@ -0,0 +1,276 @@
+//! G-code subset for plotters and small CNC experiments
+//!
+//! ```text
+//! G0 X.. Y.. Z..        rapid move
+//! G1 X.. Y.. Z.. F..    move at feed rate (units/min, modal)
+//! G90 / G91             absolute / relative coordinates
+//! M3 [S..] / M5         spindle (or relay, pen, laser) on / off
+//! M114                  report position
+//! ```
+//!
+//! Line numbers (`N..`), `;` comments and `( )` comments are accepted and
+//! ignored. Every line is answered with `ok` or `error: <reason>` so a
+//! host streamer can send the next line as soon as the previous one is
+//! accepted. The interpreter only parses and keeps the modal state; moves
+//! and the spindle go to the `GcodeMachine`, which is where the axes
+//! (homing, soft limits, motion profiles) live.
+//!
+//! `Trajectory` is a `GcodeMachine` in millimetres, so moves queue up on
+//! the look-ahead planner and blend through their junctions. A full queue
+//! answers `error: busy`; the host sends the line again.
+#![no_std]
+
+use libm::roundf;
+
+use crate::control::trajectory::{Trajectory, TrajectoryError};
+use crate::drivers::SerialConsole;
+use crate::hal::uart::SerialPort;
+use crate::pgm_str;
+
+pub use crate::control::trajectory::AXES;
+const AXIS_LETTERS: [u8; AXES] = [b'X', b'Y', b'Z'];
+
+/// Feed rate until the first `F`, units per minute
+pub const DEFAULT_FEED: f32 = 600.0;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GcodeError {
+    /// Word that is not a letter followed by a number
+    BadWord,
+    /// G or M code outside the supported subset
+    Unsupported,
+    /// Two G or M codes on one line
+    Conflict,
+    /// Axis word without a move
+    NoMotion,
+    /// The machine refused, e.g. not homed or outside the soft limits
+    Refused,
+    /// The machine can't take the move yet, send it again
+    Busy,
+}
+
+impl GcodeError {
+    fn message(self) -> &'static str {
+        match self {
+            GcodeError::BadWord => "bad word",
+            GcodeError::Unsupported => "unsupported",
+            GcodeError::Conflict => "conflicting codes",
+            GcodeError::NoMotion => "axis words without G0/G1",
+            GcodeError::Refused => "refused",
+            GcodeError::Busy => "busy",
+        }
+    }
+}
+
+/// What the interpreter drives
+pub trait GcodeMachine {
+    /// Move to an absolute target in units, `feed` in units per minute or
+    /// `None` for a rapid
+    fn move_to(&mut self, target: [f32; AXES], feed: Option<f32>) -> Result<(), GcodeError>;
+    fn spindle(&mut self, on: bool, speed: Option<f32>);
+    fn position(&self) -> [f32; AXES];
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Statement {
+    Move {
+        rapid: bool,
+        axes: [Option<f32>; AXES],
+        feed: Option<f32>,
+    },
+    Absolute,
+    Relative,
+    SpindleOn(Option<f32>),
+    SpindleOff,
+    ReportPosition,
+    /// Blank line or only comments
+    Empty,
+}
+
+pub struct Gcode {
+    relative: bool,
+    feed: f32,
+    /// Last commanded target, relative moves add to it
+    target: Option<[f32; AXES]>,
+}
+
+impl Gcode {
+    pub const fn new() -> Self {
+        Self {
+            relative: false,
+            feed: DEFAULT_FEED,
+            target: None,
+        }
+    }
+
+    /// Execute a line and print the reply
+    pub fn process_line<S: SerialPort, M: GcodeMachine>(&mut self, line: &str, console: &mut SerialConsole<S>, machine: &mut M) {
+        match self.execute(line, machine) {
+            Ok(Statement::ReportPosition) => {
+                let position = machine.position();
+                for (axis, value) in position.iter().enumerate() {
+                    if axis > 0 {
+                        console.write_byte(b' ');
+                    }
+                    console.write_byte(AXIS_LETTERS[axis]);
+                    console.write_byte(b':');
+                    console.write_fixed(*value);
+                }
+                console.write_str("\r\n");
+                console.write_pgm_line(pgm_str!("ok"));
+            }
+            Ok(_) => console.write_pgm_line(pgm_str!("ok")),
+            Err(error) => {
+                console.write_str("error: ");
+                console.write_line(error.message());
+            }
+        }
+    }
+
+    /// Parse and run a line, returns what it was
+    pub fn execute<M: GcodeMachine>(&mut self, line: &str, machine: &mut M) -> Result<Statement, GcodeError> {
+        let statement = parse(line)?;
+        match statement {
+            Statement::Move { rapid, axes, feed } => {
+                if let Some(feed) = feed {
+                    self.feed = feed;
+                }
+                let mut target = match self.target {
+                    Some(target) => target,
+                    None => machine.position(),
+                };
+                for (axis, value) in axes.iter().enumerate() {
+                    if let Some(value) = *value {
+                        target[axis] = if self.relative { target[axis] + value } else { value };
+                    }
+                }
+                machine.move_to(target, if rapid { None } else { Some(self.feed) })?;
+                self.target = Some(target);
+            }
+            Statement::Absolute => self.relative = false,
+            Statement::Relative => self.relative = true,
+            Statement::SpindleOn(speed) => machine.spindle(true, speed),
+            Statement::SpindleOff => machine.spindle(false, None),
+            Statement::ReportPosition | Statement::Empty => {}
+        }
+        Ok(statement)
+    }
+
+    /// Forget the last target, e.g. after homing moved the axes
+    pub fn resync(&mut self) {
+        self.target = None;
+    }
+}
+
+impl Default for Gcode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Trajectory positions are micrometres
+const UM_PER_MM: f32 = 1000.0;
+
+impl<const N: usize> GcodeMachine for Trajectory<N> {
+    fn move_to(&mut self, target: [f32; AXES], feed: Option<f32>) -> Result<(), GcodeError> {
+        let mut um = [0i32; AXES];
+        for (um, mm) in um.iter_mut().zip(target) {
+            *um = roundf(mm * UM_PER_MM) as i32;
+        }
+        // mm/min to um/s; rapids run at the configured speed limit
+        let speed = feed.map_or(u32::MAX, |feed| (feed * UM_PER_MM / 60.0) as u32);
+        match self.push(um, speed) {
+            Ok(()) | Err(TrajectoryError::ZeroLength) => Ok(()),
+            Err(TrajectoryError::Full) => Err(GcodeError::Busy),
+        }
+    }
+
+    // The planner has no spindle; a machine that has one wraps it
+    fn spindle(&mut self, _on: bool, _speed: Option<f32>) {}
+
+    /// The current setpoint, not the end of the queue
+    fn position(&self) -> [f32; AXES] {
+        let mut mm = [0.0; AXES];
+        for (mm, um) in mm.iter_mut().zip(Trajectory::position(self)) {
+            *mm = um as f32 / UM_PER_MM;
+        }
+        mm
+    }
+}
+
+/// Parse one line without executing it
+pub fn parse(line: &str) -> Result<Statement, GcodeError> {
+    let mut code: Option<(u8, u16)> = None;
+    let mut axes = [None; AXES];
+    let mut feed = None;
+    let mut speed = None;
+
+    let bytes = line.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let letter = bytes[i].to_ascii_uppercase();
+        match letter {
+            b' ' | b'\t' | b'\r' | b'\n' => {
+                i += 1;
+                continue;
+            }
+            b';' => break,
+            b'(' => {
+                while i < bytes.len() && bytes[i] != b')' {
+                    i += 1;
+                }
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        let start = i + 1;
+        let mut end = start;
+        while end < bytes.len() && matches!(bytes[end], b'0'..=b'9' | b'.' | b'-' | b'+') {
+            end += 1;
+        }
+        let number = line.get(start..end).filter(|s| !s.is_empty()).ok_or(GcodeError::BadWord)?;
+        i = end;
+
+        match letter {
+            b'G' | b'M' => {
+                let value = number.parse::<u16>().map_err(|_| GcodeError::Unsupported)?;
+                if code.is_some() {
+                    return Err(GcodeError::Conflict);
+                }
+                code = Some((letter, value));
+            }
+            b'N' => {}
+            _ => {
+                let value = number.parse::<f32>().map_err(|_| GcodeError::BadWord)?;
+                match letter {
+                    b'F' if value > 0.0 => feed = Some(value),
+                    b'S' => speed = Some(value),
+                    _ => match AXIS_LETTERS.iter().position(|&a| a == letter) {
+                        Some(axis) => axes[axis] = Some(value),
+                        None => return Err(GcodeError::BadWord),
+                    },
+                }
+            }
+        }
+    }
+
+    let has_axes = axes.iter().any(|a| a.is_some());
+    let statement = match code {
+        Some((b'G', 0)) => Statement::Move { rapid: true, axes, feed },
+        Some((b'G', 1)) => Statement::Move { rapid: false, axes, feed },
+        Some((b'G', 90)) => Statement::Absolute,
+        Some((b'G', 91)) => Statement::Relative,
+        Some((b'M', 3)) => Statement::SpindleOn(speed),
+        Some((b'M', 5)) => Statement::SpindleOff,
+        Some((b'M', 114)) => Statement::ReportPosition,
+        Some(_) => return Err(GcodeError::Unsupported),
+        None if has_axes => return Err(GcodeError::NoMotion),
+        None => Statement::Empty,
+    };
+    if has_axes && !matches!(statement, Statement::Move { .. }) {
+        return Err(GcodeError::Conflict);
+    }
+    Ok(statement)
+}
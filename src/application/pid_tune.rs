@@ -0,0 +1,214 @@
+//! Run-time PID tuning from the serial console
+//!
+//! Controllers register their gains here under a short name. The console
+//! commands below edit them live and persist them to the internal EEPROM
+//! once they behave on the bench, in two copies (see `redundant_store`):
+//!
+//! ```text
+//! pid show
+//! pid set [loop] kp|ki|kd|min|max <value>
+//! pid save
+//! pid load
+//! ```
+//!
+//! When the loop name is omitted the first registered loop is used. The
+//! owner of each controller picks up changes with `take_update`.
+
+#![no_std]
+
+use crate::config::{EEPROM_PID_ADDR, EEPROM_PID_COPY_ADDR, EEPROM_PID_COPY_SIZE};
+use crate::drivers::redundant_store::{self, EepromCopies, StoreError};
+use crate::drivers::{PidConfig, SerialConsole};
+use crate::pgm_str;
+
+const MAX_LOOPS: usize = 4;
+// kp, ki, kd, output_min, output_max
+const RECORD_SIZE: usize = 5 * 4;
+const BLOB_SIZE: usize = MAX_LOOPS * RECORD_SIZE;
+
+struct PidLoop {
+    name: &'static str,
+    config: PidConfig,
+}
+
+pub struct PidTuner {
+    loops: [Option<PidLoop>; MAX_LOOPS],
+    dirty: u8,
+}
+
+impl PidTuner {
+    pub const fn new() -> Self {
+        Self {
+            loops: [None, None, None, None],
+            dirty: 0,
+        }
+    }
+
+    /// Register a controller's gains, returns the loop id
+    pub fn register(&mut self, name: &'static str, config: PidConfig) -> Result<usize, ()> {
+        for (id, slot) in self.loops.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some(PidLoop { name, config });
+                return Ok(id);
+            }
+        }
+        Err(())
+    }
+
+    /// Returns the new gains if the loop was changed since the last call
+    pub fn take_update(&mut self, id: usize) -> Option<PidConfig> {
+        if id >= MAX_LOOPS || self.dirty & (1 << id) == 0 {
+            return None;
+        }
+        self.dirty &= !(1 << id);
+        self.loops[id].as_ref().map(|l| l.config.clone())
+    }
+
+    /// Execute a console line, ignoring lines for other commands
+    pub fn process_line(&mut self, line: &str, console: &mut SerialConsole) {
+        let mut args = line.split_whitespace();
+        if args.next() != Some("pid") {
+            return;
+        }
+
+        match args.next() {
+            Some("show") => self.show(console),
+            Some("set") => {
+                let mut words = [""; 3];
+                let mut count = 0;
+                for word in args {
+                    if count == words.len() {
+                        count += 1;
+                        break;
+                    }
+                    words[count] = word;
+                    count += 1;
+                }
+
+                let (id, param, value) = match count {
+                    2 => (self.first_loop(), words[0], words[1]),
+                    3 => (self.find_loop(words[0]), words[1], words[2]),
+                    _ => (None, "", ""),
+                };
+
+                match (id, value.parse::<f32>()) {
+                    (Some(id), Ok(value)) => {
+                        if self.set_param(id, param, value).is_ok() {
+                            console.write_pgm_line(pgm_str!("ok"));
+                        } else {
+                            console.write_pgm_line(pgm_str!("unknown parameter"));
+                        }
+                    }
+                    (None, _) => console.write_pgm_line(pgm_str!("unknown loop")),
+                    (_, Err(_)) => console.write_pgm_line(pgm_str!("bad value")),
+                }
+            }
+            Some("save") => {
+                if self.save().is_ok() {
+                    console.write_pgm_line(pgm_str!("saved"));
+                } else {
+                    console.write_pgm_line(pgm_str!("save failed"));
+                }
+            }
+            Some("load") => {
+                if self.load().is_ok() {
+                    console.write_pgm_line(pgm_str!("loaded"));
+                } else {
+                    console.write_pgm_line(pgm_str!("no saved gains"));
+                }
+            }
+            _ => console.write_pgm_line(pgm_str!("usage: pid show|set [loop] <param> <value>|save|load")),
+        }
+    }
+
+    fn set_param(&mut self, id: usize, param: &str, value: f32) -> Result<(), ()> {
+        let pid = self.loops[id].as_mut().ok_or(())?;
+        match param {
+            "kp" => pid.config.kp = value,
+            "ki" => pid.config.ki = value,
+            "kd" => pid.config.kd = value,
+            "min" => pid.config.output_min = value,
+            "max" => pid.config.output_max = value,
+            _ => return Err(()),
+        }
+        self.dirty |= 1 << id;
+        Ok(())
+    }
+
+    fn show(&self, console: &mut SerialConsole) {
+        for pid in self.loops.iter().flatten() {
+            console.write_str(pid.name);
+            console.write_str(": kp=");
+            console.write_fixed(pid.config.kp);
+            console.write_str(" ki=");
+            console.write_fixed(pid.config.ki);
+            console.write_str(" kd=");
+            console.write_fixed(pid.config.kd);
+            console.write_str(" out=");
+            console.write_fixed(pid.config.output_min);
+            console.write_str("..");
+            console.write_fixed(pid.config.output_max);
+            console.write_str("\r\n");
+        }
+    }
+
+    fn first_loop(&self) -> Option<usize> {
+        self.loops.iter().position(|l| l.is_some())
+    }
+
+    fn find_loop(&self, name: &str) -> Option<usize> {
+        self.loops
+            .iter()
+            .position(|l| l.as_ref().map_or(false, |l| l.name == name))
+    }
+
+    /// Persist the gains of all registered loops to both EEPROM copies
+    pub fn save(&self) -> Result<(), StoreError> {
+        let mut blob = [0u8; BLOB_SIZE];
+        for (pid, record) in self.loops.iter().zip(blob.chunks_mut(RECORD_SIZE)) {
+            if let Some(pid) = pid {
+                let c = &pid.config;
+                for (i, value) in [c.kp, c.ki, c.kd, c.output_min, c.output_max].iter().enumerate() {
+                    record[i * 4..i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+        redundant_store::save(&mut pid_copies(), &blob).map(|_| ())
+    }
+
+    /// Restore gains saved by `save` from the newest valid copy. Loops are
+    /// matched by registration order, so register them in the same order
+    /// on every boot.
+    pub fn load(&mut self) -> Result<(), StoreError> {
+        let mut blob = [0u8; BLOB_SIZE];
+        let loaded = redundant_store::load(&mut pid_copies(), &mut blob)?;
+        if loaded.len != BLOB_SIZE {
+            return Err(StoreError::NoValidCopy);
+        }
+
+        for (id, (pid, record)) in self.loops.iter_mut().zip(blob.chunks(RECORD_SIZE)).enumerate() {
+            if let Some(pid) = pid {
+                let value = |i: usize| {
+                    f32::from_le_bytes([record[i * 4], record[i * 4 + 1], record[i * 4 + 2], record[i * 4 + 3]])
+                };
+                pid.config.kp = value(0);
+                pid.config.ki = value(1);
+                pid.config.kd = value(2);
+                pid.config.output_min = value(3);
+                pid.config.output_max = value(4);
+                self.dirty |= 1 << id;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn pid_copies() -> EepromCopies {
+    EepromCopies::new([EEPROM_PID_ADDR, EEPROM_PID_COPY_ADDR], EEPROM_PID_COPY_SIZE)
+}
+
+impl Default for PidTuner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,159 @@
+//! Vehicle speed governor
+//!
+//! Turns the cumulative pulse count of a wheel encoder or pulse counter
+//! into distance travelled and a smoothed speed, and caps the setpoints
+//! handed to a `MotorController` at a configured maximum speed. The
+//! motor loop is expected to run on speed feedback in the same units.
+//!
+//! Speed is a weighted moving average over the last `SPEED_WINDOWS`
+//! sample periods, newest weighted highest, so it follows acceleration
+//! with less lag than a plain average at the same smoothing. Above the
+//! limit the setpoint is pulled below it by the overshoot, which brings a
+//! vehicle rolling downhill back under the limit.
+//!
+//! `safety::set_speed_override` lifts the limit; the odometer keeps
+//! counting either way.
+
+#![no_std]
+
+use crate::drivers::MotorController;
+use crate::safety;
+
+/// Sample periods averaged for the speed
+pub const SPEED_WINDOWS: usize = 8;
+
+pub const DEFAULT_SAMPLE_MS: u32 = 100;
+
+pub struct SpeedGovernor {
+    /// Distance per pulse, in the units the speed is wanted in
+    distance_per_pulse: f32,
+    max_speed: f32,
+    sample_ms: u32,
+    last_pulses: Option<u32>,
+    last_sample_ms: u32,
+    /// Pulses since the odometer was cleared
+    odometer_pulses: u32,
+    samples: [f32; SPEED_WINDOWS],
+    head: usize,
+    len: usize,
+    speed: f32,
+    limiting: bool,
+}
+
+impl SpeedGovernor {
+    pub fn new(distance_per_pulse: f32, max_speed: f32) -> Self {
+        Self {
+            distance_per_pulse,
+            max_speed: max_speed.max(0.0),
+            sample_ms: DEFAULT_SAMPLE_MS,
+            last_pulses: None,
+            last_sample_ms: 0,
+            odometer_pulses: 0,
+            samples: [0.0; SPEED_WINDOWS],
+            head: 0,
+            len: 0,
+            speed: 0.0,
+            limiting: false,
+        }
+    }
+
+    pub fn set_max_speed(&mut self, max_speed: f32) {
+        self.max_speed = max_speed.max(0.0);
+    }
+
+    pub fn max_speed(&self) -> f32 {
+        self.max_speed
+    }
+
+    /// Length of one speed sample, longer is smoother at low pulse rates
+    pub fn set_sample_ms(&mut self, sample_ms: u32) {
+        self.sample_ms = sample_ms.max(1);
+    }
+
+    /// Feed the cumulative pulse count, e.g. `PulseCounter::total_pulses`.
+    /// Call regularly with the system millisecond tick.
+    pub fn update(&mut self, pulses: u32, now_ms: u32) {
+        let last = match self.last_pulses {
+            Some(last) => last,
+            None => {
+                self.last_pulses = Some(pulses);
+                self.last_sample_ms = now_ms;
+                return;
+            }
+        };
+
+        let elapsed = now_ms.wrapping_sub(self.last_sample_ms);
+        if elapsed < self.sample_ms {
+            return;
+        }
+
+        let delta = pulses.wrapping_sub(last);
+        self.odometer_pulses = self.odometer_pulses.wrapping_add(delta);
+        self.last_pulses = Some(pulses);
+        self.last_sample_ms = now_ms;
+
+        let sample = delta as f32 * self.distance_per_pulse * 1000.0 / elapsed as f32;
+        self.samples[self.head] = sample;
+        self.head = (self.head + 1) % SPEED_WINDOWS;
+        self.len = (self.len + 1).min(SPEED_WINDOWS);
+        self.speed = self.weighted_average();
+    }
+
+    // Weights 1..=len from oldest to newest
+    fn weighted_average(&self) -> f32 {
+        let mut sum = 0.0;
+        let mut weights = 0.0;
+        for age in 0..self.len {
+            let i = (self.head + SPEED_WINDOWS - 1 - age) % SPEED_WINDOWS;
+            let weight = (self.len - age) as f32;
+            sum += self.samples[i] * weight;
+            weights += weight;
+        }
+        if weights > 0.0 {
+            sum / weights
+        } else {
+            0.0
+        }
+    }
+
+    /// Smoothed speed in distance units per second
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Distance travelled since the last `reset_odometer`
+    pub fn distance(&self) -> f32 {
+        self.odometer_pulses as f32 * self.distance_per_pulse
+    }
+
+    pub fn reset_odometer(&mut self) {
+        self.odometer_pulses = 0;
+    }
+
+    /// True if the last `limit` call changed the setpoint
+    pub fn is_limiting(&self) -> bool {
+        self.limiting
+    }
+
+    /// `setpoint` capped at the maximum speed, and below it by the
+    /// measured overshoot. Reverse setpoints are capped the same way.
+    pub fn limit(&mut self, setpoint: f32) -> f32 {
+        if safety::speed_override() {
+            self.limiting = false;
+            return setpoint;
+        }
+
+        let overshoot = (self.speed - self.max_speed).max(0.0);
+        let cap = (self.max_speed - overshoot).max(0.0);
+        let limited = setpoint.clamp(-cap, cap);
+        self.limiting = limited != setpoint;
+        limited
+    }
+
+    /// Hand `setpoint` to `motor` through `limit`
+    pub fn apply(&mut self, motor: &mut MotorController, setpoint: f32) -> f32 {
+        let limited = self.limit(setpoint);
+        motor.set_target(limited);
+        limited
+    }
+}
@@ -0,0 +1,93 @@
+//! Interrupt-safe 16 and 32-bit counters
+//!
+//! The AVR moves one byte at a time, so a u16 or u32 shared with an
+//! interrupt can be read half before and half after the ISR updates it:
+//! a tick going from 0x00FF to 0x0100 can read as 0x01FF. The core
+//! `AtomicU16`/`AtomicU32` types don't help here, the target has no wide
+//! atomic instructions to back them. These types do every access with
+//! interrupts masked instead, which on a single core is all atomicity
+//! takes. Single bytes are atomic already, `AtomicU8` and `AtomicBool`
+//! stay as they are.
+//!
+//! No `Ordering` arguments: masking interrupts is a compiler barrier, so
+//! every operation behaves as `SeqCst`.
+#![no_std]
+
+use avr_device::interrupt::Mutex;
+use core::cell::Cell;
+
+macro_rules! atomic_int {
+    ($name:ident, $int:ty) => {
+        pub struct $name {
+            value: Mutex<Cell<$int>>,
+        }
+
+        impl $name {
+            pub const fn new(value: $int) -> Self {
+                Self {
+                    value: Mutex::new(Cell::new(value)),
+                }
+            }
+
+            #[inline]
+            pub fn load(&self) -> $int {
+                avr_device::interrupt::free(|cs| self.value.borrow(cs).get())
+            }
+
+            #[inline]
+            pub fn store(&self, value: $int) {
+                avr_device::interrupt::free(|cs| self.value.borrow(cs).set(value));
+            }
+
+            /// Set a new value, returning the old one
+            pub fn swap(&self, value: $int) -> $int {
+                avr_device::interrupt::free(|cs| self.value.borrow(cs).replace(value))
+            }
+
+            /// Wrapping add, returning the old value
+            pub fn fetch_add(&self, value: $int) -> $int {
+                avr_device::interrupt::free(|cs| {
+                    let cell = self.value.borrow(cs);
+                    let old = cell.get();
+                    cell.set(old.wrapping_add(value));
+                    old
+                })
+            }
+
+            /// Wrapping subtract, returning the old value
+            pub fn fetch_sub(&self, value: $int) -> $int {
+                avr_device::interrupt::free(|cs| {
+                    let cell = self.value.borrow(cs);
+                    let old = cell.get();
+                    cell.set(old.wrapping_sub(value));
+                    old
+                })
+            }
+
+            /// Store `new` if the value is `current`, returning the value
+            /// found either way
+            pub fn compare_exchange(&self, current: $int, new: $int) -> Result<$int, $int> {
+                avr_device::interrupt::free(|cs| {
+                    let cell = self.value.borrow(cs);
+                    let old = cell.get();
+                    if old == current {
+                        cell.set(new);
+                        Ok(old)
+                    } else {
+                        Err(old)
+                    }
+                })
+            }
+
+            /// Access from inside an existing critical section, e.g. an ISR
+            /// updating several values together
+            #[inline]
+            pub fn borrow<'cs>(&'cs self, cs: avr_device::interrupt::CriticalSection<'cs>) -> &'cs Cell<$int> {
+                self.value.borrow(cs)
+            }
+        }
+    };
+}
+
+atomic_int!(AtomicU16, u16);
+atomic_int!(AtomicU32, u32);
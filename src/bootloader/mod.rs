@@ -1,15 +1,23 @@
 #![no_std]
 
-use crate::hal::{flash::Flash, uart::Uart};
+use avr_device::atmega128::USART0;
+
+use crate::hal::mailbox::{self, BootMailbox, BootReason, UpdateStatus};
+use crate::hal::{flash::Flash, uart::{SerialPort, Uart}};
 
 const BOOTLOADER_START: u32 = 0x1E000;
 const PAGE_SIZE: usize = 256;
 const MAGIC_WORD: u32 = 0xB007F11E;
+// Longest wait for the last reply to leave the UART before jumping away
+const TX_FLUSH_TIMEOUT_MS: u16 = 50;
 
-pub struct Bootloader {
+pub struct Bootloader<S: SerialPort = Uart<USART0>> {
     flash: Flash,
-    uart: Uart,
+    uart: S,
     state: BootloaderState,
+    entry_reason: BootReason,
+    update: UpdateStatus,
+    last_error: u8,
 }
 
 #[derive(PartialEq)]
@@ -27,15 +35,32 @@ pub struct FirmwareHeader {
     crc: u32,
 }
 
-impl Bootloader {
-    pub fn new(flash: Flash, uart: Uart) -> Self {
+impl<S: SerialPort> Bootloader<S> {
+    pub fn new(flash: Flash, uart: S) -> Self {
         Self {
             flash,
             uart,
             state: BootloaderState::Idle,
+            entry_reason: mailbox::take().map_or(BootReason::ColdStart, |m| m.reason),
+            update: UpdateStatus::None,
+            last_error: 0,
         }
     }
 
+    /// Why the application handed over, from the boot mailbox
+    pub fn entry_reason(&self) -> BootReason {
+        self.entry_reason
+    }
+
+    /// NAK the host and remember the error for the application
+    fn fail(&mut self, code: u8) -> Result<(), ()> {
+        self.uart.write_byte(code);
+        self.update = UpdateStatus::Failed;
+        self.last_error = code;
+        self.state = BootloaderState::Idle;
+        Err(())
+    }
+
     pub fn enter_bootloader(&mut self) {
         unsafe {
             let mcucr = &(*avr_device::atmega128::CPU::ptr()).mcucr;
@@ -78,15 +103,11 @@ impl Bootloader {
         let header = unsafe { core::ptr::read(header.as_ptr() as *const FirmwareHeader) };
         
         if header.magic != MAGIC_WORD {
-            self.uart.write_byte(0x45);
-            self.state = BootloaderState::Idle;
-            return Err(());
+            return self.fail(0x45);
         }
 
         if header.size > (BOOTLOADER_START - 0x1000) {
-            self.uart.write_byte(0x46);
-            self.state = BootloaderState::Idle;
-            return Err(());
+            return self.fail(0x46);
         }
 
         self.uart.write_byte(0xAA);
@@ -114,9 +135,7 @@ impl Bootloader {
             self.flash.read(address, &mut verify_buffer)?;
 
             if verify_buffer != page_buffer {
-                self.uart.write_byte(0x47);
-                self.state = BootloaderState::Idle;
-                return Err(());
+                return self.fail(0x47);
             }
 
             self.uart.write_byte(0xAC);
@@ -144,6 +163,8 @@ impl Bootloader {
         self.uart.write_byte((crc >> 8) as u8);
         self.uart.write_byte(crc as u8);
 
+        self.update = UpdateStatus::Updated;
+        self.last_error = 0;
         self.state = BootloaderState::Idle;
         Ok(())
     }
@@ -163,6 +184,15 @@ impl Bootloader {
     }
 
     pub fn jump_to_application(&mut self) {
+        // Let the final status bytes reach the host first
+        let _ = self.uart.flush(TX_FLUSH_TIMEOUT_MS);
+
+        // Tell the application what happened in this session
+        mailbox::write(&BootMailbox {
+            reason: self.entry_reason,
+            update: self.update,
+            last_error: self.last_error,
+        });
         unsafe {
             core::arch::asm!(
                 "jmp 0",
@@ -1,14 +1,34 @@
 //! Configuration constants for ATmega128 firmware
 #![no_std]
 
-/// CPU frequency in Hz
-pub const CPU_FREQ_HZ: u32 = 16_000_000;
+/// CPU frequency in Hz, see `hal::clock`
+pub const CPU_FREQ_HZ: u32 = crate::hal::clock::CPU_FREQ;
+
+/// Firmware version (major, minor, patch)
+pub const FIRMWARE_VERSION: [u8; 3] = [0, 1, 0];
 
 /// UART baud rate
 pub const UART_BAUD: u32 = 9600;
 
-/// ADC reference voltage in millivolts
-pub const ADC_VREF_MV: u16 = 5000;
+/// Baud rate of the packet protocol link: the fastest standard rate up
+/// to 115200 that the CPU clock reaches within tolerance (U2X allowed), so
+/// 115200 at 16MHz and 76800 at 8MHz
+pub const PROTOCOL_BAUD: u32 = crate::hal::clock::fastest_baud(&PROTOCOL_BAUD_CANDIDATES);
+
+// Fastest first; 9600 is reachable from any clock the board supports
+const PROTOCOL_BAUD_CANDIDATES: [u32; 6] = [115_200, 76_800, 57_600, 38_400, 19_200, 9_600];
+
+/// Board supply voltage in millivolts, from the `BOARD_VCC_MV` build
+/// variable (5000 unless overridden)
+pub const BOARD_VCC_MV: u16 = parse_mv(env!("BOARD_VCC_MV"));
+
+/// ADC reference voltage in millivolts, AVCC = the board supply
+pub const ADC_VREF_MV: u16 = BOARD_VCC_MV;
+
+/// Undervoltage limit at the supply sense input. The sense divider is
+/// sized for the board's supply, so the limit scales with it (1465mV on a
+/// 5V board).
+pub const SUPPLY_SENSE_MIN_MV: u16 = (1465 * BOARD_VCC_MV as u32 / 5000) as u16;
 
 /// Watchdog timeout period in milliseconds
 pub const WDT_TIMEOUT_MS: u16 = 1000;
@@ -16,5 +36,139 @@ pub const WDT_TIMEOUT_MS: u16 = 1000;
 /// LED matrix update interval in milliseconds
 pub const LED_UPDATE_MS: u16 = 100;
 
+/// Main loop frame budget in microseconds, see `os::frame`
+pub const FRAME_BUDGET_US: u32 = 2000;
+
 /// Button debounce time in milliseconds
 pub const BUTTON_DEBOUNCE_MS: u16 = 50;
+
+/// EEPROM addresses of the two copies of the persisted PID gains
+/// (`EEPROM_PID_COPY_SIZE` bytes each)
+pub const EEPROM_PID_ADDR: u16 = 0x0000;
+pub const EEPROM_PID_COPY_ADDR: u16 = 0x00F0;
+pub const EEPROM_PID_COPY_SIZE: u16 = 88;
+
+/// EEPROM address of the pulse counter totalizer slots (80 bytes)
+pub const EEPROM_TOTALIZER_ADDR: u16 = 0x0080;
+
+/// EEPROM address of the HX711 load cell calibration (10 bytes)
+pub const EEPROM_HX711_ADDR: u16 = 0x00D0;
+
+/// EEPROM address of the last host reset reason and count (4 bytes)
+pub const EEPROM_RESET_ADDR: u16 = 0x00DA;
+
+/// EEPROM address of the boot counter mixed into random seeds (4 bytes)
+pub const EEPROM_SEED_ADDR: u16 = 0x00EC;
+
+/// EEPROM address of the flash audit reference CRCs (32 bytes)
+pub const EEPROM_AUDIT_ADDR: u16 = 0x0148;
+
+/// EEPROM address of the homing zero offsets (16 bytes)
+pub const EEPROM_HOMING_ADDR: u16 = 0x0168;
+
+// External flash map (W25Q128, 16MB in 4KB sectors). Every region gets
+// sectors of its own: the log ring erases each sector it wraps into, so
+// nothing may share its first megabyte.
+//
+//   0x000000-0x0FFFFF  event log ring (`logger::Logger`, 256 sectors)
+//   0x100000-0x100FFF  calibration, first copy (`drivers::calibration`)
+//   0x101000-0x101FFF  cron table (`application::cron`)
+//   0x102000-0x102FFF  sampling plan (`drivers::sampling_plan`)
+//   0x103000-0x103FFF  power profile history (`diagnostics::power_profile`)
+//   0x110000-0x110FFF  calibration, second copy, in another 64KB block
+//   0x200000-0x21FFFF  sensor data log (`application::data_logger`)
+
+/// Sector size of the external flash
+pub const FLASH_SECTOR_SIZE: u32 = 4096;
+
+/// First byte and sector count of the event log ring
+pub const FLASH_LOG_START: u32 = 0x000000;
+pub const FLASH_LOG_SECTORS: u32 = 256;
+
+/// Sectors of the two calibration copies, see `drivers::redundant_store`
+pub const FLASH_CALIBRATION: [u32; 2] = [0x100000, 0x110000];
+
+/// Sector of the persisted cron table
+pub const FLASH_CRON: u32 = 0x101000;
+
+/// Sector of the persisted sampling plan
+pub const FLASH_SAMPLING: u32 = 0x102000;
+
+/// Sector of the persisted power profile history
+pub const FLASH_POWER: u32 = 0x103000;
+
+/// Sensor data log, `[start, end)`
+pub const FLASH_DATALOG_START: u32 = 0x200000;
+pub const FLASH_DATALOG_END: u32 = 0x220000;
+
+/// Bytes in each of the four UART ring buffers (TX and RX of both ports),
+/// from `UART_BUFFER_SIZE` (32 unless overridden). A power of two.
+pub const UART_BUFFER_SIZE: usize = parse_size(env!("UART_BUFFER_SIZE"));
+
+/// Packet transport receive buffer, from `TRANSPORT_RX_BUFFER` (512)
+pub const TRANSPORT_RX_BUFFER: usize = parse_size(env!("TRANSPORT_RX_BUFFER"));
+
+/// Packet transport transmit buffer, from `TRANSPORT_TX_BUFFER` (512)
+pub const TRANSPORT_TX_BUFFER: usize = parse_size(env!("TRANSPORT_TX_BUFFER"));
+
+/// Log entries held in RAM before a flush, from `LOG_BUFFER_ENTRIES` (32)
+pub const LOG_BUFFER_ENTRIES: usize = parse_size(env!("LOG_BUFFER_ENTRIES"));
+
+/// RTOS event queue length, from `EVENT_QUEUE_LEN` (32)
+pub const EVENT_QUEUE_LEN: usize = parse_size(env!("EVENT_QUEUE_LEN"));
+
+/// Stack of each RTOS task, from `TASK_STACK_SIZE` (512)
+pub const TASK_STACK_SIZE: usize = parse_size(env!("TASK_STACK_SIZE"));
+
+// The UART masks its indices and keeps 8 bytes of RTS headroom
+const _: () = assert!(UART_BUFFER_SIZE.is_power_of_two(), "UART_BUFFER_SIZE must be a power of two");
+const _: () = assert!(UART_BUFFER_SIZE >= 16 && UART_BUFFER_SIZE <= 256, "UART_BUFFER_SIZE outside 16..=256");
+const _: () = assert!(TRANSPORT_RX_BUFFER >= 64 && TRANSPORT_TX_BUFFER >= 64, "transport buffers below one packet");
+const _: () = assert!(LOG_BUFFER_ENTRIES >= 1, "LOG_BUFFER_ENTRIES must be at least 1");
+const _: () = assert!(EVENT_QUEUE_LEN >= 2, "EVENT_QUEUE_LEN must be at least 2");
+// Context switch frame: 32 registers, SREG and the return address
+const _: () = assert!(TASK_STACK_SIZE >= 64, "TASK_STACK_SIZE below one context frame");
+const _: () = assert!(
+    FLASH_DATALOG_START >= FLASH_LOG_START + FLASH_LOG_SECTORS * FLASH_SECTOR_SIZE,
+    "data log inside the event log ring"
+);
+
+/// Internal SRAM of the ATmega128
+pub const SRAM_SIZE: usize = 4096;
+
+/// SRAM left for the main stack and the ISRs running on top of it, from
+/// `STACK_RESERVE` (1024).
+///
+/// Every static lands in .noinit, .data or .bss, so the linker checks the
+/// real total: build.rs asserts `__bss_end + STACK_RESERVE` fits in SRAM
+/// and the firmware prints the size at boot. Shrink the buffers above
+/// rather than the reserve, running out of stack corrupts them silently.
+pub const STACK_RESERVE: usize = parse_size(env!("STACK_RESERVE"));
+
+const _: () = assert!(STACK_RESERVE >= 256 && STACK_RESERVE < SRAM_SIZE, "STACK_RESERVE outside 256..SRAM_SIZE");
+
+const fn parse_number(s: &str) -> u32 {
+    let bytes = s.as_bytes();
+    assert!(!bytes.is_empty(), "empty build variable");
+    let mut value = 0u32;
+    let mut i = 0;
+    while i < bytes.len() {
+        let digit = bytes[i];
+        assert!(digit >= b'0' && digit <= b'9', "build variable must be a plain number");
+        value = value * 10 + (digit - b'0') as u32;
+        i += 1;
+    }
+    value
+}
+
+const fn parse_mv(s: &str) -> u16 {
+    let value = parse_number(s);
+    assert!(value >= 1800 && value <= 5500, "BOARD_VCC_MV outside the ATmega128 supply range");
+    value as u16
+}
+
+const fn parse_size(s: &str) -> usize {
+    let value = parse_number(s);
+    assert!(value <= SRAM_SIZE as u32, "buffer larger than SRAM");
+    value as usize
+}
@@ -0,0 +1,199 @@
+//! Roll/pitch/yaw attitude-hold controller
+//!
+//! Three PID loops act on the Euler angles from the Madgwick filter and
+//! produce roll/pitch/yaw corrections. A mixer table then turns throttle plus
+//! those corrections into per-output commands (0.0 - 1.0) for the ESC or
+//! servo drivers, so the same controller serves quads, hexas or fixed-wing
+//! surfaces by swapping the table.
+#![no_std]
+
+use crate::drivers::motor_control::PidConfig;
+use crate::drivers::Vec3;
+
+pub const MAX_OUTPUTS: usize = 6;
+
+/// Desired attitude in degrees plus collective throttle (0.0 - 1.0)
+#[derive(Clone, Copy, Default)]
+pub struct AttitudeSetpoint {
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+    pub throttle: f32,
+}
+
+/// Mixer output commands, `count` of them valid
+#[derive(Clone, Copy)]
+pub struct MixerOutput {
+    pub outputs: [f32; MAX_OUTPUTS],
+    pub count: usize,
+}
+
+/// One row per output: contribution of throttle, roll, pitch and yaw
+#[derive(Clone, Copy)]
+pub struct Mixer {
+    rows: [[f32; 4]; MAX_OUTPUTS],
+    count: usize,
+}
+
+impl Mixer {
+    pub fn new(rows: &[[f32; 4]]) -> Self {
+        let mut mixer = Self {
+            rows: [[0.0; 4]; MAX_OUTPUTS],
+            count: rows.len().min(MAX_OUTPUTS),
+        };
+        mixer.rows[..mixer.count].copy_from_slice(&rows[..mixer.count]);
+        mixer
+    }
+
+    /// Quadcopter in X configuration, motors numbered front-right,
+    /// rear-left, front-left, rear-right (front-right spinning CCW)
+    pub fn quad_x() -> Self {
+        Self::new(&[
+            [1.0, -1.0, 1.0, 1.0],
+            [1.0, 1.0, -1.0, 1.0],
+            [1.0, 1.0, 1.0, -1.0],
+            [1.0, -1.0, -1.0, -1.0],
+        ])
+    }
+
+    /// Replace a single row of the table
+    pub fn set_row(&mut self, output: usize, row: [f32; 4]) {
+        if output < MAX_OUTPUTS {
+            self.rows[output] = row;
+            self.count = self.count.max(output + 1);
+        }
+    }
+
+    pub fn mix(&self, throttle: f32, roll: f32, pitch: f32, yaw: f32) -> MixerOutput {
+        let mut out = MixerOutput {
+            outputs: [0.0; MAX_OUTPUTS],
+            count: self.count,
+        };
+        for (i, row) in self.rows[..self.count].iter().enumerate() {
+            let value = row[0] * throttle + row[1] * roll + row[2] * pitch + row[3] * yaw;
+            out.outputs[i] = value.clamp(0.0, 1.0);
+        }
+        out
+    }
+}
+
+/// PID loop for a single axis, derivative on measurement
+struct AxisPid {
+    config: PidConfig,
+    iterm: f32,
+    last_input: f32,
+}
+
+impl AxisPid {
+    fn new(config: PidConfig) -> Self {
+        Self {
+            config,
+            iterm: 0.0,
+            last_input: 0.0,
+        }
+    }
+
+    fn update(&mut self, error: f32, input: f32, dt: f32) -> f32 {
+        let pterm = self.config.kp * error;
+
+        self.iterm += self.config.ki * error * dt;
+        self.iterm = self.iterm.clamp(self.config.iterm_min, self.config.iterm_max);
+
+        let dterm = if dt > 0.0 {
+            -self.config.kd * wrap_degrees(input - self.last_input) / dt
+        } else {
+            0.0
+        };
+        self.last_input = input;
+
+        (pterm + self.iterm + dterm).clamp(self.config.output_min, self.config.output_max)
+    }
+
+    fn reset(&mut self) {
+        self.iterm = 0.0;
+    }
+}
+
+fn default_axis_config() -> PidConfig {
+    PidConfig {
+        kp: 0.01,
+        ki: 0.0,
+        kd: 0.001,
+        output_min: -0.5,
+        output_max: 0.5,
+        iterm_min: -0.2,
+        iterm_max: 0.2,
+        sample_time_ms: 0,
+    }
+}
+
+/// Wrap an angle difference into -180..180 degrees
+fn wrap_degrees(angle: f32) -> f32 {
+    let mut a = angle;
+    while a > 180.0 {
+        a -= 360.0;
+    }
+    while a < -180.0 {
+        a += 360.0;
+    }
+    a
+}
+
+pub struct AttitudeController {
+    roll: AxisPid,
+    pitch: AxisPid,
+    yaw: AxisPid,
+    mixer: Mixer,
+}
+
+impl AttitudeController {
+    pub fn new(mixer: Mixer) -> Self {
+        Self {
+            roll: AxisPid::new(default_axis_config()),
+            pitch: AxisPid::new(default_axis_config()),
+            yaw: AxisPid::new(default_axis_config()),
+            mixer,
+        }
+    }
+
+    pub fn configure_roll(&mut self, config: PidConfig) {
+        self.roll = AxisPid::new(config);
+    }
+
+    pub fn configure_pitch(&mut self, config: PidConfig) {
+        self.pitch = AxisPid::new(config);
+    }
+
+    pub fn configure_yaw(&mut self, config: PidConfig) {
+        self.yaw = AxisPid::new(config);
+    }
+
+    /// Roll, pitch and yaw gains, e.g. to register them with the console
+    /// tuner
+    pub fn axis_configs(&self) -> [PidConfig; 3] {
+        [self.roll.config.clone(), self.pitch.config.clone(), self.yaw.config.clone()]
+    }
+
+    pub fn set_mixer(&mut self, mixer: Mixer) {
+        self.mixer = mixer;
+    }
+
+    /// Run the loops. `attitude` are the Euler angles from
+    /// `MadgwickFilter::get_euler_angles` (x = roll, y = pitch, z = yaw),
+    /// `dt` the time since the last update in seconds.
+    pub fn update(&mut self, attitude: Vec3, setpoint: AttitudeSetpoint, dt: f32) -> MixerOutput {
+        let roll = self.roll.update(setpoint.roll - attitude.x, attitude.x, dt);
+        let pitch = self.pitch.update(setpoint.pitch - attitude.y, attitude.y, dt);
+        // Heading hold: take the short way around
+        let yaw = self.yaw.update(wrap_degrees(setpoint.yaw - attitude.z), attitude.z, dt);
+
+        self.mixer.mix(setpoint.throttle, roll, pitch, yaw)
+    }
+
+    /// Clear integrators, e.g. while landed or disarmed
+    pub fn reset(&mut self) {
+        self.roll.reset();
+        self.pitch.reset();
+        self.yaw.reset();
+    }
+}
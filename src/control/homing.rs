@@ -0,0 +1,331 @@
+//! Limit switches, homing and soft limits for positioning axes
+//!
+//! An axis is anything that takes a velocity command and reports a
+//! position (a stepper's step count, a DC motor's encoder), see
+//! `HomingAxis`. Homing runs as a state machine from the loop:
+//!
+//! ```text
+//! Idle --start()--> Seek --switch--> BackOff --switch released
+//!   + backoff--> ReSeek (slow) --switch--> PullOff --backoff--> Homed
+//! ```
+//!
+//! The slow re-seek sets the position, so the repeatability is that of the
+//! switch at `slow_speed`. After homing, targets are clamped to the soft
+//! limits and `limit_velocity` stops motion that would leave them. A
+//! limit switch hit after homing means the axis lost position; it stops
+//! and has to be homed again.
+//!
+//! Zero offsets, the distance from the home switch to the axis zero, are
+//! kept in EEPROM per axis.
+//!
+//! `MotorAxis` homes a DC motor on `MotorController` with an `Encoder` for
+//! the position.
+#![no_std]
+
+use crate::config::EEPROM_HOMING_ADDR;
+use crate::drivers::debounced_input::DebouncedInput;
+use crate::drivers::encoder::Encoder;
+use crate::drivers::motor_control::MotorController;
+use crate::hal::eeprom;
+use crate::protocol::crc::crc16;
+
+/// Axes with a stored zero offset
+pub const MAX_AXES: usize = 3;
+
+const OFFSETS_MAGIC: u16 = 0x4E30;
+// Magic, one f32 per axis, CRC-16 of both
+const OFFSETS_SIZE: u16 = 2 + 4 * MAX_AXES as u16 + 2;
+const CRC_OFFSET: usize = OFFSETS_SIZE as usize - 2;
+
+/// Velocity-commanded axis with position feedback
+pub trait HomingAxis {
+    /// Units per second, signed. 0 stops.
+    fn set_velocity(&mut self, velocity: f32);
+    fn position(&self) -> f32;
+    /// Redefine the current position
+    fn set_position(&mut self, position: f32);
+}
+
+/// DC motor with encoder feedback. The controller regulates speed from
+/// the encoder as usual, keep calling `MotorController::update` with the
+/// measured speed every tick; `set_velocity` only moves its setpoint.
+pub struct MotorAxis<'a> {
+    pub motor: &'a mut MotorController,
+    pub encoder: &'a mut Encoder,
+    /// Axis units per encoder count
+    pub units_per_count: f32,
+}
+
+impl HomingAxis for MotorAxis<'_> {
+    fn set_velocity(&mut self, velocity: f32) {
+        self.motor.set_target(velocity);
+    }
+
+    fn position(&self) -> f32 {
+        self.encoder.count() as f32 * self.units_per_count
+    }
+
+    fn set_position(&mut self, position: f32) {
+        self.encoder.set_count((position / self.units_per_count) as i32);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HomeDirection {
+    /// Home switch at the low end of travel
+    Negative,
+    Positive,
+}
+
+impl HomeDirection {
+    fn sign(self) -> f32 {
+        match self {
+            HomeDirection::Negative => -1.0,
+            HomeDirection::Positive => 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct HomingConfig {
+    pub direction: HomeDirection,
+    /// Fast approach, units per second
+    pub seek_speed: f32,
+    /// Second approach, sets the position
+    pub slow_speed: f32,
+    /// Distance moved clear of the switch after each approach
+    pub backoff: f32,
+    /// Give up on a phase after this long
+    pub timeout_ms: u32,
+    /// Soft limits in axis units, enforced once homed
+    pub soft_min: f32,
+    pub soft_max: f32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HomingError {
+    /// A phase did not finish in `timeout_ms`
+    Timeout,
+    /// The switch did not release while backing off
+    SwitchStuck,
+    /// A limit switch tripped outside homing
+    LimitHit,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HomingState {
+    Idle,
+    Seek,
+    BackOff,
+    ReSeek,
+    PullOff,
+    Homed,
+    Failed(HomingError),
+}
+
+pub struct Homing {
+    config: HomingConfig,
+    home: DebouncedInput,
+    /// Switch at the other end of travel, if fitted
+    far: Option<DebouncedInput>,
+    zero_offset: f32,
+    state: HomingState,
+    phase_start_ms: u32,
+    // Position where the current backoff started counting
+    mark: f32,
+    released: bool,
+}
+
+impl Homing {
+    pub fn new(config: HomingConfig, home: DebouncedInput, far: Option<DebouncedInput>) -> Self {
+        Self {
+            config,
+            home,
+            far,
+            zero_offset: 0.0,
+            state: HomingState::Idle,
+            phase_start_ms: 0,
+            mark: 0.0,
+            released: false,
+        }
+    }
+
+    pub fn set_config(&mut self, config: HomingConfig) {
+        self.config = config;
+    }
+
+    pub fn config(&self) -> HomingConfig {
+        self.config
+    }
+
+    /// Distance from the home switch to the axis zero, positive into the
+    /// travel. Takes effect at the next homing.
+    pub fn set_zero_offset(&mut self, offset: f32) {
+        self.zero_offset = offset;
+    }
+
+    pub fn zero_offset(&self) -> f32 {
+        self.zero_offset
+    }
+
+    pub fn state(&self) -> HomingState {
+        self.state
+    }
+
+    pub fn is_homed(&self) -> bool {
+        self.state == HomingState::Homed
+    }
+
+    pub fn is_busy(&self) -> bool {
+        matches!(
+            self.state,
+            HomingState::Seek | HomingState::BackOff | HomingState::ReSeek | HomingState::PullOff
+        )
+    }
+
+    /// Start homing. A switch already pressed skips the fast approach.
+    pub fn start<A: HomingAxis>(&mut self, axis: &mut A, now_ms: u32) {
+        self.home.update();
+        if self.home.is_active() {
+            self.enter_backoff(axis, HomingState::BackOff, now_ms);
+        } else {
+            self.enter(HomingState::Seek, now_ms);
+            axis.set_velocity(self.config.direction.sign() * self.config.seek_speed);
+        }
+    }
+
+    /// Stop homing where it is, the axis is not homed afterwards
+    pub fn abort<A: HomingAxis>(&mut self, axis: &mut A) {
+        axis.set_velocity(0.0);
+        self.state = HomingState::Idle;
+    }
+
+    /// Sample the switches and advance homing, call every loop tick
+    pub fn update<A: HomingAxis>(&mut self, axis: &mut A, now_ms: u32) -> HomingState {
+        self.home.update();
+        if let Some(far) = self.far.as_mut() {
+            far.update();
+        }
+
+        let toward = self.config.direction.sign();
+        match self.state {
+            HomingState::Idle | HomingState::Failed(_) => {}
+            HomingState::Homed => {
+                let far_hit = self.far.as_ref().map_or(false, |f| f.is_active());
+                if self.home.is_active() || far_hit {
+                    self.fail(axis, HomingError::LimitHit);
+                }
+            }
+            HomingState::Seek => {
+                if self.home.is_active() {
+                    self.enter_backoff(axis, HomingState::BackOff, now_ms);
+                }
+            }
+            HomingState::BackOff | HomingState::PullOff => {
+                if !self.home.is_active() && !self.released {
+                    self.released = true;
+                    self.mark = axis.position();
+                }
+                let moved = (axis.position() - self.mark) * -toward;
+                if self.released && moved >= self.config.backoff {
+                    if self.state == HomingState::BackOff {
+                        self.enter(HomingState::ReSeek, now_ms);
+                        axis.set_velocity(toward * self.config.slow_speed);
+                    } else {
+                        axis.set_velocity(0.0);
+                        self.state = HomingState::Homed;
+                    }
+                    return self.state;
+                }
+            }
+            HomingState::ReSeek => {
+                if self.home.is_active() {
+                    // The switch lies `zero_offset` from zero, on the
+                    // side homing approaches from
+                    axis.set_position(toward * self.zero_offset);
+                    self.enter_backoff(axis, HomingState::PullOff, now_ms);
+                }
+            }
+        }
+
+        if self.is_busy() && now_ms.wrapping_sub(self.phase_start_ms) > self.config.timeout_ms {
+            let error = match self.state {
+                HomingState::BackOff | HomingState::PullOff => HomingError::SwitchStuck,
+                _ => HomingError::Timeout,
+            };
+            self.fail(axis, error);
+        }
+        self.state
+    }
+
+    /// Clamp a move target to the soft limits. `None` until homed, moves
+    /// are refused without a reference.
+    pub fn clamp_target(&self, target: f32) -> Option<f32> {
+        if !self.is_homed() {
+            return None;
+        }
+        Some(target.max(self.config.soft_min).min(self.config.soft_max))
+    }
+
+    /// Velocity allowed at `position`: zero when it would leave the soft
+    /// limits or the axis is not homed
+    pub fn limit_velocity(&self, position: f32, velocity: f32) -> f32 {
+        if !self.is_homed() {
+            return 0.0;
+        }
+        if (velocity > 0.0 && position >= self.config.soft_max) || (velocity < 0.0 && position <= self.config.soft_min) {
+            return 0.0;
+        }
+        velocity
+    }
+
+    fn enter(&mut self, state: HomingState, now_ms: u32) {
+        self.state = state;
+        self.phase_start_ms = now_ms;
+    }
+
+    fn enter_backoff<A: HomingAxis>(&mut self, axis: &mut A, state: HomingState, now_ms: u32) {
+        self.enter(state, now_ms);
+        self.released = false;
+        self.mark = axis.position();
+        axis.set_velocity(-self.config.direction.sign() * self.config.slow_speed);
+    }
+
+    fn fail<A: HomingAxis>(&mut self, axis: &mut A, error: HomingError) {
+        axis.set_velocity(0.0);
+        self.state = HomingState::Failed(error);
+    }
+}
+
+/// Store the zero offsets of all axes
+pub fn save_offsets(offsets: &[f32; MAX_AXES]) {
+    let mut record = [0u8; OFFSETS_SIZE as usize];
+    record[0..2].copy_from_slice(&OFFSETS_MAGIC.to_le_bytes());
+    for (i, offset) in offsets.iter().enumerate() {
+        record[2 + i * 4..6 + i * 4].copy_from_slice(&offset.to_le_bytes());
+    }
+    let crc = crc16(&record[..CRC_OFFSET]);
+    record[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+    for (i, &byte) in record.iter().enumerate() {
+        eeprom::update_byte(EEPROM_HOMING_ADDR + i as u16, byte);
+    }
+}
+
+/// Zero offsets stored by `save_offsets`, all zero if none are stored or
+/// the record is corrupt
+pub fn load_offsets() -> [f32; MAX_AXES] {
+    let mut record = [0u8; OFFSETS_SIZE as usize];
+    for (i, byte) in record.iter_mut().enumerate() {
+        *byte = eeprom::read_byte(EEPROM_HOMING_ADDR + i as u16);
+    }
+    let mut offsets = [0.0; MAX_AXES];
+    let crc = u16::from_le_bytes([record[CRC_OFFSET], record[CRC_OFFSET + 1]]);
+    if u16::from_le_bytes([record[0], record[1]]) != OFFSETS_MAGIC || crc != crc16(&record[..CRC_OFFSET]) {
+        return offsets;
+    }
+    for (i, offset) in offsets.iter_mut().enumerate() {
+        let bytes = [record[2 + i * 4], record[3 + i * 4], record[4 + i * 4], record[5 + i * 4]];
+        *offset = f32::from_le_bytes(bytes);
+    }
+    offsets
+}
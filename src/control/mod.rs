@@ -0,0 +1,12 @@
+//! Closed-loop control layers built on the drivers and estimators
+#![no_std]
+
+pub mod attitude;
+pub mod homing;
+pub mod ramp;
+pub mod trajectory;
+
+pub use attitude::{AttitudeController, AttitudeSetpoint, Mixer, MixerOutput};
+pub use homing::{HomeDirection, Homing, HomingAxis, HomingConfig, HomingError, HomingState};
+pub use ramp::{Ramp, RampConfig};
+pub use trajectory::{Trajectory, TrajectoryConfig, TrajectoryError};
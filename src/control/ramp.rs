@@ -0,0 +1,108 @@
+//! Setpoint slew-rate limiting
+//!
+//! Step commands from the protocol are fed through a `Ramp` so actuators
+//! see a bounded rate of change instead of a jump. With `max_accel` set the
+//! rate itself is limited as well, which gives an S-curve: the ramp eases in,
+//! cruises at `max_rate` and eases out onto the target.
+#![no_std]
+
+use libm::sqrtf;
+
+/// Ramp limits, in setpoint units per second (and per second squared)
+#[derive(Clone, Copy)]
+pub struct RampConfig {
+    pub max_rate: f32,
+    pub max_accel: Option<f32>,
+}
+
+impl RampConfig {
+    /// Plain linear slew limit
+    pub const fn linear(max_rate: f32) -> Self {
+        Self { max_rate, max_accel: None }
+    }
+
+    /// Slew limit with acceleration limit (S-curve)
+    pub const fn s_curve(max_rate: f32, max_accel: f32) -> Self {
+        Self { max_rate, max_accel: Some(max_accel) }
+    }
+}
+
+pub struct Ramp {
+    config: RampConfig,
+    target: f32,
+    value: f32,
+    rate: f32,
+}
+
+impl Ramp {
+    pub fn new(config: RampConfig, initial: f32) -> Self {
+        Self {
+            config,
+            target: initial,
+            value: initial,
+            rate: 0.0,
+        }
+    }
+
+    pub fn set_config(&mut self, config: RampConfig) {
+        self.config = config;
+    }
+
+    pub fn set_target(&mut self, target: f32) {
+        self.target = target;
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    /// Current shaped setpoint
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    /// Jump straight to `value` and stop moving
+    pub fn reset(&mut self, value: f32) {
+        self.target = value;
+        self.value = value;
+        self.rate = 0.0;
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.value == self.target
+    }
+
+    /// Advance the ramp by `dt` seconds and return the shaped setpoint
+    pub fn update(&mut self, dt: f32) -> f32 {
+        if dt <= 0.0 {
+            return self.value;
+        }
+
+        let error = self.target - self.value;
+        let direction = if error >= 0.0 { 1.0 } else { -1.0 };
+        let distance = error * direction;
+
+        let rate = match self.config.max_accel {
+            Some(accel) => {
+                // Fastest speed we can still brake from before the target
+                let braking = sqrtf(2.0 * accel * distance);
+                let desired = direction * self.config.max_rate.min(braking);
+                let max_change = accel * dt;
+                self.rate + (desired - self.rate).clamp(-max_change, max_change)
+            }
+            None => direction * self.config.max_rate,
+        };
+
+        let step = rate * dt;
+        if step * direction >= distance {
+            // Would overshoot - land on the target
+            self.value = self.target;
+            self.rate = 0.0;
+        } else {
+            self.value += step;
+            self.rate = rate;
+        }
+
+        self.value
+    }
+}
@@ -0,0 +1,295 @@
+//! Segment queue with look-ahead junction blending
+//!
+//! Straight-line moves are queued as segments and executed one after the
+//! other on a trapezoidal speed profile. Instead of stopping at every
+//! segment end, the speed through each junction is limited by the angle
+//! between the two segments (the junction deviation model): a straight
+//! continuation keeps full speed, a reversal stops. Every `push` re-plans
+//! the queue backwards from a stop at its end and forwards from the speed
+//! of the running segment, so the axes can always still stop in time.
+//!
+//! Everything is integer: positions in micrometres, speeds in um/s,
+//! acceleration in um/s^2, directions as Q14 unit vectors. The queue
+//! depth `N` is a const generic; deeper queues plan further ahead at
+//! about 50 bytes per segment.
+#![no_std]
+
+use crate::math::isqrt_u64;
+
+/// X, Y, Z
+pub const AXES: usize = 3;
+
+const Q14: i64 = 1 << 14;
+// cos of the junction angle past which a junction counts as straight or
+// as a reversal
+const COS_LIMIT: i64 = Q14 - 16;
+
+#[derive(Clone, Copy)]
+pub struct TrajectoryConfig {
+    /// um/s^2, the same on every axis
+    pub accel: u32,
+    /// Cornering tolerance in um, larger corners faster
+    pub junction_deviation: u32,
+    /// Speed limit of every move, um/s
+    pub max_speed: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TrajectoryError {
+    Full,
+    /// Target equals the end of the queue
+    ZeroLength,
+}
+
+#[derive(Clone, Copy)]
+struct Segment {
+    start: [i32; AXES],
+    delta: [i32; AXES],
+    length: u32,
+    /// Q14 direction
+    unit: [i16; AXES],
+    speed: u32,
+    /// Junction limit with the previous segment
+    max_entry: u32,
+    /// Planned entry speed
+    entry: u32,
+}
+
+const EMPTY: Segment = Segment {
+    start: [0; AXES],
+    delta: [0; AXES],
+    length: 0,
+    unit: [0; AXES],
+    speed: 0,
+    max_entry: 0,
+    entry: 0,
+};
+
+pub struct Trajectory<const N: usize> {
+    config: TrajectoryConfig,
+    segments: [Segment; N],
+    head: usize,
+    len: usize,
+    /// End of the last queued segment
+    end: [i32; AXES],
+    /// Setpoint of the running segment
+    position: [i32; AXES],
+    done: u32,
+    speed: u32,
+    // Distance remainder in um*us
+    carry: u64,
+}
+
+impl<const N: usize> Trajectory<N> {
+    // The queue indices are taken modulo N
+    const NOT_EMPTY: () = assert!(N > 0, "Trajectory needs a queue depth of at least 1");
+
+    pub const fn new(config: TrajectoryConfig, position: [i32; AXES]) -> Self {
+        let () = Self::NOT_EMPTY;
+        Self {
+            config,
+            segments: [EMPTY; N],
+            head: 0,
+            len: 0,
+            end: position,
+            position,
+            done: 0,
+            speed: 0,
+            carry: 0,
+        }
+    }
+
+    pub fn set_config(&mut self, config: TrajectoryConfig) {
+        self.config = config;
+    }
+
+    /// Current setpoint
+    pub fn position(&self) -> [i32; AXES] {
+        self.position
+    }
+
+    /// Setpoint speed, um/s
+    pub fn speed(&self) -> u32 {
+        self.speed
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Drop every queued move and stop dead at the current setpoint, for
+    /// aborts only
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.end = self.position;
+        self.done = 0;
+        self.speed = 0;
+        self.carry = 0;
+    }
+
+    /// Queue a straight move to `target` at up to `speed` um/s
+    pub fn push(&mut self, target: [i32; AXES], speed: u32) -> Result<(), TrajectoryError> {
+        if self.len == N {
+            return Err(TrajectoryError::Full);
+        }
+
+        let mut delta = [0i32; AXES];
+        let mut square = 0u64;
+        for axis in 0..AXES {
+            delta[axis] = target[axis].wrapping_sub(self.end[axis]);
+            square += (delta[axis] as i64 * delta[axis] as i64) as u64;
+        }
+        let length = isqrt_u64(square) as u32;
+        if length == 0 {
+            return Err(TrajectoryError::ZeroLength);
+        }
+
+        let mut unit = [0i16; AXES];
+        for axis in 0..AXES {
+            unit[axis] = (delta[axis] as i64 * Q14 / length as i64) as i16;
+        }
+
+        let speed = speed.min(self.config.max_speed).max(1);
+        let max_entry = match self.last() {
+            Some(prev) => self.junction_speed(prev, &unit, speed),
+            None => 0,
+        };
+
+        let index = (self.head + self.len) % N;
+        self.segments[index] = Segment {
+            start: self.end,
+            delta,
+            length,
+            unit,
+            speed,
+            max_entry,
+            entry: 0,
+        };
+        self.len += 1;
+        self.end = target;
+        self.plan();
+        Ok(())
+    }
+
+    /// Advance the setpoint by `dt_us` microseconds and return it
+    pub fn update(&mut self, dt_us: u32) -> [i32; AXES] {
+        let mut budget = dt_us as u64;
+        while self.len > 0 && budget > 0 {
+            let segment = self.segments[self.head];
+            let exit = self.next().map_or(0, |s| s.entry);
+            let remaining = segment.length - self.done;
+
+            // Fastest speed that can still slow down to the exit speed
+            let reachable = isqrt_u64(exit as u64 * exit as u64 + 2 * self.config.accel as u64 * remaining as u64) as u32;
+            let accelerated = self.speed as u64 + self.config.accel as u64 * budget / 1_000_000;
+            self.speed = (accelerated.min(segment.speed as u64) as u32).min(reachable).max(1);
+
+            let total = self.carry + self.speed as u64 * budget;
+            let needed = remaining as u64 * 1_000_000;
+            if total < needed {
+                self.carry = total % 1_000_000;
+                self.done += (total / 1_000_000) as u32;
+                budget = 0;
+            } else {
+                // Finish the segment and spend what is left of dt on the next
+                let used = (needed - self.carry + self.speed as u64 - 1) / self.speed as u64;
+                budget = budget.saturating_sub(used);
+                self.carry = 0;
+                self.done = 0;
+                self.head = (self.head + 1) % N;
+                self.len -= 1;
+                self.position = add(segment.start, segment.delta);
+                if self.len == 0 {
+                    self.speed = 0;
+                }
+                continue;
+            }
+
+            let done = self.done as i64;
+            for axis in 0..AXES {
+                let offset = segment.delta[axis] as i64 * done / segment.length as i64;
+                self.position[axis] = segment.start[axis].wrapping_add(offset as i32);
+            }
+        }
+        self.position
+    }
+
+    fn last(&self) -> Option<&Segment> {
+        if self.len == 0 {
+            return None;
+        }
+        Some(&self.segments[(self.head + self.len - 1) % N])
+    }
+
+    fn next(&self) -> Option<&Segment> {
+        if self.len < 2 {
+            return None;
+        }
+        Some(&self.segments[(self.head + 1) % N])
+    }
+
+    /// Speed through the corner between `prev` and a segment along `unit`
+    fn junction_speed(&self, prev: &Segment, unit: &[i16; AXES], speed: u32) -> u32 {
+        let limit = speed.min(prev.speed);
+        let mut dot = 0i64;
+        for axis in 0..AXES {
+            dot += prev.unit[axis] as i64 * unit[axis] as i64;
+        }
+        // cos of the angle between the reversed incoming and the outgoing
+        // direction: -1 straight on, +1 full reversal
+        let cos = -(dot / Q14);
+        if cos >= COS_LIMIT {
+            return 0;
+        }
+        if cos <= -COS_LIMIT {
+            return limit;
+        }
+
+        // v^2 = a * deviation * sin(theta/2) / (1 - sin(theta/2))
+        let sin_half = isqrt_u64((((Q14 - cos) / 2) * Q14) as u64) as u64;
+        let v2 = self.config.accel as u64 * self.config.junction_deviation as u64 * sin_half / (Q14 as u64 - sin_half).max(1);
+        (isqrt_u64(v2) as u32).min(limit)
+    }
+
+    fn plan(&mut self) {
+        let accel2 = 2 * self.config.accel as u64;
+
+        // Backwards from a stop at the end of the queue. The running
+        // segment's entry is history and stays as it is.
+        let mut exit = 0u64;
+        for i in (1..self.len).rev() {
+            let segment = &mut self.segments[(self.head + i) % N];
+            let entry = isqrt_u64(exit * exit + accel2 * segment.length as u64).min(segment.max_entry as u64);
+            segment.entry = entry as u32;
+            exit = entry;
+        }
+
+        // Forwards from the speed the running segment can reach
+        let head = self.segments[self.head];
+        let mut reachable = isqrt_u64(self.speed as u64 * self.speed as u64 + accel2 * (head.length - self.done) as u64);
+        for i in 1..self.len {
+            let index = (self.head + i) % N;
+            let segment = &mut self.segments[index];
+            if segment.entry as u64 > reachable {
+                segment.entry = reachable as u32;
+            }
+            reachable = isqrt_u64(segment.entry as u64 * segment.entry as u64 + accel2 * segment.length as u64);
+        }
+    }
+}
+
+fn add(a: [i32; AXES], b: [i32; AXES]) -> [i32; AXES] {
+    let mut sum = a;
+    for axis in 0..AXES {
+        sum[axis] = sum[axis].wrapping_add(b[axis]);
+    }
+    sum
+}
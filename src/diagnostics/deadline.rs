@@ -0,0 +1,135 @@
+//! Soft real-time deadline monitor
+//!
+//! Periodic activities (control loop, sensor sampling, ...) call `mark()` each
+//! time they start a new cycle. The monitor measures the period actually
+//! achieved against the nominal one and raises `ErrorCode::TimingError` with
+//! the measured deviation when it exceeds the tolerance.
+
+#![no_std]
+
+use super::{Diagnostics, ErrorCode};
+
+const MAX_ACTIVITIES: usize = 8;
+
+#[derive(Debug)]
+pub enum DeadlineError {
+    TooManyActivities,
+    InvalidActivity,
+}
+
+/// Per-activity timing statistics
+#[derive(Clone, Copy)]
+pub struct ActivityStats {
+    pub name: &'static str,
+    pub period_us: u32,
+    pub tolerance_us: u32,
+    pub max_jitter_us: u32,
+    pub overruns: u32,
+    pub cycles: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Activity {
+    stats: ActivityStats,
+    last_start_us: u32,
+    started: bool,
+}
+
+pub struct DeadlineMonitor {
+    activities: [Option<Activity>; MAX_ACTIVITIES],
+}
+
+impl DeadlineMonitor {
+    pub const fn new() -> Self {
+        Self {
+            activities: [None; MAX_ACTIVITIES],
+        }
+    }
+
+    /// Register a periodic activity, returns its id
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        period_us: u32,
+        tolerance_us: u32,
+    ) -> Result<usize, DeadlineError> {
+        for (id, slot) in self.activities.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some(Activity {
+                    stats: ActivityStats {
+                        name,
+                        period_us,
+                        tolerance_us,
+                        max_jitter_us: 0,
+                        overruns: 0,
+                        cycles: 0,
+                    },
+                    last_start_us: 0,
+                    started: false,
+                });
+                return Ok(id);
+            }
+        }
+        Err(DeadlineError::TooManyActivities)
+    }
+
+    /// Record the start of a new cycle of activity `id` at `now_us`
+    /// (microsecond clock). Reports a TimingError when the period deviates
+    /// from nominal by more than the tolerance.
+    pub fn mark(
+        &mut self,
+        id: usize,
+        now_us: u32,
+        diagnostics: &mut Diagnostics,
+    ) -> Result<(), DeadlineError> {
+        let activity = self
+            .activities
+            .get_mut(id)
+            .and_then(|a| a.as_mut())
+            .ok_or(DeadlineError::InvalidActivity)?;
+
+        if activity.started {
+            let elapsed = now_us.wrapping_sub(activity.last_start_us);
+            let jitter = if elapsed > activity.stats.period_us {
+                elapsed - activity.stats.period_us
+            } else {
+                activity.stats.period_us - elapsed
+            };
+
+            activity.stats.cycles = activity.stats.cycles.wrapping_add(1);
+            if jitter > activity.stats.max_jitter_us {
+                activity.stats.max_jitter_us = jitter;
+            }
+
+            if jitter > activity.stats.tolerance_us {
+                activity.stats.overruns = activity.stats.overruns.wrapping_add(1);
+                // Subcode carries the activity id, data the measured deviation
+                diagnostics.report_error(ErrorCode::TimingError, id as u16, jitter);
+            }
+        }
+
+        activity.last_start_us = now_us;
+        activity.started = true;
+        Ok(())
+    }
+
+    pub fn stats(&self, id: usize) -> Option<ActivityStats> {
+        self.activities.get(id).copied().flatten().map(|a| a.stats)
+    }
+
+    /// Clear the measured statistics but keep the registrations
+    pub fn reset_stats(&mut self) {
+        for activity in self.activities.iter_mut().flatten() {
+            activity.stats.max_jitter_us = 0;
+            activity.stats.overruns = 0;
+            activity.stats.cycles = 0;
+            activity.started = false;
+        }
+    }
+}
+
+impl Default for DeadlineMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,291 @@
+//! Memory inspection from the serial console
+//!
+//! ```text
+//! dump ram <addr> <len>          internal SRAM
+//! dump eeprom [addr] [len]       internal EEPROM, all 4KB by default
+//! dump flash <sector> [len]      external flash, one 4KB sector by default
+//! ```
+//!
+//! Numbers are decimal or `0x` hex. Every dump prints 16 bytes per row
+//! with an ASCII gutter and ends with the CRC-16/CCITT-FALSE of the region,
+//! so a layout can be compared against what the host expects without
+//! reading it row by row:
+//!
+//! ```text
+//! 00010000  A5 5A 01 00 10 27 00 00  00 00 00 00 FF FF FF FF  |.Z...'..........|
+//! crc16 0x3C1F, 16 bytes
+//! ```
+//!
+//! A 4KB dump is over 20KB of text, which takes seconds to send and would
+//! overrun the transmit buffer many times over. `process_line` therefore
+//! only checks the arguments and returns a `DumpJob`, which prints as much
+//! as the console takes from a background slot:
+//!
+//! ```ignore
+//! background::run_slice(&mut dump, &mut (&mut console, Some(&mut flash)), 150);
+//! ```
+#![no_std]
+
+use crate::drivers::flash::{Flash, SECTOR_SIZE};
+use crate::drivers::SerialConsole;
+use crate::hal::eeprom;
+use crate::os::background::{Job, Progress, Step};
+use crate::pgm_str;
+use crate::protocol::crc::{crc16_update, CRC16_INIT};
+
+const ROW_LEN: usize = 16;
+// "AAAAAAAA " + 16 " XX" + gap + "  |" + 16 chars + "|\r\n"
+const LINE_LEN: usize = 9 + ROW_LEN * 3 + 1 + 3 + ROW_LEN + 3;
+
+/// Internal SRAM, the register and I/O space below it is left out because
+/// reading some I/O registers (UDR, TWDR) has side effects
+pub const SRAM_START: u32 = 0x0100;
+pub const SRAM_END: u32 = 0x1100;
+pub const EEPROM_SIZE: u32 = 4096;
+/// W25Q128, 16MB
+pub const FLASH_SECTORS: u32 = 4096;
+
+/// Memory a dump reads from
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DumpRegion {
+    Ram,
+    Eeprom,
+    Flash,
+}
+
+/// Parse a console line and return the dump it asks for. Lines for other
+/// commands give `None`, as do bad arguments after printing why.
+pub fn process_line(line: &str, console: &mut SerialConsole) -> Option<DumpJob> {
+    let mut args = line.split_whitespace();
+    if args.next() != Some("dump") {
+        return None;
+    }
+
+    let region = args.next();
+    let first = args.next().map(parse_number);
+    let second = args.next().map(parse_number);
+
+    match (region, first, second) {
+        (Some("ram"), Some(Some(addr)), Some(Some(len))) => {
+            if addr < SRAM_START || addr.saturating_add(len) > SRAM_END {
+                console.write_pgm_line(pgm_str!("outside SRAM (0x0100..0x1100)"));
+                return None;
+            }
+            Some(DumpJob::new(DumpRegion::Ram, addr, len))
+        }
+        (Some("eeprom"), addr, len) => {
+            let addr = match addr {
+                None => Some(0),
+                Some(addr) => addr,
+            };
+            let (addr, len) = match (addr, len) {
+                (Some(addr), None) => (addr, EEPROM_SIZE.saturating_sub(addr)),
+                (Some(addr), Some(Some(len))) => (addr, len),
+                _ => return usage(console),
+            };
+            if addr.saturating_add(len) > EEPROM_SIZE {
+                console.write_pgm_line(pgm_str!("outside EEPROM (4096 bytes)"));
+                return None;
+            }
+            Some(DumpJob::new(DumpRegion::Eeprom, addr, len))
+        }
+        (Some("flash"), Some(Some(sector)), len) => {
+            let len = match len {
+                None => SECTOR_SIZE as u32,
+                Some(Some(len)) => len,
+                Some(None) => return usage(console),
+            };
+            if sector >= FLASH_SECTORS || len > ((FLASH_SECTORS - sector) * SECTOR_SIZE as u32) {
+                console.write_pgm_line(pgm_str!("outside flash"));
+                return None;
+            }
+            Some(DumpJob::new(DumpRegion::Flash, sector * SECTOR_SIZE as u32, len))
+        }
+        _ => usage(console),
+    }
+}
+
+fn usage(console: &mut SerialConsole) -> Option<DumpJob> {
+    console.write_pgm_line(pgm_str!("usage: dump ram <addr> <len>|eeprom [addr] [len]|flash <sector> [len]"));
+    None
+}
+
+/// Prints `len` bytes from `addr` as rows of `ROW_LEN`, followed by their
+/// CRC. Each step formats one line and hands the console only the bytes
+/// it has room for, so nothing is dropped and the loop never waits on the
+/// UART. A failed read ends the dump early.
+pub struct DumpJob {
+    region: DumpRegion,
+    addr: u32,
+    len: u32,
+    done: u32,
+    crc: u16,
+    line: [u8; LINE_LEN],
+    line_len: usize,
+    sent: usize,
+    summarized: bool,
+}
+
+impl DumpJob {
+    pub const fn new(region: DumpRegion, addr: u32, len: u32) -> Self {
+        Self {
+            region,
+            addr,
+            len,
+            done: 0,
+            crc: CRC16_INIT,
+            line: [0; LINE_LEN],
+            line_len: 0,
+            sent: 0,
+            summarized: false,
+        }
+    }
+
+    fn read_row(&self, flash: Option<&mut Flash>, addr: u32, row: &mut [u8]) -> bool {
+        match self.region {
+            DumpRegion::Ram => {
+                for (i, byte) in row.iter_mut().enumerate() {
+                    // Inside SRAM, checked by `process_line`
+                    *byte = unsafe { core::ptr::read_volatile((addr as usize + i) as *const u8) };
+                }
+                true
+            }
+            DumpRegion::Eeprom => {
+                for (i, byte) in row.iter_mut().enumerate() {
+                    *byte = eeprom::read_byte((addr as usize + i) as u16);
+                }
+                true
+            }
+            DumpRegion::Flash => flash.map_or(false, |flash| flash.read(addr, row).is_ok()),
+        }
+    }
+
+    // Format the next row, or the CRC line after the last one
+    fn next_line(&mut self, flash: Option<&mut Flash>) {
+        self.line_len = 0;
+        self.sent = 0;
+
+        if self.done == self.len {
+            self.push_str("crc16 0x");
+            self.push_hex((self.crc >> 8) as u8);
+            self.push_hex(self.crc as u8);
+            self.push_str(", ");
+            self.push_decimal(self.done);
+            self.push_str(" bytes\r\n");
+            self.summarized = true;
+            return;
+        }
+
+        let mut row = [0u8; ROW_LEN];
+        let count = (self.len - self.done).min(ROW_LEN as u32) as usize;
+        let row = &mut row[..count];
+        let addr = self.addr + self.done;
+        if !self.read_row(flash, addr, row) {
+            self.push_str("read error\r\n");
+            // The CRC line covers what was printed
+            self.len = self.done;
+            return;
+        }
+
+        for byte in addr.to_be_bytes() {
+            self.push_hex(byte);
+        }
+        self.push_str(" ");
+        for i in 0..ROW_LEN {
+            if i == ROW_LEN / 2 {
+                self.push_str(" ");
+            }
+            match row.get(i) {
+                Some(&byte) => {
+                    self.push_str(" ");
+                    self.push_hex(byte);
+                }
+                None => self.push_str("   "),
+            }
+        }
+        self.push_str("  |");
+        for &byte in row.iter() {
+            self.crc = crc16_update(self.crc, byte);
+            self.push(if (0x20..0x7F).contains(&byte) { byte } else { b'.' });
+        }
+        self.push_str("|\r\n");
+
+        self.done += count as u32;
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.line[self.line_len] = byte;
+        self.line_len += 1;
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for &byte in s.as_bytes() {
+            self.push(byte);
+        }
+    }
+
+    fn push_hex(&mut self, value: u8) {
+        const HEX_CHARS: [u8; 16] = *b"0123456789ABCDEF";
+        self.push(HEX_CHARS[(value >> 4) as usize]);
+        self.push(HEX_CHARS[(value & 0xF) as usize]);
+    }
+
+    fn push_decimal(&mut self, value: u32) {
+        let mut digits = [0u8; 10];
+        let mut n = 0;
+        let mut value = value;
+        loop {
+            digits[n] = b'0' + (value % 10) as u8;
+            n += 1;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        for i in (0..n).rev() {
+            self.push(digits[i]);
+        }
+    }
+}
+
+impl Job for DumpJob {
+    /// The console to print to and the external flash, if the board has it
+    type Context<'a> = (&'a mut SerialConsole, Option<&'a mut Flash>);
+
+    fn name(&self) -> &'static str {
+        "dump"
+    }
+
+    fn step(&mut self, (console, flash): &mut Self::Context<'_>) -> Step {
+        if self.sent == self.line_len {
+            if self.summarized {
+                return Step::Done;
+            }
+            self.next_line(flash.as_deref_mut());
+        }
+        while self.sent < self.line_len && console.is_tx_ready() {
+            console.write_byte(self.line[self.sent]);
+            self.sent += 1;
+        }
+        if self.summarized && self.sent == self.line_len {
+            Step::Done
+        } else {
+            Step::Pending
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        Progress {
+            done: self.done,
+            total: self.len,
+        }
+    }
+}
+
+/// Decimal or `0x` prefixed hex
+fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
@@ -0,0 +1,267 @@
+//! Fault injection for the flash and TWI error paths
+//!
+//! With the `fault-injection` feature the flash driver and the blocking and
+//! queued TWI operations ask `trip` before doing any work, and fail with
+//! their usual error when a fault is armed for them. This is how the
+//! logger's sector wear handling, the calibration fallback to defaults and
+//! the MPU6050 bus recovery are exercised on the bench, where the real
+//! parts hardly ever fail.
+//!
+//! ```text
+//! fault                                     armed faults and how often each fired
+//! fault <site> <n> [count] [error]          fail the nth operation from now
+//! fault clear
+//! ```
+//!
+//! Sites are `read`, `write` and `erase` for the flash and `twi` for the
+//! bus. `count` faults in a row are injected, 1 by default, 0 until the
+//! site is cleared. The TWI error is `nack` (default), `arb`, `bus`,
+//! `timeout` or `stretch`.
+//!
+//! For the HIL runs, `self_test` is registered with the `SelfTestRunner`:
+//! the host arms the faults, drives the scenario and then runs the test,
+//! which fails with a bitmask of the sites whose faults never fired, i.e.
+//! error paths the scenario did not reach. Without the feature `trip` is
+//! always false and the console only reports that.
+#![no_std]
+
+#[cfg(feature = "fault-injection")]
+use avr_device::interrupt::Mutex;
+#[cfg(feature = "fault-injection")]
+use core::cell::RefCell;
+
+use crate::drivers::SerialConsole;
+use crate::hal::twi::TwiError;
+use crate::pgm_str;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum FaultSite {
+    FlashRead = 0,
+    FlashWrite = 1,
+    FlashErase = 2,
+    Twi = 3,
+}
+
+const SITES: usize = 4;
+const SITE_NAMES: [&str; SITES] = ["read", "write", "erase", "twi"];
+const ALL_SITES: [FaultSite; SITES] = [FaultSite::FlashRead, FaultSite::FlashWrite, FaultSite::FlashErase, FaultSite::Twi];
+
+impl FaultSite {
+    pub fn name(self) -> &'static str {
+        SITE_NAMES[self as usize]
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        SITE_NAMES.iter().position(|&n| n == name).map(|i| ALL_SITES[i])
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Arming {
+    /// Operations still let through before the first fault
+    skip: u16,
+    /// Faults still to inject, 0 until cleared
+    remaining: u16,
+    unlimited: bool,
+    fired: u16,
+}
+
+#[cfg(feature = "fault-injection")]
+struct Faults {
+    sites: [Option<Arming>; SITES],
+    twi_error: TwiError,
+}
+
+#[cfg(feature = "fault-injection")]
+static FAULTS: Mutex<RefCell<Faults>> = Mutex::new(RefCell::new(Faults {
+    sites: [None; SITES],
+    twi_error: TwiError::Nack,
+}));
+
+/// Whether the hooks are compiled in
+pub const fn is_available() -> bool {
+    cfg!(feature = "fault-injection")
+}
+
+/// Fail the `nth` operation on `site` from now (1 is the next one) and
+/// `count - 1` more after it; `count` 0 fails every one until `disarm`
+pub fn arm(site: FaultSite, nth: u16, count: u16) {
+    #[cfg(feature = "fault-injection")]
+    avr_device::interrupt::free(|cs| {
+        FAULTS.borrow(cs).borrow_mut().sites[site as usize] = Some(Arming {
+            skip: nth.saturating_sub(1),
+            remaining: count,
+            unlimited: count == 0,
+            fired: 0,
+        });
+    });
+    #[cfg(not(feature = "fault-injection"))]
+    let _ = (site, nth, count);
+}
+
+/// Error the TWI operations fail with
+pub fn set_twi_error(error: TwiError) {
+    #[cfg(feature = "fault-injection")]
+    avr_device::interrupt::free(|cs| FAULTS.borrow(cs).borrow_mut().twi_error = error);
+    #[cfg(not(feature = "fault-injection"))]
+    let _ = error;
+}
+
+pub fn disarm(site: FaultSite) {
+    #[cfg(feature = "fault-injection")]
+    avr_device::interrupt::free(|cs| FAULTS.borrow(cs).borrow_mut().sites[site as usize] = None);
+    #[cfg(not(feature = "fault-injection"))]
+    let _ = site;
+}
+
+pub fn clear() {
+    #[cfg(feature = "fault-injection")]
+    avr_device::interrupt::free(|cs| FAULTS.borrow(cs).borrow_mut().sites = [None; SITES]);
+}
+
+/// Faults injected on `site` since it was armed
+pub fn fired(site: FaultSite) -> u16 {
+    arming(site).map_or(0, |a| a.fired)
+}
+
+#[cfg(feature = "fault-injection")]
+fn arming(site: FaultSite) -> Option<Arming> {
+    avr_device::interrupt::free(|cs| FAULTS.borrow(cs).borrow().sites[site as usize])
+}
+
+#[cfg(not(feature = "fault-injection"))]
+fn arming(_site: FaultSite) -> Option<Arming> {
+    None
+}
+
+/// Count one operation on `site`, true if it has to fail
+#[cfg(feature = "fault-injection")]
+pub fn trip(site: FaultSite) -> bool {
+    avr_device::interrupt::free(|cs| {
+        let mut faults = FAULTS.borrow(cs).borrow_mut();
+        let arming = match faults.sites[site as usize].as_mut() {
+            Some(arming) => arming,
+            None => return false,
+        };
+        if arming.skip > 0 {
+            arming.skip -= 1;
+            return false;
+        }
+        if !arming.unlimited {
+            if arming.remaining == 0 {
+                return false;
+            }
+            arming.remaining -= 1;
+        }
+        arming.fired = arming.fired.saturating_add(1);
+        true
+    })
+}
+
+#[cfg(not(feature = "fault-injection"))]
+#[inline(always)]
+pub fn trip(_site: FaultSite) -> bool {
+    false
+}
+
+/// `trip` for the TWI, with the error to fail with
+#[inline(always)]
+pub fn trip_twi() -> Option<TwiError> {
+    if !trip(FaultSite::Twi) {
+        return None;
+    }
+    #[cfg(feature = "fault-injection")]
+    return Some(avr_device::interrupt::free(|cs| FAULTS.borrow(cs).borrow().twi_error));
+    #[cfg(not(feature = "fault-injection"))]
+    None
+}
+
+/// Self-test for the HIL runner, the detail is a bitmask of the armed
+/// sites (bit = `FaultSite`) that never fired
+pub fn self_test() -> Result<(), u32> {
+    let missed = ALL_SITES
+        .iter()
+        .filter(|&&site| arming(site).map_or(false, |a| a.fired == 0))
+        .fold(0u32, |mask, &site| mask | 1 << site as u8);
+    if missed == 0 {
+        Ok(())
+    } else {
+        Err(missed)
+    }
+}
+
+/// Execute a console line, ignoring lines for other commands
+pub fn process_line(line: &str, console: &mut SerialConsole) {
+    let mut args = line.split_whitespace();
+    if args.next() != Some("fault") {
+        return;
+    }
+    if !is_available() {
+        console.write_pgm_line(pgm_str!("fault injection not built in"));
+        return;
+    }
+
+    match args.next() {
+        None => {
+            for site in ALL_SITES {
+                if let Some(arming) = arming(site) {
+                    console.write_str(site.name());
+                    console.write_str(": fired ");
+                    console.write_decimal(arming.fired as u32);
+                    if arming.unlimited {
+                        console.write_str(", until cleared");
+                    } else {
+                        console.write_str(", ");
+                        console.write_decimal(arming.remaining as u32);
+                        console.write_str(" left");
+                    }
+                    if arming.skip > 0 {
+                        console.write_str(" after ");
+                        console.write_decimal(arming.skip as u32);
+                        console.write_str(" more");
+                    }
+                    console.write_str("\r\n");
+                }
+            }
+            console.write_pgm_line(pgm_str!("ok"));
+        }
+        Some("clear") => {
+            clear();
+            console.write_pgm_line(pgm_str!("ok"));
+        }
+        Some(name) => {
+            let site = FaultSite::parse(name);
+            let nth = args.next().and_then(|s| s.parse::<u16>().ok()).filter(|&n| n > 0);
+            let mut count = Some(1);
+            let mut error = Some(TwiError::Nack);
+            for arg in args {
+                match arg.parse::<u16>() {
+                    Ok(n) => count = Some(n),
+                    Err(_) => error = parse_twi_error(arg),
+                }
+            }
+            match (site, nth, count, error) {
+                (Some(site), Some(nth), Some(count), Some(error)) => {
+                    if site == FaultSite::Twi {
+                        set_twi_error(error);
+                    }
+                    arm(site, nth, count);
+                    console.write_pgm_line(pgm_str!("ok"));
+                }
+                _ => console.write_pgm_line(pgm_str!("usage: fault [clear|read|write|erase|twi <n> [count] [nack|arb|bus|timeout|stretch]]")),
+            }
+        }
+    }
+}
+
+fn parse_twi_error(name: &str) -> Option<TwiError> {
+    match name {
+        "nack" => Some(TwiError::Nack),
+        "arb" => Some(TwiError::ArbitrationLost),
+        "bus" => Some(TwiError::BusError),
+        "timeout" => Some(TwiError::Timeout),
+        "stretch" => Some(TwiError::ClockStretch),
+        _ => None,
+    }
+}
@@ -0,0 +1,272 @@
+//! Flash integrity audit
+//!
+//! A background job that runs a CRC-32 over the application and bootloader
+//! sections of the program flash and over the configuration and
+//! calibration sectors of the external flash, and compares each against a
+//! reference
+//! kept in EEPROM (`config::EEPROM_AUDIT_ADDR`). A bit flipped by a weak
+//! cell or a stray write shows up as a mismatch, which
+//! `Diagnostics::poll_flash_audit` logs as a memory error with subcode
+//! `0x04xx` (xx = `AuditRegion`) and the CRC found as data.
+//!
+//! There is no build step producing the references: a region without one
+//! gets its first CRC stored as the reference. Code that legitimately
+//! rewrites a region calls `mark_written`, which drops the reference so the
+//! next pass learns the new contents. A pass that a write overlaps learns
+//! nothing and leaves it to the pass after. After a firmware update the
+//! application region is marked the same way.
+//!
+//! The event log ring and the sensor data log are not audited: they are
+//! appended to all the time, so a pass would hardly ever finish unwritten.
+//!
+//! ```ignore
+//! let mut audit = FlashAuditJob::new();
+//! frame.run(jobs_id, || { background::run_slice(&mut audit, &mut flash, 200); }).ok();
+//! ```
+#![no_std]
+
+use avr_device::interrupt::Mutex;
+use core::cell::Cell;
+
+use crate::config::{EEPROM_AUDIT_ADDR, FLASH_CALIBRATION, FLASH_CRON, FLASH_POWER, FLASH_SAMPLING, FLASH_SECTOR_SIZE};
+use crate::drivers::flash::Flash;
+use crate::hal::eeprom;
+use crate::hal::progmem;
+use crate::os::background::{Job, Progress, Step};
+use crate::protocol::crc::{crc32_finish, crc32_update, CRC32_INIT};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum AuditRegion {
+    Application = 0,
+    Bootloader = 1,
+    Cron = 2,
+    Sampling = 3,
+    Power = 4,
+    Calibration = 5,
+    CalibrationCopy = 6,
+}
+
+const REGIONS: usize = 7;
+const REGION_NAMES: [&str; REGIONS] = ["app", "boot", "cron", "sampling", "power", "calib", "calib2"];
+const ALL_REGIONS: [AuditRegion; REGIONS] = [
+    AuditRegion::Application,
+    AuditRegion::Bootloader,
+    AuditRegion::Cron,
+    AuditRegion::Sampling,
+    AuditRegion::Power,
+    AuditRegion::Calibration,
+    AuditRegion::CalibrationCopy,
+];
+
+impl AuditRegion {
+    pub fn name(self) -> &'static str {
+        REGION_NAMES[self as usize]
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+struct Span {
+    /// Program flash rather than the external flash
+    internal: bool,
+    start: u32,
+    len: u32,
+}
+
+const SPANS: [Span; REGIONS] = [
+    // Below the 8KB boot section (BOOTSZ = 00)
+    Span { internal: true, start: 0x00000, len: 0x1E000 },
+    Span { internal: true, start: 0x1E000, len: 0x02000 },
+    Span { internal: false, start: FLASH_CRON, len: FLASH_SECTOR_SIZE },
+    Span { internal: false, start: FLASH_SAMPLING, len: FLASH_SECTOR_SIZE },
+    Span { internal: false, start: FLASH_POWER, len: FLASH_SECTOR_SIZE },
+    Span { internal: false, start: FLASH_CALIBRATION[0], len: FLASH_SECTOR_SIZE },
+    Span { internal: false, start: FLASH_CALIBRATION[1], len: FLASH_SECTOR_SIZE },
+];
+
+// EEPROM layout: [valid mask, 3 spare, reference CRC (u32 LE) per region]
+const VALID_ADDR: u16 = EEPROM_AUDIT_ADDR;
+const REFERENCE_ADDR: u16 = EEPROM_AUDIT_ADDR + 4;
+
+// Bytes per step, about 150us of bitwise CRC-32
+const AUDIT_CHUNK: u32 = 32;
+
+/// A region that no longer matches its reference
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Mismatch {
+    pub region: AuditRegion,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+/// Regions written since their current pass began
+static WRITTEN: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+static LAST_MISMATCH: Mutex<Cell<Option<Mismatch>>> = Mutex::new(Cell::new(None));
+
+/// Drop the reference of a region that is about to change, call it from
+/// the code that writes the region
+pub fn mark_written(region: AuditRegion) {
+    let valid = eeprom::read_byte(VALID_ADDR);
+    if valid & region.bit() != 0 {
+        eeprom::update_byte(VALID_ADDR, valid & !region.bit());
+    }
+    avr_device::interrupt::free(|cs| {
+        let written = WRITTEN.borrow(cs);
+        written.set(written.get() | region.bit());
+    });
+}
+
+/// Drop the references of every region on the external flash, after a
+/// chip erase
+pub fn mark_external_written() {
+    for region in ALL_REGIONS {
+        if !SPANS[region as usize].internal {
+            mark_written(region);
+        }
+    }
+}
+
+/// Most recent mismatch since the last call, for
+/// `Diagnostics::poll_flash_audit`
+pub fn take_mismatch() -> Option<Mismatch> {
+    avr_device::interrupt::free(|cs| LAST_MISMATCH.borrow(cs).take())
+}
+
+/// Stored reference of a region, `None` until a pass has learned it
+pub fn reference(region: AuditRegion) -> Option<u32> {
+    if eeprom::read_byte(VALID_ADDR) & region.bit() == 0 {
+        return None;
+    }
+    let addr = REFERENCE_ADDR + 4 * region as u16;
+    Some(u32::from_le_bytes([
+        eeprom::read_byte(addr),
+        eeprom::read_byte(addr + 1),
+        eeprom::read_byte(addr + 2),
+        eeprom::read_byte(addr + 3),
+    ]))
+}
+
+fn store_reference(region: AuditRegion, crc: u32) {
+    let addr = REFERENCE_ADDR + 4 * region as u16;
+    for (i, byte) in crc.to_le_bytes().iter().enumerate() {
+        eeprom::update_byte(addr + i as u16, *byte);
+    }
+    eeprom::update_byte(VALID_ADDR, eeprom::read_byte(VALID_ADDR) | region.bit());
+}
+
+/// One pass over all regions
+pub struct FlashAuditJob {
+    region: usize,
+    offset: u32,
+    crc: u32,
+    mismatches: u8,
+}
+
+impl FlashAuditJob {
+    pub const fn new() -> Self {
+        Self {
+            region: 0,
+            offset: 0,
+            crc: CRC32_INIT,
+            mismatches: 0,
+        }
+    }
+
+    /// Regions that mismatched so far in this pass, a bit per
+    /// `AuditRegion`
+    pub fn mismatches(&self) -> u8 {
+        self.mismatches
+    }
+
+    fn finish_region(&mut self) {
+        let region = ALL_REGIONS[self.region];
+        let actual = crc32_finish(self.crc);
+        match reference(region) {
+            Some(expected) if expected != actual => {
+                self.mismatches |= region.bit();
+                let mismatch = Mismatch {
+                    region,
+                    expected,
+                    actual,
+                };
+                avr_device::interrupt::free(|cs| LAST_MISMATCH.borrow(cs).set(Some(mismatch)));
+            }
+            Some(_) => {}
+            None => {
+                let written = avr_device::interrupt::free(|cs| WRITTEN.borrow(cs).get() & region.bit() != 0);
+                if !written {
+                    store_reference(region, actual);
+                }
+            }
+        }
+    }
+}
+
+impl Default for FlashAuditJob {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Job for FlashAuditJob {
+    type Context<'a> = Flash;
+
+    fn name(&self) -> &'static str {
+        "flash audit"
+    }
+
+    fn step(&mut self, flash: &mut Flash) -> Step {
+        let span = match SPANS.get(self.region) {
+            Some(span) => span,
+            None => return Step::Done,
+        };
+        if self.offset == 0 {
+            let bit = ALL_REGIONS[self.region].bit();
+            avr_device::interrupt::free(|cs| {
+                let written = WRITTEN.borrow(cs);
+                written.set(written.get() & !bit);
+            });
+        }
+
+        let count = (span.len - self.offset).min(AUDIT_CHUNK);
+        let addr = span.start + self.offset;
+        if span.internal {
+            for a in addr..addr + count {
+                // Below 0x20000 by the span table
+                self.crc = crc32_update(self.crc, unsafe { progmem::read_far_byte(a) });
+            }
+        } else {
+            let mut chunk = [0u8; AUDIT_CHUNK as usize];
+            let chunk = &mut chunk[..count as usize];
+            if flash.read(addr, chunk).is_err() {
+                return Step::Failed(addr);
+            }
+            self.crc = chunk.iter().fold(self.crc, |crc, &b| crc32_update(crc, b));
+        }
+        self.offset += count;
+
+        if self.offset < span.len {
+            return Step::Pending;
+        }
+        self.finish_region();
+        self.region += 1;
+        self.offset = 0;
+        self.crc = CRC32_INIT;
+        if self.region == REGIONS {
+            Step::Done
+        } else {
+            Step::Pending
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        let done = SPANS[..self.region].iter().map(|s| s.len).sum::<u32>() + self.offset;
+        Progress {
+            done,
+            total: SPANS.iter().map(|s| s.len).sum(),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Periodic health report packet
+//!
+//! Sends a `Command::Heartbeat` packet every few seconds whether or not the
+//! host is polling, so monitoring systems notice when a deployed node goes
+//! quiet. Poll it from the main loop next to the watchdog feed: if the loop
+//! hangs, the heartbeats stop before the watchdog resets the node.
+//!
+//! Payload (little endian):
+//! `sequence u16, safety state u8, error count u32, battery mV u16,
+//! cpu load % u8, uptime s u32`
+#![no_std]
+
+use super::Diagnostics;
+use crate::protocol::{Command, Protocol, Result};
+use crate::safety;
+
+pub const HEARTBEAT_SIZE: usize = 14;
+
+/// Values the caller measures for the report
+#[derive(Clone, Copy, Default)]
+pub struct HeartbeatStatus {
+    pub battery_mv: u16,
+    pub cpu_load: u8,
+}
+
+pub struct Heartbeat {
+    period_ms: u32,
+    last_ms: u32,
+    sequence: u16,
+}
+
+impl Heartbeat {
+    pub fn new(period_s: u16) -> Self {
+        Self {
+            period_ms: period_s as u32 * 1000,
+            last_ms: 0,
+            sequence: 0,
+        }
+    }
+
+    pub fn set_period(&mut self, period_s: u16) {
+        self.period_ms = period_s as u32 * 1000;
+    }
+
+    /// Build the next report
+    pub fn encode(&mut self, now_ms: u32, diag: &Diagnostics, status: HeartbeatStatus) -> [u8; HEARTBEAT_SIZE] {
+        let mut data = [0u8; HEARTBEAT_SIZE];
+        data[0..2].copy_from_slice(&self.sequence.to_le_bytes());
+        data[2] = safety::state() as u8;
+        data[3..7].copy_from_slice(&diag.get_error_count().to_le_bytes());
+        data[7..9].copy_from_slice(&status.battery_mv.to_le_bytes());
+        data[9] = status.cpu_load.min(100);
+        data[10..14].copy_from_slice(&(now_ms / 1000).to_le_bytes());
+        self.sequence = self.sequence.wrapping_add(1);
+        data
+    }
+
+    /// Send a heartbeat if one is due. Returns true if a packet went out.
+    pub fn poll(
+        &mut self,
+        now_ms: u32,
+        diag: &Diagnostics,
+        status: HeartbeatStatus,
+        protocol: &mut Protocol,
+    ) -> Result<bool> {
+        if self.period_ms == 0 || now_ms.wrapping_sub(self.last_ms) < self.period_ms {
+            return Ok(false);
+        }
+        self.last_ms = now_ms;
+
+        let data = self.encode(now_ms, diag, status);
+        protocol.send_packet(Command::Heartbeat, &data)?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,181 @@
+//! Interrupt latency and tick jitter measurement
+//!
+//! Timer2 is run in CTC mode with a fixed period. Because the counter is
+//! cleared on the compare match, the value of TCNT2 read at the top of the
+//! compare ISR is exactly the time between the scheduled event and the ISR
+//! actually running. Collecting that over a window gives worst-case latency,
+//! jitter (max - min) and a distribution histogram, so the ISR budget can be
+//! re-validated after adding drivers.
+//!
+//! Timer2 must not be used by anything else while a measurement is running.
+
+#![no_std]
+
+use avr_device::atmega128::TC2;
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
+
+use crate::config::CPU_FREQ_HZ;
+use crate::hal::claims::{self, Resource};
+use crate::hal::clock;
+
+/// Timer2 prescaler used for the measurement (CS21 = clk/8)
+const PRESCALER: u32 = 8;
+const TCCR2_CTC_DIV8: u8 = 0x0A;
+/// Compare value giving a 100us sampling period (199 at 16MHz)
+const SAMPLE_PERIOD_COUNTS: u8 = (clock::us_to_counts(100, PRESCALER) - 1) as u8;
+const _: () = assert!(clock::us_to_counts(100, PRESCALER) - 1 <= u8::MAX as u32);
+const OCIE2: u8 = 1 << 7;
+
+pub const NUM_BUCKETS: usize = 8;
+/// Upper bucket edges in microseconds, the last bucket catches everything else
+pub const BUCKET_EDGES_US: [u16; NUM_BUCKETS - 1] = [1, 2, 4, 8, 16, 32, 64];
+
+/// Result of a measurement window
+#[derive(Clone, Copy)]
+pub struct LatencyReport {
+    pub samples: u16,
+    pub min_counts: u8,
+    pub max_counts: u8,
+    pub buckets: [u16; NUM_BUCKETS],
+}
+
+impl LatencyReport {
+    const fn new() -> Self {
+        Self {
+            samples: 0,
+            min_counts: u8::MAX,
+            max_counts: 0,
+            buckets: [0; NUM_BUCKETS],
+        }
+    }
+
+    /// Worst-case latency in microseconds
+    pub fn max_latency_us(&self) -> u16 {
+        counts_to_us(self.max_counts)
+    }
+
+    /// Best-case latency in microseconds
+    pub fn min_latency_us(&self) -> u16 {
+        if self.samples == 0 {
+            0
+        } else {
+            counts_to_us(self.min_counts)
+        }
+    }
+
+    /// Tick jitter (spread between best and worst case) in microseconds
+    pub fn jitter_us(&self) -> u16 {
+        self.max_latency_us() - self.min_latency_us()
+    }
+}
+
+struct LatencyState {
+    report: LatencyReport,
+    window: u16,
+    running: bool,
+}
+
+static STATE: Mutex<RefCell<LatencyState>> = Mutex::new(RefCell::new(LatencyState {
+    report: LatencyReport::new(),
+    window: 0,
+    running: false,
+}));
+
+fn counts_to_us(counts: u8) -> u16 {
+    ((counts as u32 * PRESCALER * 1_000_000) / CPU_FREQ_HZ) as u16
+}
+
+fn bucket_for(latency_us: u16) -> usize {
+    BUCKET_EDGES_US
+        .iter()
+        .position(|&edge| latency_us < edge)
+        .unwrap_or(NUM_BUCKETS - 1)
+}
+
+pub struct LatencyMonitor {
+    _private: (),
+}
+
+impl LatencyMonitor {
+    pub fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// Start a measurement window of `samples` timer events
+    pub fn start(&mut self, samples: u16) {
+        claims::claim(Resource::Timer2, "latency").ok();
+        avr_device::interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+            state.report = LatencyReport::new();
+            state.window = samples;
+            state.running = true;
+        });
+
+        unsafe {
+            let p = TC2::ptr();
+            (*p).tccr2.write(|w| w.bits(0));
+            (*p).tcnt2.write(|w| w.bits(0));
+            (*p).ocr2.write(|w| w.bits(SAMPLE_PERIOD_COUNTS));
+            (*p).timsk.modify(|r, w| w.bits(r.bits() | OCIE2));
+            (*p).tccr2.write(|w| w.bits(TCCR2_CTC_DIV8));
+        }
+    }
+
+    /// Abort the measurement and release Timer2
+    pub fn stop(&mut self) {
+        stop_timer();
+        avr_device::interrupt::free(|cs| {
+            STATE.borrow(cs).borrow_mut().running = false;
+        });
+        claims::release(Resource::Timer2, "latency");
+    }
+
+    pub fn is_done(&self) -> bool {
+        avr_device::interrupt::free(|cs| !STATE.borrow(cs).borrow().running)
+    }
+
+    /// Snapshot of the current (or last finished) window
+    pub fn report(&self) -> LatencyReport {
+        avr_device::interrupt::free(|cs| STATE.borrow(cs).borrow().report)
+    }
+}
+
+impl Default for LatencyMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn stop_timer() {
+    unsafe {
+        let p = TC2::ptr();
+        (*p).tccr2.write(|w| w.bits(0));
+        (*p).timsk.modify(|r, w| w.bits(r.bits() & !OCIE2));
+    }
+}
+
+#[avr_device::interrupt(atmega128)]
+fn TIMER2_COMP() {
+    // Sample the counter first, everything after this is bookkeeping
+    let counts = unsafe { (*TC2::ptr()).tcnt2.read().bits() };
+
+    avr_device::interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        if !state.running {
+            return;
+        }
+
+        let report = &mut state.report;
+        report.samples += 1;
+        report.min_counts = report.min_counts.min(counts);
+        report.max_counts = report.max_counts.max(counts);
+        let bucket = bucket_for(counts_to_us(counts));
+        report.buckets[bucket] = report.buckets[bucket].saturating_add(1);
+
+        if report.samples >= state.window {
+            state.running = false;
+            stop_timer();
+        }
+    });
+}
@@ -0,0 +1,292 @@
+//! March C- test of the unused RAM
+//!
+//! Tests the SRAM between the end of the static data and the stack reserve
+//! (`config::STACK_RESERVE` below the top of SRAM, which the interrupts'
+//! stack use has to fit in), plus the external RAM when the XMEM interface
+//! is enabled (SRE in MCUCR). Neither holds data, so the test writes
+//! freely and restores nothing.
+//!
+//! March C- runs six elements over every cell, with `1` the inverse of the
+//! background `0`:
+//!
+//! ```text
+//! up(w0) up(r0,w1) up(r1,w0) down(r0,w1) down(r1,w0) up(r0)
+//! ```
+//!
+//! which finds stuck-at, transition, address decoder and coupling faults
+//! between bytes. It is repeated with the backgrounds 0x00, 0x55, 0x33 and
+//! 0x0F for coupling between the bits of a byte.
+//!
+//! The test runs as an `os::background::Job`, a few dozen cells per step,
+//! started at boot. Its state is static, so `Diagnostics::check_memory`
+//! can finish a run still in progress instead of starting a second one
+//! over the same cells.
+#![no_std]
+
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
+
+use crate::config::STACK_RESERVE;
+use crate::hal::regs::mcucr;
+use crate::hal::watchdog;
+use crate::os::background::{Job, Progress, Step};
+
+const SRAM_START: u16 = 0x0100;
+const SRAM_END: u16 = 0x1100;
+/// External RAM above the internal SRAM
+const XMEM_START: u16 = 0x1100;
+const XMEM_END: u32 = 0x10000;
+
+const BACKGROUNDS: [u8; 4] = [0x00, 0x55, 0x33, 0x0F];
+
+struct Element {
+    up: bool,
+    /// Expect background (false) or inverse (true)
+    read: Option<bool>,
+    write: Option<bool>,
+}
+
+const ELEMENTS: [Element; 6] = [
+    Element { up: true, read: None, write: Some(false) },
+    Element { up: true, read: Some(false), write: Some(true) },
+    Element { up: true, read: Some(true), write: Some(false) },
+    Element { up: false, read: Some(false), write: Some(true) },
+    Element { up: false, read: Some(true), write: Some(false) },
+    Element { up: true, read: Some(false), write: None },
+];
+
+// Cells per step, about 60us on internal SRAM
+const STEP_CELLS: u32 = 64;
+
+/// A cell that read back wrong
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MemoryFault {
+    pub addr: u16,
+    /// Lowest failing bit
+    pub bit: u8,
+    pub expected: u8,
+    pub actual: u8,
+}
+
+impl MemoryFault {
+    /// `addr << 16 | expected << 8 | actual`, the diagnostics data word
+    pub fn detail(&self) -> u32 {
+        (self.addr as u32) << 16 | (self.expected as u32) << 8 | self.actual as u32
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Region {
+    start: u16,
+    /// Exclusive, 0x10000 for the top of XMEM
+    end: u32,
+}
+
+impl Region {
+    fn len(&self) -> u32 {
+        self.end.saturating_sub(self.start as u32)
+    }
+}
+
+struct MarchState {
+    regions: [Option<Region>; 2],
+    region: usize,
+    background: usize,
+    element: usize,
+    position: u32,
+    cells_done: u32,
+    cells_total: u32,
+    result: Option<Result<(), MemoryFault>>,
+}
+
+static MARCH: Mutex<RefCell<MarchState>> = Mutex::new(RefCell::new(MarchState {
+    regions: [None; 2],
+    region: 0,
+    background: 0,
+    element: 0,
+    position: 0,
+    cells_done: 0,
+    cells_total: 0,
+    result: None,
+}));
+
+/// Handle to the memory test, the state itself is static
+pub struct MemoryTest {
+    _private: (),
+}
+
+impl MemoryTest {
+    /// (Re)start the test over the unused SRAM and the XMEM, if enabled
+    pub fn start() -> Self {
+        let regions = [Some(unused_sram()), xmem()];
+        let total = regions.iter().flatten().map(|r| r.len()).sum::<u32>() * (BACKGROUNDS.len() * ELEMENTS.len()) as u32;
+        avr_device::interrupt::free(|cs| {
+            *MARCH.borrow(cs).borrow_mut() = MarchState {
+                regions,
+                region: 0,
+                background: 0,
+                element: 0,
+                position: 0,
+                cells_done: 0,
+                cells_total: total,
+                result: None,
+            };
+        });
+        Self { _private: () }
+    }
+
+    /// Outcome of the last finished run, `None` while one is in progress
+    /// or none was started
+    pub fn result() -> Option<Result<(), MemoryFault>> {
+        avr_device::interrupt::free(|cs| MARCH.borrow(cs).borrow().result)
+    }
+
+    /// Whether a run was started and has not finished
+    pub fn is_running() -> bool {
+        avr_device::interrupt::free(|cs| {
+            let state = MARCH.borrow(cs).borrow();
+            state.result.is_none() && state.cells_total > 0
+        })
+    }
+
+    /// Finish the current run (or a new one if none is running) here and
+    /// now, feeding the watchdog between steps
+    pub fn run_to_end() -> Result<(), MemoryFault> {
+        if !Self::is_running() {
+            if let Some(result) = Self::result() {
+                return result;
+            }
+            Self::start();
+        }
+        loop {
+            if let Some(result) = march_step() {
+                return result;
+            }
+            watchdog::feed();
+        }
+    }
+}
+
+impl Job for MemoryTest {
+    type Context<'a> = ();
+
+    fn name(&self) -> &'static str {
+        "march c-"
+    }
+
+    fn step(&mut self, _context: &mut ()) -> Step {
+        match march_step() {
+            None => Step::Pending,
+            Some(Ok(())) => Step::Done,
+            Some(Err(fault)) => Step::Failed(fault.detail()),
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        avr_device::interrupt::free(|cs| {
+            let state = MARCH.borrow(cs).borrow();
+            Progress {
+                done: state.cells_done,
+                total: state.cells_total,
+            }
+        })
+    }
+}
+
+/// Bytes of SRAM taken by statics, from the start of SRAM to the end of
+/// .bss as placed by the linker
+pub fn static_ram_bytes() -> u16 {
+    static_ram_end() - SRAM_START
+}
+
+fn static_ram_end() -> u16 {
+    extern "C" {
+        static __bss_end: u8;
+    }
+    unsafe { &__bss_end as *const u8 as usize as u16 }
+}
+
+/// From the end of the static data to the stack reserve
+fn unused_sram() -> Region {
+    let start = static_ram_end();
+    Region {
+        start,
+        end: (SRAM_END as u32).saturating_sub(STACK_RESERVE as u32).max(start as u32),
+    }
+}
+
+fn xmem() -> Option<Region> {
+    let enabled = unsafe { (*avr_device::atmega128::CPU::ptr()).mcucr.read().bits() & mcucr::SRE != 0 };
+    enabled.then_some(Region {
+        start: XMEM_START,
+        end: XMEM_END,
+    })
+}
+
+/// Test up to `STEP_CELLS` cells, `Some` once the run has finished
+fn march_step() -> Option<Result<(), MemoryFault>> {
+    avr_device::interrupt::free(|cs| {
+        let mut state = MARCH.borrow(cs).borrow_mut();
+        if state.result.is_some() {
+            return state.result;
+        }
+
+        for _ in 0..STEP_CELLS {
+            let region = match state.regions.get(state.region).copied() {
+                Some(Some(region)) => region,
+                Some(None) => {
+                    state.region += 1;
+                    continue;
+                }
+                None => {
+                    state.result = Some(Ok(()));
+                    return state.result;
+                }
+            };
+            if state.position >= region.len() {
+                state.position = 0;
+                state.element += 1;
+                if state.element == ELEMENTS.len() {
+                    state.element = 0;
+                    state.background += 1;
+                    if state.background == BACKGROUNDS.len() {
+                        state.background = 0;
+                        state.region += 1;
+                    }
+                }
+                continue;
+            }
+
+            let element = &ELEMENTS[state.element];
+            let background = BACKGROUNDS[state.background];
+            let value = |inverse: bool| if inverse { !background } else { background };
+            let addr = if element.up {
+                region.start as u32 + state.position
+            } else {
+                region.end - 1 - state.position
+            } as u16;
+            let cell = addr as usize as *mut u8;
+
+            if let Some(inverse) = element.read {
+                let expected = value(inverse);
+                let actual = unsafe { core::ptr::read_volatile(cell) };
+                if actual != expected {
+                    let fault = MemoryFault {
+                        addr,
+                        bit: (actual ^ expected).trailing_zeros() as u8,
+                        expected,
+                        actual,
+                    };
+                    state.result = Some(Err(fault));
+                    return state.result;
+                }
+            }
+            if let Some(inverse) = element.write {
+                unsafe { core::ptr::write_volatile(cell, value(inverse)) };
+            }
+            state.position += 1;
+            state.cells_done += 1;
+        }
+        None
+    })
+}
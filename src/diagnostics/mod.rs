@@ -1,11 +1,66 @@
 //! Error handling and diagnostics system
+//!
+//! The last `ERROR_RING_LEN` errors are kept in RAM and offered to the host
+//! as `ErrorEntry` records, instance 0 being the newest.
 #![no_std]
 
+pub mod deadline;
+pub mod dump;
+pub mod fault_inject;
+pub mod flash_audit;
+pub mod heartbeat;
+pub mod latency;
+pub mod memtest;
+pub mod morse;
+pub mod power_profile;
+pub mod selftest;
+pub mod vibration;
+pub mod watch;
+
+use crate::config::SUPPLY_SENSE_MIN_MV;
+use crate::drivers::flash::Flash;
+use crate::drivers::lm75::Lm75;
+use crate::hal::twi::{self, TwiError};
+use crate::hal::board_id;
+use crate::hal::systime;
+use crate::hal::claims::{self, Conflict};
+use crate::hal::reset::{self, ResetReason};
+use crate::hal::{adc, AdcArbiter, AdcReference, Twi};
 use crate::logger::Logger;
-use core::sync::atomic::{AtomicU32, Ordering};
+use memtest::MemoryTest;
+use crate::safety;
+use crate::shutdown::{self, ShutdownReason};
+use crate::stats::Accumulator;
+use crate::atomic::AtomicU32;
+use crate::protocol::records::{self, RecordType, SUBSYSTEM_ERRORS};
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
 
 static ERROR_COUNT: AtomicU32 = AtomicU32::new(0);
 
+/// Errors kept for the `ErrorEntry` record
+pub const ERROR_RING_LEN: usize = 8;
+
+const RECORD_ERROR: u8 = 0x01;
+
+// Worst case of a log flush, a sector erase plus one buffer of writes
+const LOG_FLUSH_COST_MS: u16 = 150;
+
+struct ErrorRing {
+    entries: [Option<Error>; ERROR_RING_LEN],
+    head: usize,
+}
+
+static ERROR_RING: Mutex<RefCell<ErrorRing>> = Mutex::new(RefCell::new(ErrorRing {
+    entries: [None; ERROR_RING_LEN],
+    head: 0,
+}));
+
+/// Errors reported since boot, for code without the `Diagnostics` at hand
+pub fn error_count() -> u32 {
+    ERROR_COUNT.load()
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum ErrorCode {
     HardwareFault = 0x1000,
@@ -26,21 +81,77 @@ pub struct Error {
     data: u32,
 }
 
+impl Error {
+    /// `[code u32, subcode u16, timestamp u32, data u32]`, LE
+    fn encode(&self, out: &mut [u8; 14]) {
+        out[0..4].copy_from_slice(&(self.code as u32).to_le_bytes());
+        out[4..6].copy_from_slice(&self.subcode.to_le_bytes());
+        out[6..10].copy_from_slice(&self.timestamp.to_le_bytes());
+        out[10..14].copy_from_slice(&self.data.to_le_bytes());
+    }
+}
+
 pub struct Diagnostics {
     logger: Logger,
     last_error: Option<Error>,
     watchdog_enabled: bool,
+    loop_time: Accumulator,
+    reset_reason: ResetReason,
 }
 
 impl Diagnostics {
     pub fn new(logger: Logger) -> Self {
+        records::register(
+            SUBSYSTEM_ERRORS,
+            RECORD_ERROR,
+            "errors",
+            RecordType::ErrorEntry,
+            ERROR_RING_LEN as u8,
+            read_error,
+        )
+        .ok();
         Self {
             logger,
             last_error: None,
             watchdog_enabled: false,
+            // Main loop time in microseconds
+            loop_time: Accumulator::with_histogram(0.0, 8000.0),
+            reset_reason: reset::reason(),
         }
     }
 
+    /// Flush the error log as part of every shutdown, after the outputs
+    /// are off.
+    ///
+    /// # Safety
+    ///
+    /// The shutdown hook keeps the address of the logger, so `self` must
+    /// not move or be dropped afterwards; main calls this once it has put
+    /// the diagnostics in place for good.
+    pub unsafe fn register_shutdown_hooks(&mut self) {
+        let logger = &mut self.logger as *mut Logger as usize;
+        shutdown::register_with_context("log flush", 128, LOG_FLUSH_COST_MS, flush_log, logger).ok();
+    }
+
+    /// The external flash holding the error log
+    pub fn flash(&mut self) -> &mut Flash {
+        self.logger.flash()
+    }
+
+    /// Record the duration of one main loop iteration
+    pub fn record_loop_time(&mut self, us: u32) {
+        self.loop_time.push(us as f32);
+    }
+
+    pub fn loop_time(&self) -> &Accumulator {
+        &self.loop_time
+    }
+
+    /// For `stats reset`, which works on the registry's copy
+    pub fn loop_time_mut(&mut self) -> &mut Accumulator {
+        &mut self.loop_time
+    }
+
     pub fn report_error(&mut self, code: ErrorCode, subcode: u16, data: u32) {
         let error = Error {
             code,
@@ -50,24 +161,94 @@ impl Diagnostics {
         };
 
         self.last_error = Some(error);
-        ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
+        ERROR_COUNT.fetch_add(1);
+        avr_device::interrupt::free(|cs| {
+            let mut ring = ERROR_RING.borrow(cs).borrow_mut();
+            let head = ring.head;
+            ring.entries[head] = Some(error);
+            ring.head = (head + 1) % ERROR_RING_LEN;
+        });
 
         let mut error_data = [0u8; 16];
-        error_data[0..4].copy_from_slice(&(error.code as u32).to_le_bytes());
-        error_data[4..6].copy_from_slice(&error.subcode.to_le_bytes());
-        error_data[6..10].copy_from_slice(&error.timestamp.to_le_bytes());
-        error_data[10..14].copy_from_slice(&error.data.to_le_bytes());
+        let mut encoded = [0u8; 14];
+        error.encode(&mut encoded);
+        error_data[..14].copy_from_slice(&encoded);
 
         self.logger.log_error(&error_data).ok();
         self.handle_error(&error);
     }
 
+    pub fn reset_reason(&self) -> ResetReason {
+        self.reset_reason
+    }
+
+    /// Log the cause of the last reset as a system entry `[b'R', reason,
+    /// flags]`. A brown-out is also reported as a power error and a
+    /// watchdog or unexplained reset as a timing error, both with subcode
+    /// `0x02xx` (xx = `ResetReason`) and the MCUCSR flags as data.
+    pub fn report_reset_reason(&mut self) {
+        let reason = self.reset_reason;
+        self.logger.log_system(&[b'R', reason as u8, reset::flags()]).ok();
+        let code = match reason {
+            ResetReason::BrownOut => ErrorCode::PowerError,
+            _ if reason.is_fault() => ErrorCode::TimingError,
+            _ => return,
+        };
+        self.report_error(code, 0x0200 | reason as u16, reset::flags() as u32);
+    }
+
+    /// Log the latest TWI bus error, if any, as a communication error with
+    /// subcode `0x02xx` (xx = `TwiError` value). Call from the main loop.
+    pub fn poll_twi_errors(&mut self) {
+        if let Some(error) = twi::take_last_error() {
+            self.report_twi_error(error);
+        }
+    }
+
+    pub fn report_twi_error(&mut self, error: TwiError) {
+        self.report_error(ErrorCode::CommunicationError, 0x0200 | error as u16, 0);
+    }
+
+    /// Log a flash region that no longer matches its reference CRC as a
+    /// memory error with subcode `0x04xx` (xx = `AuditRegion`) and the CRC
+    /// found as data. Call from the main loop.
+    pub fn poll_flash_audit(&mut self) {
+        if let Some(mismatch) = flash_audit::take_mismatch() {
+            self.report_error(ErrorCode::MemoryError, 0x0400 | mismatch.region as u16, mismatch.actual);
+        }
+    }
+
+    /// Log a refused hardware claim as a hardware fault with subcode
+    /// `0x03xx` (xx = `Resource::id`), followed by a debug entry holding
+    /// both owners' names. Call after the drivers are set up.
+    pub fn poll_claim_conflicts(&mut self) {
+        if let Some(conflict) = claims::take_conflict() {
+            self.report_claim_conflict(&conflict);
+        }
+    }
+
+    pub fn report_claim_conflict(&mut self, conflict: &Conflict) {
+        // "owner>claimant", cut to one log entry
+        let mut names = [0u8; 16];
+        let mut len = 0;
+        let parts = [conflict.owner.as_bytes(), b">", conflict.claimant.as_bytes()];
+        for &byte in parts.iter().flat_map(|p| p.iter()) {
+            if len == names.len() {
+                break;
+            }
+            names[len] = byte;
+            len += 1;
+        }
+        self.logger.log_debug(&names[..len]).ok();
+        self.report_error(ErrorCode::HardwareFault, 0x0300 | conflict.resource.id() as u16, 0);
+    }
+
     pub fn get_last_error(&self) -> Option<Error> {
         self.last_error
     }
 
     pub fn get_error_count(&self) -> u32 {
-        ERROR_COUNT.load(Ordering::Relaxed)
+        error_count()
     }
 
     pub fn enable_watchdog(&mut self) {
@@ -90,14 +271,19 @@ impl Diagnostics {
 
     pub fn reset_watchdog(&self) {
         if self.watchdog_enabled {
-            unsafe {
-                avr_device::asm::wdr();
-            }
+            crate::hal::watchdog::feed();
         }
     }
 
-    pub fn run_diagnostics(&mut self) -> Result<(), Error> {
-        self.check_voltage()?;
+    pub fn run_diagnostics(&mut self, adc: &mut AdcArbiter) -> Result<(), Error> {
+        let result = self.run_checks(adc);
+        // Arming is only allowed after a passed self-test
+        safety::set_self_test_result(result.is_ok());
+        result
+    }
+
+    fn run_checks(&mut self, adc: &mut AdcArbiter) -> Result<(), Error> {
+        self.check_voltage(adc)?;
         self.check_temperature()?;
         self.check_memory()?;
         self.check_peripherals()?;
@@ -121,6 +307,7 @@ impl Diagnostics {
 
     fn handle_power_error(&mut self, error: &Error) {
         if error.subcode & 0xFF00 == 0x0100 {
+            shutdown::run(ShutdownReason::LowVoltage);
             self.enter_low_power_mode();
         }
     }
@@ -129,152 +316,82 @@ impl Diagnostics {
         self.reset_system();
     }
 
-    fn check_voltage(&self) -> Result<(), Error> {
-        unsafe {
-            let adc = &(*avr_device::atmega128::ADC::ptr());
-            adc.admux.write(|w| w.bits(0x40));
-            adc.adcsra.write(|w| w.bits(0x87));
-            while adc.adcsra.read().bits() & 0x10 == 0 {}
-            let value = adc.adcl.read().bits() as u16 | ((adc.adch.read().bits() as u16) << 8);
-            
-            if value < 300 {
-                return Err(Error {
-                    code: ErrorCode::PowerError,
-                    subcode: 0x0101,
-                    timestamp: self.get_timestamp(),
-                    data: value as u32,
-                });
-            }
+    fn check_voltage(&self, adc: &mut AdcArbiter) -> Result<(), Error> {
+        let value = adc.convert_blocking(board_id::config().supply_channel, AdcReference::Avcc);
+        let mv = adc::counts_to_mv(value);
+
+        if mv < SUPPLY_SENSE_MIN_MV {
+            return Err(Error {
+                code: ErrorCode::PowerError,
+                subcode: 0x0101,
+                timestamp: self.get_timestamp(),
+                data: mv as u32,
+            });
         }
         Ok(())
     }
 
     fn check_temperature(&self) -> Result<(), Error> {
-        let mut lm75_temp: i16 = 0;
-        unsafe {
-            let twi = &(*avr_device::atmega128::TWI::ptr());
-            
-            // Start + SLA+W
-            twi.twcr.write(|w| w.bits(0xA4));
-            while twi.twcr.read().bits() & 0x80 == 0 {}
-            if twi.twsr.read().bits() & 0xF8 != 0x18 {
-                return Err(Error {
-                    code: ErrorCode::SensorError,
-                    subcode: 0x0102,
-                    timestamp: self.get_timestamp(),
-                    data: twi.twsr.read().bits() as u32,
-                });
-            }
-            
-            // Read temperature from external LM75
-            twi.twdr.write(|w| w.bits(0x00));
-            twi.twcr.write(|w| w.bits(0x84));
-            while twi.twcr.read().bits() & 0x80 == 0 {}
-            
-            lm75_temp = (twi.twdr.read().bits() as i16) << 8;
-            twi.twcr.write(|w| w.bits(0x84));
-            while twi.twcr.read().bits() & 0x80 == 0 {}
-            
-            lm75_temp |= twi.twdr.read().bits() as i16;
-            twi.twcr.write(|w| w.bits(0x94));
-            
-            if lm75_temp > 85 * 256 { // 85°C max temperature
-                return Err(Error {
-                    code: ErrorCode::SystemError,
-                    subcode: 0x0102,
-                    timestamp: self.get_timestamp(),
-                    data: lm75_temp as u32,
-                });
-            }
+        // Through the driver so a stuck bus times out instead of hanging
+        let mut lm75 = Lm75::new(Twi::new(), board_id::config().lm75_addr);
+        let temp = lm75.read_temperature().map_err(|_| Error {
+            code: ErrorCode::SensorError,
+            subcode: 0x0102,
+            timestamp: self.get_timestamp(),
+            data: twi::take_last_error().map_or(0, |e| e as u32),
+        })?;
+
+        if temp > 850 { // 85°C max temperature
+            return Err(Error {
+                code: ErrorCode::SystemError,
+                subcode: 0x0102,
+                timestamp: self.get_timestamp(),
+                data: temp as u32,
+            });
         }
         Ok(())
     }
 
+    /// Result of the boot-time March C- test, finished here if it is
+    /// still running in the background
     fn check_memory(&self) -> Result<(), Error> {
-        extern "C" {
-            static _heap_start: u8;
-            static _heap_end: u8;
-        }
-        
-        unsafe {
-            let heap_start = &_heap_start as *const u8 as usize;
-            let heap_end = &_heap_end as *const u8 as usize;
-            let test_size = core::cmp::min(32, heap_end - heap_start);
-            
-            let test_patterns: [u8; 4] = [0x55, 0xAA, 0x33, 0xCC];
-            
-            for i in 0..test_size {
-                for &pattern in test_patterns.iter() {
-                    let addr = (heap_start + i) as *mut u8;
-                    core::ptr::write_volatile(addr, pattern);
-                    let readback = core::ptr::read_volatile(addr);
-                    
-                    if readback != pattern {
-                        return Err(Error {
-                            code: ErrorCode::MemoryError,
-                            subcode: 0x0103,
-                            timestamp: self.get_timestamp(),
-                            data: ((i as u32) << 16) | ((pattern as u32) << 8) | readback as u32,
-                        });
-                    }
-                }
-            }
-        }
-        Ok(())
+        MemoryTest::run_to_end().map_err(|fault| Error {
+            code: ErrorCode::MemoryError,
+            subcode: 0x0103,
+            timestamp: self.get_timestamp(),
+            data: fault.detail(),
+        })
     }
 
+    /// Timer0 is the systime tick, so check it advances rather than
+    /// reprogramming it. Needs `systime::start` and interrupts enabled.
     fn check_peripherals(&self) -> Result<(), Error> {
-        unsafe {
-            let timer = &(*avr_device::atmega128::TC0::ptr());
-            
-            // Stop timer and clear settings
-            timer.tccr0.write(|w| w.bits(0x00));
-            timer.tcnt0.write(|w| w.bits(0x00));
-            
-            // Configure for normal mode, no prescaling
-            timer.tccr0.write(|w| w.bits(0x01));
-            
-            // Wait for a few ticks
-            let start = timer.tcnt0.read().bits();
-            let mut timeout = 1000;
-            
-            while timer.tcnt0.read().bits() <= start && timeout > 0 {
-                timeout -= 1;
-            }
-            
+        let fault = |data| Error {
+            code: ErrorCode::HardwareFault,
+            subcode: 0x0104,
+            timestamp: self.get_timestamp(),
+            data,
+        };
+        if !systime::is_running() {
+            return Err(fault(0x01));
+        }
+
+        // Two ticks, so a tick landing right after the first read counts
+        // as one; 100000 polls take well over 2ms at any supported clock
+        let start = systime::millis();
+        let mut timeout = 100_000u32;
+        while systime::millis().wrapping_sub(start) < 2 {
+            timeout -= 1;
             if timeout == 0 {
-                return Err(Error {
-                    code: ErrorCode::HardwareFault,
-                    subcode: 0x0104,
-                    timestamp: self.get_timestamp(),
-                    data: 0x01,
-                });
-            }
-            
-            // Stop timer
-            timer.tccr0.write(|w| w.bits(0x00));
-            
-            // Test output compare functionality
-            timer.tcnt0.write(|w| w.bits(0x00));
-            timer.ocr0.write(|w| w.bits(0x80));
-            timer.tccr0.write(|w| w.bits(0x08)); // CTC mode
-            
-            if timer.tcnt0.read().bits() > 0x80 {
-                return Err(Error {
-                    code: ErrorCode::HardwareFault,
-                    subcode: 0x0104,
-                    timestamp: self.get_timestamp(),
-                    data: 0x02,
-                });
+                return Err(fault(0x02));
             }
-            
-            // Cleanup
-            timer.tccr0.write(|w| w.bits(0x00));
         }
         Ok(())
     }
 
     fn emergency_shutdown(&mut self) {
+        safety::estop();
+        shutdown::run(ShutdownReason::EmergencyStop);
         self.logger.flush().ok();
         unsafe {
             let pmx = &(*avr_device::atmega128::PMX::ptr());
@@ -304,7 +421,24 @@ impl Diagnostics {
     }
 
     fn get_timestamp(&self) -> u32 {
-        // TODO: Implement real timestamp
-        0
+        systime::millis()
     }
 }
+
+// Instance 0 is the newest error
+fn read_error(index: u8, out: &mut [u8]) -> Option<usize> {
+    let error = avr_device::interrupt::free(|cs| {
+        let ring = ERROR_RING.borrow(cs).borrow();
+        let slot = (ring.head + ERROR_RING_LEN - 1 - index as usize) % ERROR_RING_LEN;
+        ring.entries[slot]
+    })?;
+    let out: &mut [u8; 14] = out.get_mut(..14)?.try_into().ok()?;
+    error.encode(out);
+    Some(14)
+}
+
+// Registered by `register_shutdown_hooks` with the logger's address
+fn flush_log(_reason: ShutdownReason, logger: usize) {
+    let logger = unsafe { &mut *(logger as *mut Logger) };
+    logger.flush().ok();
+}
@@ -0,0 +1,217 @@
+//! Morse beacon on the status LED and a buzzer
+//!
+//! Sends a short ASCII message (a unit name, a fault code) as Morse so a
+//! particular board can be picked out among many by eye or by ear, with
+//! no host attached. `tick` is called from the main loop and switches the
+//! outputs when the next element is due, nothing blocks.
+//!
+//! With the safe mode beacon enabled the last message repeats for as long
+//! as the safety state machine sits in `Fault` or `EStop`.
+//!
+//! Letters, digits and spaces are sent, anything else counts as a space.
+//! Timing follows the PARIS standard: a dot is 1200 / wpm ms, a dash three
+//! dots, and the gaps are one dot inside a letter, three between letters
+//! and seven between words.
+#![no_std]
+
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::safety::{self, SafetyState};
+
+pub const MAX_MESSAGE: usize = 24;
+
+pub const DEFAULT_WPM: u8 = 12;
+
+// Element bits first element in the LSB, 1 = dash, above the last element
+// a 1 marks the length
+const LETTERS: [u8; 26] = [
+    0b110, 0b10001, 0b10101, 0b1001, 0b10, 0b10100, 0b1011, 0b10000, 0b100, 0b11110, 0b1101, 0b10010, 0b111,
+    0b101, 0b1111, 0b10110, 0b11011, 0b1010, 0b1000, 0b11, 0b1100, 0b11000, 0b1110, 0b11001, 0b11101, 0b10011,
+];
+const DIGITS: [u8; 10] = [
+    0b111111, 0b111110, 0b111100, 0b111000, 0b110000, 0b100000, 0b100001, 0b100011, 0b100111, 0b101111,
+];
+
+// Gap after a letter is 3 units; a space adds 4 for the 7 unit word gap
+const WORD_EXTRA_UNITS: u16 = 4;
+// Between repeats of a message
+const REPEAT_UNITS: u16 = 14;
+
+fn code(c: u8) -> Option<u8> {
+    match c.to_ascii_uppercase() {
+        c @ b'A'..=b'Z' => Some(LETTERS[(c - b'A') as usize]),
+        c @ b'0'..=b'9' => Some(DIGITS[(c - b'0') as usize]),
+        _ => None,
+    }
+}
+
+pub struct MorseBeacon<LED, BUZZER> {
+    led: LED,
+    buzzer: Option<BUZZER>,
+    unit_ms: u32,
+    message: [u8; MAX_MESSAGE],
+    len: usize,
+    pos: usize,
+    /// Elements left of the current letter, with the length marker
+    symbol: u8,
+    on: bool,
+    active: bool,
+    repeat: bool,
+    safe_mode: bool,
+    step_start: u32,
+    step_ms: u32,
+}
+
+impl<LED: OutputPin, BUZZER: OutputPin> MorseBeacon<LED, BUZZER> {
+    pub fn new(led: LED, buzzer: Option<BUZZER>) -> Self {
+        let mut beacon = Self {
+            led,
+            buzzer,
+            unit_ms: 1200 / DEFAULT_WPM as u32,
+            message: [0; MAX_MESSAGE],
+            len: 0,
+            pos: 0,
+            symbol: 1,
+            on: false,
+            active: false,
+            repeat: false,
+            safe_mode: false,
+            step_start: 0,
+            step_ms: 0,
+        };
+        beacon.set_output(false);
+        beacon
+    }
+
+    pub fn set_wpm(&mut self, wpm: u8) {
+        self.unit_ms = 1200 / wpm.max(1) as u32;
+    }
+
+    /// Repeat the last message while the system is in `Fault` or `EStop`
+    pub fn set_safe_mode_beacon(&mut self, enabled: bool) {
+        self.safe_mode = enabled;
+    }
+
+    /// Start sending `text`, cut to `MAX_MESSAGE` characters, once or over
+    /// and over until `stop`
+    pub fn send(&mut self, text: &str, repeat: bool, now_ms: u32) {
+        let bytes = text.as_bytes();
+        self.len = bytes.len().min(MAX_MESSAGE);
+        self.message[..self.len].copy_from_slice(&bytes[..self.len]);
+        self.repeat = repeat;
+        self.restart(now_ms);
+    }
+
+    /// Send a fault code as `E` and its decimal digits, e.g. "E4097"
+    pub fn send_code(&mut self, code: u16, repeat: bool, now_ms: u32) {
+        let mut text = [0u8; 6];
+        text[0] = b'E';
+        let mut digits = [0u8; 5];
+        let mut n = 0;
+        let mut value = code;
+        loop {
+            digits[n] = b'0' + (value % 10) as u8;
+            value /= 10;
+            n += 1;
+            if value == 0 {
+                break;
+            }
+        }
+        for i in 0..n {
+            text[1 + i] = digits[n - 1 - i];
+        }
+        self.len = n + 1;
+        self.message[..self.len].copy_from_slice(&text[..self.len]);
+        self.repeat = repeat;
+        self.restart(now_ms);
+    }
+
+    pub fn stop(&mut self) {
+        self.active = false;
+        self.repeat = false;
+        self.set_output(false);
+    }
+
+    pub fn is_sending(&self) -> bool {
+        self.active
+    }
+
+    /// Advance the beacon, call from the main loop with the system tick
+    pub fn tick(&mut self, now_ms: u32) {
+        if !self.active {
+            let safe = matches!(safety::state(), SafetyState::Fault | SafetyState::EStop);
+            if self.safe_mode && safe && self.len > 0 {
+                self.restart(now_ms);
+            }
+            return;
+        }
+        if now_ms.wrapping_sub(self.step_start) < self.step_ms {
+            return;
+        }
+        self.step_start = now_ms;
+        self.next_step();
+    }
+
+    fn restart(&mut self, now_ms: u32) {
+        self.pos = 0;
+        self.symbol = 1;
+        self.active = self.len > 0;
+        self.set_output(false);
+        self.step_start = now_ms;
+        self.step_ms = 0;
+    }
+
+    fn next_step(&mut self) {
+        if self.on {
+            // Gap after the element, longer at the end of the letter
+            self.set_output(false);
+            let units = if self.symbol == 1 { 3 } else { 1 };
+            self.step_ms = units * self.unit_ms;
+            return;
+        }
+
+        let mut extra = 0;
+        while self.symbol == 1 {
+            if self.pos == self.len {
+                if !self.repeat {
+                    self.active = false;
+                    return;
+                }
+                self.pos = 0;
+                self.step_ms = REPEAT_UNITS as u32 * self.unit_ms;
+                return;
+            }
+            let c = self.message[self.pos];
+            self.pos += 1;
+            match code(c) {
+                Some(symbol) => self.symbol = symbol,
+                None => extra += WORD_EXTRA_UNITS,
+            }
+        }
+        if extra > 0 {
+            // Finish the word gap first, the letter starts on the next step
+            self.step_ms = extra as u32 * self.unit_ms;
+            return;
+        }
+
+        let dash = self.symbol & 1 != 0;
+        self.symbol >>= 1;
+        self.set_output(true);
+        self.step_ms = if dash { 3 } else { 1 } * self.unit_ms;
+    }
+
+    fn set_output(&mut self, on: bool) {
+        self.on = on;
+        if on {
+            self.led.set_high().ok();
+            if let Some(buzzer) = self.buzzer.as_mut() {
+                buzzer.set_high().ok();
+            }
+        } else {
+            self.led.set_low().ok();
+            if let Some(buzzer) = self.buzzer.as_mut() {
+                buzzer.set_low().ok();
+            }
+        }
+    }
+}
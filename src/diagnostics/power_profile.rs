@@ -0,0 +1,193 @@
+//! Sleep-state residency profile
+//!
+//! Collects `Power::take_residency` into the current hour and the current
+//! day. Every finished day is appended to a small ring in its own flash
+//! sector, so battery-life regressions from new features show up as a
+//! shrinking sleep share when comparing days. Read out through
+//! `Command::PowerProfile`.
+#![no_std]
+
+use crate::config::FLASH_POWER;
+use crate::diagnostics::flash_audit::{self, AuditRegion};
+use crate::drivers::flash::Flash;
+use crate::hal::power::{Power, Residency, RESIDENCY_STATES};
+use crate::protocol::{ProtocolError, Result};
+
+const MS_PER_HOUR: u32 = 3_600_000;
+const HOURS_PER_DAY: u8 = 24;
+
+const POWER_MAGIC: u16 = 0x5A9C;
+/// Days kept in flash
+pub const MAX_DAYS: usize = 8;
+const HEADER_SIZE: usize = 4;
+const RESIDENCY_SIZE: usize = RESIDENCY_STATES * 4;
+const DAY_SIZE: usize = 2 + RESIDENCY_SIZE;
+
+// Protocol sub-commands carried in the first payload byte of
+// Command::PowerProfile
+const OP_CURRENT_HOUR: u8 = 0x01;
+const OP_LAST_HOUR: u8 = 0x02;
+const OP_TODAY: u8 = 0x03;
+const OP_DAY: u8 = 0x04;
+const OP_RESET: u8 = 0x05;
+
+pub struct PowerProfile {
+    hour: Residency,
+    last_hour: Residency,
+    today: Residency,
+    hours_today: u8,
+    /// Number of the day being collected, continues across resets
+    day: u16,
+}
+
+impl PowerProfile {
+    pub const fn new() -> Self {
+        Self {
+            hour: Residency::new(),
+            last_hour: Residency::new(),
+            today: Residency::new(),
+            hours_today: 0,
+            day: 0,
+        }
+    }
+
+    /// Continue the day numbering after the newest stored day
+    pub fn resume(&mut self, flash: &mut Flash) {
+        if let Some((day, _)) = self.read_day(flash, 0) {
+            self.day = day.wrapping_add(1);
+        }
+    }
+
+    /// Collect the residency since the last call, storing the day once 24
+    /// hours have been counted. Call from the main loop.
+    pub fn update(&mut self, power: &mut Power, flash: &mut Flash) -> core::result::Result<(), ()> {
+        let residency = power.take_residency();
+        self.hour.add(&residency);
+        self.today.add(&residency);
+
+        if self.hour.total_ms() < MS_PER_HOUR {
+            return Ok(());
+        }
+        self.last_hour = core::mem::take(&mut self.hour);
+        self.hours_today += 1;
+
+        if self.hours_today >= HOURS_PER_DAY {
+            let today = core::mem::take(&mut self.today);
+            self.hours_today = 0;
+            self.store_day(flash, &today)?;
+            self.day = self.day.wrapping_add(1);
+        }
+        Ok(())
+    }
+
+    pub fn current_hour(&self) -> &Residency {
+        &self.hour
+    }
+
+    pub fn last_hour(&self) -> &Residency {
+        &self.last_hour
+    }
+
+    pub fn today(&self) -> &Residency {
+        &self.today
+    }
+
+    /// Stored day `age` days back (0 = newest) with its day number
+    pub fn read_day(&self, flash: &mut Flash, age: usize) -> Option<(u16, Residency)> {
+        let mut buffer = [0u8; HEADER_SIZE + MAX_DAYS * DAY_SIZE];
+        flash.read(FLASH_POWER, &mut buffer).ok()?;
+        let (count, next) = parse_header(&buffer)?;
+        if age >= count {
+            return None;
+        }
+
+        let slot = (next + MAX_DAYS - 1 - age) % MAX_DAYS;
+        let record = &buffer[HEADER_SIZE + slot * DAY_SIZE..HEADER_SIZE + (slot + 1) * DAY_SIZE];
+        let day = u16::from_le_bytes([record[0], record[1]]);
+        Some((day, Residency::decode(&record[2..])))
+    }
+
+    /// Handle a `Command::PowerProfile` payload and write the reply into
+    /// `response`.
+    ///
+    /// Residencies are replied as seven u32 LE milliseconds: active, Idle,
+    /// AdcNoiseReduction, PowerDown, PowerSave, Standby, ExtendedStandby.
+    /// `OP_CURRENT_HOUR` prefixes the hours counted today, `OP_DAY` takes
+    /// `[op, age]` and prefixes the day number (u16 LE).
+    pub fn handle_command(&mut self, data: &[u8], flash: &mut Flash, response: &mut [u8]) -> Result<usize> {
+        let op = *data.first().ok_or(ProtocolError::InvalidPacket)?;
+
+        match op {
+            OP_CURRENT_HOUR => {
+                if response.len() < 1 + RESIDENCY_SIZE {
+                    return Err(ProtocolError::BufferOverflow);
+                }
+                response[0] = self.hours_today;
+                self.hour.encode(&mut response[1..]);
+                Ok(1 + RESIDENCY_SIZE)
+            }
+            OP_LAST_HOUR | OP_TODAY => {
+                if response.len() < RESIDENCY_SIZE {
+                    return Err(ProtocolError::BufferOverflow);
+                }
+                let residency = if op == OP_LAST_HOUR { &self.last_hour } else { &self.today };
+                residency.encode(response);
+                Ok(RESIDENCY_SIZE)
+            }
+            OP_DAY => {
+                let age = *data.get(1).ok_or(ProtocolError::InvalidPacket)?;
+                if response.len() < DAY_SIZE {
+                    return Err(ProtocolError::BufferOverflow);
+                }
+                let (day, residency) = self.read_day(flash, age as usize).ok_or(ProtocolError::InvalidPacket)?;
+                response[0..2].copy_from_slice(&day.to_le_bytes());
+                residency.encode(&mut response[2..]);
+                Ok(DAY_SIZE)
+            }
+            OP_RESET => {
+                self.hour = Residency::new();
+                self.last_hour = Residency::new();
+                self.today = Residency::new();
+                self.hours_today = 0;
+                Ok(0)
+            }
+            _ => Err(ProtocolError::InvalidCommand),
+        }
+    }
+
+    fn store_day(&self, flash: &mut Flash, today: &Residency) -> core::result::Result<(), ()> {
+        let mut buffer = [0u8; HEADER_SIZE + MAX_DAYS * DAY_SIZE];
+        flash.read(FLASH_POWER, &mut buffer).map_err(|_| ())?;
+        // Start a fresh ring on an erased or foreign sector
+        let (count, next) = parse_header(&buffer).unwrap_or((0, 0));
+
+        let offset = HEADER_SIZE + next * DAY_SIZE;
+        buffer[offset..offset + 2].copy_from_slice(&self.day.to_le_bytes());
+        today.encode(&mut buffer[offset + 2..offset + DAY_SIZE]);
+
+        buffer[0..2].copy_from_slice(&POWER_MAGIC.to_le_bytes());
+        buffer[2] = (count + 1).min(MAX_DAYS) as u8;
+        buffer[3] = ((next + 1) % MAX_DAYS) as u8;
+
+        flash_audit::mark_written(AuditRegion::Power);
+        flash.erase_sector(FLASH_POWER).map_err(|_| ())?;
+        flash.write(FLASH_POWER, &buffer).map_err(|_| ())?;
+        Ok(())
+    }
+}
+
+impl Default for PowerProfile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Stored day count and next ring slot, `None` for a sector without days
+fn parse_header(buffer: &[u8]) -> Option<(usize, usize)> {
+    if u16::from_le_bytes([buffer[0], buffer[1]]) != POWER_MAGIC {
+        return None;
+    }
+    let count = buffer[2] as usize;
+    let next = buffer[3] as usize;
+    (count <= MAX_DAYS && next < MAX_DAYS).then_some((count, next))
+}
@@ -0,0 +1,218 @@
+//! On-demand self-test over the protocol
+//!
+//! The host selects tests with `Command::RunSelfTest`; they then run one per
+//! `poll()` call so the control loop keeps getting serviced in between. Each
+//! finished test streams a result record, and a summary record closes the
+//! run:
+//!
+//! * result: `[0x01, test id, passed, detail u32]`
+//! * summary: `[0x02, run, passed, failed]`
+//!
+//! Tests 0-3 are the built-in diagnostics checks, further tests can be
+//! registered by other modules.
+#![no_std]
+
+use super::Diagnostics;
+use crate::hal::AdcArbiter;
+use crate::protocol::{Command, Protocol, ProtocolError, Result};
+use crate::safety;
+
+pub const TEST_VOLTAGE: u8 = 0;
+pub const TEST_TEMPERATURE: u8 = 1;
+pub const TEST_MEMORY: u8 = 2;
+pub const TEST_PERIPHERALS: u8 = 3;
+
+const BUILTIN_TESTS: usize = 4;
+const MAX_CUSTOM_TESTS: usize = 4;
+const MAX_TESTS: usize = BUILTIN_TESTS + MAX_CUSTOM_TESTS;
+const BUILTIN_MASK: u16 = (1 << BUILTIN_TESTS) - 1;
+const BUILTIN_NAMES: [&str; BUILTIN_TESTS] = ["voltage", "temperature", "memory", "peripherals"];
+
+// Sub-commands carried in the first payload byte of Command::RunSelfTest
+const OP_RUN: u8 = 0x01;
+const OP_LIST: u8 = 0x02;
+
+// Streamed record types
+const RECORD_RESULT: u8 = 0x01;
+const RECORD_SUMMARY: u8 = 0x02;
+
+/// Custom test, returns a failure detail code on error
+pub type SelfTestFn = fn() -> core::result::Result<(), u32>;
+
+pub struct SelfTestRunner {
+    custom: [Option<(&'static str, SelfTestFn)>; MAX_CUSTOM_TESTS],
+    pending: u16,
+    active: bool,
+    run: u8,
+    passed: u8,
+    failed: u8,
+    builtins_passed: u16,
+}
+
+impl SelfTestRunner {
+    pub const fn new() -> Self {
+        Self {
+            custom: [None; MAX_CUSTOM_TESTS],
+            pending: 0,
+            active: false,
+            run: 0,
+            passed: 0,
+            failed: 0,
+            builtins_passed: 0,
+        }
+    }
+
+    /// Register an additional test, returns its id
+    pub fn register(&mut self, name: &'static str, test: SelfTestFn) -> core::result::Result<u8, ()> {
+        for (i, slot) in self.custom.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some((name, test));
+                return Ok((BUILTIN_TESTS + i) as u8);
+            }
+        }
+        Err(())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.active
+    }
+
+    /// Queue the tests selected in `mask`, returns how many will run
+    pub fn start(&mut self, mask: u16) -> core::result::Result<u8, ()> {
+        if self.active {
+            return Err(());
+        }
+        let mut available = BUILTIN_MASK;
+        for (i, slot) in self.custom.iter().enumerate() {
+            if slot.is_some() {
+                available |= 1 << (BUILTIN_TESTS + i);
+            }
+        }
+
+        self.pending = mask & available;
+        if self.pending == 0 {
+            return Err(());
+        }
+        self.active = true;
+        self.run = 0;
+        self.passed = 0;
+        self.failed = 0;
+        self.builtins_passed = 0;
+        Ok(self.pending.count_ones() as u8)
+    }
+
+    /// Handle a `Command::RunSelfTest` payload.
+    ///
+    /// * `RUN mask(u16)`: reply `[number of tests queued]`
+    /// * `LIST`: reply `[id, name_len, name...]*`
+    pub fn handle_command(&mut self, data: &[u8], response: &mut [u8]) -> Result<usize> {
+        let op = *data.first().ok_or(ProtocolError::InvalidPacket)?;
+
+        match op {
+            OP_RUN => {
+                if data.len() != 3 || response.is_empty() {
+                    return Err(ProtocolError::InvalidPacket);
+                }
+                let mask = u16::from_le_bytes([data[1], data[2]]);
+                response[0] = self.start(mask).map_err(|_| ProtocolError::InvalidCommand)?;
+                Ok(1)
+            }
+            OP_LIST => {
+                let mut len = 0;
+                for id in 0..MAX_TESTS {
+                    let name = match self.name(id as u8) {
+                        Some(name) => name.as_bytes(),
+                        None => continue,
+                    };
+                    if len + 2 + name.len() > response.len() {
+                        return Err(ProtocolError::BufferOverflow);
+                    }
+                    response[len] = id as u8;
+                    response[len + 1] = name.len() as u8;
+                    response[len + 2..len + 2 + name.len()].copy_from_slice(name);
+                    len += 2 + name.len();
+                }
+                Ok(len)
+            }
+            _ => Err(ProtocolError::InvalidCommand),
+        }
+    }
+
+    fn name(&self, id: u8) -> Option<&'static str> {
+        let id = id as usize;
+        if id < BUILTIN_TESTS {
+            Some(BUILTIN_NAMES[id])
+        } else {
+            self.custom.get(id - BUILTIN_TESTS).copied().flatten().map(|(name, _)| name)
+        }
+    }
+
+    /// Run the next queued test and send its record. Call from the main
+    /// loop; does nothing while no run is active.
+    pub fn poll(
+        &mut self,
+        diag: &mut Diagnostics,
+        adc: &mut AdcArbiter,
+        protocol: &mut Protocol,
+    ) -> Result<()> {
+        if !self.active {
+            return Ok(());
+        }
+
+        if self.pending == 0 {
+            self.active = false;
+            // A full pass of the built-in checks clears the way for arming
+            if self.failed > 0 {
+                safety::set_self_test_result(false);
+            } else if self.builtins_passed == BUILTIN_MASK {
+                safety::set_self_test_result(true);
+            }
+            return protocol.send_packet(
+                Command::RunSelfTest,
+                &[RECORD_SUMMARY, self.run, self.passed, self.failed],
+            );
+        }
+
+        let id = self.pending.trailing_zeros() as u8;
+        self.pending &= !(1 << id);
+
+        let result = match id {
+            TEST_VOLTAGE => diag.check_voltage(adc).map_err(|e| e.data),
+            TEST_TEMPERATURE => diag.check_temperature().map_err(|e| e.data),
+            TEST_MEMORY => diag.check_memory().map_err(|e| e.data),
+            TEST_PERIPHERALS => diag.check_peripherals().map_err(|e| e.data),
+            _ => match self.custom[id as usize - BUILTIN_TESTS] {
+                Some((_, test)) => test(),
+                None => Err(0),
+            },
+        };
+
+        self.run += 1;
+        let detail = match result {
+            Ok(()) => {
+                self.passed += 1;
+                if (id as usize) < BUILTIN_TESTS {
+                    self.builtins_passed |= 1 << id;
+                }
+                0
+            }
+            Err(detail) => {
+                self.failed += 1;
+                detail
+            }
+        };
+
+        let mut record = [0u8; 7];
+        record[0] = RECORD_RESULT;
+        record[1] = id;
+        record[2] = result.is_ok() as u8;
+        record[3..7].copy_from_slice(&detail.to_le_bytes());
+        protocol.send_packet(Command::RunSelfTest, &record)
+    }
+}
+
+impl Default for SelfTestRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
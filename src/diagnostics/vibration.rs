@@ -0,0 +1,179 @@
+//! Vibration analysis for filter tuning
+//!
+//! Captures a block of accelerometer samples on one axis, runs the FFT on it
+//! and keeps the magnitude spectrum for the host to read back through
+//! `Command::Vibration`. Bin `k` corresponds to `k * sample_hz / points` Hz,
+//! so resonance peaks can be located and the gyro notch placed on them.
+#![no_std]
+
+use crate::drivers::Vec3;
+use crate::math::{fft, magnitudes};
+use crate::math::fft::MAX_POINTS;
+use crate::protocol::{ProtocolError, Result};
+
+// Sub-commands carried in the first payload byte of Command::Vibration
+const OP_CAPTURE: u8 = 0x01;
+const OP_STATUS: u8 = 0x02;
+const OP_READ_BINS: u8 = 0x03;
+
+// Accel samples are stored as g * 4096, i.e. +/-8g full range
+const SAMPLE_SCALE: f32 = 4096.0;
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Axis {
+    X = 0,
+    Y = 1,
+    Z = 2,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum CaptureState {
+    Idle = 0,
+    Capturing = 1,
+    Done = 2,
+}
+
+pub struct VibrationAnalyzer {
+    sample_hz: u16,
+    axis: Axis,
+    points: usize,
+    count: usize,
+    state: CaptureState,
+    re: [i16; MAX_POINTS],
+    im: [i16; MAX_POINTS],
+    bins: [u16; MAX_POINTS / 2],
+}
+
+impl VibrationAnalyzer {
+    /// `sample_hz` is the rate at which `push` gets called
+    pub fn new(sample_hz: u16) -> Self {
+        Self {
+            sample_hz,
+            axis: Axis::Z,
+            points: MAX_POINTS,
+            count: 0,
+            state: CaptureState::Idle,
+            re: [0; MAX_POINTS],
+            im: [0; MAX_POINTS],
+            bins: [0; MAX_POINTS / 2],
+        }
+    }
+
+    /// Arm a capture of `points` samples (64 or 128) on `axis`
+    pub fn start(&mut self, axis: Axis, points: usize) -> core::result::Result<(), ()> {
+        if points != 64 && points != 128 {
+            return Err(());
+        }
+        self.axis = axis;
+        self.points = points;
+        self.count = 0;
+        self.state = CaptureState::Capturing;
+        Ok(())
+    }
+
+    pub fn state(&self) -> CaptureState {
+        self.state
+    }
+
+    /// Feed one accelerometer reading. Runs the transform once the block
+    /// is complete.
+    pub fn push(&mut self, accel: Vec3) {
+        if self.state != CaptureState::Capturing {
+            return;
+        }
+
+        let value = match self.axis {
+            Axis::X => accel.x,
+            Axis::Y => accel.y,
+            Axis::Z => accel.z,
+        };
+        self.re[self.count] = (value * SAMPLE_SCALE).clamp(-32768.0, 32767.0) as i16;
+        self.count += 1;
+
+        if self.count == self.points {
+            self.transform();
+            self.state = CaptureState::Done;
+        }
+    }
+
+    /// Magnitude spectrum, valid once the capture is done
+    pub fn bins(&self) -> &[u16] {
+        &self.bins[..self.points / 2]
+    }
+
+    fn transform(&mut self) {
+        let n = self.points;
+
+        // Remove gravity / DC so it doesn't swamp the low bins
+        let sum: i32 = self.re[..n].iter().map(|&v| v as i32).sum();
+        let mean = sum / n as i32;
+        for v in self.re[..n].iter_mut() {
+            *v = (*v as i32 - mean).clamp(-32768, 32767) as i16;
+        }
+        for v in self.im[..n].iter_mut() {
+            *v = 0;
+        }
+
+        if fft(&mut self.re[..n], &mut self.im[..n]).is_ok() {
+            magnitudes(&self.re[..n], &self.im[..n], &mut self.bins[..n / 2]);
+        }
+    }
+
+    /// Handle a `Command::Vibration` payload.
+    ///
+    /// * `CAPTURE axis points`: start a capture
+    /// * `STATUS`: reply `[state, points, sample_hz(u16)]`
+    /// * `READ_BINS first count`: reply `[first, mag(u16)...]`, clipped to
+    ///   the response buffer so the host can page through the spectrum
+    pub fn handle_command(&mut self, data: &[u8], response: &mut [u8]) -> Result<usize> {
+        let op = *data.first().ok_or(ProtocolError::InvalidPacket)?;
+
+        match op {
+            OP_CAPTURE => {
+                if data.len() != 3 {
+                    return Err(ProtocolError::InvalidPacket);
+                }
+                let axis = match data[1] {
+                    0 => Axis::X,
+                    1 => Axis::Y,
+                    2 => Axis::Z,
+                    _ => return Err(ProtocolError::InvalidPacket),
+                };
+                self.start(axis, data[2] as usize)
+                    .map_err(|_| ProtocolError::InvalidPacket)?;
+                Ok(0)
+            }
+            OP_STATUS => {
+                if response.len() < 4 {
+                    return Err(ProtocolError::BufferOverflow);
+                }
+                response[0] = self.state as u8;
+                response[1] = self.points as u8;
+                response[2..4].copy_from_slice(&self.sample_hz.to_le_bytes());
+                Ok(4)
+            }
+            OP_READ_BINS => {
+                if self.state != CaptureState::Done {
+                    return Err(ProtocolError::InvalidCommand);
+                }
+                if data.len() != 3 || response.is_empty() {
+                    return Err(ProtocolError::InvalidPacket);
+                }
+                let bins = self.bins();
+                let first = (data[1] as usize).min(bins.len());
+                let count = (data[2] as usize)
+                    .min(bins.len() - first)
+                    .min((response.len() - 1) / 2);
+
+                response[0] = first as u8;
+                for (i, mag) in bins[first..first + count].iter().enumerate() {
+                    response[1 + i * 2..3 + i * 2].copy_from_slice(&mag.to_le_bytes());
+                }
+                Ok(1 + count * 2)
+            }
+            _ => Err(ProtocolError::InvalidCommand),
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Watch variables: live peek/poke of registered variables over the protocol
+//!
+//! Modules register named variables with a type tag. The host can list them,
+//! read or write them on demand, or have a selection streamed periodically,
+//! which allows PID gains and thresholds to be tuned live without
+//! recompiling. All access goes through `Command::Watch`.
+#![no_std]
+
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
+
+use crate::protocol::{ProtocolError, Result};
+
+const MAX_WATCHES: usize = 16;
+const MAX_NAME_LEN: usize = 12;
+
+// Sub-commands carried in the first payload byte of Command::Watch
+const OP_LIST: u8 = 0x01;
+const OP_READ: u8 = 0x02;
+const OP_WRITE: u8 = 0x03;
+const OP_STREAM: u8 = 0x04;
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum WatchType {
+    U8 = 0,
+    I8 = 1,
+    U16 = 2,
+    I16 = 3,
+    U32 = 4,
+    I32 = 5,
+    F32 = 6,
+}
+
+impl WatchType {
+    pub fn size(self) -> usize {
+        match self {
+            WatchType::U8 | WatchType::I8 => 1,
+            WatchType::U16 | WatchType::I16 => 2,
+            WatchType::U32 | WatchType::I32 | WatchType::F32 => 4,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WatchError {
+    TableFull,
+    NameTooLong,
+}
+
+#[derive(Clone, Copy)]
+struct WatchEntry {
+    name: &'static str,
+    ty: WatchType,
+    ptr: *mut u8,
+    writable: bool,
+}
+
+// Entries only ever point at 'static variables
+unsafe impl Send for WatchEntry {}
+
+struct WatchTable {
+    entries: [Option<WatchEntry>; MAX_WATCHES],
+    stream_mask: u16,
+    stream_period_ms: u16,
+    last_stream_ms: u32,
+}
+
+static WATCHES: Mutex<RefCell<WatchTable>> = Mutex::new(RefCell::new(WatchTable {
+    entries: [None; MAX_WATCHES],
+    stream_mask: 0,
+    stream_period_ms: 0,
+    last_stream_ms: 0,
+}));
+
+/// Register a variable for watching, returns its id.
+///
+/// # Safety
+/// `ptr` must point to a `'static` variable of the type described by `ty`
+/// that stays valid for the rest of the program.
+pub unsafe fn register(
+    name: &'static str,
+    ty: WatchType,
+    ptr: *mut u8,
+    writable: bool,
+) -> core::result::Result<u8, WatchError> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(WatchError::NameTooLong);
+    }
+
+    avr_device::interrupt::free(|cs| {
+        let mut table = WATCHES.borrow(cs).borrow_mut();
+        for (id, slot) in table.entries.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some(WatchEntry { name, ty, ptr, writable });
+                return Ok(id as u8);
+            }
+        }
+        Err(WatchError::TableFull)
+    })
+}
+
+/// Register a writable `f32`, the common case for tuning gains
+pub fn register_f32(name: &'static str, var: &'static mut f32) -> core::result::Result<u8, WatchError> {
+    unsafe { register(name, WatchType::F32, var as *mut f32 as *mut u8, true) }
+}
+
+/// Register a read-only `u32` counter
+pub fn register_u32(name: &'static str, var: &'static u32) -> core::result::Result<u8, WatchError> {
+    unsafe { register(name, WatchType::U32, var as *const u32 as *mut u8, false) }
+}
+
+fn read_entry(entry: &WatchEntry, out: &mut [u8]) {
+    for (i, byte) in out[..entry.ty.size()].iter_mut().enumerate() {
+        *byte = unsafe { core::ptr::read_volatile(entry.ptr.add(i)) };
+    }
+}
+
+fn write_entry(entry: &WatchEntry, value: &[u8]) {
+    for (i, &byte) in value[..entry.ty.size()].iter().enumerate() {
+        unsafe { core::ptr::write_volatile(entry.ptr.add(i), byte) };
+    }
+}
+
+/// Append `id, value` records for every id set in `mask`
+fn read_masked(table: &WatchTable, mask: u16, response: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    for (id, entry) in table.entries.iter().enumerate() {
+        let entry = match entry {
+            Some(entry) if mask & (1 << id) != 0 => entry,
+            _ => continue,
+        };
+        let size = entry.ty.size();
+        if len + 1 + size > response.len() {
+            return Err(ProtocolError::BufferOverflow);
+        }
+        response[len] = id as u8;
+        read_entry(entry, &mut response[len + 1..]);
+        len += 1 + size;
+    }
+    Ok(len)
+}
+
+/// Handle a `Command::Watch` payload, writing the reply into `response`.
+///
+/// * `LIST`: reply `[id, type, writable, name_len, name...]*`
+/// * `READ mask(u16)`: reply `[id, value...]*`
+/// * `WRITE id value...`: no reply data
+/// * `STREAM period_ms(u16) mask(u16)`: period 0 stops streaming
+pub fn handle_command(data: &[u8], response: &mut [u8]) -> Result<usize> {
+    let op = *data.first().ok_or(ProtocolError::InvalidPacket)?;
+
+    avr_device::interrupt::free(|cs| {
+        let mut table = WATCHES.borrow(cs).borrow_mut();
+
+        match op {
+            OP_LIST => {
+                let mut len = 0;
+                for (id, entry) in table.entries.iter().enumerate() {
+                    if let Some(entry) = entry {
+                        let name = entry.name.as_bytes();
+                        if len + 4 + name.len() > response.len() {
+                            return Err(ProtocolError::BufferOverflow);
+                        }
+                        response[len] = id as u8;
+                        response[len + 1] = entry.ty as u8;
+                        response[len + 2] = entry.writable as u8;
+                        response[len + 3] = name.len() as u8;
+                        response[len + 4..len + 4 + name.len()].copy_from_slice(name);
+                        len += 4 + name.len();
+                    }
+                }
+                Ok(len)
+            }
+            OP_READ => {
+                if data.len() != 3 {
+                    return Err(ProtocolError::InvalidPacket);
+                }
+                let mask = u16::from_le_bytes([data[1], data[2]]);
+                read_masked(&table, mask, response)
+            }
+            OP_WRITE => {
+                let id = *data.get(1).ok_or(ProtocolError::InvalidPacket)? as usize;
+                let entry = table
+                    .entries
+                    .get(id)
+                    .copied()
+                    .flatten()
+                    .ok_or(ProtocolError::InvalidPacket)?;
+                if !entry.writable || data.len() != 2 + entry.ty.size() {
+                    return Err(ProtocolError::InvalidPacket);
+                }
+                write_entry(&entry, &data[2..]);
+                Ok(0)
+            }
+            OP_STREAM => {
+                if data.len() != 5 {
+                    return Err(ProtocolError::InvalidPacket);
+                }
+                table.stream_period_ms = u16::from_le_bytes([data[1], data[2]]);
+                table.stream_mask = u16::from_le_bytes([data[3], data[4]]);
+                Ok(0)
+            }
+            _ => Err(ProtocolError::InvalidCommand),
+        }
+    })
+}
+
+/// Build a streamed watch record if one is due. Returns the payload length
+/// to send with `Command::Watch`, or `None` if nothing is due.
+pub fn poll_stream(now_ms: u32, response: &mut [u8]) -> Option<usize> {
+    avr_device::interrupt::free(|cs| {
+        let mut table = WATCHES.borrow(cs).borrow_mut();
+        if table.stream_period_ms == 0 || table.stream_mask == 0 {
+            return None;
+        }
+        if now_ms.wrapping_sub(table.last_stream_ms) < table.stream_period_ms as u32 {
+            return None;
+        }
+        table.last_stream_ms = now_ms;
+        let mask = table.stream_mask;
+        read_masked(&table, mask, response).ok()
+    })
+}
@@ -1,16 +1,51 @@
-use crate::hal::gpio::board::{BTN0, BTN1, BTN2, BTN3};
-use crate::hal::gpio::{Input, Pin};
-use avr_device::atmega128::PORTB;
+//! Four push buttons, polled or interrupt driven
+//!
+//! `new` polls BTN0..BTN3 on PB0..PB3 every loop pass. Port B has no
+//! external interrupts, so boards that want to sleep between presses wire
+//! the buttons to INT lines and use `with_interrupts`: a press latches the
+//! button and the time into a queue from the interrupt, and `poll` debounces
+//! it against the system tick. The lines trigger on the low level, which
+//! wakes the MCU from Power-save on every line (edges on INT4..INT7 would
+//! not); the line is masked from the first interrupt until the button has
+//! been released, so a held button doesn't keep interrupting.
+use avr_device::interrupt::Mutex;
+use core::cell::{Cell, RefCell};
+
+use crate::drivers::debounced_input::{DebounceConfig, DebouncedInput, InputEdge};
+use crate::hal::claims::{self, Port, Resource};
+use crate::hal::exti::{self, ExtInt, ExtiHandler, Trigger};
+use crate::hal::systime;
 
 const DEBOUNCE_TICKS: u8 = 5; // ~5ms debounce time
 
+// BTN0..BTN3 on PB0..PB3, active low with the board's pull-ups
+const BUTTON_CONFIG: DebounceConfig = DebounceConfig {
+    press_samples: DEBOUNCE_TICKS,
+    release_samples: DEBOUNCE_TICKS,
+    active_low: true,
+    pull_up: false,
+};
+
+/// Buttons to ground on INT lines, with the internal pull-ups
+const IRQ_BUTTON_CONFIG: DebounceConfig = DebounceConfig {
+    press_samples: 1,
+    release_samples: 1,
+    active_low: true,
+    pull_up: true,
+};
+
+/// Debounce time of interrupt driven buttons
+pub const IRQ_DEBOUNCE_MS: u32 = 20;
+
+const EDGE_QUEUE_LEN: usize = 8;
+const NO_BUTTON: u8 = 0xFF;
+
 pub struct ButtonHandler {
-    buttons: [Pin<PORTB, u8, Input>; 4],
-    states: [bool; 4],
-    debounce_counters: [u8; 4],
+    buttons: [DebouncedInput; 4],
+    irq: Option<IrqButtons>,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Button {
     Button0,
     Button1,
@@ -24,46 +59,94 @@ pub enum ButtonEvent {
     Released(Button),
 }
 
+#[derive(Copy, Clone, PartialEq)]
+enum IrqState {
+    /// Interrupt enabled, waiting for a press
+    Armed,
+    /// Interrupt seen at this tick, waiting out the bounce
+    Settling(u32),
+    Pressed,
+    /// Read released since this tick
+    Releasing(u32),
+}
+
+struct IrqButtons {
+    lines: [ExtInt; 4],
+    states: [IrqState; 4],
+}
+
+// Presses latched by the interrupts: button index and tick
+struct EdgeQueue {
+    edges: [(u8, u32); EDGE_QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+static EDGES: Mutex<RefCell<EdgeQueue>> = Mutex::new(RefCell::new(EdgeQueue {
+    edges: [(0, 0); EDGE_QUEUE_LEN],
+    head: 0,
+    len: 0,
+}));
+
+// Button index of each INT line
+static LINE_BUTTON: Mutex<Cell<[u8; 8]>> = Mutex::new(Cell::new([NO_BUTTON; 8]));
+
 impl ButtonHandler {
     pub fn new() -> Self {
         Self {
             buttons: [
-                BTN0::default().into_input(),
-                BTN1::default().into_input(),
-                BTN2::default().into_input(),
-                BTN3::default().into_input(),
+                DebouncedInput::new(Port::B, 0, BUTTON_CONFIG),
+                DebouncedInput::new(Port::B, 1, BUTTON_CONFIG),
+                DebouncedInput::new(Port::B, 2, BUTTON_CONFIG),
+                DebouncedInput::new(Port::B, 3, BUTTON_CONFIG),
             ],
-            states: [false; 4],
-            debounce_counters: [0; 4],
+            irq: None,
+        }
+    }
+
+    /// Buttons on the external interrupt `lines`, in button order
+    pub fn with_interrupts(lines: [ExtInt; 4]) -> Self {
+        let buttons = lines.map(|line| {
+            let (port, bit) = line.pin();
+            claims::claim(Resource::Pin(port, bit), "buttons").ok();
+            DebouncedInput::new(port, bit, IRQ_BUTTON_CONFIG)
+        });
+
+        avr_device::interrupt::free(|cs| {
+            let mut map = [NO_BUTTON; 8];
+            for (idx, &line) in lines.iter().enumerate() {
+                map[line as usize] = idx as u8;
+            }
+            LINE_BUTTON.borrow(cs).set(map);
+            let mut queue = EDGES.borrow(cs).borrow_mut();
+            queue.head = 0;
+            queue.len = 0;
+        });
+        for &line in lines.iter() {
+            exti::attach(line, Trigger::LowLevel, true, LINE_HANDLERS[line as usize]).ok();
+        }
+
+        Self {
+            buttons,
+            irq: Some(IrqButtons {
+                lines,
+                states: [IrqState::Armed; 4],
+            }),
         }
     }
 
     pub fn poll(&mut self) -> Option<ButtonEvent> {
-        for (idx, button) in self.buttons.iter().enumerate() {
-            let raw_state = button.is_low(); // Buttons are active low
-            
-            if raw_state != self.states[idx] {
-                self.debounce_counters[idx] = self.debounce_counters[idx].saturating_add(1);
-                if self.debounce_counters[idx] >= DEBOUNCE_TICKS {
-                    self.states[idx] = raw_state;
-                    self.debounce_counters[idx] = 0;
-                    
-                    let btn = match idx {
-                        0 => Button::Button0,
-                        1 => Button::Button1,
-                        2 => Button::Button2,
-                        3 => Button::Button3,
-                        _ => unreachable!(),
-                    };
-                    
-                    return Some(if raw_state {
-                        ButtonEvent::Pressed(btn)
-                    } else {
-                        ButtonEvent::Released(btn)
-                    });
-                }
-            } else {
-                self.debounce_counters[idx] = 0;
+        if self.irq.is_some() {
+            return self.poll_irq(systime::millis());
+        }
+
+        for (idx, button) in self.buttons.iter_mut().enumerate() {
+            if let Some(edge) = button.update() {
+                let btn = button_at(idx);
+                return Some(match edge {
+                    InputEdge::Activated => ButtonEvent::Pressed(btn),
+                    InputEdge::Released => ButtonEvent::Released(btn),
+                });
             }
         }
         None
@@ -76,7 +159,68 @@ impl ButtonHandler {
             Button::Button2 => 2,
             Button::Button3 => 3,
         };
-        self.states[idx]
+        match &self.irq {
+            Some(irq) => matches!(irq.states[idx], IrqState::Pressed | IrqState::Releasing(_)),
+            None => self.buttons[idx].is_active(),
+        }
+    }
+
+    /// Nothing left to debounce, the MCU can sleep until the next press.
+    /// Polled buttons never are.
+    pub fn is_idle(&self) -> bool {
+        let queued = avr_device::interrupt::free(|cs| EDGES.borrow(cs).borrow().len);
+        match &self.irq {
+            Some(irq) => queued == 0 && irq.states.iter().all(|s| *s == IrqState::Armed),
+            None => false,
+        }
+    }
+
+    fn poll_irq(&mut self, now: u32) -> Option<ButtonEvent> {
+        let irq = self.irq.as_mut()?;
+
+        while let Some((idx, at)) = pop_edge() {
+            if let Some(state) = irq.states.get_mut(idx as usize) {
+                if *state == IrqState::Armed {
+                    *state = IrqState::Settling(at);
+                }
+            }
+        }
+
+        for idx in 0..4 {
+            let pressed = self.buttons[idx].raw();
+            let line = irq.lines[idx];
+            let state = &mut irq.states[idx];
+            match *state {
+                IrqState::Armed => {}
+                IrqState::Settling(since) => {
+                    if now.wrapping_sub(since) < IRQ_DEBOUNCE_MS {
+                        continue;
+                    }
+                    if pressed {
+                        *state = IrqState::Pressed;
+                        return Some(ButtonEvent::Pressed(button_at(idx)));
+                    }
+                    // Noise, not a press
+                    *state = IrqState::Armed;
+                    exti::enable(line);
+                }
+                IrqState::Pressed => {
+                    if !pressed {
+                        *state = IrqState::Releasing(now);
+                    }
+                }
+                IrqState::Releasing(since) => {
+                    if pressed {
+                        *state = IrqState::Pressed;
+                    } else if now.wrapping_sub(since) >= IRQ_DEBOUNCE_MS {
+                        *state = IrqState::Armed;
+                        exti::enable(line);
+                        return Some(ButtonEvent::Released(button_at(idx)));
+                    }
+                }
+            }
+        }
+        None
     }
 }
 
@@ -84,4 +228,81 @@ impl Default for ButtonHandler {
     fn default() -> Self {
         Self::new()
     }
-} 
\ No newline at end of file
+}
+
+fn button_at(idx: usize) -> Button {
+    match idx {
+        0 => Button::Button0,
+        1 => Button::Button1,
+        2 => Button::Button2,
+        3 => Button::Button3,
+        _ => unreachable!(),
+    }
+}
+
+fn pop_edge() -> Option<(u8, u32)> {
+    avr_device::interrupt::free(|cs| {
+        let mut queue = EDGES.borrow(cs).borrow_mut();
+        if queue.len == 0 {
+            return None;
+        }
+        let edge = queue.edges[queue.head];
+        queue.head = (queue.head + 1) % EDGE_QUEUE_LEN;
+        queue.len -= 1;
+        Some(edge)
+    })
+}
+
+// Runs in the interrupt: mask the line until the button is released and
+// queue the press
+fn latch(line: ExtInt) {
+    exti::disable(line);
+    let now = crate::os::SCHEDULER.get_ticks();
+    avr_device::interrupt::free(|cs| {
+        let button = LINE_BUTTON.borrow(cs).get()[line as usize];
+        if button == NO_BUTTON {
+            return;
+        }
+        let mut queue = EDGES.borrow(cs).borrow_mut();
+        // Each line is masked after one press, four buttons can't fill it
+        if queue.len < EDGE_QUEUE_LEN {
+            let tail = (queue.head + queue.len) % EDGE_QUEUE_LEN;
+            queue.edges[tail] = (button, now);
+            queue.len += 1;
+        }
+    });
+}
+
+fn on_int0() {
+    latch(ExtInt::Int0);
+}
+
+fn on_int1() {
+    latch(ExtInt::Int1);
+}
+
+fn on_int2() {
+    latch(ExtInt::Int2);
+}
+
+fn on_int3() {
+    latch(ExtInt::Int3);
+}
+
+fn on_int4() {
+    latch(ExtInt::Int4);
+}
+
+fn on_int5() {
+    latch(ExtInt::Int5);
+}
+
+fn on_int6() {
+    latch(ExtInt::Int6);
+}
+
+fn on_int7() {
+    latch(ExtInt::Int7);
+}
+
+const LINE_HANDLERS: [ExtiHandler; 8] = [on_int0, on_int1, on_int2, on_int3, on_int4, on_int5, on_int6, on_int7];
@@ -1,11 +1,13 @@
 //! Sensor calibration routines
 #![no_std]
 
+use crate::config::FLASH_CALIBRATION;
+use crate::diagnostics::flash_audit::{self, AuditRegion};
 use crate::drivers::{Vec3, Mpu6050};
-use crate::hal::flash::Flash;
+use crate::drivers::flash::Flash;
+use crate::drivers::redundant_store::{self, FlashCopies, StoreError};
 
 const CALIBRATION_SAMPLES: usize = 1000;
-const FLASH_SECTOR_CALIBRATION: u32 = 0x10000;
 
 pub struct CalibrationData {
     accel_offset: Vec3,
@@ -125,23 +127,33 @@ impl Calibration {
         }
     }
 
-    pub fn save_calibration(&mut self) -> Result<(), ()> {
+    pub fn save_calibration(&mut self) -> Result<(), StoreError> {
         let data = unsafe {
             core::slice::from_raw_parts(
                 (&self.data as *const CalibrationData) as *const u8,
                 core::mem::size_of::<CalibrationData>(),
             )
         };
-        
-        self.flash.erase_sector(FLASH_SECTOR_CALIBRATION)?;
-        self.flash.write(FLASH_SECTOR_CALIBRATION, data)?;
+
+        flash_audit::mark_written(AuditRegion::Calibration);
+        flash_audit::mark_written(AuditRegion::CalibrationCopy);
+        redundant_store::save(&mut FlashCopies::new(&mut self.flash, FLASH_CALIBRATION), data)?;
         
         Ok(())
     }
 
-    pub fn load_calibration(&mut self) -> Result<(), ()> {
+    /// Load the newest valid copy, repairing the other one. On error the
+    /// current data is kept.
+    pub fn load_calibration(&mut self) -> Result<(), StoreError> {
         let mut buffer = [0u8; core::mem::size_of::<CalibrationData>()];
-        self.flash.read(FLASH_SECTOR_CALIBRATION, &mut buffer)?;
+        let loaded = redundant_store::load(&mut FlashCopies::new(&mut self.flash, FLASH_CALIBRATION), &mut buffer)?;
+        if loaded.repaired {
+            flash_audit::mark_written(AuditRegion::Calibration);
+            flash_audit::mark_written(AuditRegion::CalibrationCopy);
+        }
+        if loaded.len != buffer.len() {
+            return Err(StoreError::NoValidCopy);
+        }
         
         self.data = unsafe {
             core::ptr::read(buffer.as_ptr() as *const CalibrationData)
@@ -0,0 +1,148 @@
+//! Debounced digital input
+//!
+//! Buttons, limit switches and reed contacts all need the same thing: a
+//! pin sampled at a steady rate that only changes state after it read the
+//! new level for a number of samples in a row. `press_samples` and
+//! `release_samples` are separate so an input can react quickly in one
+//! direction and stay latched against chatter in the other, e.g. an
+//! emergency stop that trips on two samples and releases only after twenty.
+//!
+//! The pin is picked at runtime so inputs can be kept in arrays. Activity
+//! is reported after the active level is applied, `true` always means
+//! pressed / closed / tripped.
+#![no_std]
+
+use crate::hal::gpio::{DynPin, Port};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DebounceConfig {
+    /// Consecutive active samples before the input becomes active
+    pub press_samples: u8,
+    /// Consecutive inactive samples before it becomes inactive again
+    pub release_samples: u8,
+    /// Input is active when the pin reads low
+    pub active_low: bool,
+    /// Enable the internal pull-up
+    pub pull_up: bool,
+}
+
+impl DebounceConfig {
+    /// Push button to ground with the internal pull-up, 5 samples each way
+    pub const fn button() -> Self {
+        Self {
+            press_samples: 5,
+            release_samples: 5,
+            active_low: true,
+            pull_up: true,
+        }
+    }
+
+    /// Normally-closed contact to ground, active when it opens. Trips fast,
+    /// releases slowly.
+    pub const fn normally_closed() -> Self {
+        Self {
+            press_samples: 2,
+            release_samples: 20,
+            active_low: false,
+            pull_up: true,
+        }
+    }
+}
+
+impl Default for DebounceConfig {
+    fn default() -> Self {
+        Self::button()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum InputEdge {
+    Activated,
+    Released,
+}
+
+pub struct DebouncedInput {
+    pin: DynPin,
+    config: DebounceConfig,
+    active: bool,
+    counter: u8,
+}
+
+impl DebouncedInput {
+    /// Configure `port` bit `bit` as an input. The debounced state starts
+    /// at the current level, so a switch already closed at power-up does
+    /// not report an edge.
+    pub fn new(port: Port, bit: u8, config: DebounceConfig) -> Self {
+        let mut pin = DynPin::new(port, bit);
+        configure(&mut pin, config.pull_up);
+        let mut input = Self {
+            pin,
+            config,
+            active: false,
+            counter: 0,
+        };
+        input.active = input.raw();
+        input
+    }
+
+    /// Take one sample, call at a steady rate (the thresholds count calls).
+    /// Returns the edge when the debounced state changes.
+    pub fn update(&mut self) -> Option<InputEdge> {
+        let raw = self.raw();
+        if raw == self.active {
+            self.counter = 0;
+            return None;
+        }
+
+        self.counter = self.counter.saturating_add(1);
+        let needed = if raw {
+            self.config.press_samples
+        } else {
+            self.config.release_samples
+        };
+        if self.counter < needed.max(1) {
+            return None;
+        }
+
+        self.active = raw;
+        self.counter = 0;
+        Some(if raw {
+            InputEdge::Activated
+        } else {
+            InputEdge::Released
+        })
+    }
+
+    /// Debounced state
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Current pin level with the active level applied, not debounced
+    pub fn raw(&self) -> bool {
+        self.pin.is_high() != self.config.active_low
+    }
+
+    pub fn config(&self) -> DebounceConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: DebounceConfig) {
+        if config.pull_up != self.config.pull_up {
+            configure(&mut self.pin, config.pull_up);
+        }
+        self.config = config;
+    }
+
+    pub fn pin(&self) -> (Port, u8) {
+        (self.pin.port(), self.pin.bit())
+    }
+}
+
+fn configure(pin: &mut DynPin, pull_up: bool) {
+    if pull_up {
+        pin.into_pull_up_input();
+    } else {
+        pin.into_input();
+    }
+}
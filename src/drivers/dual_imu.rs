@@ -0,0 +1,113 @@
+//! Redundant pair of MPU6050s on one TWI bus
+//!
+//! Both sensors are read every cycle. While they agree the average is
+//! returned; if one stops responding the other carries on alone. When they
+//! disagree by more than the configured limit there is no way to tell which
+//! is right, so the reading fails and a `SensorError` is reported.
+#![no_std]
+
+use crate::diagnostics::{Diagnostics, ErrorCode};
+use crate::drivers::{Mpu6050, Vec3};
+use libm::fabsf;
+
+// SensorError subcodes
+const SUBCODE_PRIMARY_FAILED: u16 = 0x0201;
+const SUBCODE_SECONDARY_FAILED: u16 = 0x0202;
+const SUBCODE_ACCEL_DIVERGED: u16 = 0x0203;
+const SUBCODE_GYRO_DIVERGED: u16 = 0x0204;
+
+/// Largest allowed per-axis difference between the two sensors
+#[derive(Clone, Copy)]
+pub struct DivergenceLimits {
+    pub accel_g: f32,
+    pub gyro_dps: f32,
+}
+
+impl Default for DivergenceLimits {
+    fn default() -> Self {
+        Self {
+            accel_g: 0.2,
+            gyro_dps: 10.0,
+        }
+    }
+}
+
+pub struct DualImu {
+    primary: Mpu6050,
+    secondary: Mpu6050,
+    limits: DivergenceLimits,
+    divergence_count: u32,
+}
+
+impl DualImu {
+    pub fn new(primary: Mpu6050, secondary: Mpu6050) -> Self {
+        Self {
+            primary,
+            secondary,
+            limits: DivergenceLimits::default(),
+            divergence_count: 0,
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: DivergenceLimits) {
+        self.limits = limits;
+    }
+
+    /// Number of readings rejected because the sensors disagreed
+    pub fn divergence_count(&self) -> u32 {
+        self.divergence_count
+    }
+
+    pub fn read_accel(&mut self, diag: &mut Diagnostics) -> Result<Vec3, ()> {
+        let a = self.primary.read_accel();
+        let b = self.secondary.read_accel();
+        let limit = self.limits.accel_g;
+        self.vote(a, b, limit, SUBCODE_ACCEL_DIVERGED, diag)
+    }
+
+    pub fn read_gyro(&mut self, diag: &mut Diagnostics) -> Result<Vec3, ()> {
+        let a = self.primary.read_gyro();
+        let b = self.secondary.read_gyro();
+        let limit = self.limits.gyro_dps;
+        self.vote(a, b, limit, SUBCODE_GYRO_DIVERGED, diag)
+    }
+
+    fn vote(
+        &mut self,
+        a: Result<Vec3, ()>,
+        b: Result<Vec3, ()>,
+        limit: f32,
+        diverged_subcode: u16,
+        diag: &mut Diagnostics,
+    ) -> Result<Vec3, ()> {
+        match (a, b) {
+            (Ok(a), Ok(b)) => {
+                let diff = fabsf(a.x - b.x).max(fabsf(a.y - b.y)).max(fabsf(a.z - b.z));
+                if diff > limit {
+                    self.divergence_count += 1;
+                    // Report the difference in thousandths
+                    diag.report_error(ErrorCode::SensorError, diverged_subcode, (diff * 1000.0) as u32);
+                    return Err(());
+                }
+                Ok(Vec3 {
+                    x: (a.x + b.x) * 0.5,
+                    y: (a.y + b.y) * 0.5,
+                    z: (a.z + b.z) * 0.5,
+                })
+            }
+            (Ok(a), Err(_)) => {
+                diag.report_error(ErrorCode::SensorError, SUBCODE_SECONDARY_FAILED, self.secondary.address() as u32);
+                Ok(a)
+            }
+            (Err(_), Ok(b)) => {
+                diag.report_error(ErrorCode::SensorError, SUBCODE_PRIMARY_FAILED, self.primary.address() as u32);
+                Ok(b)
+            }
+            (Err(_), Err(_)) => {
+                diag.report_error(ErrorCode::SensorError, SUBCODE_PRIMARY_FAILED, self.primary.address() as u32);
+                diag.report_error(ErrorCode::SensorError, SUBCODE_SECONDARY_FAILED, self.secondary.address() as u32);
+                Err(())
+            }
+        }
+    }
+}
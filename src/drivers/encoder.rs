@@ -0,0 +1,244 @@
+//! Quadrature encoder with position latching on an index or trigger line
+//!
+//! Channel A goes to one of INT4..INT7 (the only lines that interrupt on
+//! both edges), channel B to any pin. Every edge of A counts one step,
+//! the direction coming from B, which gives twice the encoder's line
+//! count per revolution.
+//!
+//! A latch line (the encoder's index channel, a probe, a touch-off
+//! contact) captures the count and the microsecond time inside its
+//! interrupt, before anything else can move the count, and queues the
+//! pair for `poll`. `LatchMode::Single` disarms after the first capture,
+//! for homing against the index mark; `Continuous` captures every pulse,
+//! one per revolution for spindle synchronization.
+#![no_std]
+
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
+
+use crate::hal::claims::{self, Port, Resource};
+use crate::hal::exti::{self, ExtInt, ExtiError, Trigger};
+use crate::hal::gpio::DynPin;
+use crate::hal::systime;
+
+const LATCH_QUEUE_LEN: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LatchMode {
+    /// Capture once, then disarm
+    Single,
+    /// Capture every pulse
+    Continuous,
+}
+
+/// Count and time captured by the latch line
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PositionLatch {
+    pub count: i32,
+    /// `systime::micros` at the capture
+    pub time_us: u32,
+}
+
+struct EncoderState {
+    count: i32,
+    a: DynPin,
+    b: DynPin,
+    latch_line: Option<ExtInt>,
+    armed: bool,
+    mode: LatchMode,
+    latches: [PositionLatch; LATCH_QUEUE_LEN],
+    head: usize,
+    len: usize,
+    overruns: u16,
+}
+
+static ENCODER: Mutex<RefCell<EncoderState>> = Mutex::new(RefCell::new(EncoderState {
+    count: 0,
+    a: DynPin::new(Port::E, 4),
+    b: DynPin::new(Port::E, 5),
+    latch_line: None,
+    armed: false,
+    mode: LatchMode::Single,
+    latches: [PositionLatch { count: 0, time_us: 0 }; LATCH_QUEUE_LEN],
+    head: 0,
+    len: 0,
+    overruns: 0,
+}));
+
+pub struct Encoder {
+    a: ExtInt,
+    b: (Port, u8),
+    latch: Option<ExtInt>,
+    reversed: bool,
+}
+
+impl Encoder {
+    /// Start counting with channel A on `a` and channel B on `b_port`/`b_bit`.
+    /// Both get pull-ups for open-collector encoders.
+    pub fn new(a: ExtInt, b_port: Port, b_bit: u8) -> Result<Self, ExtiError> {
+        let (a_port, a_bit) = a.pin();
+        claims::claim(Resource::Pin(a_port, a_bit), "encoder").ok();
+        claims::claim(Resource::Pin(b_port, b_bit), "encoder").ok();
+
+        let mut b = DynPin::new(b_port, b_bit);
+        b.into_pull_up_input();
+        avr_device::interrupt::free(|cs| {
+            let mut state = ENCODER.borrow(cs).borrow_mut();
+            state.count = 0;
+            state.a = DynPin::new(a_port, a_bit);
+            state.b = b;
+            state.latch_line = None;
+            state.armed = false;
+            state.head = 0;
+            state.len = 0;
+            state.overruns = 0;
+        });
+        exti::attach(a, Trigger::AnyEdge, true, on_edge)?;
+
+        Ok(Self {
+            a,
+            b: (b_port, b_bit),
+            latch: None,
+            reversed: false,
+        })
+    }
+
+    /// Count the other way, for encoders mounted mirrored
+    pub fn set_reversed(&mut self, reversed: bool) {
+        self.reversed = reversed;
+    }
+
+    pub fn count(&self) -> i32 {
+        self.orient(avr_device::interrupt::free(|cs| ENCODER.borrow(cs).borrow().count))
+    }
+
+    /// Redefine the current count, e.g. to a latched index position
+    pub fn set_count(&mut self, count: i32) {
+        let raw = self.orient(count);
+        avr_device::interrupt::free(|cs| ENCODER.borrow(cs).borrow_mut().count = raw);
+    }
+
+    /// Latch the count on `trigger` of `line`. Captures still queued from
+    /// an earlier arming are dropped.
+    pub fn arm_latch(&mut self, line: ExtInt, trigger: Trigger, mode: LatchMode) -> Result<(), ExtiError> {
+        if self.latch != Some(line) {
+            self.disarm_latch();
+            let (port, bit) = line.pin();
+            claims::claim(Resource::Pin(port, bit), "encoder").ok();
+        }
+        avr_device::interrupt::free(|cs| {
+            let mut state = ENCODER.borrow(cs).borrow_mut();
+            state.latch_line = Some(line);
+            state.mode = mode;
+            state.head = 0;
+            state.len = 0;
+            state.armed = true;
+        });
+        if self.latch == Some(line) {
+            exti::set_trigger(line, trigger)?;
+            exti::enable(line);
+        } else {
+            exti::attach(line, trigger, true, on_latch)?;
+            self.latch = Some(line);
+        }
+        Ok(())
+    }
+
+    /// Stop latching and release the line
+    pub fn disarm_latch(&mut self) {
+        if let Some(line) = self.latch.take() {
+            exti::detach(line);
+            let (port, bit) = line.pin();
+            claims::release(Resource::Pin(port, bit), "encoder");
+        }
+        avr_device::interrupt::free(|cs| {
+            let mut state = ENCODER.borrow(cs).borrow_mut();
+            state.latch_line = None;
+            state.armed = false;
+        });
+    }
+
+    /// A `Single` latch that has not fired yet, or a `Continuous` one
+    pub fn is_armed(&self) -> bool {
+        avr_device::interrupt::free(|cs| ENCODER.borrow(cs).borrow().armed)
+    }
+
+    /// Next captured position, oldest first
+    pub fn poll(&mut self) -> Option<PositionLatch> {
+        let latch = avr_device::interrupt::free(|cs| {
+            let mut state = ENCODER.borrow(cs).borrow_mut();
+            if state.len == 0 {
+                return None;
+            }
+            let latch = state.latches[state.head];
+            state.head = (state.head + 1) % LATCH_QUEUE_LEN;
+            state.len -= 1;
+            Some(latch)
+        })?;
+        Some(PositionLatch {
+            count: self.orient(latch.count),
+            time_us: latch.time_us,
+        })
+    }
+
+    /// Captures dropped because `poll` did not keep up
+    pub fn overruns(&self) -> u16 {
+        avr_device::interrupt::free(|cs| ENCODER.borrow(cs).borrow().overruns)
+    }
+
+    /// Stop counting and latching and release the pins
+    pub fn stop(&mut self) {
+        self.disarm_latch();
+        exti::detach(self.a);
+        let (a_port, a_bit) = self.a.pin();
+        claims::release(Resource::Pin(a_port, a_bit), "encoder");
+        claims::release(Resource::Pin(self.b.0, self.b.1), "encoder");
+    }
+
+    fn orient(&self, count: i32) -> i32 {
+        if self.reversed {
+            count.wrapping_neg()
+        } else {
+            count
+        }
+    }
+}
+
+// Runs in the interrupt: after an edge of A, A and B differ when turning
+// forwards
+fn on_edge() {
+    avr_device::interrupt::free(|cs| {
+        let mut state = ENCODER.borrow(cs).borrow_mut();
+        let step = if state.a.is_high() != state.b.is_high() { 1 } else { -1 };
+        state.count = state.count.wrapping_add(step);
+    });
+}
+
+// Runs in the interrupt with interrupts disabled, so A's edges wait until
+// the count is captured
+fn on_latch() {
+    let time_us = systime::micros();
+    avr_device::interrupt::free(|cs| {
+        let mut state = ENCODER.borrow(cs).borrow_mut();
+        if !state.armed {
+            return;
+        }
+        if state.len == LATCH_QUEUE_LEN {
+            state.overruns = state.overruns.wrapping_add(1);
+        } else {
+            let tail = (state.head + state.len) % LATCH_QUEUE_LEN;
+            state.latches[tail] = PositionLatch {
+                count: state.count,
+                time_us,
+            };
+            state.len += 1;
+        }
+        if state.mode == LatchMode::Single {
+            state.armed = false;
+            // Until the next `arm_latch`
+            if let Some(line) = state.latch_line {
+                exti::disable(line);
+            }
+        }
+    });
+}
@@ -0,0 +1,179 @@
+//! ESC (brushless motor controller) output driver
+//!
+//! Drives up to three ESCs from the Timer1 compare outputs, either with the
+//! classic 1000-2000us pulses at 50Hz or with OneShot125 (125-250us pulses,
+//! 1kHz frame) for faster multirotor loops. Each motor has its own throttle
+//! endpoints, and outputs stay at minimum throttle until the arming sequence
+//! has completed and while the safety state machine is not armed.
+#![no_std]
+
+use avr_device::atmega128::TC1;
+
+use crate::hal::{Pwm, PwmChannel, PwmFreq, PwmMode};
+use crate::safety;
+
+const MAX_MOTORS: usize = 3;
+
+/// Time minimum throttle is held before the ESCs are considered armed
+pub const ARMING_TIME_MS: u32 = 2000;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum EscProtocol {
+    /// 1000-2000us pulses at 50Hz
+    Standard,
+    /// 125-250us pulses at 1kHz
+    OneShot125,
+}
+
+impl EscProtocol {
+    fn default_calibration(self) -> EscCalibration {
+        match self {
+            EscProtocol::Standard => EscCalibration { min_us: 1000, max_us: 2000 },
+            EscProtocol::OneShot125 => EscCalibration { min_us: 125, max_us: 250 },
+        }
+    }
+}
+
+/// Pulse widths corresponding to zero and full throttle
+#[derive(Clone, Copy)]
+pub struct EscCalibration {
+    pub min_us: u16,
+    pub max_us: u16,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum EscState {
+    Disarmed,
+    Arming { started_ms: u32 },
+    Armed,
+}
+
+#[derive(Debug)]
+pub enum EscError {
+    InvalidMotor,
+    InvalidCalibration,
+    NotArmed,
+    /// Safety state machine is not armed
+    Inhibited,
+}
+
+#[derive(Clone, Copy)]
+struct EscMotor {
+    channel: PwmChannel,
+    calibration: EscCalibration,
+    throttle: f32,
+}
+
+pub struct EscController {
+    pwm: Pwm<TC1>,
+    protocol: EscProtocol,
+    motors: [EscMotor; MAX_MOTORS],
+    state: EscState,
+}
+
+impl EscController {
+    pub fn new(protocol: EscProtocol) -> Self {
+        let mut pwm = Pwm::new();
+        let freq = match protocol {
+            EscProtocol::Standard => PwmFreq::Hz50,
+            EscProtocol::OneShot125 => PwmFreq::Hz1000,
+        };
+        pwm.configure(freq, PwmMode::Fast);
+
+        let calibration = protocol.default_calibration();
+        let motor = |channel| EscMotor {
+            channel,
+            calibration,
+            throttle: 0.0,
+        };
+
+        let mut esc = Self {
+            pwm,
+            protocol,
+            motors: [
+                motor(PwmChannel::Timer1A),
+                motor(PwmChannel::Timer1B),
+                motor(PwmChannel::Timer1C),
+            ],
+            state: EscState::Disarmed,
+        };
+        esc.output_min();
+        esc
+    }
+
+    pub fn protocol(&self) -> EscProtocol {
+        self.protocol
+    }
+
+    pub fn state(&self) -> EscState {
+        self.state
+    }
+
+    /// Set the throttle endpoints of a motor
+    pub fn set_calibration(&mut self, motor: usize, calibration: EscCalibration) -> Result<(), EscError> {
+        if calibration.min_us >= calibration.max_us {
+            return Err(EscError::InvalidCalibration);
+        }
+        let m = self.motors.get_mut(motor).ok_or(EscError::InvalidMotor)?;
+        m.calibration = calibration;
+        Ok(())
+    }
+
+    /// Start the arming sequence (minimum throttle for `ARMING_TIME_MS`)
+    pub fn arm(&mut self, now_ms: u32) {
+        if self.state == EscState::Disarmed && safety::outputs_allowed() {
+            self.output_min();
+            self.state = EscState::Arming { started_ms: now_ms };
+        }
+    }
+
+    /// Drop back to minimum throttle and require re-arming
+    pub fn disarm(&mut self) {
+        self.state = EscState::Disarmed;
+        for motor in self.motors.iter_mut() {
+            motor.throttle = 0.0;
+        }
+        self.output_min();
+    }
+
+    /// Set a motor throttle (0.0 - 1.0). Only accepted once armed.
+    pub fn set_throttle(&mut self, motor: usize, throttle: f32) -> Result<(), EscError> {
+        if !safety::outputs_allowed() {
+            return Err(EscError::Inhibited);
+        }
+        if self.state != EscState::Armed {
+            return Err(EscError::NotArmed);
+        }
+        let m = self.motors.get_mut(motor).ok_or(EscError::InvalidMotor)?;
+        m.throttle = throttle.clamp(0.0, 1.0);
+        let pulse = pulse_for(m.calibration, m.throttle);
+        self.pwm.set_pulse_us(m.channel, pulse);
+        Ok(())
+    }
+
+    /// Advance the arming sequence, call periodically
+    pub fn update(&mut self, now_ms: u32) {
+        // Drop to minimum throttle as soon as the system leaves Armed
+        if self.state != EscState::Disarmed && !safety::outputs_allowed() {
+            self.disarm();
+            return;
+        }
+
+        if let EscState::Arming { started_ms } = self.state {
+            if now_ms.wrapping_sub(started_ms) >= ARMING_TIME_MS {
+                self.state = EscState::Armed;
+            }
+        }
+    }
+
+    fn output_min(&mut self) {
+        for motor in self.motors.iter() {
+            self.pwm.set_pulse_us(motor.channel, motor.calibration.min_us);
+        }
+    }
+}
+
+fn pulse_for(calibration: EscCalibration, throttle: f32) -> u16 {
+    let span = (calibration.max_us - calibration.min_us) as f32;
+    calibration.min_us + (throttle * span) as u16
+}
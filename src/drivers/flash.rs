@@ -1,7 +1,9 @@
 //! External Flash Memory Driver (W25Q128)
 #![no_std]
 
-use crate::hal::spi::{Spi, SpiMode};
+use crate::diagnostics::fault_inject::{self, FaultSite};
+use crate::hal::gpio::board::FLASH_CS;
+use crate::hal::spi::{ChipSelect, Spi, SpiDevice, SpiMode, SpiPrescaler};
 
 const WRITE_ENABLE: u8 = 0x06;
 const WRITE_DISABLE: u8 = 0x04;
@@ -19,15 +21,19 @@ const DEVICE_ID: u8 = 0x90;
 const JEDEC_ID: u8 = 0x9F;
 
 const PAGE_SIZE: usize = 256;
-const SECTOR_SIZE: usize = 4096;
+pub const SECTOR_SIZE: usize = 4096;
 const BLOCK_SIZE_32K: usize = 32768;
 const BLOCK_SIZE_64K: usize = 65536;
 
-pub struct Flash {
+// READ_DATA (0x03) is specified up to 50MHz, so the AVR's fastest SCK
+// (Fosc/2 with SPI2X) is fine
+const FLASH_MAX_SPI_HZ: u32 = 50_000_000;
+
+/// W25Q128 on the shared SPI bus. WP and HOLD have to be tied (or driven)
+/// high by the board.
+pub struct Flash<CS = FLASH_CS> {
     spi: Spi,
-    cs_pin: u8,
-    wp_pin: u8,
-    hold_pin: u8,
+    device: SpiDevice<CS>,
 }
 
 #[derive(Debug)]
@@ -56,13 +62,11 @@ struct FlashStatus {
 }
 */
 
-impl Flash {
-    pub fn new(spi: Spi, cs_pin: u8, wp_pin: u8, hold_pin: u8) -> Result<Self, FlashError> {
+impl<CS: ChipSelect> Flash<CS> {
+    pub fn new(spi: Spi, cs: CS) -> Result<Self, FlashError> {
         let mut flash = Self {
             spi,
-            cs_pin,
-            wp_pin,
-            hold_pin,
+            device: SpiDevice::new(cs, SpiMode::Mode0, SpiPrescaler::for_frequency(FLASH_MAX_SPI_HZ)),
         };
         
         flash.init()?;
@@ -70,11 +74,6 @@ impl Flash {
     }
 
     fn init(&mut self) -> Result<(), FlashError> {
-        self.spi.set_mode(SpiMode::Mode0);
-        self.set_pin_high(self.cs_pin);
-        self.set_pin_high(self.wp_pin);
-        self.set_pin_high(self.hold_pin);
-        
         let id = self.read_jedec_id()?;
         if id[0] != 0xEF || id[1] != 0x40 || id[2] != 0x18 {
             return Err(FlashError::WrongId);
@@ -84,23 +83,23 @@ impl Flash {
     }
 
     pub fn read(&mut self, addr: u32, buffer: &mut [u8]) -> Result<(), FlashError> {
-        self.wait_busy()?;
-        self.set_pin_low(self.cs_pin);
-        
-        self.spi.transfer(READ_DATA);
-        self.spi.transfer((addr >> 16) as u8);
-        self.spi.transfer((addr >> 8) as u8);
-        self.spi.transfer(addr as u8);
-        
-        for byte in buffer.iter_mut() {
-            *byte = self.spi.transfer(0x00);
+        if fault_inject::trip(FaultSite::FlashRead) {
+            return Err(FlashError::ReadError);
         }
-        
-        self.set_pin_high(self.cs_pin);
+        self.wait_busy()?;
+        self.device.transaction(&mut self.spi, |spi| {
+            send_command(spi, READ_DATA, addr);
+            for byte in buffer.iter_mut() {
+                *byte = spi.transfer(0x00);
+            }
+        });
         Ok(())
     }
 
     pub fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        if fault_inject::trip(FaultSite::FlashWrite) {
+            return Err(FlashError::WriteError);
+        }
         for (i, chunk) in data.chunks(PAGE_SIZE).enumerate() {
             let page_addr = addr + (i * PAGE_SIZE) as u32;
             self.write_page(page_addr, chunk)?;
@@ -109,111 +108,87 @@ impl Flash {
     }
 
     pub fn erase_sector(&mut self, addr: u32) -> Result<(), FlashError> {
-        self.wait_busy()?;
-        self.write_enable()?;
-        
-        self.set_pin_low(self.cs_pin);
-        self.spi.transfer(SECTOR_ERASE);
-        self.spi.transfer((addr >> 16) as u8);
-        self.spi.transfer((addr >> 8) as u8);
-        self.spi.transfer(addr as u8);
-        self.set_pin_high(self.cs_pin);
-        
-        self.wait_busy()?;
-        Ok(())
+        self.erase(SECTOR_ERASE, addr)
     }
 
     pub fn erase_block32k(&mut self, addr: u32) -> Result<(), FlashError> {
-        self.wait_busy()?;
-        self.write_enable()?;
-        
-        self.set_pin_low(self.cs_pin);
-        self.spi.transfer(BLOCK_ERASE_32K);
-        self.spi.transfer((addr >> 16) as u8);
-        self.spi.transfer((addr >> 8) as u8);
-        self.spi.transfer(addr as u8);
-        self.set_pin_high(self.cs_pin);
-        
-        self.wait_busy()?;
-        Ok(())
+        self.erase(BLOCK_ERASE_32K, addr)
     }
 
     pub fn erase_block64k(&mut self, addr: u32) -> Result<(), FlashError> {
-        self.wait_busy()?;
-        self.write_enable()?;
-        
-        self.set_pin_low(self.cs_pin);
-        self.spi.transfer(BLOCK_ERASE_64K);
-        self.spi.transfer((addr >> 16) as u8);
-        self.spi.transfer((addr >> 8) as u8);
-        self.spi.transfer(addr as u8);
-        self.set_pin_high(self.cs_pin);
-        
+        self.erase(BLOCK_ERASE_64K, addr)
+    }
+
+    /// Erase everything and wait for it. That takes far longer than the
+    /// watchdog allows, run `os::background::ChipEraseJob` from the main
+    /// loop instead.
+    pub fn erase_chip(&mut self) -> Result<(), FlashError> {
+        self.start_erase_chip()?;
         self.wait_busy()?;
         Ok(())
     }
 
-    pub fn erase_chip(&mut self) -> Result<(), FlashError> {
+    /// Start a chip erase and return, poll `is_busy` for the end
+    pub fn start_erase_chip(&mut self) -> Result<(), FlashError> {
+        if fault_inject::trip(FaultSite::FlashErase) {
+            return Err(FlashError::EraseError);
+        }
         self.wait_busy()?;
         self.write_enable()?;
-        
-        self.set_pin_low(self.cs_pin);
-        self.spi.transfer(CHIP_ERASE);
-        self.set_pin_high(self.cs_pin);
-        
-        self.wait_busy()?;
+        self.device.transaction(&mut self.spi, |spi| spi.transfer(CHIP_ERASE));
         Ok(())
     }
 
+    /// Whether an erase or program operation is still running
+    pub fn is_busy(&mut self) -> Result<bool, FlashError> {
+        Ok(self.read_status()? & 0x01 != 0)
+    }
+
     pub fn power_down(&mut self) -> Result<(), FlashError> {
         self.wait_busy()?;
-        
-        self.set_pin_low(self.cs_pin);
-        self.spi.transfer(POWER_DOWN);
-        self.set_pin_high(self.cs_pin);
-        
+        self.device.transaction(&mut self.spi, |spi| spi.transfer(POWER_DOWN));
         Ok(())
     }
 
     pub fn release_power_down(&mut self) -> Result<(), FlashError> {
-        self.set_pin_low(self.cs_pin);
-        self.spi.transfer(RELEASE_POWER_DOWN);
-        self.set_pin_high(self.cs_pin);
-        
+        self.device.transaction(&mut self.spi, |spi| spi.transfer(RELEASE_POWER_DOWN));
+        Ok(())
+    }
+
+    fn erase(&mut self, command: u8, addr: u32) -> Result<(), FlashError> {
+        if fault_inject::trip(FaultSite::FlashErase) {
+            return Err(FlashError::EraseError);
+        }
+        self.wait_busy()?;
+        self.write_enable()?;
+        self.device.transaction(&mut self.spi, |spi| send_command(spi, command, addr));
+        self.wait_busy()?;
         Ok(())
     }
 
     fn write_page(&mut self, addr: u32, data: &[u8]) -> Result<(), FlashError> {
         self.wait_busy()?;
         self.write_enable()?;
-        
-        self.set_pin_low(self.cs_pin);
-        self.spi.transfer(PAGE_PROGRAM);
-        self.spi.transfer((addr >> 16) as u8);
-        self.spi.transfer((addr >> 8) as u8);
-        self.spi.transfer(addr as u8);
-        
-        for &byte in data {
-            self.spi.transfer(byte);
-        }
-        
-        self.set_pin_high(self.cs_pin);
+        self.device.transaction(&mut self.spi, |spi| {
+            send_command(spi, PAGE_PROGRAM, addr);
+            for &byte in data {
+                spi.transfer(byte);
+            }
+        });
         self.wait_busy()?;
         Ok(())
     }
 
     fn write_enable(&mut self) -> Result<(), FlashError> {
-        self.set_pin_low(self.cs_pin);
-        self.spi.transfer(WRITE_ENABLE);
-        self.set_pin_high(self.cs_pin);
+        self.device.transaction(&mut self.spi, |spi| spi.transfer(WRITE_ENABLE));
         Ok(())
     }
 
     fn read_status(&mut self) -> Result<u8, FlashError> {
-        self.set_pin_low(self.cs_pin);
-        self.spi.transfer(READ_STATUS);
-        let status = self.spi.transfer(0x00);
-        self.set_pin_high(self.cs_pin);
+        let status = self.device.transaction(&mut self.spi, |spi| {
+            spi.transfer(READ_STATUS);
+            spi.transfer(0x00)
+        });
         Ok(status)
     }
 
@@ -230,28 +205,20 @@ impl Flash {
 
     fn read_jedec_id(&mut self) -> Result<[u8; 3], FlashError> {
         let mut id = [0u8; 3];
-        self.set_pin_low(self.cs_pin);
-        self.spi.transfer(JEDEC_ID);
-        id[0] = self.spi.transfer(0x00);
-        id[1] = self.spi.transfer(0x00);
-        id[2] = self.spi.transfer(0x00);
-        self.set_pin_high(self.cs_pin);
+        self.device.transaction(&mut self.spi, |spi| {
+            spi.transfer(JEDEC_ID);
+            for byte in id.iter_mut() {
+                *byte = spi.transfer(0x00);
+            }
+        });
         Ok(id)
     }
+}
 
-    fn set_pin_high(&mut self, pin: u8) {
-        unsafe {
-            (*avr_device::atmega128::PORTB::ptr()).portb.modify(|r, w| 
-                w.bits(r.bits() | (1 << pin))
-            );
-        }
-    }
-
-    fn set_pin_low(&mut self, pin: u8) {
-        unsafe {
-            (*avr_device::atmega128::PORTB::ptr()).portb.modify(|r, w| 
-                w.bits(r.bits() & !(1 << pin))
-            );
-        }
-    }
+/// Command byte followed by a 24-bit address
+fn send_command(spi: &mut Spi, command: u8, addr: u32) {
+    spi.transfer(command);
+    spi.transfer((addr >> 16) as u8);
+    spi.transfer((addr >> 8) as u8);
+    spi.transfer(addr as u8);
 }
@@ -0,0 +1,266 @@
+//! HX711 load cell amplifier driver
+//!
+//! The HX711 has no bus, just a clock input (PD_SCK) and a data output
+//! (DOUT) on any two GPIO pins, driven here through the embedded-hal
+//! digital traits. DOUT goes low when a conversion is ready; the 24-bit
+//! two's complement result is then clocked out MSB first, and 1 to 3
+//! further clock pulses pick the channel and gain of the next conversion.
+//!
+//! Holding PD_SCK high for more than 60us powers the chip down, so a read
+//! runs with interrupts off, about 50us at 16MHz.
+//!
+//! Readings go through a moving average; `tare` and `calibrate` turn the
+//! averaged counts into weight and are persisted to EEPROM next to the
+//! other configuration with `save_calibration`.
+
+#![no_std]
+
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use crate::config::EEPROM_HX711_ADDR;
+use crate::drivers::noise_injection::{self, NoiseTarget};
+use crate::hal::eeprom;
+
+/// Readings averaged by the stabilizer
+pub const HX711_AVERAGE: usize = 8;
+
+/// Spread of the averaged readings, in counts, below which the weight
+/// counts as stable
+pub const DEFAULT_STABLE_COUNTS: i32 = 200;
+
+const CALIBRATION_MAGIC: u16 = 0x7A11;
+// magic, offset i32, scale f32
+const CALIBRATION_SIZE: u16 = 10;
+
+// NOPs per half clock period, the HX711 needs at least 0.2us
+const HALF_PERIOD_NOPS: u8 = 8;
+
+/// Input and gain of the conversion after the next read
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum Hx711Gain {
+    /// Channel A, +-20mV full scale
+    A128 = 1,
+    /// Channel B, +-80mV full scale
+    B32 = 2,
+    /// Channel A, +-40mV full scale
+    A64 = 3,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Hx711Error {
+    /// No conversion ready, DOUT still high
+    NotReady,
+    /// The average is not filled yet
+    NotSettled,
+    /// Calibration weight too small to derive a scale from
+    BadCalibration,
+}
+
+pub struct Hx711<SCK, DOUT> {
+    sck: SCK,
+    dout: DOUT,
+    gain: Hx711Gain,
+    readings: [i32; HX711_AVERAGE],
+    head: usize,
+    len: usize,
+    /// Raw counts at zero load
+    offset: i32,
+    /// Counts per weight unit
+    scale: f32,
+    stable_counts: i32,
+    /// The next conversion was made with the previous gain
+    discard: bool,
+}
+
+impl<SCK: OutputPin, DOUT: InputPin> Hx711<SCK, DOUT> {
+    /// The gain applies from the second conversion on, the chip always
+    /// starts on channel A at 128
+    pub fn new(sck: SCK, dout: DOUT, gain: Hx711Gain) -> Self {
+        let mut hx711 = Self {
+            sck,
+            dout,
+            gain,
+            readings: [0; HX711_AVERAGE],
+            head: 0,
+            len: 0,
+            offset: 0,
+            scale: 1.0,
+            stable_counts: DEFAULT_STABLE_COUNTS,
+            discard: gain != Hx711Gain::A128,
+        };
+        hx711.sck.set_low().ok();
+        hx711
+    }
+
+    /// Change channel and gain. The conversion already running was made
+    /// with the old setting and is read and dropped.
+    pub fn set_gain(&mut self, gain: Hx711Gain) {
+        self.gain = gain;
+        self.discard = true;
+        self.clear_average();
+    }
+
+    pub fn gain(&self) -> Hx711Gain {
+        self.gain
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.dout.is_low().unwrap_or(false)
+    }
+
+    /// Read the finished conversion, 24 bits sign extended
+    pub fn read_raw(&mut self) -> Result<i32, Hx711Error> {
+        if !self.is_ready() {
+            return Err(Hx711Error::NotReady);
+        }
+
+        let raw = avr_device::interrupt::free(|_| {
+            let mut value = 0u32;
+            for _ in 0..24 {
+                value = (value << 1) | self.clock_bit() as u32;
+            }
+            for _ in 0..self.gain as u8 {
+                self.clock_bit();
+            }
+            value
+        });
+        let mut value = [((raw << 8) as i32) >> 8];
+        // An injected dropout is a conversion that never became ready
+        if !noise_injection::inject(NoiseTarget::Hx711, &mut value) {
+            return Err(Hx711Error::NotReady);
+        }
+        Ok(value[0])
+    }
+
+    fn clock_bit(&mut self) -> bool {
+        self.sck.set_high().ok();
+        for _ in 0..HALF_PERIOD_NOPS {
+            avr_device::asm::nop();
+        }
+        self.sck.set_low().ok();
+        for _ in 0..HALF_PERIOD_NOPS {
+            avr_device::asm::nop();
+        }
+        self.dout.is_high().unwrap_or(false)
+    }
+
+    /// Read a conversion if one is ready and add it to the average. Call
+    /// from the main loop; the HX711 converts at 10 or 80 per second. The
+    /// first conversion after a gain change is dropped and gives `None`.
+    pub fn update(&mut self) -> Option<i32> {
+        let raw = self.read_raw().ok()?;
+        if self.discard {
+            self.discard = false;
+            return None;
+        }
+        self.readings[self.head] = raw;
+        self.head = (self.head + 1) % HX711_AVERAGE;
+        self.len = (self.len + 1).min(HX711_AVERAGE);
+        Some(raw)
+    }
+
+    pub fn clear_average(&mut self) {
+        self.len = 0;
+        self.head = 0;
+    }
+
+    /// Averaged counts, `None` until the first reading
+    pub fn average(&self) -> Option<i32> {
+        if self.len == 0 {
+            return None;
+        }
+        let sum: i32 = self.readings[..self.len].iter().sum();
+        Some(sum / self.len as i32)
+    }
+
+    /// True once the average is full and its readings agree within the
+    /// stable spread
+    pub fn is_stable(&self) -> bool {
+        if self.len < HX711_AVERAGE {
+            return false;
+        }
+        let min = self.readings.iter().min().copied().unwrap_or(0);
+        let max = self.readings.iter().max().copied().unwrap_or(0);
+        max - min <= self.stable_counts
+    }
+
+    pub fn set_stable_counts(&mut self, counts: i32) {
+        self.stable_counts = counts;
+    }
+
+    /// Averaged weight in the calibration unit
+    pub fn weight(&self) -> Option<f32> {
+        self.average().map(|avg| (avg - self.offset) as f32 / self.scale)
+    }
+
+    /// Take the current average as zero load
+    pub fn tare(&mut self) -> Result<(), Hx711Error> {
+        if self.len < HX711_AVERAGE {
+            return Err(Hx711Error::NotSettled);
+        }
+        self.offset = self.average().unwrap_or(0);
+        Ok(())
+    }
+
+    /// Derive the scale from a known weight on the tared cell
+    pub fn calibrate(&mut self, known_weight: f32) -> Result<(), Hx711Error> {
+        if self.len < HX711_AVERAGE {
+            return Err(Hx711Error::NotSettled);
+        }
+        let counts = (self.average().unwrap_or(0) - self.offset) as f32;
+        if known_weight == 0.0 || counts == 0.0 {
+            return Err(Hx711Error::BadCalibration);
+        }
+        self.scale = counts / known_weight;
+        Ok(())
+    }
+
+    /// Zero offset in counts and counts per unit
+    pub fn calibration(&self) -> (i32, f32) {
+        (self.offset, self.scale)
+    }
+
+    pub fn set_calibration(&mut self, offset: i32, scale: f32) {
+        self.offset = offset;
+        self.scale = if scale != 0.0 { scale } else { 1.0 };
+    }
+
+    /// Persist offset and scale to EEPROM
+    pub fn save_calibration(&self) {
+        let mut record = [0u8; CALIBRATION_SIZE as usize];
+        record[0..2].copy_from_slice(&CALIBRATION_MAGIC.to_le_bytes());
+        record[2..6].copy_from_slice(&self.offset.to_le_bytes());
+        record[6..10].copy_from_slice(&self.scale.to_le_bytes());
+        for (i, &byte) in record.iter().enumerate() {
+            eeprom::update_byte(EEPROM_HX711_ADDR + i as u16, byte);
+        }
+    }
+
+    /// Restore what `save_calibration` stored
+    pub fn load_calibration(&mut self) -> Result<(), ()> {
+        let mut record = [0u8; CALIBRATION_SIZE as usize];
+        for (i, byte) in record.iter_mut().enumerate() {
+            *byte = eeprom::read_byte(EEPROM_HX711_ADDR + i as u16);
+        }
+        if u16::from_le_bytes([record[0], record[1]]) != CALIBRATION_MAGIC {
+            return Err(());
+        }
+        let offset = i32::from_le_bytes([record[2], record[3], record[4], record[5]]);
+        let scale = f32::from_le_bytes([record[6], record[7], record[8], record[9]]);
+        self.set_calibration(offset, scale);
+        Ok(())
+    }
+
+    /// Enter power down, about 1uA. The next conversion after `power_up`
+    /// is on channel A at 128 again.
+    pub fn power_down(&mut self) {
+        self.sck.set_low().ok();
+        self.sck.set_high().ok();
+    }
+
+    pub fn power_up(&mut self) {
+        self.sck.set_low().ok();
+        self.clear_average();
+    }
+}
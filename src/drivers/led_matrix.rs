@@ -1,62 +1,285 @@
-use crate::hal::gpio::board::{LED0, LED1, LED2, LED3};
-use crate::hal::gpio::Output;
-use crate::hal::gpio::Pin;
-use crate::hal::delay_ms;
-use avr_device::atmega128::PORTA;
+//! Status LEDs with a non-blocking animation engine
+//!
+//! An `Animation` is a list of LED patterns with how long each one is
+//! shown. `play` starts one right away, `queue` lines it up behind the
+//! current one; `tick` is called from the main loop with the system tick
+//! and switches frames when they are due, so nothing ever waits on a
+//! delay. A repeating animation keeps going until something is queued,
+//! then finishes its current pass and hands over.
+//!
+//! Boards that switch the LEDs' common side from OC2 can hand a `Pwm8` to
+//! `set_dimmer`; brightness then fades independently of the pattern.
+use avr_device::atmega128::TC2;
+
+use crate::hal::gpio::board;
+use crate::hal::gpio::PortBus;
+use crate::hal::pwm::{Pwm8, PwmMode};
+use crate::time::{Duration, Instant};
+
+const ANIMATION_QUEUE_LEN: usize = 4;
+
+/// Dimmer PWM frequency, well above visible flicker
+pub const DIMMER_FREQ_HZ: u32 = 1000;
+
+/// One step of an animation
+#[derive(Clone, Copy)]
+pub struct Frame {
+    pub pattern: u8,
+    pub duration_ms: u16,
+}
+
+impl Frame {
+    pub const fn new(pattern: u8, duration_ms: u16) -> Self {
+        Self { pattern, duration_ms }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Animation {
+    pub frames: &'static [Frame],
+    /// Start over after the last frame instead of ending
+    pub repeat: bool,
+}
+
+/// Light sweeping back and forth, 100ms per step
+pub const KNIGHT_RIDER: Animation = Animation {
+    frames: &[
+        Frame::new(0x01, 100),
+        Frame::new(0x02, 100),
+        Frame::new(0x04, 100),
+        Frame::new(0x08, 100),
+        Frame::new(0x04, 100),
+        Frame::new(0x02, 100),
+    ],
+    repeat: true,
+};
+
+/// All LEDs flash twice, for acknowledging an input
+pub const BLINK_TWICE: Animation = Animation {
+    frames: &[
+        Frame::new(0x0F, 80),
+        Frame::new(0x00, 80),
+        Frame::new(0x0F, 80),
+        Frame::new(0x00, 80),
+    ],
+    repeat: false,
+};
+
+struct Playback {
+    animation: Animation,
+    frame: usize,
+    /// When the current frame is over
+    due: Instant,
+}
+
+struct Dimmer {
+    pwm: Pwm8<TC2>,
+    /// Brightness in percent at `start` and at the end of the fade
+    from: u8,
+    to: u8,
+    start: Instant,
+    duration_ms: u32,
+}
 
 pub struct LedMatrix {
-    leds: [Pin<PORTA, u8, Output>; 4],
+    leds: PortBus,
+    dimmer: Option<Dimmer>,
+    playing: Option<Playback>,
+    queue: [Option<Animation>; ANIMATION_QUEUE_LEN],
+    /// Time left in the current frame while paused
+    paused: Option<Duration>,
 }
 
 impl LedMatrix {
     pub fn new() -> Self {
-        // Create LED instances - note the unsafe block is needed for raw register access
-        // but we ensure safety through our type system
         LedMatrix {
-            leds: [
-                LED0::default().into_output(),
-                LED1::default().into_output(),
-                LED2::default().into_output(),
-                LED3::default().into_output(),
-            ],
+            leds: board::led_bar(),
+            dimmer: None,
+            playing: None,
+            queue: [None; ANIMATION_QUEUE_LEN],
+            paused: None,
         }
     }
 
+    /// Bit n lights LEDn, all four change in one write
     pub fn set_pattern(&mut self, pattern: u8) {
-        for (i, led) in self.leds.iter_mut().enumerate() {
-            if (pattern & (1 << i)) != 0 {
-                led.set_high();
-            } else {
-                led.set_low();
+        self.leds.write(pattern);
+    }
+
+    pub fn toggle_all(&mut self) {
+        self.leds.toggle(0xFF);
+    }
+
+    pub fn set_all(&mut self, state: bool) {
+        self.leds.write(if state { 0xFF } else { 0x00 });
+    }
+
+    /// Dim through OC2, starting at full brightness. `None` hands the
+    /// timer back.
+    pub fn set_dimmer(&mut self, pwm: Option<Pwm8<TC2>>, now: Instant) {
+        if let Some(mut old) = self.dimmer.take() {
+            old.pwm.stop();
+        }
+        self.dimmer = pwm.map(|mut pwm| {
+            pwm.configure(PwmMode::Fast, DIMMER_FREQ_HZ);
+            pwm.set_duty(100.0);
+            Dimmer {
+                pwm,
+                from: 100,
+                to: 100,
+                start: now,
+                duration_ms: 0,
             }
+        });
+    }
+
+    /// Current brightness in percent, `None` without a dimmer
+    pub fn brightness(&self, now: Instant) -> Option<u8> {
+        self.dimmer.as_ref().map(|dimmer| dimmer.level(now))
+    }
+
+    /// Fade from the current brightness to `percent` over `duration_ms`,
+    /// 0 jumps there. Ignored without a dimmer.
+    pub fn fade_to(&mut self, percent: u8, duration_ms: u16, now: Instant) {
+        if let Some(dimmer) = self.dimmer.as_mut() {
+            dimmer.from = dimmer.level(now);
+            dimmer.to = percent.min(100);
+            dimmer.start = now;
+            dimmer.duration_ms = duration_ms as u32;
+            dimmer.apply(now);
         }
     }
 
-    pub fn toggle_all(&mut self) {
-        for led in self.leds.iter_mut() {
-            led.toggle();
+    /// Start an animation now, dropping the current one and the queue
+    pub fn play(&mut self, animation: Animation, now: Instant) {
+        self.queue = [None; ANIMATION_QUEUE_LEN];
+        self.paused = None;
+        self.start(animation, now);
+    }
+
+    /// Play an animation once the current one is done
+    pub fn queue(&mut self, animation: Animation, now: Instant) -> Result<(), ()> {
+        if self.playing.is_none() && self.paused.is_none() {
+            self.start(animation, now);
+            return Ok(());
+        }
+        match self.queue.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(animation);
+                Ok(())
+            }
+            None => Err(()),
         }
     }
 
-    pub fn set_all(&mut self, state: bool) {
-        for led in self.leds.iter_mut() {
-            if state {
-                led.set_high();
-            } else {
-                led.set_low();
+    /// Freeze on the current frame
+    pub fn pause(&mut self, now: Instant) {
+        if let Some(playback) = self.playing.as_ref() {
+            if self.paused.is_none() {
+                let left = if playback.due.is_after(now) { playback.due - now } else { Duration::ZERO };
+                self.paused = Some(left);
+            }
+        }
+    }
+
+    pub fn resume(&mut self, now: Instant) {
+        if let (Some(left), Some(playback)) = (self.paused.take(), self.playing.as_mut()) {
+            playback.due = now + left;
+        }
+    }
+
+    /// End all animations and turn the LEDs off
+    pub fn stop(&mut self) {
+        self.playing = None;
+        self.paused = None;
+        self.queue = [None; ANIMATION_QUEUE_LEN];
+        self.set_all(false);
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.is_some() && self.paused.is_none()
+    }
+
+    /// Advance the animation and the fade, call from the main loop
+    pub fn tick(&mut self, now: Instant) {
+        if let Some(dimmer) = self.dimmer.as_mut() {
+            dimmer.apply(now);
+        }
+        if self.paused.is_some() {
+            return;
+        }
+        // Catch up frame by frame if the loop was slow, keeping the timing
+        while let Some(playback) = self.playing.as_mut() {
+            if !now.has_reached(playback.due) {
+                return;
+            }
+            let due = playback.due;
+            playback.frame += 1;
+            if playback.frame >= playback.animation.frames.len() {
+                if let Some(next) = self.take_queued() {
+                    self.start(next, due);
+                    continue;
+                }
+                let playback = self.playing.as_mut().unwrap();
+                if !playback.animation.repeat {
+                    self.playing = None;
+                    return;
+                }
+                playback.frame = 0;
             }
+            self.show_frame(due);
         }
     }
 
-    // Knight Rider effect 🚗 (because why not?)
-    pub fn knight_rider(&mut self, delay_ms: u16) {
-        for i in 0..self.leds.len() {
-            self.set_pattern(1 << i);
-            delay_ms(delay_ms);
+    fn start(&mut self, animation: Animation, now: Instant) {
+        if animation.frames.is_empty() {
+            self.playing = None;
+            return;
         }
-        for i in (0..self.leds.len()).rev() {
-            self.set_pattern(1 << i);
-            delay_ms(delay_ms);
+        self.playing = Some(Playback {
+            animation,
+            frame: 0,
+            due: now,
+        });
+        self.show_frame(now);
+    }
+
+    /// Light the current frame, shown from `start` on
+    fn show_frame(&mut self, start: Instant) {
+        let pattern = match self.playing.as_mut() {
+            Some(playback) => {
+                let frame = playback.animation.frames[playback.frame];
+                // A zero length frame would spin the catch-up loop forever
+                let duration_ms = frame.duration_ms.max(1) as u32;
+                playback.due = start + Duration::from_millis(duration_ms);
+                frame.pattern
+            }
+            None => return,
+        };
+        self.set_pattern(pattern);
+    }
+
+    fn take_queued(&mut self) -> Option<Animation> {
+        let next = self.queue[0].take();
+        self.queue.rotate_left(1);
+        next
+    }
+}
+
+impl Dimmer {
+    fn level(&self, now: Instant) -> u8 {
+        let elapsed = now.duration_since(self.start).as_millis();
+        if elapsed >= self.duration_ms {
+            return self.to;
+        }
+        let span = self.to as i32 - self.from as i32;
+        (self.from as i32 + span * elapsed as i32 / self.duration_ms as i32) as u8
+    }
+
+    fn apply(&mut self, now: Instant) {
+        let duty = self.level(now) as u16 * 255 / 100;
+        if duty as u8 != self.pwm.duty_raw() {
+            self.pwm.set_raw(duty as u8);
         }
     }
 }
@@ -0,0 +1,38 @@
+//! LM75 digital temperature sensor driver
+#![no_std]
+
+use crate::drivers::noise_injection::{self, NoiseTarget};
+use crate::hal::Twi;
+
+/// Default address with A2..A0 tied low
+pub const LM75_DEFAULT_ADDR: u8 = 0x48;
+
+const REG_TEMP: u8 = 0x00;
+
+pub struct Lm75 {
+    twi: Twi,
+    address: u8,
+}
+
+impl Lm75 {
+    pub fn new(twi: Twi, address: u8) -> Self {
+        Self { twi, address }
+    }
+
+    /// Read the temperature in tenths of a degree Celsius
+    pub fn read_temperature(&mut self) -> Result<i16, ()> {
+        let mut data = [0u8; 2];
+        // The TWI driver keeps the error for diagnostics
+        self.twi
+            .read_regs(self.address, REG_TEMP, &mut data)
+            .map_err(|_| ())?;
+
+        // 9-bit two's complement, 0.5°C per LSB, left aligned
+        let raw = ((data[0] as i16) << 8 | data[1] as i16) >> 7;
+        let mut tenths = [raw as i32 * 5];
+        if !noise_injection::inject(NoiseTarget::Lm75, &mut tenths) {
+            return Err(());
+        }
+        Ok(tenths[0].clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+    }
+}
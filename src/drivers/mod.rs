@@ -1,11 +1,47 @@
 pub mod button_handler;
+pub mod debounced_input;
+pub mod dual_imu;
+pub mod encoder;
+pub mod esc;
+pub mod flash;
+pub mod hx711;
 pub mod led_matrix;
+pub mod lm75;
+pub mod motor_control;
 pub mod mpu6050;
+pub mod noise_injection;
+pub mod pulse_counter;
+pub mod rc_input;
+pub mod redundant_store;
+pub mod sampling_plan;
+pub mod sensor_fusion;
+pub mod sensor_manager;
 pub mod serial_console;
+pub mod servo;
+pub mod sync_acquisition;
+pub mod weather;
 
 pub use button_handler::{Button, ButtonEvent, ButtonHandler};
-pub use led_matrix::LedMatrix;
-pub use mpu6050::{AccelScale, GyroScale, Mpu6050, Vec3};
-pub use serial_console::SerialConsole;
+pub use debounced_input::{DebounceConfig, DebouncedInput, InputEdge};
+pub use dual_imu::{DivergenceLimits, DualImu};
+pub use encoder::{Encoder, LatchMode, PositionLatch};
+pub use esc::{EscCalibration, EscController, EscProtocol, EscState};
+pub use flash::{Flash, FlashError};
+pub use hx711::{Hx711, Hx711Error, Hx711Gain};
+pub use led_matrix::{Animation, Frame, LedMatrix};
+pub use lm75::Lm75;
+pub use motor_control::{MotorController, PidConfig};
+pub use mpu6050::{AccelScale, GyroScale, Mpu6050, Mpu6050Address, Vec3};
+pub use noise_injection::{NoiseConfig, NoiseTarget};
+pub use pulse_counter::{PulseCounter, PulseEdge};
+pub use rc_input::{RcFrame, RcInput, RcSource, SbusDecoder};
+pub use redundant_store::{CopyMedium, EepromCopies, FlashCopies, StoreError};
+pub use sampling_plan::{PlanEntry, SamplingPlan, Sink};
+pub use sensor_fusion::MadgwickFilter;
+pub use sensor_manager::{HotPlug, SensorEvent, SensorManager, SensorStatus};
+pub use serial_console::{LineInput, SerialConsole};
+pub use servo::{Servo, ServoCalibration, ServoError, ServoTimers};
+pub use sync_acquisition::{SyncAcquisition, SyncSample};
+pub use weather::{Anemometer, RainGauge, VaneKind, WeatherReading, WeatherStation, WindVane};
 
 // TODO: Add other sensor drivers
\ No newline at end of file
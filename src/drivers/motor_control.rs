@@ -1,19 +1,32 @@
 //! Motor control with PID regulation
+//!
+//! A motor runs either on one PWM channel (one direction, output 0-100%)
+//! or on a full H-bridge driven in locked anti-phase from a complementary
+//! pair: the high channel switches the high side of one leg and the low
+//! side of the other, the low channel the remaining two, with dead time
+//! between them. 50% duty is standstill, the output then spans -100..100%
+//! and its sign is the direction.
 #![no_std]
 
-use crate::hal::{Pwm, PwmChannel, PwmFreq, TC1};
+use crate::control::{Ramp, RampConfig};
+use crate::hal::claims::{self, Resource};
+use crate::hal::gpio::DynPin;
+use crate::hal::pwm::soft_start_limit;
+use crate::hal::{systime, Pwm, PwmChannel, PwmError, PwmFreq, PwmMode, TC1};
+use crate::safety;
+use crate::time::Instant;
 
 /// PID controller configuration
 #[derive(Clone)]
 pub struct PidConfig {
-    kp: f32,
-    ki: f32,
-    kd: f32,
-    output_min: f32,
-    output_max: f32,
-    iterm_min: f32,
-    iterm_max: f32,
-    sample_time_ms: u16,
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    pub output_min: f32,
+    pub output_max: f32,
+    pub iterm_min: f32,
+    pub iterm_max: f32,
+    pub sample_time_ms: u16,
 }
 
 impl Default for PidConfig {
@@ -35,7 +48,7 @@ impl Default for PidConfig {
 struct PidState {
     last_input: f32,
     iterm: f32,
-    last_time: u32,
+    last_time: Instant,
     last_output: f32,
 }
 
@@ -44,7 +57,7 @@ impl Default for PidState {
         Self {
             last_input: 0.0,
             iterm: 0.0,
-            last_time: 0,
+            last_time: Instant::from_ticks(0),
             last_output: 0.0,
         }
     }
@@ -80,37 +93,110 @@ struct MotorParams {
 pub struct MotorController {
     pwm: Pwm<TC1>,
     channel: PwmChannel,
+    // Low channel of an H-bridge, `channel` is the high one
+    bridge_low: Option<PwmChannel>,
     setpoint: f32,
+    ramp: Option<Ramp>,
     config: PidConfig,
     state: PidState,
     enabled: bool,
+    derating: f32,
 }
 
 impl MotorController {
     /// Create new motor controller
     pub fn new(channel: PwmChannel) -> Self {
+        // Motors share the timer, one per channel; `Pwm::new` claims it
         let mut pwm = Pwm::new();
         pwm.configure(PwmFreq::Hz20000, crate::hal::PwmMode::Fast);
         
         Self {
             pwm,
             channel,
+            bridge_low: None,
             setpoint: 0.0,
+            ramp: None,
             config: PidConfig::default(),
             state: PidState::default(),
             enabled: false,
+            derating: 1.0,
         }
     }
 
+    /// Motor on an H-bridge driven by the complementary pair `high`/`low`
+    /// with at least `dead_time_ns` between the switches of a leg. The
+    /// output range defaults to -100..100%; the bridge coasts with all
+    /// switches off while the controller is disabled.
+    pub fn h_bridge(high: PwmChannel, low: PwmChannel, dead_time_ns: u16) -> Result<Self, PwmError> {
+        let mut pwm = Pwm::new();
+        pwm.configure(PwmFreq::Hz20000, PwmMode::PhaseCorrect);
+        for channel in [high, low] {
+            let (port, bit) = channel.pin();
+            claims::claim(Resource::Pin(port, bit), "motor_control").ok();
+            let mut pin = DynPin::new(port, bit);
+            pin.set_low();
+            pin.into_output();
+        }
+        pwm.set_complementary(high, low, dead_time_ns)?;
+
+        let config = PidConfig {
+            output_min: -100.0,
+            ..PidConfig::default()
+        };
+        Ok(Self {
+            pwm,
+            channel: high,
+            bridge_low: Some(low),
+            setpoint: 0.0,
+            ramp: None,
+            config,
+            state: PidState::default(),
+            enabled: false,
+            derating: 1.0,
+        })
+    }
+
     /// Configure PID parameters
     pub fn configure(&mut self, config: PidConfig) {
         self.config = config;
         self.reset();
     }
 
+    /// Current PID parameters
+    pub fn config(&self) -> &PidConfig {
+        &self.config
+    }
+
     /// Set target value
     pub fn set_target(&mut self, setpoint: f32) {
-        self.setpoint = setpoint;
+        match self.ramp.as_mut() {
+            Some(ramp) => ramp.set_target(setpoint),
+            None => self.setpoint = setpoint,
+        }
+    }
+
+    /// Limit how fast the setpoint may change, `None` applies steps directly
+    pub fn set_ramp(&mut self, config: Option<RampConfig>) {
+        self.ramp = match config {
+            Some(config) => {
+                let mut ramp = Ramp::new(config, self.setpoint);
+                if let Some(old) = self.ramp.as_ref() {
+                    ramp.set_target(old.target());
+                }
+                Some(ramp)
+            }
+            None => {
+                if let Some(old) = self.ramp.as_ref() {
+                    self.setpoint = old.target();
+                }
+                None
+            }
+        };
+    }
+
+    /// Scale the maximum output (0.0 - 1.0), e.g. for thermal derating
+    pub fn set_derating(&mut self, factor: f32) {
+        self.derating = factor.clamp(0.0, 1.0);
     }
 
     /// Enable/disable motor control
@@ -118,8 +204,15 @@ impl MotorController {
         if enabled != self.enabled {
             self.enabled = enabled;
             if !enabled {
-                self.pwm.set_duty(self.channel, 0.0);
+                self.stop_output();
                 self.reset();
+                // Ramp up from standstill again on re-enable
+                if let Some(ramp) = self.ramp.as_mut() {
+                    let target = ramp.target();
+                    ramp.reset(0.0);
+                    ramp.set_target(target);
+                    self.setpoint = 0.0;
+                }
             }
         }
     }
@@ -130,13 +223,26 @@ impl MotorController {
             return 0.0;
         }
 
-        let now = get_millis();
-        let dt = (now - self.state.last_time) as f32 / 1000.0;
+        if !safety::outputs_allowed() {
+            self.stop_output();
+            self.reset();
+            return 0.0;
+        }
+
+        // Between samples the output still follows the soft-start ramp
+        self.pwm.service();
+
+        let now = Instant::from_ticks(systime::millis());
+        let dt = now.duration_since(self.state.last_time).as_secs_f32();
         
         if dt < self.config.sample_time_ms as f32 / 1000.0 {
             return self.state.last_output;
         }
 
+        if let Some(ramp) = self.ramp.as_mut() {
+            self.setpoint = ramp.update(dt);
+        }
+
         // Calculate error
         let error = self.setpoint - input;
         
@@ -159,9 +265,14 @@ impl MotorController {
 
         // Calculate output
         let mut output = pterm + self.state.iterm + dterm;
+        // Derating limits a bridge in both directions
+        let output_min = match self.bridge_low {
+            Some(_) => self.config.output_min * self.derating,
+            None => self.config.output_min,
+        };
         output = output.clamp(
-            self.config.output_min,
-            self.config.output_max
+            output_min,
+            (self.config.output_max * self.derating).max(output_min)
         );
 
         // Update state
@@ -170,11 +281,35 @@ impl MotorController {
         self.state.last_output = output;
 
         // Set PWM duty cycle
-        self.pwm.set_duty(self.channel, output);
+        self.write_output(output);
 
         output
     }
 
+    fn write_output(&mut self, output: f32) {
+        match self.bridge_low {
+            Some(_) => {
+                // The soft-start cap limits the drive, not the duty: a
+                // capped duty would run the motor backwards
+                let limit = soft_start_limit();
+                let output = output.clamp(-limit, limit);
+                self.pwm.set_complementary_duty(50.0 + output / 2.0);
+            }
+            None => self.pwm.set_duty(self.channel, output),
+        }
+    }
+
+    fn stop_output(&mut self) {
+        match self.bridge_low {
+            // All four switches off, the motor coasts
+            Some(low) => {
+                self.pwm.disable(self.channel);
+                self.pwm.disable(low);
+            }
+            None => self.pwm.set_duty(self.channel, 0.0),
+        }
+    }
+
     /// Reset controller state
     pub fn reset(&mut self) {
         self.state = PidState::default();
@@ -212,9 +347,3 @@ struct AdvancedMotorControl {
     current_error_peak: f32,
 }
 */
-
-// Helper function to get millisecond timestamp
-fn get_millis() -> u32 {
-    // TODO: Implement proper timer
-    0
-}
@@ -1,9 +1,24 @@
 //! MPU6050 6-axis IMU driver
+//!
+//! The latest raw readings of each sensor are offered to the host as the
+//! `ImuSnapshot` record, instance 0 for AD0 low and 1 for AD0 high.
 #![no_std]
 
-use crate::hal::Twi;
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
 
-const MPU6050_ADDR: u8 = 0x68;
+use crate::drivers::noise_injection::{self, NoiseTarget};
+use crate::estimation::GyroFilter;
+use crate::hal::{Twi, TwiAsyncError, TwiTicket};
+use crate::protocol::records::{self, RecordType, SUBSYSTEM_IMU};
+
+/// I2C address selected by the AD0 pin
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Mpu6050Address {
+    Ad0Low = 0x68,
+    Ad0High = 0x69,
+}
 
 // MPU6050 registers
 const REG_PWR_MGMT_1: u8 = 0x6B;
@@ -11,7 +26,31 @@ const REG_SMPLRT_DIV: u8 = 0x19;
 const REG_CONFIG: u8 = 0x1A;
 const REG_GYRO_CONFIG: u8 = 0x1B;
 const REG_ACCEL_CONFIG: u8 = 0x1C;
+const REG_INT_PIN_CFG: u8 = 0x37;
+const REG_INT_ENABLE: u8 = 0x38;
 const REG_ACCEL_XOUT_H: u8 = 0x3B;
+const REG_TEMP_OUT_H: u8 = 0x41;
+const REG_WHO_AM_I: u8 = 0x75;
+
+// ACCEL_XOUT_H through GYRO_ZOUT_L
+const MOTION_BURST_LEN: usize = 14;
+
+// INT_PIN_CFG: active low, push-pull, held until any register is read
+const INT_ACTIVE_LOW_LATCHED: u8 = 0xB0;
+const DATA_RDY_EN: u8 = 0x01;
+
+// WHO_AM_I reads 0x68 regardless of the AD0 pin
+const WHO_AM_I_VALUE: u8 = 0x68;
+
+const RECORD_SNAPSHOT: u8 = 0x01;
+
+// Snapshot fields, raw counts as in the `ImuSnapshot` record
+const SNAP_ACCEL: usize = 0;
+const SNAP_GYRO: usize = 3;
+const SNAP_TEMP: usize = 6;
+
+// Latest raw accel x/y/z, gyro x/y/z and temperature per address
+static SNAPSHOTS: Mutex<RefCell<[Option<[i16; 7]>; 2]>> = Mutex::new(RefCell::new([None; 2]));
 
 /// Accelerometer full-scale range
 #[derive(Clone, Copy)]
@@ -42,21 +81,36 @@ pub struct Vec3 {
 /// MPU6050 driver
 pub struct Mpu6050 {
     twi: Twi,
+    address: u8,
+    accel_range: AccelScale,
+    gyro_range: GyroScale,
     accel_scale: f32,
     gyro_scale: f32,
+    gyro_filter: Option<GyroFilter>,
 }
 
 impl Mpu6050 {
-    /// Create new MPU6050 instance
+    /// Create new MPU6050 instance at the default address (AD0 low)
     pub fn new(twi: Twi) -> Result<Self, ()> {
+        Self::with_address(twi, Mpu6050Address::Ad0Low)
+    }
+
+    /// Create new MPU6050 instance at the given address, allowing two
+    /// sensors on the same bus
+    pub fn with_address(twi: Twi, address: Mpu6050Address) -> Result<Self, ()> {
         let mut mpu = Self {
             twi,
+            address: address as u8,
+            accel_range: AccelScale::G2,
+            gyro_range: GyroScale::Dps250,
             accel_scale: 16384.0, // Default ±2g
             gyro_scale: 131.0,    // Default ±250°/s
+            gyro_filter: None,
         };
         
         // Initialize sensor
         mpu.init()?;
+        records::register(SUBSYSTEM_IMU, RECORD_SNAPSHOT, "imu", RecordType::ImuSnapshot, 2, read_snapshot).ok();
         
         Ok(mpu)
     }
@@ -73,15 +127,43 @@ impl Mpu6050 {
         self.write_reg(REG_CONFIG, 0x03)?;
         
         // Configure ranges
-        self.set_accel_scale(AccelScale::G2)?;
-        self.set_gyro_scale(GyroScale::Dps250)?;
+        self.set_accel_scale(self.accel_range)?;
+        self.set_gyro_scale(self.gyro_range)?;
         
         Ok(())
     }
 
+    /// Drive the INT pin low when a new sample is ready, until the next
+    /// register read. Held low, it can wake the MCU from Power-down as a
+    /// `Trigger::LowLevel` external interrupt (see `hal::exti`).
+    pub fn enable_data_ready_interrupt(&mut self, enabled: bool) -> Result<(), ()> {
+        self.write_reg(REG_INT_PIN_CFG, INT_ACTIVE_LOW_LATCHED)?;
+        self.write_reg(REG_INT_ENABLE, if enabled { DATA_RDY_EN } else { 0 })
+    }
+
+    /// Check the sensor answers with the expected WHO_AM_I value
+    pub fn probe(&mut self) -> bool {
+        let mut id = [0u8; 1];
+        self.read_regs(REG_WHO_AM_I, &mut id).is_ok() && id[0] == WHO_AM_I_VALUE
+    }
+
+    /// Re-run initialization after a brown-out or reconnect, keeping the
+    /// configured ranges
+    pub fn reinit(&mut self) -> Result<(), ()> {
+        if let Some(filter) = self.gyro_filter.as_mut() {
+            filter.reset();
+        }
+        self.init()
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
     /// Set accelerometer full-scale range
     pub fn set_accel_scale(&mut self, scale: AccelScale) -> Result<(), ()> {
         self.write_reg(REG_ACCEL_CONFIG, (scale as u8) << 3)?;
+        self.accel_range = scale;
         self.accel_scale = match scale {
             AccelScale::G2 => 16384.0,
             AccelScale::G4 => 8192.0,
@@ -94,6 +176,7 @@ impl Mpu6050 {
     /// Set gyroscope full-scale range
     pub fn set_gyro_scale(&mut self, scale: GyroScale) -> Result<(), ()> {
         self.write_reg(REG_GYRO_CONFIG, (scale as u8) << 3)?;
+        self.gyro_range = scale;
         self.gyro_scale = match scale {
             GyroScale::Dps250 => 131.0,
             GyroScale::Dps500 => 65.5,
@@ -103,6 +186,16 @@ impl Mpu6050 {
         Ok(())
     }
 
+    /// Install a filter applied to every gyro reading, `None` removes it
+    pub fn set_gyro_filter(&mut self, filter: Option<GyroFilter>) {
+        self.gyro_filter = filter;
+    }
+
+    /// Access the installed gyro filter to retune it at runtime
+    pub fn gyro_filter_mut(&mut self) -> Option<&mut GyroFilter> {
+        self.gyro_filter.as_mut()
+    }
+
     /// Read raw accelerometer data
     pub fn read_accel(&mut self) -> Result<Vec3, ()> {
         let mut data = [0u8; 6];
@@ -111,6 +204,8 @@ impl Mpu6050 {
         let raw_x = (data[0] as i16) << 8 | data[1] as i16;
         let raw_y = (data[2] as i16) << 8 | data[3] as i16;
         let raw_z = (data[4] as i16) << 8 | data[5] as i16;
+        let [raw_x, raw_y, raw_z] = inject_axes(NoiseTarget::Mpu6050Accel, [raw_x, raw_y, raw_z]).ok_or(())?;
+        self.store(SNAP_ACCEL, &[raw_x, raw_y, raw_z]);
         
         Ok(Vec3 {
             x: raw_x as f32 / self.accel_scale,
@@ -127,6 +222,14 @@ impl Mpu6050 {
         let raw_x = (data[0] as i16) << 8 | data[1] as i16;
         let raw_y = (data[2] as i16) << 8 | data[3] as i16;
         let raw_z = (data[4] as i16) << 8 | data[5] as i16;
+        let [raw_x, raw_y, raw_z] = inject_axes(NoiseTarget::Mpu6050Gyro, [raw_x, raw_y, raw_z]).ok_or(())?;
+        self.store(SNAP_GYRO, &[raw_x, raw_y, raw_z]);
+        
+        // Filter on raw counts before scaling
+        let [raw_x, raw_y, raw_z] = match self.gyro_filter.as_mut() {
+            Some(filter) => filter.apply([raw_x, raw_y, raw_z]),
+            None => [raw_x, raw_y, raw_z],
+        };
         
         Ok(Vec3 {
             x: raw_x as f32 / self.gyro_scale,
@@ -135,29 +238,106 @@ impl Mpu6050 {
         })
     }
 
+    /// Read the die temperature in tenths of a degree Celsius
+    pub fn read_temperature(&mut self) -> Result<i16, ()> {
+        let mut data = [0u8; 2];
+        self.read_regs(REG_TEMP_OUT_H, &mut data)?;
+        
+        let raw = (data[0] as i16) << 8 | data[1] as i16;
+        self.store(SNAP_TEMP, &[raw]);
+        // T = raw / 340 + 36.53
+        let mut tenths = [raw as i32 * 10 / 340 + 365];
+        if !noise_injection::inject(NoiseTarget::Mpu6050Temperature, &mut tenths) {
+            return Err(());
+        }
+        Ok(tenths[0].clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+    }
+
+    /// Queue an interrupt-driven read of accel, temperature and gyro
+    /// registers; collect it with `poll_motion`
+    pub fn start_motion_read(&mut self) -> Result<TwiTicket, TwiAsyncError> {
+        self.twi.write_read_async(self.address, &[REG_ACCEL_XOUT_H], MOTION_BURST_LEN)
+    }
+
+    /// Accel and gyro readings of a `start_motion_read` once the transfer
+    /// has finished, `None` while it is still on the bus
+    pub fn poll_motion(&mut self, ticket: TwiTicket) -> Option<Result<(Vec3, Vec3), TwiAsyncError>> {
+        let mut data = [0u8; MOTION_BURST_LEN];
+        if let Err(e) = self.twi.poll(ticket, &mut data)? {
+            return Some(Err(e));
+        }
+
+        let raw = |i: usize| (data[i] as i16) << 8 | data[i + 1] as i16;
+        // An injected dropout looks like the sensor not answering
+        let [ax, ay, az] = match inject_axes(NoiseTarget::Mpu6050Accel, [raw(0), raw(2), raw(4)]) {
+            Some(axes) => axes,
+            None => return Some(Err(TwiAsyncError::Nack)),
+        };
+        self.store(SNAP_ACCEL, &[ax, ay, az]);
+        let accel = Vec3 {
+            x: ax as f32 / self.accel_scale,
+            y: ay as f32 / self.accel_scale,
+            z: az as f32 / self.accel_scale,
+        };
+
+        // Gyro follows the two temperature bytes
+        let gyro = match inject_axes(NoiseTarget::Mpu6050Gyro, [raw(8), raw(10), raw(12)]) {
+            Some(axes) => axes,
+            None => return Some(Err(TwiAsyncError::Nack)),
+        };
+        self.store(SNAP_GYRO, &gyro);
+        self.store(SNAP_TEMP, &[raw(6)]);
+        let [gx, gy, gz] = match self.gyro_filter.as_mut() {
+            Some(filter) => filter.apply(gyro),
+            None => gyro,
+        };
+        let gyro = Vec3 {
+            x: gx as f32 / self.gyro_scale,
+            y: gy as f32 / self.gyro_scale,
+            z: gz as f32 / self.gyro_scale,
+        };
+        Some(Ok((accel, gyro)))
+    }
+
+    // Keep raw readings for the `ImuSnapshot` record, fields not read yet
+    // stay zero
+    fn store(&self, field: usize, values: &[i16]) {
+        let slot = (self.address - Mpu6050Address::Ad0Low as u8) as usize;
+        avr_device::interrupt::free(|cs| {
+            let mut snapshots = SNAPSHOTS.borrow(cs).borrow_mut();
+            let snapshot = snapshots[slot].get_or_insert([0; 7]);
+            snapshot[field..field + values.len()].copy_from_slice(values);
+        });
+    }
+
     /// Write to register
     fn write_reg(&mut self, reg: u8, val: u8) -> Result<(), ()> {
-        self.twi.start()?;
-        self.twi.write_address(MPU6050_ADDR, false)?;
-        self.twi.write_byte(reg)?;
-        self.twi.write_byte(val)?;
-        self.twi.stop();
-        Ok(())
+        // The TWI driver keeps the error for diagnostics
+        self.twi.write_regs(self.address, reg, &[val]).map_err(|_| ())
     }
 
     /// Read multiple registers
     fn read_regs(&mut self, reg: u8, buffer: &mut [u8]) -> Result<(), ()> {
-        self.twi.start()?;
-        self.twi.write_address(MPU6050_ADDR, false)?;
-        self.twi.write_byte(reg)?;
-        self.twi.start()?;
-        self.twi.write_address(MPU6050_ADDR, true)?;
-        
-        for i in 0..buffer.len() {
-            buffer[i] = self.twi.read_byte(i < buffer.len() - 1)?;
-        }
-        
-        self.twi.stop();
-        Ok(())
+        self.twi.read_regs(self.address, reg, buffer).map_err(|_| ())
+    }
+}
+
+fn read_snapshot(index: u8, out: &mut [u8]) -> Option<usize> {
+    let snapshot = avr_device::interrupt::free(|cs| SNAPSHOTS.borrow(cs).borrow()[index as usize])?;
+    if out.len() < 14 {
+        return None;
+    }
+    for (chunk, value) in out.chunks_exact_mut(2).zip(snapshot) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    Some(14)
+}
+
+// Raw axes through the noise injection hooks, `None` on a dropout
+fn inject_axes(target: NoiseTarget, axes: [i16; 3]) -> Option<[i16; 3]> {
+    let mut values = axes.map(|v| v as i32);
+    if !noise_injection::inject(target, &mut values) {
+        return None;
     }
+    Some(values.map(|v| v.clamp(i16::MIN as i32, i16::MAX as i32) as i16))
 }
@@ -0,0 +1,143 @@
+//! Noise injection hooks in the sensor drivers
+//!
+//! With the `noise-injection` feature the LM75, MPU6050 and HX711 drivers
+//! pass every reading through `inject` before returning it, which can add
+//! uniform noise, fail the reading (a dropout, returned as the driver's
+//! usual read error) or pin it to a fixed value (a stuck-at fault). Fault
+//! handling such as sensor voting and the hot-plug logic can then be
+//! exercised on the bench without damaging a sensor.
+//!
+//! Values are raw driver units: tenths of a degree for temperatures,
+//! counts for the IMU axes and the load cell. The generator is seeded with
+//! `seed`, so a run replays exactly from the same seed. Without the feature
+//! `inject` compiles to nothing and `configure` is ignored.
+#![no_std]
+
+#[cfg(feature = "noise-injection")]
+use avr_device::interrupt::Mutex;
+#[cfg(feature = "noise-injection")]
+use core::cell::RefCell;
+
+#[cfg(feature = "noise-injection")]
+use crate::math::Xorshift32;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum NoiseTarget {
+    Lm75 = 0,
+    Mpu6050Accel = 1,
+    Mpu6050Gyro = 2,
+    Mpu6050Temperature = 3,
+    Hx711 = 4,
+}
+
+#[cfg(feature = "noise-injection")]
+const TARGETS: usize = 5;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NoiseConfig {
+    /// Uniform noise of up to this many raw units either way
+    pub amplitude: u32,
+    /// Readings that fail, per mille
+    pub dropout_per_mille: u16,
+    /// Every reading returns this raw value instead
+    pub stuck_at: Option<i32>,
+}
+
+impl NoiseConfig {
+    pub const fn additive(amplitude: u32) -> Self {
+        Self {
+            amplitude,
+            dropout_per_mille: 0,
+            stuck_at: None,
+        }
+    }
+
+    pub const fn dropouts(per_mille: u16) -> Self {
+        Self {
+            amplitude: 0,
+            dropout_per_mille: per_mille,
+            stuck_at: None,
+        }
+    }
+
+    pub const fn stuck(value: i32) -> Self {
+        Self {
+            amplitude: 0,
+            dropout_per_mille: 0,
+            stuck_at: Some(value),
+        }
+    }
+}
+
+#[cfg(feature = "noise-injection")]
+struct Injector {
+    rng: Xorshift32,
+    targets: [Option<NoiseConfig>; TARGETS],
+}
+
+#[cfg(feature = "noise-injection")]
+static INJECTOR: Mutex<RefCell<Injector>> = Mutex::new(RefCell::new(Injector {
+    rng: Xorshift32::new(0),
+    targets: [None; TARGETS],
+}));
+
+/// Whether the hooks are compiled in
+pub const fn is_available() -> bool {
+    cfg!(feature = "noise-injection")
+}
+
+/// Restart the generator, e.g. from `hal::entropy::hardware_seed` or a
+/// fixed value to replay a run
+pub fn seed(seed: u32) {
+    #[cfg(feature = "noise-injection")]
+    avr_device::interrupt::free(|cs| INJECTOR.borrow(cs).borrow_mut().rng = Xorshift32::new(seed));
+    #[cfg(not(feature = "noise-injection"))]
+    let _ = seed;
+}
+
+/// Start (`Some`) or stop (`None`) injecting into `target`
+pub fn configure(target: NoiseTarget, config: Option<NoiseConfig>) {
+    #[cfg(feature = "noise-injection")]
+    avr_device::interrupt::free(|cs| INJECTOR.borrow(cs).borrow_mut().targets[target as usize] = config);
+    #[cfg(not(feature = "noise-injection"))]
+    let _ = (target, config);
+}
+
+/// Stop injecting everywhere
+pub fn clear() {
+    #[cfg(feature = "noise-injection")]
+    avr_device::interrupt::free(|cs| INJECTOR.borrow(cs).borrow_mut().targets = [None; TARGETS]);
+}
+
+/// Apply `target`'s faults to one reading of `values`, all axes at once.
+/// False means the reading drops out and the driver fails it.
+#[cfg(feature = "noise-injection")]
+pub fn inject(target: NoiseTarget, values: &mut [i32]) -> bool {
+    avr_device::interrupt::free(|cs| {
+        let mut injector = INJECTOR.borrow(cs).borrow_mut();
+        let config = match injector.targets[target as usize] {
+            Some(config) => config,
+            None => return true,
+        };
+        if let Some(stuck) = config.stuck_at {
+            values.iter_mut().for_each(|v| *v = stuck);
+            return true;
+        }
+        if injector.rng.chance(config.dropout_per_mille) {
+            return false;
+        }
+        if config.amplitude > 0 {
+            for value in values.iter_mut() {
+                *value = value.saturating_add(injector.rng.symmetric(config.amplitude));
+            }
+        }
+        true
+    })
+}
+
+#[cfg(not(feature = "noise-injection"))]
+#[inline(always)]
+pub fn inject(_target: NoiseTarget, _values: &mut [i32]) -> bool {
+    true
+}
@@ -0,0 +1,255 @@
+//! Pulse counter for flow meters and tachometers
+//!
+//! Counts edges on INT6 (PE6) through `hal::exti`. Edges closer
+//! together than the debounce time are ignored, which is enough for reed
+//! contacts and the open-collector output of hall flow sensors; at 1ms
+//! resolution it limits the counter to a few hundred Hz.
+//!
+//! A K-factor (pulses per liter, per rotation, ...) scales the counts. The
+//! running total is persisted in a small wear-leveled ring in the internal
+//! EEPROM: every save goes to the next slot, and since the total only
+//! grows, the slot with the highest valid count is the current one.
+#![no_std]
+
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
+
+use crate::config::EEPROM_TOTALIZER_ADDR;
+use crate::hal::claims::{self, Port, Resource};
+use crate::hal::eeprom;
+use crate::hal::exti::{self, ExtInt, Trigger};
+use crate::hal::uart::SerialPort;
+use crate::protocol::{Protocol, Result};
+
+/// Totalizer slots in EEPROM, each save moves on to the next one
+pub const TOTALIZER_SLOTS: u16 = 16;
+// count u32 LE + check byte
+const SLOT_SIZE: u16 = 5;
+
+/// Default time between totalizer saves while pulses come in
+pub const DEFAULT_SAVE_INTERVAL_MS: u32 = 600_000;
+// Rate is computed over at least this window
+const RATE_WINDOW_MS: u32 = 1000;
+
+pub const PULSE_TELEMETRY_SIZE: usize = 12;
+
+// INT6 on PE6
+const INT_BIT: u8 = 6;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PulseEdge {
+    Falling,
+    Rising,
+}
+
+struct PulseState {
+    count: u32,
+    last_edge_ms: u32,
+    debounce_ms: u32,
+    rejected: u32,
+}
+
+static PULSES: Mutex<RefCell<PulseState>> = Mutex::new(RefCell::new(PulseState {
+    count: 0,
+    last_edge_ms: 0,
+    debounce_ms: 0,
+    rejected: 0,
+}));
+
+pub struct PulseCounter {
+    /// Pulses per unit
+    k_factor: f32,
+    /// Total at the last load or reset, counts since then come from the ISR
+    base: u32,
+    saved: u32,
+    next_slot: u16,
+    last_save_ms: u32,
+    save_interval_ms: u32,
+    window_start_ms: u32,
+    window_start_count: u32,
+    rate: f32,
+}
+
+impl PulseCounter {
+    /// Start counting `edge`s with `k_factor` pulses per unit. The total
+    /// continues from the value saved in EEPROM.
+    pub fn new(k_factor: f32, edge: PulseEdge, debounce_ms: u32) -> Self {
+        claims::claim(Resource::Pin(Port::E, INT_BIT), "pulse_counter").ok();
+
+        let (total, slot) = load_totalizer();
+        avr_device::interrupt::free(|cs| {
+            let mut state = PULSES.borrow(cs).borrow_mut();
+            state.count = 0;
+            state.debounce_ms = debounce_ms;
+            state.rejected = 0;
+        });
+
+        let trigger = match edge {
+            PulseEdge::Falling => Trigger::Falling,
+            PulseEdge::Rising => Trigger::Rising,
+        };
+        // Pull-up for open collector sensors
+        exti::attach(ExtInt::Int6, trigger, true, on_pulse).ok();
+
+        Self {
+            k_factor: if k_factor > 0.0 { k_factor } else { 1.0 },
+            base: total,
+            saved: total,
+            next_slot: (slot + 1) % TOTALIZER_SLOTS,
+            last_save_ms: 0,
+            save_interval_ms: DEFAULT_SAVE_INTERVAL_MS,
+            window_start_ms: 0,
+            window_start_count: 0,
+            rate: 0.0,
+        }
+    }
+
+    pub fn set_save_interval_ms(&mut self, interval_ms: u32) {
+        self.save_interval_ms = interval_ms;
+    }
+
+    /// Pulses counted since the totalizer was last cleared
+    pub fn total_pulses(&self) -> u32 {
+        self.base.wrapping_add(isr_count())
+    }
+
+    /// Total in units
+    pub fn total(&self) -> f32 {
+        self.total_pulses() as f32 / self.k_factor
+    }
+
+    /// Units per second over the last rate window
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+
+    /// Edges ignored by the debounce
+    pub fn rejected(&self) -> u32 {
+        avr_device::interrupt::free(|cs| PULSES.borrow(cs).borrow().rejected)
+    }
+
+    /// Update the rate and save the totalizer when due. Call regularly
+    /// with the system millisecond tick.
+    pub fn update(&mut self, now_ms: u32) {
+        let count = isr_count();
+        let elapsed = now_ms.wrapping_sub(self.window_start_ms);
+        if elapsed >= RATE_WINDOW_MS {
+            let pulses = count.wrapping_sub(self.window_start_count);
+            self.rate = pulses as f32 * 1000.0 / (elapsed as f32 * self.k_factor);
+            self.window_start_ms = now_ms;
+            self.window_start_count = count;
+        }
+
+        if self.total_pulses() != self.saved && now_ms.wrapping_sub(self.last_save_ms) >= self.save_interval_ms {
+            self.save();
+            self.last_save_ms = now_ms;
+        }
+    }
+
+    /// Write the current total to the next EEPROM slot. Also call before a
+    /// planned power-down.
+    pub fn save(&mut self) {
+        let total = self.total_pulses();
+        write_slot(self.next_slot, total);
+        self.next_slot = (self.next_slot + 1) % TOTALIZER_SLOTS;
+        self.saved = total;
+    }
+
+    /// Clear the totalizer, in EEPROM too
+    pub fn reset_total(&mut self) {
+        avr_device::interrupt::free(|cs| PULSES.borrow(cs).borrow_mut().count = 0);
+        // Every slot, a stale higher count would win on the next load
+        for slot in 0..TOTALIZER_SLOTS {
+            write_slot(slot, 0);
+        }
+        self.base = 0;
+        self.saved = 0;
+        self.next_slot = 1;
+        self.window_start_count = 0;
+    }
+
+    /// Stop counting and save the total
+    pub fn stop(&mut self) {
+        exti::detach(ExtInt::Int6);
+        if self.total_pulses() != self.saved {
+            self.save();
+        }
+        claims::release(Resource::Pin(Port::E, INT_BIT), "pulse_counter");
+    }
+
+    /// `[rate in milli-units/s u32, total pulses u32, k-factor f32]`, LE
+    pub fn encode_telemetry(&self, out: &mut [u8; PULSE_TELEMETRY_SIZE]) {
+        let rate_milli = (self.rate * 1000.0) as u32;
+        out[0..4].copy_from_slice(&rate_milli.to_le_bytes());
+        out[4..8].copy_from_slice(&self.total_pulses().to_le_bytes());
+        out[8..12].copy_from_slice(&self.k_factor.to_le_bytes());
+    }
+
+    /// Send the telemetry record as a `Command::Telemetry` packet
+    pub fn send_telemetry<S: SerialPort>(&self, protocol: &mut Protocol<S>) -> Result<()> {
+        let mut data = [0u8; PULSE_TELEMETRY_SIZE];
+        self.encode_telemetry(&mut data);
+        protocol.send_telemetry(&data)
+    }
+}
+
+fn isr_count() -> u32 {
+    avr_device::interrupt::free(|cs| PULSES.borrow(cs).borrow().count)
+}
+
+fn slot_addr(slot: u16) -> u16 {
+    EEPROM_TOTALIZER_ADDR + slot * SLOT_SIZE
+}
+
+// Seed of the check byte: XOR of 0xFF bytes is 0, so 0xA5 never matches
+// the 0xFF check of an erased slot
+const CHECK_SEED: u8 = 0xA5;
+
+fn check_byte(bytes: &[u8; 4]) -> u8 {
+    CHECK_SEED ^ bytes[0] ^ bytes[1] ^ bytes[2] ^ bytes[3]
+}
+
+fn write_slot(slot: u16, total: u32) {
+    let addr = slot_addr(slot);
+    let bytes = total.to_le_bytes();
+    for (i, &byte) in bytes.iter().enumerate() {
+        eeprom::update_byte(addr + i as u16, byte);
+    }
+    eeprom::update_byte(addr + 4, check_byte(&bytes));
+}
+
+/// Highest valid saved total and the slot holding it, 0 if none is valid
+fn load_totalizer() -> (u32, u16) {
+    let mut best = (0, TOTALIZER_SLOTS - 1);
+    let mut found = false;
+    for slot in 0..TOTALIZER_SLOTS {
+        let addr = slot_addr(slot);
+        let mut bytes = [0u8; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = eeprom::read_byte(addr + i as u16);
+        }
+        // An erased slot is never valid, whatever its check byte reads
+        if bytes == [0xFF; 4] || eeprom::read_byte(addr + 4) != check_byte(&bytes) {
+            continue;
+        }
+        let total = u32::from_le_bytes(bytes);
+        if !found || total > best.0 {
+            best = (total, slot);
+            found = true;
+        }
+    }
+    best
+}
+
+fn on_pulse() {
+    let now = crate::os::SCHEDULER.get_ticks();
+    avr_device::interrupt::free(|cs| {
+        let mut state = PULSES.borrow(cs).borrow_mut();
+        if state.count != 0 && now.wrapping_sub(state.last_edge_ms) < state.debounce_ms {
+            state.rejected = state.rejected.wrapping_add(1);
+            return;
+        }
+        state.count = state.count.wrapping_add(1);
+        state.last_edge_ms = now;
+    });
+}
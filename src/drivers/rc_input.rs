@@ -0,0 +1,290 @@
+//! RC receiver input decoding (Futaba SBUS and PPM-sum)
+//!
+//! SBUS: 100 kbaud, 8E2, logically inverted (needs the usual transistor
+//! inverter in front of the RXD pin). Frames are 25 bytes: header 0x0F,
+//! 16 channels packed as 11 bit values, a flags byte and a 0x00 footer.
+//!
+//! PPM-sum: a single pin carrying all channel pulses back to back, measured
+//! with the Timer3 input capture unit (ICP3 / PE7). A gap longer than
+//! `PPM_SYNC_US` marks the start of a new frame.
+//!
+//! Channel values are reported in microseconds (nominal 1000..2000) so both
+//! sources feed the motor/servo layer the same way.
+#![no_std]
+
+use avr_device::atmega128::TC3;
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
+
+use crate::config::CPU_FREQ_HZ;
+use crate::hal::claims::{self, Port, Resource};
+use crate::hal::clock;
+use crate::hal::timer::{CaptureEdge, Prescaler, Timer16, TimerInterrupt};
+use crate::hal::uart::UartRegisterBlock;
+
+pub const MAX_CHANNELS: usize = 16;
+
+const SBUS_BAUD: u32 = 100_000;
+const SBUS_FRAME_LEN: usize = 25;
+const SBUS_HEADER: u8 = 0x0F;
+const SBUS_FOOTER: u8 = 0x00;
+const SBUS_FLAG_FRAME_LOST: u8 = 1 << 2;
+const SBUS_FLAG_FAILSAFE: u8 = 1 << 3;
+const UCSRC_8E2: u8 = 0x2E; // UPM1 | USBS | UCSZ1 | UCSZ0
+const RXC: u8 = 1 << 7;
+
+const PPM_SYNC_US: u16 = 3000;
+const PPM_MIN_CHANNELS: u8 = 4;
+// Timer3 runs at clk/8 for PPM capture
+const US_PER_COUNT_Q12: u32 = clock::us_per_count_q12(8);
+
+/// No valid frame for this long means the receiver is gone
+pub const FAILSAFE_TIMEOUT_MS: u32 = 100;
+
+/// Decoded channel set
+#[derive(Clone, Copy)]
+pub struct RcFrame {
+    pub channels: [u16; MAX_CHANNELS],
+    pub count: u8,
+    /// Receiver reported failsafe (SBUS only)
+    pub failsafe: bool,
+    /// Receiver reported a lost frame (SBUS only)
+    pub frame_lost: bool,
+}
+
+impl RcFrame {
+    const fn empty() -> Self {
+        Self {
+            channels: [1500; MAX_CHANNELS],
+            count: 0,
+            failsafe: false,
+            frame_lost: false,
+        }
+    }
+
+    /// Channel as 0..100% of the 1000..2000us range, suitable for
+    /// `MotorController::set_target`
+    pub fn channel_percent(&self, channel: usize) -> f32 {
+        if channel >= self.count as usize {
+            return 0.0;
+        }
+        let us = self.channels[channel].clamp(1000, 2000);
+        (us - 1000) as f32 / 10.0
+    }
+}
+
+/// Byte-wise SBUS frame decoder
+pub struct SbusDecoder {
+    buffer: [u8; SBUS_FRAME_LEN],
+    index: usize,
+}
+
+impl SbusDecoder {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; SBUS_FRAME_LEN],
+            index: 0,
+        }
+    }
+
+    /// Feed a received byte, returns a frame once one is complete
+    pub fn feed(&mut self, byte: u8) -> Option<RcFrame> {
+        if self.index == 0 && byte != SBUS_HEADER {
+            return None;
+        }
+
+        self.buffer[self.index] = byte;
+        self.index += 1;
+
+        if self.index < SBUS_FRAME_LEN {
+            return None;
+        }
+        self.index = 0;
+
+        if self.buffer[SBUS_FRAME_LEN - 1] != SBUS_FOOTER {
+            return None;
+        }
+
+        Some(self.decode())
+    }
+
+    fn decode(&self) -> RcFrame {
+        let mut frame = RcFrame::empty();
+        let payload = &self.buffer[1..23];
+
+        // 16 channels x 11 bits, LSB first
+        let mut bit_pos = 0usize;
+        for ch in 0..MAX_CHANNELS {
+            let mut value = 0u16;
+            for bit in 0..11 {
+                let byte = payload[(bit_pos + bit) / 8];
+                if byte & (1 << ((bit_pos + bit) % 8)) != 0 {
+                    value |= 1 << bit;
+                }
+            }
+            bit_pos += 11;
+            frame.channels[ch] = sbus_to_us(value);
+        }
+
+        let flags = self.buffer[23];
+        frame.count = MAX_CHANNELS as u8;
+        frame.failsafe = flags & SBUS_FLAG_FAILSAFE != 0;
+        frame.frame_lost = flags & SBUS_FLAG_FRAME_LOST != 0;
+        frame
+    }
+}
+
+impl Default for SbusDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Map the raw SBUS range (172..1811) to microseconds (988..2012)
+fn sbus_to_us(raw: u16) -> u16 {
+    ((raw as i32 - 992) * 5 / 8 + 1500) as u16
+}
+
+struct PpmState {
+    frame: RcFrame,
+    working: [u16; MAX_CHANNELS],
+    index: u8,
+    last_capture: u16,
+    new_frame: bool,
+}
+
+static PPM_STATE: Mutex<RefCell<PpmState>> = Mutex::new(RefCell::new(PpmState {
+    frame: RcFrame::empty(),
+    working: [1500; MAX_CHANNELS],
+    index: 0,
+    last_capture: 0,
+    new_frame: false,
+}));
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RcSource {
+    Sbus,
+    Ppm,
+}
+
+/// RC receiver front end with failsafe detection
+pub struct RcInput<USART> {
+    source: RcSource,
+    sbus: SbusDecoder,
+    last: RcFrame,
+    last_frame_ms: u32,
+    has_frame: bool,
+    _usart: core::marker::PhantomData<USART>,
+}
+
+impl<USART: UartRegisterBlock> RcInput<USART> {
+    /// SBUS receiver on the given USART (RX only, polled)
+    pub fn new_sbus() -> Self {
+        unsafe {
+            let p = USART::ptr();
+            (*p).ubrr.write(|w| w.bits((CPU_FREQ_HZ / (16 * SBUS_BAUD) - 1) as u16));
+            (*p).ucsrc.write(|w| w.bits(UCSRC_8E2));
+            (*p).ucsr.modify(|_, w| w.rxen().set_bit());
+        }
+        Self::with_source(RcSource::Sbus)
+    }
+
+    /// PPM-sum receiver on ICP3 (PE7), Timer3 at 0.5us resolution
+    pub fn new_ppm() -> Self {
+        claims::claim(Resource::Timer3, "rc_input").ok();
+        claims::claim(Resource::Pin(Port::E, 7), "rc_input").ok();
+        let mut timer = Timer16::<TC3>::new();
+        timer.set_capture_edge(CaptureEdge::Rising);
+        timer.enable_interrupt(TimerInterrupt::Capture);
+        timer.start(Prescaler::Div8);
+        Self::with_source(RcSource::Ppm)
+    }
+
+    fn with_source(source: RcSource) -> Self {
+        Self {
+            source,
+            sbus: SbusDecoder::new(),
+            last: RcFrame::empty(),
+            last_frame_ms: 0,
+            has_frame: false,
+            _usart: core::marker::PhantomData,
+        }
+    }
+
+    /// Collect new data. `now_ms` is the system millisecond tick.
+    /// Returns a frame whenever a new one was decoded.
+    pub fn poll(&mut self, now_ms: u32) -> Option<RcFrame> {
+        let frame = match self.source {
+            RcSource::Sbus => self.poll_sbus(),
+            RcSource::Ppm => avr_device::interrupt::free(|cs| {
+                let mut state = PPM_STATE.borrow(cs).borrow_mut();
+                if state.new_frame {
+                    state.new_frame = false;
+                    Some(state.frame)
+                } else {
+                    None
+                }
+            }),
+        };
+
+        if let Some(frame) = frame {
+            self.last = frame;
+            self.last_frame_ms = now_ms;
+            self.has_frame = true;
+        }
+        frame
+    }
+
+    fn poll_sbus(&mut self) -> Option<RcFrame> {
+        let mut result = None;
+        unsafe {
+            let p = USART::ptr();
+            while (*p).ucsra.read().bits() & RXC != 0 {
+                let byte = (*p).udr.read().bits();
+                if let Some(frame) = self.sbus.feed(byte) {
+                    result = Some(frame);
+                }
+            }
+        }
+        result
+    }
+
+    /// True if the receiver signals failsafe or has gone silent
+    pub fn is_failsafe(&self, now_ms: u32) -> bool {
+        !self.has_frame
+            || self.last.failsafe
+            || now_ms.wrapping_sub(self.last_frame_ms) > FAILSAFE_TIMEOUT_MS
+    }
+
+    /// Most recent frame, regardless of age
+    pub fn last_frame(&self) -> RcFrame {
+        self.last
+    }
+}
+
+#[avr_device::interrupt(atmega128)]
+fn TIMER3_CAPT() {
+    // Interrupts are masked here, which covers the 16-bit TEMP access
+    let capture = unsafe { (*TC3::ptr()).icr3.read().bits() };
+
+    avr_device::interrupt::free(|cs| {
+        let mut state = PPM_STATE.borrow(cs).borrow_mut();
+        let counts = capture.wrapping_sub(state.last_capture) as u32;
+        let width_us = (counts * US_PER_COUNT_Q12 >> 12) as u16;
+        state.last_capture = capture;
+
+        if width_us >= PPM_SYNC_US {
+            if state.index >= PPM_MIN_CHANNELS {
+                let count = state.index;
+                state.frame.channels = state.working;
+                state.frame.count = count;
+                state.new_frame = true;
+            }
+            state.index = 0;
+        } else if (state.index as usize) < MAX_CHANNELS {
+            let index = state.index as usize;
+            state.working[index] = width_us;
+            state.index += 1;
+        }
+    });
+}
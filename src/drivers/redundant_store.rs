@@ -0,0 +1,224 @@
+//! Two-copy storage for configuration and calibration blobs
+//!
+//! A blob is kept twice, in two flash sectors or two EEPROM regions, each
+//! copy behind a header:
+//!
+//! ```text
+//! [version u32 LE, len u16 LE, crc16 u16 LE, payload(len)]
+//! ```
+//!
+//! The CRC-16/CCITT-FALSE covers the version, the length and the payload.
+//! `save` writes the stale copy first and the other one after it with the
+//! same, incremented version, so a reset in the middle of a save always
+//! leaves one valid copy behind. `load` takes the newest valid copy and
+//! rewrites the other one when it is older or corrupt, so a worn sector or
+//! a torn write heals on the next boot.
+#![no_std]
+
+use crate::drivers::flash::Flash;
+use crate::hal::eeprom;
+use crate::hal::spi::ChipSelect;
+use crate::protocol::crc::{crc16_update, CRC16_INIT};
+
+pub const HEADER_SIZE: usize = 8;
+// Payload read back in chunks this size while checking a copy
+const CHUNK: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StoreError {
+    /// The medium failed to read, write or erase
+    Io,
+    /// Neither copy is valid, e.g. never saved
+    NoValidCopy,
+    /// The blob does not fit a copy, or the caller's buffer
+    TooLarge,
+}
+
+/// Where the two copies live
+pub trait CopyMedium {
+    /// Bytes per copy, header included
+    fn capacity(&self) -> usize;
+    fn read(&mut self, copy: usize, offset: usize, buffer: &mut [u8]) -> Result<(), StoreError>;
+    /// Prepare a copy for `write`, a no-op where bytes can be rewritten
+    fn erase(&mut self, copy: usize) -> Result<(), StoreError>;
+    fn write(&mut self, copy: usize, offset: usize, data: &[u8]) -> Result<(), StoreError>;
+}
+
+/// Two sectors of the external flash
+pub struct FlashCopies<'a, CS: ChipSelect> {
+    flash: &'a mut Flash<CS>,
+    sectors: [u32; 2],
+}
+
+impl<'a, CS: ChipSelect> FlashCopies<'a, CS> {
+    /// `sectors` are sector-aligned addresses in different sectors
+    pub fn new(flash: &'a mut Flash<CS>, sectors: [u32; 2]) -> Self {
+        Self { flash, sectors }
+    }
+}
+
+impl<'a, CS: ChipSelect> CopyMedium for FlashCopies<'a, CS> {
+    fn capacity(&self) -> usize {
+        crate::drivers::flash::SECTOR_SIZE
+    }
+
+    fn read(&mut self, copy: usize, offset: usize, buffer: &mut [u8]) -> Result<(), StoreError> {
+        self.flash.read(self.sectors[copy] + offset as u32, buffer).map_err(|_| StoreError::Io)
+    }
+
+    fn erase(&mut self, copy: usize) -> Result<(), StoreError> {
+        self.flash.erase_sector(self.sectors[copy]).map_err(|_| StoreError::Io)
+    }
+
+    fn write(&mut self, copy: usize, offset: usize, data: &[u8]) -> Result<(), StoreError> {
+        self.flash.write(self.sectors[copy] + offset as u32, data).map_err(|_| StoreError::Io)
+    }
+}
+
+/// Two regions of the internal EEPROM
+pub struct EepromCopies {
+    addrs: [u16; 2],
+    size: u16,
+}
+
+impl EepromCopies {
+    /// `size` bytes at each of `addrs`, header included
+    pub const fn new(addrs: [u16; 2], size: u16) -> Self {
+        Self { addrs, size }
+    }
+}
+
+impl CopyMedium for EepromCopies {
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+
+    fn read(&mut self, copy: usize, offset: usize, buffer: &mut [u8]) -> Result<(), StoreError> {
+        let base = self.addrs[copy] + offset as u16;
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = eeprom::read_byte(base + i as u16);
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, _copy: usize) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn write(&mut self, copy: usize, offset: usize, data: &[u8]) -> Result<(), StoreError> {
+        let base = self.addrs[copy] + offset as u16;
+        for (i, &byte) in data.iter().enumerate() {
+            eeprom::update_byte(base + i as u16, byte);
+        }
+        // update_byte skips unchanged bytes, so verify instead of trusting it
+        for (i, &byte) in data.iter().enumerate() {
+            if eeprom::read_byte(base + i as u16) != byte {
+                return Err(StoreError::Io);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What `load` found
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Loaded {
+    /// Payload bytes copied into the buffer
+    pub len: usize,
+    pub version: u32,
+    /// The other copy was stale or corrupt and has been rewritten
+    pub repaired: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Header {
+    version: u32,
+    len: u16,
+}
+
+/// Load the newest valid copy into `buffer` and bring the other copy up
+/// to date. A failed repair is not an error, the blob is still loaded.
+pub fn load<M: CopyMedium>(medium: &mut M, buffer: &mut [u8]) -> Result<Loaded, StoreError> {
+    let headers = [check(medium, 0), check(medium, 1)];
+    let (newest, header) = newest(&headers).ok_or(StoreError::NoValidCopy)?;
+    let len = header.len as usize;
+    if len > buffer.len() {
+        return Err(StoreError::TooLarge);
+    }
+    medium.read(newest, HEADER_SIZE, &mut buffer[..len])?;
+
+    let other = 1 - newest;
+    let stale = headers[other].map_or(true, |h| h.version != header.version);
+    let repaired = stale && write_copy(medium, other, header.version, &buffer[..len]).is_ok();
+    Ok(Loaded {
+        len,
+        version: header.version,
+        repaired,
+    })
+}
+
+/// Store `data` in both copies under the next version, returns it
+pub fn save<M: CopyMedium>(medium: &mut M, data: &[u8]) -> Result<u32, StoreError> {
+    if HEADER_SIZE + data.len() > medium.capacity() || data.len() > u16::MAX as usize {
+        return Err(StoreError::TooLarge);
+    }
+    let headers = [check(medium, 0), check(medium, 1)];
+    let (first, version) = match newest(&headers) {
+        // Overwrite the older copy while the newest stays intact
+        Some((newest, header)) => (1 - newest, header.version.wrapping_add(1)),
+        None => (0, 1),
+    };
+    write_copy(medium, first, version, data)?;
+    write_copy(medium, 1 - first, version, data)?;
+    Ok(version)
+}
+
+/// Header of a copy whose CRC checks out
+fn check<M: CopyMedium>(medium: &mut M, copy: usize) -> Option<Header> {
+    let mut raw = [0u8; HEADER_SIZE];
+    medium.read(copy, 0, &mut raw).ok()?;
+    let version = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]);
+    let len = u16::from_le_bytes([raw[4], raw[5]]);
+    let stored = u16::from_le_bytes([raw[6], raw[7]]);
+    if HEADER_SIZE + len as usize > medium.capacity() {
+        return None;
+    }
+
+    let mut crc = raw[..6].iter().fold(CRC16_INIT, |crc, &b| crc16_update(crc, b));
+    let mut chunk = [0u8; CHUNK];
+    let mut offset = 0;
+    while offset < len as usize {
+        let n = (len as usize - offset).min(CHUNK);
+        medium.read(copy, HEADER_SIZE + offset, &mut chunk[..n]).ok()?;
+        crc = chunk[..n].iter().fold(crc, |crc, &b| crc16_update(crc, b));
+        offset += n;
+    }
+    (crc == stored).then_some(Header { version, len })
+}
+
+/// Valid copy with the higher version, wrap-around included
+fn newest(headers: &[Option<Header>; 2]) -> Option<(usize, Header)> {
+    match (headers[0], headers[1]) {
+        (Some(a), Some(b)) if (b.version.wrapping_sub(a.version) as i32) > 0 => Some((1, b)),
+        (Some(a), _) => Some((0, a)),
+        (None, Some(b)) => Some((1, b)),
+        (None, None) => None,
+    }
+}
+
+fn write_copy<M: CopyMedium>(medium: &mut M, copy: usize, version: u32, data: &[u8]) -> Result<(), StoreError> {
+    let mut header = [0u8; HEADER_SIZE];
+    header[..4].copy_from_slice(&version.to_le_bytes());
+    header[4..6].copy_from_slice(&(data.len() as u16).to_le_bytes());
+    let crc = header[..6].iter().chain(data).fold(CRC16_INIT, |crc, &b| crc16_update(crc, b));
+    header[6..].copy_from_slice(&crc.to_le_bytes());
+
+    medium.erase(copy)?;
+    // Payload before header: a copy cut short never checks out
+    medium.write(copy, HEADER_SIZE, data)?;
+    medium.write(copy, 0, &header)?;
+    if check(medium, copy).map_or(true, |h| h.version != version) {
+        return Err(StoreError::Io);
+    }
+    Ok(())
+}
@@ -0,0 +1,233 @@
+//! Host-configurable sampling plan
+//!
+//! Says which sensors are sampled, how often and where the readings go
+//! (flash log, host stream or both), so one firmware image can serve very
+//! different data-collection deployments. The plan is edited by the host
+//! through `Command::SamplingPlan`, persisted to its own flash sector and
+//! consumed by `SensorManager::sample_due`.
+#![no_std]
+
+use crate::config::FLASH_SAMPLING;
+use crate::diagnostics::flash_audit::{self, AuditRegion};
+use crate::drivers::flash::Flash;
+use crate::protocol::{ProtocolError, Result};
+
+pub const MAX_PLAN_ENTRIES: usize = 8;
+const ENTRY_SIZE: usize = 6;
+const HEADER_SIZE: usize = 3;
+const SAMPLING_MAGIC: u16 = 0x5A9B;
+
+// Protocol sub-commands carried in the first payload byte of
+// Command::SamplingPlan
+const OP_LIST: u8 = 0x01;
+const OP_SET: u8 = 0x02;
+const OP_CLEAR: u8 = 0x03;
+const OP_SAVE: u8 = 0x04;
+const OP_LOAD: u8 = 0x05;
+
+/// Where the readings of a plan entry go
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum Sink {
+    Log = 1,
+    Stream = 2,
+    Both = 3,
+}
+
+impl Sink {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Sink::Log),
+            2 => Some(Sink::Stream),
+            3 => Some(Sink::Both),
+            _ => None,
+        }
+    }
+
+    pub fn logs(self) -> bool {
+        self as u8 & Sink::Log as u8 != 0
+    }
+
+    pub fn streams(self) -> bool {
+        self as u8 & Sink::Stream as u8 != 0
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct PlanEntry {
+    /// `SensorManager` id of the sensor
+    pub sensor: u8,
+    pub period_ms: u32,
+    pub sink: Sink,
+    last_sample: u32,
+}
+
+impl PlanEntry {
+    pub fn new(sensor: u8, period_ms: u32, sink: Sink) -> Self {
+        Self {
+            sensor,
+            period_ms,
+            sink,
+            last_sample: 0,
+        }
+    }
+
+    fn encode(&self, out: &mut [u8]) {
+        out[0] = self.sensor;
+        out[1] = self.sink as u8;
+        out[2..6].copy_from_slice(&self.period_ms.to_le_bytes());
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let period_ms = u32::from_le_bytes([data[2], data[3], data[4], data[5]]);
+        if period_ms == 0 {
+            return None;
+        }
+        let sink = Sink::from_u8(data[1])?;
+        Some(Self::new(data[0], period_ms, sink))
+    }
+}
+
+pub struct SamplingPlan {
+    entries: [Option<PlanEntry>; MAX_PLAN_ENTRIES],
+}
+
+impl SamplingPlan {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; MAX_PLAN_ENTRIES],
+        }
+    }
+
+    /// Place an entry in the given slot, replacing whatever was there
+    pub fn set_entry(&mut self, slot: usize, entry: PlanEntry) -> Result<()> {
+        if slot >= MAX_PLAN_ENTRIES {
+            return Err(ProtocolError::InvalidPacket);
+        }
+        self.entries[slot] = Some(entry);
+        Ok(())
+    }
+
+    pub fn clear_entry(&mut self, slot: usize) -> Result<()> {
+        if slot >= MAX_PLAN_ENTRIES {
+            return Err(ProtocolError::InvalidPacket);
+        }
+        self.entries[slot] = None;
+        Ok(())
+    }
+
+    pub fn get_entry(&self, slot: usize) -> Option<PlanEntry> {
+        self.entries.get(slot).copied().flatten()
+    }
+
+    /// Sink of the first entry for `sensor` whose period has elapsed,
+    /// restarting that entry's period
+    pub fn take_due(&mut self, sensor: u8, now_ms: u32) -> Option<Sink> {
+        for entry in self.entries.iter_mut().flatten() {
+            if entry.sensor == sensor && now_ms.wrapping_sub(entry.last_sample) >= entry.period_ms {
+                entry.last_sample = now_ms;
+                return Some(entry.sink);
+            }
+        }
+        None
+    }
+
+    /// Store the plan in its flash sector
+    pub fn save(&self, flash: &mut Flash) -> core::result::Result<(), ()> {
+        let mut buffer = [0u8; HEADER_SIZE + MAX_PLAN_ENTRIES * ENTRY_SIZE];
+        buffer[0..2].copy_from_slice(&SAMPLING_MAGIC.to_le_bytes());
+
+        let mut count = 0;
+        for (slot, entry) in self.entries.iter().enumerate() {
+            if let Some(entry) = entry {
+                let offset = HEADER_SIZE + slot * ENTRY_SIZE;
+                entry.encode(&mut buffer[offset..offset + ENTRY_SIZE]);
+                count += 1;
+            }
+        }
+        buffer[2] = count;
+
+        flash_audit::mark_written(AuditRegion::Sampling);
+        flash.erase_sector(FLASH_SAMPLING).map_err(|_| ())?;
+        flash.write(FLASH_SAMPLING, &buffer).map_err(|_| ())?;
+        Ok(())
+    }
+
+    /// Restore the plan from flash. An erased or foreign sector leaves the
+    /// current plan untouched.
+    pub fn load(&mut self, flash: &mut Flash) -> core::result::Result<(), ()> {
+        let mut buffer = [0u8; HEADER_SIZE + MAX_PLAN_ENTRIES * ENTRY_SIZE];
+        flash.read(FLASH_SAMPLING, &mut buffer).map_err(|_| ())?;
+
+        if u16::from_le_bytes([buffer[0], buffer[1]]) != SAMPLING_MAGIC {
+            return Err(());
+        }
+
+        // Entries are stored by slot; empty slots have a zero period
+        for (slot, entry) in self.entries.iter_mut().enumerate() {
+            let offset = HEADER_SIZE + slot * ENTRY_SIZE;
+            *entry = PlanEntry::decode(&buffer[offset..offset + ENTRY_SIZE]);
+        }
+        Ok(())
+    }
+
+    /// Handle a `Command::SamplingPlan` payload and write the reply into
+    /// `response`.
+    ///
+    /// Payload layout: `[op, slot, sensor, sink, period_ms(u32 LE)]`, where
+    /// everything after the slot is only present for `OP_SET`. Returns the
+    /// number of response bytes written.
+    pub fn handle_command(
+        &mut self,
+        data: &[u8],
+        flash: &mut Flash,
+        response: &mut [u8],
+    ) -> Result<usize> {
+        let op = *data.first().ok_or(ProtocolError::InvalidPacket)?;
+
+        match op {
+            OP_LIST => {
+                let mut len = 0;
+                for (slot, entry) in self.entries.iter().enumerate() {
+                    if let Some(entry) = entry {
+                        if len + 1 + ENTRY_SIZE > response.len() {
+                            return Err(ProtocolError::BufferOverflow);
+                        }
+                        response[len] = slot as u8;
+                        entry.encode(&mut response[len + 1..len + 1 + ENTRY_SIZE]);
+                        len += 1 + ENTRY_SIZE;
+                    }
+                }
+                Ok(len)
+            }
+            OP_SET => {
+                if data.len() != 2 + ENTRY_SIZE {
+                    return Err(ProtocolError::InvalidPacket);
+                }
+                let entry = PlanEntry::decode(&data[2..]).ok_or(ProtocolError::InvalidPacket)?;
+                self.set_entry(data[1] as usize, entry)?;
+                Ok(0)
+            }
+            OP_CLEAR => {
+                let slot = *data.get(1).ok_or(ProtocolError::InvalidPacket)?;
+                self.clear_entry(slot as usize)?;
+                Ok(0)
+            }
+            OP_SAVE => {
+                self.save(flash).map_err(|_| ProtocolError::TransportError)?;
+                Ok(0)
+            }
+            OP_LOAD => {
+                self.load(flash).map_err(|_| ProtocolError::TransportError)?;
+                Ok(0)
+            }
+            _ => Err(ProtocolError::InvalidCommand),
+        }
+    }
+}
+
+impl Default for SamplingPlan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -9,6 +9,7 @@
 use core::f32::consts::PI;
 use libm::{sqrtf, atan2f};
 use crate::drivers::Vec3;
+use crate::hal::systime;
 
 // Filter parameters - these were tuned through extensive testing
 // TODO: Make these configurable through a builder pattern
@@ -97,7 +98,7 @@ impl MadgwickFilter {
     /// Update filter with new sensor readings
     pub fn update(&mut self, accel: Vec3, gyro: Vec3) {
         // Start timing the update for performance monitoring
-        let start_time = get_micros();
+        let start_time = systime::micros();
         
         // Remove gyro bias
         let gyro = Vec3 {
@@ -177,7 +178,7 @@ impl MadgwickFilter {
 
         // Update performance stats
         self.update_count += 1;
-        let update_time = get_micros() - start_time;
+        let update_time = systime::micros().wrapping_sub(start_time);
         if update_time > self.max_update_time_us {
             self.max_update_time_us = update_time;
         }
@@ -201,6 +202,22 @@ impl MadgwickFilter {
         }
     }
 
+    /// Vertical (earth frame, up positive) linear acceleration in g with
+    /// gravity removed, from a body-frame accelerometer reading
+    pub fn vertical_accel(&self, accel: Vec3) -> f32 {
+        let qw = self.q.w;
+        let qx = self.q.x;
+        let qy = self.q.y;
+        let qz = self.q.z;
+
+        // Third row of the body-to-earth rotation matrix
+        let up = 2.0 * (qx * qz - qw * qy) * accel.x
+            + 2.0 * (qy * qz + qw * qx) * accel.y
+            + (qw * qw - qx * qx - qy * qy + qz * qz) * accel.z;
+
+        up - 1.0
+    }
+
     /* Keeping this code commented out for future reference
     /// Experimental: Adaptive filter gain based on motion intensity
     #[allow(dead_code)]
@@ -227,9 +244,3 @@ impl MadgwickFilter {
     */
 }
 
-// Helper function to get microsecond timestamp
-// TODO: Replace this with proper timer implementation
-fn get_micros() -> u32 {
-    // This is just a placeholder - we should use a hardware timer
-    0
-}
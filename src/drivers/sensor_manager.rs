@@ -0,0 +1,189 @@
+//! Sensor presence monitoring and automatic re-initialization
+//!
+//! Sensors are only configured when their driver is created, so an IMU that
+//! browns out or gets reconnected would stay dead until the next reset. The
+//! manager probes each registered sensor periodically, re-initializes it
+//! when it comes back and escalates to a hardware fault once a sensor keeps
+//! failing.
+#![no_std]
+
+use crate::diagnostics::{Diagnostics, ErrorCode};
+use crate::drivers::sampling_plan::{SamplingPlan, Sink};
+use crate::drivers::Mpu6050;
+
+const MAX_SENSORS: usize = 4;
+const CHECK_PERIOD_MS: u32 = 500;
+/// Consecutive failed recoveries before a sensor is declared failed
+const MAX_RETRIES: u8 = 5;
+/// Failed sensors are still retried, just less often
+const FAILED_CHECK_PERIOD_MS: u32 = 10_000;
+
+// Error subcodes
+const SUBCODE_SENSOR_LOST: u16 = 0x0210;
+const SUBCODE_SENSOR_FAILED: u16 = 0x0310;
+
+/// Sensors that can be probed and brought back up
+pub trait HotPlug {
+    /// True if the sensor is present and answering
+    fn probe(&mut self) -> bool;
+    /// Re-run the sensor's initialization sequence
+    fn reinit(&mut self) -> Result<(), ()>;
+}
+
+impl HotPlug for Mpu6050 {
+    fn probe(&mut self) -> bool {
+        Mpu6050::probe(self)
+    }
+
+    fn reinit(&mut self) -> Result<(), ()> {
+        Mpu6050::reinit(self)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SensorStatus {
+    Present,
+    Lost,
+    Failed,
+}
+
+#[derive(Clone, Copy)]
+pub enum SensorEvent {
+    Lost(usize),
+    Recovered(usize),
+    Failed(usize),
+}
+
+pub type SensorEventHandler = fn(SensorEvent);
+
+#[derive(Clone, Copy)]
+struct SensorSlot {
+    name: &'static str,
+    status: SensorStatus,
+    retries: u8,
+    last_check: u32,
+}
+
+pub struct SensorManager {
+    slots: [Option<SensorSlot>; MAX_SENSORS],
+    handler: Option<SensorEventHandler>,
+    plan: SamplingPlan,
+}
+
+impl SensorManager {
+    pub const fn new() -> Self {
+        Self {
+            slots: [None; MAX_SENSORS],
+            handler: None,
+            plan: SamplingPlan::new(),
+        }
+    }
+
+    pub fn set_handler(&mut self, handler: SensorEventHandler) {
+        self.handler = Some(handler);
+    }
+
+    /// Start monitoring a sensor, returns its id
+    pub fn register(&mut self, name: &'static str) -> Result<usize, ()> {
+        for (id, slot) in self.slots.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some(SensorSlot {
+                    name,
+                    status: SensorStatus::Present,
+                    retries: 0,
+                    last_check: 0,
+                });
+                return Ok(id);
+            }
+        }
+        Err(())
+    }
+
+    pub fn status(&self, id: usize) -> Option<SensorStatus> {
+        self.slots.get(id).copied().flatten().map(|s| s.status)
+    }
+
+    pub fn name(&self, id: usize) -> Option<&'static str> {
+        self.slots.get(id).copied().flatten().map(|s| s.name)
+    }
+
+    /// True if readings from the sensor can be trusted
+    pub fn is_available(&self, id: usize) -> bool {
+        self.status(id) == Some(SensorStatus::Present)
+    }
+
+    pub fn plan(&self) -> &SamplingPlan {
+        &self.plan
+    }
+
+    /// Plan being edited by the host, see `SamplingPlan::handle_command`
+    pub fn plan_mut(&mut self) -> &mut SamplingPlan {
+        &mut self.plan
+    }
+
+    /// Where a reading of the sensor should go if the sampling plan says
+    /// one is due now. Sensors that are lost or failed are never due.
+    pub fn sample_due(&mut self, id: usize, now_ms: u32) -> Option<Sink> {
+        if !self.is_available(id) {
+            return None;
+        }
+        self.plan.take_due(id as u8, now_ms)
+    }
+
+    /// Probe the sensor if its check is due and try to recover it if it
+    /// went missing. Call regularly from the main loop for every sensor.
+    pub fn poll(&mut self, id: usize, sensor: &mut dyn HotPlug, now_ms: u32, diag: &mut Diagnostics) {
+        let mut slot = match self.slots.get(id).copied().flatten() {
+            Some(slot) => slot,
+            None => return,
+        };
+
+        let period = if slot.status == SensorStatus::Failed {
+            FAILED_CHECK_PERIOD_MS
+        } else {
+            CHECK_PERIOD_MS
+        };
+        if now_ms.wrapping_sub(slot.last_check) < period {
+            return;
+        }
+        slot.last_check = now_ms;
+
+        let event = if slot.status == SensorStatus::Present {
+            if sensor.probe() {
+                None
+            } else {
+                slot.status = SensorStatus::Lost;
+                slot.retries = 0;
+                diag.report_error(ErrorCode::SensorError, SUBCODE_SENSOR_LOST, id as u32);
+                Some(SensorEvent::Lost(id))
+            }
+        } else if sensor.probe() && sensor.reinit().is_ok() {
+            slot.status = SensorStatus::Present;
+            slot.retries = 0;
+            Some(SensorEvent::Recovered(id))
+        } else if slot.status == SensorStatus::Lost {
+            slot.retries += 1;
+            if slot.retries >= MAX_RETRIES {
+                slot.status = SensorStatus::Failed;
+                diag.report_error(ErrorCode::HardwareFault, SUBCODE_SENSOR_FAILED, id as u32);
+                Some(SensorEvent::Failed(id))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        self.slots[id] = Some(slot);
+
+        if let (Some(event), Some(handler)) = (event, self.handler) {
+            handler(event);
+        }
+    }
+}
+
+impl Default for SensorManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,19 +1,72 @@
-use crate::hal::Uart;
+use crate::hal::{PgmSlice, PgmStr, SerialPort, Uart, UartError};
+use crate::protocol::packet::{self, Channel};
 use avr_device::atmega128::USART0;
 
-pub struct SerialConsole {
-    uart: Uart<USART0>,
+const REDIRECT_LINE_LEN: usize = 64;
+const FRAME_BYTE_TIMEOUT_MS: u16 = 10;
+
+/// Text console, on USART0 unless built with `with_port`
+pub struct SerialConsole<S: SerialPort = Uart<USART0>> {
+    uart: S,
+    redirect: bool,
+    line: [u8; REDIRECT_LINE_LEN],
+    line_len: usize,
 }
 
 impl SerialConsole {
     pub fn new() -> Self {
+        Self::with_port(Uart::new())
+    }
+}
+
+impl<S: SerialPort> SerialConsole<S> {
+    pub fn with_port(uart: S) -> Self {
         Self {
-            uart: Uart::new(),
+            uart,
+            redirect: false,
+            line: [0; REDIRECT_LINE_LEN],
+            line_len: 0,
+        }
+    }
+
+    /// Wrap console output in `Channel::Console` packets so it can share
+    /// the UART with the binary protocol. Output is sent per line.
+    pub fn set_redirect(&mut self, enabled: bool) {
+        if !enabled {
+            self.flush();
         }
+        self.redirect = enabled;
+    }
+
+    /// Send any buffered redirected text
+    pub fn flush(&mut self) {
+        if self.line_len == 0 {
+            return;
+        }
+        let uart = &mut self.uart;
+        // A frame cut short by a full buffer would corrupt the packet stream
+        packet::write_channel_frame(Channel::Console, &self.line[..self.line_len], |b| {
+            let _ = uart.write_byte_blocking(b, FRAME_BYTE_TIMEOUT_MS);
+        })
+        .ok();
+        self.line_len = 0;
+    }
+
+    /// Send buffered redirected text and wait until all output has left
+    /// the UART, e.g. before a reset
+    pub fn drain(&mut self, timeout_ms: u16) -> Result<(), UartError> {
+        self.flush();
+        self.uart.flush(timeout_ms)
     }
 
     pub fn write_str(&mut self, s: &str) {
-        self.uart.write_str(s);
+        if self.redirect {
+            for &byte in s.as_bytes() {
+                self.write_byte(byte);
+            }
+        } else {
+            self.uart.write_str(s);
+        }
     }
 
     pub fn write_line(&mut self, s: &str) {
@@ -21,12 +74,44 @@ impl SerialConsole {
         self.write_str("\r\n");
     }
 
+    /// Write a string kept in flash, for messages that would otherwise sit
+    /// in SRAM for the whole run
+    pub fn write_pgm_str(&mut self, s: PgmStr) {
+        self.write_from_progmem(s.as_bytes());
+    }
+
+    pub fn write_pgm_line(&mut self, s: PgmStr) {
+        self.write_pgm_str(s);
+        self.write_str("\r\n");
+    }
+
+    pub fn write_from_progmem(&mut self, data: PgmSlice) {
+        for byte in data {
+            self.write_byte(byte);
+        }
+    }
+
     pub fn read_byte(&mut self) -> Option<u8> {
         self.uart.read_byte()
     }
 
+    /// True if another byte can go out without being dropped, for output
+    /// paced by the caller
+    pub fn is_tx_ready(&self) -> bool {
+        self.uart.is_tx_ready()
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
-        self.uart.write_byte(byte);
+        if !self.redirect {
+            self.uart.write_byte(byte);
+            return;
+        }
+
+        self.line[self.line_len] = byte;
+        self.line_len += 1;
+        if byte == b'\n' || self.line_len == REDIRECT_LINE_LEN {
+            self.flush();
+        }
     }
 
     // Debug helper - print hex value
@@ -36,6 +121,39 @@ impl SerialConsole {
         self.write_byte(HEX_CHARS[(val & 0xF) as usize]);
     }
 
+    /// Print an unsigned integer in decimal
+    pub fn write_decimal(&mut self, mut value: u32) {
+        let mut digits = [0u8; 10];
+        let mut n = 0;
+        loop {
+            digits[n] = b'0' + (value % 10) as u8;
+            value /= 10;
+            n += 1;
+            if value == 0 {
+                break;
+            }
+        }
+        while n > 0 {
+            n -= 1;
+            self.write_byte(digits[n]);
+        }
+    }
+
+    /// Print a value with three decimals without pulling in float formatting
+    pub fn write_fixed(&mut self, value: f32) {
+        let scaled = (value * 1000.0) as i32;
+        if scaled < 0 {
+            self.write_byte(b'-');
+        }
+        let scaled = scaled.unsigned_abs();
+        self.write_decimal(scaled / 1000);
+        self.write_byte(b'.');
+        let frac = scaled % 1000;
+        self.write_byte(b'0' + (frac / 100) as u8);
+        self.write_byte(b'0' + (frac / 10 % 10) as u8);
+        self.write_byte(b'0' + (frac % 10) as u8);
+    }
+
     // Print formatted debug info
     pub fn debug(&mut self, msg: &str, val: u8) {
         self.write_str("[DBG] ");
@@ -50,4 +168,66 @@ impl Default for SerialConsole {
     fn default() -> Self {
         Self::new()
     }
-} 
\ No newline at end of file
+} 
+const INPUT_LINE_LEN: usize = 48;
+
+/// Command line editing for the console: echo, backspace, and the line
+/// handed out once Enter arrives
+pub struct LineInput {
+    line: [u8; INPUT_LINE_LEN],
+    len: usize,
+    ready: bool,
+}
+
+impl LineInput {
+    pub const fn new() -> Self {
+        Self {
+            line: [0; INPUT_LINE_LEN],
+            len: 0,
+            ready: false,
+        }
+    }
+
+    /// Take the bytes waiting on the console, returning a complete line.
+    /// Characters past the line length are dropped.
+    pub fn poll<S: SerialPort>(&mut self, console: &mut SerialConsole<S>) -> Option<&str> {
+        if self.ready {
+            self.ready = false;
+            self.len = 0;
+        }
+        while let Some(byte) = console.read_byte() {
+            match byte {
+                b'\r' | b'\n' => {
+                    if self.len > 0 {
+                        console.write_str("\r\n");
+                        self.ready = true;
+                        break;
+                    }
+                }
+                // Backspace / DEL
+                0x08 | 0x7F => {
+                    if self.len > 0 {
+                        self.len -= 1;
+                        console.write_str("\x08 \x08");
+                    }
+                }
+                _ if self.len < INPUT_LINE_LEN => {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                    console.write_byte(byte);
+                }
+                _ => {}
+            }
+        }
+        if !self.ready {
+            return None;
+        }
+        core::str::from_utf8(&self.line[..self.len]).ok()
+    }
+}
+
+impl Default for LineInput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,260 @@
+//! Hobby servo outputs on the 16-bit timers
+//!
+//! Up to six servos at 50Hz: servos 0-2 on the Timer1 compare outputs
+//! (OC1A/B/C on PB5..PB7), 3-5 on Timer3 (OC3A/B/C on PE3..PE5). Only the
+//! timers picked in `ServoTimers` are taken, so Timer3 stays free for the
+//! RC input and the synchronized acquisition when three servos are enough.
+//!
+//! The timers run fast PWM with ICRn as top at clk/8, so the period and
+//! every pulse are timer counts computed from the exact CPU clock.
+//!
+//! Each servo has its own pulse endpoints and travel, so `set_angle` means
+//! the same on every horn once calibrated. An output sends no pulses until
+//! its first `set_angle`/`set_pulse_us`, which keeps the servos limp rather
+//! than jumping to some default position at power-up.
+//!
+//! Pulses are only sent while the safety state machine is armed; `update`
+//! lets every servo go limp as soon as it leaves Armed.
+#![no_std]
+
+use avr_device::atmega128::{TC1, TC3};
+
+use crate::hal::claims::{self, Resource};
+use crate::hal::clock;
+use crate::hal::gpio::DynPin;
+use crate::hal::timer::{CompareChannel, CompareOutput, Prescaler, Timer16, Timer16Mode};
+use crate::hal::pwm;
+use crate::hal::PwmChannel;
+use crate::safety;
+
+pub const MAX_SERVOS: usize = 6;
+
+const PRESCALER: u32 = 8;
+const PERIOD_US: u32 = 20_000;
+const PERIOD_COUNTS: u32 = clock::us_to_counts(PERIOD_US, PRESCALER);
+const _: () = assert!(PERIOD_COUNTS <= u16::MAX as u32 + 1, "servo period needs a larger prescaler");
+const COMPARE: [CompareChannel; 3] = [CompareChannel::A, CompareChannel::B, CompareChannel::C];
+
+const CHANNELS: [PwmChannel; MAX_SERVOS] = [
+    PwmChannel::Timer1A,
+    PwmChannel::Timer1B,
+    PwmChannel::Timer1C,
+    PwmChannel::Timer3A,
+    PwmChannel::Timer3B,
+    PwmChannel::Timer3C,
+];
+
+/// Which timers drive servos
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ServoTimers {
+    /// Servos 0-2
+    Timer1,
+    /// Servos 3-5
+    Timer3,
+    Both,
+}
+
+/// Pulse widths at the two ends of a servo's travel
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ServoCalibration {
+    /// Pulse at 0 degrees
+    pub min_us: u16,
+    /// Pulse at `range_deg`
+    pub max_us: u16,
+    /// Travel between the two pulses
+    pub range_deg: f32,
+}
+
+impl ServoCalibration {
+    /// 1000-2000us over 180 degrees
+    pub const fn standard() -> Self {
+        Self {
+            min_us: 1000,
+            max_us: 2000,
+            range_deg: 180.0,
+        }
+    }
+
+    /// Pulse for `angle`, clamped to the travel
+    pub fn pulse_for(&self, angle: f32) -> u16 {
+        let fraction = (angle / self.range_deg).clamp(0.0, 1.0);
+        self.min_us + ((self.max_us - self.min_us) as f32 * fraction + 0.5) as u16
+    }
+
+    pub fn angle_for(&self, pulse_us: u16) -> f32 {
+        let span = (self.max_us - self.min_us) as f32;
+        (pulse_us.saturating_sub(self.min_us)) as f32 * self.range_deg / span
+    }
+}
+
+impl Default for ServoCalibration {
+    fn default() -> Self {
+        Self::standard()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ServoError {
+    InvalidServo,
+    /// The servo's timer was not taken in `Servo::new`
+    TimerNotEnabled,
+    /// `min_us` not below `max_us`, or no travel
+    InvalidCalibration,
+    /// Safety state machine is not armed
+    Inhibited,
+}
+
+#[derive(Clone, Copy)]
+struct ServoOutput {
+    calibration: ServoCalibration,
+    /// Pulse being sent, `None` while the output is off
+    pulse_us: Option<u16>,
+}
+
+pub struct Servo {
+    tc1: Option<Timer16<TC1>>,
+    tc3: Option<Timer16<TC3>>,
+    outputs: [ServoOutput; MAX_SERVOS],
+}
+
+impl Servo {
+    /// Take `timers` and run them at 50Hz, all outputs off
+    pub fn new(timers: ServoTimers) -> Self {
+        pwm::register_stop_hook();
+        let tc1 = match timers {
+            ServoTimers::Timer1 | ServoTimers::Both => {
+                claims::claim(Resource::Timer1, "servo").ok();
+                let mut timer = Timer16::<TC1>::new();
+                timer.set_mode(Timer16Mode::FastPwmIcr);
+                timer.set_icr((PERIOD_COUNTS - 1) as u16);
+                timer.start(Prescaler::Div8);
+                Some(timer)
+            }
+            ServoTimers::Timer3 => None,
+        };
+        let tc3 = match timers {
+            ServoTimers::Timer3 | ServoTimers::Both => {
+                claims::claim(Resource::Timer3, "servo").ok();
+                let mut timer = Timer16::<TC3>::new();
+                timer.set_mode(Timer16Mode::FastPwmIcr);
+                timer.set_icr((PERIOD_COUNTS - 1) as u16);
+                timer.start(Prescaler::Div8);
+                Some(timer)
+            }
+            ServoTimers::Timer1 => None,
+        };
+
+        Self {
+            tc1,
+            tc3,
+            outputs: [ServoOutput {
+                calibration: ServoCalibration::standard(),
+                pulse_us: None,
+            }; MAX_SERVOS],
+        }
+    }
+
+    pub fn set_calibration(&mut self, servo: usize, calibration: ServoCalibration) -> Result<(), ServoError> {
+        if calibration.min_us >= calibration.max_us || !(calibration.range_deg > 0.0) {
+            return Err(ServoError::InvalidCalibration);
+        }
+        self.output(servo)?.calibration = calibration;
+        Ok(())
+    }
+
+    pub fn calibration(&self, servo: usize) -> Option<ServoCalibration> {
+        self.outputs.get(servo).map(|o| o.calibration)
+    }
+
+    /// Move to `angle` degrees, clamped to the servo's travel
+    pub fn set_angle(&mut self, servo: usize, angle: f32) -> Result<(), ServoError> {
+        let pulse = self.output(servo)?.calibration.pulse_for(angle);
+        self.write(servo, pulse)
+    }
+
+    /// Send a raw pulse, clamped to the servo's endpoints
+    pub fn set_pulse_us(&mut self, servo: usize, pulse_us: u16) -> Result<(), ServoError> {
+        let calibration = self.output(servo)?.calibration;
+        self.write(servo, pulse_us.clamp(calibration.min_us, calibration.max_us))
+    }
+
+    /// Last commanded angle, `None` while the output is off
+    pub fn angle(&self, servo: usize) -> Option<f32> {
+        let output = self.outputs.get(servo)?;
+        output.pulse_us.map(|pulse| output.calibration.angle_for(pulse))
+    }
+
+    pub fn pulse_us(&self, servo: usize) -> Option<u16> {
+        self.outputs.get(servo)?.pulse_us
+    }
+
+    /// Release every servo once the system leaves Armed, call periodically
+    pub fn update(&mut self) {
+        if safety::outputs_allowed() {
+            return;
+        }
+        for servo in 0..MAX_SERVOS {
+            if self.outputs[servo].pulse_us.is_some() {
+                self.release(servo).ok();
+            }
+        }
+    }
+
+    /// Stop the pulses, the servo goes limp
+    pub fn release(&mut self, servo: usize) -> Result<(), ServoError> {
+        self.output(servo)?;
+        if self.outputs[servo].pulse_us.take().is_some() {
+            let channel = COMPARE[servo % 3];
+            match servo {
+                0..=2 => self.tc1.as_mut().map(|t| t.set_output(channel, CompareOutput::Disconnected)),
+                _ => self.tc3.as_mut().map(|t| t.set_output(channel, CompareOutput::Disconnected)),
+            };
+            let (port, bit) = CHANNELS[servo].pin();
+            DynPin::new(port, bit).set_low();
+            claims::release(Resource::Pin(port, bit), "servo");
+        }
+        Ok(())
+    }
+
+    fn output(&mut self, servo: usize) -> Result<&mut ServoOutput, ServoError> {
+        let enabled = match servo {
+            0..=2 => self.tc1.is_some(),
+            3..=5 => self.tc3.is_some(),
+            _ => return Err(ServoError::InvalidServo),
+        };
+        if !enabled {
+            return Err(ServoError::TimerNotEnabled);
+        }
+        Ok(&mut self.outputs[servo])
+    }
+
+    fn write(&mut self, servo: usize, pulse_us: u16) -> Result<(), ServoError> {
+        if !safety::outputs_allowed() {
+            return Err(ServoError::Inhibited);
+        }
+        if self.outputs[servo].pulse_us.is_none() {
+            // First pulse: the compare output only reaches a pin set up
+            // as an output
+            let (port, bit) = CHANNELS[servo].pin();
+            claims::claim(Resource::Pin(port, bit), "servo").ok();
+            let mut pin = DynPin::new(port, bit);
+            pin.set_low();
+            pin.into_output();
+        }
+        self.outputs[servo].pulse_us = Some(pulse_us);
+        let compare = clock::us_to_counts(pulse_us as u32, PRESCALER).min(PERIOD_COUNTS - 1) as u16;
+        let channel = COMPARE[servo % 3];
+        // Straight on the timer, `Pwm`'s soft-start cap would move the horn
+        match servo {
+            0..=2 => self.tc1.as_mut().map(|t| {
+                t.set_compare(channel, compare);
+                t.set_output(channel, CompareOutput::Clear);
+            }),
+            _ => self.tc3.as_mut().map(|t| {
+                t.set_compare(channel, compare);
+                t.set_output(channel, CompareOutput::Clear);
+            }),
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+//! Timer-synchronized ADC + IMU acquisition
+//!
+//! A Timer3 compare match defines the sample clock. On every tick the
+//! compare interrupt starts a scan of the selected ADC channels (continued
+//! channel by channel from the ADC interrupt) and flags the tick for the
+//! main loop, which reads the IMU. Both halves carry the same sample index,
+//! so recorded datasets are phase-coherent.
+//!
+//! While running the acquisition is attached to the `AdcArbiter`, which
+//! holds queued conversions back until `stop`, and owns Timer3, so it can't
+//! be combined with PPM RC input.
+#![no_std]
+
+use avr_device::atmega128::TC3;
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
+
+use crate::drivers::{Mpu6050, Vec3};
+use crate::hal::claims::{self, Resource};
+use crate::hal::clock;
+use crate::hal::timer::{CompareChannel, Prescaler, Timer16, Timer16Mode, TimerInterrupt};
+use crate::hal::{adc, AdcArbiter, AdcChannel, AdcReference};
+
+pub const MAX_ADC_CHANNELS: usize = 8;
+
+// Timer3 in CTC mode on OCR3A at clk/64
+const TIMER_PRESCALER: u32 = 64;
+
+#[derive(Debug)]
+pub enum AcquisitionError {
+    NoChannels,
+    TooManyChannels,
+    InvalidRate,
+}
+
+/// One sample of every channel, taken on the same timer tick
+#[derive(Clone, Copy)]
+pub struct SyncSample {
+    pub index: u32,
+    pub adc: [u16; MAX_ADC_CHANNELS],
+    pub adc_count: u8,
+    pub accel: Vec3,
+    pub gyro: Vec3,
+    /// False if the IMU read failed, `accel`/`gyro` are zero then
+    pub imu_ok: bool,
+}
+
+struct ScanState {
+    channels: [AdcChannel; MAX_ADC_CHANNELS],
+    count: u8,
+    next: u8,
+    results: [u16; MAX_ADC_CHANNELS],
+    index: u32,
+    /// Tick taken and not yet handed out by `poll`
+    busy: bool,
+    /// Tick not yet seen by `poll` (IMU still to be read)
+    tick: bool,
+    adc_done: bool,
+    overruns: u32,
+}
+
+static SCAN: Mutex<RefCell<ScanState>> = Mutex::new(RefCell::new(ScanState {
+    channels: [AdcChannel::Adc0; MAX_ADC_CHANNELS],
+    count: 0,
+    next: 0,
+    results: [0; MAX_ADC_CHANNELS],
+    index: 0,
+    busy: false,
+    tick: false,
+    adc_done: false,
+    overruns: 0,
+}));
+
+struct PendingImu {
+    index: u32,
+    accel: Vec3,
+    gyro: Vec3,
+    ok: bool,
+}
+
+pub struct SyncAcquisition {
+    rate_hz: u16,
+    pending: Option<PendingImu>,
+    /// Timer3 while running
+    timer: Option<Timer16<TC3>>,
+}
+
+impl SyncAcquisition {
+    /// Sample `channels` and the IMU at `rate_hz` (4Hz and up at 16MHz)
+    pub fn new(channels: &[AdcChannel], rate_hz: u16) -> Result<Self, AcquisitionError> {
+        if channels.is_empty() {
+            return Err(AcquisitionError::NoChannels);
+        }
+        if channels.len() > MAX_ADC_CHANNELS {
+            return Err(AcquisitionError::TooManyChannels);
+        }
+        if rate_hz == 0 || clock::ctc_top(TIMER_PRESCALER, rate_hz as u32) > u16::MAX as u32 {
+            return Err(AcquisitionError::InvalidRate);
+        }
+
+        avr_device::interrupt::free(|cs| {
+            let mut scan = SCAN.borrow(cs).borrow_mut();
+            for (slot, &channel) in scan.channels.iter_mut().zip(channels) {
+                *slot = channel;
+            }
+            scan.count = channels.len() as u8;
+        });
+
+        Ok(Self {
+            rate_hz,
+            pending: None,
+            timer: None,
+        })
+    }
+
+    pub fn rate_hz(&self) -> u16 {
+        self.rate_hz
+    }
+
+    /// Start sampling, converting against the AVCC reference through
+    /// `arbiter` until `stop`
+    pub fn start(&mut self, arbiter: &mut AdcArbiter) {
+        claims::claim(Resource::Timer3, "sync_acquisition").ok();
+        avr_device::interrupt::free(|cs| {
+            let mut scan = SCAN.borrow(cs).borrow_mut();
+            scan.index = 0;
+            scan.busy = false;
+            scan.tick = false;
+            scan.adc_done = false;
+            scan.overruns = 0;
+        });
+        self.pending = None;
+        arbiter.attach(AdcReference::Avcc, on_adc_complete);
+
+        let mut timer = Timer16::<TC3>::new();
+        timer.set_mode(Timer16Mode::CtcOcrA);
+        timer.set_compare(CompareChannel::A, clock::ctc_top(TIMER_PRESCALER, self.rate_hz as u32) as u16);
+        timer.enable_interrupt(TimerInterrupt::Compare(CompareChannel::A));
+        timer.start(Prescaler::Div64);
+        self.timer = Some(timer);
+    }
+
+    /// Stop sampling and give the ADC back to `arbiter`
+    pub fn stop(&mut self, arbiter: &mut AdcArbiter) {
+        if let Some(mut timer) = self.timer.take() {
+            timer.disable_interrupt(TimerInterrupt::Compare(CompareChannel::A));
+            timer.stop();
+        }
+        arbiter.detach();
+        claims::release(Resource::Timer3, "sync_acquisition");
+    }
+
+    /// Ticks skipped because the previous sample had not been collected
+    pub fn overruns(&self) -> u32 {
+        avr_device::interrupt::free(|cs| SCAN.borrow(cs).borrow().overruns)
+    }
+
+    /// Read the IMU for a new tick and return the sample once the ADC scan
+    /// for the same tick has finished. Call at least once per tick.
+    pub fn poll(&mut self, imu: &mut Mpu6050) -> Option<SyncSample> {
+        let tick = avr_device::interrupt::free(|cs| {
+            let mut scan = SCAN.borrow(cs).borrow_mut();
+            let tick = scan.tick.then_some(scan.index);
+            scan.tick = false;
+            tick
+        });
+
+        if let Some(index) = tick {
+            let accel = imu.read_accel();
+            let gyro = imu.read_gyro();
+            let ok = accel.is_ok() && gyro.is_ok();
+            self.pending = Some(PendingImu {
+                index,
+                accel: accel.unwrap_or_default(),
+                gyro: gyro.unwrap_or_default(),
+                ok,
+            });
+        }
+
+        let imu = self.pending.as_ref()?;
+        let sample = avr_device::interrupt::free(|cs| {
+            let mut scan = SCAN.borrow(cs).borrow_mut();
+            if !scan.adc_done || scan.index != imu.index {
+                return None;
+            }
+            scan.adc_done = false;
+            scan.busy = false;
+            Some(SyncSample {
+                index: imu.index,
+                adc: scan.results,
+                adc_count: scan.count,
+                accel: imu.accel,
+                gyro: imu.gyro,
+                imu_ok: imu.ok,
+            })
+        });
+        if sample.is_some() {
+            self.pending = None;
+        }
+        sample
+    }
+}
+
+#[avr_device::interrupt(atmega128)]
+fn TIMER3_COMPA() {
+    avr_device::interrupt::free(|cs| {
+        let mut scan = SCAN.borrow(cs).borrow_mut();
+        if scan.busy {
+            // Previous sample still in flight - skip rather than mix ticks
+            scan.overruns = scan.overruns.wrapping_add(1);
+            return;
+        }
+        scan.index = scan.index.wrapping_add(1);
+        scan.busy = true;
+        scan.tick = true;
+        scan.adc_done = false;
+        scan.next = 0;
+        adc::start_for_handler(scan.channels[0]);
+    });
+}
+
+// Attached to the ADC interrupt through the arbiter while running
+fn on_adc_complete(result: u16) {
+    avr_device::interrupt::free(|cs| {
+        let mut scan = SCAN.borrow(cs).borrow_mut();
+        let slot = scan.next as usize;
+        scan.results[slot] = result;
+        scan.next += 1;
+        if scan.next < scan.count {
+            let next = scan.channels[scan.next as usize];
+            adc::start_for_handler(next);
+        } else {
+            scan.adc_done = true;
+        }
+    });
+}
@@ -0,0 +1,366 @@
+//! Weather station sensors: tipping bucket rain gauge, cup anemometer and
+//! wind vane, as on Davis and SparkFun/Argent style weather meters
+//!
+//! The rain gauge and the anemometer are reed switches to ground, counted
+//! on INT4 (PE4) and INT5 (PE5) with the internal pull-ups. The vane is
+//! read through the ADC: either a potentiometer (Davis, linear over the
+//! full circle) or a resistor network that gives one voltage per compass
+//! point against a pull-up, matched to the closest `VaneTable` entry.
+//!
+//! `WeatherStation` ties them together for the sampling plan: register it
+//! with the `SensorManager`, and when `sample_due` says a reading is due,
+//! `take_reading` returns the rain and mean wind since the previous one
+//! plus the strongest 3s gust. Readings encode into a data logger record
+//! or print as a CSV line (`CSV_HEADER`) for logging on the host.
+#![no_std]
+
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
+
+use crate::application::data_logger::RECORD_DATA;
+use crate::drivers::serial_console::SerialConsole;
+use crate::drivers::sensor_manager::SensorManager;
+use crate::hal::adc::{AdcArbiter, AdcChannel, AdcReference};
+use crate::hal::claims::{self, Port, Resource};
+use crate::hal::exti::{self, ExtInt, ExtiHandler, Trigger};
+use crate::hal::uart::SerialPort;
+
+/// Rain per bucket tip in hundredths of a mm, 0.2mm for the metric
+/// Davis and SparkFun gauges (0.01" gauges: 25)
+pub const DEFAULT_RAIN_PER_TIP: u16 = 20;
+
+/// Wind speed per pulse per second in mm/s, Davis 6410: 2.25mph
+pub const DAVIS_MM_S_PER_HZ: u32 = 1006;
+/// SparkFun/Argent: 2.4km/h
+pub const SPARKFUN_MM_S_PER_HZ: u32 = 667;
+
+/// Gusts are the highest wind speed averaged over this window (WMO)
+pub const GUST_WINDOW_MS: u32 = 3000;
+
+pub const CSV_HEADER: &str = "time_s,rain_mm,wind_ms,gust_ms,dir_deg";
+
+// A bucket tip bounces for a few ms; the anemometer closes at most about
+// 100 times a second in a storm
+const RAIN_DEBOUNCE_MS: u32 = 50;
+const WIND_DEBOUNCE_MS: u32 = 4;
+
+const RAIN_INT: ExtInt = ExtInt::Int4;
+const WIND_INT: ExtInt = ExtInt::Int5;
+
+/// One vane position, the ADC count it reads and its direction
+#[derive(Clone, Copy)]
+pub struct VanePoint {
+    pub counts: u16,
+    pub degrees: u16,
+}
+
+pub type VaneTable = &'static [VanePoint];
+
+const fn point(counts: u16, degrees: u16) -> VanePoint {
+    VanePoint { counts, degrees }
+}
+
+/// SparkFun/Argent vane with a 10k pull-up to AVCC, 16 positions (half
+/// degrees rounded down)
+pub const SPARKFUN_VANE: VaneTable = &[
+    point(786, 0),
+    point(405, 22),
+    point(461, 45),
+    point(84, 67),
+    point(92, 90),
+    point(66, 112),
+    point(184, 135),
+    point(127, 157),
+    point(287, 180),
+    point(244, 202),
+    point(631, 225),
+    point(600, 247),
+    point(946, 270),
+    point(827, 292),
+    point(887, 315),
+    point(702, 337),
+];
+
+// Debounced reed switch on an external interrupt
+struct Contact {
+    count: u32,
+    last_edge_ms: u32,
+    debounce_ms: u32,
+}
+
+impl Contact {
+    const fn new(debounce_ms: u32) -> Self {
+        Self {
+            count: 0,
+            last_edge_ms: 0,
+            debounce_ms,
+        }
+    }
+
+    fn edge(&mut self, now: u32) {
+        if self.count != 0 && now.wrapping_sub(self.last_edge_ms) < self.debounce_ms {
+            return;
+        }
+        self.count = self.count.wrapping_add(1);
+        self.last_edge_ms = now;
+    }
+}
+
+static RAIN: Mutex<RefCell<Contact>> = Mutex::new(RefCell::new(Contact::new(RAIN_DEBOUNCE_MS)));
+static WIND: Mutex<RefCell<Contact>> = Mutex::new(RefCell::new(Contact::new(WIND_DEBOUNCE_MS)));
+
+/// Falling edge interrupt on `line` (INT4..INT7, port E) with pull-up
+fn enable_contact(line: ExtInt, owner: &'static str, handler: ExtiHandler) {
+    claims::claim(Resource::Pin(Port::E, line as u8), owner).ok();
+    exti::attach(line, Trigger::Falling, true, handler).ok();
+}
+
+fn contact_count(contact: &Mutex<RefCell<Contact>>) -> u32 {
+    avr_device::interrupt::free(|cs| contact.borrow(cs).borrow().count)
+}
+
+/// Tipping bucket rain gauge on INT4
+pub struct RainGauge {
+    per_tip: u16,
+    last_count: u32,
+}
+
+impl RainGauge {
+    /// `per_tip` in hundredths of a mm
+    pub fn new(per_tip: u16) -> Self {
+        enable_contact(RAIN_INT, "rain_gauge", on_rain_tip);
+        Self {
+            per_tip,
+            last_count: contact_count(&RAIN),
+        }
+    }
+
+    /// Bucket tips since power-up
+    pub fn tips(&self) -> u32 {
+        contact_count(&RAIN)
+    }
+
+    /// Rain in hundredths of a mm since the last call
+    pub fn take_rain(&mut self) -> u32 {
+        let count = self.tips();
+        let tips = count.wrapping_sub(self.last_count);
+        self.last_count = count;
+        tips * self.per_tip as u32
+    }
+}
+
+/// Cup anemometer on INT5
+pub struct Anemometer {
+    mm_s_per_hz: u32,
+    window_start_ms: u32,
+    window_start_count: u32,
+    mean_start_ms: u32,
+    mean_start_count: u32,
+    gust: u32,
+}
+
+impl Anemometer {
+    pub fn new(mm_s_per_hz: u32, now_ms: u32) -> Self {
+        enable_contact(WIND_INT, "anemometer", on_wind_pulse);
+        let count = contact_count(&WIND);
+        Self {
+            mm_s_per_hz,
+            window_start_ms: now_ms,
+            window_start_count: count,
+            mean_start_ms: now_ms,
+            mean_start_count: count,
+            gust: 0,
+        }
+    }
+
+    fn speed(&self, pulses: u32, elapsed_ms: u32) -> u32 {
+        if elapsed_ms == 0 {
+            return 0;
+        }
+        (pulses as u64 * self.mm_s_per_hz as u64 * 1000 / elapsed_ms as u64) as u32
+    }
+
+    /// Close gust windows, call at least every `GUST_WINDOW_MS`
+    pub fn update(&mut self, now_ms: u32) {
+        let elapsed = now_ms.wrapping_sub(self.window_start_ms);
+        if elapsed < GUST_WINDOW_MS {
+            return;
+        }
+        let count = contact_count(&WIND);
+        let speed = self.speed(count.wrapping_sub(self.window_start_count), elapsed);
+        self.gust = self.gust.max(speed);
+        self.window_start_ms = now_ms;
+        self.window_start_count = count;
+    }
+
+    /// Mean speed and strongest gust in mm/s since the last call
+    pub fn take_wind(&mut self, now_ms: u32) -> (u32, u32) {
+        self.update(now_ms);
+        let count = contact_count(&WIND);
+        let mean = self.speed(
+            count.wrapping_sub(self.mean_start_count),
+            now_ms.wrapping_sub(self.mean_start_ms),
+        );
+        self.mean_start_ms = now_ms;
+        self.mean_start_count = count;
+        // No full gust window yet, the mean is the best there is
+        let gust = core::mem::replace(&mut self.gust, 0).max(mean);
+        (mean, gust)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum VaneKind {
+    /// Potentiometer wiper, 0 counts at north going clockwise
+    Linear,
+    Table(VaneTable),
+}
+
+pub struct WindVane {
+    channel: AdcChannel,
+    kind: VaneKind,
+    /// Degrees added to align the vane with north
+    offset: u16,
+}
+
+impl WindVane {
+    pub fn new(channel: AdcChannel, kind: VaneKind) -> Self {
+        Self {
+            channel,
+            kind,
+            offset: 0,
+        }
+    }
+
+    pub fn set_offset(&mut self, degrees: u16) {
+        self.offset = degrees % 360;
+    }
+
+    /// Direction in degrees for an ADC reading, `None` for a table vane
+    /// reading far from every position (open or shorted sensor)
+    pub fn direction(&self, counts: u16) -> Option<u16> {
+        let degrees = match self.kind {
+            VaneKind::Linear => (counts as u32 * 360 / 1024) as u16,
+            VaneKind::Table(table) => {
+                let closest = table
+                    .iter()
+                    .min_by_key(|p| (p.counts as i16 - counts as i16).unsigned_abs())?;
+                // Neighbouring positions are at least ~8 counts apart
+                if (closest.counts as i16 - counts as i16).unsigned_abs() > 40 {
+                    return None;
+                }
+                closest.degrees
+            }
+        };
+        Some((degrees + self.offset) % 360)
+    }
+
+    pub fn read(&self, adc: &mut AdcArbiter) -> Option<u16> {
+        self.direction(adc.convert_blocking(self.channel, AdcReference::Avcc))
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct WeatherReading {
+    /// Seconds since power-up
+    pub time_s: u32,
+    /// Hundredths of a mm
+    pub rain: u32,
+    /// mm/s
+    pub wind: u32,
+    pub gust: u32,
+    pub direction: Option<u16>,
+}
+
+impl WeatherReading {
+    /// `[rain u16, wind cm/s u16, gust cm/s u16, direction u16]` LE, the
+    /// direction 0xFFFF when unknown. Returns the bytes used.
+    pub fn encode(&self, out: &mut [u8; RECORD_DATA]) -> u8 {
+        let clamp = |v: u32| v.min(u16::MAX as u32) as u16;
+        out[0..2].copy_from_slice(&clamp(self.rain).to_le_bytes());
+        out[2..4].copy_from_slice(&clamp(self.wind / 10).to_le_bytes());
+        out[4..6].copy_from_slice(&clamp(self.gust / 10).to_le_bytes());
+        out[6..8].copy_from_slice(&self.direction.unwrap_or(0xFFFF).to_le_bytes());
+        8
+    }
+
+    /// One line in `CSV_HEADER` column order, the direction left empty
+    /// when unknown
+    pub fn write_csv<S: SerialPort>(&self, console: &mut SerialConsole<S>) {
+        console.write_decimal(self.time_s);
+        console.write_byte(b',');
+        write_hundredths(console, self.rain);
+        console.write_byte(b',');
+        console.write_fixed(self.wind as f32 / 1000.0);
+        console.write_byte(b',');
+        console.write_fixed(self.gust as f32 / 1000.0);
+        console.write_byte(b',');
+        if let Some(direction) = self.direction {
+            console.write_decimal(direction as u32);
+        }
+        console.write_str("\r\n");
+    }
+}
+
+fn write_hundredths<S: SerialPort>(console: &mut SerialConsole<S>, value: u32) {
+    console.write_decimal(value / 100);
+    console.write_byte(b'.');
+    console.write_byte(b'0' + (value / 10 % 10) as u8);
+    console.write_byte(b'0' + (value % 10) as u8);
+}
+
+pub struct WeatherStation {
+    pub rain: RainGauge,
+    pub anemometer: Anemometer,
+    pub vane: WindVane,
+    /// `SensorManager` id for the sampling plan
+    id: Option<usize>,
+}
+
+impl WeatherStation {
+    pub fn new(rain: RainGauge, anemometer: Anemometer, vane: WindVane) -> Self {
+        Self {
+            rain,
+            anemometer,
+            vane,
+            id: None,
+        }
+    }
+
+    /// Register as "weather" so plan entries can refer to the station
+    pub fn register(&mut self, manager: &mut SensorManager) -> Result<usize, ()> {
+        let id = manager.register("weather")?;
+        self.id = Some(id);
+        Ok(id)
+    }
+
+    pub fn id(&self) -> Option<usize> {
+        self.id
+    }
+
+    /// Keep gust windows going, call from the main loop
+    pub fn update(&mut self, now_ms: u32) {
+        self.anemometer.update(now_ms);
+    }
+
+    pub fn take_reading(&mut self, adc: &mut AdcArbiter, now_ms: u32) -> WeatherReading {
+        let (wind, gust) = self.anemometer.take_wind(now_ms);
+        WeatherReading {
+            time_s: now_ms / 1000,
+            rain: self.rain.take_rain(),
+            wind,
+            gust,
+            direction: self.vane.read(adc),
+        }
+    }
+}
+
+fn on_rain_tip() {
+    let now = crate::os::SCHEDULER.get_ticks();
+    avr_device::interrupt::free(|cs| RAIN.borrow(cs).borrow_mut().edge(now));
+}
+
+fn on_wind_pulse() {
+    let now = crate::os::SCHEDULER.get_ticks();
+    avr_device::interrupt::free(|cs| WIND.borrow(cs).borrow_mut().edge(now));
+}
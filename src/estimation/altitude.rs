@@ -0,0 +1,104 @@
+//! Complementary altitude estimator (barometer + vertical acceleration)
+//!
+//! The barometer gives an absolute but noisy and laggy altitude, the
+//! accelerometer a smooth but drifting one once integrated twice. This
+//! second-order complementary filter predicts altitude and climb rate from
+//! the earth-frame vertical acceleration (see
+//! `MadgwickFilter::vertical_accel`) and corrects the prediction towards the
+//! barometric altitude.
+//!
+//! Everything is in integer millimetres / milliseconds so the update runs
+//! without soft-float on the AVR.
+#![no_std]
+
+/// Standard gravity in mm/s^2
+const GRAVITY_MM_S2: f32 = 9806.65;
+
+/// Filter gains in Q8 fixed point (256 = 1.0)
+#[derive(Clone, Copy)]
+pub struct AltitudeConfig {
+    /// Fraction of the baro error applied to altitude per update
+    pub alt_gain_q8: i32,
+    /// Baro error to climb rate correction, per second
+    pub vel_gain_q8: i32,
+}
+
+impl Default for AltitudeConfig {
+    fn default() -> Self {
+        // Tuned for a 20-50Hz update rate with a BMP280 in standard mode
+        Self {
+            alt_gain_q8: 20,   // ~0.08
+            vel_gain_q8: 128,  // ~0.5 /s
+        }
+    }
+}
+
+pub struct AltitudeEstimator {
+    config: AltitudeConfig,
+    altitude_mm: i32,
+    climb_rate_mm_s: i32,
+    initialized: bool,
+}
+
+impl AltitudeEstimator {
+    pub fn new(config: AltitudeConfig) -> Self {
+        Self {
+            config,
+            altitude_mm: 0,
+            climb_rate_mm_s: 0,
+            initialized: false,
+        }
+    }
+
+    /// Restart from the given barometric altitude with zero climb rate
+    pub fn reset(&mut self, baro_altitude_mm: i32) {
+        self.altitude_mm = baro_altitude_mm;
+        self.climb_rate_mm_s = 0;
+        self.initialized = true;
+    }
+
+    /// Run one filter step.
+    ///
+    /// `baro_altitude_mm`: barometric altitude, `vertical_accel_mm_s2`:
+    /// earth-frame vertical acceleration with gravity removed (up positive),
+    /// `dt_ms`: time since the previous update.
+    pub fn update(&mut self, baro_altitude_mm: i32, vertical_accel_mm_s2: i32, dt_ms: u16) {
+        if !self.initialized {
+            self.reset(baro_altitude_mm);
+            return;
+        }
+
+        let dt = dt_ms as i64;
+        let accel = vertical_accel_mm_s2 as i64;
+        let vel = self.climb_rate_mm_s as i64;
+
+        // Predict: x += v*dt + a*dt^2/2, v += a*dt
+        let alt_pred = self.altitude_mm as i64 + vel * dt / 1000 + accel * dt * dt / 2_000_000;
+        let vel_pred = vel + accel * dt / 1000;
+
+        // Correct towards the barometer
+        let error = baro_altitude_mm as i64 - alt_pred;
+        let alt = alt_pred + ((error * self.config.alt_gain_q8 as i64) >> 8);
+        let vel = vel_pred + ((error * self.config.vel_gain_q8 as i64 * dt) >> 8) / 1000;
+
+        self.altitude_mm = alt as i32;
+        self.climb_rate_mm_s = vel as i32;
+    }
+
+    pub fn altitude_mm(&self) -> i32 {
+        self.altitude_mm
+    }
+
+    pub fn climb_rate_mm_s(&self) -> i32 {
+        self.climb_rate_mm_s
+    }
+
+    pub fn set_config(&mut self, config: AltitudeConfig) {
+        self.config = config;
+    }
+}
+
+/// Convert an acceleration in g (as returned by the IMU drivers) to mm/s^2
+pub fn g_to_mm_s2(accel_g: f32) -> i32 {
+    (accel_g * GRAVITY_MM_S2) as i32
+}
@@ -0,0 +1,195 @@
+//! Biquad low-pass and notch filters for gyro data
+//!
+//! Motor vibration aliases into the gyro signal and upsets both the PID
+//! loops and the fusion filter. These filters run on the raw 16-bit sensor
+//! counts with Q12 fixed-point coefficients, so a full update is a handful of
+//! 32-bit multiplies per axis. Coefficients are computed in float when the
+//! cutoff or notch frequency changes, which allows retuning at runtime.
+#![no_std]
+
+use core::f32::consts::PI;
+use libm::{cosf, sinf};
+
+const COEF_SHIFT: u32 = 12;
+const COEF_ONE: f32 = (1 << COEF_SHIFT) as f32;
+const BUTTERWORTH_Q: f32 = 0.7071;
+
+/// Normalized biquad coefficients in Q12 (a0 = 1)
+#[derive(Clone, Copy)]
+pub struct BiquadCoeffs {
+    b0: i32,
+    b1: i32,
+    b2: i32,
+    a1: i32,
+    a2: i32,
+}
+
+impl BiquadCoeffs {
+    /// Filter that passes the input unchanged
+    pub const fn passthrough() -> Self {
+        Self { b0: 1 << COEF_SHIFT, b1: 0, b2: 0, a1: 0, a2: 0 }
+    }
+
+    /// Second order Butterworth low-pass
+    pub fn lowpass(cutoff_hz: f32, sample_hz: f32) -> Self {
+        let w0 = 2.0 * PI * cutoff_hz / sample_hz;
+        let cos = cosf(w0);
+        let alpha = sinf(w0) / (2.0 * BUTTERWORTH_Q);
+        let b = (1.0 - cos) / 2.0;
+        Self::normalize(b, 1.0 - cos, b, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+    }
+
+    /// Notch at `center_hz`; higher `q` gives a narrower notch
+    pub fn notch(center_hz: f32, q: f32, sample_hz: f32) -> Self {
+        let w0 = 2.0 * PI * center_hz / sample_hz;
+        let cos = cosf(w0);
+        let alpha = sinf(w0) / (2.0 * q);
+        Self::normalize(1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+    }
+
+    fn normalize(b0: f32, b1: f32, b2: f32, a0: f32, a1: f32, a2: f32) -> Self {
+        let q12 = |v: f32| {
+            let scaled = v / a0 * COEF_ONE;
+            // Round to nearest
+            if scaled >= 0.0 { (scaled + 0.5) as i32 } else { (scaled - 0.5) as i32 }
+        };
+        Self {
+            b0: q12(b0),
+            b1: q12(b1),
+            b2: q12(b2),
+            a1: q12(a1),
+            a2: q12(a2),
+        }
+    }
+}
+
+/// Direct form I biquad section
+#[derive(Clone, Copy)]
+pub struct Biquad {
+    coeffs: BiquadCoeffs,
+    x1: i16,
+    x2: i16,
+    y1: i16,
+    y2: i16,
+}
+
+impl Biquad {
+    pub const fn new(coeffs: BiquadCoeffs) -> Self {
+        Self { coeffs, x1: 0, x2: 0, y1: 0, y2: 0 }
+    }
+
+    /// Swap coefficients, keeping the filter history to avoid a glitch
+    pub fn set_coeffs(&mut self, coeffs: BiquadCoeffs) {
+        self.coeffs = coeffs;
+    }
+
+    pub fn reset(&mut self) {
+        self.x1 = 0;
+        self.x2 = 0;
+        self.y1 = 0;
+        self.y2 = 0;
+    }
+
+    pub fn process(&mut self, x: i16) -> i16 {
+        let c = &self.coeffs;
+        // |b0|+|b1|+|b2|+|a1|+|a2| stays below 7, so 16-bit samples times
+        // Q12 coefficients can't overflow the 32-bit accumulator
+        let acc = c.b0 * x as i32 + c.b1 * self.x1 as i32 + c.b2 * self.x2 as i32
+            - c.a1 * self.y1 as i32
+            - c.a2 * self.y2 as i32;
+        let y = ((acc + (1 << (COEF_SHIFT - 1))) >> COEF_SHIFT)
+            .clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+/// Gyro filter settings
+#[derive(Clone, Copy)]
+pub struct GyroFilterConfig {
+    /// Rate at which samples are fed in
+    pub sample_hz: f32,
+    pub lowpass_hz: Option<f32>,
+    pub notch_hz: Option<f32>,
+    pub notch_q: f32,
+}
+
+impl Default for GyroFilterConfig {
+    fn default() -> Self {
+        Self {
+            sample_hz: 1000.0,
+            lowpass_hz: Some(90.0),
+            notch_hz: None,
+            notch_q: 3.0,
+        }
+    }
+}
+
+/// Low-pass followed by notch on all three gyro axes
+pub struct GyroFilter {
+    config: GyroFilterConfig,
+    lowpass: [Biquad; 3],
+    notch: [Biquad; 3],
+}
+
+impl GyroFilter {
+    pub fn new(config: GyroFilterConfig) -> Self {
+        let mut filter = Self {
+            config,
+            lowpass: [Biquad::new(BiquadCoeffs::passthrough()); 3],
+            notch: [Biquad::new(BiquadCoeffs::passthrough()); 3],
+        };
+        filter.set_lowpass(config.lowpass_hz);
+        filter.set_notch(config.notch_hz, config.notch_q);
+        filter
+    }
+
+    pub fn config(&self) -> GyroFilterConfig {
+        self.config
+    }
+
+    /// Change the low-pass cutoff, `None` disables it
+    pub fn set_lowpass(&mut self, cutoff_hz: Option<f32>) {
+        self.config.lowpass_hz = cutoff_hz;
+        let coeffs = match cutoff_hz {
+            Some(hz) => BiquadCoeffs::lowpass(hz, self.config.sample_hz),
+            None => BiquadCoeffs::passthrough(),
+        };
+        for stage in self.lowpass.iter_mut() {
+            stage.set_coeffs(coeffs);
+        }
+    }
+
+    /// Move the notch, e.g. to track motor RPM. `None` disables it
+    pub fn set_notch(&mut self, center_hz: Option<f32>, q: f32) {
+        self.config.notch_hz = center_hz;
+        self.config.notch_q = q;
+        let coeffs = match center_hz {
+            Some(hz) => BiquadCoeffs::notch(hz, q, self.config.sample_hz),
+            None => BiquadCoeffs::passthrough(),
+        };
+        for stage in self.notch.iter_mut() {
+            stage.set_coeffs(coeffs);
+        }
+    }
+
+    /// Filter one raw x/y/z sample
+    pub fn apply(&mut self, raw: [i16; 3]) -> [i16; 3] {
+        let mut out = [0i16; 3];
+        for axis in 0..3 {
+            let low = self.lowpass[axis].process(raw[axis]);
+            out[axis] = self.notch[axis].process(low);
+        }
+        out
+    }
+
+    pub fn reset(&mut self) {
+        for stage in self.lowpass.iter_mut().chain(self.notch.iter_mut()) {
+            stage.reset();
+        }
+    }
+}
@@ -0,0 +1,8 @@
+//! State estimation built on top of the raw sensor drivers
+#![no_std]
+
+pub mod altitude;
+pub mod filter;
+
+pub use altitude::{AltitudeConfig, AltitudeEstimator};
+pub use filter::{Biquad, BiquadCoeffs, GyroFilter, GyroFilterConfig};
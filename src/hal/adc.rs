@@ -1,6 +1,188 @@
 use avr_device::atmega128::ADC;
+use avr_device::interrupt::{CriticalSection, Mutex};
+use core::cell::{Cell, RefCell};
 
-#[derive(Clone, Copy)]
+use crate::config::ADC_VREF_MV;
+use crate::hal::claims::{self, Resource};
+use crate::hal::clock;
+use crate::hal::regs::{adcsra, admux};
+use crate::protocol::records::{self, RecordType, SUBSYSTEM_ADC};
+use crate::stats::Accumulator;
+
+/// Samples kept by free-running mode, oldest overwritten first
+pub const CONTINUOUS_BUFFER: usize = 16;
+
+// Clock cycles per conversion after the first
+const CYCLES_PER_CONVERSION: u32 = 13;
+
+/// Most extra bits `read_oversampled` can add, 4^3 = 64 conversions
+pub const MAX_OVERSAMPLE_BITS: u8 = 3;
+
+/// Called from the ADC interrupt with each result while attached with
+/// `AdcArbiter::attach`, for drivers that run their own interrupt-driven
+/// conversions
+pub type AdcCompleteHandler = fn(u16);
+
+// Free-running state shared with the ADC interrupt. Each stored sample is
+// the mean of `decimation` conversions, which brings the fixed conversion
+// rate down to the requested one and averages out noise on the way.
+struct Continuous {
+    active: bool,
+    decimation: u16,
+    count: u16,
+    sum: u32,
+    samples: [u16; CONTINUOUS_BUFFER],
+    head: usize,
+    len: usize,
+    latest: Option<u16>,
+}
+
+impl Continuous {
+    const fn new() -> Self {
+        Self {
+            active: false,
+            decimation: 1,
+            count: 0,
+            sum: 0,
+            samples: [0; CONTINUOUS_BUFFER],
+            head: 0,
+            len: 0,
+            latest: None,
+        }
+    }
+
+    fn push(&mut self, result: u16) {
+        self.sum += result as u32;
+        self.count += 1;
+        if self.count < self.decimation {
+            return;
+        }
+        let sample = (self.sum / self.count as u32) as u16;
+        self.sum = 0;
+        self.count = 0;
+
+        self.samples[self.head] = sample;
+        self.head = (self.head + 1) % CONTINUOUS_BUFFER;
+        self.len = (self.len + 1).min(CONTINUOUS_BUFFER);
+        self.latest = Some(sample);
+    }
+
+    fn pop_oldest(&mut self) -> Option<u16> {
+        if self.len == 0 {
+            return None;
+        }
+        let tail = (self.head + CONTINUOUS_BUFFER - self.len) % CONTINUOUS_BUFFER;
+        self.len -= 1;
+        Some(self.samples[tail])
+    }
+}
+
+static CONTINUOUS: Mutex<RefCell<Continuous>> = Mutex::new(RefCell::new(Continuous::new()));
+
+/// Channels an `AdcScanner` can cycle through
+pub const SCAN_CHANNELS: usize = 8;
+
+// Round-robin scan shared with the ADC interrupt. Each result is stored
+// for the channel it was converted on and the next channel in the list is
+// started straight away, in single conversion mode so the MUX change
+// applies to the conversion it was meant for.
+struct Scan {
+    active: bool,
+    channels: [u8; SCAN_CHANNELS],
+    len: usize,
+    index: usize,
+    values: [Option<u16>; SCAN_CHANNELS],
+    sweeps: u32,
+}
+
+impl Scan {
+    const fn new() -> Self {
+        Self {
+            active: false,
+            channels: [0; SCAN_CHANNELS],
+            len: 0,
+            index: 0,
+            values: [None; SCAN_CHANNELS],
+            sweeps: 0,
+        }
+    }
+
+    /// Store `result` and return the channel to convert next
+    fn push(&mut self, result: u16) -> u8 {
+        self.values[self.channels[self.index] as usize] = Some(result);
+        self.index += 1;
+        if self.index == self.len {
+            self.index = 0;
+            self.sweeps = self.sweeps.wrapping_add(1);
+        }
+        self.channels[self.index]
+    }
+}
+
+static SCAN: Mutex<RefCell<Scan>> = Mutex::new(RefCell::new(Scan::new()));
+static COMPLETE_HANDLER: Mutex<Cell<Option<AdcCompleteHandler>>> = Mutex::new(Cell::new(None));
+
+const RECORD_BLOCK: u8 = 0x01;
+
+/// `AdcBlock` record value of a channel not converted since boot
+pub const NOT_CONVERTED: u16 = 0xFFFF;
+
+// Latest result per channel from the arbiter or the scanner, for the
+// `AdcBlock` record
+static LATEST: Mutex<Cell<[u16; SCAN_CHANNELS]>> = Mutex::new(Cell::new([NOT_CONVERTED; SCAN_CHANNELS]));
+
+fn note_latest(cs: CriticalSection, channel: u8, value: u16) {
+    let latest = LATEST.borrow(cs);
+    let mut values = latest.get();
+    values[channel as usize] = value;
+    latest.set(values);
+}
+
+fn register_record() {
+    records::register(SUBSYSTEM_ADC, RECORD_BLOCK, "adc", RecordType::AdcBlock, 0, read_block).ok();
+}
+
+// ADC0-ADC7 in one block
+fn read_block(_index: u8, out: &mut [u8]) -> Option<usize> {
+    let len = 2 + 2 * SCAN_CHANNELS;
+    if out.len() < len {
+        return None;
+    }
+    let values = avr_device::interrupt::free(|cs| LATEST.borrow(cs).get());
+    out[0] = 0;
+    out[1] = SCAN_CHANNELS as u8;
+    for (chunk, value) in out[2..len].chunks_exact_mut(2).zip(values) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    Some(len)
+}
+
+fn set_complete_handler(handler: Option<AdcCompleteHandler>) {
+    avr_device::interrupt::free(|cs| COMPLETE_HANDLER.borrow(cs).set(handler));
+}
+
+/// Start a conversion of `channel` for the attached handler, from the
+/// handler itself or another interrupt. Does nothing unless a handler is
+/// attached, see `AdcArbiter::attach`.
+pub fn start_for_handler(channel: AdcChannel) {
+    avr_device::interrupt::free(|cs| {
+        if COMPLETE_HANDLER.borrow(cs).get().is_none() {
+            return;
+        }
+        unsafe {
+            let p = ADC::ptr();
+            (*p).admux.modify(|r, w| w.bits((r.bits() & !admux::MUX_MASK) | (channel as u8)));
+            (*p).adcsra.modify(|r, w| w.bits(r.bits() | adcsra::ADSC));
+        }
+    });
+}
+
+/// Millivolts at the pin for a conversion against the AVCC reference
+pub fn counts_to_mv(counts: u16) -> u16 {
+    (counts as u32 * ADC_VREF_MV as u32 / 1024) as u16
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 #[repr(u8)]
 pub enum AdcChannel {
     Adc0 = 0,
@@ -41,10 +223,10 @@ impl Adc {
     pub fn new() -> Self {
         unsafe {
             let p = ADC::ptr();
-            // Enable ADC, prescaler div128 (125kHz @ 16MHz)
-            (*p).adcsra.write(|w| w.bits(0x87));
+            // Enable ADC, prescaler for an ADC clock of at most 200kHz
+            (*p).adcsra.write(|w| w.bits(adcsra::ADEN | clock::adc_prescaler_bits()));
             // Reference voltage = AVCC
-            (*p).admux.write(|w| w.bits(0x40));
+            (*p).admux.write(|w| w.bits((AdcReference::Avcc as u8) << admux::REFS_SHIFT));
         }
         Self { _private: () }
     }
@@ -53,34 +235,47 @@ impl Adc {
         unsafe {
             let p = ADC::ptr();
             (*p).admux.modify(|r, w| {
-                w.bits((r.bits() & 0x3F) | ((reference as u8) << 6))
+                let bits = (r.bits() & !admux::REFS_MASK) | ((reference as u8) << admux::REFS_SHIFT);
+                debug_assert!(admux::valid(bits));
+                w.bits(bits)
             });
         }
     }
 
     pub fn set_prescaler(&mut self, prescaler: AdcPrescaler) {
+        debug_assert!(adcsra::valid_clock(clock::CPU_FREQ, prescaler as u8));
         unsafe {
             let p = ADC::ptr();
             (*p).adcsra.modify(|r, w| {
-                w.bits((r.bits() & 0xF8) | (prescaler as u8))
+                w.bits((r.bits() & !adcsra::ADPS_MASK) | (prescaler as u8))
             });
         }
     }
 
-    pub fn read_channel(&mut self, channel: AdcChannel) -> u16 {
+    /// Select a channel and start a single conversion without waiting
+    pub fn start_conversion(&mut self, channel: AdcChannel) {
         unsafe {
             let p = ADC::ptr();
             
             // Select channel
             (*p).admux.modify(|r, w| {
-                w.bits((r.bits() & 0xE0) | (channel as u8))
+                w.bits((r.bits() & !admux::MUX_MASK) | (channel as u8))
             });
             
             // Start conversion
-            (*p).adcsra.modify(|r, w| w.bits(r.bits() | 0x40));
-            
-            // Wait for completion
-            while (*p).adcsra.read().bits() & 0x40 != 0 {}
+            (*p).adcsra.modify(|r, w| w.bits(r.bits() | adcsra::ADSC));
+        }
+    }
+
+    /// True once the conversion started by `start_conversion` has finished
+    pub fn is_complete(&self) -> bool {
+        unsafe { (*ADC::ptr()).adcsra.read().bits() & adcsra::ADSC == 0 }
+    }
+
+    /// Read the result of the last conversion
+    pub fn read_result(&mut self) -> u16 {
+        unsafe {
+            let p = ADC::ptr();
             
             // Read result (ADCL must be read first)
             let low = (*p).adcl.read().bits() as u16;
@@ -90,24 +285,136 @@ impl Adc {
         }
     }
 
+    pub fn read_channel(&mut self, channel: AdcChannel) -> u16 {
+        self.start_conversion(channel);
+        
+        // Wait for completion
+        while !self.is_complete() {}
+        
+        self.read_result()
+    }
+
+    /// Sum 4^`extra_bits` conversions of `channel` and shift the sum right
+    /// by `extra_bits`, for a 10 + `extra_bits` bit result. This only adds
+    /// resolution when the input carries at least 1 LSB of noise, which
+    /// the ADC's own noise usually provides; a perfectly quiet input reads
+    /// the same as a plain conversion scaled up. `extra_bits` is capped at
+    /// `MAX_OVERSAMPLE_BITS`, 64 conversions take about 6.5ms at a 125kHz
+    /// ADC clock.
+    pub fn read_oversampled(&mut self, channel: AdcChannel, extra_bits: u8) -> u16 {
+        let extra_bits = extra_bits.min(MAX_OVERSAMPLE_BITS);
+        let samples = 1u16 << (2 * extra_bits);
+        // 64 * 1023 fits in 16 bits
+        let mut sum = 0u16;
+        for _ in 0..samples {
+            sum += self.read_channel(channel);
+        }
+        sum >> extra_bits
+    }
+
+    /// `read_voltage` from an oversampled reading, see `read_oversampled`
+    pub fn read_voltage_oversampled(&mut self, channel: AdcChannel, extra_bits: u8) -> f32 {
+        let extra_bits = extra_bits.min(MAX_OVERSAMPLE_BITS);
+        let raw = self.read_oversampled(channel, extra_bits);
+        (raw as f32) * ADC_VREF_MV as f32 / 1000.0 / (1024u32 << extra_bits) as f32
+    }
+
     pub fn read_voltage(&mut self, channel: AdcChannel) -> f32 {
         let raw = self.read_channel(channel);
-        // Convert to voltage (assuming AVCC = 5V)
-        (raw as f32) * 5.0 / 1024.0
+        // AVCC reference, see config::BOARD_VCC_MV
+        (raw as f32) * ADC_VREF_MV as f32 / 1000.0 / 1024.0
     }
 
     pub fn enable_interrupt(&mut self) {
         unsafe {
             let p = ADC::ptr();
-            (*p).adcsra.modify(|r, w| w.bits(r.bits() | 0x08));
+            (*p).adcsra.modify(|r, w| w.bits(r.bits() | adcsra::ADIE));
         }
     }
 
     pub fn disable_interrupt(&mut self) {
         unsafe {
             let p = ADC::ptr();
-            (*p).adcsra.modify(|r, w| w.bits(r.bits() & !0x08));
+            (*p).adcsra.modify(|r, w| w.bits(r.bits() & !adcsra::ADIE));
+        }
+    }
+
+    /// Conversions per second in free-running mode at the current prescaler
+    pub fn conversion_rate(&self) -> u32 {
+        let adps = unsafe { (*ADC::ptr()).adcsra.read().bits() & adcsra::ADPS_MASK };
+        let div = match adps {
+            0 | 1 => 2,
+            n => 1 << n,
+        };
+        clock::CPU_FREQ / (div * CYCLES_PER_CONVERSION)
+    }
+
+    /// Convert `channel` over and over in free-running mode and keep
+    /// `rate_hz` samples per second in a ring buffer, each averaged over
+    /// the conversions in between. Returns the rate actually kept. The
+    /// interrupt runs at the full conversion rate, see `conversion_rate`.
+    pub fn start_continuous(&mut self, channel: AdcChannel, rate_hz: u32) -> Result<u32, AdcError> {
+        let free_rate = self.conversion_rate();
+        if rate_hz == 0 || rate_hz > free_rate {
+            return Err(AdcError::InvalidRate);
+        }
+        let decimation = (free_rate / rate_hz).min(u16::MAX as u32) as u16;
+
+        self.stop_continuous();
+        avr_device::interrupt::free(|cs| {
+            let mut continuous = CONTINUOUS.borrow(cs).borrow_mut();
+            *continuous = Continuous::new();
+            continuous.active = true;
+            continuous.decimation = decimation;
+        });
+
+        unsafe {
+            let p = ADC::ptr();
+            (*p).admux.modify(|r, w| w.bits((r.bits() & !admux::MUX_MASK) | (channel as u8)));
+            // Clear a stale completion flag before enabling its interrupt
+            (*p).adcsra.modify(|r, w| {
+                w.bits(r.bits() | adcsra::ADFR | adcsra::ADIF | adcsra::ADIE | adcsra::ADSC)
+            });
         }
+        Ok(free_rate / decimation as u32)
+    }
+
+    /// Leave free-running mode after the conversion in progress
+    pub fn stop_continuous(&mut self) {
+        unsafe {
+            let p = ADC::ptr();
+            (*p).adcsra.modify(|r, w| w.bits(r.bits() & !(adcsra::ADFR | adcsra::ADIE)));
+        }
+        while !self.is_complete() {}
+        avr_device::interrupt::free(|cs| CONTINUOUS.borrow(cs).borrow_mut().active = false);
+    }
+
+    pub fn is_continuous(&self) -> bool {
+        avr_device::interrupt::free(|cs| CONTINUOUS.borrow(cs).borrow().active)
+    }
+
+    /// Newest free-running sample, without taking it out of the buffer
+    pub fn read_latest(&self) -> Option<u16> {
+        avr_device::interrupt::free(|cs| CONTINUOUS.borrow(cs).borrow().latest)
+    }
+
+    /// Move buffered free-running samples into `buffer`, oldest first.
+    /// Returns the count copied.
+    pub fn read_samples(&mut self, buffer: &mut [u16]) -> usize {
+        avr_device::interrupt::free(|cs| {
+            let mut continuous = CONTINUOUS.borrow(cs).borrow_mut();
+            let mut count = 0;
+            while count < buffer.len() {
+                match continuous.pop_oldest() {
+                    Some(sample) => {
+                        buffer[count] = sample;
+                        count += 1;
+                    }
+                    None => break,
+                }
+            }
+            count
+        })
     }
 }
 
@@ -115,4 +422,389 @@ impl Default for Adc {
     fn default() -> Self {
         Self::new()
     }
-} 
\ No newline at end of file
+}
+
+/// Converts a list of channels round-robin from the ADC interrupt and keeps
+/// the latest result of each, so the main loop reads ADC0-ADC7 without
+/// waiting on eight blocking conversions. Every channel is refreshed once
+/// per sweep, a little under `conversion_rate() / channels` times a
+/// second since each conversion is started from the interrupt.
+///
+/// Takes the ADC over like `AdcArbiter` does, use one or the other.
+pub struct AdcScanner {
+    adc: Adc,
+}
+
+impl AdcScanner {
+    pub fn new(adc: Adc) -> Self {
+        claims::claim(Resource::Adc, "adc_scanner").ok();
+        register_record();
+        Self { adc }
+    }
+
+    pub fn set_reference(&mut self, reference: AdcReference) {
+        let running = self.is_running();
+        self.stop();
+        self.adc.set_reference(reference);
+        if running {
+            self.restart();
+        }
+    }
+
+    /// Scan `channels` in the given order, repeating a channel in the list
+    /// samples it more often. Results from a previous scan are dropped.
+    pub fn start(&mut self, channels: &[AdcChannel]) -> Result<(), AdcError> {
+        if channels.is_empty() || channels.len() > SCAN_CHANNELS {
+            return Err(AdcError::InvalidChannels);
+        }
+        self.stop();
+        if self.adc.is_continuous() {
+            self.adc.stop_continuous();
+        }
+
+        avr_device::interrupt::free(|cs| {
+            let mut scan = SCAN.borrow(cs).borrow_mut();
+            *scan = Scan::new();
+            for (slot, &channel) in scan.channels.iter_mut().zip(channels) {
+                *slot = channel as u8;
+            }
+            scan.len = channels.len();
+        });
+        self.restart();
+        Ok(())
+    }
+
+    /// Stop after the conversion in progress, the stored values stay
+    pub fn stop(&mut self) {
+        avr_device::interrupt::free(|cs| SCAN.borrow(cs).borrow_mut().active = false);
+        self.adc.disable_interrupt();
+        while !self.adc.is_complete() {}
+    }
+
+    pub fn is_running(&self) -> bool {
+        avr_device::interrupt::free(|cs| SCAN.borrow(cs).borrow().active)
+    }
+
+    /// Latest result of `channel`, `None` until it has been converted once
+    pub fn read(&self, channel: AdcChannel) -> Option<u16> {
+        avr_device::interrupt::free(|cs| SCAN.borrow(cs).borrow().values[channel as usize])
+    }
+
+    /// Latest result of every channel, indexed by channel number
+    pub fn values(&self) -> [Option<u16>; SCAN_CHANNELS] {
+        avr_device::interrupt::free(|cs| SCAN.borrow(cs).borrow().values)
+    }
+
+    /// Completed passes over the channel list, to tell fresh values from
+    /// ones already seen
+    pub fn sweeps(&self) -> u32 {
+        avr_device::interrupt::free(|cs| SCAN.borrow(cs).borrow().sweeps)
+    }
+
+    /// Stop scanning and hand the ADC back
+    pub fn release(mut self) -> Adc {
+        self.stop();
+        claims::release(Resource::Adc, "adc_scanner");
+        self.adc
+    }
+
+    // Start from the first channel of the list
+    fn restart(&mut self) {
+        let first = avr_device::interrupt::free(|cs| {
+            let mut scan = SCAN.borrow(cs).borrow_mut();
+            scan.index = 0;
+            scan.active = true;
+            scan.channels[0]
+        });
+        unsafe {
+            let p = ADC::ptr();
+            (*p).admux.modify(|r, w| w.bits((r.bits() & !admux::MUX_MASK) | first));
+            // Clear a stale completion flag before enabling its interrupt
+            (*p).adcsra.modify(|r, w| {
+                w.bits((r.bits() & !adcsra::ADFR) | adcsra::ADIF | adcsra::ADIE | adcsra::ADSC)
+            });
+        }
+    }
+}
+
+const ARBITER_QUEUE_SIZE: usize = 8;
+
+#[derive(Debug)]
+pub enum AdcError {
+    QueueFull,
+    /// Free-running rate of zero or above the conversion rate
+    InvalidRate,
+    /// Scan list empty or longer than `SCAN_CHANNELS`
+    InvalidChannels,
+}
+
+/// Callback invoked with the finished conversion result
+pub type AdcCallback = fn(AdcChannel, u16);
+
+/// Queued conversion request
+#[derive(Clone, Copy)]
+pub struct AdcRequest {
+    pub channel: AdcChannel,
+    pub reference: AdcReference,
+    pub priority: u8,
+    pub callback: AdcCallback,
+}
+
+/// Owns the ADC exclusively and serializes conversion requests from the
+/// battery monitor, diagnostics and application so they can no longer
+/// clobber each other's ADMUX settings.
+///
+/// Requests are served highest priority first, FIFO within a priority.
+pub struct AdcArbiter {
+    adc: Adc,
+    /// Requests with the sequence number they were submitted under
+    queue: [Option<(u16, AdcRequest)>; ARBITER_QUEUE_SIZE],
+    next_seq: u16,
+    active: Option<AdcRequest>,
+    reference: Option<AdcReference>,
+    monitor: Option<(u8, Accumulator)>,
+    /// Free-running channel and rate, paused for blocking conversions
+    continuous: Option<(AdcChannel, u32)>,
+    /// An interrupt handler runs its own conversions, see `attach`
+    attached: bool,
+}
+
+impl AdcArbiter {
+    pub fn new(adc: Adc) -> Self {
+        claims::claim(Resource::Adc, "adc_arbiter").ok();
+        register_record();
+        Self {
+            adc,
+            queue: [None; ARBITER_QUEUE_SIZE],
+            next_seq: 0,
+            active: None,
+            reference: None,
+            monitor: None,
+            continuous: None,
+            attached: false,
+        }
+    }
+
+    /// Hand conversions over to `handler`, which is called from the ADC
+    /// interrupt with every result and starts the next conversion with
+    /// `start_for_handler`. Queued requests wait until `detach`, free-running
+    /// mode is stopped and `convert_blocking` must not be used meanwhile.
+    pub fn attach(&mut self, reference: AdcReference, handler: AdcCompleteHandler) {
+        while self.active.is_some() {
+            self.poll_active();
+        }
+        if self.continuous.is_some() {
+            self.stop_continuous();
+        }
+        self.select_reference(reference);
+        set_complete_handler(Some(handler));
+        self.attached = true;
+        unsafe {
+            // Clear a stale completion flag before enabling its interrupt
+            (*ADC::ptr()).adcsra.modify(|r, w| w.bits(r.bits() | adcsra::ADIF | adcsra::ADIE));
+        }
+    }
+
+    /// Take the ADC back from the attached handler after the conversion in
+    /// progress and resume queued requests
+    pub fn detach(&mut self) {
+        if !self.attached {
+            return;
+        }
+        self.adc.disable_interrupt();
+        set_complete_handler(None);
+        while !self.adc.is_complete() {}
+        self.attached = false;
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.attached
+    }
+
+    /// Put the ADC in free-running mode on `channel`, see
+    /// `Adc::start_continuous`. Queued requests wait until
+    /// `stop_continuous`; `convert_blocking` pauses it for one conversion.
+    pub fn start_continuous(
+        &mut self,
+        channel: AdcChannel,
+        reference: AdcReference,
+        rate_hz: u32,
+    ) -> Result<u32, AdcError> {
+        while self.active.is_some() {
+            self.poll_active();
+        }
+        self.select_reference(reference);
+        let rate = self.adc.start_continuous(channel, rate_hz)?;
+        self.continuous = Some((channel, rate));
+        Ok(rate)
+    }
+
+    pub fn stop_continuous(&mut self) {
+        self.adc.stop_continuous();
+        self.continuous = None;
+    }
+
+    /// Newest free-running sample
+    pub fn read_latest(&self) -> Option<u16> {
+        self.adc.read_latest()
+    }
+
+    pub fn read_samples(&mut self, buffer: &mut [u16]) -> usize {
+        self.adc.read_samples(buffer)
+    }
+
+    /// Collect statistics of every result converted on `channel`, or stop
+    /// monitoring with `None`
+    pub fn set_monitor(&mut self, channel: Option<AdcChannel>, accumulator: Accumulator) {
+        self.monitor = channel.map(|c| (c as u8, accumulator));
+    }
+
+    /// Statistics of the monitored channel in raw counts
+    pub fn monitor(&self) -> Option<&Accumulator> {
+        self.monitor.as_ref().map(|(_, acc)| acc)
+    }
+
+    fn record(&mut self, channel: AdcChannel, value: u16) {
+        avr_device::interrupt::free(|cs| note_latest(cs, channel as u8, value));
+        if let Some((monitored, acc)) = self.monitor.as_mut() {
+            if *monitored == channel as u8 {
+                acc.push(value as f32);
+            }
+        }
+    }
+
+    /// Queue a conversion request
+    pub fn submit(&mut self, request: AdcRequest) -> Result<(), AdcError> {
+        for slot in self.queue.iter_mut() {
+            if slot.is_none() {
+                *slot = Some((self.next_seq, request));
+                self.next_seq = self.next_seq.wrapping_add(1);
+                return Ok(());
+            }
+        }
+        Err(AdcError::QueueFull)
+    }
+
+    /// Number of requests waiting (not counting the active conversion)
+    pub fn pending(&self) -> usize {
+        self.queue.iter().filter(|r| r.is_some()).count()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.active.is_none() && self.pending() == 0
+    }
+
+    /// Advance the arbiter: finish the running conversion and start the
+    /// next one. Call this regularly from the main loop.
+    pub fn poll(&mut self) {
+        self.poll_active();
+        if self.active.is_some() || self.continuous.is_some() || self.attached {
+            return;
+        }
+
+        if let Some(request) = self.take_next() {
+            self.start(request);
+            self.active = Some(request);
+        }
+    }
+
+    /// Run a conversion synchronously. Any conversion already in flight is
+    /// completed first so its result is not lost.
+    pub fn convert_blocking(&mut self, channel: AdcChannel, reference: AdcReference) -> u16 {
+        debug_assert!(!self.attached, "ADC attached to an interrupt handler");
+        while self.active.is_some() {
+            self.poll_active();
+        }
+        let continuous = self.continuous;
+        if continuous.is_some() {
+            self.adc.stop_continuous();
+        }
+
+        let previous_reference = self.reference;
+        self.select_reference(reference);
+        let value = self.adc.read_channel(channel);
+        self.record(channel, value);
+
+        if let Some((channel, rate)) = continuous {
+            if let Some(reference) = previous_reference {
+                self.select_reference(reference);
+            }
+            self.adc.start_continuous(channel, rate).ok();
+        }
+        value
+    }
+
+    fn poll_active(&mut self) {
+        if let Some(request) = self.active {
+            if self.adc.is_complete() {
+                let value = self.adc.read_result();
+                self.active = None;
+                self.record(request.channel, value);
+                (request.callback)(request.channel, value);
+            }
+        }
+    }
+
+    fn take_next(&mut self) -> Option<AdcRequest> {
+        let mut best: Option<(usize, u16, u8)> = None;
+        for (i, slot) in self.queue.iter().enumerate() {
+            if let Some((seq, request)) = slot {
+                let better = match best {
+                    None => true,
+                    Some((_, best_seq, best_priority)) => {
+                        request.priority > best_priority
+                            // Older first; the sequence wraps, the queue is short
+                            || (request.priority == best_priority && (seq.wrapping_sub(best_seq) as i16) < 0)
+                    }
+                };
+                if better {
+                    best = Some((i, *seq, request.priority));
+                }
+            }
+        }
+        best.and_then(|(i, _, _)| self.queue[i].take()).map(|(_, request)| request)
+    }
+
+    fn start(&mut self, request: AdcRequest) {
+        self.select_reference(request.reference);
+        self.adc.start_conversion(request.channel);
+    }
+
+    fn select_reference(&mut self, reference: AdcReference) {
+        // Only touch ADMUX when the reference actually changes; the first
+        // conversion after a switch may still be settling.
+        if self.reference.map(|r| r as u8) != Some(reference as u8) {
+            self.adc.set_reference(reference);
+            self.reference = Some(reference);
+        }
+    }
+} 
+
+#[avr_device::interrupt(atmega128)]
+fn ADC() {
+    let result = unsafe {
+        let p = ADC::ptr();
+        // ADCL must be read first
+        let low = (*p).adcl.read().bits() as u16;
+        let high = (*p).adch.read().bits() as u16;
+        (high << 8) | low
+    };
+
+    avr_device::interrupt::free(|cs| {
+        let mut continuous = CONTINUOUS.borrow(cs).borrow_mut();
+        let mut scan = SCAN.borrow(cs).borrow_mut();
+        if continuous.active {
+            continuous.push(result);
+        } else if scan.active {
+            note_latest(cs, scan.channels[scan.index], result);
+            let next = scan.push(result);
+            unsafe {
+                let p = ADC::ptr();
+                (*p).admux.modify(|r, w| w.bits((r.bits() & !admux::MUX_MASK) | next));
+                (*p).adcsra.modify(|r, w| w.bits(r.bits() | adcsra::ADSC));
+            }
+        } else if let Some(handler) = COMPLETE_HANDLER.borrow(cs).get() {
+            handler(result);
+        }
+    });
+}
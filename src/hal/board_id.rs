@@ -0,0 +1,123 @@
+//! Hardware revision strap
+//!
+//! Every board revision fits a different resistor divider on the strap pin
+//! (ADC7/PF7), so one firmware image can tell them apart:
+//!
+//! | revision | divider            | counts |
+//! |----------|--------------------|--------|
+//! | A        | pin tied to GND    | 0      |
+//! | B        | 20k over 10k       | 341    |
+//! | C        | 10k over 20k       | 683    |
+//!
+//! `detect` runs once at boot and selects the matching `BoardConfig`,
+//! which drivers read through `config()` afterwards. A reading outside all
+//! windows (strap missing, pin floating) reports `Unknown` and falls back
+//! to the rev A mapping.
+#![no_std]
+
+use avr_device::interrupt::Mutex;
+use core::cell::Cell;
+
+use crate::drivers::lm75::LM75_DEFAULT_ADDR;
+use crate::hal::{AdcArbiter, AdcChannel, AdcReference};
+
+/// ADC channel the strap divider is wired to
+pub const STRAP_CHANNEL: AdcChannel = AdcChannel::Adc7;
+
+// Averaged so a noisy supply can't shift a reading into the next window
+const STRAP_SAMPLES: u16 = 4;
+// Accepted distance from the nominal reading, divider tolerance plus noise
+const STRAP_WINDOW: u16 = 64;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum BoardRevision {
+    Unknown = 0,
+    A = 1,
+    B = 2,
+    C = 3,
+}
+
+impl BoardRevision {
+    /// Revision whose strap reading is within the window of `counts`
+    pub fn from_strap(counts: u16) -> Self {
+        const NOMINAL: [(u16, BoardRevision); 3] = [
+            (0, BoardRevision::A),
+            (341, BoardRevision::B),
+            (683, BoardRevision::C),
+        ];
+        for &(nominal, revision) in NOMINAL.iter() {
+            if counts.abs_diff(nominal) <= STRAP_WINDOW {
+                return revision;
+            }
+        }
+        BoardRevision::Unknown
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BoardRevision::Unknown => "unknown",
+            BoardRevision::A => "A",
+            BoardRevision::B => "B",
+            BoardRevision::C => "C",
+        }
+    }
+}
+
+/// What changed between board revisions
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BoardConfig {
+    pub revision: BoardRevision,
+    /// ADC channel of the supply voltage divider
+    pub supply_channel: AdcChannel,
+    /// LM75 address, rev C has A0 pulled high
+    pub lm75_addr: u8,
+}
+
+impl BoardConfig {
+    pub const fn for_revision(revision: BoardRevision) -> Self {
+        match revision {
+            BoardRevision::Unknown | BoardRevision::A => Self {
+                revision,
+                supply_channel: AdcChannel::Adc0,
+                lm75_addr: LM75_DEFAULT_ADDR,
+            },
+            // Supply sense moved off ADC0 to free it for the expansion header
+            BoardRevision::B => Self {
+                revision,
+                supply_channel: AdcChannel::Adc6,
+                lm75_addr: LM75_DEFAULT_ADDR,
+            },
+            BoardRevision::C => Self {
+                revision,
+                supply_channel: AdcChannel::Adc6,
+                lm75_addr: LM75_DEFAULT_ADDR | 0x01,
+            },
+        }
+    }
+}
+
+static BOARD: Mutex<Cell<BoardConfig>> =
+    Mutex::new(Cell::new(BoardConfig::for_revision(BoardRevision::Unknown)));
+
+/// Read the strap and select the board configuration. Call once at boot,
+/// before drivers that depend on `config()` are set up.
+pub fn detect(adc: &mut AdcArbiter) -> BoardConfig {
+    let mut sum = 0u16;
+    for _ in 0..STRAP_SAMPLES {
+        sum += adc.convert_blocking(STRAP_CHANNEL, AdcReference::Avcc);
+    }
+
+    let config = BoardConfig::for_revision(BoardRevision::from_strap(sum / STRAP_SAMPLES));
+    avr_device::interrupt::free(|cs| BOARD.borrow(cs).set(config));
+    config
+}
+
+/// Configuration selected by `detect`, rev A mapping before that
+pub fn config() -> BoardConfig {
+    avr_device::interrupt::free(|cs| BOARD.borrow(cs).get())
+}
+
+pub fn revision() -> BoardRevision {
+    config().revision
+}
@@ -0,0 +1,143 @@
+//! Hardware resource claims
+//!
+//! Drivers claim the timers, peripherals and pins they take over while
+//! initialising. A second claim of the same resource by a different owner
+//! is refused and kept as a conflict, which
+//! `Diagnostics::poll_claim_conflicts` reports as a `HardwareFault`
+//! naming both owners. Two drivers configuring the same timer otherwise
+//! just makes one of them misbehave, usually much later.
+//!
+//! Drivers ignore the result of `claim`, the conflict reaches diagnostics
+//! either way. Claims are bookkeeping only, nothing stops a driver from
+//! touching a resource it didn't claim.
+#![no_std]
+
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
+
+const MAX_CLAIMS: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum Port {
+    A = 0,
+    B = 1,
+    C = 2,
+    D = 3,
+    E = 4,
+    F = 5,
+    G = 6,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Resource {
+    Timer0,
+    Timer1,
+    Timer2,
+    Timer3,
+    Usart0,
+    Usart1,
+    Spi,
+    Twi,
+    Adc,
+    Pin(Port, u8),
+}
+
+impl Resource {
+    /// Compact id used in diagnostics subcodes, pins are `0x80 | port << 3 | pin`
+    pub fn id(&self) -> u8 {
+        match *self {
+            Resource::Timer0 => 0,
+            Resource::Timer1 => 1,
+            Resource::Timer2 => 2,
+            Resource::Timer3 => 3,
+            Resource::Usart0 => 4,
+            Resource::Usart1 => 5,
+            Resource::Spi => 6,
+            Resource::Twi => 7,
+            Resource::Adc => 8,
+            Resource::Pin(port, pin) => 0x80 | ((port as u8) << 3) | (pin & 0x07),
+        }
+    }
+}
+
+/// A claim refused because someone else holds the resource
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Conflict {
+    pub resource: Resource,
+    /// Current holder
+    pub owner: &'static str,
+    /// Driver whose claim was refused
+    pub claimant: &'static str,
+}
+
+#[derive(Clone, Copy)]
+struct Claim {
+    resource: Resource,
+    owner: &'static str,
+}
+
+struct Registry {
+    claims: [Option<Claim>; MAX_CLAIMS],
+    // First conflict not yet collected by diagnostics
+    conflict: Option<Conflict>,
+}
+
+static REGISTRY: Mutex<RefCell<Registry>> = Mutex::new(RefCell::new(Registry {
+    claims: [None; MAX_CLAIMS],
+    conflict: None,
+}));
+
+/// Claim `resource` for `owner`. Claiming again under the same owner is
+/// fine, e.g. a driver that is re-initialised.
+pub fn claim(resource: Resource, owner: &'static str) -> Result<(), Conflict> {
+    avr_device::interrupt::free(|cs| {
+        let mut registry = REGISTRY.borrow(cs).borrow_mut();
+
+        let held = registry.claims.iter().flatten().find(|c| c.resource == resource).copied();
+        if let Some(held) = held {
+            if held.owner == owner {
+                return Ok(());
+            }
+            let conflict = Conflict {
+                resource,
+                owner: held.owner,
+                claimant: owner,
+            };
+            registry.conflict.get_or_insert(conflict);
+            return Err(conflict);
+        }
+
+        match registry.claims.iter_mut().find(|c| c.is_none()) {
+            Some(slot) => *slot = Some(Claim { resource, owner }),
+            // Raise MAX_CLAIMS, running out hides conflicts
+            None => debug_assert!(false, "claim registry full"),
+        }
+        Ok(())
+    })
+}
+
+/// Give a resource back, ignored unless `owner` holds it
+pub fn release(resource: Resource, owner: &'static str) {
+    avr_device::interrupt::free(|cs| {
+        let mut registry = REGISTRY.borrow(cs).borrow_mut();
+        for slot in registry.claims.iter_mut() {
+            if matches!(slot, Some(c) if c.resource == resource && c.owner == owner) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+/// Current holder of a resource
+pub fn owner(resource: Resource) -> Option<&'static str> {
+    avr_device::interrupt::free(|cs| {
+        let registry = REGISTRY.borrow(cs).borrow();
+        registry.claims.iter().flatten().find(|c| c.resource == resource).map(|c| c.owner)
+    })
+}
+
+/// Oldest conflict since the last call
+pub fn take_conflict() -> Option<Conflict> {
+    avr_device::interrupt::free(|cs| REGISTRY.borrow(cs).borrow_mut().conflict.take())
+}
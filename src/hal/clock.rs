@@ -0,0 +1,177 @@
+//! Compile-time CPU clock and the timing constants derived from it
+//!
+//! `build.rs` exports `MCU_FREQ_HZ` (16MHz unless overridden in the build
+//! environment). Baud, TWI, ADC and timer settings are computed from it here
+//! so 8MHz or 14.7456MHz boards only need a different build variable.
+#![no_std]
+
+/// CPU frequency in Hz, from the `MCU_FREQ_HZ` build variable
+pub const CPU_FREQ: u32 = parse_hz(env!("MCU_FREQ_HZ"));
+
+/// Maximum ADC clock for full 10-bit resolution
+const ADC_MAX_CLOCK_HZ: u32 = 200_000;
+
+const fn parse_hz(s: &str) -> u32 {
+    let bytes = s.as_bytes();
+    let mut value = 0u32;
+    let mut i = 0;
+    while i < bytes.len() {
+        let digit = bytes[i];
+        assert!(digit >= b'0' && digit <= b'9', "MCU_FREQ_HZ must be a plain number");
+        value = value * 10 + (digit - b'0') as u32;
+        i += 1;
+    }
+    value
+}
+
+/// UBRR value for `baud` in normal (16x) mode, rounded to nearest
+pub const fn ubrr(baud: u32) -> u16 {
+    ((CPU_FREQ + 8 * baud) / (16 * baud) - 1) as u16
+}
+
+/// UBRR value for `baud` in double-speed (U2X, 8x) mode, rounded to nearest
+pub const fn ubrr_u2x(baud: u32) -> u16 {
+    ((CPU_FREQ + 4 * baud) / (8 * baud) - 1) as u16
+}
+
+/// Largest baud rate error accepted by `baud_setting`, in tenths of a
+/// percent. 115200 at 16MHz needs +2.1% (U2X).
+pub const MAX_BAUD_ERROR_PERMILLE: u32 = 25;
+
+// UBRR is 12 bits wide
+const UBRR_MAX: u32 = 4095;
+
+/// Baud rate error of a UBRR setting in tenths of a percent, `u32::MAX`
+/// if the setting can't be programmed
+pub const fn baud_error_permille(baud: u32, double_speed: bool) -> u32 {
+    let divisor = if double_speed { 8 } else { 16 };
+    if baud == 0 || CPU_FREQ < divisor * baud {
+        return u32::MAX;
+    }
+    let ubrr = if double_speed { ubrr_u2x(baud) } else { ubrr(baud) } as u32;
+    if ubrr > UBRR_MAX {
+        return u32::MAX;
+    }
+    let actual = CPU_FREQ / (divisor * (ubrr + 1));
+    let diff = if actual > baud { actual - baud } else { baud - actual };
+    diff * 1000 / baud
+}
+
+/// UBRR and U2X for `baud`: normal mode unless double speed is closer, and
+/// `None` if neither is within `MAX_BAUD_ERROR_PERMILLE`. Usable in const
+/// context to reject a baud rate at compile time.
+pub const fn baud_setting(baud: u32) -> Option<(u16, bool)> {
+    let normal = baud_error_permille(baud, false);
+    let double = baud_error_permille(baud, true);
+    if normal <= double && normal <= MAX_BAUD_ERROR_PERMILLE {
+        Some((ubrr(baud), false))
+    } else if double <= MAX_BAUD_ERROR_PERMILLE {
+        Some((ubrr_u2x(baud), true))
+    } else {
+        None
+    }
+}
+
+/// First of `candidates` that `baud_setting` accepts, the last one if none
+/// is (the UART then runs it with the nearest setting)
+pub const fn fastest_baud(candidates: &[u32]) -> u32 {
+    let mut i = 0;
+    while i + 1 < candidates.len() {
+        if baud_setting(candidates[i]).is_some() {
+            return candidates[i];
+        }
+        i += 1;
+    }
+    candidates[candidates.len() - 1]
+}
+
+/// TWBR value for an SCL frequency with the TWI prescaler at 1
+pub const fn twbr(scl_hz: u32) -> u8 {
+    ((CPU_FREQ / scl_hz - 16) / 2) as u8
+}
+
+// Smallest TWBR the datasheet allows in master mode
+const TWBR_MIN: u32 = 10;
+
+/// TWBR and TWPS for the fastest SCL frequency not above `scl_hz`,
+/// clamped to what the TWI can generate at this CPU clock
+pub const fn twi_setting(scl_hz: u32) -> (u8, u8) {
+    let hz = if scl_hz == 0 { 1 } else { scl_hz };
+    let cycles = CPU_FREQ.div_ceil(hz);
+    let mut twps: u32 = 0;
+    while twps < 4 {
+        let step = 2 * (1 << (2 * twps));
+        let twbr = if cycles > 16 { (cycles - 16).div_ceil(step) } else { 0 };
+        if twbr <= 255 {
+            let twbr = if twbr < TWBR_MIN { TWBR_MIN } else { twbr };
+            return (twbr as u8, twps as u8);
+        }
+        twps += 1;
+    }
+    (255, 3)
+}
+
+/// SCL frequency produced by a TWBR/TWPS pair
+pub const fn twi_frequency(twbr: u8, twps: u8) -> u32 {
+    CPU_FREQ / (16 + 2 * twbr as u32 * (1 << (2 * (twps as u32 & 0x03))))
+}
+
+/// ADPS bits for the fastest ADC clock that stays within spec
+pub const fn adc_prescaler_bits() -> u8 {
+    let mut bits = 1;
+    while bits < 7 && CPU_FREQ >> bits > ADC_MAX_CLOCK_HZ {
+        bits += 1;
+    }
+    bits
+}
+
+/// Compare value for a CTC timer firing at `hz` with the given prescaler
+pub const fn ctc_top(prescaler: u32, hz: u32) -> u32 {
+    CPU_FREQ / (prescaler * hz) - 1
+}
+
+/// Timer counts per millisecond at the given prescaler
+pub const fn ticks_per_ms(prescaler: u32) -> u32 {
+    CPU_FREQ / prescaler / 1000
+}
+
+/// CPU cycles per microsecond, truncated; for busy-wait loop counts only,
+/// use `us_to_counts` for timer values
+pub const fn cycles_per_us() -> u32 {
+    CPU_FREQ / 1_000_000
+}
+
+/// Timer counts in `us` microseconds at the given prescaler, rounded to
+/// nearest. Exact for clocks that aren't a whole number of MHz, where
+/// counts per microsecond is a fraction (0.92 at 7.3728MHz and clk/8).
+pub const fn us_to_counts(us: u32, prescaler: u32) -> u32 {
+    let div = prescaler as u64 * 1_000_000;
+    ((us as u64 * CPU_FREQ as u64 + div / 2) / div) as u32
+}
+
+/// Microseconds in `counts` timer counts at the given prescaler, rounded
+/// to nearest
+pub const fn counts_to_us(counts: u32, prescaler: u32) -> u32 {
+    let div = CPU_FREQ as u64;
+    ((counts as u64 * prescaler as u64 * 1_000_000 + div / 2) / div) as u32
+}
+
+/// Timer counts covering at least `ns` nanoseconds at the given
+/// prescaler, rounded up so a requested minimum is never undercut
+pub const fn ns_to_counts_ceil(ns: u32, prescaler: u32) -> u32 {
+    let div = prescaler as u64 * 1_000_000_000;
+    ((ns as u64 * CPU_FREQ as u64 + div - 1) / div) as u32
+}
+
+/// Nanoseconds in `counts` timer counts at the given prescaler, truncated
+pub const fn counts_to_ns(counts: u32, prescaler: u32) -> u32 {
+    (counts as u64 * prescaler as u64 * 1_000_000_000 / CPU_FREQ as u64) as u32
+}
+
+/// Microseconds per timer count at the given prescaler in 1/4096 (Q12),
+/// for converting captures in an interrupt without a 64-bit division:
+/// `us = counts * us_per_count_q12(8) >> 12` stays within u32 for 16-bit
+/// counts at any prescaler up to 8 and clocks down to 1MHz
+pub const fn us_per_count_q12(prescaler: u32) -> u32 {
+    ((prescaler as u64 * 1_000_000 << 12) / CPU_FREQ as u64) as u32
+}
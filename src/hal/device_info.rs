@@ -0,0 +1,145 @@
+//! Fuse, lock bit and signature information
+//!
+//! Fuse and lock bytes are read at runtime with `LPM` right after setting
+//! BLBSET|SPMEN in SPMCSR. The ATmega128 has no SIGRD bit, so its signature
+//! row is only reachable from a programmer; the signature reported here is
+//! the one the firmware was built for.
+//!
+//! `warnings()` flags fuse settings that don't fit this build, most usefully
+//! an internal RC oscillator running at a different frequency than the
+//! compiled `MCU_FREQ_HZ`.
+#![no_std]
+
+use crate::hal::clock::CPU_FREQ;
+
+/// ATmega128 signature bytes
+pub const SIGNATURE: [u8; 3] = [0x1E, 0x97, 0x02];
+
+// SPMCSR bits
+const SPMEN: u8 = 1 << 0;
+const BLBSET: u8 = 1 << 3;
+
+// Z pointer values selecting the byte read by LPM after BLBSET
+const Z_LOW_FUSE: u16 = 0x0000;
+const Z_LOCK_BITS: u16 = 0x0001;
+const Z_EXT_FUSE: u16 = 0x0002;
+const Z_HIGH_FUSE: u16 = 0x0003;
+
+// Fuse bits, programmed = 0
+const CKSEL_MASK: u8 = 0x0F;
+const HIGH_CKOPT: u8 = 1 << 4;
+const EXT_WDTON: u8 = 1 << 0;
+const EXT_M103C: u8 = 1 << 1;
+
+/// `warnings()` bits
+pub const WARN_CLOCK_MISMATCH: u8 = 1 << 0;
+/// ATmega103 compatibility mode is on (factory default), which remaps
+/// memory and disables peripherals this firmware uses
+pub const WARN_M103C: u8 = 1 << 1;
+/// Watchdog is forced on by fuse and can't be disabled in software
+pub const WARN_WDT_ALWAYS_ON: u8 = 1 << 2;
+/// Flash is not protected against readout
+pub const WARN_UNLOCKED: u8 = 1 << 3;
+
+/// Clock source selected by CKSEL
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ClockSource {
+    External,
+    /// Calibrated internal RC oscillator at the given frequency
+    InternalRc(u32),
+    ExternalRc,
+    LowFrequencyCrystal,
+    Crystal,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DeviceInfo {
+    pub signature: [u8; 3],
+    pub low_fuse: u8,
+    pub high_fuse: u8,
+    pub ext_fuse: u8,
+    pub lock_bits: u8,
+}
+
+impl DeviceInfo {
+    /// Read the fuse and lock bytes of the running device
+    pub fn read() -> Self {
+        Self {
+            signature: SIGNATURE,
+            low_fuse: read_fuse_byte(Z_LOW_FUSE),
+            high_fuse: read_fuse_byte(Z_HIGH_FUSE),
+            ext_fuse: read_fuse_byte(Z_EXT_FUSE),
+            lock_bits: read_fuse_byte(Z_LOCK_BITS),
+        }
+    }
+
+    pub fn clock_source(&self) -> ClockSource {
+        match self.low_fuse & CKSEL_MASK {
+            0x0 => ClockSource::External,
+            0x1 => ClockSource::InternalRc(1_000_000),
+            0x2 => ClockSource::InternalRc(2_000_000),
+            0x3 => ClockSource::InternalRc(4_000_000),
+            0x4 => ClockSource::InternalRc(8_000_000),
+            0x5..=0x8 => ClockSource::ExternalRc,
+            0x9 => ClockSource::LowFrequencyCrystal,
+            _ => ClockSource::Crystal,
+        }
+    }
+
+    /// CKOPT programmed: full-swing oscillator, needed above 8MHz
+    pub fn ckopt(&self) -> bool {
+        self.high_fuse & HIGH_CKOPT == 0
+    }
+
+    /// `WARN_*` bits for fuse settings that don't fit this build
+    pub fn warnings(&self) -> u8 {
+        let mut warnings = 0;
+
+        // Only the RC frequencies are known; crystals above 8MHz need CKOPT
+        let clock_ok = match self.clock_source() {
+            ClockSource::InternalRc(hz) => hz == CPU_FREQ,
+            ClockSource::Crystal => CPU_FREQ <= 8_000_000 || self.ckopt(),
+            ClockSource::LowFrequencyCrystal => CPU_FREQ == 32_768,
+            _ => true,
+        };
+        if !clock_ok {
+            warnings |= WARN_CLOCK_MISMATCH;
+        }
+        if self.ext_fuse & EXT_M103C == 0 {
+            warnings |= WARN_M103C;
+        }
+        if self.ext_fuse & EXT_WDTON == 0 {
+            warnings |= WARN_WDT_ALWAYS_ON;
+        }
+        // LB2:1 unprogrammed
+        if self.lock_bits & 0x03 == 0x03 {
+            warnings |= WARN_UNLOCKED;
+        }
+        warnings
+    }
+
+    /// Status payload: `[sig x3, low, high, ext, lock, warnings]`
+    pub fn encode(&self, out: &mut [u8; 8]) {
+        out[0..3].copy_from_slice(&self.signature);
+        out[3] = self.low_fuse;
+        out[4] = self.high_fuse;
+        out[5] = self.ext_fuse;
+        out[6] = self.lock_bits;
+        out[7] = self.warnings();
+    }
+}
+
+fn read_fuse_byte(z: u16) -> u8 {
+    // LPM has to follow the SPMCSR write within four cycles
+    avr_device::interrupt::free(|_| unsafe {
+        let value: u8;
+        core::arch::asm!(
+            "sts 0x68, {bits}",
+            "lpm {value}, Z",
+            bits = in(reg) BLBSET | SPMEN,
+            value = out(reg) value,
+            in("Z") z,
+        );
+        value
+    })
+}
@@ -0,0 +1,154 @@
+//! Internal EEPROM, 4KB
+//!
+//! Every access sets EEAR, so the register sequences run in critical
+//! sections and an interrupt handler reading the EEPROM cannot redirect a
+//! write half way. A write takes about 8.5ms; the next access waits for it
+//! with interrupts enabled and only the final check and the timed
+//! EEMWE/EEWE pair run with them disabled.
+//!
+//! The ATmega128 erases and programs a byte in one operation, so the only
+//! wear saving there is is not writing at all: `update_byte`,
+//! `update_block` and `write_obj` skip bytes that already hold the value.
+//! Allocations are the `EEPROM_*_ADDR` constants in `config`.
+//!
+//! `read_obj`/`write_obj` move small `Plain` structures in one call:
+//!
+//! ```ignore
+//! #[derive(Clone, Copy)]
+//! #[repr(C)]
+//! struct Offsets { x: i16, y: i16 }
+//! unsafe impl eeprom::Plain for Offsets {}
+//!
+//! eeprom::write_obj(EEPROM_OFFSETS_ADDR, &offsets)?;
+//! let offsets: Offsets = eeprom::read_obj(EEPROM_OFFSETS_ADDR)?;
+//! ```
+#![no_std]
+
+use avr_device::atmega128::EEPROM;
+use core::mem::{size_of, MaybeUninit};
+
+pub const EEPROM_SIZE: u16 = 4096;
+
+// EECR bits
+const EERE: u8 = 1 << 0;
+const EEWE: u8 = 1 << 1;
+const EEMWE: u8 = 1 << 2;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EepromError {
+    /// Access past the end of the EEPROM
+    OutOfRange,
+}
+
+/// Types that are valid for any bit pattern, so can be read back from
+/// whatever the EEPROM holds (all 0xFF when erased)
+///
+/// # Safety
+/// Implement only for `Copy` types without padding, references, `bool`,
+/// `char` or enums
+pub unsafe trait Plain: Copy {}
+
+macro_rules! impl_plain {
+    ($($t:ty),*) => {
+        $(unsafe impl Plain for $t {})*
+    };
+}
+
+impl_plain!(u8, u16, u32, u64, i8, i16, i32, i64, f32);
+
+unsafe impl<T: Plain, const N: usize> Plain for [T; N] {}
+
+#[inline]
+fn busy() -> bool {
+    unsafe { (*EEPROM::ptr()).eecr.read().bits() & EEWE != 0 }
+}
+
+pub fn read_byte(addr: u16) -> u8 {
+    loop {
+        while busy() {}
+        let data = avr_device::interrupt::free(|_| unsafe {
+            // EEAR can't change while a write started by a handler runs
+            if busy() {
+                return None;
+            }
+            let p = EEPROM::ptr();
+            (*p).eear.write(|w| w.bits(addr));
+            (*p).eecr.write(|w| w.bits(EERE));
+            Some((*p).eedr.read().bits())
+        });
+        if let Some(data) = data {
+            return data;
+        }
+    }
+}
+
+/// Program `data` at `addr`, even if it holds it already
+pub fn write_byte(addr: u16, data: u8) {
+    loop {
+        while busy() {}
+        let started = avr_device::interrupt::free(|_| unsafe {
+            // An interrupt handler may have started a write meanwhile
+            if busy() {
+                return false;
+            }
+            let p = EEPROM::ptr();
+            (*p).eear.write(|w| w.bits(addr));
+            (*p).eedr.write(|w| w.bits(data));
+            // EEMWE then EEWE within 4 cycles
+            (*p).eecr.write(|w| w.bits(EEMWE));
+            (*p).eecr.write(|w| w.bits(EEMWE | EEWE));
+            true
+        });
+        if started {
+            return;
+        }
+    }
+}
+
+/// Program `data` at `addr` unless it holds it already
+pub fn update_byte(addr: u16, data: u8) {
+    if read_byte(addr) != data {
+        write_byte(addr, data);
+    }
+}
+
+pub fn read_block(addr: u16, buffer: &mut [u8]) -> Result<(), EepromError> {
+    check_range(addr, buffer.len())?;
+    for (i, byte) in buffer.iter_mut().enumerate() {
+        *byte = read_byte(addr + i as u16);
+    }
+    Ok(())
+}
+
+/// `update_byte` for every byte of `data`
+pub fn update_block(addr: u16, data: &[u8]) -> Result<(), EepromError> {
+    check_range(addr, data.len())?;
+    for (i, &byte) in data.iter().enumerate() {
+        update_byte(addr + i as u16, byte);
+    }
+    Ok(())
+}
+
+pub fn read_obj<T: Plain>(addr: u16) -> Result<T, EepromError> {
+    check_range(addr, size_of::<T>())?;
+    let mut value = MaybeUninit::<T>::uninit();
+    let bytes = value.as_mut_ptr() as *mut u8;
+    for i in 0..size_of::<T>() {
+        unsafe { bytes.add(i).write(read_byte(addr + i as u16)) };
+    }
+    // Every byte written, and any bit pattern is a valid T
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Store `value`, only the bytes that changed are programmed
+pub fn write_obj<T: Plain>(addr: u16, value: &T) -> Result<(), EepromError> {
+    let bytes = unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    update_block(addr, bytes)
+}
+
+fn check_range(addr: u16, len: usize) -> Result<(), EepromError> {
+    if addr as usize + len > EEPROM_SIZE as usize {
+        return Err(EepromError::OutOfRange);
+    }
+    Ok(())
+}
@@ -0,0 +1,49 @@
+//! Seeds for the pseudo-random generator
+//!
+//! The lowest bit of an ADC conversion is mostly noise, but a quiet board
+//! on a stable input can return the same reading every time. A boot
+//! counter in EEPROM is mixed in as well, so two boots never start from the
+//! same seed even then.
+#![no_std]
+
+use crate::config::EEPROM_SEED_ADDR;
+use crate::hal::adc::{AdcArbiter, AdcChannel, AdcReference};
+use crate::hal::eeprom;
+
+// Conversions sampled, two LSBs each
+const SAMPLES: u8 = 32;
+
+/// Seed from ADC noise on `channel` (an unconnected or noisy input works
+/// best) and the boot counter, which this advances. The conversions go
+/// through the arbiter, see `AdcArbiter::convert_blocking`.
+pub fn hardware_seed(adc: &mut AdcArbiter, channel: AdcChannel) -> u32 {
+    let mut noise = 0u32;
+    for _ in 0..SAMPLES {
+        let sample = adc.convert_blocking(channel, AdcReference::Avcc);
+        noise = noise.rotate_left(2) ^ (sample as u32 & 0x03);
+    }
+    mix(noise ^ next_boot_count())
+}
+
+/// Boot counter from EEPROM, incremented and written back
+pub fn next_boot_count() -> u32 {
+    let mut bytes = [0u8; 4];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = eeprom::read_byte(EEPROM_SEED_ADDR + i as u16);
+    }
+    let count = u32::from_le_bytes(bytes).wrapping_add(1);
+    for (i, &byte) in count.to_le_bytes().iter().enumerate() {
+        eeprom::update_byte(EEPROM_SEED_ADDR + i as u16, byte);
+    }
+    count
+}
+
+// Finalizer from MurmurHash3, spreads a counter step over all bits
+fn mix(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x85EB_CA6B);
+    x ^= x >> 13;
+    x = x.wrapping_mul(0xC2B2_AE35);
+    x ^= x >> 16;
+    x
+}
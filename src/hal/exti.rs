@@ -0,0 +1,226 @@
+//! External interrupts INT0..INT7
+//!
+//! INT0..INT3 are on PD0..PD3 (shared with the TWI and USART1 pins),
+//! INT4..INT7 on PE4..PE7. Drivers attach a handler and trigger to a line;
+//! the handler runs in the interrupt with interrupts disabled, so it only
+//! records what happened.
+//!
+//! INT0..INT3 detect edges asynchronously and wake the CPU from every
+//! sleep mode on any trigger. INT4..INT7 need the I/O clock for edges, so
+//! only their low level wakes from Power-down and Power-save; see
+//! `wakes_from_power_down`. A level has to be held until the CPU is awake
+//! (start-up time, up to 65ms with the slowest fuse setting) or the wake-up
+//! happens without the interrupt running.
+#![no_std]
+
+use avr_device::atmega128::EXINT;
+use avr_device::interrupt::Mutex;
+use core::cell::Cell;
+
+use crate::hal::gpio::{DynPin, Port};
+
+/// Runs inside the interrupt
+pub type ExtiHandler = fn();
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum ExtInt {
+    Int0 = 0,
+    Int1 = 1,
+    Int2 = 2,
+    Int3 = 3,
+    Int4 = 4,
+    Int5 = 5,
+    Int6 = 6,
+    Int7 = 7,
+}
+
+impl ExtInt {
+    fn bit(self) -> u8 {
+        self as u8
+    }
+
+    /// INT4..INT7 on port E, the rest on port D
+    fn on_port_e(self) -> bool {
+        self.bit() >= 4
+    }
+
+    /// Port and bit of the line's pin
+    pub fn pin(self) -> (Port, u8) {
+        if self.on_port_e() {
+            (Port::E, self.bit())
+        } else {
+            (Port::D, self.bit())
+        }
+    }
+}
+
+/// ISCn1:ISCn0 in EICRA/EICRB
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum Trigger {
+    /// Interrupts repeatedly while the pin is held low
+    LowLevel = 0b00,
+    /// INT4..INT7 only, reserved on INT0..INT3
+    AnyEdge = 0b01,
+    Falling = 0b10,
+    Rising = 0b11,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExtiError {
+    /// `AnyEdge` on INT0..INT3
+    UnsupportedTrigger,
+    /// Another handler is attached, `detach` it first
+    InUse,
+}
+
+const NO_HANDLER: Mutex<Cell<Option<ExtiHandler>>> = Mutex::new(Cell::new(None));
+static HANDLERS: [Mutex<Cell<Option<ExtiHandler>>>; 8] = [NO_HANDLER; 8];
+
+/// Make the line's pin an input, set the trigger and enable the interrupt
+/// with `handler`
+pub fn attach(line: ExtInt, trigger: Trigger, pull_up: bool, handler: ExtiHandler) -> Result<(), ExtiError> {
+    if trigger == Trigger::AnyEdge && !line.on_port_e() {
+        return Err(ExtiError::UnsupportedTrigger);
+    }
+    avr_device::interrupt::free(|cs| {
+        let slot = HANDLERS[line as usize].borrow(cs);
+        match slot.get() {
+            Some(current) if current as usize != handler as usize => return Err(ExtiError::InUse),
+            _ => slot.set(Some(handler)),
+        }
+
+        configure_pin(line, pull_up);
+        write_trigger(line, trigger);
+        unsafe {
+            let p = EXINT::ptr();
+            // Changing the sense bits can raise a spurious flag
+            (*p).eifr.write(|w| w.bits(1 << line.bit()));
+            (*p).eimsk.modify(|r, w| w.bits(r.bits() | (1 << line.bit())));
+        }
+        Ok(())
+    })
+}
+
+/// Disable the interrupt and drop its handler, the pin is left as it is
+pub fn detach(line: ExtInt) {
+    avr_device::interrupt::free(|cs| {
+        disable(line);
+        HANDLERS[line as usize].borrow(cs).set(None);
+    });
+}
+
+pub fn enable(line: ExtInt) {
+    unsafe {
+        let p = EXINT::ptr();
+        (*p).eifr.write(|w| w.bits(1 << line.bit()));
+        (*p).eimsk.modify(|r, w| w.bits(r.bits() | (1 << line.bit())));
+    }
+}
+
+pub fn disable(line: ExtInt) {
+    unsafe {
+        (*EXINT::ptr()).eimsk.modify(|r, w| w.bits(r.bits() & !(1 << line.bit())));
+    }
+}
+
+pub fn is_enabled(line: ExtInt) -> bool {
+    unsafe { (*EXINT::ptr()).eimsk.read().bits() & (1 << line.bit()) != 0 }
+}
+
+/// Change the trigger of an attached line
+pub fn set_trigger(line: ExtInt, trigger: Trigger) -> Result<(), ExtiError> {
+    if trigger == Trigger::AnyEdge && !line.on_port_e() {
+        return Err(ExtiError::UnsupportedTrigger);
+    }
+    avr_device::interrupt::free(|_| {
+        let enabled = is_enabled(line);
+        // The datasheet asks for the interrupt to be off while ISC changes
+        disable(line);
+        write_trigger(line, trigger);
+        if enabled {
+            enable(line);
+        }
+    });
+    Ok(())
+}
+
+/// Whether `trigger` on `line` can bring the CPU out of Power-down or
+/// Power-save
+pub fn wakes_from_power_down(line: ExtInt, trigger: Trigger) -> bool {
+    !line.on_port_e() || trigger == Trigger::LowLevel
+}
+
+fn write_trigger(line: ExtInt, trigger: Trigger) {
+    let shift = (line.bit() & 0x03) * 2;
+    let value = |r: u8| (r & !(0x03 << shift)) | ((trigger as u8) << shift);
+    unsafe {
+        let p = EXINT::ptr();
+        if line.on_port_e() {
+            (*p).eicrb.modify(|r, w| w.bits(value(r.bits())));
+        } else {
+            (*p).eicra.modify(|r, w| w.bits(value(r.bits())));
+        }
+    }
+}
+
+fn configure_pin(line: ExtInt, pull_up: bool) {
+    let (port, bit) = line.pin();
+    let mut pin = DynPin::new(port, bit);
+    if pull_up {
+        pin.into_pull_up_input();
+    } else {
+        pin.into_input();
+    }
+}
+
+#[inline(always)]
+fn dispatch(line: ExtInt) {
+    let handler = avr_device::interrupt::free(|cs| HANDLERS[line as usize].borrow(cs).get());
+    match handler {
+        Some(handler) => handler(),
+        // Nobody to clear a level trigger, it would never stop firing
+        None => disable(line),
+    }
+}
+
+#[avr_device::interrupt(atmega128)]
+fn INT0() {
+    dispatch(ExtInt::Int0);
+}
+
+#[avr_device::interrupt(atmega128)]
+fn INT1() {
+    dispatch(ExtInt::Int1);
+}
+
+#[avr_device::interrupt(atmega128)]
+fn INT2() {
+    dispatch(ExtInt::Int2);
+}
+
+#[avr_device::interrupt(atmega128)]
+fn INT3() {
+    dispatch(ExtInt::Int3);
+}
+
+#[avr_device::interrupt(atmega128)]
+fn INT4() {
+    dispatch(ExtInt::Int4);
+}
+
+#[avr_device::interrupt(atmega128)]
+fn INT5() {
+    dispatch(ExtInt::Int5);
+}
+
+#[avr_device::interrupt(atmega128)]
+fn INT6() {
+    dispatch(ExtInt::Int6);
+}
+
+#[avr_device::interrupt(atmega128)]
+fn INT7() {
+    dispatch(ExtInt::Int7);
+}
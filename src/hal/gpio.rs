@@ -1,11 +1,38 @@
-use avr_device::atmega128::{PORTA, PORTB, PORTC, PORTD, PORTE, PORTF};
+use avr_device::atmega128::{PORTA, PORTB, PORTC, PORTD, PORTE, PORTF, PORTG};
 use core::marker::PhantomData;
 
-pub trait PinMode {}
+pub use crate::hal::claims::Port;
+
+pub trait PinMode {
+    /// Mode of the pin as a `DynPin`
+    const DYN: DynMode;
+}
+/// Modes the pin level can be read in
+pub trait InputMode: PinMode {}
+
+/// Floating input
 pub struct Input;
+/// Input with the internal pull-up
+pub struct PullUpInput;
 pub struct Output;
-impl PinMode for Input {}
-impl PinMode for Output {}
+/// Open-drain emulated with the direction bit: low drives, high releases
+/// the line to an external pull-up
+pub struct OpenDrain;
+impl PinMode for Input {
+    const DYN: DynMode = DynMode::Input;
+}
+impl PinMode for PullUpInput {
+    const DYN: DynMode = DynMode::PullUpInput;
+}
+impl PinMode for Output {
+    const DYN: DynMode = DynMode::Output;
+}
+impl PinMode for OpenDrain {
+    const DYN: DynMode = DynMode::OpenDrain;
+}
+impl InputMode for Input {}
+impl InputMode for PullUpInput {}
+impl InputMode for OpenDrain {}
 
 #[derive(Debug)]
 pub struct Pin<PORT, const PIN: u8, MODE> {
@@ -14,7 +41,28 @@ pub struct Pin<PORT, const PIN: u8, MODE> {
 }
 
 macro_rules! impl_port {
-    ($PORT:ident, $port:ident) => {
+    ($PORT:ident, $port:ident, $dyn_port:ident) => {
+        impl<const P: u8, MODE: PinMode> From<Pin<$PORT, P, MODE>> for DynPin {
+            fn from(_: Pin<$PORT, P, MODE>) -> Self {
+                DynPin {
+                    port: Port::$dyn_port,
+                    bit: P,
+                    mode: MODE::DYN,
+                }
+            }
+        }
+
+        impl<const P: u8> Pin<$PORT, P, Input> {
+            /// Claim a pin in its reset state (input, no pull-up). The
+            /// caller makes sure no other handle drives the same pin.
+            pub const fn new() -> Self {
+                Pin {
+                    _port: PhantomData,
+                    _mode: PhantomData,
+                }
+            }
+        }
+
         impl<const P: u8, MODE: PinMode> Pin<$PORT, P, MODE> {
             pub fn into_output(self) -> Pin<$PORT, P, Output> {
                 // Set DDRx bit
@@ -27,7 +75,12 @@ macro_rules! impl_port {
                 }
             }
 
+            /// Floating input, same as `into_floating_input`
             pub fn into_input(self) -> Pin<$PORT, P, Input> {
+                self.into_floating_input()
+            }
+
+            pub fn into_floating_input(self) -> Pin<$PORT, P, Input> {
                 // Clear DDRx bit and disable pull-up
                 unsafe {
                     (*$PORT::ptr()).$port.ddr.modify(|r, w| w.bits(r.bits() & !(1 << P)));
@@ -38,17 +91,43 @@ macro_rules! impl_port {
                     _mode: PhantomData,
                 }
             }
+
+            pub fn into_pull_up_input(self) -> Pin<$PORT, P, PullUpInput> {
+                // Clear DDRx bit and enable pull-up
+                unsafe {
+                    (*$PORT::ptr()).$port.ddr.modify(|r, w| w.bits(r.bits() & !(1 << P)));
+                    (*$PORT::ptr()).$port.port.modify(|r, w| w.bits(r.bits() | (1 << P)));
+                }
+                Pin {
+                    _port: PhantomData,
+                    _mode: PhantomData,
+                }
+            }
+
+            /// Open-drain, starts released. The line needs an external
+            /// pull-up, the internal one can't stay on while PORTx is low.
+            pub fn into_open_drain(self) -> Pin<$PORT, P, OpenDrain> {
+                // Release first so an output that was high doesn't glitch low
+                unsafe {
+                    (*$PORT::ptr()).$port.ddr.modify(|r, w| w.bits(r.bits() & !(1 << P)));
+                    (*$PORT::ptr()).$port.port.modify(|r, w| w.bits(r.bits() & !(1 << P)));
+                }
+                Pin {
+                    _port: PhantomData,
+                    _mode: PhantomData,
+                }
+            }
         }
     };
 }
 
 // Implement for all ATmega128 ports
-impl_port!(PORTA, porta);
-impl_port!(PORTB, portb);
-impl_port!(PORTC, portc);
-impl_port!(PORTD, portd);
-impl_port!(PORTE, porte);
-impl_port!(PORTF, portf);
+impl_port!(PORTA, porta, A);
+impl_port!(PORTB, portb, B);
+impl_port!(PORTC, portc, C);
+impl_port!(PORTD, portd, D);
+impl_port!(PORTE, porte, E);
+impl_port!(PORTF, portf, F);
 
 // Output pin implementation
 impl<PORT, const P: u8> Pin<PORT, P, Output> {
@@ -74,8 +153,35 @@ impl<PORT, const P: u8> Pin<PORT, P, Output> {
     }
 }
 
-// Input pin implementation
-impl<PORT, const P: u8> Pin<PORT, P, Input> {
+// Open-drain implementation, PORTx stays low and DDRx switches
+impl<PORT, const P: u8> Pin<PORT, P, OpenDrain> {
+    /// Pull the line low
+    #[inline]
+    pub fn set_low(&mut self) where Self: PinOps {
+        unsafe {
+            self.port_ptr().ddr.modify(|r, w| w.bits(r.bits() | (1 << P)));
+        }
+    }
+
+    /// Release the line, it reads high unless another device holds it low
+    #[inline]
+    pub fn set_high(&mut self) where Self: PinOps {
+        unsafe {
+            self.port_ptr().ddr.modify(|r, w| w.bits(r.bits() & !(1 << P)));
+        }
+    }
+
+    /// Whether this pin is pulling the line low
+    #[inline]
+    pub fn is_set_low(&self) -> bool where Self: PinOps {
+        unsafe {
+            (self.port_ptr().ddr.read().bits() & (1 << P)) != 0
+        }
+    }
+}
+
+// Input pin implementation, open-drain pins read the line
+impl<PORT, const P: u8, MODE: InputMode> Pin<PORT, P, MODE> {
     #[inline]
     pub fn is_high(&self) -> bool where Self: PinOps {
         unsafe {
@@ -90,7 +196,7 @@ impl<PORT, const P: u8> Pin<PORT, P, Input> {
 }
 
 // Internal trait for port operations
-trait PinOps {
+pub(crate) trait PinOps {
     type PORT;
     fn port_ptr(&self) -> &Self::PORT;
 }
@@ -115,6 +221,281 @@ impl_pin_ops!(PORTD);
 impl_pin_ops!(PORTE);
 impl_pin_ops!(PORTF);
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DynMode {
+    Input,
+    PullUpInput,
+    Output,
+    OpenDrain,
+}
+
+/// Pin picked at runtime, for arrays of mixed pins and pins that come from
+/// configuration. Same registers as the typed pins, one match per access
+/// more. Port G (5 pins) is only reachable this way.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DynPin {
+    port: Port,
+    bit: u8,
+    mode: DynMode,
+}
+
+impl DynPin {
+    /// Claim a pin in its reset state (input, no pull-up) without touching
+    /// the registers. The caller makes sure no other handle drives it.
+    pub const fn new(port: Port, bit: u8) -> Self {
+        Self {
+            port,
+            bit: bit & 0x07,
+            mode: DynMode::Input,
+        }
+    }
+
+    pub fn port(&self) -> Port {
+        self.port
+    }
+
+    pub fn bit(&self) -> u8 {
+        self.bit
+    }
+
+    pub fn mode(&self) -> DynMode {
+        self.mode
+    }
+
+    pub fn into_output(&mut self) {
+        self.modify_ddr(true);
+        self.mode = DynMode::Output;
+    }
+
+    pub fn into_input(&mut self) {
+        self.modify_ddr(false);
+        self.modify_port(false);
+        self.mode = DynMode::Input;
+    }
+
+    pub fn into_floating_input(&mut self) {
+        self.into_input();
+    }
+
+    pub fn into_pull_up_input(&mut self) {
+        self.modify_ddr(false);
+        self.modify_port(true);
+        self.mode = DynMode::PullUpInput;
+    }
+
+    /// Open-drain, starts released, see `OpenDrain`
+    pub fn into_open_drain(&mut self) {
+        self.modify_ddr(false);
+        self.modify_port(false);
+        self.mode = DynMode::OpenDrain;
+    }
+
+    /// Drive high, on an input this enables the pull-up instead and on an
+    /// open-drain pin it releases the line
+    pub fn set_high(&mut self) {
+        if self.mode == DynMode::OpenDrain {
+            self.modify_ddr(false);
+        } else {
+            self.modify_port(true);
+        }
+    }
+
+    /// Drive low, on an input this disables the pull-up instead
+    pub fn set_low(&mut self) {
+        if self.mode == DynMode::OpenDrain {
+            self.modify_ddr(true);
+        } else {
+            self.modify_port(false);
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        let mask = 1 << self.bit;
+        if self.mode == DynMode::OpenDrain {
+            update_ddr(self.port, |r| r ^ mask);
+        } else {
+            // The ATmega128 can't toggle through PINx, read-modify-write PORTx
+            update_port(self.port, |r| r ^ mask);
+        }
+    }
+
+    /// Pin level, also readable in output mode
+    pub fn is_high(&self) -> bool {
+        read_pins(self.port) & (1 << self.bit) != 0
+    }
+
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+
+    /// Whether an open-drain pin pulls the line low
+    pub fn is_set_low(&self) -> bool {
+        self.mode == DynMode::OpenDrain && read_ddr(self.port) & (1 << self.bit) != 0
+    }
+
+    fn modify_ddr(&self, set: bool) {
+        let mask = 1 << self.bit;
+        update_ddr(self.port, |r| if set { r | mask } else { r & !mask });
+    }
+
+    fn modify_port(&self, set: bool) {
+        let mask = 1 << self.bit;
+        update_port(self.port, |r| if set { r | mask } else { r & !mask });
+    }
+}
+
+/// Pins of one port read and written together, for parallel buses (an
+/// HD44780 data bus, 7-segment digits, the LED bar). `mask` selects the
+/// pins; the others keep their state, so two buses can share a port.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PortBus {
+    port: Port,
+    mask: u8,
+}
+
+impl PortBus {
+    /// The `mask` pins of `port`, registers untouched. The caller makes
+    /// sure no other handle drives them.
+    pub const fn new(port: Port, mask: u8) -> Self {
+        // Port G has 5 pins
+        let mask = match port {
+            Port::G => mask & 0x1F,
+            _ => mask,
+        };
+        Self { port, mask }
+    }
+
+    /// All pins of `port`
+    pub const fn full(port: Port) -> Self {
+        Self::new(port, 0xFF)
+    }
+
+    pub fn port(&self) -> Port {
+        self.port
+    }
+
+    pub fn mask(&self) -> u8 {
+        self.mask
+    }
+
+    pub fn into_output(&mut self) {
+        let mask = self.mask;
+        update_ddr(self.port, |r| r | mask);
+    }
+
+    pub fn into_input(&mut self, pull_up: bool) {
+        let mask = self.mask;
+        update_ddr(self.port, |r| r & !mask);
+        update_port(self.port, |r| if pull_up { r | mask } else { r & !mask });
+    }
+
+    /// Drive the bus pins to the matching bits of `value` in one register
+    /// write
+    pub fn write(&mut self, value: u8) {
+        let mask = self.mask;
+        update_port(self.port, |r| (r & !mask) | (value & mask));
+    }
+
+    /// Drive the bus pins set in `bits` high
+    pub fn set_bits(&mut self, bits: u8) {
+        let bits = bits & self.mask;
+        update_port(self.port, |r| r | bits);
+    }
+
+    /// Drive the bus pins set in `bits` low
+    pub fn clear_bits(&mut self, bits: u8) {
+        let bits = bits & self.mask;
+        update_port(self.port, |r| r & !bits);
+    }
+
+    pub fn toggle(&mut self, bits: u8) {
+        let bits = bits & self.mask;
+        update_port(self.port, |r| r ^ bits);
+    }
+
+    /// Levels of the bus pins, the other bits read 0
+    pub fn read(&self) -> u8 {
+        read_pins(self.port) & self.mask
+    }
+
+    /// What the bus drives, the other bits read 0
+    pub fn output(&self) -> u8 {
+        read_port(self.port) & self.mask
+    }
+}
+
+// Read-modify-write with interrupts off, so an ISR writing other pins of
+// the same port can't be undone
+fn update_ddr(port: Port, f: impl Fn(u8) -> u8) {
+    avr_device::interrupt::free(|_| unsafe {
+        match port {
+            Port::A => (*PORTA::ptr()).ddra.modify(|r, w| w.bits(f(r.bits()))),
+            Port::B => (*PORTB::ptr()).ddrb.modify(|r, w| w.bits(f(r.bits()))),
+            Port::C => (*PORTC::ptr()).ddrc.modify(|r, w| w.bits(f(r.bits()))),
+            Port::D => (*PORTD::ptr()).ddrd.modify(|r, w| w.bits(f(r.bits()))),
+            Port::E => (*PORTE::ptr()).ddre.modify(|r, w| w.bits(f(r.bits()))),
+            Port::F => (*PORTF::ptr()).ddrf.modify(|r, w| w.bits(f(r.bits()))),
+            Port::G => (*PORTG::ptr()).ddrg.modify(|r, w| w.bits(f(r.bits()))),
+        }
+    });
+}
+
+fn update_port(port: Port, f: impl Fn(u8) -> u8) {
+    avr_device::interrupt::free(|_| unsafe {
+        match port {
+            Port::A => (*PORTA::ptr()).porta.modify(|r, w| w.bits(f(r.bits()))),
+            Port::B => (*PORTB::ptr()).portb.modify(|r, w| w.bits(f(r.bits()))),
+            Port::C => (*PORTC::ptr()).portc.modify(|r, w| w.bits(f(r.bits()))),
+            Port::D => (*PORTD::ptr()).portd.modify(|r, w| w.bits(f(r.bits()))),
+            Port::E => (*PORTE::ptr()).porte.modify(|r, w| w.bits(f(r.bits()))),
+            Port::F => (*PORTF::ptr()).portf.modify(|r, w| w.bits(f(r.bits()))),
+            Port::G => (*PORTG::ptr()).portg.modify(|r, w| w.bits(f(r.bits()))),
+        }
+    });
+}
+
+fn read_pins(port: Port) -> u8 {
+    unsafe {
+        match port {
+            Port::A => (*PORTA::ptr()).pina.read().bits(),
+            Port::B => (*PORTB::ptr()).pinb.read().bits(),
+            Port::C => (*PORTC::ptr()).pinc.read().bits(),
+            Port::D => (*PORTD::ptr()).pind.read().bits(),
+            Port::E => (*PORTE::ptr()).pine.read().bits(),
+            Port::F => (*PORTF::ptr()).pinf.read().bits(),
+            Port::G => (*PORTG::ptr()).ping.read().bits(),
+        }
+    }
+}
+
+fn read_ddr(port: Port) -> u8 {
+    unsafe {
+        match port {
+            Port::A => (*PORTA::ptr()).ddra.read().bits(),
+            Port::B => (*PORTB::ptr()).ddrb.read().bits(),
+            Port::C => (*PORTC::ptr()).ddrc.read().bits(),
+            Port::D => (*PORTD::ptr()).ddrd.read().bits(),
+            Port::E => (*PORTE::ptr()).ddre.read().bits(),
+            Port::F => (*PORTF::ptr()).ddrf.read().bits(),
+            Port::G => (*PORTG::ptr()).ddrg.read().bits(),
+        }
+    }
+}
+
+fn read_port(port: Port) -> u8 {
+    unsafe {
+        match port {
+            Port::A => (*PORTA::ptr()).porta.read().bits(),
+            Port::B => (*PORTB::ptr()).portb.read().bits(),
+            Port::C => (*PORTC::ptr()).portc.read().bits(),
+            Port::D => (*PORTD::ptr()).portd.read().bits(),
+            Port::E => (*PORTE::ptr()).porte.read().bits(),
+            Port::F => (*PORTF::ptr()).portf.read().bits(),
+            Port::G => (*PORTG::ptr()).portg.read().bits(),
+        }
+    }
+}
+
 // BigAVR2 board-specific pin definitions
 pub mod board {
     use super::*;
@@ -124,6 +505,13 @@ pub mod board {
     pub type LED1 = Pin<PORTA, 1, Output>;
     pub type LED2 = Pin<PORTA, 2, Output>;
     pub type LED3 = Pin<PORTA, 3, Output>;
+
+    /// LED0..LED3 as one bus, bit n is LEDn
+    pub fn led_bar() -> PortBus {
+        let mut bus = PortBus::new(Port::A, 0x0F);
+        bus.into_output();
+        bus
+    }
     
     // Button definitions (PORTB)
     pub type BTN0 = Pin<PORTB, 0, Input>;
@@ -131,5 +519,15 @@ pub mod board {
     pub type BTN2 = Pin<PORTB, 2, Input>;
     pub type BTN3 = Pin<PORTB, 3, Input>;
     
+    // External SPI flash chip select on the SS pin (PORTB)
+    pub type FLASH_CS = Pin<PORTB, 0, Output>;
+
+    /// Flash chip select, driven high (deselected)
+    pub fn flash_cs() -> FLASH_CS {
+        let mut cs = Pin::<PORTB, 0, Input>::new().into_output();
+        cs.set_high();
+        cs
+    }
+
     // TODO: Add more board-specific pins (UART, SPI, etc)
 } 
\ No newline at end of file
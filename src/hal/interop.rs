@@ -0,0 +1,270 @@
+//! embedded-hal 0.2 trait implementations
+//!
+//! Lets sensor drivers written against the embedded-hal traits (the ones
+//! avr-hal and most crates.io drivers use) run on this HAL unchanged:
+//! `Uart` implements the serial traits, `Twi` the blocking I2C traits, `Spi`
+//! the blocking SPI traits, GPIO pins the digital v2 traits and `Delay` the
+//! delay traits.
+//!
+//! `SharedBus` splits one bus between several such drivers. Each driver gets
+//! a `BusProxy` that borrows the bus for the length of one call, like the
+//! `shared-bus` crate's single-context manager. Proxies are for the main
+//! loop only; a driver used from an interrupt needs its own bus.
+#![no_std]
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use embedded_hal::blocking::{delay, i2c, spi};
+use embedded_hal::digital::v2::{InputPin, OutputPin, ToggleableOutputPin};
+use embedded_hal::serial;
+
+use crate::hal::gpio::{DynPin, InputMode, OpenDrain, Output, Pin, PinOps};
+use crate::hal::spi::Spi;
+use crate::hal::timer::delay_ms;
+use crate::hal::twi::{Twi, TwiError};
+use crate::hal::uart::{Uart, UartError, UartRegisterBlock};
+
+impl<USART: UartRegisterBlock> serial::Read<u8> for Uart<USART> {
+    type Error = UartError;
+
+    fn read(&mut self) -> nb::Result<u8, UartError> {
+        self.read_byte().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl<USART: UartRegisterBlock> serial::Write<u8> for Uart<USART> {
+    type Error = UartError;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), UartError> {
+        self.try_write_byte(word).map_err(|_| nb::Error::WouldBlock)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), UartError> {
+        Uart::flush(self, 0).map_err(|_| nb::Error::WouldBlock)
+    }
+}
+
+impl i2c::Read for Twi {
+    type Error = TwiError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), TwiError> {
+        self.write_then_read(address, &[], buffer)
+    }
+}
+
+impl i2c::Write for Twi {
+    type Error = TwiError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), TwiError> {
+        self.write_then_read(address, bytes, &mut [])
+    }
+}
+
+impl i2c::WriteRead for Twi {
+    type Error = TwiError;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), TwiError> {
+        self.write_then_read(address, bytes, buffer)
+    }
+}
+
+impl spi::Transfer<u8> for Spi {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+        for word in words.iter_mut() {
+            *word = Spi::transfer(self, *word);
+        }
+        Ok(words)
+    }
+}
+
+impl spi::Write<u8> for Spi {
+    type Error = Infallible;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+        for &word in words {
+            Spi::transfer(self, word);
+        }
+        Ok(())
+    }
+}
+
+impl<PORT, const P: u8> OutputPin for Pin<PORT, P, Output>
+where
+    Self: PinOps,
+{
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        Pin::set_low(self);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Pin::set_high(self);
+        Ok(())
+    }
+}
+
+impl<PORT, const P: u8> ToggleableOutputPin for Pin<PORT, P, Output>
+where
+    Self: PinOps,
+{
+    type Error = Infallible;
+
+    fn toggle(&mut self) -> Result<(), Infallible> {
+        Pin::toggle(self);
+        Ok(())
+    }
+}
+
+impl<PORT, const P: u8, MODE: InputMode> InputPin for Pin<PORT, P, MODE>
+where
+    Self: PinOps,
+{
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(Pin::is_high(self))
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(Pin::is_low(self))
+    }
+}
+
+impl<PORT, const P: u8> OutputPin for Pin<PORT, P, OpenDrain>
+where
+    Self: PinOps,
+{
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        Pin::set_low(self);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Pin::set_high(self);
+        Ok(())
+    }
+}
+
+impl OutputPin for DynPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        DynPin::set_low(self);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        DynPin::set_high(self);
+        Ok(())
+    }
+}
+
+impl ToggleableOutputPin for DynPin {
+    type Error = Infallible;
+
+    fn toggle(&mut self) -> Result<(), Infallible> {
+        DynPin::toggle(self);
+        Ok(())
+    }
+}
+
+impl InputPin for DynPin {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(DynPin::is_high(self))
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(DynPin::is_low(self))
+    }
+}
+
+/// Busy-wait delay on Timer0, see `timer::delay_ms`
+pub struct Delay;
+
+impl delay::DelayMs<u16> for Delay {
+    fn delay_ms(&mut self, ms: u16) {
+        delay_ms(ms);
+    }
+}
+
+impl delay::DelayMs<u8> for Delay {
+    fn delay_ms(&mut self, ms: u8) {
+        delay_ms(ms as u16);
+    }
+}
+
+/// One bus shared by several drivers
+pub struct SharedBus<BUS> {
+    bus: RefCell<BUS>,
+}
+
+impl<BUS> SharedBus<BUS> {
+    pub const fn new(bus: BUS) -> Self {
+        Self {
+            bus: RefCell::new(bus),
+        }
+    }
+
+    /// Handle for one driver, implementing the same bus traits as `BUS`
+    pub fn acquire(&self) -> BusProxy<'_, BUS> {
+        BusProxy { bus: &self.bus }
+    }
+
+    /// Give the bus back once every proxy is gone
+    pub fn into_inner(self) -> BUS {
+        self.bus.into_inner()
+    }
+}
+
+pub struct BusProxy<'a, BUS> {
+    bus: &'a RefCell<BUS>,
+}
+
+impl<BUS: i2c::Read> i2c::Read for BusProxy<'_, BUS> {
+    type Error = BUS::Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), BUS::Error> {
+        self.bus.borrow_mut().read(address, buffer)
+    }
+}
+
+impl<BUS: i2c::Write> i2c::Write for BusProxy<'_, BUS> {
+    type Error = BUS::Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), BUS::Error> {
+        self.bus.borrow_mut().write(address, bytes)
+    }
+}
+
+impl<BUS: i2c::WriteRead> i2c::WriteRead for BusProxy<'_, BUS> {
+    type Error = BUS::Error;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), BUS::Error> {
+        self.bus.borrow_mut().write_read(address, bytes, buffer)
+    }
+}
+
+impl<BUS: spi::Transfer<u8>> spi::Transfer<u8> for BusProxy<'_, BUS> {
+    type Error = BUS::Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], BUS::Error> {
+        self.bus.borrow_mut().transfer(words)
+    }
+}
+
+impl<BUS: spi::Write<u8>> spi::Write<u8> for BusProxy<'_, BUS> {
+    type Error = BUS::Error;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), BUS::Error> {
+        self.bus.borrow_mut().write(words)
+    }
+}
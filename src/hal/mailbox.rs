@@ -0,0 +1,163 @@
+//! Boot mailbox shared between bootloader and application
+//!
+//! A few bytes of RAM that the C runtime never clears, so they survive a
+//! watchdog or software reset (but not a power cycle). The bootloader leaves
+//! the result of an update there before jumping to the application; the
+//! application leaves the reason it is asking for the bootloader. A magic
+//! word and CRC tell a real message from the random contents after power-on.
+//!
+//! Both images must link `.noinit` at the same address: `bootloader.ld`
+//! places it at the start of SRAM and `build.rs` does the same for the
+//! application, moving `.data` up by `MAILBOX_RESERVED` bytes.
+#![no_std]
+
+use core::mem::{size_of, MaybeUninit};
+use core::ptr::{addr_of, addr_of_mut};
+
+const MAILBOX_MAGIC: u16 = 0xB0A7;
+/// Bytes kept free for the mailbox at the start of SRAM
+pub const MAILBOX_RESERVED: usize = 16;
+
+/// Why the bootloader was entered
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum BootReason {
+    /// No valid mailbox, e.g. after power-on
+    ColdStart = 0,
+    /// The application asked for a firmware update
+    UpdateRequest = 1,
+    /// The application found its image or settings unusable
+    AppFault = 2,
+    /// The application was reset by the watchdog
+    Watchdog = 3,
+}
+
+impl BootReason {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(BootReason::ColdStart),
+            1 => Some(BootReason::UpdateRequest),
+            2 => Some(BootReason::AppFault),
+            3 => Some(BootReason::Watchdog),
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of the last bootloader session
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum UpdateStatus {
+    None = 0,
+    /// A new image was written and verified
+    Updated = 1,
+    /// An update was started and failed, see `last_error`
+    Failed = 2,
+}
+
+impl UpdateStatus {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(UpdateStatus::None),
+            1 => Some(UpdateStatus::Updated),
+            2 => Some(UpdateStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BootMailbox {
+    pub reason: BootReason,
+    pub update: UpdateStatus,
+    /// Writer specific error code, 0 for none. The bootloader stores the
+    /// NAK byte it sent to the host.
+    pub last_error: u8,
+}
+
+impl BootMailbox {
+    pub const fn new(reason: BootReason) -> Self {
+        Self {
+            reason,
+            update: UpdateStatus::None,
+            last_error: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawMailbox {
+    magic: u16,
+    reason: u8,
+    update: u8,
+    last_error: u8,
+    reserved: u8,
+    crc: u16,
+}
+
+const _: () = assert!(size_of::<RawMailbox>() <= MAILBOX_RESERVED);
+
+#[link_section = ".noinit"]
+static mut MAILBOX: MaybeUninit<RawMailbox> = MaybeUninit::uninit();
+
+/// Message left by the other image, `None` if the RAM holds no valid one
+pub fn read() -> Option<BootMailbox> {
+    // Uninitialized after power-on, so every field is checked
+    let raw = unsafe { core::ptr::read_volatile(addr_of!(MAILBOX)).assume_init() };
+    if raw.magic != MAILBOX_MAGIC || raw.crc != crc16(&raw) {
+        return None;
+    }
+
+    Some(BootMailbox {
+        reason: BootReason::from_u8(raw.reason)?,
+        update: UpdateStatus::from_u8(raw.update)?,
+        last_error: raw.last_error,
+    })
+}
+
+/// Leave a message for the image that runs after the next reset
+pub fn write(mailbox: &BootMailbox) {
+    let mut raw = RawMailbox {
+        magic: MAILBOX_MAGIC,
+        reason: mailbox.reason as u8,
+        update: mailbox.update as u8,
+        last_error: mailbox.last_error,
+        reserved: 0,
+        crc: 0,
+    };
+    raw.crc = crc16(&raw);
+    unsafe { core::ptr::write_volatile(addr_of_mut!(MAILBOX), MaybeUninit::new(raw)) };
+}
+
+/// Invalidate the mailbox so a message is only acted on once
+pub fn clear() {
+    unsafe { core::ptr::write_volatile(addr_of_mut!(MAILBOX).cast::<u16>(), 0) };
+}
+
+/// Read and clear in one go
+pub fn take() -> Option<BootMailbox> {
+    let mailbox = read();
+    clear();
+    mailbox
+}
+
+/// CRC-16/CCITT over everything but the CRC itself
+fn crc16(raw: &RawMailbox) -> u16 {
+    let bytes = [
+        raw.magic as u8,
+        (raw.magic >> 8) as u8,
+        raw.reason,
+        raw.update,
+        raw.last_error,
+        raw.reserved,
+    ];
+    let mut crc = 0xFFFFu16;
+    for &byte in &bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
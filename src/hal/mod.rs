@@ -1,24 +1,48 @@
 pub mod adc;
+pub mod board_id;
+pub mod claims;
+pub mod clock;
+pub mod device_info;
+pub mod eeprom;
+pub mod entropy;
+pub mod exti;
 pub mod gpio;
+pub mod interop;
+pub mod mailbox;
 pub mod power;
+pub mod progmem;
+pub mod pwm;
+pub mod regs;
+pub mod reset;
+pub mod rtc_soft;
 pub mod spi;
+pub mod systime;
 pub mod timer;
 pub mod twi;
 pub mod uart;
 pub mod watchdog;
 
 // Re-export commonly used types
-pub use adc::{Adc, AdcChannel, AdcPrescaler, AdcReference};
+pub use adc::{Adc, AdcArbiter, AdcCallback, AdcChannel, AdcCompleteHandler, AdcError, AdcPrescaler, AdcReference, AdcRequest, AdcScanner};
+pub use board_id::{BoardConfig, BoardRevision};
+pub use device_info::DeviceInfo;
+pub use eeprom::{EepromError, Plain};
+pub use exti::{ExtInt, ExtiError, ExtiHandler};
 pub use gpio::board;
-pub use gpio::{Input, Output, Pin};
-pub use power::{Power, SleepMode};
-pub use spi::{DataOrder, Spi, SpiMode, SpiPrescaler};
-pub use timer::{delay_ms, Prescaler, Timer};
-pub use twi::{Twi, TwiSpeed};
-pub use uart::Uart;
-pub use watchdog::{Watchdog, WatchdogTimeout};
+pub use gpio::{DynMode, DynPin, Input, OpenDrain, Output, Pin, PortBus, PullUpInput};
+pub use interop::{BusProxy, Delay, SharedBus};
+pub use mailbox::{BootMailbox, BootReason, UpdateStatus};
+pub use power::{Power, Residency, SleepMode};
+pub use progmem::{PgmSlice, PgmStr};
+pub use pwm::{Pwm, Pwm8, PwmChannel, PwmError, PwmFreq, PwmMode, SoftStart};
+pub use reset::ResetReason;
+pub use spi::{ChipSelect, DataOrder, Spi, SpiDevice, SpiMode, SpiPrescaler};
+pub use timer::{delay_ms, CaptureEdge, CompareChannel, CompareOutput, Prescaler, Timer, Timer16, Timer16Mode, TimerInterrupt};
+pub use twi::{Twi, TwiAsyncError, TwiCallback, TwiError, TwiSpeed, TwiTicket};
+pub use uart::{DataBits, DriverEnable, FlowControl, FlowPin, FlowPort, MpFrame, Parity, SerialPort, StopBits, Uart, UartConfig, UartError, UartStats};
+pub use watchdog::{TimeoutHook, Watchdog, WatchdogTimeout};
 
-// TODO: Add other HAL modules (PWM, etc)
+// TODO: Add other HAL modules
 #[allow(dead_code)]
 pub(crate) struct Hal {
     // Will contain HAL instances
@@ -1,6 +1,19 @@
+//! Sleep control and sleep-state residency
+//!
+//! Every sleep entered through `Power` is timed on the system tick, so
+//! `take_residency` tells how long the CPU was active and how long it spent
+//! in each sleep mode. Sleeps that stop the system tick (PowerDown,
+//! PowerSave) read as zero; whoever wakes the CPU from them knows the time
+//! asleep and adds it with `account`.
 use avr_device::atmega128::CPU;
 
-#[derive(Clone, Copy)]
+use crate::hal::regs::mcucr;
+use crate::os::SCHEDULER;
+
+/// Active plus one slot per sleep mode
+pub const RESIDENCY_STATES: usize = 7;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 #[repr(u8)]
 pub enum SleepMode {
     Idle = 0,
@@ -11,21 +24,94 @@ pub enum SleepMode {
     ExtendedStandby = 7,
 }
 
+impl SleepMode {
+    /// Slot of this mode in `Residency::ms`
+    pub fn residency_index(self) -> usize {
+        match self {
+            SleepMode::Idle => 1,
+            SleepMode::AdcNoiseReduction => 2,
+            SleepMode::PowerDown => 3,
+            SleepMode::PowerSave => 4,
+            SleepMode::Standby => 5,
+            SleepMode::ExtendedStandby => 6,
+        }
+    }
+}
+
+/// Milliseconds spent active (slot 0) and in each sleep mode
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Residency {
+    pub ms: [u32; RESIDENCY_STATES],
+}
+
+impl Residency {
+    pub const ACTIVE: usize = 0;
+
+    pub const fn new() -> Self {
+        Self {
+            ms: [0; RESIDENCY_STATES],
+        }
+    }
+
+    pub fn active_ms(&self) -> u32 {
+        self.ms[Self::ACTIVE]
+    }
+
+    pub fn sleep_ms(&self, mode: SleepMode) -> u32 {
+        self.ms[mode.residency_index()]
+    }
+
+    pub fn total_ms(&self) -> u32 {
+        self.ms.iter().fold(0u32, |sum, &ms| sum.saturating_add(ms))
+    }
+
+    pub fn add(&mut self, other: &Residency) {
+        for (ms, &more) in self.ms.iter_mut().zip(other.ms.iter()) {
+            *ms = ms.saturating_add(more);
+        }
+    }
+
+    /// `ms` as u32 LE, active first
+    pub fn encode(&self, out: &mut [u8]) {
+        for (i, ms) in self.ms.iter().enumerate() {
+            out[i * 4..i * 4 + 4].copy_from_slice(&ms.to_le_bytes());
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Self {
+        let mut residency = Self::new();
+        for (i, ms) in residency.ms.iter_mut().enumerate() {
+            *ms = u32::from_le_bytes([data[i * 4], data[i * 4 + 1], data[i * 4 + 2], data[i * 4 + 3]]);
+        }
+        residency
+    }
+}
+
 pub struct Power {
-    _private: (),
+    mode: SleepMode,
+    residency: Residency,
+    /// System tick at the last state change
+    mark: u32,
 }
 
 impl Power {
     pub fn new() -> Self {
-        Self { _private: () }
+        Self {
+            mode: SleepMode::Idle,
+            residency: Residency::new(),
+            mark: SCHEDULER.get_ticks(),
+        }
     }
 
     #[inline]
     pub fn set_sleep_mode(&mut self, mode: SleepMode) {
+        self.mode = mode;
+        debug_assert!(mcucr::valid_sleep_mode(mode as u8));
         unsafe {
             let p = CPU::ptr();
+            // SM2 sits below SM1 and SM0, the mode number can't just be shifted
             (*p).mcucr.modify(|r, w| {
-                w.bits((r.bits() & !0x70) | ((mode as u8) << 4))
+                w.bits((r.bits() & !mcucr::SM_MASK) | mcucr::sleep_mode_bits(mode as u8))
             });
         }
     }
@@ -34,7 +120,7 @@ impl Power {
     pub fn enable_sleep(&mut self) {
         unsafe {
             let p = CPU::ptr();
-            (*p).mcucr.modify(|r, w| w.bits(r.bits() | 0x20));
+            (*p).mcucr.modify(|r, w| w.bits(r.bits() | mcucr::SE));
         }
     }
 
@@ -42,15 +128,40 @@ impl Power {
     pub fn disable_sleep(&mut self) {
         unsafe {
             let p = CPU::ptr();
-            (*p).mcucr.modify(|r, w| w.bits(r.bits() & !0x20));
+            (*p).mcucr.modify(|r, w| w.bits(r.bits() & !mcucr::SE));
         }
     }
 
+    /// Sleep in the selected mode until an interrupt, timing both the
+    /// active stretch before and the sleep itself
     #[inline]
     pub fn sleep(&mut self) {
+        self.close(Residency::ACTIVE);
         unsafe {
             avr_device::asm::sleep()
         }
+        self.close(self.mode.residency_index());
+    }
+
+    /// Add time the system tick could not see, e.g. PowerSave measured on
+    /// the asynchronous timer
+    pub fn account(&mut self, mode: Option<SleepMode>, ms: u32) {
+        let index = mode.map_or(Residency::ACTIVE, SleepMode::residency_index);
+        self.residency.ms[index] = self.residency.ms[index].saturating_add(ms);
+    }
+
+    /// Residency since the previous call, counting up to now as active
+    pub fn take_residency(&mut self) -> Residency {
+        self.close(Residency::ACTIVE);
+        core::mem::take(&mut self.residency)
+    }
+
+    // Charge the time since the last mark to one state
+    fn close(&mut self, index: usize) {
+        let now = SCHEDULER.get_ticks();
+        let elapsed = now.wrapping_sub(self.mark);
+        self.residency.ms[index] = self.residency.ms[index].saturating_add(elapsed);
+        self.mark = now;
     }
 
     pub fn enter_idle_mode(&mut self) {
@@ -67,6 +178,15 @@ impl Power {
         self.disable_sleep();
     }
 
+    /// Power-down with the asynchronous timer still running, so it can
+    /// wake the CPU
+    pub fn enter_power_save(&mut self) {
+        self.set_sleep_mode(SleepMode::PowerSave);
+        self.enable_sleep();
+        self.sleep();
+        self.disable_sleep();
+    }
+
     // Module clock control
     pub fn disable_module_clock(&mut self, module: u8) {
         unsafe {
@@ -0,0 +1,157 @@
+//! String constants kept in flash
+//!
+//! On AVR every `&str` literal is copied from flash into SRAM at startup,
+//! which adds up fast with 4KB of RAM. `pgm_str!` places a literal in the
+//! `.progmem.data` section instead and hands out a `PgmStr`, whose bytes
+//! are fetched one at a time with `LPM` while writing it out:
+//!
+//! ```ignore
+//! console.write_pgm_line(pgm_str!("Ready..."));
+//! ```
+//!
+//! Program memory is only addressable by `LPM` below 64KB; the linker puts
+//! `.progmem.data` right after the vector table, so that always holds.
+#![no_std]
+
+/// Flash resident string, created by `pgm_str!`
+#[derive(Clone, Copy)]
+pub struct PgmStr {
+    bytes: PgmSlice,
+}
+
+/// Flash resident bytes
+#[derive(Clone, Copy)]
+pub struct PgmSlice {
+    addr: *const u8,
+    len: usize,
+}
+
+// Flash is read-only, sharing the address between contexts is fine
+unsafe impl Sync for PgmSlice {}
+unsafe impl Send for PgmSlice {}
+
+impl PgmStr {
+    /// # Safety
+    /// `addr` must be the address of `len` bytes of UTF-8 in program memory
+    pub const unsafe fn from_raw(addr: *const u8, len: usize) -> Self {
+        Self {
+            bytes: PgmSlice { addr, len },
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.len == 0
+    }
+
+    pub fn as_bytes(&self) -> PgmSlice {
+        self.bytes
+    }
+}
+
+impl PgmSlice {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Byte at `index` read from flash
+    pub fn get(&self, index: usize) -> Option<u8> {
+        (index < self.len).then(|| unsafe { read_byte(self.addr.add(index)) })
+    }
+
+    pub fn iter(&self) -> PgmIter {
+        PgmIter {
+            bytes: *self,
+            pos: 0,
+        }
+    }
+}
+
+impl IntoIterator for PgmSlice {
+    type Item = u8;
+    type IntoIter = PgmIter;
+
+    fn into_iter(self) -> PgmIter {
+        self.iter()
+    }
+}
+
+pub struct PgmIter {
+    bytes: PgmSlice,
+    pos: usize,
+}
+
+impl Iterator for PgmIter {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.bytes.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+}
+
+/// Read one byte of program memory
+///
+/// # Safety
+/// `addr` must point into program memory below 64KB
+pub unsafe fn read_byte(addr: *const u8) -> u8 {
+    let value: u8;
+    core::arch::asm!(
+        "lpm {value}, Z",
+        value = out(reg) value,
+        in("Z") addr as u16,
+        options(pure, readonly, nostack),
+    );
+    value
+}
+
+/// Read one byte anywhere in the 128KB of program memory, through RAMPZ
+///
+/// # Safety
+/// `addr` must be below 0x20000
+pub unsafe fn read_far_byte(addr: u32) -> u8 {
+    let value: u8;
+    core::arch::asm!(
+        "out 0x3B, {page}",
+        "elpm {value}, Z",
+        // RAMPZ back to 0 for the LPM users
+        "clr {page}",
+        "out 0x3B, {page}",
+        page = inout(reg) (addr >> 16) as u8 => _,
+        value = out(reg) value,
+        in("Z") addr as u16,
+        options(readonly, nostack),
+    );
+    value
+}
+
+/// Copy a string literal into an array, used by `pgm_str!` at compile time
+pub const fn literal_bytes<const N: usize>(s: &str) -> [u8; N] {
+    let src = s.as_bytes();
+    let mut out = [0u8; N];
+    let mut i = 0;
+    while i < N {
+        out[i] = src[i];
+        i += 1;
+    }
+    out
+}
+
+/// Place a string literal in flash and return it as a `PgmStr`
+#[macro_export]
+macro_rules! pgm_str {
+    ($s:literal) => {{
+        #[link_section = ".progmem.data"]
+        static TEXT: [u8; $s.len()] = $crate::hal::progmem::literal_bytes($s);
+        // TEXT holds the UTF-8 literal and lives in flash
+        unsafe { $crate::hal::progmem::PgmStr::from_raw(TEXT.as_ptr(), TEXT.len()) }
+    }};
+}
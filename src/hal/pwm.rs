@@ -1,16 +1,124 @@
 //! PWM (Pulse Width Modulation) HAL implementation
 //! 
 //! This module provides hardware PWM support using Timer1/3 in various modes.
-//! 
-//! Note to self: We might want to add support for Timer2 as well, but it's 8-bit
-//! and the prescaler options are different. Left some old code commented out below
-//! that shows how we could implement it.
+//!
+//! `Pwm8` drives the single output of the 8-bit timers, OC0 (PB4) and OC2
+//! (PB7), for LED dimming and other loads that don't need 16-bit
+//! resolution or a servo period. Timer0 is also the system tick and the
+//! soft RTC, so `Pwm8<TC0>` is only for builds that use neither; Timer2 is
+//! shared with the latency monitor and DMX break timing, and OC2 with OC1C.
+//! The soft-start cap is for motors and doesn't apply to `Pwm8`.
+//!
+//! Complementary pairs: two channels of Timer1 or Timer3 can drive the
+//! high and low side switches of a half-bridge, the low side inverted and
+//! both held off for a dead time around each edge, see `set_complementary`.
+//!
+//! Soft-start: for a while after boot and after every `restart_soft_start`
+//! (the safety module calls it on arming) duty cycles are capped by a limit
+//! rising linearly to 100%, so motors can't pull the supply into brown-out
+//! with their inrush current. The cap applies to every `Pwm::set_duty`;
+//! call `Pwm::service` from the main loop so outputs set once during the
+//! ramp still reach their requested duty.
+//!
+//! Shutdown: `Pwm::new` registers `stop_outputs` as a shutdown hook, which
+//! disconnects every Timer1/Timer3 compare output so motors and servos
+//! stop before the reset.
 
 #![no_std]
 
-use avr_device::atmega128::{TC1, TC3};
+use avr_device::atmega128::{TC0, TC1, TC2, TC3};
+use avr_device::interrupt::Mutex;
+use core::cell::Cell;
 use core::marker::PhantomData;
 
+use crate::config::CPU_FREQ_HZ;
+use crate::hal::claims::{self, Port, Resource};
+use crate::hal::clock;
+use crate::hal::gpio::DynPin;
+use crate::hal::regs::{tccr0, tccr1a, tccr2};
+use crate::hal::timer::{CompareChannel, CompareOutput, Prescaler, Timer16, Timer16Mode};
+use crate::os::SCHEDULER;
+use crate::shutdown::{self, ShutdownReason};
+
+// All three compare output modes of a 16-bit timer, TCCR3A has the same layout
+const COM_BITS: u8 = tccr1a::COM1A1
+    | tccr1a::COM1A0
+    | tccr1a::COM1B1
+    | tccr1a::COM1B0
+    | tccr1a::COM1C1
+    | tccr1a::COM1C0;
+
+/// Shutdown hook: disconnect every compare output of Timer1 and Timer3, so
+/// the pins fall back to their port value (low) and motors, ESCs and
+/// servos stop. Registered by `Pwm::new` and `Servo::new`.
+pub fn stop_outputs(_reason: ShutdownReason) {
+    unsafe {
+        (*TC1::ptr()).tccr1a.modify(|r, w| w.bits(r.bits() & !COM_BITS));
+        (*TC3::ptr()).tccr3a.modify(|r, w| w.bits(r.bits() & !COM_BITS));
+    }
+}
+
+/// Register `stop_outputs` once, ahead of every other hook
+pub(crate) fn register_stop_hook() {
+    shutdown::register("actuators", u8::MAX, 1, stop_outputs).ok();
+}
+
+/// Duty cap ramp applied to all PWM outputs
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SoftStart {
+    /// Length of the ramp
+    pub duration_ms: u16,
+    /// Cap at the start of the ramp in percent
+    pub initial_duty: f32,
+}
+
+impl SoftStart {
+    pub const fn new() -> Self {
+        Self {
+            duration_ms: 500,
+            initial_duty: 0.0,
+        }
+    }
+}
+
+impl Default for SoftStart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static SOFT_START: Mutex<Cell<Option<SoftStart>>> = Mutex::new(Cell::new(Some(SoftStart::new())));
+// System tick the current ramp started at, boot is tick 0
+static SOFT_START_BEGIN: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Change the soft-start ramp, `None` disables it
+pub fn set_soft_start(config: Option<SoftStart>) {
+    avr_device::interrupt::free(|cs| SOFT_START.borrow(cs).set(config));
+}
+
+/// Run the soft-start ramp again from now, e.g. after fault recovery
+pub fn restart_soft_start() {
+    avr_device::interrupt::free(|cs| SOFT_START_BEGIN.borrow(cs).set(SCHEDULER.get_ticks()));
+}
+
+/// Current duty cap in percent, 100 once the ramp is over
+pub fn soft_start_limit() -> f32 {
+    let (config, begin) = avr_device::interrupt::free(|cs| {
+        (SOFT_START.borrow(cs).get(), SOFT_START_BEGIN.borrow(cs).get())
+    });
+    let config = match config {
+        Some(config) if config.duration_ms > 0 => config,
+        _ => return 100.0,
+    };
+
+    let elapsed = SCHEDULER.get_ticks().wrapping_sub(begin);
+    if elapsed >= config.duration_ms as u32 {
+        return 100.0;
+    }
+    let initial = config.initial_duty.clamp(0.0, 100.0);
+    initial + (100.0 - initial) * elapsed as f32 / config.duration_ms as f32
+}
+
 /// PWM frequency presets
 #[derive(Clone, Copy)]
 pub enum PwmFreq {
@@ -21,7 +129,7 @@ pub enum PwmFreq {
 }
 
 /// PWM channel configuration
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum PwmChannel {
     Timer1A,
     Timer1B,
@@ -31,6 +139,20 @@ pub enum PwmChannel {
     Timer3C,
 }
 
+impl PwmChannel {
+    /// Compare output pin: OC1A..OC1C on PB5..PB7, OC3A..OC3C on PE3..PE5
+    pub fn pin(self) -> (Port, u8) {
+        match self {
+            PwmChannel::Timer1A => (Port::B, 5),
+            PwmChannel::Timer1B => (Port::B, 6),
+            PwmChannel::Timer1C => (Port::B, 7),
+            PwmChannel::Timer3A => (Port::E, 3),
+            PwmChannel::Timer3B => (Port::E, 4),
+            PwmChannel::Timer3C => (Port::E, 5),
+        }
+    }
+}
+
 /// PWM mode configuration
 #[derive(Clone, Copy)]
 pub enum PwmMode {
@@ -39,23 +161,41 @@ pub enum PwmMode {
     PhaseFreq,      // Phase and frequency correct PWM mode
 }
 
+#[derive(Debug, PartialEq)]
+pub enum PwmError {
+    /// Channel of another timer, or the same channel twice
+    InvalidChannel,
+    /// Complementary pairs need `PhaseCorrect` or `PhaseFreq`
+    NotPhaseCorrect,
+    /// The dead time leaves no room for a pulse at this frequency
+    DeadTimeTooLong,
+    /// A nonzero dead time that rounds to no timer ticks
+    DeadTimeTooShort,
+}
+
 /// PWM peripheral driver
 pub struct Pwm<T> {
-    _timer: PhantomData<T>,
+    timer: Timer16<T>,
     freq: PwmFreq,
     mode: PwmMode,
     
     // Cache configured values for dynamic updates
     period: u16,
     prescaler: u8,
+
+    // Duty last requested per channel (A, B, C), before the soft-start cap
+    requested: [Option<f32>; 3],
+    // True while some output runs below its requested duty
+    capped: bool,
+
+    // Complementary pair (high side, low side channel index) and the gap
+    // between their edges in timer ticks
+    pair: Option<(usize, usize)>,
+    dead_time_ticks: u16,
     
     /* Commenting out experimental features
     // Advanced PWM features I was testing:
     #[allow(dead_code)]
-    complementary_output: bool,  // Complementary output mode
-    #[allow(dead_code)]
-    dead_time_ns: u16,          // Dead time for complementary mode
-    #[allow(dead_code)]
     fault_detection: bool,       // Fault detection enable
     
     // Different synchronization modes:
@@ -69,147 +209,330 @@ pub struct Pwm<T> {
     */
 }
 
-// Timer1 implementation
-impl Pwm<TC1> {
-    /// Create new PWM instance using Timer1
-    pub fn new() -> Self {
-        // Disable timer interrupts during initialization
-        unsafe {
-            (*TC1::ptr()).timsk.write(|w| w.bits(0));
-        }
-        
-        Self {
-            _timer: PhantomData,
-            freq: PwmFreq::Hz50,
-            mode: PwmMode::Fast,
-            period: 0,
-            prescaler: 0,
-        }
-    }
+macro_rules! impl_pwm16 {
+    ($TC:ident, $resource:ident, [$chan_a:ident, $chan_b:ident, $chan_c:ident]) => {
+        impl Pwm<$TC> {
+            const CHANNELS: [PwmChannel; 3] = [PwmChannel::$chan_a, PwmChannel::$chan_b, PwmChannel::$chan_c];
+            const COMPARE: [CompareChannel; 3] = [CompareChannel::A, CompareChannel::B, CompareChannel::C];
 
-    /// Configure PWM frequency and mode
-    pub fn configure(&mut self, freq: PwmFreq, mode: PwmMode) {
-        self.freq = freq;
-        self.mode = mode;
-        
-        // Calculate timer parameters for 16MHz clock
-        let (period, prescaler) = match freq {
-            PwmFreq::Hz50 => (40000, 8),   // 16MHz / (50Hz * 8) = 40000
-            PwmFreq::Hz200 => (10000, 8),  // 16MHz / (200Hz * 8) = 10000
-            PwmFreq::Hz400 => (5000, 8),   // 16MHz / (400Hz * 8) = 5000
-            PwmFreq::Hz1000 => (2000, 8),  // 16MHz / (1000Hz * 8) = 2000
-        };
-        self.period = period;
-        self.prescaler = prescaler;
-        
-        unsafe {
-            let p = TC1::ptr();
-            
-            // Set PWM mode and prescaler
-            match mode {
-                PwmMode::Fast => {
-                    (*p).tccr1a.write(|w| w.bits(0x02));  // Fast PWM, ICR1 top
-                    (*p).tccr1b.write(|w| w.bits(0x18 | prescaler));
-                }
-                PwmMode::PhaseCorrect => {
-                    (*p).tccr1a.write(|w| w.bits(0x02));  // Phase correct PWM, ICR1 top
-                    (*p).tccr1b.write(|w| w.bits(0x10 | prescaler));
-                }
-                PwmMode::PhaseFreq => {
-                    (*p).tccr1a.write(|w| w.bits(0x02));  // Phase & freq correct PWM
-                    (*p).tccr1b.write(|w| w.bits(0x10 | prescaler));
-                }
-            }
-            
-            // Set period
-            (*p).icr1.write(|w| w.bits(period));
-        }
-    }
+            /// Create new PWM instance on the timer, stopped with its
+            /// interrupts off
+            pub fn new() -> Self {
+                claims::claim(Resource::$resource, "pwm").ok();
+                register_stop_hook();
+                Self {
+                    timer: Timer16::<$TC>::new(),
+                    freq: PwmFreq::Hz50,
+                    mode: PwmMode::Fast,
+                    period: 0,
+                    prescaler: 0,
+                    requested: [None; 3],
+                    capped: false,
+                    pair: None,
+                    dead_time_ticks: 0,
+                }
+            }
+
+            /// Configure PWM frequency and mode
+            pub fn configure(&mut self, freq: PwmFreq, mode: PwmMode) {
+                self.freq = freq;
+                self.mode = mode;
+
+                // Calculate timer parameters for the CPU clock, prescaler 8
+                let hz: u32 = match freq {
+                    PwmFreq::Hz50 => 50,
+                    PwmFreq::Hz200 => 200,
+                    PwmFreq::Hz400 => 400,
+                    PwmFreq::Hz1000 => 1000,
+                };
+                let prescaler = 8;
+                let period = (CPU_FREQ_HZ / (hz * prescaler as u32)) as u16;
+                self.period = period;
+                self.prescaler = prescaler;
+
+                self.timer.stop();
+                for channel in Self::COMPARE {
+                    self.timer.set_output(channel, CompareOutput::Disconnected);
+                }
+                self.timer.set_mode(match mode {
+                    PwmMode::Fast => Timer16Mode::FastPwmIcr,
+                    PwmMode::PhaseCorrect => Timer16Mode::PhaseCorrectIcr,
+                    PwmMode::PhaseFreq => Timer16Mode::PhaseFrequencyIcr,
+                });
+                self.timer.set_icr(period);
+                self.timer.start(Prescaler::Div8);
+            }
+
+            /// Set duty cycle for a channel (0-100%), capped while soft-start runs
+            pub fn set_duty(&mut self, channel: PwmChannel, duty: f32) {
+                let index = match Self::index(channel) {
+                    Some(index) => index,
+                    None => return,
+                };
+                self.requested[index] = Some(duty);
+
+                let limit = soft_start_limit();
+                if duty > limit {
+                    self.capped = true;
+                }
+                self.write_duty(channel, duty.min(limit));
+            }
 
-    /// Set duty cycle for a channel (0-100%)
-    pub fn set_duty(&mut self, channel: PwmChannel, duty: f32) {
-        let duty = (duty.max(0.0).min(100.0) / 100.0) * self.period as f32;
-        let duty = duty as u16;
-        
-        unsafe {
-            let p = TC1::ptr();
-            match channel {
-                PwmChannel::Timer1A => {
-                    (*p).tccr1a.modify(|r, w| w.bits(r.bits() | 0x80));
-                    (*p).ocr1a.write(|w| w.bits(duty));
+            /// Raise capped outputs along the soft-start ramp. Call regularly;
+            /// `MotorController::update` does on every call.
+            pub fn service(&mut self) {
+                if !self.capped {
+                    return;
                 }
-                PwmChannel::Timer1B => {
-                    (*p).tccr1a.modify(|r, w| w.bits(r.bits() | 0x20));
-                    (*p).ocr1b.write(|w| w.bits(duty));
+                self.capped = false;
+                for (channel, requested) in Self::CHANNELS.into_iter().zip(self.requested) {
+                    if let Some(duty) = requested {
+                        self.set_duty(channel, duty);
+                    }
                 }
-                PwmChannel::Timer1C => {
-                    (*p).tccr1a.modify(|r, w| w.bits(r.bits() | 0x08));
-                    (*p).ocr1c.write(|w| w.bits(duty));
+            }
+
+            fn write_duty(&mut self, channel: PwmChannel, duty: f32) {
+                match self.pair {
+                    Some((high, _)) if Self::index(channel) == Some(high) => self.write_pair(duty),
+                    // Follows its high side
+                    Some((_, low)) if Self::index(channel) == Some(low) => {}
+                    _ => {
+                        let duty = (duty.max(0.0).min(100.0) / 100.0) * self.period as f32;
+                        self.write_compare(channel, duty as u16);
+                    }
+                }
+            }
+
+            /// Drive `low` as the inverse of `high` with both off for at
+            /// least `dead_time_ns` around every edge, for the two switches
+            /// of a half-bridge. The timer has to run in `PhaseCorrect` or
+            /// `PhaseFreq` mode: `high` is on while the counter is below its
+            /// compare value, `low` while it is above a compare value
+            /// `dead_time_ns` later, which spaces the edges the same on the
+            /// way up and down. Both outputs are disconnected until
+            /// `set_duty(high, ..)` or `set_complementary_duty` sets the
+            /// high side's on-time.
+            pub fn set_complementary(&mut self, high: PwmChannel, low: PwmChannel, dead_time_ns: u16) -> Result<(), PwmError> {
+                let (high_index, low_index) = match (Self::index(high), Self::index(low)) {
+                    (Some(h), Some(l)) if h != l => (h, l),
+                    _ => return Err(PwmError::InvalidChannel),
+                };
+                if let PwmMode::Fast = self.mode {
+                    return Err(PwmError::NotPhaseCorrect);
+                }
+                // Round up, a dead time too short shoots through
+                let ticks = clock::ns_to_counts_ceil(dead_time_ns as u32, self.prescaler.max(1) as u32);
+                if ticks == 0 && dead_time_ns > 0 {
+                    return Err(PwmError::DeadTimeTooShort);
+                }
+                if ticks * 2 >= self.period as u32 {
+                    return Err(PwmError::DeadTimeTooLong);
+                }
+                let ticks = ticks as u16;
+
+                self.pair = Some((high_index, low_index));
+                self.dead_time_ticks = ticks;
+                self.disable(high);
+                self.disable(low);
+                Ok(())
+            }
+
+            /// Back to independent channels, both outputs disconnected
+            pub fn clear_complementary(&mut self) {
+                if let Some((high, low)) = self.pair.take() {
+                    self.disable(Self::CHANNELS[high]);
+                    self.disable(Self::CHANNELS[low]);
+                }
+            }
+
+            /// High side on-time of the complementary pair (0-100%) without
+            /// the soft-start cap, for callers that cap the output
+            /// themselves, e.g. a locked anti-phase bridge where 50% is
+            /// standstill
+            pub fn set_complementary_duty(&mut self, duty: f32) {
+                if let Some((high, _)) = self.pair {
+                    self.requested[high] = Some(duty);
+                    self.write_pair(duty);
+                }
+            }
+
+            /// Dead time actually inserted, rounded up to whole timer ticks
+            pub fn dead_time_ns(&self) -> u32 {
+                clock::counts_to_ns(self.dead_time_ticks as u32, self.prescaler.max(1) as u32)
+            }
+
+            fn write_pair(&mut self, duty: f32) {
+                let (high, low) = match self.pair {
+                    Some(pair) => pair,
+                    None => return,
+                };
+                let span = self.period - self.dead_time_ticks;
+                let on = ((duty.max(0.0).min(100.0) / 100.0) * span as f32) as u16;
+                self.write_compare(Self::CHANNELS[high], on);
+                self.write_compare(Self::CHANNELS[low], on + self.dead_time_ticks);
+            }
+
+            /// Set the high time of a channel in microseconds (servos, ESCs)
+            pub fn set_pulse_us(&mut self, channel: PwmChannel, pulse_us: u16) {
+                let counts = clock::us_to_counts(pulse_us as u32, self.prescaler.max(1) as u32);
+                let compare = counts.min(self.period as u32) as u16;
+                self.write_compare(channel, compare);
+            }
+
+            /// Disconnect a channel from its pin, which then holds its
+            /// port value
+            pub fn disable(&mut self, channel: PwmChannel) {
+                if let Some(index) = Self::index(channel) {
+                    self.requested[index] = None;
+                    self.timer.set_output(Self::COMPARE[index], CompareOutput::Disconnected);
                 }
-                _ => {} // Invalid channel for Timer1
             }
-        }
-    }
-}
 
-// Timer3 implementation (similar to Timer1)
-impl Pwm<TC3> {
-    /// Create new PWM instance using Timer3
-    pub fn new() -> Self {
-        unsafe {
-            (*TC3::ptr()).timsk.write(|w| w.bits(0));
+            fn write_compare(&mut self, channel: PwmChannel, compare: u16) {
+                let index = match Self::index(channel) {
+                    Some(index) => index,
+                    None => return, // Invalid channel for this timer
+                };
+                // The low side of a pair is inverted: set on up-counting match
+                let output = match self.pair {
+                    Some((_, low)) if low == index => CompareOutput::Set,
+                    _ => CompareOutput::Clear,
+                };
+                self.timer.set_output(Self::COMPARE[index], output);
+                self.timer.set_compare(Self::COMPARE[index], compare);
+            }
+
+            fn index(channel: PwmChannel) -> Option<usize> {
+                Self::CHANNELS.iter().position(|&c| c == channel)
+            }
         }
-        
-        Self {
-            _timer: PhantomData,
-            freq: PwmFreq::Hz50,
-            mode: PwmMode::Fast,
-            period: 0,
-            prescaler: 0,
+
+        impl Default for Pwm<$TC> {
+            fn default() -> Self {
+                Self::new()
+            }
         }
-    }
+    };
+}
 
-    // Implementation similar to Timer1...
-    // TODO: Implement Timer3 specific functions
+impl_pwm16!(TC1, Timer1, [Timer1A, Timer1B, Timer1C]);
+impl_pwm16!(TC3, Timer3, [Timer3A, Timer3B, Timer3C]);
+
+/// 8-bit PWM on the compare output of Timer0 or Timer2
+pub struct Pwm8<T> {
+    _timer: PhantomData<T>,
+    mode: PwmMode,
+    divider: u16,
+    duty: u8,
 }
 
-/* Old Timer2 8-bit PWM implementation - keeping for reference
-#[allow(dead_code)]
-impl Pwm<TC2> {
-    const PRESCALER_VALUES: [u16; 7] = [1, 8, 32, 64, 128, 256, 1024];
-    
-    pub fn new() -> Self {
-        unsafe {
-            (*TC2::ptr()).timsk.write(|w| w.bits(0));
-        }
-        
-        Self {
-            _timer: PhantomData,
-            freq: PwmFreq::Hz50,
-            mode: PwmMode::Fast,
-            period: 0,
-            prescaler: 0,
+macro_rules! impl_pwm8 {
+    ($TC:ident, $tccr:ident, $ocr:ident, $regs:ident, $wgm0:ident, $wgm1:ident, $com1:ident, $resource:ident, $pin:expr, $dividers:expr) => {
+        impl Pwm8<$TC> {
+            const DIVIDERS: &'static [u16] = &$dividers;
+
+            /// Claim the timer, output pin low and the timer stopped until
+            /// `configure`
+            pub fn new() -> Self {
+                claims::claim(Resource::$resource, "pwm8").ok();
+                claims::claim(Resource::Pin(Port::B, $pin), "pwm8").ok();
+                unsafe {
+                    (*$TC::ptr()).$tccr.write(|w| w.bits(0));
+                }
+                let mut pin = DynPin::new(Port::B, $pin);
+                pin.set_low();
+                pin.into_output();
+
+                Self {
+                    _timer: PhantomData,
+                    mode: PwmMode::Fast,
+                    divider: 0,
+                    duty: 0,
+                }
+            }
+
+            /// Start the timer at the frequency nearest to `freq_hz` the
+            /// prescalers allow, and return that frequency. `PhaseFreq` is
+            /// the same as `PhaseCorrect` on an 8-bit timer.
+            pub fn configure(&mut self, mode: PwmMode, freq_hz: u32) -> u32 {
+                // Counts per period: 256 up, or 255 up and 255 down
+                let counts = match mode {
+                    PwmMode::Fast => 256,
+                    PwmMode::PhaseCorrect | PwmMode::PhaseFreq => 510,
+                };
+                let freq = |div: u16| CPU_FREQ_HZ / (div as u32 * counts);
+                let divider = Self::DIVIDERS
+                    .iter()
+                    .copied()
+                    .min_by_key(|&div| freq(div).abs_diff(freq_hz))
+                    .unwrap_or(1024);
+                self.mode = mode;
+                self.divider = divider;
+
+                let wgm = match mode {
+                    PwmMode::Fast => $regs::$wgm0 | $regs::$wgm1,
+                    PwmMode::PhaseCorrect | PwmMode::PhaseFreq => $regs::$wgm0,
+                };
+                let cs = $regs::cs_for_divider(divider);
+                debug_assert!(cs != 0);
+                unsafe {
+                    (*$TC::ptr()).$tccr.write(|w| w.bits(wgm | cs));
+                }
+                self.set_raw(self.duty);
+                freq(divider)
+            }
+
+            /// Duty cycle 0-100%
+            pub fn set_duty(&mut self, duty: f32) {
+                let raw = duty.max(0.0).min(100.0) * 255.0 / 100.0 + 0.5;
+                self.set_raw(raw as u8);
+            }
+
+            /// Duty in 1/255 steps, 0 is off and 255 fully on
+            pub fn set_raw(&mut self, duty: u8) {
+                self.duty = duty;
+                unsafe {
+                    let p = $TC::ptr();
+                    if duty == 0 || self.divider == 0 {
+                        // Fast PWM still pulses for one count at OCR 0, so
+                        // disconnect and let the port hold the pin low
+                        (*p).$tccr.modify(|r, w| w.bits(r.bits() & !$regs::$com1));
+                    } else {
+                        (*p).$ocr.write(|w| w.bits(duty));
+                        (*p).$tccr.modify(|r, w| w.bits(r.bits() | $regs::$com1));
+                    }
+                }
+            }
+
+            pub fn duty_raw(&self) -> u8 {
+                self.duty
+            }
+
+            pub fn mode(&self) -> PwmMode {
+                self.mode
+            }
+
+            /// Stop the timer and release it, the pin stays low
+            pub fn stop(&mut self) {
+                unsafe {
+                    (*$TC::ptr()).$tccr.write(|w| w.bits(0));
+                }
+                self.divider = 0;
+                claims::release(Resource::$resource, "pwm8");
+                claims::release(Resource::Pin(Port::B, $pin), "pwm8");
+            }
         }
-    }
-    
-    fn calculate_8bit_params(freq: u16) -> (u8, u8) {
-        let clock = 16_000_000u32;
-        let target = clock / freq as u32;
-        
-        for (i, prescaler) in Self::PRESCALER_VALUES.iter().enumerate() {
-            let period = target / *prescaler as u32;
-            if period <= 256 {
-                return (i as u8, period as u8);
+
+        impl Default for Pwm8<$TC> {
+            fn default() -> Self {
+                Self::new()
             }
         }
-        
-        (6, 255) // Default to max prescaler and period
-    }
+    };
 }
-*/
 
-impl<T> Default for Pwm<T> {
-    fn default() -> Self {
-        Self::new()
-    }
-}
+// OC0 on PB4, OC2 on PB7
+impl_pwm8!(TC0, tccr0, ocr0, tccr0, WGM00, WGM01, COM01, Timer0, 4, [1, 8, 32, 64, 128, 256, 1024]);
+impl_pwm8!(TC2, tccr2, ocr2, tccr2, WGM20, WGM21, COM21, Timer2, 7, [1, 8, 64, 256, 1024]);
+
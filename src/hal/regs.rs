@@ -0,0 +1,224 @@
+//! Named bits of the control registers the HAL writes by hand
+//!
+//! One module per register, names as in the ATmega128 datasheet. Writes go
+//! through `w.bits()` with these constants rather than the svd2rust field
+//! writers because several registers (TWCR, WDTCR) must be written in one
+//! go. The `valid` helpers catch combinations the datasheet marks reserved
+//! or that silently do nothing; the HAL checks them with `debug_assert!`.
+#![no_std]
+
+pub mod adcsra {
+    /// ADC enable
+    pub const ADEN: u8 = 1 << 7;
+    /// Start conversion, reads 1 while converting
+    pub const ADSC: u8 = 1 << 6;
+    /// Free running select
+    pub const ADFR: u8 = 1 << 5;
+    /// Conversion complete, cleared by writing 1
+    pub const ADIF: u8 = 1 << 4;
+    /// Conversion complete interrupt enable
+    pub const ADIE: u8 = 1 << 3;
+    pub const ADPS_MASK: u8 = 0x07;
+
+    /// The ADC stops working reliably above 1MHz, whatever the resolution
+    pub const fn valid_clock(cpu_hz: u32, adps: u8) -> bool {
+        let div = match adps & ADPS_MASK {
+            0 | 1 => 2,
+            n => 1 << n,
+        };
+        cpu_hz / div <= 1_000_000
+    }
+}
+
+pub mod admux {
+    pub const REFS_SHIFT: u8 = 6;
+    pub const REFS_MASK: u8 = 0xC0;
+    /// Left adjust result
+    pub const ADLAR: u8 = 1 << 5;
+    pub const MUX_MASK: u8 = 0x1F;
+
+    /// REFS = 10 is reserved
+    pub const fn valid(bits: u8) -> bool {
+        bits & REFS_MASK != 0x80
+    }
+}
+
+pub mod spcr {
+    pub const SPIE: u8 = 1 << 7;
+    pub const SPE: u8 = 1 << 6;
+    pub const DORD: u8 = 1 << 5;
+    pub const MSTR: u8 = 1 << 4;
+    pub const CPOL: u8 = 1 << 3;
+    pub const CPHA: u8 = 1 << 2;
+    pub const MODE_MASK: u8 = CPOL | CPHA;
+    pub const SPR_MASK: u8 = 0x03;
+}
+
+pub mod spsr {
+    /// Transfer complete
+    pub const SPIF: u8 = 1 << 7;
+    /// Write collision
+    pub const WCOL: u8 = 1 << 6;
+    /// Double speed in master mode
+    pub const SPI2X: u8 = 1 << 0;
+}
+
+pub mod twcr {
+    pub const TWINT: u8 = 1 << 7;
+    pub const TWEA: u8 = 1 << 6;
+    pub const TWSTA: u8 = 1 << 5;
+    pub const TWSTO: u8 = 1 << 4;
+    pub const TWWC: u8 = 1 << 3;
+    pub const TWEN: u8 = 1 << 2;
+    pub const TWIE: u8 = 1 << 0;
+
+    /// Without TWEN every other bit is ignored and the pins are released
+    pub const fn valid(bits: u8) -> bool {
+        bits & (TWINT | TWSTA | TWSTO) == 0 || bits & TWEN != 0
+    }
+}
+
+pub mod twsr {
+    /// Status code, the low bits hold the prescaler
+    pub const STATUS_MASK: u8 = 0xF8;
+    pub const TWPS_MASK: u8 = 0x03;
+}
+
+pub mod wdtcr {
+    /// Change enable, opens the four cycle window for WDE and WDP
+    pub const WDCE: u8 = 1 << 4;
+    pub const WDE: u8 = 1 << 3;
+    pub const WDP_MASK: u8 = 0x07;
+
+    /// First write of the timed sequence needs both WDCE and WDE set
+    pub const fn valid_unlock(bits: u8) -> bool {
+        bits & (WDCE | WDE) == WDCE | WDE
+    }
+}
+
+pub mod tccr1a {
+    pub const COM1A1: u8 = 1 << 7;
+    pub const COM1A0: u8 = 1 << 6;
+    pub const COM1B1: u8 = 1 << 5;
+    pub const COM1B0: u8 = 1 << 4;
+    pub const COM1C1: u8 = 1 << 3;
+    pub const COM1C0: u8 = 1 << 2;
+    pub const WGM11: u8 = 1 << 1;
+    pub const WGM10: u8 = 1 << 0;
+}
+
+pub mod tccr1b {
+    pub const ICNC1: u8 = 1 << 7;
+    pub const ICES1: u8 = 1 << 6;
+    pub const WGM13: u8 = 1 << 4;
+    pub const WGM12: u8 = 1 << 3;
+    pub const CS_MASK: u8 = 0x07;
+
+    /// Clock select bits for a prescaler divider, 0 (timer stopped) if the
+    /// timer has no such divider
+    pub const fn cs_for_divider(div: u16) -> u8 {
+        match div {
+            1 => 1,
+            8 => 2,
+            64 => 3,
+            256 => 4,
+            1024 => 5,
+            _ => 0,
+        }
+    }
+
+    /// Waveform generation mode 13 is reserved
+    pub const fn valid(tccr1a: u8, tccr1b: u8) -> bool {
+        let wgm = (tccr1a & 0x03) | ((tccr1b >> 1) & 0x0C);
+        wgm != 13
+    }
+}
+
+pub mod tccr0 {
+    pub const FOC0: u8 = 1 << 7;
+    pub const WGM00: u8 = 1 << 6;
+    pub const COM01: u8 = 1 << 5;
+    pub const COM00: u8 = 1 << 4;
+    pub const WGM01: u8 = 1 << 3;
+    pub const CS_MASK: u8 = 0x07;
+
+    /// Clock select bits for a prescaler divider, 0 (timer stopped) if the
+    /// timer has no such divider. Timer0 has 32 and 128 as well.
+    pub const fn cs_for_divider(div: u16) -> u8 {
+        match div {
+            1 => 1,
+            8 => 2,
+            32 => 3,
+            64 => 4,
+            128 => 5,
+            256 => 6,
+            1024 => 7,
+            _ => 0,
+        }
+    }
+}
+
+pub mod tccr2 {
+    pub const FOC2: u8 = 1 << 7;
+    pub const WGM20: u8 = 1 << 6;
+    pub const COM21: u8 = 1 << 5;
+    pub const COM20: u8 = 1 << 4;
+    pub const WGM21: u8 = 1 << 3;
+    pub const CS_MASK: u8 = 0x07;
+
+    /// Clock select bits for a prescaler divider, 0 (timer stopped) if the
+    /// timer has no such divider. CS 6 and 7 clock from the T2 pin.
+    pub const fn cs_for_divider(div: u16) -> u8 {
+        match div {
+            1 => 1,
+            8 => 2,
+            64 => 3,
+            256 => 4,
+            1024 => 5,
+            _ => 0,
+        }
+    }
+}
+
+pub mod mcucr {
+    /// External memory interface enable
+    pub const SRE: u8 = 1 << 7;
+    /// Sleep enable
+    pub const SE: u8 = 1 << 5;
+    pub const SM1: u8 = 1 << 4;
+    pub const SM0: u8 = 1 << 3;
+    pub const SM2: u8 = 1 << 2;
+    pub const SM_MASK: u8 = SM2 | SM1 | SM0;
+
+    /// SM2..0 as a number to its scattered register bits
+    pub const fn sleep_mode_bits(mode: u8) -> u8 {
+        let mut bits = 0;
+        if mode & 0x01 != 0 {
+            bits |= SM0;
+        }
+        if mode & 0x02 != 0 {
+            bits |= SM1;
+        }
+        if mode & 0x04 != 0 {
+            bits |= SM2;
+        }
+        bits
+    }
+
+    /// Sleep modes 4 and 5 are reserved
+    pub const fn valid_sleep_mode(mode: u8) -> bool {
+        mode != 4 && mode != 5 && mode < 8
+    }
+}
+
+pub mod mcucsr {
+    /// JTAG interface disable, needs a timed double write to change
+    pub const JTD: u8 = 1 << 7;
+    pub const JTRF: u8 = 1 << 4;
+    pub const WDRF: u8 = 1 << 3;
+    pub const BORF: u8 = 1 << 2;
+    pub const EXTRF: u8 = 1 << 1;
+    pub const PORF: u8 = 1 << 0;
+    /// Reset flags, cleared by writing 0
+    pub const FLAGS_MASK: u8 = JTRF | WDRF | BORF | EXTRF | PORF;
+}
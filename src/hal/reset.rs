@@ -0,0 +1,87 @@
+//! Cause of the last reset
+//!
+//! MCUCSR collects a flag per reset source and keeps it until cleared, so
+//! after a watchdog reset that follows a power-on both flags are set. `init`
+//! reads the flags once at boot, clears them for the next reset and keeps
+//! them for `reason`. The bootloader leaves MCUCSR alone for this.
+//!
+//! Deliberate resets go through the watchdog as well: the host's
+//! `Command::Reset` and the watchdog pre-reset hook both show up as
+//! `Watchdog`. `shutdown::reset::last_reset` tells the host's apart.
+#![no_std]
+
+use avr_device::atmega128::CPU;
+use avr_device::interrupt::Mutex;
+use core::cell::Cell;
+
+use crate::hal::regs::mcucsr;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum ResetReason {
+    PowerOn = 0,
+    /// RESET pin pulled low
+    External = 1,
+    BrownOut = 2,
+    Watchdog = 3,
+    /// JTAG AVR_RESET instruction
+    Jtag = 4,
+    /// No flag set, e.g. a jump to the reset vector
+    Unknown = 5,
+}
+
+const REASON_NAMES: [&str; 6] = ["power-on", "external", "brown-out", "watchdog", "jtag", "unknown"];
+
+impl ResetReason {
+    /// The most telling source when several flags are set: a power-on
+    /// explains everything after it, a watchdog reset only itself
+    pub fn from_flags(flags: u8) -> Self {
+        if flags & mcucsr::PORF != 0 {
+            ResetReason::PowerOn
+        } else if flags & mcucsr::BORF != 0 {
+            ResetReason::BrownOut
+        } else if flags & mcucsr::EXTRF != 0 {
+            ResetReason::External
+        } else if flags & mcucsr::WDRF != 0 {
+            ResetReason::Watchdog
+        } else if flags & mcucsr::JTRF != 0 {
+            ResetReason::Jtag
+        } else {
+            ResetReason::Unknown
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        REASON_NAMES[self as usize]
+    }
+
+    /// Not a normal power cycle or reset button press
+    pub fn is_fault(self) -> bool {
+        matches!(self, ResetReason::BrownOut | ResetReason::Watchdog | ResetReason::Unknown)
+    }
+}
+
+static FLAGS: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+/// Read and clear the reset flags. Call once, first thing in `main`.
+pub fn init() -> ResetReason {
+    let flags = unsafe {
+        let cpu = &*CPU::ptr();
+        let flags = cpu.mcucsr.read().bits() & mcucsr::FLAGS_MASK;
+        // JTD written back unchanged, which needs no timed sequence
+        cpu.mcucsr.modify(|r, w| w.bits(r.bits() & !mcucsr::FLAGS_MASK));
+        flags
+    };
+    avr_device::interrupt::free(|cs| FLAGS.borrow(cs).set(flags));
+    ResetReason::from_flags(flags)
+}
+
+/// Reason found by `init`
+pub fn reason() -> ResetReason {
+    ResetReason::from_flags(flags())
+}
+
+/// Raw MCUCSR reset flags found by `init`
+pub fn flags() -> u8 {
+    avr_device::interrupt::free(|cs| FLAGS.borrow(cs).get())
+}
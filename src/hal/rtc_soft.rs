@@ -0,0 +1,164 @@
+//! Wall clock on Timer0 in asynchronous mode
+//!
+//! With a 32.768kHz watch crystal on TOSC1/TOSC2, Timer0 keeps counting in
+//! PowerSave while the rest of the chip sleeps. It runs at /64, 512 counts
+//! per second, and overflows every half second; the overflow interrupt both
+//! advances the clock and wakes the CPU, which also keeps a 1s watchdog fed.
+//!
+//! Timer0 is the system timebase too, so starting the RTC stops
+//! `systime`. The overflow interrupt carries the scheduler tick on by
+//! 500ms instead, so `millis` keeps running in half-second steps and
+//! `systime::micros` reads the counter in between, at about 2ms
+//! resolution. Timeouts and frame timing keep working, only coarser.
+//!
+//! `now` counts wall-clock seconds from whatever epoch `set_time` was given
+//! (the host sends Unix time), and seconds since `start` until then.
+#![no_std]
+
+use avr_device::atmega128::TC0;
+use avr_device::interrupt::Mutex;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::hal::claims::{self, Resource};
+use crate::hal::systime;
+use crate::os::SCHEDULER;
+
+/// Timer counts per second, /64 from the crystal
+pub const COUNTS_PER_SECOND: u32 = 512;
+const OVERFLOWS_PER_SECOND: u32 = 2;
+const MS_PER_OVERFLOW: u32 = 1000 / OVERFLOWS_PER_SECOND;
+
+// CS02 = clk/64 once AS0 selects the crystal, normal mode
+const TCCR0_ASYNC_DIV64: u8 = 0x04;
+// ASSR
+const AS0: u8 = 1 << 3;
+const TCN0UB: u8 = 1 << 2;
+const OCR0UB: u8 = 1 << 1;
+const TCR0UB: u8 = 1 << 0;
+const TOIE0: u8 = 1 << 0;
+const TOV0: u8 = 1 << 0;
+
+static OVERFLOWS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// Wall-clock seconds at `start`
+static EPOCH: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static TIME_SET: AtomicBool = AtomicBool::new(false);
+
+/// Switch Timer0 to the crystal and start counting from zero. The crystal
+/// needs up to a second to settle after power-up, call this early.
+pub fn start() {
+    if systime::is_running() {
+        systime::stop();
+    }
+    claims::claim(Resource::Timer0, "rtc_soft").ok();
+    unsafe {
+        let p = TC0::ptr();
+        (*p).timsk.modify(|r, w| w.bits(r.bits() & !0x03));
+        (*p).assr.write(|w| w.bits(AS0));
+        (*p).tcnt0.write(|w| w.bits(0));
+        (*p).tccr0.write(|w| w.bits(TCCR0_ASYNC_DIV64));
+        // Updates cross into the asynchronous clock domain
+        while (*p).assr.read().bits() & (TCN0UB | TCR0UB) != 0 {}
+        (*p).tifr.write(|w| w.bits(TOV0));
+        (*p).timsk.modify(|r, w| w.bits(r.bits() | TOIE0));
+    }
+    avr_device::interrupt::free(|cs| OVERFLOWS.borrow(cs).set(0));
+    RUNNING.store(true, Ordering::SeqCst);
+}
+
+/// Stop the clock and give Timer0 up, e.g. to restart `systime`. The wall
+/// clock is lost.
+pub fn stop() {
+    unsafe {
+        let p = TC0::ptr();
+        (*p).timsk.modify(|r, w| w.bits(r.bits() & !TOIE0));
+        (*p).tccr0.write(|w| w.bits(0));
+        (*p).assr.write(|w| w.bits(0));
+    }
+    RUNNING.store(false, Ordering::SeqCst);
+    TIME_SET.store(false, Ordering::SeqCst);
+    claims::release(Resource::Timer0, "rtc_soft");
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// Timer counts since `start`, `COUNTS_PER_SECOND` per second. Wraps after
+/// ~97 days, compare through `wrapping_sub`.
+pub fn counts() -> u32 {
+    avr_device::interrupt::free(|cs| unsafe {
+        let p = TC0::ptr();
+        let mut overflows = OVERFLOWS.borrow(cs).get();
+        let count = (*p).tcnt0.read().bits();
+        // Overflow that happened after interrupts were masked
+        if (*p).tifr.read().bits() & TOV0 != 0 && count < 0x80 {
+            overflows += 1;
+        }
+        (overflows << 8) | count as u32
+    })
+}
+
+/// Microseconds on the scheduler tick while the RTC has Timer0, for
+/// `systime::micros64`
+pub(crate) fn micros64() -> u64 {
+    avr_device::interrupt::free(|_| unsafe {
+        let p = TC0::ptr();
+        let mut ms = SCHEDULER.get_ticks64();
+        let count = (*p).tcnt0.read().bits();
+        // Overflow that happened after interrupts were masked
+        if (*p).tifr.read().bits() & TOV0 != 0 && count < 0x80 {
+            ms += MS_PER_OVERFLOW as u64;
+        }
+        ms * 1000 + count as u64 * 1_000_000 / COUNTS_PER_SECOND as u64
+    })
+}
+
+pub fn counts_to_ms(counts: u32) -> u32 {
+    (counts as u64 * 1000 / COUNTS_PER_SECOND as u64) as u32
+}
+
+/// Seconds since `start`
+pub fn uptime() -> u32 {
+    avr_device::interrupt::free(|cs| OVERFLOWS.borrow(cs).get()) / OVERFLOWS_PER_SECOND
+}
+
+/// Set the wall clock. The fraction of the current second is not
+/// adjusted, so the clock may be up to a second behind.
+pub fn set_time(seconds: u32) {
+    let uptime = uptime();
+    avr_device::interrupt::free(|cs| EPOCH.borrow(cs).set(seconds.wrapping_sub(uptime)));
+    TIME_SET.store(true, Ordering::SeqCst);
+}
+
+/// Whether `set_time` was called since `start`
+pub fn is_time_set() -> bool {
+    TIME_SET.load(Ordering::SeqCst)
+}
+
+/// Wall-clock seconds, seconds since `start` until the time is set
+pub fn now() -> u32 {
+    avr_device::interrupt::free(|cs| EPOCH.borrow(cs).get()).wrapping_add(uptime())
+}
+
+/// Call right before entering PowerSave. Sleeping before the last register
+/// update has reached the asynchronous domain would lose the wake-up, so
+/// this rewrites TCCR0 and waits for it to go through, which also makes
+/// sure the overflow interrupt that just woke the CPU has finished.
+pub fn prepare_sleep() {
+    unsafe {
+        let p = TC0::ptr();
+        (*p).tccr0.write(|w| w.bits(TCCR0_ASYNC_DIV64));
+        while (*p).assr.read().bits() & (TCR0UB | OCR0UB | TCN0UB) != 0 {}
+    }
+}
+
+#[avr_device::interrupt(atmega128)]
+fn TIMER0_OVF() {
+    avr_device::interrupt::free(|cs| {
+        let overflows = OVERFLOWS.borrow(cs);
+        overflows.set(overflows.get().wrapping_add(1));
+    });
+    SCHEDULER.advance(MS_PER_OVERFLOW);
+}
@@ -2,18 +2,135 @@
 #![no_std]
 
 use avr_device::atmega128::SPI;
+use avr_device::interrupt::Mutex;
+use core::cell::{Cell, RefCell};
 use core::marker::PhantomData;
 
-/// SPI clock prescaler options
-#[derive(Clone, Copy)]
+use crate::hal::claims::{self, Resource};
+use crate::hal::clock::CPU_FREQ;
+use crate::hal::gpio::{DynPin, Output, Pin, PinOps};
+use crate::hal::regs::{spcr, spsr};
+
+// Buffer size must be power of 2 for efficient masking
+const SPI_BUFFER_SIZE: usize = 64;
+const SPI_BUFFER_MASK: usize = SPI_BUFFER_SIZE - 1;
+
+struct SpiBuffer {
+    data: [u8; SPI_BUFFER_SIZE],
+    write_idx: usize,
+    read_idx: usize,
+}
+
+impl SpiBuffer {
+    const fn new() -> Self {
+        Self {
+            data: [0; SPI_BUFFER_SIZE],
+            write_idx: 0,
+            read_idx: 0,
+        }
+    }
+
+    fn write(&mut self, byte: u8) -> bool {
+        let next_write = (self.write_idx + 1) & SPI_BUFFER_MASK;
+        if next_write != self.read_idx {
+            self.data[self.write_idx] = byte;
+            self.write_idx = next_write;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        if self.read_idx != self.write_idx {
+            let byte = self.data[self.read_idx];
+            self.read_idx = (self.read_idx + 1) & SPI_BUFFER_MASK;
+            Some(byte)
+        } else {
+            None
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.read_idx == self.write_idx
+    }
+}
+
+// Buffers shared with the SPI_STC interrupt
+static SPI_TX_BUFFER: Mutex<RefCell<SpiBuffer>> = Mutex::new(RefCell::new(SpiBuffer::new()));
+static SPI_RX_BUFFER: Mutex<RefCell<SpiBuffer>> = Mutex::new(RefCell::new(SpiBuffer::new()));
+static SPI_BUSY: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static SPI_RX_OVERRUN: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+// Software loopback and the byte last written to SPDR, echoed back in
+// place of MISO
+static SPI_LOOPBACK: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static SPI_LAST_SENT: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+// SPIF polls before a loopback transfer counts as stuck, far more than the
+// 256 cycles a byte takes at Fosc/128
+const LOOPBACK_POLLS: u16 = 1000;
+
+/// SPI clock prescaler options. The low two bits are SPR1:0, bit 2 is
+/// SPI2X which doubles the clock (master mode only).
+#[derive(Clone, Copy, PartialEq, Debug)]
 #[repr(u8)]
 pub enum SpiPrescaler {
+    Div2 = 4,
     Div4 = 0,
+    Div8 = 5,
     Div16 = 1,
+    Div32 = 6,
     Div64 = 2,
     Div128 = 3,
 }
 
+// Fastest first
+const PRESCALERS: [SpiPrescaler; 7] = [
+    SpiPrescaler::Div2,
+    SpiPrescaler::Div4,
+    SpiPrescaler::Div8,
+    SpiPrescaler::Div16,
+    SpiPrescaler::Div32,
+    SpiPrescaler::Div64,
+    SpiPrescaler::Div128,
+];
+
+impl SpiPrescaler {
+    pub const fn divisor(self) -> u32 {
+        match self {
+            SpiPrescaler::Div2 => 2,
+            SpiPrescaler::Div4 => 4,
+            SpiPrescaler::Div8 => 8,
+            SpiPrescaler::Div16 => 16,
+            SpiPrescaler::Div32 => 32,
+            SpiPrescaler::Div64 => 64,
+            SpiPrescaler::Div128 => 128,
+        }
+    }
+
+    /// Fastest setting whose SCK doesn't exceed `hz`, `Div128` if even
+    /// that is too fast. Devices have a maximum clock, so never round up.
+    pub const fn for_frequency(hz: u32) -> Self {
+        let mut i = 0;
+        while i < PRESCALERS.len() {
+            if CPU_FREQ / PRESCALERS[i].divisor() <= hz {
+                return PRESCALERS[i];
+            }
+            i += 1;
+        }
+        SpiPrescaler::Div128
+    }
+
+    /// SCK frequency in Hz
+    pub const fn frequency(self) -> u32 {
+        CPU_FREQ / self.divisor()
+    }
+
+    fn double_speed(self) -> bool {
+        self as u8 & 0x04 != 0
+    }
+}
+
 /// SPI data order
 #[derive(Clone, Copy)]
 pub enum DataOrder {
@@ -33,16 +150,18 @@ pub enum SpiMode {
 /// SPI peripheral driver
 pub struct Spi {
     _spi: PhantomData<SPI>,
+    interrupt_mode: bool,
 }
 
 impl Spi {
     /// Create new SPI instance
     pub fn new() -> Self {
+        claims::claim(Resource::Spi, "spi").ok();
         unsafe {
             let p = SPI::ptr();
             
             // Enable SPI, Master mode
-            (*p).spcr.write(|w| w.bits(0x50));
+            (*p).spcr.write(|w| w.bits(spcr::SPE | spcr::MSTR));
             
             // Default: Mode0, MSB first, Fosc/4
             (*p).spcr.modify(|_, w| {
@@ -51,7 +170,10 @@ impl Spi {
             });
         }
         
-        Self { _spi: PhantomData }
+        Self {
+            _spi: PhantomData,
+            interrupt_mode: false,
+        }
     }
 
     /// Configure SPI mode
@@ -61,10 +183,10 @@ impl Spi {
             (*p).spcr.modify(|r, w| {
                 let bits = r.bits();
                 let bits = match mode {
-                    SpiMode::Mode0 => bits & !0x0C,
-                    SpiMode::Mode1 => (bits & !0x0C) | 0x04,
-                    SpiMode::Mode2 => (bits & !0x0C) | 0x08,
-                    SpiMode::Mode3 => bits | 0x0C,
+                    SpiMode::Mode0 => bits & !spcr::MODE_MASK,
+                    SpiMode::Mode1 => (bits & !spcr::MODE_MASK) | spcr::CPHA,
+                    SpiMode::Mode2 => (bits & !spcr::MODE_MASK) | spcr::CPOL,
+                    SpiMode::Mode3 => bits | spcr::MODE_MASK,
                 };
                 w.bits(bits)
             });
@@ -76,24 +198,125 @@ impl Spi {
         unsafe {
             let p = SPI::ptr();
             (*p).spcr.modify(|r, w| {
-                w.bits((r.bits() & !0x03) | (prescaler as u8))
+                w.bits((r.bits() & !spcr::SPR_MASK) | (prescaler as u8 & spcr::SPR_MASK))
+            });
+            (*p).spsr.modify(|r, w| {
+                if prescaler.double_speed() {
+                    w.bits(r.bits() | spsr::SPI2X)
+                } else {
+                    w.bits(r.bits() & !spsr::SPI2X)
+                }
             });
         }
     }
 
+    /// Set the fastest SCK not above `hz`, returns the frequency in use
+    pub fn set_frequency(&mut self, hz: u32) -> u32 {
+        let prescaler = SpiPrescaler::for_frequency(hz);
+        self.set_clock(prescaler);
+        prescaler.frequency()
+    }
+
     /// Set data order (MSB/LSB first)
     pub fn set_data_order(&mut self, order: DataOrder) {
         unsafe {
             let p = SPI::ptr();
             match order {
-                DataOrder::MsbFirst => (*p).spcr.modify(|r, w| w.bits(r.bits() & !0x20)),
-                DataOrder::LsbFirst => (*p).spcr.modify(|r, w| w.bits(r.bits() | 0x20)),
+                DataOrder::MsbFirst => (*p).spcr.modify(|r, w| w.bits(r.bits() & !spcr::DORD)),
+                DataOrder::LsbFirst => (*p).spcr.modify(|r, w| w.bits(r.bits() | spcr::DORD)),
             }
         }
     }
 
+    /// Switch between busy-wait transfers and interrupt-driven transfers
+    /// through `transfer_async`
+    pub fn set_interrupt_mode(&mut self, enabled: bool) {
+        if !enabled {
+            while !self.poll_complete() {}
+        }
+        unsafe {
+            let p = SPI::ptr();
+            if enabled {
+                (*p).spcr.modify(|r, w| w.bits(r.bits() | spcr::SPIE));
+            } else {
+                (*p).spcr.modify(|r, w| w.bits(r.bits() & !spcr::SPIE));
+            }
+        }
+        self.interrupt_mode = enabled;
+    }
+
+    /// Queue bytes for an interrupt-driven transfer and start shifting if
+    /// idle. Returns how many bytes fit in the TX buffer; received bytes are
+    /// collected with `read_async`. Chip select must stay asserted until
+    /// `poll_complete` returns true.
+    pub fn transfer_async(&mut self, data: &[u8]) -> usize {
+        if !self.interrupt_mode {
+            return 0;
+        }
+
+        avr_device::interrupt::free(|cs| {
+            let mut tx = SPI_TX_BUFFER.borrow(cs).borrow_mut();
+            let mut queued = 0;
+            for &byte in data {
+                if !tx.write(byte) {
+                    break;
+                }
+                queued += 1;
+            }
+
+            // Kick off the first byte, the ISR feeds the rest
+            let busy = SPI_BUSY.borrow(cs);
+            if !busy.get() {
+                if let Some(byte) = tx.read() {
+                    busy.set(true);
+                    SPI_LAST_SENT.borrow(cs).set(byte);
+                    unsafe { (*SPI::ptr()).spdr.write(|w| w.bits(byte)) };
+                }
+            }
+            queued
+        })
+    }
+
+    /// Drain received bytes into `buffer`, returns the count copied
+    pub fn read_async(&mut self, buffer: &mut [u8]) -> usize {
+        avr_device::interrupt::free(|cs| {
+            let mut rx = SPI_RX_BUFFER.borrow(cs).borrow_mut();
+            let mut count = 0;
+            while count < buffer.len() {
+                match rx.read() {
+                    Some(byte) => {
+                        buffer[count] = byte;
+                        count += 1;
+                    }
+                    None => break,
+                }
+            }
+            count
+        })
+    }
+
+    /// True once every queued byte has been shifted out
+    pub fn poll_complete(&self) -> bool {
+        avr_device::interrupt::free(|cs| {
+            !SPI_BUSY.borrow(cs).get() && SPI_TX_BUFFER.borrow(cs).borrow().is_empty()
+        })
+    }
+
+    /// True (once) if received bytes were dropped because nobody read them
+    pub fn take_overrun(&mut self) -> bool {
+        avr_device::interrupt::free(|cs| SPI_RX_OVERRUN.borrow(cs).replace(false))
+    }
+
     /// Transfer a single byte
     pub fn transfer(&mut self, byte: u8) -> u8 {
+        if self.interrupt_mode {
+            // Let queued bytes finish and keep the ISR out of the way
+            self.set_interrupt_mode(false);
+            let received = self.transfer(byte);
+            self.set_interrupt_mode(true);
+            return received;
+        }
+
         unsafe {
             let p = SPI::ptr();
             
@@ -101,10 +324,78 @@ impl Spi {
             (*p).spdr.write(|w| w.bits(byte));
             
             // Wait for transmission complete
-            while (*p).spsr.read().bits() & 0x80 == 0 {}
+            while (*p).spsr.read().bits() & spsr::SPIF == 0 {}
             
             // Read received byte
-            (*p).spdr.read().bits()
+            let received = (*p).spdr.read().bits();
+            if self.is_loopback() {
+                byte
+            } else {
+                received
+            }
+        }
+    }
+
+    /// Software loopback for self-tests on a board without a MOSI to MISO
+    /// jumper. Bytes are still shifted out by the hardware, but each
+    /// transfer returns the byte that was sent instead of the one clocked
+    /// in on MISO. A master that fell back to slave mode (SS pulled low)
+    /// or a transfer that never completes still shows up as a failure in
+    /// `loopback_check`.
+    pub fn set_loopback(&mut self, enabled: bool) {
+        while !self.poll_complete() {}
+        avr_device::interrupt::free(|cs| SPI_LOOPBACK.borrow(cs).set(enabled));
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        avr_device::interrupt::free(|cs| SPI_LOOPBACK.borrow(cs).get())
+    }
+
+    /// Send `pattern` in loopback mode and compare the echo. Returns the
+    /// index of the first byte that didn't come back.
+    pub fn loopback_check(&mut self, pattern: &[u8]) -> Result<(), usize> {
+        let was_loopback = self.is_loopback();
+        let interrupts = self.interrupt_mode;
+        if interrupts {
+            self.set_interrupt_mode(false);
+        }
+        self.set_loopback(true);
+
+        let mut result = Ok(());
+        for (i, &byte) in pattern.iter().enumerate() {
+            let master = unsafe { (*SPI::ptr()).spcr.read().bits() & spcr::MSTR != 0 };
+            if !master {
+                result = Err(i);
+                break;
+            }
+            match self.transfer_timeout(byte, LOOPBACK_POLLS) {
+                Some(echo) if echo == byte => {}
+                _ => {
+                    result = Err(i);
+                    break;
+                }
+            }
+        }
+
+        self.set_loopback(was_loopback);
+        if interrupts {
+            self.set_interrupt_mode(true);
+        }
+        result
+    }
+
+    // Busy-wait transfer that gives up after `polls` SPIF checks
+    fn transfer_timeout(&mut self, byte: u8, polls: u16) -> Option<u8> {
+        unsafe {
+            let p = SPI::ptr();
+            (*p).spdr.write(|w| w.bits(byte));
+            for _ in 0..polls {
+                if (*p).spsr.read().bits() & spsr::SPIF != 0 {
+                    let received = (*p).spdr.read().bits();
+                    return Some(if self.is_loopback() { byte } else { received });
+                }
+            }
+            None
         }
     }
 
@@ -121,3 +412,101 @@ impl Default for Spi {
         Self::new()
     }
 }
+
+/// Chip select line of an SPI device, active low
+pub trait ChipSelect {
+    fn select(&mut self);
+    fn deselect(&mut self);
+}
+
+impl<PORT, const P: u8> ChipSelect for Pin<PORT, P, Output>
+where
+    Self: PinOps,
+{
+    fn select(&mut self) {
+        self.set_low();
+    }
+
+    fn deselect(&mut self) {
+        self.set_high();
+    }
+}
+
+impl ChipSelect for DynPin {
+    fn select(&mut self) {
+        self.set_low();
+    }
+
+    fn deselect(&mut self) {
+        self.set_high();
+    }
+}
+
+/// One device on the shared SPI bus: its chip select plus the mode and
+/// clock it needs, applied at the start of every transaction
+pub struct SpiDevice<CS> {
+    cs: CS,
+    mode: SpiMode,
+    prescaler: SpiPrescaler,
+}
+
+impl<CS: ChipSelect> SpiDevice<CS> {
+    pub fn new(mut cs: CS, mode: SpiMode, prescaler: SpiPrescaler) -> Self {
+        cs.deselect();
+        Self { cs, mode, prescaler }
+    }
+
+    /// Run `f` with the bus set up for this device and its chip select
+    /// asserted. Chip select is released when `f` returns, early returns
+    /// through `?` inside `f` included.
+    pub fn transaction<R>(&mut self, spi: &mut Spi, f: impl FnOnce(&mut Spi) -> R) -> R {
+        spi.set_mode(self.mode);
+        spi.set_clock(self.prescaler);
+        let _selected = Selected::new(&mut self.cs);
+        f(spi)
+    }
+
+    /// Give back the chip select pin
+    pub fn release(self) -> CS {
+        self.cs
+    }
+}
+
+// Holds chip select asserted for its lifetime
+struct Selected<'a, CS: ChipSelect> {
+    cs: &'a mut CS,
+}
+
+impl<'a, CS: ChipSelect> Selected<'a, CS> {
+    fn new(cs: &'a mut CS) -> Self {
+        cs.select();
+        Self { cs }
+    }
+}
+
+impl<CS: ChipSelect> Drop for Selected<'_, CS> {
+    fn drop(&mut self) {
+        self.cs.deselect();
+    }
+}
+
+#[avr_device::interrupt(atmega128)]
+fn SPI_STC() {
+    avr_device::interrupt::free(|cs| unsafe {
+        let p = SPI::ptr();
+        let mut received = (*p).spdr.read().bits();
+        if SPI_LOOPBACK.borrow(cs).get() {
+            received = SPI_LAST_SENT.borrow(cs).get();
+        }
+        if !SPI_RX_BUFFER.borrow(cs).borrow_mut().write(received) {
+            SPI_RX_OVERRUN.borrow(cs).set(true);
+        }
+
+        if let Some(byte) = SPI_TX_BUFFER.borrow(cs).borrow_mut().read() {
+            SPI_LAST_SENT.borrow(cs).set(byte);
+            (*p).spdr.write(|w| w.bits(byte));
+        } else {
+            SPI_BUSY.borrow(cs).set(false);
+        }
+    });
+}
@@ -0,0 +1,137 @@
+//! System timebase on Timer0
+//!
+//! Timer0 runs in CTC mode at clk/64 and interrupts once per millisecond.
+//! The interrupt advances the scheduler tick (`os::SCHEDULER`), which is
+//! the millisecond clock everything else reads; `micros` adds the running
+//! counter for 4us resolution at 16MHz. The 64-bit tick never wraps, the
+//! 32-bit `millis` after ~49.7 days and `micros` after ~71.6 minutes, so
+//! compare those through `wrapping_sub`.
+//!
+//! At clocks where a millisecond isn't a whole number of counts (115.2 at
+//! 7.3728MHz) the interrupt carries the fraction over and stretches a
+//! period by one count whenever it adds up to a whole one, so the tick
+//! keeps time with the crystal instead of running fast.
+//!
+//! The crystal clock (`hal::rtc_soft`) takes Timer0 over for PowerSave and
+//! stops the timebase while it runs; it advances the tick itself then, in
+//! half-second steps.
+#![no_std]
+
+use avr_device::atmega128::TC0;
+use avr_device::interrupt::Mutex;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::hal::claims::{self, Resource};
+use crate::hal::clock;
+use crate::hal::rtc_soft;
+use crate::hal::watchdog;
+use crate::os::SCHEDULER;
+
+const PRESCALER: u32 = 64;
+/// Whole counts per millisecond, the exact figure is
+/// `COUNTS_PER_MS + COUNT_FRACTION / FRACTION_ONE`
+const COUNTS_PER_MS: u32 = clock::ticks_per_ms(PRESCALER);
+const FRACTION_ONE: u32 = PRESCALER * 1000;
+const COUNT_FRACTION: u32 = clock::CPU_FREQ % FRACTION_ONE;
+const _: () = assert!(
+    COUNTS_PER_MS + (COUNT_FRACTION != 0) as u32 <= 256,
+    "systime needs a larger Timer0 prescaler"
+);
+
+// WGM01 = CTC, CS02 = clk/64 (Timer0 has its own prescaler table)
+const TCCR0_CTC_DIV64: u8 = 0x0C;
+const OCIE0: u8 = 1 << 1;
+const OCF0: u8 = 1 << 1;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// Fraction of a count carried between ticks, in 1/`FRACTION_ONE`
+static CARRY: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Start the 1ms tick. Call once during init, before interrupts are
+/// enabled.
+pub fn start() {
+    claims::claim(Resource::Timer0, "systime").ok();
+    unsafe {
+        let p = TC0::ptr();
+        (*p).timsk.modify(|r, w| w.bits(r.bits() & !0x03));
+        // Synchronous clock, the data logger may have left it on the crystal
+        (*p).assr.write(|w| w.bits(0));
+        (*p).tcnt0.write(|w| w.bits(0));
+        (*p).ocr0.write(|w| w.bits((COUNTS_PER_MS - 1) as u8));
+        avr_device::interrupt::free(|cs| CARRY.borrow(cs).set(0));
+        (*p).tccr0.write(|w| w.bits(TCCR0_CTC_DIV64));
+        (*p).tifr.write(|w| w.bits(OCF0));
+        (*p).timsk.modify(|r, w| w.bits(r.bits() | OCIE0));
+    }
+    RUNNING.store(true, Ordering::SeqCst);
+}
+
+/// Stop the tick and give Timer0 up. Time stands still until `start`.
+pub fn stop() {
+    unsafe {
+        let p = TC0::ptr();
+        (*p).timsk.modify(|r, w| w.bits(r.bits() & !OCIE0));
+        (*p).tccr0.write(|w| w.bits(0));
+    }
+    RUNNING.store(false, Ordering::SeqCst);
+    claims::release(Resource::Timer0, "systime");
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// Milliseconds since `start`, wraps after ~49.7 days
+#[inline]
+pub fn millis() -> u32 {
+    SCHEDULER.get_ticks()
+}
+
+/// Milliseconds since `start` as a 64-bit count that never wraps
+pub fn ticks64() -> u64 {
+    SCHEDULER.get_ticks64()
+}
+
+/// Microseconds since `start`, wraps after ~71.6 minutes
+pub fn micros() -> u32 {
+    micros64() as u32
+}
+
+/// Microseconds since `start` as a 64-bit count
+pub fn micros64() -> u64 {
+    if rtc_soft::is_running() {
+        return rtc_soft::micros64();
+    }
+    avr_device::interrupt::free(|_| {
+        let p = TC0::ptr();
+        let mut ms = SCHEDULER.get_ticks64();
+        let (counts, pending) = unsafe {
+            ((*p).tcnt0.read().bits(), (*p).tifr.read().bits() & OCF0 != 0)
+        };
+        // The counter cleared after interrupts were disabled, the tick for
+        // that millisecond is still pending
+        if pending && (counts as u32) < COUNTS_PER_MS / 2 {
+            ms += 1;
+        }
+        ms * 1000 + (counts as u64 * 1000 / COUNTS_PER_MS as u64).min(999)
+    })
+}
+
+#[avr_device::interrupt(atmega128)]
+fn TIMER0_COMP() {
+    if COUNT_FRACTION != 0 {
+        // OCR0 isn't buffered in CTC mode, the new top applies to the
+        // period that just started
+        let stretch = avr_device::interrupt::free(|cs| {
+            let carry = CARRY.borrow(cs);
+            let next = carry.get() + COUNT_FRACTION;
+            carry.set(next % FRACTION_ONE);
+            next >= FRACTION_ONE
+        });
+        let top = COUNTS_PER_MS - 1 + stretch as u32;
+        unsafe { (*TC0::ptr()).ocr0.write(|w| w.bits(top as u8)) };
+    }
+    SCHEDULER.tick();
+    watchdog::tick();
+}
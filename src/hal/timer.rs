@@ -1,6 +1,19 @@
-use avr_device::atmega128::{TC0, TC1};
+//! Timers
+//!
+//! `Timer` drives the 8-bit Timer0. `Timer16` covers the 16-bit Timer1
+//! and Timer3 with their full counter, three compare channels and the
+//! input capture register, which the 8-bit register layout can't reach.
+//!
+//! The 16-bit registers go through the shared TEMP byte, so every 16-bit
+//! access runs with interrupts masked; an ISR touching another 16-bit
+//! timer register in between would otherwise corrupt it.
+
+use avr_device::atmega128::{CPU, TC0, TC1, TC3};
 use core::marker::PhantomData;
 
+use crate::hal::regs::tccr1b;
+use crate::hal::{clock, systime};
+
 pub trait TimerRegisterBlock {
     fn ptr() -> *mut avr_device::atmega128::tc0::RegisterBlock;
     const PRESCALER_MASK: u8;
@@ -13,13 +26,6 @@ impl TimerRegisterBlock for TC0 {
     const PRESCALER_MASK: u8 = 0x07;
 }
 
-impl TimerRegisterBlock for TC1 {
-    fn ptr() -> *mut avr_device::atmega128::tc0::RegisterBlock {
-        TC1::ptr() as *mut _
-    }
-    const PRESCALER_MASK: u8 = 0x07;
-}
-
 #[derive(Clone, Copy)]
 pub enum Prescaler {
     Stop = 0,
@@ -106,18 +112,287 @@ impl<T: TimerRegisterBlock> Timer<T> {
     }
 }
 
-// Millisecond delay using Timer0
+// Timer0 counts per millisecond at clk/64, must fit the 8-bit counter
+const DELAY_TICKS_PER_MS: u32 = clock::ticks_per_ms(64);
+const _: () = assert!(DELAY_TICKS_PER_MS <= 255, "delay_ms needs a slower clock or larger prescaler");
+
+// Millisecond delay using Timer0, on the system tick once it runs
 pub fn delay_ms(ms: u16) {
+    // The tick only advances with interrupts enabled (SREG I)
+    let interrupts = unsafe { (*CPU::ptr()).sreg.read().bits() & 0x80 != 0 };
+    if systime::is_running() && interrupts {
+        let start = systime::millis();
+        while systime::millis().wrapping_sub(start) < ms as u32 {}
+        return;
+    }
+
     let mut timer = Timer::<TC0>::new();
     
-    // Configure for 1ms ticks (16MHz/64 = 250kHz, 250 ticks = 1ms)
+    // Configure for 1ms ticks at clk/64
     timer.set_counter(0);
     timer.start(Prescaler::Div64);
 
     for _ in 0..ms {
-        while timer.get_counter() < 250 {}
+        while timer.get_counter() < DELAY_TICKS_PER_MS as u8 {}
         timer.set_counter(0);
     }
 
     timer.stop();
-} 
\ No newline at end of file
+}
+
+/// Compare unit of a 16-bit timer
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CompareChannel {
+    A,
+    B,
+    C,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TimerInterrupt {
+    Overflow,
+    Compare(CompareChannel),
+    Capture,
+}
+
+/// Counting mode of a 16-bit timer
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Timer16Mode {
+    /// Count up to 0xFFFF and wrap (WGM 0)
+    Normal,
+    /// Clear on compare match with OCRnA, which sets the period (WGM 4)
+    CtcOcrA,
+    /// Clear on compare match with ICRn, all three compare channels stay
+    /// free (WGM 12)
+    CtcIcr,
+    /// Fast PWM with ICRn as top (WGM 14)
+    FastPwmIcr,
+    /// Phase correct PWM with ICRn as top (WGM 10)
+    PhaseCorrectIcr,
+    /// Phase and frequency correct PWM with ICRn as top (WGM 8)
+    PhaseFrequencyIcr,
+}
+
+/// What a compare match does to the OCnx pin (COMnx1:0)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CompareOutput {
+    /// The pin is a plain port pin
+    Disconnected,
+    /// Non-inverting PWM: cleared on the match (up-counting match in the
+    /// phase correct modes)
+    Clear,
+    /// Inverting PWM: set on the match
+    Set,
+}
+
+/// Edge latched by the input capture unit
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CaptureEdge {
+    Falling,
+    Rising,
+}
+
+impl Timer16Mode {
+    /// WGMn1:0 for TCCRnA and WGMn3:2 for TCCRnB
+    const fn wgm_bits(self) -> (u8, u8) {
+        let wgm = match self {
+            Timer16Mode::Normal => 0,
+            Timer16Mode::CtcOcrA => 4,
+            Timer16Mode::CtcIcr => 12,
+            Timer16Mode::FastPwmIcr => 14,
+            Timer16Mode::PhaseCorrectIcr => 10,
+            Timer16Mode::PhaseFrequencyIcr => 8,
+        };
+        (wgm & 0x03, (wgm & 0x0C) << 1)
+    }
+}
+
+const WGM_A_MASK: u8 = 0x03;
+const WGM_B_MASK: u8 = tccr1b::WGM13 | tccr1b::WGM12;
+// COMnx1:0 of channels A, B, C, TCCR3A has TCCR1A's layout
+const COM_SHIFT: [u8; 3] = [6, 4, 2];
+
+// Interrupt enable bits, the same positions in TIMSK (Timer1) and ETIMSK
+// (Timer3). OCIE1C lives in ETIMSK bit 0, OCIE3C in ETIMSK bit 1, and
+// their flags OCF1C/OCF3C at the same bits of ETIFR.
+const TOIE: u8 = 1 << 2;
+const OCIEB: u8 = 1 << 3;
+const OCIEA: u8 = 1 << 4;
+const TICIE: u8 = 1 << 5;
+const OCIE1C: u8 = 1 << 0;
+const OCIE3C: u8 = 1 << 1;
+
+/// 16-bit Timer1 or Timer3
+pub struct Timer16<T> {
+    _timer: PhantomData<T>,
+}
+
+macro_rules! impl_timer16 {
+    ($TC:ident, $tccra:ident, $tccrb:ident, $tcnt:ident, $ocra:ident, $ocrb:ident, $ocrc:ident,
+     $icr:ident, $timsk:ident, $tifr:ident, $ocie_c:expr) => {
+        impl Timer16<$TC> {
+            /// Stopped, normal mode, compare outputs disconnected and its
+            /// interrupts off
+            pub fn new() -> Self {
+                let mut timer = Self { _timer: PhantomData };
+                timer.stop();
+                unsafe {
+                    let p = $TC::ptr();
+                    (*p).$tccra.write(|w| w.bits(0));
+                    (*p).$tccrb.write(|w| w.bits(0));
+                }
+                for irq in [
+                    TimerInterrupt::Overflow,
+                    TimerInterrupt::Capture,
+                    TimerInterrupt::Compare(CompareChannel::A),
+                    TimerInterrupt::Compare(CompareChannel::B),
+                    TimerInterrupt::Compare(CompareChannel::C),
+                ] {
+                    timer.disable_interrupt(irq);
+                }
+                timer.set_counter(0);
+                timer
+            }
+
+            pub fn set_mode(&mut self, mode: Timer16Mode) {
+                let (a, b) = mode.wgm_bits();
+                unsafe {
+                    let p = $TC::ptr();
+                    (*p).$tccra.modify(|r, w| w.bits((r.bits() & !WGM_A_MASK) | a));
+                    (*p).$tccrb.modify(|r, w| {
+                        let bits = (r.bits() & !WGM_B_MASK) | b;
+                        debug_assert!(tccr1b::valid(a, bits));
+                        w.bits(bits)
+                    });
+                }
+            }
+
+            /// Connect a compare channel to its pin or disconnect it
+            pub fn set_output(&mut self, channel: CompareChannel, output: CompareOutput) {
+                let shift = COM_SHIFT[channel as usize];
+                let com = match output {
+                    CompareOutput::Disconnected => 0,
+                    CompareOutput::Clear => 0b10,
+                    CompareOutput::Set => 0b11,
+                };
+                unsafe {
+                    (*$TC::ptr()).$tccra.modify(|r, w| w.bits((r.bits() & !(0b11 << shift)) | com << shift));
+                }
+            }
+
+            /// Edge the input capture unit latches the counter on
+            pub fn set_capture_edge(&mut self, edge: CaptureEdge) {
+                unsafe {
+                    (*$TC::ptr()).$tccrb.modify(|r, w| match edge {
+                        CaptureEdge::Rising => w.bits(r.bits() | tccr1b::ICES1),
+                        CaptureEdge::Falling => w.bits(r.bits() & !tccr1b::ICES1),
+                    });
+                }
+            }
+
+            pub fn start(&mut self, prescaler: Prescaler) {
+                unsafe {
+                    (*$TC::ptr()).$tccrb.modify(|r, w| {
+                        w.bits((r.bits() & !tccr1b::CS_MASK) | prescaler as u8)
+                    });
+                }
+            }
+
+            pub fn stop(&mut self) {
+                unsafe {
+                    (*$TC::ptr()).$tccrb.modify(|r, w| w.bits(r.bits() & !tccr1b::CS_MASK));
+                }
+            }
+
+            pub fn counter(&self) -> u16 {
+                avr_device::interrupt::free(|_| unsafe { (*$TC::ptr()).$tcnt.read().bits() })
+            }
+
+            pub fn set_counter(&mut self, value: u16) {
+                avr_device::interrupt::free(|_| unsafe {
+                    (*$TC::ptr()).$tcnt.write(|w| w.bits(value));
+                });
+            }
+
+            /// Top of the ICR modes, the capture value otherwise
+            pub fn set_icr(&mut self, value: u16) {
+                avr_device::interrupt::free(|_| unsafe {
+                    (*$TC::ptr()).$icr.write(|w| w.bits(value));
+                });
+            }
+
+            /// Counter value latched by the last input capture event
+            pub fn capture(&self) -> u16 {
+                avr_device::interrupt::free(|_| unsafe { (*$TC::ptr()).$icr.read().bits() })
+            }
+
+            pub fn set_compare(&mut self, channel: CompareChannel, value: u16) {
+                avr_device::interrupt::free(|_| unsafe {
+                    let p = $TC::ptr();
+                    match channel {
+                        CompareChannel::A => (*p).$ocra.write(|w| w.bits(value)),
+                        CompareChannel::B => (*p).$ocrb.write(|w| w.bits(value)),
+                        CompareChannel::C => (*p).$ocrc.write(|w| w.bits(value)),
+                    }
+                });
+            }
+
+            pub fn compare(&self, channel: CompareChannel) -> u16 {
+                avr_device::interrupt::free(|_| unsafe {
+                    let p = $TC::ptr();
+                    match channel {
+                        CompareChannel::A => (*p).$ocra.read().bits(),
+                        CompareChannel::B => (*p).$ocrb.read().bits(),
+                        CompareChannel::C => (*p).$ocrc.read().bits(),
+                    }
+                })
+            }
+
+            pub fn enable_interrupt(&mut self, irq: TimerInterrupt) {
+                self.mask_interrupt(irq, true);
+            }
+
+            pub fn disable_interrupt(&mut self, irq: TimerInterrupt) {
+                self.mask_interrupt(irq, false);
+            }
+
+            fn mask_interrupt(&mut self, irq: TimerInterrupt, enable: bool) {
+                let apply = |bits: u8, mask: u8| if enable { bits | mask } else { bits & !mask };
+                unsafe {
+                    let p = $TC::ptr();
+                    match irq {
+                        TimerInterrupt::Compare(CompareChannel::C) => {
+                            if enable {
+                                (*p).etifr.write(|w| w.bits($ocie_c));
+                            }
+                            (*p).etimsk.modify(|r, w| w.bits(apply(r.bits(), $ocie_c)));
+                        }
+                        _ => {
+                            let mask = match irq {
+                                TimerInterrupt::Overflow => TOIE,
+                                TimerInterrupt::Capture => TICIE,
+                                TimerInterrupt::Compare(CompareChannel::A) => OCIEA,
+                                _ => OCIEB,
+                            };
+                            // Clear a stale flag before enabling (flags are
+                            // cleared by writing 1)
+                            if enable {
+                                (*p).$tifr.write(|w| w.bits(mask));
+                            }
+                            (*p).$timsk.modify(|r, w| w.bits(apply(r.bits(), mask)));
+                        }
+                    }
+                }
+            }
+        }
+
+        impl Default for Timer16<$TC> {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+    };
+}
+
+impl_timer16!(TC1, tccr1a, tccr1b, tcnt1, ocr1a, ocr1b, ocr1c, icr1, timsk, tifr, OCIE1C);
+impl_timer16!(TC3, tccr3a, tccr3b, tcnt3, ocr3a, ocr3b, ocr3c, icr3, etimsk, etifr, OCIE3C);
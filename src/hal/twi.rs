@@ -1,16 +1,64 @@
 //! TWI (I2C) HAL implementation
+//!
+//! Besides the blocking byte-level API the master can run queued
+//! transactions from the TWI interrupt: `write_read_async` copies the write
+//! data into a queue slot and returns a ticket, the interrupt state machine
+//! walks START, SLA+W, data, repeated START, SLA+R and STOP on its own, and
+//! the result is collected with `poll` or reported through a completion
+//! callback. Blocking calls wait for the queue to drain first.
 #![no_std]
 
-use avr_device::atmega128::TWI;
+use avr_device::atmega128::{PORTD, TWI};
+use avr_device::interrupt::Mutex;
+use core::cell::{Cell, RefCell};
 use core::marker::PhantomData;
 
+use crate::diagnostics::fault_inject;
+use crate::hal::claims::{self, Resource};
+use crate::hal::clock;
+use crate::hal::regs::twcr::{self, TWEA, TWEN, TWIE, TWINT, TWSTA, TWSTO};
+use crate::hal::regs::twsr;
+
+pub const TWI_QUEUE_LEN: usize = 4;
+/// Largest write part of an async transaction (register address + data)
+pub const TWI_MAX_WRITE: usize = 8;
+/// Largest read part, enough for an MPU6050 accel+temp+gyro burst
+pub const TWI_MAX_READ: usize = 14;
+
+// SCL and SDA are PD0 and PD1
+const SCL: u8 = 1 << 0;
+const SDA: u8 = 1 << 1;
+
+/// Per-operation timeout, several byte times at 100kHz
+const DEFAULT_TIMEOUT_US: u16 = 1000;
+/// Longest a slave may hold SCL low on top of the operation timeout
+const DEFAULT_STRETCH_US: u16 = 10_000;
+// set_frequency sizes the operation timeout to this many byte times
+const TIMEOUT_BYTES: u32 = 10;
+/// Longest wait for queued async transactions before a blocking START
+const QUEUE_DRAIN_TIMEOUT_US: u32 = 20_000;
+// TWINT polls per microsecond, about four cycles per iteration
+const POLLS_PER_US: u32 = clock::cycles_per_us() / 4;
+
 /// TWI speed modes
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum TwiSpeed {
+    /// For long cables and heavily loaded buses
+    Slow50k,
     Standard100k,
     Fast400k,
 }
 
+impl TwiSpeed {
+    pub fn hz(&self) -> u32 {
+        match self {
+            TwiSpeed::Slow50k => 50_000,
+            TwiSpeed::Standard100k => 100_000,
+            TwiSpeed::Fast400k => 400_000,
+        }
+    }
+}
+
 /// TWI status codes
 #[derive(Clone, Copy, PartialEq)]
 #[repr(u8)]
@@ -28,62 +76,241 @@ pub enum TwiStatus {
     DataReadNack = 0x58,
 }
 
+/// Why a blocking bus operation failed
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum TwiError {
+    ArbitrationLost = 1,
+    /// Address or data byte not acknowledged
+    Nack = 2,
+    /// Illegal START/STOP or an unexpected status code
+    BusError = 3,
+    /// TWINT never came
+    Timeout = 4,
+    /// A slave held SCL low for longer than the stretch timeout
+    ClockStretch = 5,
+}
+
+impl TwiError {
+    fn from_status(status: u8) -> Self {
+        match status {
+            0x38 => TwiError::ArbitrationLost,
+            0x20 | 0x30 | 0x48 => TwiError::Nack,
+            _ => TwiError::BusError,
+        }
+    }
+}
+
+/// Why an async transaction failed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TwiAsyncError {
+    /// Write or read part longer than the queue slots hold
+    TooLong,
+    /// All queue slots are in use
+    QueueFull,
+    /// Address or data byte not acknowledged
+    Nack,
+    ArbitrationLost,
+    /// Unexpected status code from the hardware
+    Bus(u8),
+}
+
+impl From<TwiError> for TwiAsyncError {
+    fn from(error: TwiError) -> Self {
+        match error {
+            TwiError::ArbitrationLost => TwiAsyncError::ArbitrationLost,
+            TwiError::Nack => TwiAsyncError::Nack,
+            // 0x00 is the hardware's bus error status
+            TwiError::BusError | TwiError::Timeout | TwiError::ClockStretch => TwiAsyncError::Bus(0x00),
+        }
+    }
+}
+
+/// Handle to a queued transaction
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TwiTicket {
+    slot: u8,
+    seq: u8,
+}
+
+/// Called from the TWI interrupt when a transaction finishes; read data is
+/// still collected with `Twi::poll`
+pub type TwiCallback = fn(TwiTicket, Result<(), TwiAsyncError>);
+
+#[derive(Clone, Copy, PartialEq)]
+enum SlotState {
+    Free,
+    Queued,
+    Active,
+    Done(Result<(), TwiAsyncError>),
+}
+
+#[derive(Clone, Copy)]
+struct Transaction {
+    state: SlotState,
+    seq: u8,
+    addr: u8,
+    write: [u8; TWI_MAX_WRITE],
+    write_len: u8,
+    read: [u8; TWI_MAX_READ],
+    read_len: u8,
+    pos: u8,
+    callback: Option<TwiCallback>,
+}
+
+impl Transaction {
+    const EMPTY: Self = Self {
+        state: SlotState::Free,
+        seq: 0,
+        addr: 0,
+        write: [0; TWI_MAX_WRITE],
+        write_len: 0,
+        read: [0; TWI_MAX_READ],
+        read_len: 0,
+        pos: 0,
+        callback: None,
+    };
+}
+
+struct TwiQueue {
+    slots: [Transaction; TWI_QUEUE_LEN],
+    /// Slots in submission order, `order[0]` is next
+    order: [u8; TWI_QUEUE_LEN],
+    pending: usize,
+    active: Option<u8>,
+    /// True while the write part is being sent
+    writing: bool,
+    next_seq: u8,
+}
+
+impl TwiQueue {
+    fn pop_next(&mut self) -> Option<u8> {
+        if self.pending == 0 {
+            return None;
+        }
+        let slot = self.order[0];
+        self.order.copy_within(1.., 0);
+        self.pending -= 1;
+        Some(slot)
+    }
+}
+
+// Shared with the TWI interrupt
+static TWI_QUEUE: Mutex<RefCell<TwiQueue>> = Mutex::new(RefCell::new(TwiQueue {
+    slots: [Transaction::EMPTY; TWI_QUEUE_LEN],
+    order: [0; TWI_QUEUE_LEN],
+    pending: 0,
+    active: None,
+    writing: false,
+    next_seq: 0,
+}));
+
+static LAST_ERROR: Mutex<Cell<Option<TwiError>>> = Mutex::new(Cell::new(None));
+
+/// Most recent blocking-operation error since the last call, for
+/// `Diagnostics::poll_twi_errors`
+pub fn take_last_error() -> Option<TwiError> {
+    avr_device::interrupt::free(|cs| LAST_ERROR.borrow(cs).take())
+}
+
 /// TWI peripheral driver
 pub struct Twi {
     _twi: PhantomData<TWI>,
+    timeout_us: u16,
+    stretch_us: u16,
 }
 
 impl Twi {
     /// Create new TWI instance
     pub fn new() -> Self {
+        claims::claim(Resource::Twi, "twi").ok();
         unsafe {
             let p = TWI::ptr();
             
             // Enable TWI with internal pullups
-            (*p).twcr.write(|w| w.bits(0x44));
+            (*p).twcr.write(|w| w.bits(TWEA | TWEN));
             
-            // Default to 100kHz with 16MHz CPU clock
-            (*p).twbr.write(|w| w.bits(72));
+            // Default to 100kHz
+            (*p).twbr.write(|w| w.bits(clock::twbr(100_000)));
             (*p).twsr.write(|w| w.bits(0));
         }
         
-        Self { _twi: PhantomData }
+        Self {
+            _twi: PhantomData,
+            timeout_us: DEFAULT_TIMEOUT_US,
+            stretch_us: DEFAULT_STRETCH_US,
+        }
     }
 
     /// Set TWI speed
     pub fn set_speed(&mut self, speed: TwiSpeed) {
+        self.set_frequency(speed.hz());
+    }
+
+    /// Run SCL at the fastest rate not above `hz` that `MCU_FREQ_HZ`
+    /// allows and return that rate. The operation timeout is resized to
+    /// the new byte time; call `set_timeout_us` afterwards to override it.
+    pub fn set_frequency(&mut self, hz: u32) -> u32 {
+        let (twbr, twps) = clock::twi_setting(hz);
         unsafe {
             let p = TWI::ptr();
-            match speed {
-                TwiSpeed::Standard100k => {
-                    (*p).twbr.write(|w| w.bits(72)); // 100kHz @ 16MHz
-                    (*p).twsr.write(|w| w.bits(0));
-                }
-                TwiSpeed::Fast400k => {
-                    (*p).twbr.write(|w| w.bits(12)); // 400kHz @ 16MHz
-                    (*p).twsr.write(|w| w.bits(0));
-                }
-            }
+            (*p).twbr.write(|w| w.bits(twbr));
+            (*p).twsr.write(|w| w.bits(twps & twsr::TWPS_MASK));
         }
+
+        let actual = clock::twi_frequency(twbr, twps);
+        // Nine SCL periods per byte
+        let byte_us = 9_000_000 / actual + 1;
+        self.timeout_us = (byte_us * TIMEOUT_BYTES).min(u16::MAX as u32) as u16;
+        actual
     }
 
-    /// Start TWI transmission
-    pub fn start(&mut self) -> Result<(), ()> {
+    /// Current SCL frequency
+    pub fn frequency(&self) -> u32 {
         unsafe {
             let p = TWI::ptr();
-            
-            // Send START condition
-            (*p).twcr.write(|w| w.bits(0xA4));
-            
-            // Wait for completion
-            while (*p).twcr.read().bits() & 0x80 == 0 {}
-            
-            // Check status
-            if ((*p).twsr.read().bits() & 0xF8) == TwiStatus::StartTransmitted as u8 {
-                Ok(())
-            } else {
-                Err(())
+            clock::twi_frequency((*p).twbr.read().bits(), (*p).twsr.read().bits() & twsr::TWPS_MASK)
+        }
+    }
+
+    /// Longest wait for any single bus operation before giving up with
+    /// `TwiError::Timeout`
+    pub fn set_timeout_us(&mut self, timeout_us: u16) {
+        self.timeout_us = timeout_us.max(1);
+    }
+
+    /// How long a slave may stretch the clock (hold SCL low) on top of the
+    /// operation timeout, e.g. a sensor finishing a conversion. Waits that
+    /// run out with SCL still low fail with `TwiError::ClockStretch`.
+    pub fn set_stretch_timeout_us(&mut self, stretch_us: u16) {
+        self.stretch_us = stretch_us;
+    }
+
+    // Polls before a wait gives up
+    fn wait_polls(&self) -> u32 {
+        (self.timeout_us as u32 + self.stretch_us as u32) * POLLS_PER_US
+    }
+
+    fn scl_held_low() -> bool {
+        unsafe { (*PORTD::ptr()).pind.read().bits() & SCL == 0 }
+    }
+
+    /// Start TWI transmission
+    pub fn start(&mut self) -> Result<(), TwiError> {
+        // Let queued transactions finish, they own the bus until STOP
+        let mut polls = QUEUE_DRAIN_TIMEOUT_US * POLLS_PER_US;
+        while !self.is_idle() {
+            if polls == 0 {
+                return Err(self.fail(TwiError::Timeout));
             }
+            polls -= 1;
+        }
+
+        // Send START condition
+        unsafe { (*TWI::ptr()).twcr.write(|w| w.bits(TWINT | TWSTA | TWEN)) };
+        match self.wait()? {
+            0x08 | 0x10 => Ok(()),
+            status => Err(self.fail(TwiError::from_status(status))),
         }
     }
 
@@ -91,60 +318,352 @@ impl Twi {
     pub fn stop(&mut self) {
         unsafe {
             let p = TWI::ptr();
-            (*p).twcr.write(|w| w.bits(0x94));
-            while (*p).twcr.read().bits() & 0x10 != 0 {}
+            (*p).twcr.write(|w| w.bits(TWINT | TWSTO | TWEN));
+
+            // TWSTO clears once STOP is on the bus, a held SCL keeps it set
+            let mut polls = self.wait_polls();
+            while (*p).twcr.read().bits() & TWSTO != 0 {
+                if polls == 0 {
+                    self.fail(if Self::scl_held_low() { TwiError::ClockStretch } else { TwiError::Timeout });
+                    return;
+                }
+                polls -= 1;
+            }
         }
     }
 
     /// Write address + R/W bit
-    pub fn write_address(&mut self, addr: u8, read: bool) -> Result<(), ()> {
+    pub fn write_address(&mut self, addr: u8, read: bool) -> Result<(), TwiError> {
         let addr = (addr << 1) | (read as u8);
         self.write_byte(addr)
     }
 
     /// Write a single byte
-    pub fn write_byte(&mut self, byte: u8) -> Result<(), ()> {
+    pub fn write_byte(&mut self, byte: u8) -> Result<(), TwiError> {
         unsafe {
             let p = TWI::ptr();
-            
+
             // Load data and start transmission
             (*p).twdr.write(|w| w.bits(byte));
-            (*p).twcr.write(|w| w.bits(0x84));
-            
-            // Wait for completion
-            while (*p).twcr.read().bits() & 0x80 == 0 {}
-            
-            // Check status
-            let status = (*p).twsr.read().bits() & 0xF8;
-            if status == TwiStatus::DataWriteAck as u8 || 
-               status == TwiStatus::AddrWriteAck as u8 {
+            (*p).twcr.write(|w| w.bits(TWINT | TWEN));
+        }
+
+        // SLA+W, SLA+R or data acknowledged
+        match self.wait()? {
+            0x18 | 0x28 | 0x40 => Ok(()),
+            status => Err(self.fail(TwiError::from_status(status))),
+        }
+    }
+
+    /// Read a byte and send ACK/NACK
+    pub fn read_byte(&mut self, ack: bool) -> Result<u8, TwiError> {
+        // Start read with ACK/NACK
+        let ack = if ack { TWEA } else { 0 };
+        unsafe { (*TWI::ptr()).twcr.write(|w| w.bits(TWINT | ack | TWEN)) };
+
+        match self.wait()? {
+            0x50 | 0x58 => Ok(unsafe { (*TWI::ptr()).twdr.read().bits() }),
+            status => Err(self.fail(TwiError::from_status(status))),
+        }
+    }
+
+    /// Write `wbuf`, then read `rbuf.len()` bytes after a repeated START.
+    /// Either part may be empty. The bus is always released with STOP,
+    /// also when a step fails.
+    pub fn write_then_read(&mut self, addr: u8, wbuf: &[u8], rbuf: &mut [u8]) -> Result<(), TwiError> {
+        if let Some(error) = fault_inject::trip_twi() {
+            return Err(self.fail(error));
+        }
+        let result = self.transfer(addr, wbuf, rbuf);
+        self.stop();
+        result
+    }
+
+    /// Write `data` to consecutive registers starting at `reg`
+    pub fn write_regs(&mut self, addr: u8, reg: u8, data: &[u8]) -> Result<(), TwiError> {
+        if let Some(error) = fault_inject::trip_twi() {
+            return Err(self.fail(error));
+        }
+        let result = self.start().and_then(|_| {
+            self.write_address(addr, false)?;
+            self.write_byte(reg)?;
+            data.iter().try_for_each(|&byte| self.write_byte(byte))
+        });
+        self.stop();
+        result
+    }
+
+    /// Read consecutive registers starting at `reg`
+    pub fn read_regs(&mut self, addr: u8, reg: u8, buffer: &mut [u8]) -> Result<(), TwiError> {
+        self.write_then_read(addr, &[reg], buffer)
+    }
+
+    fn transfer(&mut self, addr: u8, wbuf: &[u8], rbuf: &mut [u8]) -> Result<(), TwiError> {
+        // With nothing to read, an empty write still addresses the device:
+        // START, SLA+W, STOP is the usual presence probe and has to NACK
+        // when nobody answers
+        if !wbuf.is_empty() || rbuf.is_empty() {
+            self.start()?;
+            self.write_address(addr, false)?;
+            for &byte in wbuf {
+                self.write_byte(byte)?;
+            }
+        }
+
+        if !rbuf.is_empty() {
+            // Repeated START keeps the bus between the two parts
+            self.start()?;
+            self.write_address(addr, true)?;
+            let last = rbuf.len() - 1;
+            for (i, byte) in rbuf.iter_mut().enumerate() {
+                *byte = self.read_byte(i < last)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Free a bus held by a slave stuck mid-byte (SDA low). Takes the pins
+    /// from the TWI, clocks SCL until the slave lets go of SDA (nine pulses
+    /// at most), sends a STOP by hand and hands the pins back.
+    pub fn bus_recover(&mut self) -> Result<(), TwiError> {
+        unsafe {
+            let twi = TWI::ptr();
+            (*twi).twcr.write(|w| w.bits(0));
+
+            let port = PORTD::ptr();
+            let sda_high = || (*port).pind.read().bits() & SDA != 0;
+            // Open drain: release with the pull-up, drive low with the port
+            // bit already cleared so the line is never driven high
+            let release = |mask: u8| {
+                (*port).ddrd.modify(|r, w| w.bits(r.bits() & !mask));
+                (*port).portd.modify(|r, w| w.bits(r.bits() | mask));
+            };
+            let pull_low = |mask: u8| {
+                (*port).portd.modify(|r, w| w.bits(r.bits() & !mask));
+                (*port).ddrd.modify(|r, w| w.bits(r.bits() | mask));
+            };
+
+            release(SCL | SDA);
+            half_bit();
+            for _ in 0..9 {
+                if sda_high() {
+                    break;
+                }
+                pull_low(SCL);
+                half_bit();
+                release(SCL);
+                half_bit();
+            }
+
+            // STOP: SDA rises while SCL is high
+            pull_low(SDA);
+            half_bit();
+            release(SCL);
+            half_bit();
+            release(SDA);
+            half_bit();
+            let recovered = sda_high();
+
+            (*twi).twcr.write(|w| w.bits(TWEA | TWEN));
+            if recovered {
                 Ok(())
             } else {
-                Err(())
+                Err(self.fail(TwiError::BusError))
             }
         }
     }
 
-    /// Read a byte and send ACK/NACK
-    pub fn read_byte(&mut self, ack: bool) -> Result<u8, ()> {
+    /// Wait for TWINT and return the status code. A slave stretching the
+    /// clock only delays TWINT, so the wait covers the stretch timeout too.
+    fn wait(&mut self) -> Result<u8, TwiError> {
         unsafe {
             let p = TWI::ptr();
-            
-            // Start read with ACK/NACK
-            (*p).twcr.write(|w| w.bits(if ack { 0xC4 } else { 0x84 }));
-            
-            // Wait for completion
-            while (*p).twcr.read().bits() & 0x80 == 0 {}
-            
-            // Check status
-            let status = (*p).twsr.read().bits() & 0xF8;
-            if status == TwiStatus::DataReadAck as u8 || 
-               status == TwiStatus::DataReadNack as u8 {
-                Ok((*p).twdr.read().bits())
-            } else {
-                Err(())
+            let mut polls = self.wait_polls();
+            while (*p).twcr.read().bits() & TWINT == 0 {
+                if polls == 0 {
+                    let error = if Self::scl_held_low() { TwiError::ClockStretch } else { TwiError::Timeout };
+                    // Drop the half-finished operation, the bus may need
+                    // bus_recover() before the next one
+                    (*p).twcr.write(|w| w.bits(0));
+                    (*p).twcr.write(|w| w.bits(TWEA | TWEN));
+                    return Err(self.fail(error));
+                }
+                polls -= 1;
+            }
+            Ok((*p).twsr.read().bits() & twsr::STATUS_MASK)
+        }
+    }
+
+    /// Latch an error for `take_last_error` and pass it on
+    fn fail(&mut self, error: TwiError) -> TwiError {
+        avr_device::interrupt::free(|cs| LAST_ERROR.borrow(cs).set(Some(error)));
+        error
+    }
+
+    /// Queue a transaction that writes `wbuf` and then reads `read_len`
+    /// bytes with a repeated START. Either part may be empty. Starts the
+    /// bus right away if it is idle.
+    pub fn write_read_async(
+        &mut self,
+        addr: u8,
+        wbuf: &[u8],
+        read_len: usize,
+    ) -> Result<TwiTicket, TwiAsyncError> {
+        self.submit(addr, wbuf, read_len, None)
+    }
+
+    /// Like `write_read_async`, calling `callback` from the interrupt when
+    /// the transaction finishes
+    pub fn write_read_async_with(
+        &mut self,
+        addr: u8,
+        wbuf: &[u8],
+        read_len: usize,
+        callback: TwiCallback,
+    ) -> Result<TwiTicket, TwiAsyncError> {
+        self.submit(addr, wbuf, read_len, Some(callback))
+    }
+
+    /// Result of a transaction once it has finished, copying read data
+    /// into `rbuf`. Frees the queue slot; later polls of the same ticket
+    /// return `None`.
+    pub fn poll(&mut self, ticket: TwiTicket, rbuf: &mut [u8]) -> Option<Result<usize, TwiAsyncError>> {
+        avr_device::interrupt::free(|cs| {
+            let mut queue = TWI_QUEUE.borrow(cs).borrow_mut();
+            let tx = queue.slots.get_mut(ticket.slot as usize)?;
+            if tx.seq != ticket.seq {
+                return None;
+            }
+            let mut result = match tx.state {
+                SlotState::Done(result) => result,
+                _ => return None,
+            };
+            tx.state = SlotState::Free;
+            if let Some(error) = fault_inject::trip_twi() {
+                result = Err(error.into());
             }
+            Some(result.map(|_| {
+                let len = (tx.read_len as usize).min(rbuf.len());
+                rbuf[..len].copy_from_slice(&tx.read[..len]);
+                len
+            }))
+        })
+    }
+
+    /// True while the transaction is queued or on the bus
+    pub fn is_pending(&self, ticket: TwiTicket) -> bool {
+        avr_device::interrupt::free(|cs| {
+            let queue = TWI_QUEUE.borrow(cs).borrow();
+            let tx = &queue.slots[ticket.slot as usize];
+            tx.seq == ticket.seq && matches!(tx.state, SlotState::Queued | SlotState::Active)
+        })
+    }
+
+    /// True when no async transaction is queued or running
+    pub fn is_idle(&self) -> bool {
+        avr_device::interrupt::free(|cs| {
+            let queue = TWI_QUEUE.borrow(cs).borrow();
+            queue.active.is_none() && queue.pending == 0
+        })
+    }
+
+    fn submit(
+        &mut self,
+        addr: u8,
+        wbuf: &[u8],
+        read_len: usize,
+        callback: Option<TwiCallback>,
+    ) -> Result<TwiTicket, TwiAsyncError> {
+        if wbuf.len() > TWI_MAX_WRITE || read_len > TWI_MAX_READ {
+            return Err(TwiAsyncError::TooLong);
+        }
+
+        avr_device::interrupt::free(|cs| {
+            let mut queue = TWI_QUEUE.borrow(cs).borrow_mut();
+            let slot = queue
+                .slots
+                .iter()
+                .position(|tx| tx.state == SlotState::Free)
+                .ok_or(TwiAsyncError::QueueFull)?;
+
+            let seq = queue.next_seq;
+            queue.next_seq = seq.wrapping_add(1);
+            let tx = &mut queue.slots[slot];
+            tx.state = SlotState::Queued;
+            tx.seq = seq;
+            tx.addr = addr;
+            tx.write[..wbuf.len()].copy_from_slice(wbuf);
+            tx.write_len = wbuf.len() as u8;
+            tx.read_len = read_len as u8;
+            tx.pos = 0;
+            tx.callback = callback;
+
+            let pending = queue.pending;
+            queue.order[pending] = slot as u8;
+            queue.pending += 1;
+
+            if queue.active.is_none() {
+                start_next(&mut queue, false);
+            }
+            Ok(TwiTicket {
+                slot: slot as u8,
+                seq,
+            })
+        })
+    }
+}
+
+/// Put the next queued transaction on the bus. `after_stop` sends STOP
+/// for the finished one first; the hardware follows it with the START.
+fn start_next(queue: &mut TwiQueue, after_stop: bool) {
+    let stop = if after_stop { TWSTO } else { 0 };
+    match queue.pop_next() {
+        Some(slot) => {
+            queue.slots[slot as usize].state = SlotState::Active;
+            queue.active = Some(slot);
+            queue.writing = queue.slots[slot as usize].write_len > 0;
+            unsafe { (*TWI::ptr()).twcr.write(|w| w.bits(TWINT | TWSTA | stop | TWEN | TWIE)) };
+        }
+        None => {
+            queue.active = None;
+            if after_stop {
+                unsafe { (*TWI::ptr()).twcr.write(|w| w.bits(TWINT | TWSTO | TWEN)) };
+            }
+        }
+    }
+}
+
+/// Close the active transaction and move on to the next one
+fn finish(queue: &mut TwiQueue, slot: usize, result: Result<(), TwiAsyncError>) {
+    let tx = &mut queue.slots[slot];
+    tx.state = SlotState::Done(result);
+    let ticket = TwiTicket {
+        slot: slot as u8,
+        seq: tx.seq,
+    };
+    let callback = tx.callback;
+
+    if result == Err(TwiAsyncError::ArbitrationLost) {
+        // Bus is released already, START again once it is free
+        queue.active = None;
+        if queue.pending > 0 {
+            start_next(queue, false);
+        } else {
+            unsafe { (*TWI::ptr()).twcr.write(|w| w.bits(TWINT | TWEN)) };
         }
+    } else {
+        start_next(queue, true);
+    }
+
+    if let Some(callback) = callback {
+        callback(ticket, result);
+    }
+}
+
+// Half of a 100kHz SCL period
+fn half_bit() {
+    for _ in 0..POLLS_PER_US * 5 {
+        avr_device::asm::nop();
     }
 }
 
@@ -153,3 +672,81 @@ impl Default for Twi {
         Self::new()
     }
 }
+
+#[avr_device::interrupt(atmega128)]
+fn TWI() {
+    avr_device::interrupt::free(|cs| {
+        let mut queue = TWI_QUEUE.borrow(cs).borrow_mut();
+        let slot = match queue.active {
+            Some(slot) => slot as usize,
+            None => {
+                // Stray interrupt, hand the bus back
+                unsafe { (*TWI::ptr()).twcr.write(|w| w.bits(TWINT | TWSTO | TWEN)) };
+                return;
+            }
+        };
+        let status = unsafe { (*TWI::ptr()).twsr.read().bits() & twsr::STATUS_MASK };
+        let writing = queue.writing;
+        let tx = &mut queue.slots[slot];
+
+        // Next TWCR value when the transaction continues
+        let next = match status {
+            0x08 | 0x10 => {
+                let sla = (tx.addr << 1) | (!writing as u8);
+                unsafe { (*TWI::ptr()).twdr.write(|w| w.bits(sla)) };
+                TWINT | TWEN | TWIE
+            }
+            0x18 | 0x28 => {
+                if tx.pos < tx.write_len {
+                    let byte = tx.write[tx.pos as usize];
+                    tx.pos += 1;
+                    unsafe { (*TWI::ptr()).twdr.write(|w| w.bits(byte)) };
+                    TWINT | TWEN | TWIE
+                } else if tx.read_len > 0 {
+                    tx.pos = 0;
+                    queue.writing = false;
+                    TWINT | TWSTA | TWEN | TWIE
+                } else {
+                    finish(&mut queue, slot, Ok(()));
+                    return;
+                }
+            }
+            0x40 => {
+                if tx.read_len > 1 {
+                    TWINT | TWEA | TWEN | TWIE
+                } else {
+                    TWINT | TWEN | TWIE
+                }
+            }
+            0x50 | 0x58 => {
+                let byte = unsafe { (*TWI::ptr()).twdr.read().bits() };
+                tx.read[tx.pos as usize] = byte;
+                tx.pos += 1;
+                if status == 0x58 || tx.pos >= tx.read_len {
+                    finish(&mut queue, slot, Ok(()));
+                    return;
+                }
+                // NACK the last byte so the slave releases the bus
+                if tx.read_len - tx.pos > 1 {
+                    TWINT | TWEA | TWEN | TWIE
+                } else {
+                    TWINT | TWEN | TWIE
+                }
+            }
+            0x20 | 0x30 | 0x48 => {
+                finish(&mut queue, slot, Err(TwiAsyncError::Nack));
+                return;
+            }
+            0x38 => {
+                finish(&mut queue, slot, Err(TwiAsyncError::ArbitrationLost));
+                return;
+            }
+            other => {
+                finish(&mut queue, slot, Err(TwiAsyncError::Bus(other)));
+                return;
+            }
+        };
+        debug_assert!(twcr::valid(next));
+        unsafe { (*TWI::ptr()).twcr.write(|w| w.bits(next)) };
+    });
+}
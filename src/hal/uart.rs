@@ -1,16 +1,318 @@
 #![allow(clippy::missing_safety_doc)]
 
-use avr_device::atmega128::{USART0, USART1};
+use avr_device::atmega128::{PORTA, PORTB, PORTC, PORTD, PORTE, PORTF, USART0, USART1};
 use core::marker::PhantomData;
-use core::cell::RefCell;
-use avr_device::interrupt::Mutex;
+use core::cell::{Cell, RefCell};
+use avr_device::interrupt::{CriticalSection, Mutex};
 
-// Buffer size must be power of 2 for efficient masking
-const BUFFER_SIZE: usize = 32;
+use crate::config::{PROTOCOL_BAUD, UART_BAUD};
+use crate::hal::claims::{self, Resource};
+use crate::hal::clock;
+use crate::hal::progmem::{PgmSlice, PgmStr};
+
+// Fail the build rather than run a link with too much baud rate error
+const _: () = assert!(clock::baud_setting(UART_BAUD).is_some(), "UART_BAUD not reachable at MCU_FREQ_HZ");
+const _: () = assert!(clock::baud_setting(PROTOCOL_BAUD).is_some(), "PROTOCOL_BAUD not reachable at MCU_FREQ_HZ");
+
+// Power of 2 for efficient masking, checked in config
+const BUFFER_SIZE: usize = crate::config::UART_BUFFER_SIZE;
 const BUFFER_MASK: usize = BUFFER_SIZE - 1;
 
-// Baud rate calculation (16MHz clock)
-const UBRR_9600: u16 = 103;  // (16_000_000 / (16 * 9600)) - 1
+// RTS is released above the high mark, leaving room for the characters a
+// USB bridge still sends after seeing it, and asserted again below the low
+const RTS_HIGH_WATER: usize = BUFFER_SIZE - 8;
+const RTS_LOW_WATER: usize = 8;
+
+// UCSRB bits not covered by the named field accessors
+const UCSZ2: u8 = 1 << 2;
+const RXB8: u8 = 1 << 1;
+const TXB8: u8 = 1 << 0;
+
+// UCSRA status bits
+const RXC: u8 = 1 << 7;
+const TXC: u8 = 1 << 6;
+const UDRE: u8 = 1 << 5;
+const FE: u8 = 1 << 4;
+const DOR: u8 = 1 << 3;
+const UPE: u8 = 1 << 2;
+// UCSRA control bits that must survive writing TXC to clear it
+const U2X: u8 = 1 << 1;
+const MPCM: u8 = 1 << 0;
+const UCSRA_CONTROL: u8 = U2X | MPCM;
+
+/// Address every station accepts in multiprocessor mode
+pub const BROADCAST_ADDRESS: u8 = 0xFF;
+// A break holds the line low for two frame times of up to 13 bits
+const BREAK_BITS: u32 = 26;
+
+// How long `set_driver_enable` waits for pending output to go out
+const DRIVER_RELEASE_TIMEOUT_MS: u16 = 100;
+
+// Blocking calls poll about every 10us
+const POLLS_PER_MS: u32 = 100;
+const POLL_SPIN: u32 = clock::cycles_per_us() * 10 / 4;
+
+// UCSRC frame format bits
+const UPM1: u8 = 1 << 5;
+const UPM0: u8 = 1 << 4;
+const USBS: u8 = 1 << 3;
+const UCSZ1: u8 = 1 << 2;
+const UCSZ0: u8 = 1 << 1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum UartError {
+    /// TX buffer has no room for another byte
+    BufferFull,
+    /// Blocking call gave up before the data left the chip
+    Timeout,
+}
+
+/// Receive and transmit error counters of one USART
+#[derive(Clone, Copy, Default, Debug)]
+pub struct UartStats {
+    pub framing_errors: u32,
+    /// Characters lost in hardware because UDR wasn't read in time
+    pub data_overruns: u32,
+    pub parity_errors: u32,
+    /// Received bytes dropped because the RX buffer was full
+    pub rx_dropped: u32,
+    /// Bytes dropped by `write_byte` because the TX buffer was full
+    pub tx_dropped: u32,
+    /// Breaks received (all-zero character with a framing error)
+    pub breaks: u32,
+}
+
+impl UartStats {
+    const fn new() -> Self {
+        Self {
+            framing_errors: 0,
+            data_overruns: 0,
+            parity_errors: 0,
+            rx_dropped: 0,
+            tx_dropped: 0,
+            breaks: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+    /// 9-bit frames, exchanged with `write_9bit`/`read_9bit`
+    Nine,
+}
+
+/// Line settings for `Uart::with_config`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct UartConfig {
+    pub baud: u32,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub data_bits: DataBits,
+}
+
+impl UartConfig {
+    /// 8N1 at the given baud rate, up to 1Mbps at 16MHz
+    pub const fn new(baud: u32) -> Self {
+        Self {
+            baud,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            data_bits: DataBits::Eight,
+        }
+    }
+
+    /// Like `new`, but refuses a rate that can't be reached within
+    /// `clock::MAX_BAUD_ERROR_PERMILLE`. In a `const` the check runs at
+    /// compile time.
+    pub const fn checked(baud: u32) -> Self {
+        assert!(clock::baud_setting(baud).is_some(), "baud rate error too large at MCU_FREQ_HZ");
+        Self::new(baud)
+    }
+
+    pub const fn parity(mut self, parity: Parity) -> Self {
+        self.parity = parity;
+        self
+    }
+
+    pub const fn stop_bits(mut self, stop_bits: StopBits) -> Self {
+        self.stop_bits = stop_bits;
+        self
+    }
+
+    pub const fn data_bits(mut self, data_bits: DataBits) -> Self {
+        self.data_bits = data_bits;
+        self
+    }
+
+    fn ucsrc(&self) -> u8 {
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Even => UPM1,
+            Parity::Odd => UPM1 | UPM0,
+        };
+        let stop = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => USBS,
+        };
+        let size = match self.data_bits {
+            DataBits::Five => 0,
+            DataBits::Six => UCSZ0,
+            DataBits::Seven => UCSZ1,
+            DataBits::Eight | DataBits::Nine => UCSZ1 | UCSZ0,
+        };
+        parity | stop | size
+    }
+}
+
+impl Default for UartConfig {
+    /// 8N1 at `config::UART_BAUD`
+    fn default() -> Self {
+        Self::new(UART_BAUD)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FlowPort {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+}
+
+/// GPIO used for a flow control line
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FlowPin {
+    pub port: FlowPort,
+    pub bit: u8,
+}
+
+impl FlowPin {
+    pub const fn new(port: FlowPort, bit: u8) -> Self {
+        Self { port, bit }
+    }
+
+    fn into_output(self) {
+        let mask = 1 << self.bit;
+        unsafe {
+            match self.port {
+                FlowPort::A => (*PORTA::ptr()).ddra.modify(|r, w| w.bits(r.bits() | mask)),
+                FlowPort::B => (*PORTB::ptr()).ddrb.modify(|r, w| w.bits(r.bits() | mask)),
+                FlowPort::C => (*PORTC::ptr()).ddrc.modify(|r, w| w.bits(r.bits() | mask)),
+                FlowPort::D => (*PORTD::ptr()).ddrd.modify(|r, w| w.bits(r.bits() | mask)),
+                FlowPort::E => (*PORTE::ptr()).ddre.modify(|r, w| w.bits(r.bits() | mask)),
+                FlowPort::F => (*PORTF::ptr()).ddrf.modify(|r, w| w.bits(r.bits() | mask)),
+            }
+        }
+    }
+
+    fn into_input(self) {
+        let mask = 1 << self.bit;
+        unsafe {
+            match self.port {
+                FlowPort::A => (*PORTA::ptr()).ddra.modify(|r, w| w.bits(r.bits() & !mask)),
+                FlowPort::B => (*PORTB::ptr()).ddrb.modify(|r, w| w.bits(r.bits() & !mask)),
+                FlowPort::C => (*PORTC::ptr()).ddrc.modify(|r, w| w.bits(r.bits() & !mask)),
+                FlowPort::D => (*PORTD::ptr()).ddrd.modify(|r, w| w.bits(r.bits() & !mask)),
+                FlowPort::E => (*PORTE::ptr()).ddre.modify(|r, w| w.bits(r.bits() & !mask)),
+                FlowPort::F => (*PORTF::ptr()).ddrf.modify(|r, w| w.bits(r.bits() & !mask)),
+            }
+        }
+    }
+
+    fn set(self, high: bool) {
+        let mask = 1 << self.bit;
+        let apply = |bits: u8| if high { bits | mask } else { bits & !mask };
+        unsafe {
+            match self.port {
+                FlowPort::A => (*PORTA::ptr()).porta.modify(|r, w| w.bits(apply(r.bits()))),
+                FlowPort::B => (*PORTB::ptr()).portb.modify(|r, w| w.bits(apply(r.bits()))),
+                FlowPort::C => (*PORTC::ptr()).portc.modify(|r, w| w.bits(apply(r.bits()))),
+                FlowPort::D => (*PORTD::ptr()).portd.modify(|r, w| w.bits(apply(r.bits()))),
+                FlowPort::E => (*PORTE::ptr()).porte.modify(|r, w| w.bits(apply(r.bits()))),
+                FlowPort::F => (*PORTF::ptr()).portf.modify(|r, w| w.bits(apply(r.bits()))),
+            }
+        }
+    }
+
+    fn is_high(self) -> bool {
+        let bits = unsafe {
+            match self.port {
+                FlowPort::A => (*PORTA::ptr()).pina.read().bits(),
+                FlowPort::B => (*PORTB::ptr()).pinb.read().bits(),
+                FlowPort::C => (*PORTC::ptr()).pinc.read().bits(),
+                FlowPort::D => (*PORTD::ptr()).pind.read().bits(),
+                FlowPort::E => (*PORTE::ptr()).pine.read().bits(),
+                FlowPort::F => (*PORTF::ptr()).pinf.read().bits(),
+            }
+        };
+        bits & (1 << self.bit) != 0
+    }
+}
+
+/// RTS/CTS hardware flow control. Both lines are active low: RTS is driven
+/// low while there is room in the RX buffer, and transmission pauses while
+/// the peer holds CTS high.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FlowControl {
+    /// Output to the peer's CTS
+    pub rts: FlowPin,
+    /// Input from the peer's RTS
+    pub cts: FlowPin,
+}
+
+impl FlowControl {
+    fn clear_to_send(&self) -> bool {
+        !self.cts.is_high()
+    }
+}
+
+/// Driver enable of an RS-485 transceiver, usually wired to both DE and
+/// /RE so the receiver is off while the port transmits. The pin is
+/// asserted when a byte is queued and released from the TXC interrupt once
+/// the last byte has left the shift register.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DriverEnable {
+    pub pin: FlowPin,
+    /// Level that enables the driver, high on the usual transceivers
+    pub active_high: bool,
+}
+
+impl DriverEnable {
+    pub const fn new(pin: FlowPin) -> Self {
+        Self { pin, active_high: true }
+    }
+
+    fn assert(self) {
+        self.pin.set(self.active_high);
+    }
+
+    fn release(self) {
+        self.pin.set(!self.active_high);
+    }
+
+    fn is_driving(self) -> bool {
+        self.pin.is_high() == self.active_high
+    }
+}
 
 pub struct Buffer {
     data: [u8; BUFFER_SIZE],
@@ -38,6 +340,10 @@ impl Buffer {
         }
     }
 
+    fn len(&self) -> usize {
+        self.write_idx.wrapping_sub(self.read_idx) & BUFFER_MASK
+    }
+
     fn read(&mut self) -> Option<u8> {
         if self.read_idx != self.write_idx {
             let byte = self.data[self.read_idx];
@@ -49,39 +355,340 @@ impl Buffer {
     }
 }
 
-// Global buffers for interrupt handlers
-static TX_BUFFER: Mutex<RefCell<Buffer>> = Mutex::new(RefCell::new(Buffer::new()));
-static RX_BUFFER: Mutex<RefCell<Buffer>> = Mutex::new(RefCell::new(Buffer::new()));
+// Per-USART buffers for interrupt handlers
+static USART0_TX_BUFFER: Mutex<RefCell<Buffer>> = Mutex::new(RefCell::new(Buffer::new()));
+static USART0_RX_BUFFER: Mutex<RefCell<Buffer>> = Mutex::new(RefCell::new(Buffer::new()));
+static USART1_TX_BUFFER: Mutex<RefCell<Buffer>> = Mutex::new(RefCell::new(Buffer::new()));
+static USART1_RX_BUFFER: Mutex<RefCell<Buffer>> = Mutex::new(RefCell::new(Buffer::new()));
+static USART0_FLOW: Mutex<Cell<Option<FlowControl>>> = Mutex::new(Cell::new(None));
+static USART1_FLOW: Mutex<Cell<Option<FlowControl>>> = Mutex::new(Cell::new(None));
+static USART0_DE: Mutex<Cell<Option<DriverEnable>>> = Mutex::new(Cell::new(None));
+static USART1_DE: Mutex<Cell<Option<DriverEnable>>> = Mutex::new(Cell::new(None));
+static USART0_LOOPBACK: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static USART1_LOOPBACK: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static USART0_SENT: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static USART1_SENT: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static USART0_STATS: Mutex<Cell<UartStats>> = Mutex::new(Cell::new(UartStats::new()));
+static USART1_STATS: Mutex<Cell<UartStats>> = Mutex::new(Cell::new(UartStats::new()));
+
+/// Character received in multiprocessor mode
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MpFrame {
+    /// Address frame matching this station or `BROADCAST_ADDRESS`
+    Address(u8),
+    Data(u8),
+}
 
 pub struct Uart<USART> {
     usart: PhantomData<USART>,
+    config: UartConfig,
+    /// Own station address in multiprocessor mode
+    address: Option<u8>,
+    /// `UartStats::breaks` at the last `take_break`
+    breaks_seen: u32,
 }
 
 impl<USART: UartRegisterBlock> Uart<USART> {
+    /// 8N1 at `config::UART_BAUD`
     pub fn new() -> Self {
+        Self::with_config(UartConfig::default())
+    }
+
+    /// Configure the USART with the given line settings. UBRR is computed
+    /// from the `MCU_FREQ_HZ` build setting, with double speed (U2X) where
+    /// that lands closer to the requested rate.
+    ///
+    /// 9-bit mode is polled: the RX interrupt and the byte buffers carry
+    /// only 8 bits, so it stays disabled and `write_9bit`/`read_9bit` must
+    /// be used instead of the byte functions.
+    pub fn with_config(config: UartConfig) -> Self {
+        claims::claim(USART::resource(), "uart").ok();
+        let nine_bit = config.data_bits == DataBits::Nine;
         unsafe {
             let p = USART::ptr();
-            
-            // Set baud rate
-            (*p).ubrr.write(|w| w.bits(UBRR_9600));
-            
+
+            // Set baud rate; an unreachable rate falls back to the nearest
+            // normal-speed setting
+            let (ubrr, double_speed) = clock::baud_setting(config.baud).unwrap_or((clock::ubrr(config.baud), false));
+            (*p).ubrr.write(|w| w.bits(ubrr));
+            (*p).ucsra.modify(|r, w| {
+                let bits = r.bits() & UCSRA_CONTROL;
+                w.bits(if double_speed { bits | U2X } else { bits & !U2X })
+            });
+
+            // Frame format
+            (*p).ucsrc.write(|w| w.bits(config.ucsrc()));
+            (*p).ucsr.modify(|r, w| {
+                if nine_bit {
+                    w.bits(r.bits() | UCSZ2)
+                } else {
+                    w.bits(r.bits() & !UCSZ2)
+                }
+            });
+
             // Enable TX, RX and RX interrupt
             (*p).ucsr.modify(|_, w| {
                 w.rxen().set_bit()
                  .txen().set_bit()
-                 .rxcie().set_bit()
+                 .rxcie().bit(!nine_bit)
             });
         }
-        
+
         Self {
             usart: PhantomData,
+            config,
+            address: None,
+            breaks_seen: 0,
         }
     }
 
-    pub fn write_byte(&mut self, byte: u8) {
+    pub fn config(&self) -> UartConfig {
+        self.config
+    }
+
+    /// Send a 9-bit character, waiting for the data register to be free
+    pub fn write_9bit(&mut self, word: u16) {
+        avr_device::interrupt::free(|cs| start_driving::<USART>(cs));
+        unsafe {
+            let p = USART::ptr();
+            while (*p).ucsra.read().bits() & UDRE == 0 {}
+            // The ninth bit has to be in place before UDR is written
+            (*p).ucsr.modify(|r, w| {
+                if word & 0x100 != 0 {
+                    w.bits(r.bits() | TXB8)
+                } else {
+                    w.bits(r.bits() & !TXB8)
+                }
+            });
+            (*p).ucsra.modify(|r, w| w.bits((r.bits() & UCSRA_CONTROL) | TXC));
+            (*p).udr.write(|w| w.bits(word as u8));
+        }
+        avr_device::interrupt::free(|cs| USART::sent().borrow(cs).set(true));
+    }
+
+    /// Read a received 9-bit character, if any. Breaks are counted in
+    /// the stats and not returned.
+    pub fn read_9bit(&mut self) -> Option<u16> {
+        unsafe {
+            let p = USART::ptr();
+            let status = (*p).ucsra.read().bits();
+            if status & RXC == 0 {
+                return None;
+            }
+            // RXB8 must be read before UDR
+            let high = if (*p).ucsr.read().bits() & RXB8 != 0 { 0x100 } else { 0 };
+            let word = high | (*p).udr.read().bits() as u16;
+            if status & FE != 0 && word == 0 {
+                count_break::<USART>();
+                return None;
+            }
+            Some(word)
+        }
+    }
+
+    /// Multiprocessor communication mode for a shared RS-485 bus. With an
+    /// address set the USART drops data frames until an address frame for
+    /// this station (or `BROADCAST_ADDRESS`) arrives; `read_frame` then
+    /// passes the data up to the next address frame for someone else.
+    /// Address frames carry the ninth bit, so the port must be configured
+    /// with `DataBits::Nine`.
+    pub fn set_address_filter(&mut self, address: Option<u8>) {
+        debug_assert!(address.is_none() || self.config.data_bits == DataBits::Nine);
+        self.address = address;
+        self.set_mpcm(address.is_some());
+    }
+
+    pub fn address_filter(&self) -> Option<u8> {
+        self.address
+    }
+
+    /// Select the station the following `write_9bit` data is meant for
+    pub fn write_address(&mut self, address: u8) {
+        self.write_9bit(0x100 | address as u16);
+    }
+
+    /// Next frame in multiprocessor mode, addressed to this station
+    pub fn read_frame(&mut self) -> Option<MpFrame> {
+        let word = self.read_9bit()?;
+        let byte = word as u8;
+        if word & 0x100 == 0 {
+            return Some(MpFrame::Data(byte));
+        }
+
+        let ours = match self.address {
+            Some(address) => byte == address || byte == BROADCAST_ADDRESS,
+            None => true,
+        };
+        if self.address.is_some() {
+            // Listen to the data that follows only if it is for us
+            self.set_mpcm(!ours);
+        }
+        ours.then_some(MpFrame::Address(byte))
+    }
+
+    fn set_mpcm(&mut self, enabled: bool) {
+        unsafe {
+            (*USART::ptr()).ucsra.modify(|r, w| {
+                let bits = r.bits() & UCSRA_CONTROL;
+                w.bits(if enabled { bits | MPCM } else { bits & !MPCM })
+            });
+        }
+    }
+
+    /// Send a break: wait for queued output to go out, then hold TXD low
+    /// for two frame times
+    pub fn send_break(&mut self, timeout_ms: u16) -> Result<(), UartError> {
+        self.flush(timeout_ms)?;
+
+        let txd = USART::txd();
+        let de = self.driver_enable();
+        // One poll is about 10us
+        let polls = BREAK_BITS * 100_000 / self.config.baud + 1;
+        unsafe {
+            // No frame is sent, so TXC won't release the driver afterwards
+            if let Some(de) = de {
+                de.assert();
+            }
+            txd.set(false);
+            txd.into_output();
+            // The pin is a plain GPIO while the transmitter is off
+            (*USART::ptr()).ucsr.modify(|_, w| w.txen().clear_bit());
+            for _ in 0..polls {
+                spin();
+            }
+            txd.set(true);
+            (*USART::ptr()).ucsr.modify(|_, w| w.txen().set_bit());
+            if let Some(de) = de {
+                de.release();
+            }
+        }
+        Ok(())
+    }
+
+    /// True once for every time breaks were received since the last call
+    pub fn take_break(&mut self) -> bool {
+        let breaks = self.stats().breaks;
+        let seen = breaks != self.breaks_seen;
+        self.breaks_seen = breaks;
+        seen
+    }
+
+    /// Enable RTS/CTS flow control on the given pins, or disable it
+    pub fn set_flow_control(&mut self, flow: Option<FlowControl>) {
+        avr_device::interrupt::free(|cs| {
+            if let Some(old) = USART::flow().borrow(cs).get() {
+                old.rts.into_input();
+            }
+            if let Some(flow) = flow {
+                flow.cts.into_input();
+                let full = USART::rx_buffer().borrow(cs).borrow().len() >= RTS_HIGH_WATER;
+                flow.rts.set(full);
+                flow.rts.into_output();
+            }
+            USART::flow().borrow(cs).set(flow);
+        });
+        self.service_flow();
+    }
+
+    pub fn flow_control(&self) -> Option<FlowControl> {
+        avr_device::interrupt::free(|cs| USART::flow().borrow(cs).get())
+    }
+
+    /// Switch an RS-485 transceiver between transmit and receive around
+    /// each transmission, or stop doing so. The driver starts released.
+    pub fn set_driver_enable(&mut self, de: Option<DriverEnable>) {
+        // Don't cut off a transmission in progress
+        let _ = self.flush(DRIVER_RELEASE_TIMEOUT_MS);
+        avr_device::interrupt::free(|cs| {
+            if let Some(old) = USART::driver_enable().borrow(cs).get() {
+                old.release();
+                old.pin.into_input();
+            }
+            if let Some(de) = de {
+                de.release();
+                de.pin.into_output();
+            }
+            USART::driver_enable().borrow(cs).set(de);
+        });
+    }
+
+    pub fn driver_enable(&self) -> Option<DriverEnable> {
+        avr_device::interrupt::free(|cs| USART::driver_enable().borrow(cs).get())
+    }
+
+    /// Internal loopback for self-tests without a jumper from TXD to RXD.
+    /// The USART has no loopback of its own, so the receiver is switched
+    /// off and every byte the UDRE interrupt writes to UDR is also stored
+    /// in the RX buffer. That covers the buffers, the interrupts and the
+    /// baud generator but not the pins; the bytes still go out on TXD.
+    pub fn set_loopback(&mut self, enabled: bool) {
+        let _ = self.flush(DRIVER_RELEASE_TIMEOUT_MS);
+        let nine_bit = self.config.data_bits == DataBits::Nine;
+        avr_device::interrupt::free(|cs| {
+            USART::loopback().borrow(cs).set(enabled);
+            unsafe {
+                (*USART::ptr()).ucsr.modify(|_, w| {
+                    w.rxen().bit(!enabled)
+                     .rxcie().bit(!enabled && !nine_bit)
+                });
+            }
+        });
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        avr_device::interrupt::free(|cs| USART::loopback().borrow(cs).get())
+    }
+
+    /// Send `pattern` in loopback mode and compare the echo, waiting up to
+    /// `timeout_ms` per byte. Returns the index of the first byte that
+    /// didn't come back. Pending received data is discarded.
+    pub fn loopback_check(&mut self, pattern: &[u8], timeout_ms: u16) -> Result<(), usize> {
+        let was_loopback = self.is_loopback();
+        self.set_loopback(true);
+        while self.read_byte().is_some() {}
+
+        let mut result = Ok(());
+        for (i, &byte) in pattern.iter().enumerate() {
+            if self.write_byte_blocking(byte, timeout_ms).is_err() {
+                result = Err(i);
+                break;
+            }
+            let mut polls = timeout_ms as u32 * POLLS_PER_MS;
+            let echo = loop {
+                match self.read_byte() {
+                    Some(echo) => break Some(echo),
+                    None if polls == 0 => break None,
+                    None => {
+                        polls -= 1;
+                        spin();
+                    }
+                }
+            };
+            if echo != Some(byte) {
+                result = Err(i);
+                break;
+            }
+        }
+
+        self.set_loopback(was_loopback);
+        result
+    }
+
+    /// True if another byte can be queued and the peer is accepting data
+    pub fn is_tx_ready(&self) -> bool {
         avr_device::interrupt::free(|cs| {
-            if !TX_BUFFER.borrow(cs).borrow_mut().write(byte) {
-                // Buffer full - enable TX interrupt to start sending
+            let clear = USART::flow().borrow(cs).get().map_or(true, |f| f.clear_to_send());
+            clear && USART::tx_buffer().borrow(cs).borrow().len() < BUFFER_SIZE - 1
+        })
+    }
+
+    /// Resume transmission once the peer asserts CTS again. There is no
+    /// interrupt on the CTS pin, so call this regularly while flow control
+    /// is enabled.
+    pub fn service_flow(&mut self) {
+        avr_device::interrupt::free(|cs| {
+            let clear = USART::flow().borrow(cs).get().map_or(true, |f| f.clear_to_send());
+            if clear && USART::tx_buffer().borrow(cs).borrow().len() > 0 {
                 unsafe {
                     (*USART::ptr()).ucsr.modify(|_, w| w.udrie().set_bit());
                 }
@@ -89,9 +696,103 @@ impl<USART: UartRegisterBlock> Uart<USART> {
         });
     }
 
+    /// Queue a byte for transmission, dropped if the TX buffer is full.
+    /// Use `try_write_byte` or `write_byte_blocking` when that matters.
+    pub fn write_byte(&mut self, byte: u8) {
+        let _ = self.try_write_byte(byte);
+    }
+
+    /// Queue a byte for transmission without waiting
+    pub fn try_write_byte(&mut self, byte: u8) -> Result<(), UartError> {
+        avr_device::interrupt::free(|cs| {
+            let queued = USART::tx_buffer().borrow(cs).borrow_mut().write(byte);
+            if queued {
+                start_driving::<USART>(cs);
+            }
+            // Make sure the UDRE interrupt is draining the buffer
+            unsafe {
+                (*USART::ptr()).ucsr.modify(|_, w| w.udrie().set_bit());
+            }
+            if queued {
+                Ok(())
+            } else {
+                let stats = USART::stats().borrow(cs);
+                let mut s = stats.get();
+                s.tx_dropped = s.tx_dropped.wrapping_add(1);
+                stats.set(s);
+                Err(UartError::BufferFull)
+            }
+        })
+    }
+
+    /// Queue a byte, waiting up to `timeout_ms` for room in the TX buffer
+    pub fn write_byte_blocking(&mut self, byte: u8, timeout_ms: u16) -> Result<(), UartError> {
+        let mut polls = timeout_ms as u32 * POLLS_PER_MS;
+        loop {
+            match self.try_write_byte(byte) {
+                Err(UartError::BufferFull) if polls > 0 => {
+                    polls -= 1;
+                    self.service_flow();
+                    spin();
+                }
+                Err(UartError::BufferFull) => return Err(UartError::Timeout),
+                result => return result,
+            }
+        }
+    }
+
+    /// Wait until every queued byte, including the one in the shift
+    /// register, has been sent. Call before resetting or sleeping.
+    pub fn flush(&mut self, timeout_ms: u16) -> Result<(), UartError> {
+        let mut polls = timeout_ms as u32 * POLLS_PER_MS;
+        loop {
+            let done = avr_device::interrupt::free(|cs| {
+                let empty = USART::tx_buffer().borrow(cs).borrow().len() == 0;
+                // The TXC interrupt clears TXC when it releases the driver.
+                // TXC is only ever set by a transmission, before the first
+                // one an empty UDR is idle.
+                let idle = match USART::driver_enable().borrow(cs).get() {
+                    Some(de) => !de.is_driving(),
+                    None => {
+                        let status = unsafe { (*USART::ptr()).ucsra.read().bits() };
+                        let sent = USART::sent().borrow(cs).get();
+                        status & UDRE != 0 && (status & TXC != 0 || !sent)
+                    }
+                };
+                empty && idle
+            });
+            if done {
+                return Ok(());
+            }
+            if polls == 0 {
+                return Err(UartError::Timeout);
+            }
+            polls -= 1;
+            self.service_flow();
+            spin();
+        }
+    }
+
+    /// Error counters since start-up or the last `reset_stats`
+    pub fn stats(&self) -> UartStats {
+        avr_device::interrupt::free(|cs| USART::stats().borrow(cs).get())
+    }
+
+    pub fn reset_stats(&mut self) {
+        avr_device::interrupt::free(|cs| USART::stats().borrow(cs).set(UartStats::new()));
+    }
+
     pub fn read_byte(&mut self) -> Option<u8> {
         avr_device::interrupt::free(|cs| {
-            RX_BUFFER.borrow(cs).borrow_mut().read()
+            let mut rx = USART::rx_buffer().borrow(cs).borrow_mut();
+            let byte = rx.read();
+            if let Some(flow) = USART::flow().borrow(cs).get() {
+                if rx.len() <= RTS_LOW_WATER {
+                    // Room again - let the peer send
+                    flow.rts.set(false);
+                }
+            }
+            byte
         })
     }
 
@@ -100,11 +801,113 @@ impl<USART: UartRegisterBlock> Uart<USART> {
             self.write_byte(byte);
         }
     }
+
+    /// Write a string stored in flash, see `pgm_str!`
+    pub fn write_pgm_str(&mut self, s: PgmStr) {
+        self.write_from_progmem(s.as_bytes());
+    }
+
+    /// Write bytes straight from flash without staging them in SRAM
+    pub fn write_from_progmem(&mut self, data: PgmSlice) {
+        for byte in data {
+            self.write_byte(byte);
+        }
+    }
+}
+
+/// Byte stream interface of a serial port, so the protocol and console
+/// can be routed to either USART
+pub trait SerialPort {
+    fn read_byte(&mut self) -> Option<u8>;
+    /// Queue a byte, dropping it if the transmit buffer is full
+    fn write_byte(&mut self, byte: u8);
+    /// Queue a byte, waiting up to `timeout_ms` for buffer space
+    fn write_byte_blocking(&mut self, byte: u8, timeout_ms: u16) -> Result<(), UartError>;
+    /// Wait until all queued output has left the port
+    fn flush(&mut self, timeout_ms: u16) -> Result<(), UartError>;
+    fn stats(&self) -> UartStats;
+    fn reset_stats(&mut self);
+
+    /// False while flow control holds off transmission
+    fn is_tx_ready(&self) -> bool {
+        true
+    }
+
+    /// Ports without RTS/CTS ignore this
+    fn set_flow_control(&mut self, _flow: Option<FlowControl>) {}
+
+    fn service_flow(&mut self) {}
+
+    fn write_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+    }
+}
+
+impl<USART: UartRegisterBlock> SerialPort for Uart<USART> {
+    fn read_byte(&mut self) -> Option<u8> {
+        Uart::read_byte(self)
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        Uart::write_byte(self, byte)
+    }
+
+    fn write_byte_blocking(&mut self, byte: u8, timeout_ms: u16) -> Result<(), UartError> {
+        Uart::write_byte_blocking(self, byte, timeout_ms)
+    }
+
+    fn flush(&mut self, timeout_ms: u16) -> Result<(), UartError> {
+        Uart::flush(self, timeout_ms)
+    }
+
+    fn stats(&self) -> UartStats {
+        Uart::stats(self)
+    }
+
+    fn reset_stats(&mut self) {
+        Uart::reset_stats(self)
+    }
+
+    fn is_tx_ready(&self) -> bool {
+        Uart::is_tx_ready(self)
+    }
+
+    fn set_flow_control(&mut self, flow: Option<FlowControl>) {
+        Uart::set_flow_control(self, flow)
+    }
+
+    fn service_flow(&mut self) {
+        Uart::service_flow(self)
+    }
+
+    fn write_str(&mut self, s: &str) {
+        Uart::write_str(self, s)
+    }
 }
 
 // Trait for USART register block access
 pub trait UartRegisterBlock {
     fn ptr() -> *mut avr_device::atmega128::usart0::RegisterBlock;
+    /// Interrupt-side transmit buffer of this USART
+    fn tx_buffer() -> &'static Mutex<RefCell<Buffer>>;
+    /// Interrupt-side receive buffer of this USART
+    fn rx_buffer() -> &'static Mutex<RefCell<Buffer>>;
+    /// Flow control pins of this USART, if enabled
+    fn flow() -> &'static Mutex<Cell<Option<FlowControl>>>;
+    /// RS-485 driver enable of this USART, if enabled
+    fn driver_enable() -> &'static Mutex<Cell<Option<DriverEnable>>>;
+    /// Internal loopback flag of this USART
+    fn loopback() -> &'static Mutex<Cell<bool>>;
+    /// Set once a byte went into UDR; TXC means nothing before that
+    fn sent() -> &'static Mutex<Cell<bool>>;
+    /// Error counters of this USART
+    fn stats() -> &'static Mutex<Cell<UartStats>>;
+    /// TXD pin, driven by hand for breaks
+    fn txd() -> FlowPin;
+    /// Claim registry entry of this USART
+    fn resource() -> Resource;
 }
 
 // Implement for both USART0 and USART1
@@ -112,37 +915,240 @@ impl UartRegisterBlock for USART0 {
     fn ptr() -> *mut avr_device::atmega128::usart0::RegisterBlock {
         USART0::ptr()
     }
+
+    fn tx_buffer() -> &'static Mutex<RefCell<Buffer>> {
+        &USART0_TX_BUFFER
+    }
+
+    fn rx_buffer() -> &'static Mutex<RefCell<Buffer>> {
+        &USART0_RX_BUFFER
+    }
+
+    fn flow() -> &'static Mutex<Cell<Option<FlowControl>>> {
+        &USART0_FLOW
+    }
+
+    fn driver_enable() -> &'static Mutex<Cell<Option<DriverEnable>>> {
+        &USART0_DE
+    }
+
+    fn loopback() -> &'static Mutex<Cell<bool>> {
+        &USART0_LOOPBACK
+    }
+
+    fn sent() -> &'static Mutex<Cell<bool>> {
+        &USART0_SENT
+    }
+
+    fn stats() -> &'static Mutex<Cell<UartStats>> {
+        &USART0_STATS
+    }
+
+    fn txd() -> FlowPin {
+        FlowPin::new(FlowPort::E, 1)
+    }
+
+    fn resource() -> Resource {
+        Resource::Usart0
+    }
 }
 
 impl UartRegisterBlock for USART1 {
     fn ptr() -> *mut avr_device::atmega128::usart0::RegisterBlock {
         USART1::ptr() as *mut _
     }
+
+    fn tx_buffer() -> &'static Mutex<RefCell<Buffer>> {
+        &USART1_TX_BUFFER
+    }
+
+    fn rx_buffer() -> &'static Mutex<RefCell<Buffer>> {
+        &USART1_RX_BUFFER
+    }
+
+    fn flow() -> &'static Mutex<Cell<Option<FlowControl>>> {
+        &USART1_FLOW
+    }
+
+    fn driver_enable() -> &'static Mutex<Cell<Option<DriverEnable>>> {
+        &USART1_DE
+    }
+
+    fn loopback() -> &'static Mutex<Cell<bool>> {
+        &USART1_LOOPBACK
+    }
+
+    fn sent() -> &'static Mutex<Cell<bool>> {
+        &USART1_SENT
+    }
+
+    fn stats() -> &'static Mutex<Cell<UartStats>> {
+        &USART1_STATS
+    }
+
+    fn txd() -> FlowPin {
+        FlowPin::new(FlowPort::D, 3)
+    }
+
+    fn resource() -> Resource {
+        Resource::Usart1
+    }
 }
 
-// Interrupt handlers
-#[avr_device::interrupt(atmega128)]
-fn USART0_RX() {
+// Busy wait for one poll interval, about four cycles per iteration
+fn spin() {
+    for _ in 0..POLL_SPIN {
+        avr_device::asm::nop();
+    }
+}
+
+// Turn the RS-485 driver on ahead of a transmission and have the TXC
+// interrupt turn it off again
+fn start_driving<USART: UartRegisterBlock>(cs: CriticalSection) {
+    if let Some(de) = USART::driver_enable().borrow(cs).get() {
+        de.assert();
+        unsafe {
+            let p = USART::ptr();
+            // A TXC left from an earlier transmission would fire at once
+            // and release the driver under the new byte. Cleared by
+            // writing a one; a byte still in flight sets it again.
+            (*p).ucsra.modify(|r, w| w.bits((r.bits() & UCSRA_CONTROL) | TXC));
+            (*p).ucsr.modify(|_, w| w.txcie().set_bit());
+        }
+    }
+}
+
+fn count_break<USART: UartRegisterBlock>() {
+    avr_device::interrupt::free(|cs| {
+        let stats = USART::stats().borrow(cs);
+        let mut s = stats.get();
+        s.breaks = s.breaks.wrapping_add(1);
+        stats.set(s);
+    });
+}
+
+// Shared bodies of the per-USART interrupt handlers
+fn on_rx<USART: UartRegisterBlock>() {
     unsafe {
-        let byte = (*USART0::ptr()).udr.read().bits();
+        let p = USART::ptr();
+        // Error flags belong to the character in UDR, read them first
+        let status = (*p).ucsra.read().bits();
+        let byte = (*p).udr.read().bits();
+        if status & FE != 0 && byte == 0 {
+            // A break, not data
+            count_break::<USART>();
+            return;
+        }
         avr_device::interrupt::free(|cs| {
-            RX_BUFFER.borrow(cs).borrow_mut().write(byte);
+            let mut rx = USART::rx_buffer().borrow(cs).borrow_mut();
+            let stored = rx.write(byte);
+
+            if status & (FE | DOR | UPE) != 0 || !stored {
+                let stats = USART::stats().borrow(cs);
+                let mut s = stats.get();
+                if status & FE != 0 {
+                    s.framing_errors = s.framing_errors.wrapping_add(1);
+                }
+                if status & DOR != 0 {
+                    s.data_overruns = s.data_overruns.wrapping_add(1);
+                }
+                if status & UPE != 0 {
+                    s.parity_errors = s.parity_errors.wrapping_add(1);
+                }
+                if !stored {
+                    s.rx_dropped = s.rx_dropped.wrapping_add(1);
+                }
+                stats.set(s);
+            }
+
+            if let Some(flow) = USART::flow().borrow(cs).get() {
+                if rx.len() >= RTS_HIGH_WATER {
+                    // Nearly full - ask the peer to pause
+                    flow.rts.set(true);
+                }
+            }
         });
     }
 }
 
-#[avr_device::interrupt(atmega128)]
-fn USART0_UDRE() {
+fn on_udre<USART: UartRegisterBlock>() {
     avr_device::interrupt::free(|cs| {
-        if let Some(byte) = TX_BUFFER.borrow(cs).borrow_mut().read() {
+        let clear = USART::flow().borrow(cs).get().map_or(true, |f| f.clear_to_send());
+        if !clear {
+            // Peer is busy - stop until `service_flow` sees CTS again
+            unsafe {
+                (*USART::ptr()).ucsr.modify(|_, w| w.udrie().clear_bit());
+            }
+        } else if let Some(byte) = USART::tx_buffer().borrow(cs).borrow_mut().read() {
             unsafe {
-                (*USART0::ptr()).udr.write(|w| w.bits(byte));
+                let p = USART::ptr();
+                // TXC is cleared by writing a one; it is set again once
+                // this byte has fully left the shift register
+                (*p).ucsra.modify(|r, w| w.bits((r.bits() & UCSRA_CONTROL) | TXC));
+                (*p).udr.write(|w| w.bits(byte));
+            }
+            USART::sent().borrow(cs).set(true);
+            if USART::loopback().borrow(cs).get() && !USART::rx_buffer().borrow(cs).borrow_mut().write(byte) {
+                let stats = USART::stats().borrow(cs);
+                let mut s = stats.get();
+                s.rx_dropped = s.rx_dropped.wrapping_add(1);
+                stats.set(s);
             }
         } else {
             // Buffer empty - disable TX interrupt
             unsafe {
-                (*USART0::ptr()).ucsr.modify(|_, w| w.udrie().clear_bit());
+                (*USART::ptr()).ucsr.modify(|_, w| w.udrie().clear_bit());
             }
         }
     });
-} 
\ No newline at end of file
+}
+
+// Runs once the shift register is empty with nothing in UDR; while bytes
+// keep coming UDR is refilled before that happens
+fn on_txc<USART: UartRegisterBlock>() {
+    avr_device::interrupt::free(|cs| {
+        // A byte queued since has UDRE pending, keep driving for it
+        if USART::tx_buffer().borrow(cs).borrow().len() > 0 {
+            return;
+        }
+        if let Some(de) = USART::driver_enable().borrow(cs).get() {
+            de.release();
+        }
+        unsafe {
+            (*USART::ptr()).ucsr.modify(|_, w| w.txcie().clear_bit());
+        }
+    });
+}
+
+// Interrupt handlers
+#[avr_device::interrupt(atmega128)]
+fn USART0_RX() {
+    on_rx::<USART0>();
+}
+
+#[avr_device::interrupt(atmega128)]
+fn USART0_UDRE() {
+    on_udre::<USART0>();
+}
+
+#[avr_device::interrupt(atmega128)]
+fn USART0_TX() {
+    on_txc::<USART0>();
+}
+
+// The DMX receiver owns the USART1 RX vector when the `dmx` feature is on
+#[cfg(not(feature = "dmx"))]
+#[avr_device::interrupt(atmega128)]
+fn USART1_RX() {
+    on_rx::<USART1>();
+}
+
+#[avr_device::interrupt(atmega128)]
+fn USART1_UDRE() {
+    on_udre::<USART1>();
+}
+
+#[avr_device::interrupt(atmega128)]
+fn USART1_TX() {
+    on_txc::<USART1>();
+}
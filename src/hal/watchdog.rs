@@ -1,4 +1,24 @@
+//! Watchdog timer with a pre-reset hook
+//!
+//! The ATmega128 watchdog can only reset, it has no interrupt mode. For
+//! `on_timeout` the system tick (`hal::systime`) counts the milliseconds
+//! since the last feed instead, and once three quarters of the timeout
+//! have gone by without one it runs the hook from the tick interrupt
+//! and resets the CPU right after. The hardware watchdog, which runs a
+//! little faster than the nominal timeouts, stays armed underneath for
+//! when the tick itself is stuck (interrupts disabled, Timer0 taken over
+//! by the soft RTC); that reset runs no hook.
+//!
+//! The hook gets a fresh watchdog period to save what it can, but runs
+//! with interrupts disabled: EEPROM writes and polled SPI work, anything
+//! that waits on an interrupt (buffered UART output) does not.
+#![no_std]
+
 use avr_device::atmega128::WDT;
+use avr_device::interrupt::Mutex;
+use core::cell::Cell;
+
+use crate::hal::regs::wdtcr::{self, WDCE, WDE};
 
 #[derive(Clone, Copy)]
 #[repr(u8)]
@@ -13,6 +33,38 @@ pub enum WatchdogTimeout {
     Ms2000 = 7,
 }
 
+impl WatchdogTimeout {
+    pub fn as_ms(self) -> u16 {
+        match self {
+            WatchdogTimeout::Ms16 => 16,
+            WatchdogTimeout::Ms32 => 32,
+            WatchdogTimeout::Ms64 => 64,
+            WatchdogTimeout::Ms125 => 125,
+            WatchdogTimeout::Ms250 => 250,
+            WatchdogTimeout::Ms500 => 500,
+            WatchdogTimeout::Ms1000 => 1000,
+            WatchdogTimeout::Ms2000 => 2000,
+        }
+    }
+}
+
+/// Runs from the tick interrupt just before the watchdog reset
+pub type TimeoutHook = fn();
+
+#[derive(Clone, Copy)]
+struct Supervision {
+    hook: Option<TimeoutHook>,
+    /// Tick count at which the hook runs, 0 while the watchdog is off
+    limit_ms: u16,
+    since_feed_ms: u16,
+}
+
+static SUPERVISION: Mutex<Cell<Supervision>> = Mutex::new(Cell::new(Supervision {
+    hook: None,
+    limit_ms: 0,
+    since_feed_ms: 0,
+}));
+
 pub struct Watchdog {
     _private: (),
 }
@@ -28,17 +80,19 @@ impl Watchdog {
         unsafe {
             let p = WDT::ptr();
             // Enable change bit and system reset mode
-            (*p).wdtcr.write(|w| w.bits(0x18));
+            (*p).wdtcr.write(|w| w.bits(WDCE | WDE));
             // Set timeout and enable watchdog
-            (*p).wdtcr.write(|w| w.bits(0x08 | timeout as u8));
+            (*p).wdtcr.write(|w| w.bits(WDE | (timeout as u8 & wdtcr::WDP_MASK)));
         }
+        update(|s| {
+            s.limit_ms = (timeout.as_ms() / 4 * 3).max(1);
+            s.since_feed_ms = 0;
+        });
     }
 
     #[inline]
     pub fn feed(&mut self) {
-        unsafe {
-            avr_device::asm::wdr();
-        }
+        feed();
     }
 
     #[inline]
@@ -46,9 +100,20 @@ impl Watchdog {
         unsafe {
             let p = WDT::ptr();
             // Timed sequence to disable watchdog
-            (*p).wdtcr.write(|w| w.bits(0x18));
-            (*p).wdtcr.write(|w| w.bits(0x00));
+            (*p).wdtcr.write(|w| w.bits(WDCE | WDE));
+            (*p).wdtcr.write(|w| w.bits(0));
         }
+        update(|s| s.limit_ms = 0);
+    }
+
+    /// Run `hook` before a watchdog reset, e.g. to flush the log or save
+    /// state to EEPROM. Replaces an earlier hook.
+    pub fn on_timeout(&mut self, hook: TimeoutHook) {
+        update(|s| s.hook = Some(hook));
+    }
+
+    pub fn clear_timeout_hook(&mut self) {
+        update(|s| s.hook = None);
     }
 }
 
@@ -56,4 +121,52 @@ impl Default for Watchdog {
     fn default() -> Self {
         Self::new()
     }
-} 
\ No newline at end of file
+}
+
+/// Feed the watchdog, for code without the `Watchdog` at hand
+#[inline]
+pub fn feed() {
+    unsafe {
+        avr_device::asm::wdr();
+    }
+    update(|s| s.since_feed_ms = 0);
+}
+
+/// Count one millisecond without a feed, from the system tick interrupt
+pub(crate) fn tick() {
+    let expired = avr_device::interrupt::free(|cs| {
+        let cell = SUPERVISION.borrow(cs);
+        let mut s = cell.get();
+        s.since_feed_ms = s.since_feed_ms.saturating_add(1);
+        let expired = s.limit_ms > 0 && s.since_feed_ms >= s.limit_ms;
+        let hook = if expired { s.hook } else { None };
+        cell.set(s);
+        hook
+    });
+    if let Some(hook) = expired {
+        unsafe {
+            avr_device::asm::wdr();
+        }
+        hook();
+        reset_now();
+    }
+}
+
+/// Reset through the watchdog at its shortest timeout
+fn reset_now() -> ! {
+    unsafe {
+        let p = WDT::ptr();
+        (*p).wdtcr.write(|w| w.bits(WDCE | WDE));
+        (*p).wdtcr.write(|w| w.bits(WDE | WatchdogTimeout::Ms16 as u8));
+    }
+    loop {}
+}
+
+fn update<F: FnOnce(&mut Supervision)>(f: F) {
+    avr_device::interrupt::free(|cs| {
+        let cell = SUPERVISION.borrow(cs);
+        let mut s = cell.get();
+        f(&mut s);
+        cell.set(s);
+    });
+}
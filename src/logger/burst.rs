@@ -0,0 +1,314 @@
+//! Event-triggered burst logging
+//!
+//! Samples are normally logged at a slow baseline rate. A trigger (a sensor
+//! channel crossing a threshold, a button press or `Command::Burst`) switches
+//! to logging every sample for a while, preceded by the most recent samples
+//! kept in a RAM ring, so the moments leading up to an impact or fault are
+//! captured as well.
+#![no_std]
+
+use super::Logger;
+use crate::drivers::Button;
+use crate::protocol::{ProtocolError, Result};
+
+/// Sensor channels per sample
+pub const CHANNELS: usize = 6;
+const MAX_THRESHOLDS: usize = 4;
+/// Samples of history written ahead of a burst
+const PRE_TRIGGER_SAMPLES: usize = 16;
+
+// Protocol sub-commands carried in the first payload byte of Command::Burst
+const OP_TRIGGER: u8 = 0x01;
+const OP_SET_THRESHOLD: u8 = 0x02;
+const OP_CLEAR_THRESHOLD: u8 = 0x03;
+const OP_STATUS: u8 = 0x04;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum Edge {
+    /// Fires when the channel rises to or above the level
+    Rising = 0,
+    /// Fires when the channel falls to or below the level
+    Falling = 1,
+}
+
+#[derive(Clone, Copy)]
+pub struct Threshold {
+    pub channel: u8,
+    pub edge: Edge,
+    pub level: i16,
+}
+
+impl Threshold {
+    fn crossed(&self, previous: i16, value: i16) -> bool {
+        match self.edge {
+            Edge::Rising => previous < self.level && value >= self.level,
+            Edge::Falling => previous > self.level && value <= self.level,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum TriggerSource {
+    /// Threshold slot that fired
+    Threshold(u8),
+    Button(Button),
+    Command,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum BurstState {
+    Baseline = 0,
+    Burst = 1,
+}
+
+#[derive(Clone, Copy)]
+pub struct BurstConfig {
+    /// Log one in this many samples outside a burst
+    pub baseline_divider: u16,
+    /// Samples logged after the trigger
+    pub post_trigger_samples: u16,
+}
+
+impl Default for BurstConfig {
+    fn default() -> Self {
+        Self {
+            baseline_divider: 100,
+            post_trigger_samples: 200,
+        }
+    }
+}
+
+/// Callback invoked when a burst starts
+pub type BurstHandler = fn(TriggerSource);
+
+#[derive(Clone, Copy)]
+struct Sample {
+    index: u32,
+    channels: [i16; CHANNELS],
+}
+
+pub struct BurstLogger {
+    config: BurstConfig,
+    thresholds: [Option<Threshold>; MAX_THRESHOLDS],
+    button: Option<Button>,
+    handler: Option<BurstHandler>,
+    history: [Sample; PRE_TRIGGER_SAMPLES],
+    history_head: usize,
+    history_len: usize,
+    previous: Option<[i16; CHANNELS]>,
+    pending: Option<TriggerSource>,
+    state: BurstState,
+    remaining: u16,
+    index: u32,
+    bursts: u16,
+}
+
+impl BurstLogger {
+    pub const fn new(config: BurstConfig) -> Self {
+        Self {
+            config,
+            thresholds: [None; MAX_THRESHOLDS],
+            button: None,
+            handler: None,
+            history: [Sample {
+                index: 0,
+                channels: [0; CHANNELS],
+            }; PRE_TRIGGER_SAMPLES],
+            history_head: 0,
+            history_len: 0,
+            previous: None,
+            pending: None,
+            state: BurstState::Baseline,
+            remaining: 0,
+            index: 0,
+            bursts: 0,
+        }
+    }
+
+    pub fn set_config(&mut self, config: BurstConfig) {
+        self.config = config;
+    }
+
+    pub fn set_handler(&mut self, handler: BurstHandler) {
+        self.handler = Some(handler);
+    }
+
+    pub fn set_threshold(&mut self, slot: usize, threshold: Threshold) -> Result<()> {
+        if slot >= MAX_THRESHOLDS || threshold.channel as usize >= CHANNELS {
+            return Err(ProtocolError::InvalidPacket);
+        }
+        self.thresholds[slot] = Some(threshold);
+        Ok(())
+    }
+
+    pub fn clear_threshold(&mut self, slot: usize) -> Result<()> {
+        if slot >= MAX_THRESHOLDS {
+            return Err(ProtocolError::InvalidPacket);
+        }
+        self.thresholds[slot] = None;
+        Ok(())
+    }
+
+    /// Button that starts a burst, passed on from `ButtonHandler` events
+    pub fn set_button_trigger(&mut self, button: Option<Button>) {
+        self.button = button;
+    }
+
+    pub fn on_button(&mut self, button: Button) {
+        if self.button == Some(button) {
+            self.trigger(TriggerSource::Button(button));
+        }
+    }
+
+    /// Start a burst with the next sample
+    pub fn trigger(&mut self, source: TriggerSource) {
+        if self.pending.is_none() {
+            self.pending = Some(source);
+        }
+    }
+
+    pub fn state(&self) -> BurstState {
+        self.state
+    }
+
+    /// Feed one sample of every channel at the full acquisition rate
+    pub fn push(&mut self, channels: [i16; CHANNELS], logger: &mut Logger) -> core::result::Result<(), ()> {
+        let sample = Sample {
+            index: self.index,
+            channels,
+        };
+        self.index = self.index.wrapping_add(1);
+
+        if let Some(previous) = self.previous {
+            for (slot, threshold) in self.thresholds.iter().enumerate() {
+                if let Some(t) = threshold {
+                    let ch = t.channel as usize;
+                    if t.crossed(previous[ch], channels[ch]) {
+                        self.trigger(TriggerSource::Threshold(slot as u8));
+                    }
+                }
+            }
+        }
+        self.previous = Some(channels);
+
+        if self.state == BurstState::Baseline {
+            if let Some(source) = self.pending.take() {
+                self.start_burst(source, logger)?;
+            }
+        } else {
+            // Triggers during a burst are absorbed by it
+            self.pending = None;
+        }
+
+        match self.state {
+            BurstState::Burst => {
+                write_sample(logger, &sample)?;
+                self.remaining = self.remaining.saturating_sub(1);
+                if self.remaining == 0 {
+                    self.state = BurstState::Baseline;
+                    self.history_len = 0;
+                    logger.flush()?;
+                }
+            }
+            BurstState::Baseline => {
+                if self.is_baseline(sample.index) {
+                    write_sample(logger, &sample)?;
+                }
+                self.history[self.history_head] = sample;
+                self.history_head = (self.history_head + 1) % PRE_TRIGGER_SAMPLES;
+                self.history_len = (self.history_len + 1).min(PRE_TRIGGER_SAMPLES);
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a `Command::Burst` payload and write the reply into `response`.
+    ///
+    /// `OP_SET_THRESHOLD` takes `[op, slot, channel, edge, level(i16 LE)]`,
+    /// `OP_CLEAR_THRESHOLD` takes `[op, slot]`. `OP_STATUS` replies with
+    /// `[state, remaining(u16 LE), bursts(u16 LE)]`.
+    pub fn handle_command(&mut self, data: &[u8], response: &mut [u8]) -> Result<usize> {
+        let op = *data.first().ok_or(ProtocolError::InvalidPacket)?;
+
+        match op {
+            OP_TRIGGER => {
+                self.trigger(TriggerSource::Command);
+                Ok(0)
+            }
+            OP_SET_THRESHOLD => {
+                if data.len() != 6 {
+                    return Err(ProtocolError::InvalidPacket);
+                }
+                let edge = match data[3] {
+                    0 => Edge::Rising,
+                    1 => Edge::Falling,
+                    _ => return Err(ProtocolError::InvalidPacket),
+                };
+                let threshold = Threshold {
+                    channel: data[2],
+                    edge,
+                    level: i16::from_le_bytes([data[4], data[5]]),
+                };
+                self.set_threshold(data[1] as usize, threshold)?;
+                Ok(0)
+            }
+            OP_CLEAR_THRESHOLD => {
+                let slot = *data.get(1).ok_or(ProtocolError::InvalidPacket)?;
+                self.clear_threshold(slot as usize)?;
+                Ok(0)
+            }
+            OP_STATUS => {
+                if response.len() < 5 {
+                    return Err(ProtocolError::BufferOverflow);
+                }
+                response[0] = self.state as u8;
+                response[1..3].copy_from_slice(&self.remaining.to_le_bytes());
+                response[3..5].copy_from_slice(&self.bursts.to_le_bytes());
+                Ok(5)
+            }
+            _ => Err(ProtocolError::InvalidCommand),
+        }
+    }
+
+    fn start_burst(&mut self, source: TriggerSource, logger: &mut Logger) -> core::result::Result<(), ()> {
+        // Oldest history first, skipping what the baseline already logged
+        let start = (self.history_head + PRE_TRIGGER_SAMPLES - self.history_len) % PRE_TRIGGER_SAMPLES;
+        for i in 0..self.history_len {
+            let sample = self.history[(start + i) % PRE_TRIGGER_SAMPLES];
+            if !self.is_baseline(sample.index) {
+                write_sample(logger, &sample)?;
+            }
+        }
+        self.history_len = 0;
+
+        self.state = BurstState::Burst;
+        self.remaining = self.config.post_trigger_samples.max(1);
+        self.bursts = self.bursts.wrapping_add(1);
+        if let Some(handler) = self.handler {
+            handler(source);
+        }
+        Ok(())
+    }
+
+    fn is_baseline(&self, index: u32) -> bool {
+        index % self.config.baseline_divider.max(1) as u32 == 0
+    }
+}
+
+impl Default for BurstLogger {
+    fn default() -> Self {
+        Self::new(BurstConfig::default())
+    }
+}
+
+/// Log entry layout: `[index(u32 LE), channels(i16 LE x 6)]`
+fn write_sample(logger: &mut Logger, sample: &Sample) -> core::result::Result<(), ()> {
+    let mut data = [0u8; 4 + CHANNELS * 2];
+    data[0..4].copy_from_slice(&sample.index.to_le_bytes());
+    for (i, value) in sample.channels.iter().enumerate() {
+        data[4 + i * 2..6 + i * 2].copy_from_slice(&value.to_le_bytes());
+    }
+    logger.log_sensor(&data)
+}
@@ -1,14 +1,30 @@
 //! Data logging system implementation
 #![no_std]
 
+pub mod burst;
+
+use crate::config::{FLASH_LOG_SECTORS, FLASH_LOG_START, FLASH_SECTOR_SIZE, LOG_BUFFER_ENTRIES};
 use crate::drivers::flash::Flash;
+use crate::hal::{rtc_soft, systime};
 use crate::hal::timer::Timer;
 
 pub struct LogEntry {
+    /// Wall-clock seconds if `wall_clock` is set, milliseconds since boot
+    /// otherwise
     timestamp: u32,
     log_type: LogType,
     data: [u8; 16],
     length: u8,
+    /// `timestamp` came from `rtc_soft` after the time was set
+    wall_clock: bool,
+}
+
+impl LogEntry {
+    /// Timestamp and whether it is wall-clock seconds rather than
+    /// milliseconds since boot
+    pub fn timestamp(&self) -> (u32, bool) {
+        (self.timestamp, self.wall_clock)
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -23,7 +39,7 @@ pub struct Logger {
     flash: Flash,
     current_sector: u32,
     write_pointer: u32,
-    buffer: [LogEntry; 32],
+    buffer: [LogEntry; LOG_BUFFER_ENTRIES],
     buffer_index: usize,
 }
 
@@ -38,11 +54,17 @@ impl Logger {
                 log_type: LogType::System,
                 data: [0; 16],
                 length: 0,
-            }; 32],
+                wall_clock: false,
+            }; LOG_BUFFER_ENTRIES],
             buffer_index: 0,
         }
     }
 
+    /// The flash under the ring, for background jobs reading other regions
+    pub fn flash(&mut self) -> &mut Flash {
+        &mut self.flash
+    }
+
     pub fn init(&mut self) -> Result<(), ()> {
         self.current_sector = self.find_last_sector()?;
         self.write_pointer = self.find_write_pointer()?;
@@ -70,8 +92,10 @@ impl Logger {
             return Err(());
         }
 
+        let (timestamp, wall_clock) = timestamp();
         let entry = LogEntry {
-            timestamp: get_timestamp(),
+            timestamp,
+            wall_clock,
             log_type,
             data: {
                 let mut buf = [0u8; 16];
@@ -103,17 +127,17 @@ impl Logger {
             )
         };
 
-        if self.write_pointer + data.len() as u32 > 0x1000 {
+        if self.write_pointer + data.len() as u32 > FLASH_SECTOR_SIZE {
             self.current_sector += 1;
-            if self.current_sector >= 0x100 {
+            if self.current_sector >= FLASH_LOG_SECTORS {
                 self.current_sector = 0;
             }
-            self.flash.erase_sector(self.current_sector * 0x1000)?;
+            self.flash.erase_sector(sector_addr(self.current_sector))?;
             self.write_pointer = 0;
         }
 
         self.flash.write(
-            self.current_sector * 0x1000 + self.write_pointer,
+            sector_addr(self.current_sector) + self.write_pointer,
             data,
         )?;
 
@@ -129,9 +153,9 @@ impl Logger {
             let mut buffer = [0u8; core::mem::size_of::<LogEntry>()];
             let mut offset = 0;
 
-            while offset < 0x1000 {
+            while offset < FLASH_SECTOR_SIZE {
                 self.flash.read(
-                    sector * 0x1000 + offset,
+                    sector_addr(sector) + offset,
                     &mut buffer,
                 )?;
 
@@ -152,9 +176,9 @@ impl Logger {
     }
 
     fn find_last_sector(&mut self) -> Result<u32, ()> {
-        for sector in 0..0x100 {
+        for sector in 0..FLASH_LOG_SECTORS {
             let mut buffer = [0u8; 4];
-            self.flash.read(sector * 0x1000, &mut buffer)?;
+            self.flash.read(sector_addr(sector), &mut buffer)?;
             if buffer == [0xFF; 4] {
                 return Ok(if sector == 0 { 0 } else { sector - 1 });
             }
@@ -164,7 +188,7 @@ impl Logger {
 
     fn find_write_pointer(&mut self) -> Result<u32, ()> {
         let mut left = 0;
-        let mut right = 0x1000;
+        let mut right = FLASH_SECTOR_SIZE;
 
         while left < right {
             let mid = left + (right - left) / 2;
@@ -172,7 +196,7 @@ impl Logger {
 
             let mut buffer = [0u8; 4];
             self.flash.read(
-                self.current_sector * 0x1000 + mid,
+                sector_addr(self.current_sector) + mid,
                 &mut buffer,
             )?;
 
@@ -187,7 +211,17 @@ impl Logger {
     }
 }
 
-fn get_timestamp() -> u32 {
-    // TODO: Implement real timestamp
-    0
+/// Address of a sector of the ring, see the flash map in `config`
+fn sector_addr(sector: u32) -> u32 {
+    FLASH_LOG_START + sector * FLASH_SECTOR_SIZE
+}
+
+/// Wall-clock seconds once `rtc_soft` has the time, milliseconds since
+/// boot before that; the flag tells which
+fn timestamp() -> (u32, bool) {
+    if rtc_soft::is_time_set() {
+        (rtc_soft::now(), true)
+    } else {
+        (systime::millis(), false)
+    }
 }
@@ -10,13 +10,38 @@ use avr_device::interrupt::{self, Mutex};
 mod hal;
 mod drivers;
 mod application;
+mod atomic;
 mod config;
+mod control;
+mod diagnostics;
+mod estimation;
+mod logger;
+mod math;
 mod os;
+mod protocol;
+mod safety;
+mod shutdown;
+mod stats;
+mod thermal;
+mod time;
 
-use drivers::{LedMatrix, SerialConsole, ButtonHandler, ButtonEvent, Button};
-use hal::{Power, SleepMode, Watchdog, WatchdogTimeout, Adc, AdcChannel};
+use drivers::{LedMatrix, LineInput, SerialConsole, ButtonHandler, ButtonEvent, Button};
+use drivers::flash::Flash;
+use hal::{Power, SleepMode, Spi, Watchdog, WatchdogTimeout, Adc, AdcArbiter, DeviceInfo, UpdateStatus};
 use application::Application;
+use application::pid_tune::PidTuner;
+use stats::{Accumulator, StatsRegistry};
+use control::{AttitudeController, Mixer};
 use os::Scheduler;
+use os::background::{self, FlashJob, JobState};
+use os::frame::{FramePriority, FrameScheduler};
+use diagnostics::{Diagnostics, ErrorCode};
+use diagnostics::dump::{self, DumpJob};
+use diagnostics::fault_inject;
+use diagnostics::flash_audit::{self, AuditRegion, FlashAuditJob};
+use logger::Logger;
+use diagnostics::memtest::{self, MemoryTest};
+use shutdown::ShutdownReason;
 
 // Global state for interrupt handling
 static GLOBAL_PERIPHERALS: Mutex<RefCell<Option<Peripherals>>> = 
@@ -25,6 +50,9 @@ static GLOBAL_PERIPHERALS: Mutex<RefCell<Option<Peripherals>>> =
 #[avr_device::entry]
 fn main() -> ! {
     let dp = Peripherals::take().unwrap();
+
+    // Before anything can cause another reset
+    let reset_reason = hal::reset::init();
     
     interrupt::free(|cs| {
         GLOBAL_PERIPHERALS.borrow(cs).replace(Some(dp));
@@ -36,27 +64,226 @@ fn main() -> ! {
     let mut buttons = ButtonHandler::new();
     let mut power = Power::new();
     let mut watchdog = Watchdog::new();
-    let mut adc = Adc::new();
+    let mut adc = AdcArbiter::new(Adc::new());
     let mut scheduler = Scheduler::new();
 
+    // Pick the pin mapping of this board revision before anything uses it
+    let board = hal::board_id::detect(&mut adc);
+
+    // Error log on the external flash, which the background jobs share;
+    // a board without the chip runs without either
+    let mut diagnostics = Flash::new(Spi::new(), hal::gpio::board::flash_cs()).ok().map(|flash| {
+        let mut logger = Logger::new(flash);
+        logger.init().ok();
+        let mut diagnostics = Diagnostics::new(logger);
+        // Brown-outs and watchdog resets go to the error log as well
+        diagnostics.report_reset_reason();
+        diagnostics
+    });
+    if let Some(diagnostics) = diagnostics.as_mut() {
+        // Stays in this frame until reset
+        unsafe { diagnostics.register_shutdown_hooks() };
+    }
+
     // Enable watchdog with 1s timeout
     watchdog.start(WatchdogTimeout::Ms1000);
+    // Run the shutdown hooks (outputs off, log flush) before a watchdog reset
+    watchdog.on_timeout(|| {
+        shutdown::run(ShutdownReason::WatchdogImminent);
+    });
+
+    // 1ms system tick
+    hal::systime::start();
 
     // Enable interrupts globally
     unsafe { avr_device::interrupt::enable() };
 
     // Print startup message
-    console.write_line("ATmega128 Firmware v0.1.0");
-    console.write_line("Ready...");
+    console.write_pgm_line(pgm_str!("ATmega128 Firmware v0.1.0"));
+    console.write_str("Board rev ");
+    console.write_line(board.revision.name());
+    console.write_str("Reset: ");
+    console.write_line(reset_reason.name());
+    console.write_str("Static RAM: ");
+    console.write_decimal(memtest::static_ram_bytes() as u32);
+    console.write_line("B");
+    console.write_pgm_line(pgm_str!("Ready..."));
+
+    // Fuses that don't match this build make timing silently wrong
+    let warnings = DeviceInfo::read().warnings();
+    if warnings != 0 {
+        console.debug("Fuse warnings", warnings);
+    }
+
+    // Report what the bootloader did before handing over
+    if let Some(boot) = hal::mailbox::take() {
+        match boot.update {
+            UpdateStatus::Updated => {
+                // New image, the audit learns its CRC again
+                flash_audit::mark_written(AuditRegion::Application);
+                console.write_pgm_line(pgm_str!("Firmware updated"));
+            }
+            UpdateStatus::Failed => console.debug("Update failed", boot.last_error),
+            UpdateStatus::None => {}
+        }
+    }
 
     // Main application loop
     let mut app = Application::new();
+
+    // LED animation is the first thing to go when the loop runs late
+    let mut frame = FrameScheduler::default();
+    let app_slot = frame.register("app", 1500, FramePriority::High).unwrap();
+    let leds_slot = frame.register("leds", 300, FramePriority::Low).unwrap();
+    let adc_slot = frame.register("adc", 200, FramePriority::High).unwrap();
+    let jobs_slot = frame.register("jobs", 300, FramePriority::Low).unwrap();
+    let console_slot = frame.register("console", 200, FramePriority::Low).unwrap();
+
+    // Test the unused RAM in the background, a step at a time, once
+    let mut memtest = Some(MemoryTest::start());
+    // CRC the program and configuration flash over and over
+    let mut audit = FlashAuditJob::new();
+    // Started from console commands, one of each at a time
+    let mut input = LineInput::new();
+    let mut dump_job: Option<DumpJob> = None;
+    let mut flash_job: Option<FlashJob> = None;
+
+    // Attitude gains, tuned live with `pid set` and kept with `pid save`
+    let mut attitude = AttitudeController::new(Mixer::quad_x());
+    let mut pid_tune = PidTuner::new();
+    let [roll, pitch, yaw] = attitude.axis_configs();
+    let pid_loops = [
+        pid_tune.register("roll", roll).unwrap(),
+        pid_tune.register("pitch", pitch).unwrap(),
+        pid_tune.register("yaw", yaw).unwrap(),
+    ];
+    pid_tune.load().ok();
+
+    // `stats` console command; the loop time is kept by the diagnostics
+    // and copied in when a command comes
+    let mut stats = StatsRegistry::new();
+    let loop_stat = stats.register("loop_us", Accumulator::with_histogram(0.0, 8000.0)).unwrap();
+    let mut last_frame_report = 0u32;
+
+    // Boot self-test, finishing the RAM test started above. Without it
+    // passed (or without the flash to log faults to) the outputs can't be
+    // armed.
+    let self_test_passed = diagnostics.as_mut().map_or(false, |d| d.run_diagnostics(&mut adc).is_ok());
+    if !self_test_passed {
+        console.write_pgm_line(pgm_str!("Self-test failed"));
+    }
+    safety::init_complete(self_test_passed);
     
     loop {
-        let ticks = scheduler.get_ticks();
+        let ticks = hal::systime::millis();
+        let loop_start = hal::systime::micros();
+        frame.begin();
         
         // Update application state
-        app.update(&mut leds, &mut console, &mut buttons, &mut adc, ticks);
+        frame.run(app_slot, || app.update(&mut leds, &mut buttons, &mut adc)).ok();
+        
+        // Advance LED animations
+        frame.run(leds_slot, || leds.tick(time::Instant::from_ticks(ticks))).ok();
+
+        // Service queued ADC conversions
+        frame.run(adc_slot, || adc.poll()).ok();
+
+        // Background jobs get what is left of the frame
+        frame.run(jobs_slot, || {
+            if let Some(job) = memtest.as_mut() {
+                match background::run_slice(job, &mut (), 150) {
+                    JobState::Running(_) => {}
+                    JobState::Done => memtest = None,
+                    JobState::Failed(detail) => {
+                        print_memory_fault(&mut console, detail);
+                        if let Some(diagnostics) = diagnostics.as_mut() {
+                            diagnostics.report_error(ErrorCode::MemoryError, 0x0103, detail);
+                        }
+                        memtest = None;
+                    }
+                }
+            }
+            if let Some(job) = dump_job.as_mut() {
+                let flash = diagnostics.as_mut().map(|d| d.flash());
+                if !matches!(background::run_slice(job, &mut (&mut console, flash), 100), JobState::Running(_)) {
+                    dump_job = None;
+                }
+            }
+            if let Some(diagnostics) = diagnostics.as_mut() {
+                match flash_job.as_mut().map(|job| background::run_slice(job, diagnostics.flash(), 150)) {
+                    None => {
+                        // The audit would read a chip being erased
+                        if !matches!(background::run_slice(&mut audit, diagnostics.flash(), 150), JobState::Running(_)) {
+                            audit = FlashAuditJob::new();
+                        }
+                    }
+                    Some(JobState::Running(_)) => {}
+                    Some(JobState::Done) => {
+                        if let Some(FlashJob::Erase(_)) = flash_job {
+                            // The external regions are blank now
+                            flash_audit::mark_external_written();
+                        }
+                        if let Some(job) = flash_job.take() {
+                            job.report(&mut console);
+                        }
+                    }
+                    Some(JobState::Failed(detail)) => {
+                        console.write_str("flash job failed: ");
+                        console.write_decimal(detail);
+                        console.write_str("\r\n");
+                        flash_job = None;
+                    }
+                }
+                diagnostics.poll_flash_audit();
+                diagnostics.poll_claim_conflicts();
+                diagnostics.poll_twi_errors();
+            }
+        }).ok();
+
+        // Console output and commands, after the work that can't wait
+        frame.run(console_slot, || {
+            app.write_console(&mut console);
+            if let Some(line) = input.poll(&mut console) {
+                if let Some(job) = dump::process_line(line, &mut console) {
+                    dump_job = Some(job);
+                } else if let Some(job) = background::process_line(line, &mut console) {
+                    flash_job = Some(job);
+                } else {
+                    pid_tune.process_line(line, &mut console);
+                    if let Some(diagnostics) = diagnostics.as_ref() {
+                        stats.publish(loop_stat, diagnostics.loop_time());
+                    }
+                    stats.process_line(line, &mut console);
+                    // Answers "not built in" without the fault-injection feature
+                    fault_inject::process_line(line, &mut console);
+                    if let (Some(diagnostics), Some(loop_time)) = (diagnostics.as_mut(), stats.get(loop_stat)) {
+                        *diagnostics.loop_time_mut() = *loop_time;
+                    }
+                }
+            }
+            if let Some(config) = pid_tune.take_update(pid_loops[0]) {
+                attitude.configure_roll(config);
+            }
+            if let Some(config) = pid_tune.take_update(pid_loops[1]) {
+                attitude.configure_pitch(config);
+            }
+            if let Some(config) = pid_tune.take_update(pid_loops[2]) {
+                attitude.configure_yaw(config);
+            }
+        }).ok();
+        frame.end();
+        if let Some(diagnostics) = diagnostics.as_mut() {
+            diagnostics.record_loop_time(hal::systime::micros().wrapping_sub(loop_start));
+        }
+
+        // Overruns go to the error log at most once a second, so a loop
+        // that keeps overrunning doesn't fill the log
+        if ticks.wrapping_sub(last_frame_report) >= 1000 {
+            last_frame_report = ticks;
+            if let Some(diagnostics) = diagnostics.as_mut() {
+                frame.report(diagnostics);
+            }
+        }
         
         // Pet watchdog
         watchdog.feed();
@@ -64,4 +291,17 @@ fn main() -> ! {
         // Enter sleep mode until next tick
         scheduler.sleep(&mut power);
     }
-} 
\ No newline at end of file
+} 
+
+/// "RAM fault at 0xAAAA: wrote 0xEE, read 0xRR" from a `MemoryFault` detail
+fn print_memory_fault(console: &mut SerialConsole, detail: u32) {
+    let [addr_hi, addr_lo, expected, actual] = detail.to_be_bytes();
+    console.write_str("RAM fault at 0x");
+    console.write_hex(addr_hi);
+    console.write_hex(addr_lo);
+    console.write_str(": wrote 0x");
+    console.write_hex(expected);
+    console.write_str(", read 0x");
+    console.write_hex(actual);
+    console.write_str("\r\n");
+}
@@ -0,0 +1,107 @@
+//! Radix-2 fixed-point FFT
+//!
+//! In-place decimation-in-time transform over Q15 samples for 2..=128
+//! points. Every butterfly stage halves its output, so the result is scaled
+//! by 1/N and can never overflow 16 bits.
+#![no_std]
+
+pub const MAX_POINTS: usize = 128;
+
+// Quarter sine wave, sin(2*pi*k/128) in Q15 for k = 0..=32
+const QUARTER_SINE: [i16; 33] = [
+    0, 1608, 3212, 4808, 6393, 7962, 9512, 11039, 12540, 14010, 15447, 16846, 18205, 19520,
+    20788, 22006, 23170, 24279, 25330, 26320, 27246, 28106, 28899, 29622, 30274, 30853, 31357,
+    31786, 32138, 32413, 32610, 32729, 32767,
+];
+
+/// sin(2*pi*k/128) in Q15
+fn sin_q15(k: usize) -> i32 {
+    let k = k % MAX_POINTS;
+    match k {
+        0..=32 => QUARTER_SINE[k] as i32,
+        33..=64 => QUARTER_SINE[64 - k] as i32,
+        65..=96 => -(QUARTER_SINE[k - 64] as i32),
+        _ => -(QUARTER_SINE[128 - k] as i32),
+    }
+}
+
+fn cos_q15(k: usize) -> i32 {
+    sin_q15(k + MAX_POINTS / 4)
+}
+
+/// Transform `re`/`im` in place. Both slices must have the same power of
+/// two length, at most `MAX_POINTS`.
+pub fn fft(re: &mut [i16], im: &mut [i16]) -> Result<(), ()> {
+    let n = re.len();
+    if n != im.len() || n < 2 || n > MAX_POINTS || !n.is_power_of_two() {
+        return Err(());
+    }
+
+    // Bit-reversal permutation
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = (i as u8).reverse_bits() as usize >> (8 - bits);
+        if j > i {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let half = len / 2;
+        let step = MAX_POINTS / len;
+        for start in (0..n).step_by(len) {
+            for j in 0..half {
+                // W = exp(-2*pi*i*j/len)
+                let wr = cos_q15(j * step);
+                let wi = -sin_q15(j * step);
+                let a = start + j;
+                let b = a + half;
+
+                let br = re[b] as i32;
+                let bi = im[b] as i32;
+                let tr = (br * wr - bi * wi) >> 15;
+                let ti = (br * wi + bi * wr) >> 15;
+                let ar = re[a] as i32;
+                let ai = im[a] as i32;
+
+                re[a] = ((ar + tr) >> 1) as i16;
+                im[a] = ((ai + ti) >> 1) as i16;
+                re[b] = ((ar - tr) >> 1) as i16;
+                im[b] = ((ai - ti) >> 1) as i16;
+            }
+        }
+        len *= 2;
+    }
+
+    Ok(())
+}
+
+/// Magnitude of the first `out.len()` bins
+pub fn magnitudes(re: &[i16], im: &[i16], out: &mut [u16]) {
+    for (i, mag) in out.iter_mut().enumerate() {
+        let r = re[i] as i32;
+        let m = im[i] as i32;
+        *mag = isqrt((r * r) as u32 + (m * m) as u32) as u16;
+    }
+}
+
+fn isqrt(value: u32) -> u32 {
+    let mut result = 0u32;
+    let mut bit = 1u32 << 30;
+    let mut rem = value;
+    while bit > rem {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rem >= result + bit {
+            rem -= result + bit;
+            result = (result >> 1) + bit;
+        } else {
+            result >>= 1;
+        }
+        bit >>= 2;
+    }
+    result
+}
@@ -0,0 +1,28 @@
+//! Fixed-point numeric routines
+#![no_std]
+
+pub mod fft;
+pub mod prng;
+
+pub use fft::{fft, magnitudes};
+pub use prng::Xorshift32;
+
+/// Integer square root, rounded down
+pub fn isqrt_u64(value: u64) -> u64 {
+    let mut result = 0u64;
+    let mut bit = 1u64 << 62;
+    let mut rem = value;
+    while bit > rem {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rem >= result + bit {
+            rem -= result + bit;
+            result = (result >> 1) + bit;
+        } else {
+            result >>= 1;
+        }
+        bit >>= 2;
+    }
+    result
+}
@@ -0,0 +1,55 @@
+//! xorshift32 pseudo-random numbers
+//!
+//! Cheap on an 8-bit core (shifts and XORs only) and fully determined by
+//! the seed, so a simulation or a fuzz run can be replayed. Not for
+//! anything secret.
+#![no_std]
+
+// Zero is a fixed point of xorshift and would only ever produce zeros
+const ZERO_SEED_REPLACEMENT: u32 = 0x2545_F491;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Xorshift32(u32);
+
+impl Xorshift32 {
+    pub const fn new(seed: u32) -> Self {
+        Self(if seed == 0 { ZERO_SEED_REPLACEMENT } else { seed })
+    }
+
+    /// Current state, seeding a new generator with it continues the sequence
+    pub fn state(&self) -> u32 {
+        self.0
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        self.next_u32() as u8
+    }
+
+    /// Uniform in `0..bound`, 0 for a bound of 0
+    pub fn below(&mut self, bound: u32) -> u32 {
+        if bound == 0 {
+            return 0;
+        }
+        ((self.next_u32() as u64 * bound as u64) >> 32) as u32
+    }
+
+    /// Uniform in `-amplitude..=amplitude`
+    pub fn symmetric(&mut self, amplitude: u32) -> i32 {
+        let span = amplitude.saturating_mul(2).saturating_add(1);
+        self.below(span) as i32 - amplitude.min(i32::MAX as u32 / 2) as i32
+    }
+
+    /// True `per_mille` times in a thousand
+    pub fn chance(&mut self, per_mille: u16) -> bool {
+        self.below(1000) < per_mille as u32
+    }
+}
@@ -3,32 +3,65 @@
 
 use crate::config::CPU_FREQ_HZ;
 use crate::hal::Power;
-use core::cell::Cell;
+use crate::atomic::AtomicU32;
+
+pub mod background;
+pub mod frame;
 
 /// Simple task scheduler and system time tracking
 pub struct Scheduler {
-    tick_count: Cell<u32>,
+    tick_count: AtomicU32,
+    /// Number of times `tick_count` wrapped, extends it to 64 bits
+    tick_epoch: AtomicU32,
 }
 
 impl Scheduler {
     /// Create new scheduler instance
     pub const fn new() -> Self {
         Self {
-            tick_count: Cell::new(0),
+            tick_count: AtomicU32::new(0),
+            tick_epoch: AtomicU32::new(0),
         }
     }
 
     /// Increment system tick counter
     #[inline]
     pub fn tick(&self) {
-        let count = self.tick_count.get();
-        self.tick_count.set(count.wrapping_add(1));
+        avr_device::interrupt::free(|cs| {
+            let count = self.tick_count.borrow(cs);
+            count.set(count.get().wrapping_add(1));
+            if count.get() == 0 {
+                let epoch = self.tick_epoch.borrow(cs);
+                epoch.set(epoch.get().wrapping_add(1));
+            }
+        });
+    }
+
+    /// Add `ms` ticks at once, for a timebase slower than 1ms
+    pub fn advance(&self, ms: u32) {
+        avr_device::interrupt::free(|cs| {
+            let count = self.tick_count.borrow(cs);
+            let (next, wrapped) = count.get().overflowing_add(ms);
+            count.set(next);
+            if wrapped {
+                let epoch = self.tick_epoch.borrow(cs);
+                epoch.set(epoch.get().wrapping_add(1));
+            }
+        });
     }
 
     /// Get current system tick count
     #[inline]
     pub fn get_ticks(&self) -> u32 {
-        self.tick_count.get()
+        self.tick_count.load()
+    }
+
+    /// Get the tick count extended to 64 bits so it never wraps
+    pub fn get_ticks64(&self) -> u64 {
+        // Both halves have to come from the same side of a wrap
+        avr_device::interrupt::free(|cs| {
+            ((self.tick_epoch.borrow(cs).get() as u64) << 32) | self.tick_count.borrow(cs).get() as u64
+        })
     }
 
     /// Enter sleep mode until next interrupt
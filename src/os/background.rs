@@ -0,0 +1,312 @@
+//! Long operations in time-bounded steps
+//!
+//! A chip erase takes most of a minute and a CRC over the whole flash
+//! several seconds; run in one go they hold the main loop long past the
+//! watchdog timeout. A `Job` instead does one small piece of work per
+//! `step` (one page, one status poll), and `run_slice` steps it until the
+//! slice's time budget on `systime::micros` is spent. Give jobs a `Low`
+//! slot of the `FrameScheduler`, so they only get time the control loop
+//! does not need:
+//!
+//! ```ignore
+//! frame.run(jobs_id, || { background::run_slice(&mut crc_job, &mut flash, 500); }).ok();
+//! ```
+//!
+//! Jobs report progress as `done` of `total` units of their own choosing
+//! (bytes, milliseconds), for the console and the host.
+//!
+//! The flash jobs are started from the serial console:
+//!
+//! ```text
+//! flash crc <sector> [len]   CRC of a region, one 4KB sector by default
+//! flash erase yes            erase the whole chip, log and settings included
+//! ```
+#![no_std]
+
+use crate::diagnostics::dump::FLASH_SECTORS;
+use crate::drivers::flash::{Flash, FlashError, SECTOR_SIZE};
+use crate::drivers::SerialConsole;
+use crate::hal::systime;
+use crate::pgm_str;
+use crate::protocol::crc::{crc16_update, CRC16_INIT};
+
+/// Work done so far, in units of the job's choosing
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Progress {
+    pub done: u32,
+    pub total: u32,
+}
+
+impl Progress {
+    pub fn percent(&self) -> u8 {
+        if self.total == 0 {
+            return 100;
+        }
+        (self.done.min(self.total) as u64 * 100 / self.total as u64) as u8
+    }
+}
+
+/// Result of one step
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Step {
+    /// More to do
+    Pending,
+    Done,
+    /// Gave up, with a job-specific detail code
+    Failed(u32),
+}
+
+/// Job state after a slice
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum JobState {
+    Running(Progress),
+    Done,
+    Failed(u32),
+}
+
+pub trait Job {
+    /// What `step` works on, e.g. the flash, or a tuple of borrows when a
+    /// job needs more than one thing
+    type Context<'a>;
+
+    fn name(&self) -> &'static str;
+
+    /// Do one small, bounded piece of the work. Called again after
+    /// `Pending` only.
+    fn step(&mut self, context: &mut Self::Context<'_>) -> Step;
+
+    fn progress(&self) -> Progress;
+}
+
+/// Step `job` until `budget_us` is spent or it finishes. At least one step
+/// runs, so a job always moves on.
+pub fn run_slice<J: Job>(job: &mut J, context: &mut J::Context<'_>, budget_us: u32) -> JobState {
+    let start = systime::micros();
+    loop {
+        match job.step(context) {
+            Step::Pending => {}
+            Step::Done => return JobState::Done,
+            Step::Failed(detail) => return JobState::Failed(detail),
+        }
+        if systime::micros().wrapping_sub(start) >= budget_us {
+            return JobState::Running(job.progress());
+        }
+    }
+}
+
+// Flash bytes read per step, about 150us at the full SPI clock
+const CRC_CHUNK: usize = 64;
+
+/// CRC-16/CCITT-FALSE of a flash region, as printed by `dump`
+pub struct FlashCrcJob {
+    start: u32,
+    len: u32,
+    done: u32,
+    crc: u16,
+}
+
+impl FlashCrcJob {
+    pub const fn new(start: u32, len: u32) -> Self {
+        Self {
+            start,
+            len,
+            done: 0,
+            crc: CRC16_INIT,
+        }
+    }
+
+    /// Final CRC once the job is done
+    pub fn crc(&self) -> Option<u16> {
+        (self.done == self.len).then_some(self.crc)
+    }
+}
+
+impl Job for FlashCrcJob {
+    type Context<'a> = Flash;
+
+    fn name(&self) -> &'static str {
+        "flash crc"
+    }
+
+    fn step(&mut self, flash: &mut Flash) -> Step {
+        if self.done == self.len {
+            return Step::Done;
+        }
+        let mut chunk = [0u8; CRC_CHUNK];
+        let count = (self.len - self.done).min(CRC_CHUNK as u32) as usize;
+        if flash.read(self.start + self.done, &mut chunk[..count]).is_err() {
+            return Step::Failed(self.start + self.done);
+        }
+        self.crc = chunk[..count].iter().fold(self.crc, |crc, &b| crc16_update(crc, b));
+        self.done += count as u32;
+        if self.done == self.len {
+            Step::Done
+        } else {
+            Step::Pending
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        Progress {
+            done: self.done,
+            total: self.len,
+        }
+    }
+}
+
+/// Typical W25Q128 chip erase time, for progress only
+const CHIP_ERASE_TYPICAL_MS: u32 = 40_000;
+/// Datasheet maximum, the job fails after it
+const CHIP_ERASE_MAX_MS: u32 = 200_000;
+
+/// Erase the whole flash without blocking for the erase time
+pub struct ChipEraseJob {
+    started_ms: Option<u32>,
+    elapsed_ms: u32,
+}
+
+impl ChipEraseJob {
+    pub const fn new() -> Self {
+        Self {
+            started_ms: None,
+            elapsed_ms: 0,
+        }
+    }
+}
+
+impl Default for ChipEraseJob {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Job for ChipEraseJob {
+    type Context<'a> = Flash;
+
+    fn name(&self) -> &'static str {
+        "chip erase"
+    }
+
+    fn step(&mut self, flash: &mut Flash) -> Step {
+        let now = systime::millis();
+        let started = match self.started_ms {
+            Some(started) => started,
+            None => {
+                if let Err(error) = flash.start_erase_chip() {
+                    return Step::Failed(flash_detail(error));
+                }
+                self.started_ms = Some(now);
+                return Step::Pending;
+            }
+        };
+        self.elapsed_ms = now.wrapping_sub(started);
+        match flash.is_busy() {
+            Ok(false) => Step::Done,
+            Ok(true) if self.elapsed_ms > CHIP_ERASE_MAX_MS => Step::Failed(flash_detail(FlashError::TimeoutError)),
+            Ok(true) => Step::Pending,
+            Err(error) => Step::Failed(flash_detail(error)),
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        // Erase time varies, stay below 100% until the flash says done
+        Progress {
+            done: self.elapsed_ms.min(CHIP_ERASE_TYPICAL_MS - 1),
+            total: CHIP_ERASE_TYPICAL_MS,
+        }
+    }
+}
+
+/// A flash job started from the console
+pub enum FlashJob {
+    Crc(FlashCrcJob),
+    Erase(ChipEraseJob),
+}
+
+impl FlashJob {
+    /// Print the outcome once the job is done
+    pub fn report(&self, console: &mut SerialConsole) {
+        match self {
+            FlashJob::Crc(job) => {
+                if let Some(crc) = job.crc() {
+                    console.write_str("crc16 0x");
+                    console.write_hex((crc >> 8) as u8);
+                    console.write_hex(crc as u8);
+                    console.write_str(", ");
+                    console.write_decimal(job.len);
+                    console.write_pgm_line(pgm_str!(" bytes"));
+                }
+            }
+            FlashJob::Erase(_) => console.write_pgm_line(pgm_str!("flash erased")),
+        }
+    }
+}
+
+impl Job for FlashJob {
+    type Context<'a> = Flash;
+
+    fn name(&self) -> &'static str {
+        match self {
+            FlashJob::Crc(job) => job.name(),
+            FlashJob::Erase(job) => job.name(),
+        }
+    }
+
+    fn step(&mut self, flash: &mut Flash) -> Step {
+        match self {
+            FlashJob::Crc(job) => job.step(flash),
+            FlashJob::Erase(job) => job.step(flash),
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        match self {
+            FlashJob::Crc(job) => job.progress(),
+            FlashJob::Erase(job) => job.progress(),
+        }
+    }
+}
+
+/// Parse a console line and return the flash job it asks for. Lines for
+/// other commands give `None`, as do bad arguments after printing why.
+pub fn process_line(line: &str, console: &mut SerialConsole) -> Option<FlashJob> {
+    let mut args = line.split_whitespace();
+    if args.next() != Some("flash") {
+        return None;
+    }
+
+    match (args.next(), args.next(), args.next()) {
+        (Some("crc"), Some(sector), len) => {
+            let sector = sector.parse::<u32>().ok().filter(|&s| s < FLASH_SECTORS);
+            let len = match len {
+                None => Some(SECTOR_SIZE as u32),
+                Some(len) => len.parse::<u32>().ok(),
+            };
+            match (sector, len) {
+                (Some(sector), Some(len)) if len <= (FLASH_SECTORS - sector) * SECTOR_SIZE as u32 => {
+                    Some(FlashJob::Crc(FlashCrcJob::new(sector * SECTOR_SIZE as u32, len)))
+                }
+                _ => {
+                    console.write_pgm_line(pgm_str!("outside flash"));
+                    None
+                }
+            }
+        }
+        // Spelled out, this takes the error log and every setting with it
+        (Some("erase"), Some("yes"), None) => Some(FlashJob::Erase(ChipEraseJob::new())),
+        _ => {
+            console.write_pgm_line(pgm_str!("usage: flash crc <sector> [len]|erase yes"));
+            None
+        }
+    }
+}
+
+fn flash_detail(error: FlashError) -> u32 {
+    match error {
+        FlashError::WriteError => 1,
+        FlashError::ReadError => 2,
+        FlashError::EraseError => 3,
+        FlashError::TimeoutError => 4,
+        FlashError::WrongId => 5,
+    }
+}
@@ -0,0 +1,239 @@
+//! Time budgets for the main superloop
+//!
+//! Each pass through the loop is a frame. The work done in it is
+//! registered up front with a budget and a priority, and each run is timed
+//! on `systime::micros`. A frame that takes longer than the frame budget
+//! counts as an overrun (the TimingError statistic); from then on `Low`
+//! work (console output, LED animation) is skipped until a frame fits the
+//! budget again, so the essential work and the watchdog feed keep their
+//! rate. `Low` work is also skipped for the rest of a frame that is
+//! already over budget when it comes up.
+//!
+//! `report` also publishes the slot statistics to the host as the
+//! `TaskStats` record, one instance per slot id.
+//!
+//! ```ignore
+//! frame.begin();
+//! frame.run(app_id, || app.update(...)).ok();
+//! frame.run(leds_id, || leds.tick(now)).ok();
+//! frame.end();
+//! watchdog.feed();
+//! ```
+#![no_std]
+
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
+
+use crate::diagnostics::{Diagnostics, ErrorCode};
+use crate::hal::systime;
+use crate::protocol::records::{self, RecordType, SUBSYSTEM_TASKS};
+
+const MAX_SLOTS: usize = 8;
+
+const RECORD_SLOT: u8 = 0x01;
+
+// Runs, skipped, overruns and max_us of each slot as of the last `report`
+static PUBLISHED: Mutex<RefCell<[Option<[u32; 4]>; MAX_SLOTS]>> = Mutex::new(RefCell::new([None; MAX_SLOTS]));
+
+/// Diagnostics subcode of frame overruns
+pub const FRAME_SUBCODE: u16 = 0x00FF;
+
+#[derive(Debug)]
+pub enum FrameError {
+    TooManySlots,
+    InvalidSlot,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FramePriority {
+    /// Runs every frame
+    High,
+    /// Skipped while frames overrun
+    Low,
+}
+
+/// Timing statistics of one registered update function
+#[derive(Clone, Copy)]
+pub struct SlotStats {
+    pub name: &'static str,
+    pub budget_us: u32,
+    pub priority: FramePriority,
+    pub runs: u32,
+    pub skipped: u32,
+    /// Runs that took longer than `budget_us`
+    pub overruns: u32,
+    pub max_us: u32,
+}
+
+/// Timing statistics of the whole loop
+#[derive(Clone, Copy, Default)]
+pub struct FrameStats {
+    pub frames: u32,
+    /// Frames over the frame budget, the TimingError statistic
+    pub timing_errors: u32,
+    /// Frames that skipped `Low` work
+    pub shed_frames: u32,
+    pub max_frame_us: u32,
+}
+
+pub struct FrameScheduler {
+    slots: [Option<SlotStats>; MAX_SLOTS],
+    budget_us: u32,
+    stats: FrameStats,
+    frame_start_us: u32,
+    shedding: bool,
+    shed_this_frame: bool,
+    // Timing errors already passed on to diagnostics
+    reported: u32,
+}
+
+impl FrameScheduler {
+    pub const fn new(budget_us: u32) -> Self {
+        Self {
+            slots: [None; MAX_SLOTS],
+            budget_us,
+            stats: FrameStats {
+                frames: 0,
+                timing_errors: 0,
+                shed_frames: 0,
+                max_frame_us: 0,
+            },
+            frame_start_us: 0,
+            shedding: false,
+            shed_this_frame: false,
+            reported: 0,
+        }
+    }
+
+    /// Register an update function, returns its slot id
+    pub fn register(&mut self, name: &'static str, budget_us: u32, priority: FramePriority) -> Result<usize, FrameError> {
+        records::register(SUBSYSTEM_TASKS, RECORD_SLOT, "tasks", RecordType::TaskStats, MAX_SLOTS as u8, read_slot).ok();
+        for (id, slot) in self.slots.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some(SlotStats {
+                    name,
+                    budget_us,
+                    priority,
+                    runs: 0,
+                    skipped: 0,
+                    overruns: 0,
+                    max_us: 0,
+                });
+                return Ok(id);
+            }
+        }
+        Err(FrameError::TooManySlots)
+    }
+
+    /// Start a frame, call at the top of the loop
+    pub fn begin(&mut self) {
+        self.frame_start_us = systime::micros();
+        self.shed_this_frame = false;
+    }
+
+    /// Run `update` as slot `id` unless the slot is `Low` priority and the
+    /// loop is shedding. Returns whether it ran.
+    pub fn run<F: FnOnce()>(&mut self, id: usize, update: F) -> Result<bool, FrameError> {
+        let over_budget = self.elapsed_us() > self.budget_us;
+        let shedding = self.shedding;
+        let slot = self.slots.get_mut(id).and_then(|s| s.as_mut()).ok_or(FrameError::InvalidSlot)?;
+
+        if slot.priority == FramePriority::Low && (shedding || over_budget) {
+            slot.skipped = slot.skipped.wrapping_add(1);
+            self.shed_this_frame = true;
+            return Ok(false);
+        }
+
+        let start = systime::micros();
+        update();
+        let took = systime::micros().wrapping_sub(start);
+
+        slot.runs = slot.runs.wrapping_add(1);
+        slot.max_us = slot.max_us.max(took);
+        if took > slot.budget_us {
+            slot.overruns = slot.overruns.wrapping_add(1);
+        }
+        Ok(true)
+    }
+
+    /// End the frame, returns whether it overran. Shedding starts with an
+    /// overrun and stops after the first frame back within budget.
+    pub fn end(&mut self) -> bool {
+        let took = self.elapsed_us();
+        let overran = took > self.budget_us;
+
+        self.stats.frames = self.stats.frames.wrapping_add(1);
+        self.stats.max_frame_us = self.stats.max_frame_us.max(took);
+        if overran {
+            self.stats.timing_errors = self.stats.timing_errors.wrapping_add(1);
+        }
+        if self.shed_this_frame {
+            self.stats.shed_frames = self.stats.shed_frames.wrapping_add(1);
+        }
+        self.shedding = overran;
+        overran
+    }
+
+    /// Whether `Low` work is currently skipped
+    pub fn is_shedding(&self) -> bool {
+        self.shedding
+    }
+
+    /// Report overruns since the last call as one `TimingError`, the data
+    /// carries how many frames overran, and publish the slot statistics
+    pub fn report(&mut self, diagnostics: &mut Diagnostics) {
+        let new = self.stats.timing_errors.wrapping_sub(self.reported);
+        if new > 0 {
+            diagnostics.report_error(ErrorCode::TimingError, FRAME_SUBCODE, new);
+            self.reported = self.stats.timing_errors;
+        }
+
+        avr_device::interrupt::free(|cs| {
+            let mut published = PUBLISHED.borrow(cs).borrow_mut();
+            for (entry, slot) in published.iter_mut().zip(self.slots.iter()) {
+                *entry = slot.map(|s| [s.runs, s.skipped, s.overruns, s.max_us]);
+            }
+        });
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    pub fn slot_stats(&self, id: usize) -> Option<SlotStats> {
+        self.slots.get(id).copied().flatten()
+    }
+
+    /// Clear the statistics but keep the registrations
+    pub fn reset_stats(&mut self) {
+        for slot in self.slots.iter_mut().flatten() {
+            slot.runs = 0;
+            slot.skipped = 0;
+            slot.overruns = 0;
+            slot.max_us = 0;
+        }
+        self.stats = FrameStats::default();
+        self.reported = 0;
+    }
+
+    fn elapsed_us(&self) -> u32 {
+        systime::micros().wrapping_sub(self.frame_start_us)
+    }
+}
+
+fn read_slot(index: u8, out: &mut [u8]) -> Option<usize> {
+    let stats = avr_device::interrupt::free(|cs| PUBLISHED.borrow(cs).borrow()[index as usize])?;
+    if out.len() < 16 {
+        return None;
+    }
+    for (chunk, value) in out.chunks_exact_mut(4).zip(stats) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    Some(16)
+}
+
+impl Default for FrameScheduler {
+    fn default() -> Self {
+        Self::new(crate::config::FRAME_BUDGET_US)
+    }
+}
@@ -0,0 +1,41 @@
+//! Checksums shared by the protocol and diagnostics
+#![no_std]
+
+/// Start value of CRC-16/CCITT-FALSE
+pub const CRC16_INIT: u16 = 0xFFFF;
+
+/// Feed one byte into a CRC-16/CCITT-FALSE (poly 0x1021), bitwise to keep
+/// the 512 byte table out of flash
+pub fn crc16_update(mut crc: u16, byte: u8) -> u16 {
+    crc ^= (byte as u16) << 8;
+    for _ in 0..8 {
+        crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+    }
+    crc
+}
+
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(CRC16_INIT, |crc, &byte| crc16_update(crc, byte))
+}
+
+/// Start value of CRC-32 (the zlib/Ethernet CRC), see `crc32_finish`
+pub const CRC32_INIT: u32 = 0xFFFF_FFFF;
+
+/// Feed one byte into a CRC-32 (reflected poly 0xEDB88320), bitwise like
+/// `crc16_update`
+pub fn crc32_update(mut crc: u32, byte: u8) -> u32 {
+    crc ^= byte as u32;
+    for _ in 0..8 {
+        crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+    }
+    crc
+}
+
+/// Final XOR, after the last `crc32_update`
+pub fn crc32_finish(crc: u32) -> u32 {
+    !crc
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_finish(data.iter().fold(CRC32_INIT, |crc, &byte| crc32_update(crc, byte)))
+}
@@ -0,0 +1,317 @@
+//! DMX512 receiver and transmitter on USART1
+//!
+//! DMX runs at 250 kbaud, 8 data bits, 2 stop bits. A packet starts with a
+//! break (line low for >= 88us), a mark-after-break, a start code (0x00 for
+//! dimmer data) and up to 512 slots.
+//!
+//! Receiving needs the `dmx` feature, which installs the USART1 RX interrupt
+//! handler: at 44us per slot polling cannot keep up. A break is seen by the
+//! USART as a 0x00 byte with the framing error flag set. Transmitting is
+//! polled, since DMX allows arbitrary mark time between slots; the break and
+//! mark-after-break are timed with Timer2.
+#![no_std]
+
+use avr_device::atmega128::{PORTD, TC2, USART1};
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
+
+use crate::config::CPU_FREQ_HZ;
+use crate::hal::claims::{self, Resource};
+use crate::hal::clock;
+use crate::hal::uart::UartRegisterBlock;
+
+pub const DMX_BAUD: u32 = 250_000;
+pub const DMX_SLOTS: usize = 512;
+/// Largest number of consecutive channels watched for changes
+pub const MAX_FOOTPRINT: usize = 32;
+const START_CODE_DIMMER: u8 = 0x00;
+
+// USART1 registers
+const RXC: u8 = 1 << 7;
+const UDRE: u8 = 1 << 5;
+const FE: u8 = 1 << 4;
+const UCSRC_8N2: u8 = 0x0E; // USBS | UCSZ1 | UCSZ0
+const TXD1_PIN: u8 = 3; // PD3
+
+// Timer2 at clk/8 counts in 0.5us steps @ 16MHz
+const TCCR2_NORMAL_DIV8: u8 = 0x02;
+const OCF2: u8 = 1 << 7;
+const BREAK_US: u32 = 100;
+const MAB_US: u32 = 12;
+
+#[derive(Debug)]
+pub enum DmxError {
+    InvalidAddress,
+    Busy,
+}
+
+/// Called from `DmxReceiver::poll` for each watched channel whose value
+/// changed (channel numbers are 1-based, as on a lighting desk)
+pub type DmxHandler = fn(channel: u16, value: u8);
+
+struct RxState {
+    slots: [u8; DMX_SLOTS],
+    index: usize,
+    receiving: bool,
+    frames: u32,
+}
+
+static RX_STATE: Mutex<RefCell<RxState>> = Mutex::new(RefCell::new(RxState {
+    slots: [0; DMX_SLOTS],
+    index: 0,
+    receiving: false,
+    frames: 0,
+}));
+
+fn configure_usart(rx: bool, tx: bool) {
+    claims::claim(Resource::Usart1, "dmx").ok();
+    unsafe {
+        let p = <USART1 as UartRegisterBlock>::ptr();
+        (*p).ubrr.write(|w| w.bits((CPU_FREQ_HZ / (16 * DMX_BAUD) - 1) as u16));
+        (*p).ucsrc.write(|w| w.bits(UCSRC_8N2));
+        (*p).ucsr.modify(|_, w| {
+            let w = if rx { w.rxen().set_bit().rxcie().set_bit() } else { w };
+            if tx { w.txen().set_bit() } else { w }
+        });
+    }
+}
+
+fn us_to_timer2_counts(us: u32) -> u8 {
+    clock::us_to_counts(us, 8).min(u8::MAX as u32) as u8
+}
+
+/// DMX512 receiver listening to a block of channels
+pub struct DmxReceiver {
+    start_address: u16,
+    footprint: u16,
+    shadow: [u8; MAX_FOOTPRINT],
+    handler: Option<DmxHandler>,
+}
+
+impl DmxReceiver {
+    /// `start_address` is 1-based (1..=512), `footprint` the number of
+    /// consecutive channels this device responds to
+    pub fn new(start_address: u16, footprint: u16) -> Result<Self, DmxError> {
+        let mut rx = Self {
+            start_address: 1,
+            footprint: 0,
+            shadow: [0; MAX_FOOTPRINT],
+            handler: None,
+        };
+        rx.set_start_address(start_address, footprint)?;
+        configure_usart(true, false);
+        Ok(rx)
+    }
+
+    pub fn set_start_address(&mut self, start_address: u16, footprint: u16) -> Result<(), DmxError> {
+        if start_address == 0
+            || footprint as usize > MAX_FOOTPRINT
+            || (start_address + footprint - 1) as usize > DMX_SLOTS
+        {
+            return Err(DmxError::InvalidAddress);
+        }
+        self.start_address = start_address;
+        self.footprint = footprint;
+        self.shadow = [0; MAX_FOOTPRINT];
+        Ok(())
+    }
+
+    pub fn set_handler(&mut self, handler: DmxHandler) {
+        self.handler = Some(handler);
+    }
+
+    /// Current value of a 1-based DMX channel
+    pub fn slot(&self, channel: u16) -> u8 {
+        if channel == 0 || channel as usize > DMX_SLOTS {
+            return 0;
+        }
+        avr_device::interrupt::free(|cs| RX_STATE.borrow(cs).borrow().slots[channel as usize - 1])
+    }
+
+    /// Number of complete packets received
+    pub fn frames_received(&self) -> u32 {
+        avr_device::interrupt::free(|cs| RX_STATE.borrow(cs).borrow().frames)
+    }
+
+    /// Compare the watched channels against the last seen values and invoke
+    /// the change callback. Call from the main loop.
+    pub fn poll(&mut self) {
+        let mut current = [0u8; MAX_FOOTPRINT];
+        let first = self.start_address as usize - 1;
+        let count = self.footprint as usize;
+
+        avr_device::interrupt::free(|cs| {
+            let state = RX_STATE.borrow(cs).borrow();
+            current[..count].copy_from_slice(&state.slots[first..first + count]);
+        });
+
+        for i in 0..count {
+            if current[i] != self.shadow[i] {
+                self.shadow[i] = current[i];
+                if let Some(handler) = self.handler {
+                    handler(self.start_address + i as u16, current[i]);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TxState {
+    Idle,
+    Break,
+    MarkAfterBreak,
+    Sending(usize),
+}
+
+/// DMX512 transmitter (controller mode)
+pub struct DmxTransmitter {
+    slots: [u8; DMX_SLOTS],
+    slot_count: usize,
+    state: TxState,
+}
+
+impl DmxTransmitter {
+    pub fn new(slot_count: usize) -> Self {
+        // Break and mark-after-break timing
+        claims::claim(Resource::Timer2, "dmx").ok();
+        configure_usart(false, true);
+        Self {
+            slots: [0; DMX_SLOTS],
+            slot_count: slot_count.clamp(1, DMX_SLOTS),
+            state: TxState::Idle,
+        }
+    }
+
+    /// Set a 1-based channel value for the next packet
+    pub fn set_channel(&mut self, channel: u16, value: u8) -> Result<(), DmxError> {
+        if channel == 0 || channel as usize > self.slot_count {
+            return Err(DmxError::InvalidAddress);
+        }
+        self.slots[channel as usize - 1] = value;
+        Ok(())
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.state == TxState::Idle
+    }
+
+    /// Begin a new packet with a break
+    pub fn start_packet(&mut self) -> Result<(), DmxError> {
+        if self.state != TxState::Idle {
+            return Err(DmxError::Busy);
+        }
+
+        unsafe {
+            // Hand TXD1 back to the port and drive it low
+            let p = <USART1 as UartRegisterBlock>::ptr();
+            (*p).ucsr.modify(|_, w| w.txen().clear_bit());
+            let port = &*PORTD::ptr();
+            port.ddrd.modify(|r, w| w.bits(r.bits() | (1 << TXD1_PIN)));
+            port.portd.modify(|r, w| w.bits(r.bits() & !(1 << TXD1_PIN)));
+        }
+
+        start_timer2(BREAK_US);
+        self.state = TxState::Break;
+        Ok(())
+    }
+
+    /// Advance the transmit state machine. Call as often as possible from
+    /// the main loop while a packet is in flight.
+    pub fn poll(&mut self) {
+        match self.state {
+            TxState::Idle => {}
+            TxState::Break => {
+                if timer2_expired() {
+                    unsafe {
+                        (*PORTD::ptr()).portd.modify(|r, w| w.bits(r.bits() | (1 << TXD1_PIN)));
+                    }
+                    start_timer2(MAB_US);
+                    self.state = TxState::MarkAfterBreak;
+                }
+            }
+            TxState::MarkAfterBreak => {
+                if timer2_expired() {
+                    stop_timer2();
+                    unsafe {
+                        let p = <USART1 as UartRegisterBlock>::ptr();
+                        (*p).ucsr.modify(|_, w| w.txen().set_bit());
+                        (*p).udr.write(|w| w.bits(START_CODE_DIMMER));
+                    }
+                    self.state = TxState::Sending(0);
+                }
+            }
+            TxState::Sending(index) => unsafe {
+                let p = <USART1 as UartRegisterBlock>::ptr();
+                if (*p).ucsra.read().bits() & UDRE != 0 {
+                    (*p).udr.write(|w| w.bits(self.slots[index]));
+                    self.state = if index + 1 < self.slot_count {
+                        TxState::Sending(index + 1)
+                    } else {
+                        TxState::Idle
+                    };
+                }
+            },
+        }
+    }
+}
+
+fn start_timer2(us: u32) {
+    unsafe {
+        let p = TC2::ptr();
+        (*p).tccr2.write(|w| w.bits(0));
+        (*p).tcnt2.write(|w| w.bits(0));
+        (*p).ocr2.write(|w| w.bits(us_to_timer2_counts(us)));
+        // Clear a stale compare flag by writing one
+        (*p).tifr.write(|w| w.bits(OCF2));
+        (*p).tccr2.write(|w| w.bits(TCCR2_NORMAL_DIV8));
+    }
+}
+
+fn stop_timer2() {
+    unsafe {
+        (*TC2::ptr()).tccr2.write(|w| w.bits(0));
+    }
+}
+
+fn timer2_expired() -> bool {
+    unsafe { (*TC2::ptr()).tifr.read().bits() & OCF2 != 0 }
+}
+
+#[cfg(feature = "dmx")]
+#[avr_device::interrupt(atmega128)]
+fn USART1_RX() {
+    let (status, byte) = unsafe {
+        let p = <USART1 as UartRegisterBlock>::ptr();
+        let status = (*p).ucsra.read().bits();
+        (status, (*p).udr.read().bits())
+    };
+
+    if status & RXC == 0 {
+        return;
+    }
+
+    avr_device::interrupt::free(|cs| {
+        let mut state = RX_STATE.borrow(cs).borrow_mut();
+
+        if status & FE != 0 {
+            // Break: previous packet (if any) is complete
+            if state.receiving && state.index > 0 {
+                state.frames = state.frames.wrapping_add(1);
+            }
+            state.receiving = false;
+            state.index = usize::MAX;
+            return;
+        }
+
+        if state.index == usize::MAX {
+            // First byte after the break is the start code
+            state.receiving = byte == START_CODE_DIMMER;
+            state.index = 0;
+        } else if state.receiving && state.index < DMX_SLOTS {
+            let index = state.index;
+            state.slots[index] = byte;
+            state.index += 1;
+        }
+    });
+}
@@ -0,0 +1,398 @@
+//! Hardened packet reception
+//!
+//! The plain `Protocol::process` cuts frames at every 0x0A, so a payload or
+//! checksum byte of 0x0A splits a packet and line noise goes to the packet
+//! handler as is. In hardened mode frames are delimited by their length
+//! field instead, and the length is checked against `MAX_PAYLOAD` before a
+//! single payload byte is stored. Rejected frames are counted by kind and
+//! answered with a rejection record, at most `ERROR_RESPONSES_PER_SECOND`
+//! of them so a babbling peer can't keep the link busy with error replies.
+//!
+//! `Command::Hardening` reads the counters and runs a loopback fuzz test,
+//! which pushes pseudo-random bytes through the same framer and validator
+//! without touching the serial port or the packet handler:
+//!
+//! | op   | request                         | reply                    |
+//! |------|---------------------------------|--------------------------|
+//! | 0x01 | -                               | `MalformedStats` (28 B)  |
+//! | 0x02 | -                               | -, counters cleared      |
+//! | 0x03 | enabled u8                      | -                        |
+//! | 0x04 | seed u32 LE, iterations u16 LE  | `FuzzReport` (24 B)      |
+//!
+//! A fuzz request runs at most `FUZZ_MAX_ITERATIONS` sequences, in a few
+//! milliseconds, since the packet handler runs inside the main loop; ask
+//! again with another seed for a longer run. The report's byte count shows
+//! how much was fed.
+//!
+//! Rejections go out unsolicited as `[0x80, Malformed]`.
+#![no_std]
+
+use super::packet::{self, Malformed, FOOTER_SIZE, HEADER_SIZE, MAX_PAYLOAD};
+use super::{Command, Protocol, ProtocolError, Result};
+use crate::hal::uart::SerialPort;
+use crate::math::Xorshift32;
+use crate::os::SCHEDULER;
+
+/// Cap on rejection records, the rest are only counted
+pub const ERROR_RESPONSES_PER_SECOND: u8 = 4;
+
+const OP_GET_STATS: u8 = 0x01;
+const OP_RESET_STATS: u8 = 0x02;
+const OP_SET_MODE: u8 = 0x03;
+const OP_FUZZ: u8 = 0x04;
+
+const RECORD_REJECTED: u8 = 0x80;
+
+pub const STATS_SIZE: usize = 28;
+pub const FUZZ_REPORT_SIZE: usize = 24;
+
+// Longest well-formed frame the fuzzer builds, payloads up to 16 bytes
+const FUZZ_MAX_PAYLOAD: usize = 16;
+
+/// Fuzz sequences per request, at most 22 bytes each
+pub const FUZZ_MAX_ITERATIONS: u16 = 64;
+
+/// Rejected frames by kind
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MalformedStats {
+    pub accepted: u32,
+    pub bad_length: u32,
+    pub bad_checksum: u32,
+    pub oversized: u32,
+    pub unknown_command: u32,
+    pub bad_framing: u32,
+    /// Rejection records not sent because of the rate limit
+    pub responses_suppressed: u32,
+}
+
+impl MalformedStats {
+    pub const fn new() -> Self {
+        Self {
+            accepted: 0,
+            bad_length: 0,
+            bad_checksum: 0,
+            oversized: 0,
+            unknown_command: 0,
+            bad_framing: 0,
+            responses_suppressed: 0,
+        }
+    }
+
+    pub fn record(&mut self, kind: Malformed) {
+        let counter = match kind {
+            Malformed::BadLength => &mut self.bad_length,
+            Malformed::BadChecksum => &mut self.bad_checksum,
+            Malformed::Oversized => &mut self.oversized,
+            Malformed::UnknownCommand => &mut self.unknown_command,
+            Malformed::BadFraming => &mut self.bad_framing,
+        };
+        *counter = counter.wrapping_add(1);
+    }
+
+    pub fn rejected(&self) -> u32 {
+        self.bad_length
+            .wrapping_add(self.bad_checksum)
+            .wrapping_add(self.oversized)
+            .wrapping_add(self.unknown_command)
+            .wrapping_add(self.bad_framing)
+    }
+
+    /// All counters as u32 LE, in field order
+    pub fn encode(&self, out: &mut [u8; STATS_SIZE]) {
+        let fields = [
+            self.accepted,
+            self.bad_length,
+            self.bad_checksum,
+            self.oversized,
+            self.unknown_command,
+            self.bad_framing,
+            self.responses_suppressed,
+        ];
+        for (chunk, value) in out.chunks_exact_mut(4).zip(fields.iter()) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+/// Outcome of a fuzz run
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FuzzReport {
+    pub bytes: u32,
+    pub stats: MalformedStats,
+}
+
+impl FuzzReport {
+    /// `[bytes u32, accepted, bad length, bad checksum, oversized,
+    /// unknown command, bad framing]`, all u32 LE
+    pub fn encode(&self, out: &mut [u8; FUZZ_REPORT_SIZE]) {
+        let mut stats = [0u8; STATS_SIZE];
+        self.stats.encode(&mut stats);
+        out[..4].copy_from_slice(&self.bytes.to_le_bytes());
+        // Nothing is sent during a fuzz run, so no suppressed count
+        out[4..].copy_from_slice(&stats[..FUZZ_REPORT_SIZE - 4]);
+    }
+}
+
+/// Result of feeding one byte to a `Framer`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Step {
+    Pending,
+    /// A frame of this many bytes is complete at the start of the buffer
+    Frame(usize),
+    Rejected(Malformed),
+}
+
+/// Length-delimited frame assembly
+pub struct Framer {
+    index: usize,
+}
+
+impl Framer {
+    pub const fn new() -> Self {
+        Self { index: 0 }
+    }
+
+    pub fn reset(&mut self) {
+        self.index = 0;
+    }
+
+    /// Store `byte` in `buffer` and report whether a frame is complete.
+    /// Bytes before a start sequence are skipped without counting them.
+    pub fn push(&mut self, buffer: &mut [u8], byte: u8) -> Step {
+        match self.index {
+            0 if byte != 0x55 => return Step::Pending,
+            1 if byte != 0xAA => {
+                // A repeated 0x55 may still start the real frame
+                self.index = if byte == 0x55 { 1 } else { 0 };
+                return Step::Rejected(Malformed::BadFraming);
+            }
+            3 if byte as usize > MAX_PAYLOAD => {
+                self.index = 0;
+                return Step::Rejected(Malformed::Oversized);
+            }
+            _ => {}
+        }
+
+        match buffer.get_mut(self.index) {
+            Some(slot) => *slot = byte,
+            None => {
+                // Buffer smaller than a full frame, can't happen with the
+                // protocol's own
+                self.index = 0;
+                return Step::Rejected(Malformed::BadLength);
+            }
+        }
+        self.index += 1;
+
+        if self.index > HEADER_SIZE && self.index == HEADER_SIZE + buffer[3] as usize + FOOTER_SIZE {
+            let len = self.index;
+            self.index = 0;
+            return Step::Frame(len);
+        }
+        Step::Pending
+    }
+}
+
+impl Default for Framer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Allows `ERROR_RESPONSES_PER_SECOND` events per one second window
+pub struct ErrorLimiter {
+    window_start_ms: u32,
+    sent: u8,
+}
+
+impl ErrorLimiter {
+    pub const fn new() -> Self {
+        Self {
+            window_start_ms: 0,
+            sent: 0,
+        }
+    }
+
+    pub fn allow(&mut self, now_ms: u32) -> bool {
+        if now_ms.wrapping_sub(self.window_start_ms) >= 1000 {
+            self.window_start_ms = now_ms;
+            self.sent = 0;
+        }
+        if self.sent >= ERROR_RESPONSES_PER_SECOND {
+            return false;
+        }
+        self.sent += 1;
+        true
+    }
+}
+
+impl Default for ErrorLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: SerialPort> Protocol<S> {
+    /// Switch between hardened and plain reception. Any partial frame is
+    /// dropped.
+    pub fn set_hardened(&mut self, hardened: bool) {
+        self.hardened = hardened;
+        self.framer.reset();
+        self.rx_index = 0;
+    }
+
+    pub fn is_hardened(&self) -> bool {
+        self.hardened
+    }
+
+    pub fn malformed_stats(&self) -> MalformedStats {
+        self.malformed
+    }
+
+    pub fn reset_malformed_stats(&mut self) {
+        self.malformed = MalformedStats::new();
+    }
+
+    pub(super) fn process_hardened(&mut self) -> Result<()> {
+        while let Some(byte) = self.uart.read_byte() {
+            match self.framer.push(&mut self.rx_buffer, byte) {
+                Step::Pending => {}
+                Step::Frame(len) => match packet::validate(&self.rx_buffer[..len]) {
+                    Ok(_) => {
+                        self.malformed.accepted = self.malformed.accepted.wrapping_add(1);
+                        if let Some(handler) = self.packet_handler {
+                            handler(&self.rx_buffer[..len])?;
+                        }
+                    }
+                    Err(kind) => self.reject(kind)?,
+                },
+                Step::Rejected(kind) => self.reject(kind)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn reject(&mut self, kind: Malformed) -> Result<()> {
+        self.malformed.record(kind);
+        if self.error_limiter.allow(SCHEDULER.get_ticks()) {
+            self.send_packet(Command::Hardening, &[RECORD_REJECTED, kind as u8])
+        } else {
+            self.malformed.responses_suppressed = self.malformed.responses_suppressed.wrapping_add(1);
+            Ok(())
+        }
+    }
+
+    /// Feed `iterations` pseudo-random byte sequences, at most
+    /// `FUZZ_MAX_ITERATIONS`, through the framer and validator. Frames that
+    /// validate are only counted, never dispatched. A partial frame from
+    /// the port is dropped.
+    pub fn run_fuzz(&mut self, seed: u32, iterations: u16) -> FuzzReport {
+        let iterations = iterations.min(FUZZ_MAX_ITERATIONS);
+        let mut rng = Xorshift32::new(seed);
+        let mut report = FuzzReport::default();
+        self.framer.reset();
+
+        let mut frame = [0u8; HEADER_SIZE + FUZZ_MAX_PAYLOAD + FOOTER_SIZE];
+        for _ in 0..iterations {
+            let len = match rng.next_u32() % 4 {
+                // Plain noise
+                0 => {
+                    let len = 1 + (rng.next_u32() as usize % frame.len());
+                    for byte in frame[..len].iter_mut() {
+                        *byte = rng.next_u8();
+                    }
+                    len
+                }
+                // Valid start, anything after
+                1 => {
+                    frame[0] = 0x55;
+                    frame[1] = 0xAA;
+                    frame[2] = rng.next_u8();
+                    frame[3] = rng.next_u8();
+                    let len = HEADER_SIZE + (rng.next_u32() as usize % (frame.len() - HEADER_SIZE));
+                    for byte in frame[HEADER_SIZE..len].iter_mut() {
+                        *byte = rng.next_u8();
+                    }
+                    len
+                }
+                // Well-formed, possibly with one bit flipped
+                _ => {
+                    let payload = rng.next_u32() as usize % (FUZZ_MAX_PAYLOAD + 1);
+                    frame[0] = 0x55;
+                    frame[1] = 0xAA;
+                    frame[2] = rng.next_u8() & 0x1F;
+                    frame[3] = payload as u8;
+                    for byte in frame[HEADER_SIZE..HEADER_SIZE + payload].iter_mut() {
+                        *byte = rng.next_u8();
+                    }
+                    let sum = frame[..HEADER_SIZE + payload].iter().fold(0u8, |s, &b| s.wrapping_add(b));
+                    frame[HEADER_SIZE + payload] = !sum;
+                    frame[HEADER_SIZE + payload + 1] = 0x0A;
+                    let len = HEADER_SIZE + payload + FOOTER_SIZE;
+                    if rng.next_u32() & 1 != 0 {
+                        let bit = rng.next_u32() as usize % (len * 8);
+                        frame[bit / 8] ^= 1 << (bit % 8);
+                    }
+                    len
+                }
+            };
+
+            for &byte in frame[..len].iter() {
+                report.bytes = report.bytes.wrapping_add(1);
+                match self.framer.push(&mut self.rx_buffer, byte) {
+                    Step::Pending => {}
+                    Step::Frame(n) => match packet::validate(&self.rx_buffer[..n]) {
+                        Ok(_) => report.stats.accepted = report.stats.accepted.wrapping_add(1),
+                        Err(kind) => report.stats.record(kind),
+                    },
+                    Step::Rejected(kind) => report.stats.record(kind),
+                }
+            }
+        }
+
+        self.framer.reset();
+        report
+    }
+
+    /// Handle a `Command::Hardening` payload and write the reply into
+    /// `response`. Returns the number of response bytes written.
+    pub fn handle_hardening(&mut self, data: &[u8], response: &mut [u8]) -> Result<usize> {
+        let op = *data.first().ok_or(ProtocolError::InvalidPacket)?;
+
+        match op {
+            OP_GET_STATS => {
+                let out: &mut [u8; STATS_SIZE] = response
+                    .get_mut(..STATS_SIZE)
+                    .and_then(|r| r.try_into().ok())
+                    .ok_or(ProtocolError::BufferOverflow)?;
+                self.malformed.encode(out);
+                Ok(STATS_SIZE)
+            }
+            OP_RESET_STATS => {
+                self.reset_malformed_stats();
+                Ok(0)
+            }
+            OP_SET_MODE => {
+                if data.len() != 2 {
+                    return Err(ProtocolError::InvalidPacket);
+                }
+                self.set_hardened(data[1] != 0);
+                Ok(0)
+            }
+            OP_FUZZ => {
+                if data.len() != 7 {
+                    return Err(ProtocolError::InvalidPacket);
+                }
+                let out: &mut [u8; FUZZ_REPORT_SIZE] = response
+                    .get_mut(..FUZZ_REPORT_SIZE)
+                    .and_then(|r| r.try_into().ok())
+                    .ok_or(ProtocolError::BufferOverflow)?;
+                let seed = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+                let iterations = u16::from_le_bytes([data[5], data[6]]);
+                self.run_fuzz(seed, iterations).encode(out);
+                Ok(FUZZ_REPORT_SIZE)
+            }
+            _ => Err(ProtocolError::InvalidCommand),
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Host link: versioned command namespace for host-side tooling
+//!
+//! Everything a host tool needs to discover and drive the firmware lives
+//! under `Command::HostLink`, so the reference client in
+//! `scripts/host_link.py` keeps working as other commands come and go.
+//! Bump `HOST_LINK_VERSION` whenever a sub-command's payload changes.
+//!
+//! | op   | request             | reply                                        |
+//! |------|---------------------|----------------------------------------------|
+//! | 0x01 | -                   | version, fw major, minor, patch, max payload, board revision |
+//! | 0x02 | -                   | supported command ids                        |
+//! | 0x03 | -                   | capability bits (u16 LE)                     |
+//! | 0x04 | period_ms (u16 LE)  | -, period 0 stops the telemetry stream       |
+//! | 0x05 | -                   | -, then the firmware enters the bootloader   |
+#![no_std]
+
+use super::{Command, ProtocolError, Result};
+use crate::config::FIRMWARE_VERSION;
+use crate::hal::board_id;
+use crate::hal::mailbox::{self, BootMailbox, BootReason};
+
+pub const HOST_LINK_VERSION: u8 = 3;
+
+/// Largest payload the packet layer accepts
+pub const MAX_PAYLOAD: u8 = super::packet::MAX_PAYLOAD as u8;
+
+const OP_HELLO: u8 = 0x01;
+const OP_LIST_COMMANDS: u8 = 0x02;
+const OP_GET_CAPABILITIES: u8 = 0x03;
+const OP_TELEMETRY: u8 = 0x04;
+const OP_ENTER_BOOTLOADER: u8 = 0x05;
+
+// Capability bits reported by GetCapabilities, one per Cargo feature
+pub const CAP_ATMEGA128: u16 = 1 << 0;
+pub const CAP_DEBUG: u16 = 1 << 1;
+pub const CAP_RELEASE: u16 = 1 << 2;
+pub const CAP_DMX: u16 = 1 << 3;
+pub const CAP_NOISE_INJECTION: u16 = 1 << 4;
+pub const CAP_FAULT_INJECTION: u16 = 1 << 5;
+
+const SUPPORTED_COMMANDS: [Command; 21] = [
+    Command::Ping,
+    Command::GetStatus,
+    Command::SetConfig,
+    Command::GetData,
+    Command::Reset,
+    Command::UpdateFirmware,
+    Command::Debug,
+    Command::Cron,
+    Command::Watch,
+    Command::Vibration,
+    Command::HostLink,
+    Command::Channel,
+    Command::Safety,
+    Command::Heartbeat,
+    Command::RunSelfTest,
+    Command::SamplingPlan,
+    Command::Burst,
+    Command::PowerProfile,
+    Command::Hardening,
+    Command::Pipeline,
+    Command::Telemetry,
+];
+
+/// Feature flags this firmware was built with
+pub const fn capabilities() -> u16 {
+    let mut caps = 0;
+    if cfg!(feature = "atmega128") {
+        caps |= CAP_ATMEGA128;
+    }
+    if cfg!(feature = "debug") {
+        caps |= CAP_DEBUG;
+    }
+    if cfg!(feature = "release") {
+        caps |= CAP_RELEASE;
+    }
+    if cfg!(feature = "dmx") {
+        caps |= CAP_DMX;
+    }
+    if cfg!(feature = "noise-injection") {
+        caps |= CAP_NOISE_INJECTION;
+    }
+    if cfg!(feature = "fault-injection") {
+        caps |= CAP_FAULT_INJECTION;
+    }
+    caps
+}
+
+pub struct HostLink {
+    telemetry_period_ms: u16,
+    last_telemetry_ms: u32,
+    bootloader_requested: bool,
+}
+
+impl HostLink {
+    pub const fn new() -> Self {
+        Self {
+            telemetry_period_ms: 0,
+            last_telemetry_ms: 0,
+            bootloader_requested: false,
+        }
+    }
+
+    /// Handle a `Command::HostLink` payload and write the reply into
+    /// `response`. Returns the number of response bytes written.
+    pub fn handle_command(&mut self, data: &[u8], response: &mut [u8]) -> Result<usize> {
+        let op = *data.first().ok_or(ProtocolError::InvalidPacket)?;
+
+        match op {
+            OP_HELLO => {
+                if response.len() < 6 {
+                    return Err(ProtocolError::BufferOverflow);
+                }
+                response[0] = HOST_LINK_VERSION;
+                response[1..4].copy_from_slice(&FIRMWARE_VERSION);
+                response[4] = MAX_PAYLOAD;
+                response[5] = board_id::revision() as u8;
+                Ok(6)
+            }
+            OP_LIST_COMMANDS => {
+                if response.len() < SUPPORTED_COMMANDS.len() {
+                    return Err(ProtocolError::BufferOverflow);
+                }
+                for (out, &command) in response.iter_mut().zip(SUPPORTED_COMMANDS.iter()) {
+                    *out = command as u8;
+                }
+                Ok(SUPPORTED_COMMANDS.len())
+            }
+            OP_GET_CAPABILITIES => {
+                if response.len() < 2 {
+                    return Err(ProtocolError::BufferOverflow);
+                }
+                response[..2].copy_from_slice(&capabilities().to_le_bytes());
+                Ok(2)
+            }
+            OP_TELEMETRY => {
+                if data.len() != 3 {
+                    return Err(ProtocolError::InvalidPacket);
+                }
+                self.telemetry_period_ms = u16::from_le_bytes([data[1], data[2]]);
+                Ok(0)
+            }
+            OP_ENTER_BOOTLOADER => {
+                // Reply first, the caller switches over once it has been sent
+                self.bootloader_requested = true;
+                Ok(0)
+            }
+            _ => Err(ProtocolError::InvalidCommand),
+        }
+    }
+
+    /// True when a telemetry packet (`Command::Telemetry`) should go out
+    pub fn telemetry_due(&mut self, now_ms: u32) -> bool {
+        if self.telemetry_period_ms == 0 {
+            return false;
+        }
+        if now_ms.wrapping_sub(self.last_telemetry_ms) < self.telemetry_period_ms as u32 {
+            return false;
+        }
+        self.last_telemetry_ms = now_ms;
+        true
+    }
+
+    /// True once if the host asked to enter the bootloader. The request is
+    /// also left in the boot mailbox so the bootloader knows why it runs.
+    pub fn take_bootloader_request(&mut self) -> bool {
+        let requested = self.bootloader_requested;
+        self.bootloader_requested = false;
+        if requested {
+            mailbox::write(&BootMailbox::new(BootReason::UpdateRequest));
+        }
+        requested
+    }
+}
+
+impl Default for HostLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
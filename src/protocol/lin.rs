@@ -0,0 +1,328 @@
+//! LIN 2.x slave node on USART1
+//!
+//! Frame layout on the bus: `break | sync (0x55) | PID | data[1..8] | checksum`.
+//! The break is detected as a 0x00 byte received with the framing error flag
+//! set, the sync byte is used as a baud sanity check (this node runs at a
+//! fixed rate, no auto-baud), and the protected identifier selects an entry
+//! of the frame table which is either published (we answer) or subscribed
+//! (we receive the data the master or another slave sends).
+#![no_std]
+
+use avr_device::atmega128::USART1;
+
+use crate::config::CPU_FREQ_HZ;
+use crate::hal::uart::UartRegisterBlock;
+
+pub const LIN_BAUD: u32 = 19_200;
+const MAX_FRAMES: usize = 8;
+const MAX_DATA: usize = 8;
+const SYNC_BYTE: u8 = 0x55;
+
+// Diagnostic frames always use the classic checksum
+const MASTER_REQUEST_ID: u8 = 0x3C;
+const SLAVE_RESPONSE_ID: u8 = 0x3D;
+
+// UCSRnA flags
+const RXC: u8 = 1 << 7;
+const UDRE: u8 = 1 << 5;
+const FE: u8 = 1 << 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinError {
+    Framing,
+    Sync,
+    Parity,
+    Checksum,
+    TableFull,
+    InvalidFrame,
+}
+
+/// Which side provides the frame data
+#[derive(Clone, Copy, PartialEq)]
+pub enum LinDirection {
+    /// This node answers the header with data
+    Publish,
+    /// This node receives the data
+    Subscribe,
+}
+
+#[derive(Clone, Copy)]
+pub struct LinFrame {
+    pub id: u8,
+    pub direction: LinDirection,
+    pub length: u8,
+    pub data: [u8; MAX_DATA],
+    /// Set when a subscribed frame was received with a valid checksum
+    pub updated: bool,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct LinStats {
+    pub frames_ok: u32,
+    pub framing_errors: u16,
+    pub sync_errors: u16,
+    pub parity_errors: u16,
+    pub checksum_errors: u16,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    WaitBreak,
+    WaitSync,
+    WaitPid,
+    Receiving { slot: usize, index: u8 },
+    /// Our own response is looped back by the transceiver, discard it
+    Echo { remaining: u8 },
+}
+
+/// Callback for newly received subscribed frames
+pub type LinHandler = fn(id: u8, data: &[u8]);
+
+pub struct LinSlave {
+    frames: [Option<LinFrame>; MAX_FRAMES],
+    state: State,
+    pid: u8,
+    rx_data: [u8; MAX_DATA],
+    stats: LinStats,
+    handler: Option<LinHandler>,
+}
+
+impl LinSlave {
+    /// Configure USART1 for LIN (19200 8N1, polled) and create the slave
+    pub fn new() -> Self {
+        unsafe {
+            let p = <USART1 as UartRegisterBlock>::ptr();
+            let ubrr = (CPU_FREQ_HZ / (16 * LIN_BAUD) - 1) as u16;
+            (*p).ubrr.write(|w| w.bits(ubrr));
+            (*p).ucsr.modify(|_, w| w.rxen().set_bit().txen().set_bit());
+        }
+
+        Self {
+            frames: [None; MAX_FRAMES],
+            state: State::WaitBreak,
+            pid: 0,
+            rx_data: [0; MAX_DATA],
+            stats: LinStats::default(),
+            handler: None,
+        }
+    }
+
+    pub fn set_handler(&mut self, handler: LinHandler) {
+        self.handler = Some(handler);
+    }
+
+    /// Add a frame to the publish/subscribe table
+    pub fn add_frame(&mut self, id: u8, direction: LinDirection, length: u8) -> Result<(), LinError> {
+        if id > 0x3F || length == 0 || length as usize > MAX_DATA {
+            return Err(LinError::InvalidFrame);
+        }
+
+        for slot in self.frames.iter_mut() {
+            if slot.is_none() {
+                *slot = Some(LinFrame {
+                    id,
+                    direction,
+                    length,
+                    data: [0; MAX_DATA],
+                    updated: false,
+                });
+                return Ok(());
+            }
+        }
+        Err(LinError::TableFull)
+    }
+
+    /// Update the data published for frame `id`
+    pub fn set_data(&mut self, id: u8, data: &[u8]) -> Result<(), LinError> {
+        let frame = self.find_frame_mut(id).ok_or(LinError::InvalidFrame)?;
+        let len = data.len().min(frame.length as usize);
+        frame.data[..len].copy_from_slice(&data[..len]);
+        Ok(())
+    }
+
+    /// Fetch the last data received for a subscribed frame, clearing its
+    /// updated flag
+    pub fn take_data(&mut self, id: u8) -> Option<[u8; MAX_DATA]> {
+        let frame = self.find_frame_mut(id)?;
+        if frame.updated {
+            frame.updated = false;
+            Some(frame.data)
+        } else {
+            None
+        }
+    }
+
+    pub fn stats(&self) -> LinStats {
+        self.stats
+    }
+
+    /// Poll USART1 and run the frame state machine for any received byte
+    pub fn poll(&mut self) {
+        let (status, byte) = unsafe {
+            let p = <USART1 as UartRegisterBlock>::ptr();
+            let status = (*p).ucsra.read().bits();
+            if status & RXC == 0 {
+                return;
+            }
+            // Status must be read before UDR, reading UDR clears FE
+            (status, (*p).udr.read().bits())
+        };
+
+        if let Err(err) = self.process_byte(byte, status & FE != 0) {
+            match err {
+                LinError::Framing => self.stats.framing_errors += 1,
+                LinError::Sync => self.stats.sync_errors += 1,
+                LinError::Parity => self.stats.parity_errors += 1,
+                LinError::Checksum => self.stats.checksum_errors += 1,
+                _ => {}
+            }
+        }
+    }
+
+    /// Feed one received byte into the state machine
+    pub fn process_byte(&mut self, byte: u8, framing_error: bool) -> Result<(), LinError> {
+        // A break (dominant for > 11 bits) always restarts the frame
+        if framing_error {
+            self.state = State::WaitBreak;
+            if byte == 0x00 {
+                self.state = State::WaitSync;
+                return Ok(());
+            }
+            return Err(LinError::Framing);
+        }
+
+        match self.state {
+            State::WaitBreak => Ok(()),
+            State::WaitSync => {
+                if byte == SYNC_BYTE {
+                    self.state = State::WaitPid;
+                    Ok(())
+                } else {
+                    self.state = State::WaitBreak;
+                    Err(LinError::Sync)
+                }
+            }
+            State::WaitPid => {
+                self.state = State::WaitBreak;
+                let id = parse_pid(byte).ok_or(LinError::Parity)?;
+                self.pid = byte;
+
+                let slot = match self.frames.iter().position(|f| f.map(|f| f.id) == Some(id)) {
+                    Some(slot) => slot,
+                    // Not for us
+                    None => return Ok(()),
+                };
+
+                let frame = self.frames[slot].unwrap();
+                match frame.direction {
+                    LinDirection::Publish => {
+                        self.send_response(&frame);
+                        self.stats.frames_ok += 1;
+                        self.state = State::Echo {
+                            remaining: frame.length + 1,
+                        };
+                    }
+                    LinDirection::Subscribe => {
+                        self.state = State::Receiving { slot, index: 0 };
+                    }
+                }
+                Ok(())
+            }
+            State::Receiving { slot, index } => {
+                let mut frame = self.frames[slot].unwrap();
+                if index < frame.length {
+                    self.rx_data[index as usize] = byte;
+                    self.state = State::Receiving { slot, index: index + 1 };
+                    return Ok(());
+                }
+
+                self.state = State::WaitBreak;
+                let len = frame.length as usize;
+                if byte != checksum(self.pid, &self.rx_data[..len]) {
+                    return Err(LinError::Checksum);
+                }
+
+                frame.data[..len].copy_from_slice(&self.rx_data[..len]);
+                frame.updated = true;
+                self.frames[slot] = Some(frame);
+                self.stats.frames_ok += 1;
+
+                if let Some(handler) = self.handler {
+                    handler(frame.id, &frame.data[..len]);
+                }
+                Ok(())
+            }
+            State::Echo { remaining } => {
+                self.state = if remaining > 1 {
+                    State::Echo { remaining: remaining - 1 }
+                } else {
+                    State::WaitBreak
+                };
+                Ok(())
+            }
+        }
+    }
+
+    fn send_response(&mut self, frame: &LinFrame) {
+        let data = &frame.data[..frame.length as usize];
+        for &byte in data {
+            write_byte(byte);
+        }
+        write_byte(checksum(self.pid, data));
+    }
+
+    fn find_frame_mut(&mut self, id: u8) -> Option<&mut LinFrame> {
+        self.frames.iter_mut().flatten().find(|f| f.id == id)
+    }
+}
+
+impl Default for LinSlave {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn write_byte(byte: u8) {
+    unsafe {
+        let p = <USART1 as UartRegisterBlock>::ptr();
+        while (*p).ucsra.read().bits() & UDRE == 0 {}
+        (*p).udr.write(|w| w.bits(byte));
+    }
+}
+
+/// Compute the protected identifier (id + parity bits P0/P1) for a frame id
+pub fn protect_id(id: u8) -> u8 {
+    let bit = |n: u8| (id >> n) & 1;
+    let p0 = bit(0) ^ bit(1) ^ bit(2) ^ bit(4);
+    let p1 = !(bit(1) ^ bit(3) ^ bit(4) ^ bit(5)) & 1;
+    (id & 0x3F) | (p0 << 6) | (p1 << 7)
+}
+
+/// Validate the parity bits of a protected identifier, returning the frame id
+pub fn parse_pid(pid: u8) -> Option<u8> {
+    let id = pid & 0x3F;
+    if protect_id(id) == pid {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+/// LIN checksum: inverted 8-bit sum with carry wrap-around. LIN 2.x
+/// "enhanced" checksum includes the PID, diagnostic frames use the classic one.
+pub fn checksum(pid: u8, data: &[u8]) -> u8 {
+    let id = pid & 0x3F;
+    let mut sum: u16 = if id == MASTER_REQUEST_ID || id == SLAVE_RESPONSE_ID {
+        0
+    } else {
+        pid as u16
+    };
+
+    for &byte in data {
+        sum += byte as u16;
+        if sum > 0xFF {
+            sum -= 0xFF;
+        }
+    }
+    !(sum as u8)
+}
@@ -4,8 +4,20 @@
 pub mod packet;
 pub mod transport;
 pub mod crc;
+pub mod dmx;
+pub mod hardening;
+pub mod host_link;
+pub mod lin;
+pub mod pipeline;
+pub mod records;
 
-use crate::hal::uart::Uart;
+use crate::hal::board_id;
+use crate::hal::device_info::DeviceInfo;
+use avr_device::atmega128::USART0;
+
+use crate::hal::uart::{SerialPort, Uart};
+
+use self::hardening::{ErrorLimiter, Framer, MalformedStats};
 
 #[derive(Debug)]
 pub enum ProtocolError {
@@ -28,14 +40,35 @@ pub enum Command {
     Reset = 0x05,
     UpdateFirmware = 0x06,
     Debug = 0x07,
+    Cron = 0x08,
+    Watch = 0x09,
+    Vibration = 0x0A,
+    HostLink = 0x0B,
+    Channel = 0x0C,
+    Safety = 0x0D,
+    Heartbeat = 0x0E,
+    RunSelfTest = 0x0F,
+    SamplingPlan = 0x10,
+    Burst = 0x11,
+    PowerProfile = 0x12,
+    Hardening = 0x13,
+    Pipeline = 0x14,
+    /// Unsolicited telemetry records, kept apart from `GetData` replies
+    Telemetry = 0x15,
 }
 
-pub struct Protocol {
-    uart: Uart,
+/// Packet protocol over any `SerialPort`, USART0 unless given another
+pub struct Protocol<S: SerialPort = Uart<USART0>> {
+    uart: S,
     rx_buffer: [u8; 256],
     tx_buffer: [u8; 256],
     rx_index: usize,
     packet_handler: Option<fn(&[u8]) -> Result<()>>,
+    /// Length-delimited reception, see `hardening`
+    hardened: bool,
+    framer: Framer,
+    malformed: MalformedStats,
+    error_limiter: ErrorLimiter,
 }
 
 /*
@@ -59,14 +92,18 @@ struct ProtocolConfig {
 }
 */
 
-impl Protocol {
-    pub fn new(uart: Uart) -> Self {
+impl<S: SerialPort> Protocol<S> {
+    pub fn new(uart: S) -> Self {
         Self {
             uart,
             rx_buffer: [0; 256],
             tx_buffer: [0; 256],
             rx_index: 0,
             packet_handler: None,
+            hardened: false,
+            framer: Framer::new(),
+            malformed: MalformedStats::new(),
+            error_limiter: ErrorLimiter::new(),
         }
     }
 
@@ -75,6 +112,10 @@ impl Protocol {
     }
 
     pub fn process(&mut self) -> Result<()> {
+        if self.hardened {
+            return self.process_hardened();
+        }
+
         while let Some(byte) = self.uart.read_byte() {
             if self.rx_index >= self.rx_buffer.len() {
                 self.rx_index = 0;
@@ -121,14 +162,27 @@ impl Protocol {
         self.send_packet(Command::Ping, &[])
     }
 
+    /// Status reply: `[status, signature x3, low/high/ext fuse, lock bits,
+    /// device_info warning bits, board revision]`
     pub fn send_status(&mut self, status: u8) -> Result<()> {
-        self.send_packet(Command::GetStatus, &[status])
+        let mut data = [0u8; 10];
+        data[0] = status;
+        let mut info = [0u8; 8];
+        DeviceInfo::read().encode(&mut info);
+        data[1..9].copy_from_slice(&info);
+        data[9] = board_id::revision() as u8;
+        self.send_packet(Command::GetStatus, &data)
     }
 
     pub fn send_data(&mut self, data: &[u8]) -> Result<()> {
         self.send_packet(Command::GetData, data)
     }
 
+    /// Send a telemetry record, see `HostLink::telemetry_due`
+    pub fn send_telemetry(&mut self, data: &[u8]) -> Result<()> {
+        self.send_packet(Command::Telemetry, data)
+    }
+
     fn calculate_checksum(&self, data: &[u8]) -> u8 {
         let mut sum: u8 = 0;
         for &byte in data {
@@ -4,8 +4,52 @@
 use super::{Command, Result, ProtocolError};
 
 const MAX_PACKET_SIZE: usize = 256;
-const HEADER_SIZE: usize = 4;
-const FOOTER_SIZE: usize = 2;
+pub const HEADER_SIZE: usize = 4;
+pub const FOOTER_SIZE: usize = 2;
+
+/// Largest payload a packet may carry, longer length fields are rejected
+/// before anything is indexed with them
+pub const MAX_PAYLOAD: usize = 250;
+
+/// Largest chunk of channel data that fits one packet
+pub const MAX_CHANNEL_DATA: usize = MAX_PAYLOAD - 1;
+
+/// Why a received frame was rejected
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum Malformed {
+    /// Frame length doesn't match its length field, or is too short
+    BadLength = 0x01,
+    BadChecksum = 0x02,
+    /// Length field above `MAX_PAYLOAD`
+    Oversized = 0x03,
+    UnknownCommand = 0x04,
+    /// Start or end bytes missing
+    BadFraming = 0x05,
+}
+
+impl Malformed {
+    pub fn error(self) -> ProtocolError {
+        match self {
+            Malformed::BadChecksum => ProtocolError::InvalidChecksum,
+            Malformed::Oversized => ProtocolError::BufferOverflow,
+            Malformed::UnknownCommand => ProtocolError::InvalidCommand,
+            Malformed::BadLength | Malformed::BadFraming => ProtocolError::InvalidPacket,
+        }
+    }
+}
+
+/// Logical streams multiplexed onto one UART through `Command::Channel`.
+///
+/// Channel packets carry `[channel, data...]` as payload, so the host can
+/// demultiplex console text from binary telemetry by the first byte while
+/// all other commands keep their usual framing.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum Channel {
+    Console = 0x00,
+    Telemetry = 0x01,
+}
 
 pub struct Packet {
     buffer: [u8; MAX_PACKET_SIZE],
@@ -34,46 +78,11 @@ impl Packet {
     }
 
     pub fn parse(&mut self, data: &[u8]) -> Result<Command> {
-        if data.len() < HEADER_SIZE + FOOTER_SIZE {
-            return Err(ProtocolError::InvalidPacket);
-        }
-
-        if data[0] != 0x55 || data[1] != 0xAA {
-            return Err(ProtocolError::InvalidPacket);
-        }
-
-        let command = data[2];
-        let length = data[3] as usize;
-        
-        if data.len() != HEADER_SIZE + length + FOOTER_SIZE {
-            return Err(ProtocolError::InvalidPacket);
-        }
-
-        let checksum = data[HEADER_SIZE + length];
-        let end_byte = data[HEADER_SIZE + length + 1];
-
-        if end_byte != 0x0A {
-            return Err(ProtocolError::InvalidPacket);
-        }
-
-        let calc_checksum = self.calculate_checksum(&data[..HEADER_SIZE + length]);
-        if checksum != calc_checksum {
-            return Err(ProtocolError::InvalidChecksum);
-        }
+        let command = validate(data).map_err(Malformed::error)?;
 
         self.buffer[..data.len()].copy_from_slice(data);
         self.length = data.len();
-
-        match command {
-            0x01 => Ok(Command::Ping),
-            0x02 => Ok(Command::GetStatus),
-            0x03 => Ok(Command::SetConfig),
-            0x04 => Ok(Command::GetData),
-            0x05 => Ok(Command::Reset),
-            0x06 => Ok(Command::UpdateFirmware),
-            0x07 => Ok(Command::Debug),
-            _ => Err(ProtocolError::InvalidCommand),
-        }
+        Ok(command)
     }
 
     pub fn get_data(&self) -> &[u8] {
@@ -84,7 +93,7 @@ impl Packet {
     }
 
     pub fn create(&mut self, command: Command, data: &[u8]) -> Result<&[u8]> {
-        if data.len() > MAX_PACKET_SIZE - HEADER_SIZE - FOOTER_SIZE {
+        if data.len() > MAX_PAYLOAD {
             return Err(ProtocolError::BufferOverflow);
         }
 
@@ -95,19 +104,109 @@ impl Packet {
 
         self.buffer[HEADER_SIZE..HEADER_SIZE + data.len()].copy_from_slice(data);
 
-        let checksum = self.calculate_checksum(&self.buffer[..HEADER_SIZE + data.len()]);
+        let checksum = calculate_checksum(&self.buffer[..HEADER_SIZE + data.len()]);
         self.buffer[HEADER_SIZE + data.len()] = checksum;
         self.buffer[HEADER_SIZE + data.len() + 1] = 0x0A;
 
         self.length = HEADER_SIZE + data.len() + FOOTER_SIZE;
         Ok(&self.buffer[..self.length])
     }
+}
 
-    fn calculate_checksum(&self, data: &[u8]) -> u8 {
-        let mut sum: u8 = 0;
-        for &byte in data {
-            sum = sum.wrapping_add(byte);
-        }
-        !sum
+/// Check a complete frame and classify what is wrong with it. Every index
+/// is bounds checked against `data` first, so arbitrary input is safe.
+pub fn validate(data: &[u8]) -> core::result::Result<Command, Malformed> {
+    if data.len() < HEADER_SIZE + FOOTER_SIZE {
+        return Err(Malformed::BadLength);
+    }
+
+    if data[0] != 0x55 || data[1] != 0xAA {
+        return Err(Malformed::BadFraming);
+    }
+
+    let command = data[2];
+    let length = data[3] as usize;
+    if length > MAX_PAYLOAD {
+        return Err(Malformed::Oversized);
+    }
+    if data.len() != HEADER_SIZE + length + FOOTER_SIZE {
+        return Err(Malformed::BadLength);
+    }
+
+    let checksum = data[HEADER_SIZE + length];
+    let end_byte = data[HEADER_SIZE + length + 1];
+
+    if end_byte != 0x0A {
+        return Err(Malformed::BadFraming);
+    }
+
+    if checksum != calculate_checksum(&data[..HEADER_SIZE + length]) {
+        return Err(Malformed::BadChecksum);
+    }
+
+    command_from_id(command).ok_or(Malformed::UnknownCommand)
+}
+
+pub fn command_from_id(id: u8) -> Option<Command> {
+    match id {
+        0x01 => Some(Command::Ping),
+        0x02 => Some(Command::GetStatus),
+        0x03 => Some(Command::SetConfig),
+        0x04 => Some(Command::GetData),
+        0x05 => Some(Command::Reset),
+        0x06 => Some(Command::UpdateFirmware),
+        0x07 => Some(Command::Debug),
+        0x08 => Some(Command::Cron),
+        0x09 => Some(Command::Watch),
+        0x0A => Some(Command::Vibration),
+        0x0B => Some(Command::HostLink),
+        0x0C => Some(Command::Channel),
+        0x0D => Some(Command::Safety),
+        0x0E => Some(Command::Heartbeat),
+        0x0F => Some(Command::RunSelfTest),
+        0x10 => Some(Command::SamplingPlan),
+        0x11 => Some(Command::Burst),
+        0x12 => Some(Command::PowerProfile),
+        0x13 => Some(Command::Hardening),
+        0x14 => Some(Command::Pipeline),
+        0x15 => Some(Command::Telemetry),
+        _ => None,
+    }
+}
+
+fn calculate_checksum(data: &[u8]) -> u8 {
+    let mut sum: u8 = 0;
+    for &byte in data {
+        sum = sum.wrapping_add(byte);
+    }
+    !sum
+}
+
+/// Split a `Command::Channel` payload into its channel and data
+pub fn split_channel(payload: &[u8]) -> Result<(Channel, &[u8])> {
+    let (&id, data) = payload.split_first().ok_or(ProtocolError::InvalidPacket)?;
+    let channel = match id {
+        0x00 => Channel::Console,
+        0x01 => Channel::Telemetry,
+        _ => return Err(ProtocolError::InvalidPacket),
+    };
+    Ok((channel, data))
+}
+
+/// Frame `data` as a `Command::Channel` packet byte by byte, without
+/// needing a packet buffer
+pub fn write_channel_frame<F: FnMut(u8)>(channel: Channel, data: &[u8], mut write: F) -> Result<()> {
+    if data.len() > MAX_CHANNEL_DATA {
+        return Err(ProtocolError::BufferOverflow);
+    }
+
+    let header = [0x55, 0xAA, Command::Channel as u8, (data.len() + 1) as u8, channel as u8];
+    let mut sum: u8 = 0;
+    for &byte in header.iter().chain(data.iter()) {
+        sum = sum.wrapping_add(byte);
+        write(byte);
     }
+    write(!sum);
+    write(0x0A);
+    Ok(())
 }
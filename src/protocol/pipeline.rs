@@ -0,0 +1,272 @@
+//! Command pipeline between packet reception and the actuators
+//!
+//! Parsing a packet is fast, carrying it out (a move, a relay sequence, a
+//! flash write) may not be. The packet handler decodes a command into the
+//! application's own type `T`, `submit`s it with the host's sequence id and
+//! returns; the actuator side takes commands with `next` in arrival order
+//! and reports each one with `complete`. The acknowledgement goes out only
+//! then, so an ack means "done", not "received".
+//!
+//! At most `in_flight_limit` commands are queued, running or waiting for
+//! their ack; a submit beyond that is refused at once and the host retries,
+//! which keeps a fast host from stalling the link behind a slow actuator.
+//!
+//! Pipelined commands carry the sequence id in front of their payload
+//! (`[seq u16 LE, ...]`, see `split_seq`). `Command::Pipeline` watches the
+//! queue:
+//!
+//! | op   | request        | reply                                             |
+//! |------|----------------|---------------------------------------------------|
+//! | 0x01 | -              | queued, running, unacked, limit, submitted u16, refused u16 |
+//! | 0x02 | seq u16 LE     | stage, status                                     |
+//! | 0x03 | limit u8       | -, 1..=depth                                      |
+//! | 0x04 | seq u16 LE     | -, drops a command that has not started           |
+//!
+//! Acks go out unsolicited as `[0x81, seq u16 LE, status]`; status 0 is
+//! success, `STATUS_CANCELLED` a cancelled command, anything else the
+//! actuator's error code.
+#![no_std]
+
+use super::{Command, Protocol, ProtocolError, Result};
+use crate::hal::uart::SerialPort;
+
+const OP_STATUS: u8 = 0x01;
+const OP_QUERY: u8 = 0x02;
+const OP_SET_LIMIT: u8 = 0x03;
+const OP_CANCEL: u8 = 0x04;
+
+const RECORD_ACK: u8 = 0x81;
+
+pub const STATUS_OK: u8 = 0x00;
+/// Ack status of a command dropped by `OP_CANCEL`
+pub const STATUS_CANCELLED: u8 = 0xFF;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PipelineError {
+    /// `in_flight_limit` commands are pending, retry later
+    Busy,
+    /// The sequence id is still pending
+    Duplicate,
+}
+
+/// Where a command is, as reported by `OP_QUERY`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stage {
+    /// Not pending: acked already or never seen
+    Unknown,
+    Queued,
+    Running,
+    /// Finished with this status, ack not sent yet
+    Done(u8),
+}
+
+impl Stage {
+    fn encode(self) -> [u8; 2] {
+        match self {
+            Stage::Unknown => [0, 0],
+            Stage::Queued => [1, 0],
+            Stage::Running => [2, 0],
+            Stage::Done(status) => [3, status],
+        }
+    }
+}
+
+/// A command handed to the actuator side
+#[derive(Clone, Copy, Debug)]
+pub struct Pending<T> {
+    pub seq: u16,
+    pub command: T,
+}
+
+#[derive(Clone, Copy)]
+struct Slot<T> {
+    seq: u16,
+    command: T,
+    stage: Stage,
+    /// Arrival order, commands run oldest first
+    order: u16,
+}
+
+pub struct CommandPipeline<T: Copy, const N: usize> {
+    slots: [Option<Slot<T>>; N],
+    in_flight_limit: usize,
+    next_order: u16,
+    submitted: u16,
+    refused: u16,
+}
+
+impl<T: Copy, const N: usize> CommandPipeline<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [None; N],
+            in_flight_limit: N,
+            next_order: 0,
+            submitted: 0,
+            refused: 0,
+        }
+    }
+
+    /// Pending commands allowed at once, clamped to 1..=N
+    pub fn set_in_flight_limit(&mut self, limit: usize) {
+        self.in_flight_limit = limit.max(1).min(N);
+    }
+
+    pub fn in_flight_limit(&self) -> usize {
+        self.in_flight_limit
+    }
+
+    /// Commands queued, running or waiting for their ack
+    pub fn in_flight(&self) -> usize {
+        self.slots.iter().filter(|s| s.is_some()).count()
+    }
+
+    /// Queue a command, from the packet handler
+    pub fn submit(&mut self, seq: u16, command: T) -> core::result::Result<(), PipelineError> {
+        if self.find(seq).is_some() {
+            self.refused = self.refused.wrapping_add(1);
+            return Err(PipelineError::Duplicate);
+        }
+        if self.in_flight() >= self.in_flight_limit {
+            self.refused = self.refused.wrapping_add(1);
+            return Err(PipelineError::Busy);
+        }
+        // The limit is at most N, so a slot is free
+        let slot = self.slots.iter_mut().find(|s| s.is_none()).ok_or(PipelineError::Busy)?;
+        *slot = Some(Slot {
+            seq,
+            command,
+            stage: Stage::Queued,
+            order: self.next_order,
+        });
+        self.next_order = self.next_order.wrapping_add(1);
+        self.submitted = self.submitted.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Oldest queued command, now running. The actuator reports it back
+    /// with `complete`.
+    pub fn next(&mut self) -> Option<Pending<T>> {
+        let base = self.next_order;
+        let slot = self
+            .slots
+            .iter_mut()
+            .flatten()
+            .filter(|s| s.stage == Stage::Queued)
+            // Age relative to the next order number, so wrap-around keeps FIFO
+            .max_by_key(|s| base.wrapping_sub(s.order))?;
+        slot.stage = Stage::Running;
+        Some(Pending {
+            seq: slot.seq,
+            command: slot.command,
+        })
+    }
+
+    /// Finish a running command, its ack goes out with the next `send_acks`
+    pub fn complete(&mut self, seq: u16, status: u8) {
+        if let Some(slot) = self.find(seq) {
+            if slot.stage == Stage::Running {
+                slot.stage = Stage::Done(status);
+            }
+        }
+    }
+
+    /// Drop a command that has not started, it is acked as cancelled.
+    /// False if it is running or not pending.
+    pub fn cancel(&mut self, seq: u16) -> bool {
+        match self.find(seq) {
+            Some(slot) if slot.stage == Stage::Queued => {
+                slot.stage = Stage::Done(STATUS_CANCELLED);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn stage(&self, seq: u16) -> Stage {
+        self.slots
+            .iter()
+            .flatten()
+            .find(|s| s.seq == seq)
+            .map_or(Stage::Unknown, |s| s.stage)
+    }
+
+    /// Send the acks of finished commands and free their slots
+    pub fn send_acks<S: SerialPort>(&mut self, protocol: &mut Protocol<S>) -> Result<()> {
+        for entry in self.slots.iter_mut() {
+            if let Some(Slot { seq, stage: Stage::Done(status), .. }) = *entry {
+                let seq = seq.to_le_bytes();
+                protocol.send_packet(Command::Pipeline, &[RECORD_ACK, seq[0], seq[1], status])?;
+                *entry = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a `Command::Pipeline` payload and write the reply into
+    /// `response`. Returns the number of response bytes written.
+    pub fn handle_command(&mut self, data: &[u8], response: &mut [u8]) -> Result<usize> {
+        let (&op, args) = data.split_first().ok_or(ProtocolError::InvalidPacket)?;
+
+        match op {
+            OP_STATUS => {
+                if response.len() < 8 {
+                    return Err(ProtocolError::BufferOverflow);
+                }
+                let count = |stage: fn(Stage) -> bool| self.slots.iter().flatten().filter(|s| stage(s.stage)).count() as u8;
+                response[0] = count(|s| s == Stage::Queued);
+                response[1] = count(|s| s == Stage::Running);
+                response[2] = count(|s| matches!(s, Stage::Done(_)));
+                response[3] = self.in_flight_limit as u8;
+                response[4..6].copy_from_slice(&self.submitted.to_le_bytes());
+                response[6..8].copy_from_slice(&self.refused.to_le_bytes());
+                Ok(8)
+            }
+            OP_QUERY => {
+                if response.len() < 2 {
+                    return Err(ProtocolError::BufferOverflow);
+                }
+                let seq = read_seq(args)?;
+                response[..2].copy_from_slice(&self.stage(seq).encode());
+                Ok(2)
+            }
+            OP_SET_LIMIT => {
+                let &limit = args.first().ok_or(ProtocolError::InvalidPacket)?;
+                if limit == 0 || limit as usize > N {
+                    return Err(ProtocolError::InvalidPacket);
+                }
+                self.set_in_flight_limit(limit as usize);
+                Ok(0)
+            }
+            OP_CANCEL => {
+                if self.cancel(read_seq(args)?) {
+                    Ok(0)
+                } else {
+                    Err(ProtocolError::InvalidPacket)
+                }
+            }
+            _ => Err(ProtocolError::InvalidCommand),
+        }
+    }
+
+    fn find(&mut self, seq: u16) -> Option<&mut Slot<T>> {
+        self.slots.iter_mut().flatten().find(|s| s.seq == seq)
+    }
+}
+
+impl<T: Copy, const N: usize> Default for CommandPipeline<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Split the sequence id off the payload of a pipelined command
+pub fn split_seq(payload: &[u8]) -> Result<(u16, &[u8])> {
+    if payload.len() < 2 {
+        return Err(ProtocolError::InvalidPacket);
+    }
+    Ok((u16::from_le_bytes([payload[0], payload[1]]), &payload[2..]))
+}
+
+fn read_seq(args: &[u8]) -> Result<u16> {
+    split_seq(args).map(|(seq, _)| seq)
+}
@@ -0,0 +1,213 @@
+//! Structured queries under `Command::GetData`
+//!
+//! Data the host can ask for is organised as records, addressed by a
+//! subsystem id and a record id within it, some with several instances
+//! picked by an index (an IMU of the dual pair, an ADC channel block, a
+//! frame slot). Modules register a reader per record; the host lists what
+//! the running build offers and decodes each payload by its record type,
+//! so a host tool works against any build without knowing its features.
+//!
+//! Payload ops, the first byte of a `Command::GetData` request:
+//!
+//! ```text
+//! 0x01 list  [first]                    [total, first, entry*]
+//!            entry = [subsystem, record, type, count, name_len, name...]
+//! 0x02 read  [subsystem, record, index] [subsystem, record, index, type, payload...]
+//! ```
+//!
+//! `list` fills the reply with as many entries as fit, from entry `first`;
+//! the host asks again from where it stopped until it has `total`. `index`
+//! may be left out for records with a single instance (count 0 or 1).
+//!
+//! Unsolicited telemetry goes out under `Command::Telemetry`, so replies
+//! here can be told apart from the telemetry stream.
+#![no_std]
+
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
+
+use super::{ProtocolError, Result};
+use crate::config::FIRMWARE_VERSION;
+use crate::diagnostics;
+use crate::hal::{board_id, reset, systime};
+
+const MAX_RECORDS: usize = 16;
+const MAX_NAME_LEN: usize = 12;
+
+const OP_LIST: u8 = 0x01;
+const OP_READ: u8 = 0x02;
+
+// Subsystem ids
+pub const SUBSYSTEM_SYSTEM: u8 = 0x01;
+pub const SUBSYSTEM_IMU: u8 = 0x02;
+pub const SUBSYSTEM_ADC: u8 = 0x03;
+pub const SUBSYSTEM_TASKS: u8 = 0x04;
+pub const SUBSYSTEM_ERRORS: u8 = 0x05;
+
+/// Payload layout of a record, all fields little endian
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum RecordType {
+    /// Opaque bytes, only the registering module knows the layout
+    Raw = 0,
+    /// `[fw major, minor, patch, board revision, reset reason, reset
+    /// flags, uptime_ms u32, error count u32]`
+    System = 1,
+    /// `[accel x/y/z i16, gyro x/y/z i16, temperature i16]`, raw counts
+    ImuSnapshot = 2,
+    /// `[first channel u8, count u8, counts u16 * count]`, a channel not
+    /// converted yet reads `adc::NOT_CONVERTED`
+    AdcBlock = 3,
+    /// `[runs u32, skipped u32, overruns u32, max_us u32]`, one instance
+    /// per frame slot
+    TaskStats = 4,
+    /// `[code u32, subcode u16, timestamp u32, data u32]`, instance 0 is
+    /// the newest
+    ErrorEntry = 5,
+}
+
+#[derive(Debug)]
+pub enum RecordError {
+    TableFull,
+    NameTooLong,
+    /// The subsystem/record pair is taken
+    Duplicate,
+}
+
+/// Fill `out` with instance `index` of the record, returning the length,
+/// or `None` if there is no such instance (yet)
+pub type RecordReader = fn(index: u8, out: &mut [u8]) -> Option<usize>;
+
+#[derive(Clone, Copy)]
+struct RecordEntry {
+    subsystem: u8,
+    record: u8,
+    name: &'static str,
+    ty: RecordType,
+    /// Number of instances, 0 for a single one without an index
+    count: u8,
+    read: RecordReader,
+}
+
+/// Always present, ahead of the registered records
+const BUILTIN: [RecordEntry; 1] = [RecordEntry {
+    subsystem: SUBSYSTEM_SYSTEM,
+    record: 0x01,
+    name: "system",
+    ty: RecordType::System,
+    count: 0,
+    read: read_system,
+}];
+
+static RECORDS: Mutex<RefCell<[Option<RecordEntry>; MAX_RECORDS]>> = Mutex::new(RefCell::new([None; MAX_RECORDS]));
+
+/// Offer a record to the host. `count` is the number of instances
+/// (`index` 0..count), 0 for a single one.
+pub fn register(
+    subsystem: u8,
+    record: u8,
+    name: &'static str,
+    ty: RecordType,
+    count: u8,
+    read: RecordReader,
+) -> core::result::Result<(), RecordError> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(RecordError::NameTooLong);
+    }
+    if find(subsystem, record).is_some() {
+        return Err(RecordError::Duplicate);
+    }
+
+    avr_device::interrupt::free(|cs| {
+        let mut records = RECORDS.borrow(cs).borrow_mut();
+        for slot in records.iter_mut() {
+            if slot.is_none() {
+                *slot = Some(RecordEntry {
+                    subsystem,
+                    record,
+                    name,
+                    ty,
+                    count,
+                    read,
+                });
+                return Ok(());
+            }
+        }
+        Err(RecordError::TableFull)
+    })
+}
+
+/// Handle a `Command::GetData` request, writing the reply into `response`
+pub fn handle_command(data: &[u8], response: &mut [u8]) -> Result<usize> {
+    let op = *data.first().ok_or(ProtocolError::InvalidPacket)?;
+
+    match op {
+        OP_LIST => {
+            let first = data.get(1).copied().unwrap_or(0) as usize;
+            if response.len() < 2 {
+                return Err(ProtocolError::BufferOverflow);
+            }
+            let registered = avr_device::interrupt::free(|cs| *RECORDS.borrow(cs).borrow());
+            let entries = BUILTIN.iter().chain(registered.iter().flatten());
+            response[0] = entries.clone().count() as u8;
+            response[1] = first as u8;
+
+            let mut len = 2;
+            for entry in entries.skip(first) {
+                let name = entry.name.as_bytes();
+                if len + 5 + name.len() > response.len() {
+                    break;
+                }
+                response[len] = entry.subsystem;
+                response[len + 1] = entry.record;
+                response[len + 2] = entry.ty as u8;
+                response[len + 3] = entry.count;
+                response[len + 4] = name.len() as u8;
+                response[len + 5..len + 5 + name.len()].copy_from_slice(name);
+                len += 5 + name.len();
+            }
+            Ok(len)
+        }
+        OP_READ => {
+            if data.len() != 3 && data.len() != 4 {
+                return Err(ProtocolError::InvalidPacket);
+            }
+            let entry = find(data[1], data[2]).ok_or(ProtocolError::InvalidPacket)?;
+            let index = data.get(3).copied().unwrap_or(0);
+            if index >= entry.count.max(1) {
+                return Err(ProtocolError::InvalidPacket);
+            }
+            if response.len() < 4 {
+                return Err(ProtocolError::BufferOverflow);
+            }
+            let payload = (entry.read)(index, &mut response[4..]).ok_or(ProtocolError::InvalidPacket)?;
+            response[0] = entry.subsystem;
+            response[1] = entry.record;
+            response[2] = index;
+            response[3] = entry.ty as u8;
+            Ok(4 + payload)
+        }
+        _ => Err(ProtocolError::InvalidCommand),
+    }
+}
+
+fn find(subsystem: u8, record: u8) -> Option<RecordEntry> {
+    let matches = |e: &RecordEntry| e.subsystem == subsystem && e.record == record;
+    if let Some(entry) = BUILTIN.iter().find(|e| matches(e)) {
+        return Some(*entry);
+    }
+    avr_device::interrupt::free(|cs| RECORDS.borrow(cs).borrow().iter().flatten().find(|e| matches(e)).copied())
+}
+
+fn read_system(_index: u8, out: &mut [u8]) -> Option<usize> {
+    if out.len() < 14 {
+        return None;
+    }
+    out[0..3].copy_from_slice(&FIRMWARE_VERSION);
+    out[3] = board_id::revision() as u8;
+    out[4] = reset::reason() as u8;
+    out[5] = reset::flags();
+    out[6..10].copy_from_slice(&systime::millis().to_le_bytes());
+    out[10..14].copy_from_slice(&diagnostics::error_count().to_le_bytes());
+    Some(14)
+}
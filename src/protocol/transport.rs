@@ -2,19 +2,24 @@
 #![no_std]
 
 use super::{Result, ProtocolError};
-use crate::hal::uart::Uart;
+use avr_device::atmega128::USART0;
 
-const RX_BUFFER_SIZE: usize = 512;
-const TX_BUFFER_SIZE: usize = 512;
+use crate::hal::uart::{FlowControl, SerialPort, Uart};
 
-pub struct Transport {
-    uart: Uart,
+const RX_BUFFER_SIZE: usize = crate::config::TRANSPORT_RX_BUFFER;
+const TX_BUFFER_SIZE: usize = crate::config::TRANSPORT_TX_BUFFER;
+
+pub struct Transport<S: SerialPort = Uart<USART0>> {
+    uart: S,
     rx_buffer: [u8; RX_BUFFER_SIZE],
     tx_buffer: [u8; TX_BUFFER_SIZE],
     rx_head: usize,
     rx_tail: usize,
     tx_head: usize,
     tx_tail: usize,
+    flow_control: bool,
+    rx_overruns: u32,
+    tx_overruns: u32,
 }
 
 /*
@@ -24,17 +29,23 @@ struct TransportConfig {
     cts_threshold: u16,
     timeout_ms: u16,
 }
+*/
 
-struct TransportStats {
-    rx_overruns: u32,
-    tx_overruns: u32,
-    frame_errors: u32,
-    parity_errors: u32,
+/// Transport and line error counters
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TransportStats {
+    /// Bytes lost because the transport RX buffer was full
+    pub rx_overruns: u32,
+    /// Writes rejected because the transport TX buffer was full
+    pub tx_overruns: u32,
+    pub frame_errors: u32,
+    pub parity_errors: u32,
+    /// Characters lost in the UART itself, in hardware or its RX buffer
+    pub uart_overruns: u32,
 }
-*/
 
-impl Transport {
-    pub fn new(uart: Uart) -> Self {
+impl<S: SerialPort> Transport<S> {
+    pub fn new(uart: S) -> Self {
         Self {
             uart,
             rx_buffer: [0; RX_BUFFER_SIZE],
@@ -43,9 +54,19 @@ impl Transport {
             rx_tail: 0,
             tx_head: 0,
             tx_tail: 0,
+            flow_control: false,
+            rx_overruns: 0,
+            tx_overruns: 0,
         }
     }
 
+    /// Enable RTS/CTS on the underlying UART. With flow control a full
+    /// receive buffer holds off the sender instead of dropping data.
+    pub fn set_flow_control(&mut self, flow: Option<FlowControl>) {
+        self.flow_control = flow.is_some();
+        self.uart.set_flow_control(flow);
+    }
+
     pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
         let mut count = 0;
         while count < buffer.len() && self.rx_head != self.rx_tail {
@@ -61,6 +82,7 @@ impl Transport {
         for &byte in data {
             let next_head = (self.tx_head + 1) % TX_BUFFER_SIZE;
             if next_head == self.tx_tail {
+                self.tx_overruns = self.tx_overruns.wrapping_add(1);
                 return Err(ProtocolError::BufferOverflow);
             }
             self.tx_buffer[self.tx_head] = byte;
@@ -78,9 +100,18 @@ impl Transport {
     }
 
     fn process_rx(&mut self) -> Result<()> {
-        while let Some(byte) = self.uart.read_byte() {
+        loop {
             let next_head = (self.rx_head + 1) % RX_BUFFER_SIZE;
+            if next_head == self.rx_tail && self.flow_control {
+                // Leave the rest in the UART so RTS pauses the sender
+                return Ok(());
+            }
+            let byte = match self.uart.read_byte() {
+                Some(byte) => byte,
+                None => break,
+            };
             if next_head == self.rx_tail {
+                self.rx_overruns = self.rx_overruns.wrapping_add(1);
                 return Err(ProtocolError::BufferOverflow);
             }
             self.rx_buffer[self.rx_head] = byte;
@@ -90,6 +121,7 @@ impl Transport {
     }
 
     fn process_tx(&mut self) -> Result<()> {
+        self.uart.service_flow();
         self.flush_tx()
     }
 
@@ -104,6 +136,23 @@ impl Transport {
         Ok(())
     }
 
+    pub fn stats(&self) -> TransportStats {
+        let uart = self.uart.stats();
+        TransportStats {
+            rx_overruns: self.rx_overruns,
+            tx_overruns: self.tx_overruns,
+            frame_errors: uart.framing_errors,
+            parity_errors: uart.parity_errors,
+            uart_overruns: uart.data_overruns.wrapping_add(uart.rx_dropped),
+        }
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.rx_overruns = 0;
+        self.tx_overruns = 0;
+        self.uart.reset_stats();
+    }
+
     pub fn bytes_available(&self) -> usize {
         if self.rx_head >= self.rx_tail {
             self.rx_head - self.rx_tail
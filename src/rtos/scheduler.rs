@@ -2,8 +2,13 @@
 #![no_std]
 
 use super::task::{Task, TaskState, TaskControl};
-use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::atomic::AtomicU32;
 use avr_device::atmega128::{TC0, interrupt};
+use crate::hal::clock;
+use crate::config::EVENT_QUEUE_LEN;
+use crate::time::{Duration, Instant};
 
 const MAX_TASKS: usize = 16;
 const TICK_MS: u32 = 1;
@@ -61,7 +66,7 @@ pub struct Event {
 }
 
 pub struct EventQueue {
-    events: [Option<Event>; 32],
+    events: [Option<Event>; EVENT_QUEUE_LEN],
     head: usize,
     tail: usize,
 }
@@ -69,7 +74,7 @@ pub struct EventQueue {
 impl EventQueue {
     pub const fn new() -> Self {
         Self {
-            events: [None; 32],
+            events: [None; EVENT_QUEUE_LEN],
             head: 0,
             tail: 0,
         }
@@ -186,7 +191,7 @@ impl Scheduler {
             w.cs0().bits(0b011) // Prescaler 64
              .wgm0().bits(0b10) // CTC mode
         });
-        self.timer.ocr0.write(|w| unsafe { w.bits(250) }); // 16MHz/64/250 = 1kHz
+        self.timer.ocr0.write(|w| unsafe { w.bits(clock::ctc_top(64, 1000) as u8) }); // 1kHz
         self.timer.timsk.modify(|_, w| w.ocie0().set_bit());
 
         // Create and add idle task
@@ -326,7 +331,7 @@ impl Scheduler {
         let event = Event {
             event_type,
             data,
-            timestamp: SYSTEM_TICKS.load(Ordering::Relaxed),
+            timestamp: SYSTEM_TICKS.load(),
         };
 
         if !self.event_queue.push(event) {
@@ -339,7 +344,8 @@ impl Scheduler {
     }
 
     pub fn wait_for_event(&mut self, event_type: EventType, timeout_ms: u32) -> Result<Event> {
-        let deadline = SYSTEM_TICKS.load(Ordering::Relaxed) + timeout_ms;
+        let deadline = Instant::from_ticks(SYSTEM_TICKS.load())
+            + Duration::from_millis(timeout_ms);
         
         loop {
             if let Some(event) = self.event_queue.pop() {
@@ -348,7 +354,7 @@ impl Scheduler {
                 }
             }
 
-            if SYSTEM_TICKS.load(Ordering::Relaxed) >= deadline {
+            if Instant::from_ticks(SYSTEM_TICKS.load()).has_reached(deadline) {
                 return Err(SchedulerError::Timeout);
             }
 
@@ -1,6 +1,7 @@
 #![no_std]
 
 use core::sync::atomic::{AtomicU8, Ordering};
+use crate::config::TASK_STACK_SIZE;
 
 static NEXT_TASK_ID: AtomicU8 = AtomicU8::new(0);
 
@@ -36,7 +37,7 @@ pub struct TaskControl {
 
 pub struct Task {
     pub control: TaskControl,
-    stack: [u8; 512],
+    stack: [u8; TASK_STACK_SIZE],
 }
 
 impl Task {
@@ -50,7 +51,7 @@ impl Task {
                 id: NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed),
                 stack_ptr: core::ptr::null_mut(),
                 stack_base: core::ptr::null_mut(),
-                stack_size: 512,
+                stack_size: TASK_STACK_SIZE,
                 state: TaskState::Ready,
                 priority,
                 name,
@@ -58,7 +59,7 @@ impl Task {
                 last_wake_time: 0,
                 deadline_ms: 0,
             },
-            stack: [0; 512],
+            stack: [0; TASK_STACK_SIZE],
         };
 
         let stack_top = task.init_stack(entry);
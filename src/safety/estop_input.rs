@@ -0,0 +1,46 @@
+//! Emergency stop button input
+//!
+//! A normally-closed mushroom button (or a chain of them) to ground with
+//! the internal pull-up, so a broken wire trips it too. `poll` runs on the
+//! loop tick and stops as soon as the contact has read open for two
+//! samples. While the button stays pressed every poll stops again, which
+//! undoes a `release_estop` from the host until the button is pulled out.
+#![no_std]
+
+use crate::drivers::debounced_input::{DebounceConfig, DebouncedInput};
+use crate::hal::claims::{self, Port, Resource};
+
+use super::{estop, state, SafetyState};
+
+pub struct EStopInput {
+    input: DebouncedInput,
+}
+
+impl EStopInput {
+    pub fn new(port: Port, bit: u8) -> Self {
+        Self::with_config(port, bit, DebounceConfig::normally_closed())
+    }
+
+    pub fn with_config(port: Port, bit: u8, config: DebounceConfig) -> Self {
+        claims::claim(Resource::Pin(port, bit), "estop").ok();
+        let input = DebouncedInput::new(port, bit, config);
+        // Pressed at power-up reports no edge, stop anyway
+        if input.is_active() {
+            estop();
+        }
+        Self { input }
+    }
+
+    /// Sample the button, returns whether it is pressed
+    pub fn poll(&mut self) -> bool {
+        self.input.update();
+        if self.input.is_active() && state() != SafetyState::EStop {
+            estop();
+        }
+        self.input.is_active()
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.input.is_active()
+    }
+}
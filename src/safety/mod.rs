@@ -0,0 +1,208 @@
+//! Central safety state machine gating all actuator outputs
+//!
+//! Actuator drivers query `outputs_allowed()` before producing any output,
+//! so nothing can move until the system has been explicitly armed. Arming
+//! requires a passed self-test and an arm request; any fault or emergency
+//! stop drops the outputs immediately.
+//!
+//! ```text
+//! Init --init_complete(ok)--> Standby --arm()--> Armed
+//!   |                           ^   ^              |
+//!   +--(failed)--> Fault -------+   +---disarm()---+
+//!                    ^ clear_fault()
+//! any --fault()--> Fault          any --estop()--> EStop --release_estop()--> Standby
+//! ```
+//!
+//! The speed limit override lifts the `speed_governor` limit, e.g. to move
+//! a vehicle off a test stand. It can only be set outside `Armed` so the
+//! limit never drops away mid-run, and a fault or emergency stop clears it.
+#![no_std]
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::hal::pwm;
+use crate::protocol::{ProtocolError, Result};
+
+pub mod estop_input;
+
+pub use estop_input::EStopInput;
+
+// Sub-commands carried in the first payload byte of Command::Safety
+const OP_STATUS: u8 = 0x01;
+const OP_ARM: u8 = 0x02;
+const OP_DISARM: u8 = 0x03;
+const OP_ESTOP: u8 = 0x04;
+const OP_RELEASE: u8 = 0x05;
+const OP_CLEAR_FAULT: u8 = 0x06;
+const OP_SPEED_OVERRIDE: u8 = 0x07;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum SafetyState {
+    Init = 0,
+    Standby = 1,
+    Armed = 2,
+    Fault = 3,
+    EStop = 4,
+}
+
+impl SafetyState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => SafetyState::Standby,
+            2 => SafetyState::Armed,
+            3 => SafetyState::Fault,
+            4 => SafetyState::EStop,
+            _ => SafetyState::Init,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SafetyError {
+    /// Transition not allowed from the current state
+    InvalidTransition,
+    /// Self-test has not passed since the last fault
+    SelfTestFailed,
+}
+
+static STATE: AtomicU8 = AtomicU8::new(SafetyState::Init as u8);
+static SELF_TEST_OK: AtomicBool = AtomicBool::new(false);
+static SPEED_OVERRIDE: AtomicBool = AtomicBool::new(false);
+
+pub fn state() -> SafetyState {
+    SafetyState::from_u8(STATE.load(Ordering::SeqCst))
+}
+
+/// True only while armed; actuators must output nothing otherwise
+pub fn outputs_allowed() -> bool {
+    state() == SafetyState::Armed
+}
+
+/// Move from `from` to `to` atomically, failing if the state changed
+fn transition(from: SafetyState, to: SafetyState) -> core::result::Result<(), SafetyError> {
+    avr_device::interrupt::free(|_| {
+        if state() != from {
+            return Err(SafetyError::InvalidTransition);
+        }
+        STATE.store(to as u8, Ordering::SeqCst);
+        Ok(())
+    })
+}
+
+/// Record the outcome of the latest self-test
+pub fn set_self_test_result(passed: bool) {
+    SELF_TEST_OK.store(passed, Ordering::SeqCst);
+}
+
+/// Finish initialization once drivers are up and the boot self-test ran
+pub fn init_complete(self_test_passed: bool) {
+    set_self_test_result(self_test_passed);
+    let next = if self_test_passed {
+        SafetyState::Standby
+    } else {
+        SafetyState::Fault
+    };
+    transition(SafetyState::Init, next).ok();
+}
+
+/// Explicit arm request
+pub fn arm() -> core::result::Result<(), SafetyError> {
+    if !SELF_TEST_OK.load(Ordering::SeqCst) {
+        return Err(SafetyError::SelfTestFailed);
+    }
+    transition(SafetyState::Standby, SafetyState::Armed)?;
+    // Outputs come back from zero, ramp them in gently
+    pwm::restart_soft_start();
+    Ok(())
+}
+
+pub fn disarm() {
+    transition(SafetyState::Armed, SafetyState::Standby).ok();
+}
+
+/// Latch a fault. Has no effect during an emergency stop.
+pub fn fault() {
+    avr_device::interrupt::free(|_| {
+        if state() != SafetyState::EStop {
+            STATE.store(SafetyState::Fault as u8, Ordering::SeqCst);
+            SELF_TEST_OK.store(false, Ordering::SeqCst);
+            SPEED_OVERRIDE.store(false, Ordering::SeqCst);
+        }
+    });
+}
+
+/// Leave the fault state, requires a passed self-test since the fault
+pub fn clear_fault() -> core::result::Result<(), SafetyError> {
+    if !SELF_TEST_OK.load(Ordering::SeqCst) {
+        return Err(SafetyError::SelfTestFailed);
+    }
+    transition(SafetyState::Fault, SafetyState::Standby)
+}
+
+/// Emergency stop from any state
+pub fn estop() {
+    STATE.store(SafetyState::EStop as u8, Ordering::SeqCst);
+    SPEED_OVERRIDE.store(false, Ordering::SeqCst);
+}
+
+pub fn release_estop() -> core::result::Result<(), SafetyError> {
+    transition(SafetyState::EStop, SafetyState::Standby)
+}
+
+/// Lift or restore the speed limit. Refused while armed, clearing is
+/// always allowed.
+pub fn set_speed_override(enabled: bool) -> core::result::Result<(), SafetyError> {
+    avr_device::interrupt::free(|_| {
+        if enabled && state() == SafetyState::Armed {
+            return Err(SafetyError::InvalidTransition);
+        }
+        SPEED_OVERRIDE.store(enabled, Ordering::SeqCst);
+        Ok(())
+    })
+}
+
+pub fn speed_override() -> bool {
+    SPEED_OVERRIDE.load(Ordering::SeqCst)
+}
+
+/// Handle a `Command::Safety` payload. Every operation replies with the
+/// resulting state byte; refused transitions report `InvalidCommand`.
+/// `OP_SPEED_OVERRIDE` takes an on/off byte and appends the override flag.
+pub fn handle_command(data: &[u8], response: &mut [u8]) -> Result<usize> {
+    let op = *data.first().ok_or(ProtocolError::InvalidPacket)?;
+    if response.is_empty() {
+        return Err(ProtocolError::BufferOverflow);
+    }
+
+    let result = match op {
+        OP_STATUS => Ok(()),
+        OP_ARM => arm(),
+        OP_DISARM => {
+            disarm();
+            Ok(())
+        }
+        OP_ESTOP => {
+            estop();
+            Ok(())
+        }
+        OP_RELEASE => release_estop(),
+        OP_CLEAR_FAULT => clear_fault(),
+        OP_SPEED_OVERRIDE => {
+            let enabled = *data.get(1).ok_or(ProtocolError::InvalidPacket)? != 0;
+            if response.len() < 2 {
+                return Err(ProtocolError::BufferOverflow);
+            }
+            set_speed_override(enabled)
+        }
+        _ => return Err(ProtocolError::InvalidCommand),
+    };
+
+    result.map_err(|_| ProtocolError::InvalidCommand)?;
+    response[0] = state() as u8;
+    if op == OP_SPEED_OVERRIDE {
+        response[1] = speed_override() as u8;
+        return Ok(2);
+    }
+    Ok(1)
+}
@@ -0,0 +1,183 @@
+//! Brown-out safe shutdown hooks
+//!
+//! Modules register hooks (flush logger, disable motors, park servos, write
+//! counters, ...) with a priority and a worst-case execution time. When a
+//! shutdown is triggered the hooks run highest priority first until the total
+//! time budget is used up; hooks that no longer fit are skipped so the most
+//! important work always gets done before the supply or the watchdog gives
+//! out.
+//!
+//! Registered today: the PWM/servo outputs (`hal::pwm::stop_outputs`, from
+//! `Pwm::new` and `Servo::new`) and the error log flush
+//! (`Diagnostics::register_shutdown_hooks`).
+#![no_std]
+
+pub mod reset;
+
+use avr_device::interrupt::Mutex;
+use core::cell::RefCell;
+
+use crate::hal::watchdog;
+
+const MAX_HOOKS: usize = 12;
+
+/// Total time allowed for all hooks, well below the 1s watchdog timeout
+pub const TOTAL_BUDGET_MS: u16 = 200;
+
+/// Why the system is shutting down
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum ShutdownReason {
+    WatchdogImminent = 0,
+    LowVoltage = 1,
+    EmergencyStop = 2,
+    HostReset = 3,
+}
+
+#[derive(Debug)]
+pub enum ShutdownError {
+    TooManyHooks,
+    BudgetTooLarge,
+    /// A hook of the same name is registered already
+    Duplicate,
+}
+
+pub type ShutdownHook = fn(ShutdownReason);
+
+/// Hook called with the context address it was registered with, for work
+/// on a driver instance rather than on statics
+pub type ContextHook = fn(ShutdownReason, usize);
+
+#[derive(Clone, Copy)]
+enum Hook {
+    Plain(ShutdownHook),
+    Context(ContextHook, usize),
+}
+
+#[derive(Clone, Copy)]
+struct HookEntry {
+    name: &'static str,
+    priority: u8,
+    cost_ms: u16,
+    hook: Hook,
+}
+
+/// Outcome of a shutdown run
+#[derive(Clone, Copy)]
+pub struct ShutdownReport {
+    pub reason: ShutdownReason,
+    pub executed: u8,
+    pub skipped: u8,
+    pub budget_used_ms: u16,
+    /// Name of the first hook that did not fit in the budget
+    pub first_skipped: Option<&'static str>,
+}
+
+static HOOKS: Mutex<RefCell<[Option<HookEntry>; MAX_HOOKS]>> =
+    Mutex::new(RefCell::new([None; MAX_HOOKS]));
+
+/// Register a shutdown hook.
+///
+/// `priority`: higher runs first. `cost_ms`: worst-case execution time of the
+/// hook, used to fit hooks into `TOTAL_BUDGET_MS`. Drivers register from
+/// their constructor, a second registration under the same name returns
+/// `Duplicate`.
+pub fn register(
+    name: &'static str,
+    priority: u8,
+    cost_ms: u16,
+    hook: ShutdownHook,
+) -> Result<(), ShutdownError> {
+    insert(name, priority, cost_ms, Hook::Plain(hook))
+}
+
+/// Register a hook called with `context`, see `register`.
+///
+/// # Safety
+///
+/// `context` is handed back to `hook` whenever a shutdown runs, from the
+/// watchdog interrupt too; whatever it points to must stay in place for
+/// the rest of the program.
+pub unsafe fn register_with_context(
+    name: &'static str,
+    priority: u8,
+    cost_ms: u16,
+    hook: ContextHook,
+    context: usize,
+) -> Result<(), ShutdownError> {
+    insert(name, priority, cost_ms, Hook::Context(hook, context))
+}
+
+fn insert(name: &'static str, priority: u8, cost_ms: u16, hook: Hook) -> Result<(), ShutdownError> {
+    if cost_ms > TOTAL_BUDGET_MS {
+        return Err(ShutdownError::BudgetTooLarge);
+    }
+
+    avr_device::interrupt::free(|cs| {
+        let mut hooks = HOOKS.borrow(cs).borrow_mut();
+        if hooks.iter().flatten().any(|e| e.name == name) {
+            return Err(ShutdownError::Duplicate);
+        }
+        for slot in hooks.iter_mut() {
+            if slot.is_none() {
+                *slot = Some(HookEntry {
+                    name,
+                    priority,
+                    cost_ms,
+                    hook,
+                });
+                return Ok(());
+            }
+        }
+        Err(ShutdownError::TooManyHooks)
+    })
+}
+
+/// Run all registered hooks for `reason` within the time budget
+pub fn run(reason: ShutdownReason) -> ShutdownReport {
+    // Work on a copy so hooks are free to use critical sections themselves
+    let mut hooks = avr_device::interrupt::free(|cs| *HOOKS.borrow(cs).borrow());
+
+    // Insertion sort by descending priority, registration order kept on ties
+    for i in 1..hooks.len() {
+        let mut j = i;
+        while j > 0 && priority_of(&hooks[j]) > priority_of(&hooks[j - 1]) {
+            hooks.swap(j, j - 1);
+            j -= 1;
+        }
+    }
+
+    let mut report = ShutdownReport {
+        reason,
+        executed: 0,
+        skipped: 0,
+        budget_used_ms: 0,
+        first_skipped: None,
+    };
+
+    for entry in hooks.iter().flatten() {
+        if report.budget_used_ms + entry.cost_ms > TOTAL_BUDGET_MS {
+            report.skipped += 1;
+            if report.first_skipped.is_none() {
+                report.first_skipped = Some(entry.name);
+            }
+            continue;
+        }
+
+        match entry.hook {
+            Hook::Plain(hook) => hook(reason),
+            Hook::Context(hook, context) => hook(reason, context),
+        }
+        report.budget_used_ms += entry.cost_ms;
+        report.executed += 1;
+
+        // Keep the watchdog from firing in the middle of the sequence
+        watchdog::feed();
+    }
+
+    report
+}
+
+fn priority_of(entry: &Option<HookEntry>) -> i16 {
+    entry.map(|e| e.priority as i16).unwrap_or(-1)
+}
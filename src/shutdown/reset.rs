@@ -0,0 +1,177 @@
+//! Host requested resets (`Command::Reset`)
+//!
+//! The host says why it resets the node and may give it a few seconds'
+//! notice, so it can disconnect cleanly or change its mind with a cancel.
+//! The reason and a running count of host resets are kept in EEPROM and
+//! can be read back after the restart. The reset itself runs the shutdown
+//! hooks with `ShutdownReason::HostReset` and lets the watchdog expire.
+//!
+//! Payload ops, every one replies with the status record
+//! `[pending u8, reason u8, seconds left u16, last reason u8, count u16]`:
+//!
+//! ```text
+//! 0x01 now      [reason]              reset once the reply is out
+//! 0x02 delayed  [reason, seconds u16] reset after the delay
+//! 0x03 cancel                         drop a pending reset
+//! 0x04 status
+//! ```
+//!
+//! An empty payload is an immediate reset without a reason, as before
+//! reasons existed.
+#![no_std]
+
+use crate::config::EEPROM_RESET_ADDR;
+use crate::hal::eeprom;
+use crate::hal::{systime, Watchdog, WatchdogTimeout};
+use crate::protocol::{ProtocolError, Result};
+use crate::shutdown::{self, ShutdownReason};
+
+const OP_NOW: u8 = 0x01;
+const OP_DELAYED: u8 = 0x02;
+const OP_CANCEL: u8 = 0x03;
+const OP_STATUS: u8 = 0x04;
+
+pub const RESET_STATUS_SIZE: usize = 7;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum ResetReason {
+    Unspecified = 0,
+    Maintenance = 1,
+    Update = 2,
+    FaultRecovery = 3,
+}
+
+impl ResetReason {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ResetReason::Unspecified),
+            1 => Some(ResetReason::Maintenance),
+            2 => Some(ResetReason::Update),
+            3 => Some(ResetReason::FaultRecovery),
+            _ => None,
+        }
+    }
+}
+
+/// Reason and count of the host resets so far
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ResetRecord {
+    pub reason: ResetReason,
+    pub count: u16,
+}
+
+/// Last recorded host reset, `None` if there never was one
+pub fn last_reset() -> Option<ResetRecord> {
+    let reason = eeprom::read_byte(EEPROM_RESET_ADDR);
+    if eeprom::read_byte(EEPROM_RESET_ADDR + 1) != !reason {
+        return None;
+    }
+    let count = u16::from_le_bytes([eeprom::read_byte(EEPROM_RESET_ADDR + 2), eeprom::read_byte(EEPROM_RESET_ADDR + 3)]);
+    Some(ResetRecord {
+        reason: ResetReason::from_u8(reason)?,
+        count,
+    })
+}
+
+fn record(reason: ResetReason) {
+    let count = last_reset().map_or(0, |r| r.count).wrapping_add(1);
+    eeprom::update_byte(EEPROM_RESET_ADDR, reason as u8);
+    eeprom::update_byte(EEPROM_RESET_ADDR + 1, !(reason as u8));
+    for (i, &byte) in count.to_le_bytes().iter().enumerate() {
+        eeprom::update_byte(EEPROM_RESET_ADDR + 2 + i as u16, byte);
+    }
+}
+
+/// Record `reason`, run the shutdown hooks and reset through the watchdog
+pub fn reset(reason: ResetReason) -> ! {
+    record(reason);
+    shutdown::run(ShutdownReason::HostReset);
+    avr_device::interrupt::disable();
+    Watchdog::new().start(WatchdogTimeout::Ms16);
+    loop {}
+}
+
+pub struct ResetControl {
+    /// Reason and deadline of the pending reset
+    pending: Option<(ResetReason, u32)>,
+}
+
+impl ResetControl {
+    pub const fn new() -> Self {
+        Self { pending: None }
+    }
+
+    /// Reset after `delay_s` seconds, replacing any pending reset
+    pub fn schedule(&mut self, reason: ResetReason, delay_s: u16, now_ms: u32) {
+        self.pending = Some((reason, now_ms.wrapping_add(delay_s as u32 * 1000)));
+    }
+
+    pub fn cancel(&mut self) {
+        self.pending = None;
+    }
+
+    /// Reason and seconds left of the pending reset
+    pub fn pending(&self, now_ms: u32) -> Option<(ResetReason, u16)> {
+        self.pending.map(|(reason, deadline)| {
+            let left_ms = (deadline.wrapping_sub(now_ms) as i32).max(0) as u32;
+            (reason, ((left_ms + 999) / 1000).min(u16::MAX as u32) as u16)
+        })
+    }
+
+    /// The reason once a pending reset is due; pass it to `reset`. Call
+    /// from the main loop after replies have been sent.
+    pub fn poll(&mut self, now_ms: u32) -> Option<ResetReason> {
+        let (reason, deadline) = self.pending?;
+        if (now_ms.wrapping_sub(deadline) as i32) < 0 {
+            return None;
+        }
+        self.pending = None;
+        Some(reason)
+    }
+
+    /// Handle a `Command::Reset` payload, see the module docs
+    pub fn handle_command(&mut self, data: &[u8], response: &mut [u8]) -> Result<usize> {
+        let now = systime::millis();
+        let reason = |i: usize| -> Result<ResetReason> {
+            let value = *data.get(i).ok_or(ProtocolError::InvalidPacket)?;
+            ResetReason::from_u8(value).ok_or(ProtocolError::InvalidPacket)
+        };
+
+        match data.first() {
+            None => self.schedule(ResetReason::Unspecified, 0, now),
+            Some(&OP_NOW) => self.schedule(reason(1)?, 0, now),
+            Some(&OP_DELAYED) => {
+                if data.len() != 4 {
+                    return Err(ProtocolError::InvalidPacket);
+                }
+                let delay_s = u16::from_le_bytes([data[2], data[3]]);
+                self.schedule(reason(1)?, delay_s, now);
+            }
+            Some(&OP_CANCEL) => self.cancel(),
+            Some(&OP_STATUS) => {}
+            Some(_) => return Err(ProtocolError::InvalidCommand),
+        }
+
+        if response.len() < RESET_STATUS_SIZE {
+            return Err(ProtocolError::BufferOverflow);
+        }
+        let (pending, reason, left) = match self.pending(now) {
+            Some((reason, left)) => (1, reason as u8, left),
+            None => (0, 0, 0),
+        };
+        let last = last_reset();
+        response[0] = pending;
+        response[1] = reason;
+        response[2..4].copy_from_slice(&left.to_le_bytes());
+        response[4] = last.map_or(0xFF, |r| r.reason as u8);
+        response[5..7].copy_from_slice(&last.map_or(0, |r| r.count).to_le_bytes());
+        Ok(RESET_STATUS_SIZE)
+    }
+}
+
+impl Default for ResetControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
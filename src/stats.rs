@@ -0,0 +1,272 @@
+//! Running statistics
+//!
+//! `Accumulator` keeps count, min/max and a Welford mean/variance of a
+//! stream of values in constant memory, optionally with a fixed-bucket
+//! histogram. `StatsRegistry` names a handful of them so they can be
+//! printed from the serial console:
+//!
+//! ```text
+//! stats              all registered accumulators
+//! stats <name>       one accumulator, with its histogram
+//! stats reset [name]
+//! ```
+#![no_std]
+
+use libm::sqrtf;
+
+use crate::drivers::SerialConsole;
+use crate::pgm_str;
+
+pub const HISTOGRAM_BUCKETS: usize = 8;
+const MAX_ACCUMULATORS: usize = 6;
+
+/// Equal-width buckets over `[low, high)`; values outside land in the
+/// first or last bucket
+#[derive(Clone, Copy)]
+pub struct Histogram {
+    pub low: f32,
+    pub high: f32,
+    pub buckets: [u16; HISTOGRAM_BUCKETS],
+}
+
+impl Histogram {
+    pub const fn new(low: f32, high: f32) -> Self {
+        Self {
+            low,
+            high,
+            buckets: [0; HISTOGRAM_BUCKETS],
+        }
+    }
+
+    fn add(&mut self, value: f32) {
+        let width = (self.high - self.low) / HISTOGRAM_BUCKETS as f32;
+        let index = if value <= self.low || width <= 0.0 {
+            0
+        } else {
+            (((value - self.low) / width) as usize).min(HISTOGRAM_BUCKETS - 1)
+        };
+        self.buckets[index] = self.buckets[index].saturating_add(1);
+    }
+
+    /// Lower edge of a bucket
+    pub fn bucket_low(&self, index: usize) -> f32 {
+        self.low + (self.high - self.low) * index as f32 / HISTOGRAM_BUCKETS as f32
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Accumulator {
+    count: u32,
+    mean: f32,
+    m2: f32,
+    min: f32,
+    max: f32,
+    histogram: Option<Histogram>,
+}
+
+impl Accumulator {
+    pub const fn new() -> Self {
+        Self {
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+            min: 0.0,
+            max: 0.0,
+            histogram: None,
+        }
+    }
+
+    pub const fn with_histogram(low: f32, high: f32) -> Self {
+        let mut acc = Self::new();
+        acc.histogram = Some(Histogram::new(low, high));
+        acc
+    }
+
+    pub fn push(&mut self, value: f32) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.count = self.count.saturating_add(1);
+
+        // Welford's update, stable without storing the samples
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (value - self.mean);
+
+        if let Some(histogram) = self.histogram.as_mut() {
+            histogram.add(value);
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn mean(&self) -> f32 {
+        self.mean
+    }
+
+    /// Sample variance, zero until there are two values
+    pub fn variance(&self) -> f32 {
+        if self.count < 2 {
+            0.0
+        } else {
+            self.m2 / (self.count - 1) as f32
+        }
+    }
+
+    pub fn std_dev(&self) -> f32 {
+        sqrtf(self.variance())
+    }
+
+    pub fn min(&self) -> Option<f32> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    pub fn max(&self) -> Option<f32> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    pub fn histogram(&self) -> Option<&Histogram> {
+        self.histogram.as_ref()
+    }
+
+    /// Clear all values, keeping the histogram range
+    pub fn reset(&mut self) {
+        let histogram = self.histogram.map(|h| Histogram::new(h.low, h.high));
+        *self = Self::new();
+        self.histogram = histogram;
+    }
+
+    /// One line summary: `n=.. mean=.. sd=.. min=.. max=..`
+    pub fn write_summary(&self, console: &mut SerialConsole) {
+        console.write_str("n=");
+        console.write_decimal(self.count);
+        console.write_str(" mean=");
+        console.write_fixed(self.mean);
+        console.write_str(" sd=");
+        console.write_fixed(self.std_dev());
+        console.write_str(" min=");
+        console.write_fixed(self.min().unwrap_or(0.0));
+        console.write_str(" max=");
+        console.write_fixed(self.max().unwrap_or(0.0));
+    }
+
+    /// One line per histogram bucket: `>=<low>: <count>`
+    pub fn write_histogram(&self, console: &mut SerialConsole) {
+        if let Some(histogram) = self.histogram.as_ref() {
+            for (i, &count) in histogram.buckets.iter().enumerate() {
+                console.write_str("  >=");
+                console.write_fixed(histogram.bucket_low(i));
+                console.write_str(": ");
+                console.write_decimal(count as u32);
+                console.write_str("\r\n");
+            }
+        }
+    }
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Entry {
+    name: &'static str,
+    accumulator: Accumulator,
+}
+
+/// Named accumulators reachable from the `stats` console command
+pub struct StatsRegistry {
+    entries: [Option<Entry>; MAX_ACCUMULATORS],
+}
+
+impl StatsRegistry {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None, None, None, None, None, None],
+        }
+    }
+
+    /// Register an accumulator, returns its id
+    pub fn register(&mut self, name: &'static str, accumulator: Accumulator) -> Result<usize, ()> {
+        for (id, slot) in self.entries.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some(Entry { name, accumulator });
+                return Ok(id);
+            }
+        }
+        Err(())
+    }
+
+    pub fn push(&mut self, id: usize, value: f32) {
+        if let Some(acc) = self.get_mut(id) {
+            acc.push(value);
+        }
+    }
+
+    /// Replace the registered copy, for owners that keep their own
+    /// accumulator (e.g. `Diagnostics::loop_time`)
+    pub fn publish(&mut self, id: usize, accumulator: &Accumulator) {
+        if let Some(acc) = self.get_mut(id) {
+            *acc = *accumulator;
+        }
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Accumulator> {
+        self.entries.get(id)?.as_ref().map(|e| &e.accumulator)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Accumulator> {
+        self.entries.get_mut(id)?.as_mut().map(|e| &mut e.accumulator)
+    }
+
+    /// Execute a console line, ignoring lines for other commands
+    pub fn process_line(&mut self, line: &str, console: &mut SerialConsole) {
+        let mut args = line.split_whitespace();
+        if args.next() != Some("stats") {
+            return;
+        }
+
+        match args.next() {
+            None => {
+                for entry in self.entries.iter().flatten() {
+                    console.write_str(entry.name);
+                    console.write_str(": ");
+                    entry.accumulator.write_summary(console);
+                    console.write_str("\r\n");
+                }
+            }
+            Some("reset") => {
+                let name = args.next();
+                for entry in self.entries.iter_mut().flatten() {
+                    if name.map_or(true, |n| n == entry.name) {
+                        entry.accumulator.reset();
+                    }
+                }
+                console.write_pgm_line(pgm_str!("ok"));
+            }
+            Some(name) => match self.entries.iter().flatten().find(|e| e.name == name) {
+                Some(entry) => {
+                    console.write_str(entry.name);
+                    console.write_str(": ");
+                    entry.accumulator.write_summary(console);
+                    console.write_str("\r\n");
+                    entry.accumulator.write_histogram(console);
+                }
+                None => console.write_pgm_line(pgm_str!("usage: stats [name]|reset [name]")),
+            },
+        }
+    }
+}
+
+impl Default for StatsRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,8 +1,19 @@
 #![no_std]
 
 use crate::drivers::SerialConsole;
+use crate::hal::{AdcArbiter, AdcChannel, AdcReference};
+use crate::stats::Accumulator;
+use avr_device::atmega128::TC3;
+use core::cell::RefCell;
 use core::fmt::Write;
 
+// Timer3 at clk/8 times benchmark runs (0.5us steps at 16MHz, 32ms range)
+const BENCH_TCCR3B_DIV8: u8 = 0x02;
+const BENCH_PRESCALER: u32 = 8;
+
+// Alternating bits plus both extremes for the loopback tests
+const LOOPBACK_PATTERN: [u8; 4] = [0x55, 0xAA, 0x00, 0xFF];
+
 pub struct TestRunner {
     console: SerialConsole,
     total_tests: u32,
@@ -60,6 +71,29 @@ impl TestRunner {
         self.print_summary();
     }
 
+    /// Time `iterations` runs of `f` and print the statistics in us
+    pub fn benchmark(&mut self, name: &'static str, iterations: u16, f: fn()) -> Accumulator {
+        let mut acc = Accumulator::with_histogram(0.0, 1000.0);
+        unsafe {
+            let p = TC3::ptr();
+            (*p).tccr3a.write(|w| w.bits(0));
+            (*p).tccr3b.write(|w| w.bits(BENCH_TCCR3B_DIV8));
+            for _ in 0..iterations {
+                (*p).tcnt3.write(|w| w.bits(0));
+                f();
+                let ticks = (*p).tcnt3.read().bits() as u32;
+                acc.push(crate::hal::clock::counts_to_us(ticks, BENCH_PRESCALER) as f32);
+            }
+            (*p).tccr3b.write(|w| w.bits(0));
+        }
+
+        self.console.write_fmt(format_args!("Benchmark {}: ", name)).ok();
+        acc.write_summary(&mut self.console);
+        self.console.write_line("");
+        acc.write_histogram(&mut self.console);
+        acc
+    }
+
     fn print_summary(&mut self) {
         self.console.write_fmt(format_args!(
             "\nTest Summary for {}:\n", 
@@ -127,41 +161,35 @@ impl TestCase for UartTest {
     }
 
     fn run(&self) -> TestResult {
-        let mut uart = crate::hal::uart::Uart::new();
-        let test_byte = 0x55;
-
-        uart.write_byte(test_byte);
-        assert_timeout!(uart.is_rx_ready(), 1000);
-        
-        match uart.read_byte() {
-            Some(byte) => assert_eq!(byte, test_byte),
-            None => return TestResult::Fail(TestError::HardwareFault),
+        // Internal loopback, no TXD-RXD jumper needed
+        let mut uart = crate::hal::uart::Uart::<avr_device::atmega128::USART0>::new();
+        match uart.loopback_check(&LOOPBACK_PATTERN, 100) {
+            Ok(()) => TestResult::Pass,
+            Err(_) => TestResult::Fail(TestError::HardwareFault),
         }
+    }
+}
 
-        TestResult::Pass
+/// Converts ADC0 through the arbiter that owns the ADC, so queued
+/// requests and free-running mode are not disturbed
+pub struct AdcTest<'a> {
+    adc: RefCell<&'a mut AdcArbiter>,
+}
+
+impl<'a> AdcTest<'a> {
+    pub fn new(adc: &'a mut AdcArbiter) -> Self {
+        Self { adc: RefCell::new(adc) }
     }
 }
 
-pub struct AdcTest;
-impl TestCase for AdcTest {
+impl TestCase for AdcTest<'_> {
     fn name(&self) -> &'static str {
         "ADC Functionality"
     }
 
     fn run(&self) -> TestResult {
-        unsafe {
-            let adc = &(*avr_device::atmega128::ADC::ptr());
-            
-            adc.admux.write(|w| w.bits(0x40));
-            adc.adcsra.write(|w| w.bits(0x87));
-            
-            assert_timeout!(adc.adcsra.read().bits() & 0x10 != 0, 1000);
-            
-            let value = adc.adcl.read().bits() as u16 | 
-                      ((adc.adch.read().bits() as u16) << 8);
-            
-            assert_within!(value, 512, 100);
-        }
+        let value = self.adc.borrow_mut().convert_blocking(AdcChannel::Adc0, AdcReference::Avcc) as i32;
+        assert_within!(value, 512, 100);
 
         TestResult::Pass
     }
@@ -198,17 +226,11 @@ impl TestCase for SpiTest {
     }
 
     fn run(&self) -> TestResult {
+        // Software echo in the HAL, no MOSI-MISO jumper needed
         let mut spi = crate::hal::spi::Spi::new();
-        let test_byte = 0xA5;
-
-        spi.transfer(test_byte);
-        assert_timeout!(spi.is_rx_ready(), 1000);
-        
-        match spi.read() {
-            Ok(byte) => assert_eq!(byte, test_byte),
-            Err(_) => return TestResult::Fail(TestError::HardwareFault),
+        match spi.loopback_check(&LOOPBACK_PATTERN) {
+            Ok(()) => TestResult::Pass,
+            Err(_) => TestResult::Fail(TestError::HardwareFault),
         }
-
-        TestResult::Pass
     }
 }
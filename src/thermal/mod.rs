@@ -0,0 +1,247 @@
+//! Thermal zone manager
+//!
+//! Temperature sources (LM75, MPU6050 die, NTC thermistors on the ADC) are
+//! grouped into zones. Each zone takes the hottest reading reported since the
+//! last evaluation, moves between Normal, Warning and Critical with
+//! hysteresis, drives an optional fan curve and contributes a derating factor
+//! for the motors. State changes are reported through diagnostics.
+//!
+//! All temperatures are in tenths of a degree Celsius.
+#![no_std]
+
+use crate::diagnostics::{Diagnostics, ErrorCode};
+use libm::logf;
+
+const MAX_ZONES: usize = 4;
+const FAN_CURVE_POINTS: usize = 4;
+
+// HardwareFault subcodes (0x04xx, kept clear of the 0x01xx emergency range)
+const SUBCODE_THERMAL_WARNING: u16 = 0x0401;
+const SUBCODE_THERMAL_CRITICAL: u16 = 0x0402;
+
+#[derive(Debug)]
+pub enum ThermalError {
+    TooManyZones,
+    InvalidZone,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum ZoneState {
+    Normal = 0,
+    Warning = 1,
+    Critical = 2,
+}
+
+/// Piecewise linear temperature to fan duty (0.0 - 100.0) curve. Points
+/// must be sorted by temperature.
+#[derive(Clone, Copy)]
+pub struct FanCurve {
+    pub points: [(i16, f32); FAN_CURVE_POINTS],
+}
+
+impl FanCurve {
+    pub fn duty(&self, temp: i16) -> f32 {
+        let first = self.points[0];
+        if temp <= first.0 {
+            return first.1;
+        }
+        for pair in self.points.windows(2) {
+            let (t0, d0) = pair[0];
+            let (t1, d1) = pair[1];
+            if temp <= t1 {
+                if t1 == t0 {
+                    return d1;
+                }
+                return d0 + (d1 - d0) * (temp - t0) as f32 / (t1 - t0) as f32;
+            }
+        }
+        self.points[FAN_CURVE_POINTS - 1].1
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct ZoneConfig {
+    pub name: &'static str,
+    pub warning: i16,
+    pub critical: i16,
+    /// A state is left only once the temperature drops this far below
+    /// its threshold
+    pub hysteresis: i16,
+    pub fan_curve: Option<FanCurve>,
+    /// Motor output is reduced linearly from 100% at `derate_start` to 0%
+    /// at `derate_end`
+    pub derate_start: i16,
+    pub derate_end: i16,
+}
+
+/// Callback invoked when a zone changes state
+pub type ThermalHandler = fn(usize, ZoneState);
+
+#[derive(Clone, Copy)]
+struct Zone {
+    config: ZoneConfig,
+    state: ZoneState,
+    temperature: Option<i16>,
+    pending: Option<i16>,
+}
+
+pub struct ThermalManager {
+    zones: [Option<Zone>; MAX_ZONES],
+    handler: Option<ThermalHandler>,
+}
+
+impl ThermalManager {
+    pub const fn new() -> Self {
+        Self {
+            zones: [None; MAX_ZONES],
+            handler: None,
+        }
+    }
+
+    pub fn set_handler(&mut self, handler: ThermalHandler) {
+        self.handler = Some(handler);
+    }
+
+    pub fn add_zone(&mut self, config: ZoneConfig) -> Result<usize, ThermalError> {
+        for (id, slot) in self.zones.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some(Zone {
+                    config,
+                    state: ZoneState::Normal,
+                    temperature: None,
+                    pending: None,
+                });
+                return Ok(id);
+            }
+        }
+        Err(ThermalError::TooManyZones)
+    }
+
+    /// Report a reading from one of the zone's sources
+    pub fn report(&mut self, zone: usize, temperature: i16) -> Result<(), ThermalError> {
+        let zone = self.zone_mut(zone)?;
+        zone.pending = Some(match zone.pending {
+            Some(hottest) => hottest.max(temperature),
+            None => temperature,
+        });
+        Ok(())
+    }
+
+    /// Update zone states from the readings reported since the last call
+    pub fn evaluate(&mut self, diag: &mut Diagnostics) {
+        for (id, slot) in self.zones.iter_mut().enumerate() {
+            let zone = match slot {
+                Some(zone) => zone,
+                None => continue,
+            };
+            let temp = match zone.pending.take() {
+                Some(temp) => temp,
+                None => continue,
+            };
+            zone.temperature = Some(temp);
+
+            let c = &zone.config;
+            let state = match zone.state {
+                ZoneState::Normal if temp >= c.critical => ZoneState::Critical,
+                ZoneState::Normal if temp >= c.warning => ZoneState::Warning,
+                ZoneState::Warning if temp >= c.critical => ZoneState::Critical,
+                ZoneState::Warning if temp < c.warning - c.hysteresis => ZoneState::Normal,
+                ZoneState::Critical if temp < c.critical - c.hysteresis => {
+                    if temp < c.warning - c.hysteresis {
+                        ZoneState::Normal
+                    } else {
+                        ZoneState::Warning
+                    }
+                }
+                current => current,
+            };
+
+            if state != zone.state {
+                zone.state = state;
+                match state {
+                    ZoneState::Warning => {
+                        diag.report_error(ErrorCode::HardwareFault, SUBCODE_THERMAL_WARNING, id as u32)
+                    }
+                    ZoneState::Critical => {
+                        diag.report_error(ErrorCode::HardwareFault, SUBCODE_THERMAL_CRITICAL, id as u32)
+                    }
+                    ZoneState::Normal => {}
+                }
+                if let Some(handler) = self.handler {
+                    handler(id, state);
+                }
+            }
+        }
+    }
+
+    pub fn state(&self, zone: usize) -> Option<ZoneState> {
+        self.zones.get(zone).copied().flatten().map(|z| z.state)
+    }
+
+    pub fn temperature(&self, zone: usize) -> Option<i16> {
+        self.zones.get(zone).copied().flatten().and_then(|z| z.temperature)
+    }
+
+    /// Fan duty for the zone, `None` if it has no fan curve or no reading
+    pub fn fan_duty(&self, zone: usize) -> Option<f32> {
+        let zone = self.zones.get(zone).copied().flatten()?;
+        let temp = zone.temperature?;
+        zone.config.fan_curve.map(|curve| curve.duty(temp))
+    }
+
+    /// Motor output factor (0.0 - 1.0) from the hottest zone, to be passed
+    /// to `MotorController::set_derating`
+    pub fn derating(&self) -> f32 {
+        let mut factor = 1.0f32;
+        for zone in self.zones.iter().flatten() {
+            let temp = match zone.temperature {
+                Some(temp) => temp,
+                None => continue,
+            };
+            let c = &zone.config;
+            let zone_factor = if zone.state == ZoneState::Critical || temp >= c.derate_end {
+                0.0
+            } else if temp <= c.derate_start {
+                1.0
+            } else {
+                (c.derate_end - temp) as f32 / (c.derate_end - c.derate_start) as f32
+            };
+            factor = factor.min(zone_factor);
+        }
+        factor
+    }
+
+    fn zone_mut(&mut self, zone: usize) -> Result<&mut Zone, ThermalError> {
+        self.zones
+            .get_mut(zone)
+            .and_then(|z| z.as_mut())
+            .ok_or(ThermalError::InvalidZone)
+    }
+}
+
+impl Default for ThermalManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// NTC thermistor wired as the low side of a divider to the ADC reference
+#[derive(Clone, Copy)]
+pub struct NtcParams {
+    pub beta: f32,
+    pub r25_ohms: f32,
+    pub series_ohms: f32,
+}
+
+/// Convert a 10-bit ADC reading of an NTC divider to tenths of a degree
+pub fn ntc_temperature(raw: u16, params: &NtcParams) -> Option<i16> {
+    if raw == 0 || raw >= 1023 {
+        // Shorted or open thermistor
+        return None;
+    }
+    let resistance = params.series_ohms * raw as f32 / (1023 - raw) as f32;
+    // Beta equation: 1/T = 1/T25 + ln(R/R25)/beta
+    let inv_t = 1.0 / 298.15 + logf(resistance / params.r25_ohms) / params.beta;
+    Some(((1.0 / inv_t - 273.15) * 10.0) as i16)
+}
@@ -0,0 +1,104 @@
+//! Rollover-safe time math on the 1ms system tick
+//!
+//! The tick counter is a u32 that wraps after ~49.7 days, so a comparison
+//! like `ticks >= deadline` silently breaks at the wrap. `Instant` compares
+//! through the wrapping difference instead, which stays correct as long as
+//! the two instants are less than ~24.8 days apart. For longer spans the
+//! scheduler also keeps a wrap counter extending the tick to 64 bits.
+#![no_std]
+
+use core::ops::{Add, Sub};
+
+use crate::os::SCHEDULER;
+
+/// Span of time in milliseconds
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct Duration(u32);
+
+impl Duration {
+    pub const ZERO: Duration = Duration(0);
+
+    pub const fn from_millis(ms: u32) -> Self {
+        Duration(ms)
+    }
+
+    pub const fn from_secs(secs: u32) -> Self {
+        Duration(secs * 1000)
+    }
+
+    pub const fn as_millis(self) -> u32 {
+        self.0
+    }
+
+    pub fn as_secs_f32(self) -> f32 {
+        self.0 as f32 / 1000.0
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, rhs: Duration) -> Duration {
+        Duration(self.0.saturating_add(rhs.0))
+    }
+}
+
+/// Point in time on the wrapping system tick
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Instant(u32);
+
+impl Instant {
+    /// Current system time
+    pub fn now() -> Self {
+        Instant(SCHEDULER.get_ticks())
+    }
+
+    pub const fn from_ticks(ticks: u32) -> Self {
+        Instant(ticks)
+    }
+
+    pub const fn ticks(self) -> u32 {
+        self.0
+    }
+
+    /// Time elapsed from `earlier` to `self`, correct across one wrap
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration(self.0.wrapping_sub(earlier.0))
+    }
+
+    /// Time elapsed since this instant
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+
+    /// True if `self` lies after `other` (within half the tick range)
+    pub fn is_after(self, other: Instant) -> bool {
+        (self.0.wrapping_sub(other.0) as i32) > 0
+    }
+
+    /// True once `self` has reached or passed `deadline`
+    pub fn has_reached(self, deadline: Instant) -> bool {
+        (self.0.wrapping_sub(deadline.0) as i32) >= 0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.wrapping_add(rhs.0))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Milliseconds since boot without wrapping
+pub fn uptime_ms() -> u64 {
+    SCHEDULER.get_ticks64()
+}